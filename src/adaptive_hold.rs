@@ -0,0 +1,220 @@
+use crate::onewire::Device;
+use chrono::{Local, Timelike};
+use serde::{Deserialize, Serialize};
+use simplelog::*;
+use std::collections::HashMap;
+use std::fs;
+use std::time::{Duration, Instant};
+
+pub const ADAPTIVE_HOLD_RETRIGGER_SECS: f32 = 30.0; //PIR re-trigger after auto turn-off within this time means the hold was too short
+pub const ADAPTIVE_HOLD_GROW_FACTOR: f32 = 1.25; //hold time multiplier after a re-trigger
+pub const ADAPTIVE_HOLD_SHRINK_FACTOR: f32 = 0.97; //hold time multiplier after a "clean" turn-off
+pub const ADAPTIVE_HOLD_SAVE_INTERVAL_SECS: f32 = 300.0; //how often the learned values are persisted
+
+pub static ADAPTIVE_HOLD_TAG: &str = "adaptive_hold"; //relay tag: adaptive_hold:MIN_SECS:MAX_SECS
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RelayHold {
+    pub name: String,
+    pub min_secs: f32,
+    pub max_secs: f32,
+    pub hold_secs: [f32; 24],
+    pub turn_ons: [u32; 24],
+    pub retriggers: [u32; 24],
+}
+
+/// Per-relay and per-hour PIR hold times learned from re-trigger patterns
+#[derive(Default, Serialize, Deserialize)]
+pub struct AdaptiveHold {
+    pub relays: HashMap<i32, RelayHold>,
+    #[serde(skip)]
+    dirty: bool,
+    #[serde(skip)]
+    last_save: Option<Instant>,
+}
+
+impl AdaptiveHold {
+    fn parse_tag(tags: &Vec<String>) -> Option<(f32, f32)> {
+        let tag = tags.iter().find(|t| t.starts_with(ADAPTIVE_HOLD_TAG))?;
+        let v: Vec<&str> = tag.split(':').collect();
+        let min = v.get(1)?.parse().ok()?;
+        let max = v.get(2)?.parse().ok()?;
+        Some((min, max))
+    }
+
+    pub fn load(path: &str) -> Self {
+        match fs::read_to_string(path) {
+            Ok(data) => match serde_json::from_str(&data) {
+                Ok(adaptive_hold) => adaptive_hold,
+                Err(e) => {
+                    error!("adaptive_hold: invalid file {}: {}", path, e);
+                    AdaptiveHold::default()
+                }
+            },
+            Err(e) => {
+                warn!("adaptive_hold: unable to read {}: {}", path, e);
+                AdaptiveHold::default()
+            }
+        }
+    }
+
+    /// Persists the learned values when changed, not more often than the save interval
+    pub fn save(&mut self, path: &str) {
+        if !self.dirty
+            || self.last_save.map_or(false, |t| {
+                t.elapsed() < Duration::from_secs_f32(ADAPTIVE_HOLD_SAVE_INTERVAL_SECS)
+            })
+        {
+            return;
+        }
+        self.last_save = Some(Instant::now());
+        match serde_json::to_string(&self) {
+            Ok(data) => match fs::write(path, data) {
+                Ok(_) => self.dirty = false,
+                Err(e) => error!("adaptive_hold: unable to write {}: {}", path, e),
+            },
+            Err(e) => error!("adaptive_hold: serialization error: {}", e),
+        }
+    }
+
+    /// Learns from the PIR trigger and returns the hold time for the current hour,
+    /// `None` for relays without the adaptive_hold tag
+    pub fn hold_time(&mut self, device: &Device, currently_off: bool) -> Option<Duration> {
+        self.hold_time_at(device, currently_off, Local::now().hour() as usize)
+    }
+
+    fn hold_time_at(
+        &mut self,
+        device: &Device,
+        currently_off: bool,
+        hour: usize,
+    ) -> Option<Duration> {
+        let (min_secs, max_secs) = AdaptiveHold::parse_tag(&device.tags)?;
+        let initial = device.pir_hold_secs.max(min_secs).min(max_secs);
+        let relay = self.relays.entry(device.id).or_insert_with(|| RelayHold {
+            name: device.name.clone(),
+            min_secs,
+            max_secs,
+            hold_secs: [initial; 24],
+            turn_ons: [0; 24],
+            retriggers: [0; 24],
+        });
+        //limits may be changed in the tag
+        relay.min_secs = min_secs;
+        relay.max_secs = max_secs;
+
+        //turning on after an auto turn-off (and not during the hold): evaluate the previous hold,
+        //a manual turn-off says nothing about the hold time
+        if currently_off && device.stop_after.is_none() {
+            if let Some(off_time) = device.last_toggled.filter(|t| device.auto_off == Some(*t)) {
+                let old = relay.hold_secs[hour];
+                relay.turn_ons[hour] += 1;
+                if off_time.elapsed() < Duration::from_secs_f32(ADAPTIVE_HOLD_RETRIGGER_SECS) {
+                    relay.retriggers[hour] += 1;
+                    relay.hold_secs[hour] = (old * ADAPTIVE_HOLD_GROW_FACTOR).min(max_secs);
+                    debug!(
+                        "adaptive_hold: {}: re-triggered {:?} after turn-off, hour {} hold: {:.0}s -> {:.0}s",
+                        device.name,
+                        off_time.elapsed(),
+                        hour,
+                        old,
+                        relay.hold_secs[hour]
+                    );
+                } else {
+                    relay.hold_secs[hour] = (old * ADAPTIVE_HOLD_SHRINK_FACTOR).max(min_secs);
+                }
+                self.dirty = true;
+            }
+        }
+        Some(Duration::from_secs_f32(relay.hold_secs[hour]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device() -> Device {
+        Device {
+            id: 1,
+            name: "hall_light".to_string(),
+            tags: vec![format!("{}:60:600", ADAPTIVE_HOLD_TAG)],
+            pir_exclude: false,
+            pir_hold_secs: 120.0,
+            switch_hold_secs: 0.0,
+            pir_all_day: false,
+            override_mode: false,
+            last_toggled: None,
+            stop_after: None,
+            auto_off: None,
+        }
+    }
+
+    fn turned_off(device: &mut Device, secs_ago: u64, auto: bool) {
+        let time = Instant::now() - Duration::from_secs(secs_ago);
+        device.last_toggled = Some(time);
+        device.auto_off = if auto { Some(time) } else { None };
+    }
+
+    #[test]
+    fn hold_time_is_learned_per_hour() {
+        let mut adaptive_hold = AdaptiveHold::default();
+        let mut device = device();
+        assert_eq!(
+            adaptive_hold.hold_time_at(&device, true, 20),
+            Some(Duration::from_secs(120))
+        );
+
+        //re-triggered right after the auto turn-off: the hold was too short
+        turned_off(&mut device, 5, true);
+        let grown = 120.0 * ADAPTIVE_HOLD_GROW_FACTOR;
+        assert_eq!(
+            adaptive_hold.hold_time_at(&device, true, 20),
+            Some(Duration::from_secs_f32(grown))
+        );
+
+        //a clean turn-off shrinks the hold of its own hour only
+        turned_off(&mut device, 600, true);
+        adaptive_hold.hold_time_at(&device, true, 3);
+        let relay = &adaptive_hold.relays[&1];
+        assert_eq!(relay.hold_secs[20], grown);
+        assert_eq!(relay.hold_secs[3], 120.0 * ADAPTIVE_HOLD_SHRINK_FACTOR);
+        assert_eq!(relay.hold_secs[4], 120.0);
+        assert_eq!((relay.turn_ons[20], relay.retriggers[20]), (1, 1));
+        assert_eq!((relay.turn_ons[3], relay.retriggers[3]), (1, 0));
+    }
+
+    #[test]
+    fn manual_turn_off_is_not_learned() {
+        let mut adaptive_hold = AdaptiveHold::default();
+        let mut device = device();
+        turned_off(&mut device, 5, false);
+        assert_eq!(
+            adaptive_hold.hold_time_at(&device, true, 20),
+            Some(Duration::from_secs(120))
+        );
+
+        //switched on and off by hand after the auto turn-off
+        let auto_off = Instant::now() - Duration::from_secs(60);
+        device.auto_off = Some(auto_off);
+        assert_eq!(
+            adaptive_hold.hold_time_at(&device, true, 20),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(adaptive_hold.relays[&1].turn_ons[20], 0);
+    }
+
+    #[test]
+    fn hold_time_stays_in_the_tag_limits() {
+        let mut adaptive_hold = AdaptiveHold::default();
+        let mut device = device();
+        device.pir_hold_secs = 1000.0;
+        turned_off(&mut device, 5, true);
+        assert_eq!(
+            adaptive_hold.hold_time_at(&device, true, 20),
+            Some(Duration::from_secs(600))
+        );
+        device.tags.clear();
+        assert_eq!(adaptive_hold.hold_time_at(&device, true, 20), None);
+    }
+}
@@ -0,0 +1,371 @@
+use crate::database::{CommandCode, DbTask};
+use crate::ethlcd::{BeepMethod, EthLcd};
+use crate::lcdproc::{LcdTask, LcdTaskCommand, ScreenPriority};
+use crate::mqtt::MqttEvent;
+use crate::onewire::{OneWireTask, StateMachine, TaskCommand};
+use crate::queue::Sender;
+use simplelog::*;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub static ALARM_TAG: &str = "alarm"; //sensor tag: alarm:<perimeter|interior|24h>[:entry]
+pub static ALARM_KEYSWITCH_TAG: &str = "alarm_keyswitch"; //sensor on: arm, off: disarm
+pub static ALARM_RFID_TAG: &str = "alarm_toggle"; //rfid tag arming/disarming the alarm
+pub static SIREN_TAG: &str = "siren"; //relay tag group turned on by the alarm
+
+pub const DEFAULT_EXIT_DELAY_SECS: f32 = 30.0;
+pub const DEFAULT_ENTRY_DELAY_SECS: f32 = 30.0;
+pub const DEFAULT_SIREN_SECS: f32 = 300.0;
+pub const ALARM_LCD_SCREEN: &str = "alarm";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ZoneKind {
+    /// doors/windows contacts: instant alarm when armed
+    Perimeter,
+    /// PIRs inside: subject to pet immunity
+    Interior,
+    /// tamper/24h zones: alarm even when disarmed
+    Tamper,
+}
+
+impl fmt::Display for ZoneKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZoneKind::Perimeter => write!(f, "perimeter"),
+            ZoneKind::Interior => write!(f, "interior"),
+            ZoneKind::Tamper => write!(f, "24h"),
+        }
+    }
+}
+
+impl ZoneKind {
+    /// Gets the zone from the sensor tags
+    pub fn from_tags(tags: &Vec<String>) -> Option<ZoneKind> {
+        let tag = tags.iter().find(|t| t.starts_with(ALARM_TAG))?;
+        let v: Vec<&str> = tag.split(':').collect();
+        let zone = match v.get(1) {
+            Some(&"perimeter") => ZoneKind::Perimeter,
+            Some(&"interior") => ZoneKind::Interior,
+            Some(&"24h") | Some(&"tamper") => ZoneKind::Tamper,
+            _ => return None,
+        };
+        Some(zone)
+    }
+
+    /// Perimeter zone with the entry delay (eg. the front door): `alarm:perimeter:entry`
+    pub fn is_entry(tags: &Vec<String>) -> bool {
+        tags.iter()
+            .filter(|t| t.starts_with(ALARM_TAG))
+            .any(|t| t.split(':').nth(2) == Some("entry"))
+    }
+}
+
+pub struct Alarm {
+    pub name: String,
+    pub armed: Arc<AtomicBool>,
+    pub pet_immunity: Option<Duration>,
+    pub alarm_script: Option<String>,
+    pub lcd_transmitter: Sender<LcdTask>,
+    pub mqtt_transmitter: Sender<MqttEvent>,
+    pub db_transmitter: Sender<DbTask>,
+    pub exit_delay: Duration,
+    pub entry_delay: Duration,
+    pub siren_duration: Duration,
+    interior_triggers: Vec<(Instant, i32)>,
+    triggered: bool,
+    was_armed: bool,
+    armed_at: Option<Instant>,
+    //entry zone which started the entry delay: (time, sensor name, id_sensor)
+    entry_started: Option<(Instant, String, i32)>,
+}
+
+impl Alarm {
+    pub fn new(
+        armed: Arc<AtomicBool>,
+        pet_immunity: Option<Duration>,
+        alarm_script: Option<String>,
+        lcd_transmitter: Sender<LcdTask>,
+        mqtt_transmitter: Sender<MqttEvent>,
+        db_transmitter: Sender<DbTask>,
+    ) -> Self {
+        Alarm {
+            name: "alarm".to_string(),
+            armed,
+            pet_immunity,
+            alarm_script,
+            lcd_transmitter,
+            mqtt_transmitter,
+            db_transmitter,
+            exit_delay: Duration::from_secs_f32(DEFAULT_EXIT_DELAY_SECS),
+            entry_delay: Duration::from_secs_f32(DEFAULT_ENTRY_DELAY_SECS),
+            siren_duration: Duration::from_secs_f32(DEFAULT_SIREN_SECS),
+            interior_triggers: vec![],
+            triggered: false,
+            was_armed: false,
+            armed_at: None,
+            entry_started: None,
+        }
+    }
+
+    /// Arms/disarms the alarm (RFID tag, keyswitch), the transition is handled in check()
+    pub fn set_armed(&self, arm: bool, source: &str) {
+        if self.armed.swap(arm, Ordering::SeqCst) != arm {
+            info!(
+                "{}: {} requested by {}",
+                self.name,
+                if arm { "arming" } else { "disarming" },
+                source
+            );
+        }
+    }
+
+    pub fn toggle(&self, source: &str) {
+        self.set_armed(!self.armed.load(Ordering::SeqCst), source);
+    }
+
+    fn log_event(&self, command: CommandCode, id_sensor: Option<i32>) {
+        let _ = self.db_transmitter.send(DbTask {
+            command,
+            value: id_sensor,
+        });
+    }
+
+    fn in_exit_delay(&self) -> bool {
+        self.armed_at
+            .map_or(false, |armed_at| armed_at.elapsed() < self.exit_delay)
+    }
+
+    /// Pet immunity: an interior zone triggers only when two different interior
+    /// sensors detected a movement within the time window
+    fn interior_confirmed(&mut self, id_sensor: i32) -> bool {
+        let window = match self.pet_immunity {
+            Some(window) => window,
+            None => return true,
+        };
+        self.interior_triggers
+            .retain(|(time, _)| time.elapsed() < window);
+        self.interior_triggers.push((Instant::now(), id_sensor));
+        let confirmed = self
+            .interior_triggers
+            .iter()
+            .any(|(_, id)| *id != id_sensor);
+        if !confirmed {
+            info!(
+                "{}: 🐈 interior movement waiting for confirmation (pet immunity)",
+                self.name
+            );
+        }
+        confirmed
+    }
+
+    fn trigger(
+        &mut self,
+        zone: ZoneKind,
+        sensor_name: &str,
+        id_sensor: i32,
+        ethlcd: Option<&mut EthLcd>,
+        pending_tasks: &mut Vec<OneWireTask>,
+    ) {
+        warn!(
+            "{}: 🚨 ALARM! zone: <b>{}</>, sensor: <b>{}</>",
+            self.name, zone, sensor_name
+        );
+        self.entry_started = None;
+        self.log_event(CommandCode::AlarmTriggered, Some(id_sensor));
+        if !self.triggered {
+            self.triggered = true;
+            let task = LcdTask {
+                command: LcdTaskCommand::SetEmergencyMode,
+                int_arg: 1,
+                string_arg: None,
+            };
+            let _ = self.lcd_transmitter.send(task);
+            //own alert screen with the zone and sensor
+            let _ = self.lcd_transmitter.send(LcdTask {
+                command: LcdTaskCommand::AddScreen {
+                    screen: ALARM_LCD_SCREEN.to_string(),
+                    priority: ScreenPriority::Alert,
+                },
+                int_arg: 0,
+                string_arg: None,
+            });
+            for (line, text) in [format!("ALARM! {}", zone), sensor_name.to_string()]
+                .iter()
+                .enumerate()
+            {
+                let _ = self.lcd_transmitter.send(LcdTask {
+                    command: LcdTaskCommand::SetScreenLine {
+                        screen: ALARM_LCD_SCREEN.to_string(),
+                    },
+                    int_arg: line as u8,
+                    string_arg: Some(text.clone()),
+                });
+            }
+            if let Some(ethlcd) = ethlcd {
+                ethlcd.async_beep(BeepMethod::AlarmArming);
+            }
+        }
+        //siren, prolonged with every trigger
+        pending_tasks.push(OneWireTask {
+            command: TaskCommand::TurnOnProlong,
+            id_relay: None,
+            tag_group: Some(SIREN_TAG.to_owned()),
+            id_yeelight: None,
+            id_plug: None,
+            duration: Some(self.siren_duration),
+        });
+        let _ = self.mqtt_transmitter.send(MqttEvent::new(
+            "alarm",
+            format!("{}:{}", zone, sensor_name),
+            false,
+        ));
+        if let Some(ref cmd) = self.alarm_script {
+            let mut cmd = cmd.clone();
+            cmd = str::replace(&cmd, "%zone%", &zone.to_string());
+            cmd = str::replace(&cmd, "%name%", sensor_name);
+            thread::spawn(move || StateMachine::run_shell_command(cmd));
+        }
+    }
+
+    /// Periodic check: arming/disarming transitions, entry delay timeout
+    /// and clearing the triggered state after disarming
+    pub fn check(&mut self, ethlcd: Option<&mut EthLcd>, pending_tasks: &mut Vec<OneWireTask>) {
+        let armed = self.armed.load(Ordering::SeqCst);
+        if armed != self.was_armed {
+            self.was_armed = armed;
+            let _ = self.mqtt_transmitter.send(MqttEvent::new(
+                "alarm/armed",
+                if armed { "on" } else { "off" },
+                true,
+            ));
+            if armed {
+                info!(
+                    "{}: 🔒 armed, exit delay: {}s",
+                    self.name,
+                    self.exit_delay.as_secs()
+                );
+                self.armed_at = Some(Instant::now());
+                self.log_event(CommandCode::AlarmArmed, None);
+                if let Some(ethlcd) = ethlcd {
+                    ethlcd.async_beep(BeepMethod::AlarmArming);
+                }
+                return;
+            }
+            info!("{}: 🔓 disarmed", self.name);
+            self.armed_at = None;
+            self.entry_started = None;
+            self.log_event(CommandCode::AlarmDisarmed, None);
+            if let Some(ethlcd) = ethlcd {
+                ethlcd.async_beep(BeepMethod::Confirmation);
+            }
+            pending_tasks.push(OneWireTask {
+                command: TaskCommand::TurnOff,
+                id_relay: None,
+                tag_group: Some(SIREN_TAG.to_owned()),
+                id_yeelight: None,
+                id_plug: None,
+                duration: None,
+            });
+            return;
+        }
+
+        if armed {
+            //not disarmed in time after entering
+            let expired = self
+                .entry_started
+                .as_ref()
+                .map_or(false, |(started, _, _)| {
+                    started.elapsed() > self.entry_delay
+                });
+            if expired {
+                let (_, name, id_sensor) = self.entry_started.take().unwrap();
+                self.trigger(ZoneKind::Perimeter, &name, id_sensor, ethlcd, pending_tasks);
+            }
+            return;
+        }
+        self.interior_triggers.clear();
+        if self.triggered {
+            self.triggered = false;
+            info!("{}: alarm cleared", self.name);
+            let task = LcdTask {
+                command: LcdTaskCommand::SetEmergencyMode,
+                int_arg: 0,
+                string_arg: None,
+            };
+            let _ = self.lcd_transmitter.send(task);
+            let _ = self.lcd_transmitter.send(LcdTask {
+                command: LcdTaskCommand::RemoveScreen {
+                    screen: ALARM_LCD_SCREEN.to_string(),
+                },
+                int_arg: 0,
+                string_arg: None,
+            });
+        }
+    }
+
+    pub fn sensor_event(
+        &mut self,
+        sensor_name: &str,
+        id_sensor: i32,
+        sensor_tags: &Vec<String>,
+        sensor_on: bool,
+        ethlcd: Option<&mut EthLcd>,
+        pending_tasks: &mut Vec<OneWireTask>,
+    ) {
+        //keyswitch: on arms, off disarms
+        if sensor_tags.iter().any(|t| t == ALARM_KEYSWITCH_TAG) {
+            self.set_armed(sensor_on, &format!("keyswitch {}", sensor_name));
+            return;
+        }
+
+        let armed = self.armed.load(Ordering::SeqCst);
+        //the state is already inverted on read for NC contacts
+        let zone = match ZoneKind::from_tags(sensor_tags) {
+            Some(zone) => zone,
+            None => return,
+        };
+        if !sensor_on {
+            return;
+        }
+        if zone != ZoneKind::Tamper && armed && self.in_exit_delay() {
+            debug!(
+                "{}: {}: ignoring {} zone during the exit delay",
+                self.name, sensor_name, zone
+            );
+            return;
+        }
+        match zone {
+            ZoneKind::Tamper => self.trigger(zone, sensor_name, id_sensor, ethlcd, pending_tasks),
+            ZoneKind::Perimeter if armed && ZoneKind::is_entry(sensor_tags) => {
+                if self.entry_started.is_none() && !self.triggered {
+                    warn!(
+                        "{}: 🚪 {}: entry, disarm within {}s",
+                        self.name,
+                        sensor_name,
+                        self.entry_delay.as_secs()
+                    );
+                    self.entry_started = Some((Instant::now(), sensor_name.to_string(), id_sensor));
+                    if let Some(ethlcd) = ethlcd {
+                        ethlcd.async_beep(BeepMethod::DoorBell);
+                    }
+                }
+            }
+            ZoneKind::Perimeter if armed => {
+                self.trigger(zone, sensor_name, id_sensor, ethlcd, pending_tasks)
+            }
+            ZoneKind::Interior if armed => {
+                //walking to the keypad after entering
+                if self.entry_started.is_some() {
+                    return;
+                }
+                if self.interior_confirmed(id_sensor) {
+                    self.trigger(zone, sensor_name, id_sensor, ethlcd, pending_tasks);
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,414 @@
+use crate::modbus::{read_register_block, BlockRead, MODBUS_ATTEMPTS_PER_BLOCK};
+use crate::mqtt::MqttEvent;
+use crate::notify::{Notification, Severity};
+use crate::queue::Sender;
+use crate::virtual_sensor::SensorValues;
+use humantime::format_duration;
+use simplelog::*;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+use tokio_modbus::prelude::*;
+
+pub const APPLIANCE_POLL_INTERVAL_SECS: f32 = 10.0; //secs between reading the power meters
+pub const APPLIANCE_METER_TIMEOUT_SECS: u64 = 5;
+pub const APPLIANCE_DEFAULT_SDM_SLAVE: u8 = 1;
+pub const SDM_ACTIVE_POWER_REGISTER: u16 = 0x000c; //input register, f32 W (single phase meter or L1)
+pub const APPLIANCE_DEFAULT_RUNNING_WATTS: f32 = 10.0; //above this power the cycle is started
+pub const APPLIANCE_DEFAULT_IDLE_WATTS: f32 = 3.0; //below this power the cycle may be finished
+pub const APPLIANCE_DEFAULT_START_SECS: u64 = 60; //how long the power has to be high to start
+pub const APPLIANCE_DEFAULT_FINISH_SECS: u64 = 300; //how long the power has to be low to finish
+pub const APPLIANCE_FINISHED_HOLD_SECS: u64 = 3600; //"finished" state is kept this long
+
+// Just a generic Result type to ease error handling for us. Errors in multithreaded
+// async contexts needs some extra restrictions
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Metered plug type, or an Eastron SDM meter behind a Modbus-TCP gateway
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MeterKind {
+    Tasmota,
+    Shelly,
+    Sdm,
+}
+
+impl MeterKind {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim() {
+            "tasmota" => Some(MeterKind::Tasmota),
+            "shelly" => Some(MeterKind::Shelly),
+            "sdm" => Some(MeterKind::Sdm),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApplianceState {
+    Idle,
+    Running,
+    Finished,
+}
+
+impl fmt::Display for ApplianceState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApplianceState::Idle => write!(f, "idle"),
+            ApplianceState::Running => write!(f, "running"),
+            ApplianceState::Finished => write!(f, "finished"),
+        }
+    }
+}
+
+impl ApplianceState {
+    /// Numeric value for the virtual sensor expressions
+    fn value(&self) -> f32 {
+        match self {
+            ApplianceState::Idle => 0.0,
+            ApplianceState::Running => 1.0,
+            ApplianceState::Finished => 2.0,
+        }
+    }
+}
+
+pub struct Appliance {
+    pub name: String,
+    pub meter: MeterKind,
+    pub host: String,
+    /// Modbus slave address of the SDM meter
+    pub slave: u8,
+    pub running_watts: f32,
+    pub idle_watts: f32,
+    pub start_time: Duration,
+    pub finish_time: Duration,
+    pub state: ApplianceState,
+    pub started: Option<Instant>,
+    pub state_changed: Instant,
+    //since when the power is continuously above/below the threshold
+    high_since: Option<Instant>,
+    low_since: Option<Instant>,
+}
+
+impl Appliance {
+    pub fn new(name: String, meter: MeterKind, host: String) -> Self {
+        Appliance {
+            name,
+            meter,
+            host,
+            slave: APPLIANCE_DEFAULT_SDM_SLAVE,
+            running_watts: APPLIANCE_DEFAULT_RUNNING_WATTS,
+            idle_watts: APPLIANCE_DEFAULT_IDLE_WATTS,
+            start_time: Duration::from_secs(APPLIANCE_DEFAULT_START_SECS),
+            finish_time: Duration::from_secs(APPLIANCE_DEFAULT_FINISH_SECS),
+            state: ApplianceState::Idle,
+            started: None,
+            state_changed: Instant::now(),
+            high_since: None,
+            low_since: None,
+        }
+    }
+
+    /// Reads the active power of the SDM meter, `host` is the `address:port` of the gateway
+    async fn read_sdm_power(&self) -> Result<f32> {
+        let socket_addr = self.host.parse()?;
+        let mut ctx = timeout(
+            Duration::from_secs(APPLIANCE_METER_TIMEOUT_SECS),
+            tcp::connect_slave(socket_addr, Slave(self.slave)),
+        )
+        .await??;
+        match read_register_block(
+            &mut ctx,
+            &self.name,
+            true,
+            SDM_ACTIVE_POWER_REGISTER,
+            2,
+            MODBUS_ATTEMPTS_PER_BLOCK,
+        )
+        .await
+        {
+            BlockRead::Data(data) if data.len() >= 2 => {
+                Ok(f32::from_bits(((data[0] as u32) << 16) | data[1] as u32))
+            }
+            _ => Err("no power value from the meter".into()),
+        }
+    }
+
+    async fn read_power(&self, client: &reqwest::Client) -> Result<f32> {
+        let url = match self.meter {
+            MeterKind::Tasmota => format!("http://{}/cm?cmnd=Status%208", self.host),
+            MeterKind::Shelly => format!("http://{}/meter/0", self.host),
+            MeterKind::Sdm => return self.read_sdm_power().await,
+        };
+        let body = client.get(&url).send().await?.text().await?;
+        let json: serde_json::Value = serde_json::from_str(&body)?;
+        let power = match self.meter {
+            MeterKind::Tasmota => &json["StatusSNS"]["ENERGY"]["Power"],
+            _ => &json["power"],
+        };
+        match power.as_f64() {
+            Some(power) => Ok(power as f32),
+            None => Err(format!("no power value in response: {}", body).into()),
+        }
+    }
+
+    /// Feeds a new power reading taken at `now`, returns the new state when it has changed
+    fn update(&mut self, power: f32, now: Instant) -> Option<ApplianceState> {
+        if power >= self.running_watts {
+            self.high_since.get_or_insert(now);
+        } else {
+            self.high_since = None;
+        }
+        if power <= self.idle_watts {
+            self.low_since.get_or_insert(now);
+        } else {
+            self.low_since = None;
+        }
+
+        let new_state = match self.state {
+            ApplianceState::Idle | ApplianceState::Finished
+                if self
+                    .high_since
+                    .map_or(false, |t| now.duration_since(t) >= self.start_time) =>
+            {
+                ApplianceState::Running
+            }
+            ApplianceState::Running
+                if self
+                    .low_since
+                    .map_or(false, |t| now.duration_since(t) >= self.finish_time) =>
+            {
+                ApplianceState::Finished
+            }
+            ApplianceState::Finished
+                if now.duration_since(self.state_changed)
+                    >= Duration::from_secs(APPLIANCE_FINISHED_HOLD_SECS) =>
+            {
+                ApplianceState::Idle
+            }
+            _ => return None,
+        };
+        if new_state == ApplianceState::Running {
+            self.started = Some(now);
+        }
+        self.state = new_state;
+        self.state_changed = now;
+        Some(new_state)
+    }
+}
+
+pub struct ApplianceMonitor {
+    pub name: String,
+    pub appliances: Vec<Appliance>,
+    pub notify_transmitter: Sender<Notification>,
+    pub sensor_values: Arc<RwLock<SensorValues>>,
+    pub mqtt_transmitter: Sender<MqttEvent>,
+}
+
+impl ApplianceMonitor {
+    fn state_changed(&self, appliance: &Appliance) {
+        if let Ok(mut values) = self.sensor_values.write() {
+            values.insert(appliance.name.clone(), appliance.state.value());
+        }
+        let _ = self.mqtt_transmitter.send(MqttEvent::new(
+            format!("appliance/{}", appliance.name),
+            appliance.state,
+            true,
+        ));
+
+        if appliance.state == ApplianceState::Finished {
+            let cycle = appliance
+                .started
+                .map(|t| Duration::from_secs(t.elapsed().as_secs()))
+                .unwrap_or_default();
+            info!(
+                "{}: 🧺 {} finished (cycle time: {})",
+                self.name,
+                appliance.name,
+                format_duration(cycle)
+            );
+            let _ = self.notify_transmitter.send(Notification::new(
+                self.name.as_str(),
+                Severity::Info,
+                format!("{} finished", appliance.name),
+                format!("cycle time: {}", format_duration(cycle)),
+            ));
+        } else {
+            info!("{}: {} is {}", self.name, appliance.name, appliance.state);
+        }
+    }
+
+    pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
+        info!(
+            "{}: Starting task, monitoring: {:?}",
+            self.name,
+            self.appliances.iter().map(|a| &a.name).collect::<Vec<_>>()
+        );
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(APPLIANCE_METER_TIMEOUT_SECS))
+            .build()?;
+        let mut poll_interval: Option<Instant> = None;
+
+        loop {
+            if worker_cancel_flag.load(Ordering::SeqCst) {
+                debug!("{}: Got terminate signal from main", self.name);
+                break;
+            }
+
+            if poll_interval.map_or(true, |t| {
+                t.elapsed() > Duration::from_secs_f32(APPLIANCE_POLL_INTERVAL_SECS)
+            }) {
+                poll_interval = Some(Instant::now());
+                let mut changed = vec![];
+                for (i, appliance) in self.appliances.iter_mut().enumerate() {
+                    match appliance.read_power(&client).await {
+                        Ok(power) => {
+                            debug!("{}: {}: {} W", self.name, appliance.name, power);
+                            if appliance.update(power, Instant::now()).is_some() {
+                                changed.push(i);
+                            }
+                        }
+                        Err(e) => {
+                            error!("{}: {}: power read error: {}", self.name, appliance.name, e);
+                        }
+                    }
+                }
+                for i in changed {
+                    self.state_changed(&self.appliances[i]);
+                }
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        info!("{}: task stopped", self.name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn washer() -> Appliance {
+        Appliance::new(
+            "washer".to_string(),
+            MeterKind::Shelly,
+            "192.168.0.30".to_string(),
+        )
+    }
+
+    /// Feeds the readings given as (secs from the start, W), returns the state changes
+    fn feed(
+        appliance: &mut Appliance,
+        start: Instant,
+        readings: &[(u64, f32)],
+    ) -> Vec<(u64, ApplianceState)> {
+        readings
+            .iter()
+            .filter_map(|&(secs, power)| {
+                appliance
+                    .update(power, start + Duration::from_secs(secs))
+                    .map(|state| (secs, state))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn full_cycle() {
+        let mut washer = washer();
+        let start = Instant::now();
+        let readings = [
+            (0, 0.5),
+            (10, 2000.0),
+            (40, 150.0),
+            (70, 2000.0),
+            (100, 1.0),
+            (300, 1.5),
+            (400, 1.2),
+            (410, 0.0),
+        ];
+        assert_eq!(
+            feed(&mut washer, start, &readings),
+            vec![
+                (70, ApplianceState::Running),
+                (400, ApplianceState::Finished)
+            ]
+        );
+        assert_eq!(washer.started, Some(start + Duration::from_secs(70)));
+
+        //the finished state is kept for a while
+        let expired = 400 + APPLIANCE_FINISHED_HOLD_SECS;
+        assert_eq!(
+            feed(&mut washer, start, &[(expired - 1, 0.0), (expired, 0.0)]),
+            vec![(expired, ApplianceState::Idle)]
+        );
+    }
+
+    #[test]
+    fn short_spikes_and_pauses_are_ignored() {
+        let mut washer = washer();
+        let start = Instant::now();
+        let readings = [
+            //pump spike
+            (0, 2000.0),
+            (50, 0.0),
+            (60, 2000.0),
+            (110, 2000.0),
+            (120, 2000.0),
+            //drum turning in between the thresholds restarts the finish debounce
+            (130, 1.0),
+            (400, 5.0),
+            (500, 1.0),
+            (790, 0.0),
+            (800, 0.0),
+        ];
+        assert_eq!(
+            feed(&mut washer, start, &readings),
+            vec![
+                (120, ApplianceState::Running),
+                (800, ApplianceState::Finished)
+            ]
+        );
+    }
+
+    #[test]
+    fn restarted_before_the_finished_state_expires() {
+        let mut dryer = Appliance {
+            running_watts: 100.0,
+            idle_watts: 20.0,
+            start_time: Duration::from_secs(10),
+            finish_time: Duration::from_secs(60),
+            ..washer()
+        };
+        let start = Instant::now();
+        let readings = [
+            (0, 500.0),
+            (10, 500.0),
+            (20, 15.0),
+            (80, 15.0),
+            //anti-crease turns: below the running threshold
+            (90, 50.0),
+            (100, 500.0),
+            (110, 500.0),
+        ];
+        assert_eq!(
+            feed(&mut dryer, start, &readings),
+            vec![
+                (10, ApplianceState::Running),
+                (80, ApplianceState::Finished),
+                (110, ApplianceState::Running)
+            ]
+        );
+        assert_eq!(dryer.started, Some(start + Duration::from_secs(110)));
+    }
+
+    #[test]
+    fn meter_kind() {
+        assert_eq!(MeterKind::parse("tasmota"), Some(MeterKind::Tasmota));
+        assert_eq!(MeterKind::parse(" shelly "), Some(MeterKind::Shelly));
+        assert_eq!(MeterKind::parse("sdm"), Some(MeterKind::Sdm));
+        assert_eq!(MeterKind::parse("sdm630"), None);
+    }
+}
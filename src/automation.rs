@@ -0,0 +1,501 @@
+use crate::onewire::{DEFAULT_PIR_PROLONG_SECS, DEFAULT_SWITCH_HOLD_SECS, MIN_TOGGLE_DELAY_SECS};
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProlongKind {
+    PIR,
+    Remote,
+    Switch,
+    AutoOff,
+    DayNight,
+}
+
+/// Why a request doesn't change anything
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IgnoreReason {
+    /// PIR sensor went inactive
+    SensorInactive,
+    /// device is not turned on by the PIR sensors
+    PirExcluded,
+    /// PIR during the day for a night-only device
+    Daytime,
+    /// turn-off request for a device which is off
+    AlreadyOff,
+    /// too fast state change after the previous one
+    FlipFlop,
+}
+
+impl fmt::Display for IgnoreReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            IgnoreReason::SensorInactive => "sensor inactive",
+            IgnoreReason::PirExcluded => "excluded from PIR",
+            IgnoreReason::Daytime => "daytime",
+            IgnoreReason::AlreadyOff => "already off",
+            IgnoreReason::FlipFlop => "flip-flop protection",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// What to do with the device
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// turn on for the duration, `None`: until turned off
+    TurnOn {
+        duration: Option<Duration>,
+    },
+    TurnOff,
+    /// wall switch: toggle and keep the device in the override mode for the duration
+    Toggle {
+        duration: Duration,
+    },
+    /// the device is on already: `duration` is added, `stop_after` is the new on-time
+    /// counted from the last toggle (`None` keeps the current one)
+    Prolong {
+        duration: Duration,
+        stop_after: Option<Duration>,
+    },
+    /// the device switched off by hand is given back to the automation
+    EndOverride,
+    Ignore {
+        reason: IgnoreReason,
+    },
+}
+
+impl Action {
+    /// The device output has to be changed
+    pub fn changes_output(&self) -> bool {
+        matches!(
+            self,
+            Action::TurnOn { .. } | Action::TurnOff | Action::Toggle { .. }
+        )
+    }
+}
+
+/// Settings and state of the device the decision is based on
+#[derive(Clone, Debug)]
+pub struct DeviceState {
+    pub override_mode: bool,
+    pub pir_exclude: bool,
+    pub pir_all_day: bool,
+    pub pir_hold_secs: f32,
+    pub switch_hold_secs: f32,
+    /// time since the last state change, `None` when not changed yet
+    pub toggled_elapsed: Option<Duration>,
+}
+
+/// A turn-on/prolong request
+#[derive(Clone, Debug)]
+pub struct Trigger {
+    pub kind: ProlongKind,
+    pub night: bool,
+    /// sensor active or turn-on request
+    pub on: bool,
+    pub currently_off: bool,
+    /// explicit duration instead of the device hold time
+    pub duration: Option<Duration>,
+}
+
+fn rejected(device: &DeviceState, trigger: &Trigger) -> Option<IgnoreReason> {
+    match trigger.kind {
+        ProlongKind::PIR if !trigger.on => Some(IgnoreReason::SensorInactive),
+        //a device in the override mode is prolonged by any motion
+        ProlongKind::PIR if device.override_mode => None,
+        ProlongKind::PIR if device.pir_exclude => Some(IgnoreReason::PirExcluded),
+        ProlongKind::PIR if !(trigger.night || device.pir_all_day) => Some(IgnoreReason::Daytime),
+        ProlongKind::Remote | ProlongKind::AutoOff
+            if !trigger.on
+                && trigger.currently_off
+                && !(trigger.kind == ProlongKind::AutoOff && device.override_mode) =>
+        {
+            Some(IgnoreReason::AlreadyOff)
+        }
+        _ => None,
+    }
+}
+
+/// On-time when no explicit duration is given
+fn hold_time(device: &DeviceState, trigger: &Trigger) -> Duration {
+    let mut secs = match trigger.kind {
+        ProlongKind::Switch => device.switch_hold_secs,
+        _ => device.pir_hold_secs,
+    };
+    if trigger.kind != ProlongKind::Switch {
+        if !device.override_mode && trigger.currently_off {
+            //a custom switch hold time is used for the remote turn-on too
+            if trigger.kind == ProlongKind::Remote
+                && device.switch_hold_secs != DEFAULT_SWITCH_HOLD_SECS
+            {
+                secs = device.switch_hold_secs;
+            }
+        } else if device.override_mode && DEFAULT_PIR_PROLONG_SECS > secs {
+            secs = DEFAULT_PIR_PROLONG_SECS;
+        }
+    }
+    Duration::from_secs_f32(secs)
+}
+
+/// Decides what a turn-on/prolong request does with the device, without any side effects
+pub fn decide(device: &DeviceState, trigger: &Trigger) -> Action {
+    if let Some(reason) = rejected(device, trigger) {
+        return Action::Ignore { reason };
+    }
+    let duration = trigger
+        .duration
+        .unwrap_or_else(|| hold_time(device, trigger));
+
+    let state_change = match trigger.kind {
+        ProlongKind::Switch | ProlongKind::DayNight => true,
+        ProlongKind::Remote | ProlongKind::AutoOff if !trigger.on => true,
+        _ => !device.override_mode && trigger.currently_off,
+    };
+    if !state_change {
+        let elapsed = device.toggled_elapsed.unwrap_or_default();
+        //in the override mode the on-time is only extended near its end
+        let extend = !device.override_mode
+            || (device.switch_hold_secs > duration.as_secs_f32()
+                && elapsed
+                    > Duration::from_secs_f32(device.switch_hold_secs - duration.as_secs_f32()));
+        return Action::Prolong {
+            duration,
+            stop_after: if extend {
+                Some(elapsed + duration)
+            } else {
+                None
+            },
+        };
+    }
+
+    if device.toggled_elapsed.map_or(false, |elapsed| {
+        elapsed < Duration::from_secs_f32(MIN_TOGGLE_DELAY_SECS)
+    }) {
+        return Action::Ignore {
+            reason: IgnoreReason::FlipFlop,
+        };
+    }
+    match trigger.kind {
+        ProlongKind::AutoOff if trigger.currently_off && device.override_mode => {
+            Action::EndOverride
+        }
+        ProlongKind::AutoOff => Action::TurnOff,
+        ProlongKind::Remote if !trigger.on => Action::TurnOff,
+        ProlongKind::DayNight if trigger.on => Action::TurnOn { duration: None },
+        ProlongKind::DayNight => Action::TurnOff,
+        ProlongKind::Switch => Action::Toggle { duration },
+        _ => Action::TurnOn {
+            duration: Some(duration),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onewire::DEFAULT_PIR_HOLD_SECS;
+
+    fn device() -> DeviceState {
+        DeviceState {
+            override_mode: false,
+            pir_exclude: false,
+            pir_all_day: false,
+            pir_hold_secs: DEFAULT_PIR_HOLD_SECS,
+            switch_hold_secs: DEFAULT_SWITCH_HOLD_SECS,
+            toggled_elapsed: None,
+        }
+    }
+
+    fn trigger(kind: ProlongKind, on: bool, currently_off: bool) -> Trigger {
+        Trigger {
+            kind,
+            night: true,
+            on,
+            currently_off,
+            duration: None,
+        }
+    }
+
+    fn secs(secs: f32) -> Duration {
+        Duration::from_secs_f32(secs)
+    }
+
+    fn ignored(reason: IgnoreReason) -> Action {
+        Action::Ignore { reason }
+    }
+
+    #[test]
+    fn pir_turns_on_at_night() {
+        let action = decide(&device(), &trigger(ProlongKind::PIR, true, true));
+        assert_eq!(
+            action,
+            Action::TurnOn {
+                duration: Some(secs(DEFAULT_PIR_HOLD_SECS))
+            }
+        );
+        assert!(action.changes_output());
+    }
+
+    #[test]
+    fn pir_is_ignored_during_the_day() {
+        let mut t = trigger(ProlongKind::PIR, true, true);
+        t.night = false;
+        assert_eq!(decide(&device(), &t), ignored(IgnoreReason::Daytime));
+
+        let mut d = device();
+        d.pir_all_day = true;
+        assert_eq!(
+            decide(&d, &t),
+            Action::TurnOn {
+                duration: Some(secs(DEFAULT_PIR_HOLD_SECS))
+            }
+        );
+    }
+
+    #[test]
+    fn pir_inactive_or_excluded_is_ignored() {
+        let t = trigger(ProlongKind::PIR, false, true);
+        assert_eq!(decide(&device(), &t), ignored(IgnoreReason::SensorInactive));
+
+        let mut d = device();
+        d.pir_exclude = true;
+        let t = trigger(ProlongKind::PIR, true, true);
+        assert_eq!(decide(&d, &t), ignored(IgnoreReason::PirExcluded));
+        assert!(!decide(&d, &t).changes_output());
+    }
+
+    #[test]
+    fn pir_prolongs_a_device_which_is_on() {
+        let mut d = device();
+        d.toggled_elapsed = Some(secs(100.0));
+        let action = decide(&d, &trigger(ProlongKind::PIR, true, false));
+        assert_eq!(
+            action,
+            Action::Prolong {
+                duration: secs(DEFAULT_PIR_HOLD_SECS),
+                stop_after: Some(secs(100.0 + DEFAULT_PIR_HOLD_SECS)),
+            }
+        );
+        assert!(!action.changes_output());
+    }
+
+    #[test]
+    fn pir_in_override_mode_extends_only_near_the_end() {
+        let mut d = device();
+        d.override_mode = true;
+        d.pir_exclude = true;
+        d.toggled_elapsed = Some(secs(60.0));
+        //the day and the exclusion don't matter in the override mode
+        let mut t = trigger(ProlongKind::PIR, true, false);
+        t.night = false;
+        assert_eq!(
+            decide(&d, &t),
+            Action::Prolong {
+                duration: secs(DEFAULT_PIR_PROLONG_SECS),
+                stop_after: None,
+            }
+        );
+
+        let elapsed = DEFAULT_SWITCH_HOLD_SECS - DEFAULT_PIR_PROLONG_SECS + 10.0;
+        d.toggled_elapsed = Some(secs(elapsed));
+        assert_eq!(
+            decide(&d, &t),
+            Action::Prolong {
+                duration: secs(DEFAULT_PIR_PROLONG_SECS),
+                stop_after: Some(secs(elapsed) + secs(DEFAULT_PIR_PROLONG_SECS)),
+            }
+        );
+    }
+
+    #[test]
+    fn pir_does_not_turn_on_a_device_switched_off_by_hand() {
+        let mut d = device();
+        d.override_mode = true;
+        d.toggled_elapsed = Some(secs(60.0));
+        let action = decide(&d, &trigger(ProlongKind::PIR, true, true));
+        assert!(matches!(action, Action::Prolong { .. }));
+        assert!(!action.changes_output());
+    }
+
+    #[test]
+    fn switch_toggles_with_the_switch_hold_time() {
+        let on = decide(&device(), &trigger(ProlongKind::Switch, true, true));
+        assert_eq!(
+            on,
+            Action::Toggle {
+                duration: secs(DEFAULT_SWITCH_HOLD_SECS)
+            }
+        );
+        //during the day too, turning off as well
+        let mut t = trigger(ProlongKind::Switch, true, false);
+        t.night = false;
+        let mut d = device();
+        d.override_mode = true;
+        d.toggled_elapsed = Some(secs(10.0));
+        assert_eq!(decide(&d, &t), on);
+    }
+
+    #[test]
+    fn explicit_duration_is_used() {
+        let mut t = trigger(ProlongKind::Switch, true, true);
+        t.duration = Some(secs(30.0));
+        assert_eq!(
+            decide(&device(), &t),
+            Action::Toggle {
+                duration: secs(30.0)
+            }
+        );
+        let mut t = trigger(ProlongKind::Remote, true, true);
+        t.duration = Some(secs(5.0));
+        assert_eq!(
+            decide(&device(), &t),
+            Action::TurnOn {
+                duration: Some(secs(5.0))
+            }
+        );
+    }
+
+    #[test]
+    fn flip_flop_protection() {
+        let mut d = device();
+        d.toggled_elapsed = Some(secs(MIN_TOGGLE_DELAY_SECS / 2.0));
+        for t in &[
+            trigger(ProlongKind::Switch, true, true),
+            trigger(ProlongKind::Remote, false, false),
+            trigger(ProlongKind::PIR, true, true),
+            trigger(ProlongKind::DayNight, true, true),
+        ] {
+            assert_eq!(decide(&d, t), ignored(IgnoreReason::FlipFlop), "{:?}", t);
+        }
+        //prolonging is not a state change
+        assert!(matches!(
+            decide(&d, &trigger(ProlongKind::PIR, true, false)),
+            Action::Prolong { .. }
+        ));
+        d.toggled_elapsed = Some(secs(MIN_TOGGLE_DELAY_SECS * 2.0));
+        assert!(decide(&d, &trigger(ProlongKind::Switch, true, true)).changes_output());
+    }
+
+    #[test]
+    fn remote_turn_on_and_off() {
+        assert_eq!(
+            decide(&device(), &trigger(ProlongKind::Remote, true, true)),
+            Action::TurnOn {
+                duration: Some(secs(DEFAULT_PIR_HOLD_SECS))
+            }
+        );
+        assert_eq!(
+            decide(&device(), &trigger(ProlongKind::Remote, false, false)),
+            Action::TurnOff
+        );
+        assert_eq!(
+            decide(&device(), &trigger(ProlongKind::Remote, false, true)),
+            ignored(IgnoreReason::AlreadyOff)
+        );
+        //remote requests don't depend on the time of day
+        let mut t = trigger(ProlongKind::Remote, true, true);
+        t.night = false;
+        assert!(decide(&device(), &t).changes_output());
+    }
+
+    #[test]
+    fn remote_uses_a_custom_switch_hold_time() {
+        let mut d = device();
+        d.switch_hold_secs = 1800.0;
+        assert_eq!(
+            decide(&d, &trigger(ProlongKind::Remote, true, true)),
+            Action::TurnOn {
+                duration: Some(secs(1800.0))
+            }
+        );
+        //prolonging still uses the PIR hold time
+        d.toggled_elapsed = Some(secs(100.0));
+        assert_eq!(
+            decide(&d, &trigger(ProlongKind::Remote, true, false)),
+            Action::Prolong {
+                duration: secs(DEFAULT_PIR_HOLD_SECS),
+                stop_after: Some(secs(100.0 + DEFAULT_PIR_HOLD_SECS)),
+            }
+        );
+    }
+
+    #[test]
+    fn remote_turn_on_prolongs_in_override_mode() {
+        let mut d = device();
+        d.override_mode = true;
+        d.toggled_elapsed = Some(secs(10.0));
+        assert!(matches!(
+            decide(&d, &trigger(ProlongKind::Remote, true, true)),
+            Action::Prolong {
+                stop_after: None,
+                ..
+            }
+        ));
+        //turning off ends the override mode
+        assert_eq!(
+            decide(&d, &trigger(ProlongKind::Remote, false, false)),
+            Action::TurnOff
+        );
+    }
+
+    #[test]
+    fn auto_off() {
+        assert_eq!(
+            decide(&device(), &trigger(ProlongKind::AutoOff, false, false)),
+            Action::TurnOff
+        );
+        assert_eq!(
+            decide(&device(), &trigger(ProlongKind::AutoOff, false, true)),
+            ignored(IgnoreReason::AlreadyOff)
+        );
+    }
+
+    #[test]
+    fn auto_off_in_override_mode() {
+        let mut d = device();
+        d.override_mode = true;
+        d.toggled_elapsed = Some(secs(DEFAULT_SWITCH_HOLD_SECS));
+        //turned on by hand: turned off at the end of the hold time
+        assert_eq!(
+            decide(&d, &trigger(ProlongKind::AutoOff, false, false)),
+            Action::TurnOff
+        );
+        //turned off by hand: the automation takes over again
+        let action = decide(&d, &trigger(ProlongKind::AutoOff, false, true));
+        assert_eq!(action, Action::EndOverride);
+        assert!(!action.changes_output());
+    }
+
+    #[test]
+    fn day_night() {
+        let mut t = trigger(ProlongKind::DayNight, true, true);
+        assert_eq!(decide(&device(), &t), Action::TurnOn { duration: None });
+        t.on = false;
+        t.currently_off = false;
+        assert_eq!(decide(&device(), &t), Action::TurnOff);
+
+        //the day/night switching overrides the override mode
+        let mut d = device();
+        d.override_mode = true;
+        d.toggled_elapsed = Some(secs(10.0));
+        t.on = true;
+        assert_eq!(decide(&d, &t), Action::TurnOn { duration: None });
+    }
+
+    #[test]
+    fn override_mode_uses_the_pir_prolong_minimum() {
+        let mut d = device();
+        d.override_mode = true;
+        d.pir_hold_secs = 30.0;
+        d.toggled_elapsed = Some(secs(10.0));
+        assert!(matches!(
+            decide(&d, &trigger(ProlongKind::PIR, true, false)),
+            Action::Prolong { duration, .. } if duration == secs(DEFAULT_PIR_PROLONG_SECS)
+        ));
+        d.pir_hold_secs = DEFAULT_PIR_PROLONG_SECS * 2.0;
+        assert!(matches!(
+            decide(&d, &trigger(ProlongKind::PIR, true, false)),
+            Action::Prolong { duration, .. } if duration == secs(DEFAULT_PIR_PROLONG_SECS * 2.0)
+        ));
+    }
+}
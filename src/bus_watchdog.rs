@@ -0,0 +1,249 @@
+use crate::config;
+use crate::notify::{Notification, Severity};
+use crate::onewire::{get_w1_device_name, RelayBoard, SensorBoard, StateMachine};
+use crate::queue::Sender;
+use humantime::format_duration;
+use simplelog::*;
+use std::collections::HashSet;
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const BUS_WATCHDOG_MAX_READ_ERRORS: u32 = 10; //consecutive failed reads of a board starting the recovery before it degrades
+pub const BUS_WATCHDOG_RECOVERY_INTERVAL_SECS: f32 = 60.0; //secs between the recovery steps
+pub const BUS_WATCHDOG_MIN_RECOVERY_INTERVAL_SECS: f32 = 10.0;
+pub const BUS_WATCHDOG_NOTIFY_SECS: f32 = 300.0; //unreachable board for that long is notified
+pub const BUS_WATCHDOG_POWER_OFF_SECS: f32 = 5.0;
+pub const BUS_WATCHDOG_UNBIND_DELAY_SECS: u64 = 2; //secs between unbinding and binding the bus master driver
+
+/// Escalating recovery steps, the bus-wide ones are skipped when not configured
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Recovery {
+    Reopen,
+    MasterReset,
+    PowerCycle,
+}
+
+pub struct BusWatchdog {
+    pub name: String,
+    pub max_read_errors: u32,
+    pub recovery_interval: Duration,
+    pub notify_after: Duration,
+    /// sysfs driver directory of the bus master, eg. `/sys/bus/i2c/drivers/ds2482`
+    pub master_driver: Option<String>,
+    /// bus master device bound to the driver, eg. `1-0018`
+    pub master_device: Option<String>,
+    /// relay cutting the bus power when turned on (normally closed contact)
+    pub power_relay: Option<i32>,
+    pub power_off: Duration,
+    /// `watchdog_script` reporting the degraded boards instead of the notifications
+    pub script: Option<String>,
+    pub notify_transmitter: Sender<Notification>,
+    attempts: u32,
+    last_recovery: Option<Instant>,
+    power_cut: Option<Instant>,
+    notified: HashSet<String>,
+}
+
+impl BusWatchdog {
+    pub fn new(
+        config: &config::BusWatchdog,
+        script: Option<String>,
+        notify_transmitter: Sender<Notification>,
+    ) -> Self {
+        BusWatchdog {
+            name: "bus_watchdog".to_string(),
+            max_read_errors: config.max_read_errors,
+            recovery_interval: config.recovery_interval,
+            notify_after: config.notify_after,
+            master_driver: config.master_driver.clone(),
+            master_device: config.master_device.clone(),
+            power_relay: config.power_relay,
+            power_off: config.power_off,
+            script,
+            notify_transmitter,
+            attempts: 0,
+            last_recovery: None,
+            power_cut: None,
+            notified: HashSet::new(),
+        }
+    }
+
+    fn steps(&self) -> Vec<Recovery> {
+        let mut steps = vec![Recovery::Reopen];
+        if self.master_driver.is_some() && self.master_device.is_some() {
+            steps.push(Recovery::MasterReset);
+        }
+        if self.power_relay.is_some() {
+            steps.push(Recovery::PowerCycle);
+        }
+        steps
+    }
+
+    /// Rebinds the bus master driver, in a thread as the kernel can take a while
+    fn master_reset(&self) {
+        let (driver, device) = match (&self.master_driver, &self.master_device) {
+            (Some(driver), Some(device)) => (driver.clone(), device.clone()),
+            _ => return,
+        };
+        let name = self.name.clone();
+        thread::spawn(move || {
+            if let Err(e) = fs::write(format!("{}/unbind", driver), &device) {
+                error!("{}: unbinding {} failed: {}", name, device, e);
+            }
+            thread::sleep(Duration::from_secs(BUS_WATCHDOG_UNBIND_DELAY_SECS));
+            match fs::write(format!("{}/bind", driver), &device) {
+                Ok(_) => info!("{}: bus master {} rebound", name, device),
+                Err(e) => error!("{}: binding {} failed: {}", name, device, e),
+            }
+        });
+    }
+
+    /// Reports a board state change once: with the watchdog script when configured,
+    /// otherwise with a notification
+    fn report(&self, board: &str, degraded: bool, message: String) {
+        if let Some(ref cmd) = self.script {
+            let cmd = str::replace(cmd, "%name%", board);
+            let cmd = str::replace(&cmd, "%state%", if degraded { "degraded" } else { "ok" });
+            thread::spawn(move || StateMachine::run_shell_command(cmd));
+            return;
+        }
+        let (severity, title) = if degraded {
+            (Severity::Critical, format!("{} unreachable", board))
+        } else {
+            (Severity::Info, format!("{} reachable", board))
+        };
+        let _ = self.notify_transmitter.send(Notification::new(
+            self.name.as_str(),
+            severity,
+            title,
+            message,
+        ));
+    }
+
+    /// Degraded board reports, once per outage: a failing board after `notify_after`
+    /// without a valid read, a stale one right away
+    fn check_degraded(&mut self, boards: &[SensorBoard]) {
+        for sb in boards {
+            let board = get_w1_device_name(sb.ow_family, sb.ow_address);
+            let unreachable = sb.degraded
+                && (sb.read_failures == 0 || sb.last_success.elapsed() >= self.notify_after);
+            if unreachable && !self.notified.contains(&board) {
+                let message = if sb.read_failures > 0 {
+                    format!(
+                        "no valid read for {}, failed reads: {}",
+                        format_duration(Duration::from_secs(sb.last_success.elapsed().as_secs())),
+                        sb.read_failures
+                    )
+                } else {
+                    format!(
+                        "no state change for {}",
+                        format_duration(Duration::from_secs(sb.last_change.elapsed().as_secs()))
+                    )
+                };
+                error!("{}: {}: {}", self.name, board, message);
+                self.report(&board, true, message);
+                self.notified.insert(board);
+            } else if !sb.degraded && self.notified.remove(&board) {
+                info!("{}: {}: reachable again", self.name, board);
+                self.report(&board, false, "the board is read again".to_string());
+            }
+        }
+    }
+
+    /// Switches the bus power relay directly, so neither the command governor
+    /// nor the flip-flop guard can drop the power cycle
+    fn switch_power(&mut self, relay_boards: &mut [RelayBoard]) {
+        let (id, started) = match (self.power_relay, self.power_cut) {
+            (Some(id), Some(started)) => (id, started),
+            _ => return,
+        };
+        let cut = started.elapsed() < self.power_off;
+        if !cut {
+            self.power_cut = None;
+            info!("{}: 🔌 restoring the bus power", self.name);
+        }
+        if !relay_boards.iter_mut().any(|rb| rb.switch_relay(id, cut)) {
+            error!("{}: power relay {} not found", self.name, id);
+            self.power_cut = None;
+        }
+    }
+
+    /// Runs the next recovery step while any board is degraded or has too many
+    /// consecutive read errors, the degraded boards are reported
+    pub fn check(&mut self, boards: &mut [SensorBoard], relay_boards: &mut [RelayBoard]) {
+        self.check_degraded(boards);
+        self.switch_power(relay_boards);
+
+        //gpio inputs are not on the bus, a degraded one is just reopened
+        for sb in boards
+            .iter_mut()
+            .filter(|sb| sb.gpio.is_some() && sb.degraded)
+        {
+            if sb
+                .last_reopen
+                .map_or(true, |t| t.elapsed() >= self.recovery_interval)
+            {
+                sb.close();
+            }
+        }
+
+        let failing: Vec<String> = boards
+            .iter()
+            .filter(|sb| {
+                sb.gpio.is_none() && (sb.degraded || sb.read_failures >= self.max_read_errors)
+            })
+            .map(|sb| get_w1_device_name(sb.ow_family, sb.ow_address))
+            .collect();
+        if failing.is_empty() {
+            if self.attempts > 0 {
+                info!(
+                    "{}: 🩺 bus recovered after {} recovery step(s)",
+                    self.name, self.attempts
+                );
+                self.attempts = 0;
+                self.last_recovery = None;
+            }
+            return;
+        }
+        if self
+            .last_recovery
+            .map_or(false, |t| t.elapsed() < self.recovery_interval)
+        {
+            return;
+        }
+        self.last_recovery = Some(Instant::now());
+
+        let steps = self.steps();
+        let step = steps[self.attempts as usize % steps.len()];
+        self.attempts += 1;
+        warn!(
+            "{}: 🩺 {} failing board(s): {:?}, recovery #{}: {:?}",
+            self.name,
+            failing.len(),
+            failing,
+            self.attempts,
+            step
+        );
+        match step {
+            Recovery::Reopen => (),
+            Recovery::MasterReset => self.master_reset(),
+            Recovery::PowerCycle => {
+                info!(
+                    "{}: 🔌 cutting the bus power for {:?}",
+                    self.name, self.power_off
+                );
+                self.power_cut = Some(Instant::now());
+                self.switch_power(relay_boards);
+            }
+        }
+        //files are reopened on the next read: the failing ones or all of them after a bus reset
+        for sb in boards.iter_mut().filter(|sb| sb.gpio.is_none()) {
+            if step != Recovery::Reopen
+                || failing.contains(&get_w1_device_name(sb.ow_family, sb.ow_address))
+            {
+                sb.close();
+            }
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+pub const CESSPOOL_EMPTIED_DROP: u8 = 30; //level drop in percent points to detect emptying
+pub const CESSPOOL_HISTORY_DAYS: i64 = 90; //how long the level history is kept
+pub const CESSPOOL_FILL_RATE_DAYS: i64 = 14; //the fill rate is computed from the recent days only
+
+#[derive(Clone, Serialize)]
+pub struct CesspoolLevelEntry {
+    pub time: DateTime<Utc>,
+    pub level: u8,
+}
+
+#[derive(Clone, Serialize)]
+pub struct CesspoolEmptying {
+    pub time: DateTime<Utc>,
+    pub level_before: u8,
+    pub level_after: u8,
+}
+
+#[derive(Default, Serialize)]
+pub struct CesspoolHistory {
+    pub levels: Vec<CesspoolLevelEntry>,
+    pub emptied: Vec<CesspoolEmptying>,
+}
+
+impl CesspoolHistory {
+    pub fn current_level(&self) -> Option<u8> {
+        self.levels.last().map(|e| e.level)
+    }
+
+    /// Adds a new level and returns the emptying when it was detected
+    pub fn add_level(&mut self, time: DateTime<Utc>, level: u8) -> Option<CesspoolEmptying> {
+        let emptying = match self.current_level() {
+            Some(previous) if previous >= level + CESSPOOL_EMPTIED_DROP => {
+                let emptying = CesspoolEmptying {
+                    time,
+                    level_before: previous,
+                    level_after: level,
+                };
+                self.emptied.push(emptying.clone());
+                Some(emptying)
+            }
+            _ => None,
+        };
+        self.levels.push(CesspoolLevelEntry { time, level });
+
+        let oldest = time - Duration::days(CESSPOOL_HISTORY_DAYS);
+        self.levels.retain(|e| e.time > oldest);
+        emptying
+    }
+
+    /// Recent fill rate in percent points per day: since the last emptying,
+    /// but not older than `CESSPOOL_FILL_RATE_DAYS` before the last level change
+    pub fn fill_rate(&self) -> Option<f64> {
+        let last = self.levels.last()?;
+        let since = self.emptied.last().map(|e| e.time);
+        let recent = last.time - Duration::days(CESSPOOL_FILL_RATE_DAYS);
+        let levels: Vec<&CesspoolLevelEntry> = self
+            .levels
+            .iter()
+            .filter(|e| since.map_or(true, |since| e.time >= since))
+            .collect();
+        //the level is logged on change: the newest entry before the window is the start of it
+        let first = levels
+            .iter()
+            .filter(|e| e.time <= recent)
+            .last()
+            .or_else(|| levels.first())?;
+        if last.level <= first.level || last.time <= first.time {
+            return None;
+        }
+        let days = (last.time - first.time).num_seconds() as f64 / 86400.0;
+        Some((last.level - first.level) as f64 / days)
+    }
+
+    /// Predicts when the cesspool will be full using the recent fill rate
+    pub fn predict_full(&self) -> Option<DateTime<Utc>> {
+        let last = self.levels.last()?;
+        let rate = self.fill_rate()?;
+        let remaining = 100u8.saturating_sub(last.level) as f64;
+        Some(last.time + Duration::seconds((remaining / rate * 86400.0) as i64))
+    }
+
+    /// Days left until the cesspool is full, counted from `now`
+    pub fn days_until_full(&self, now: DateTime<Utc>) -> Option<f64> {
+        let full = self.predict_full()?;
+        Some(((full - now).num_seconds() as f64 / 86400.0).max(0.0))
+    }
+}
@@ -0,0 +1,81 @@
+use simplelog::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Failures which can be injected in the developer chaos mode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// influxdb write fails as if the database was unreachable
+    InfluxWrite,
+    /// modbus register read times out
+    ModbusTimeout,
+    /// 1-wire sysfs read fails
+    W1Read,
+    /// message stays in the channel for another consumer loop
+    ChannelDelay,
+}
+
+/// Injection rates (0.0 - 1.0) for all faults, everything disabled by default
+#[derive(Clone)]
+pub struct ChaosRates {
+    pub influx_write: f32,
+    pub modbus_timeout: f32,
+    pub w1_read: f32,
+    pub channel_delay: f32,
+}
+
+static RATES: RwLock<Option<ChaosRates>> = RwLock::new(None);
+static RNG_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Enables the fault injection for the whole process, called once on startup.
+/// Only available when built with the `chaos` feature.
+pub fn init(rates: ChaosRates) {
+    if !cfg!(feature = "chaos") {
+        warn!("chaos: 🐒 fault injection requested but hard was built without the `chaos` feature, ignoring");
+        return;
+    }
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    //xorshift state must not be zero
+    RNG_STATE.store(seed | 1, Ordering::Relaxed);
+    warn!(
+        "chaos: 🐒 <b><red>FAULT INJECTION ENABLED</> - influx write: {}, modbus timeout: {}, w1 read: {}, channel delay: {}",
+        rates.influx_write, rates.modbus_timeout, rates.w1_read, rates.channel_delay
+    );
+    *RATES.write().unwrap() = Some(rates);
+}
+
+//xorshift64, good enough for dice rolls without an extra dependency
+fn random() -> f32 {
+    let mut x = RNG_STATE.load(Ordering::Relaxed);
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    RNG_STATE.store(x, Ordering::Relaxed);
+    (x >> 40) as f32 / (1u64 << 24) as f32
+}
+
+/// Returns true when the given fault should be injected now
+pub fn inject(fault: Fault) -> bool {
+    if !cfg!(feature = "chaos") {
+        return false;
+    }
+    let rate = match &*RATES.read().unwrap() {
+        Some(rates) => match fault {
+            Fault::InfluxWrite => rates.influx_write,
+            Fault::ModbusTimeout => rates.modbus_timeout,
+            Fault::W1Read => rates.w1_read,
+            Fault::ChannelDelay => rates.channel_delay,
+        },
+        None => return false,
+    };
+    if rate > 0.0 && random() < rate {
+        debug!("chaos: 🐒 injecting {:?}", fault);
+        true
+    } else {
+        false
+    }
+}
@@ -0,0 +1,323 @@
+use crate::database::{CommandCode, DbTask};
+use crate::onewire::{OneWireTask, TaskCommand};
+use crate::queue::Sender;
+use chrono::{DateTime, Local, NaiveDate, Timelike};
+use humantime::format_duration;
+use serde::{Deserialize, Serialize};
+use simplelog::*;
+use std::fs;
+use std::time::{Duration, Instant, SystemTime};
+
+pub const DEFAULT_CIRCULATION_RUN_SECS: u64 = 180; //3min of pump run per demand
+pub const DEFAULT_CIRCULATION_COOLDOWN_SECS: u64 = 900; //15min between runs
+pub const DEFAULT_CIRCULATION_DOUBLE_TAP_SECS: u64 = 10; //max delay between the two night triggers
+const USAGE_SLOT_MINUTES: u32 = 15; //resolution of learned daily usage pattern
+const USAGE_SLOTS: usize = (24 * 60 / USAGE_SLOT_MINUTES) as usize;
+const USAGE_DAILY_DECAY: f32 = 0.9; //older days have less impact on the pattern
+const USAGE_PRERUN_THRESHOLD: f32 = 3.0; //minimum learned weight (decayed count of days with a demand) of a slot to pre-run the pump
+
+pub static CIRCULATION_PUMP_TAG: &str = "circulation_pump";
+pub static CIRCULATION_DEMAND_TAG: &str = "circulation_demand";
+pub static DHW_RECIRC_TAG: &str = "dhw_recirc"; //same as circulation_demand
+
+/// Why the pump was started, stored with every run
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CirculationTrigger {
+    Demand,
+    NightDoubleTap,
+    Learned,
+}
+
+impl CirculationTrigger {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CirculationTrigger::Demand => "demand",
+            CirculationTrigger::NightDoubleTap => "night_double_tap",
+            CirculationTrigger::Learned => "learned",
+        }
+    }
+}
+
+/// Learned daily usage: the slot weights count the days with a demand in the slot,
+/// a day is added to the weights when it's over
+#[derive(Serialize, Deserialize)]
+struct UsagePattern {
+    weights: Vec<f32>,
+    day: Option<NaiveDate>,
+    today: Vec<bool>,
+}
+
+impl Default for UsagePattern {
+    fn default() -> Self {
+        UsagePattern {
+            weights: vec![0.0; USAGE_SLOTS],
+            day: None,
+            today: vec![false; USAGE_SLOTS],
+        }
+    }
+}
+
+impl UsagePattern {
+    fn load(path: &str) -> Self {
+        match fs::read_to_string(path) {
+            Ok(data) => match serde_json::from_str::<UsagePattern>(&data) {
+                Ok(usage)
+                    if usage.weights.len() == USAGE_SLOTS && usage.today.len() == USAGE_SLOTS =>
+                {
+                    usage
+                }
+                Ok(_) => {
+                    error!("circulation: invalid usage slots in {}", path);
+                    UsagePattern::default()
+                }
+                Err(e) => {
+                    error!("circulation: invalid file {}: {}", path, e);
+                    UsagePattern::default()
+                }
+            },
+            Err(e) => {
+                warn!("circulation: unable to read {}: {}", path, e);
+                UsagePattern::default()
+            }
+        }
+    }
+
+    fn save(&self, path: &str) {
+        match serde_json::to_string(self) {
+            Ok(data) => {
+                if let Err(e) = fs::write(path, data) {
+                    error!("circulation: unable to write {}: {}", path, e);
+                }
+            }
+            Err(e) => error!("circulation: serialization error: {}", e),
+        }
+    }
+
+    /// Adds the finished day(s) to the weights, returns true on a day change
+    fn roll_day(&mut self, today: NaiveDate) -> bool {
+        let day = match self.day {
+            Some(day) if day == today => return false,
+            Some(day) => day,
+            None => {
+                self.day = Some(today);
+                return true;
+            }
+        };
+        //decay the pattern so it follows the habit changes, also for the days without any demand
+        let days = (today - day).num_days().max(1) as i32;
+        for (weight, used) in self.weights.iter_mut().zip(self.today.iter_mut()) {
+            *weight = (*weight * USAGE_DAILY_DECAY + if *used { 1.0 } else { 0.0 })
+                * USAGE_DAILY_DECAY.powi(days - 1);
+            *used = false;
+        }
+        self.day = Some(today);
+        true
+    }
+
+    /// Marks a demand in the slot of today, returns true when it's the first one
+    fn mark(&mut self, today: NaiveDate, slot: usize) -> bool {
+        let changed = self.roll_day(today);
+        !std::mem::replace(&mut self.today[slot], true) || changed
+    }
+}
+
+pub struct CirculationPump {
+    pub name: String,
+    pub run_time: Duration,
+    pub cooldown: Duration,
+    pub learning: bool,
+    /// at night the pump runs only after a second trigger within `double_tap`
+    pub night_suppress: bool,
+    pub double_tap: Duration,
+    pub db_transmitter: Sender<DbTask>,
+    /// the learned usage pattern is kept in this file across restarts
+    pub usage_file: Option<String>,
+    last_run: Option<Instant>,
+    night_tap: Option<Instant>,
+    usage: UsagePattern,
+    last_prerun: Option<(NaiveDate, usize)>,
+}
+
+impl CirculationPump {
+    pub fn new(
+        run_secs: Option<u64>,
+        cooldown_secs: Option<u64>,
+        learning: bool,
+        night_suppress: bool,
+        double_tap_secs: Option<u64>,
+        usage_file: Option<String>,
+        db_transmitter: Sender<DbTask>,
+    ) -> Self {
+        CirculationPump {
+            name: "circulation".to_string(),
+            run_time: Duration::from_secs(run_secs.unwrap_or(DEFAULT_CIRCULATION_RUN_SECS)),
+            cooldown: Duration::from_secs(
+                cooldown_secs.unwrap_or(DEFAULT_CIRCULATION_COOLDOWN_SECS),
+            ),
+            learning,
+            night_suppress,
+            double_tap: Duration::from_secs(
+                double_tap_secs.unwrap_or(DEFAULT_CIRCULATION_DOUBLE_TAP_SECS),
+            ),
+            db_transmitter,
+            usage: match &usage_file {
+                Some(path) if learning => UsagePattern::load(path),
+                _ => UsagePattern::default(),
+            },
+            usage_file,
+            last_run: None,
+            night_tap: None,
+            last_prerun: None,
+        }
+    }
+
+    fn get_slot(now: &DateTime<Local>) -> usize {
+        ((now.hour() * 60 + now.minute()) / USAGE_SLOT_MINUTES) as usize
+    }
+
+    fn save_usage(&self) {
+        if let Some(path) = &self.usage_file {
+            self.usage.save(path);
+        }
+    }
+
+    /// Tags of a sensor signalling the hot water demand
+    pub fn is_demand_tag(tag: &str) -> bool {
+        tag == CIRCULATION_DEMAND_TAG || tag == DHW_RECIRC_TAG
+    }
+
+    fn start(
+        &mut self,
+        pending_tasks: &mut Vec<OneWireTask>,
+        trigger: CirculationTrigger,
+        reason: &str,
+    ) -> bool {
+        //the cooldown starts when the previous run is finished
+        if let Some(last_run) = self.last_run {
+            if last_run.elapsed() < self.run_time + self.cooldown {
+                debug!(
+                    "{}: {} ignored, cooldown in progress ({} since last run)",
+                    self.name,
+                    reason,
+                    format_duration(Duration::from_secs(last_run.elapsed().as_secs())),
+                );
+                return false;
+            }
+        }
+
+        info!(
+            "{}: 🔄 starting circulation pump ({}) for {}",
+            self.name,
+            reason,
+            format_duration(self.run_time)
+        );
+        self.last_run = Some(Instant::now());
+        let _ = self.db_transmitter.send(DbTask {
+            command: CommandCode::CirculationRun {
+                trigger,
+                time: SystemTime::now(),
+            },
+            value: Some(self.run_time.as_secs() as i32),
+        });
+        pending_tasks.push(OneWireTask {
+            command: TaskCommand::TurnOnProlong,
+            id_relay: None,
+            tag_group: Some(CIRCULATION_PUMP_TAG.to_owned()),
+            id_yeelight: None,
+            id_plug: None,
+            duration: Some(self.run_time),
+        });
+        true
+    }
+
+    /// Hot water demand signalled by a sensor (bathroom PIR, wall switch)
+    pub fn demand(&mut self, pending_tasks: &mut Vec<OneWireTask>, sensor_name: &str, night: bool) {
+        if self.learning {
+            let now = Local::now();
+            if self
+                .usage
+                .mark(now.date_naive(), CirculationPump::get_slot(&now))
+            {
+                self.save_usage();
+            }
+        }
+        if night && self.night_suppress {
+            match self.night_tap.take() {
+                Some(tap) if tap.elapsed() <= self.double_tap => {
+                    self.start(
+                        pending_tasks,
+                        CirculationTrigger::NightDoubleTap,
+                        &format!("night double tap from {}", sensor_name),
+                    );
+                }
+                _ => {
+                    debug!(
+                        "{}: night demand from {} suppressed, waiting for a second trigger",
+                        self.name, sensor_name
+                    );
+                    self.night_tap = Some(Instant::now());
+                }
+            }
+            return;
+        }
+        self.start(
+            pending_tasks,
+            CirculationTrigger::Demand,
+            &format!("demand from {}", sensor_name),
+        );
+    }
+
+    /// Periodic check for pre-running the pump based on the learned usage pattern
+    pub fn check(&mut self, pending_tasks: &mut Vec<OneWireTask>) {
+        if !self.learning {
+            return;
+        }
+        let now = Local::now();
+        let today = now.date_naive();
+
+        if self.usage.roll_day(today) {
+            self.save_usage();
+        }
+
+        //pre-run the pump when the next slot is a typical usage time
+        let slot = CirculationPump::get_slot(&now);
+        let next_slot = (slot + 1) % USAGE_SLOTS;
+        if self.usage.weights[next_slot] >= USAGE_PRERUN_THRESHOLD
+            && self.last_prerun != Some((today, slot))
+        {
+            self.last_prerun = Some((today, slot));
+            self.start(
+                pending_tasks,
+                CirculationTrigger::Learned,
+                "learned usage pattern",
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_counts_days_not_demands() {
+        let day = NaiveDate::from_ymd_opt(2021, 1, 4).unwrap();
+        let mut usage = UsagePattern::default();
+        assert!(usage.mark(day, 28));
+        //the following demands in the same slot and day are not counted
+        assert!(!usage.mark(day, 28));
+        assert!(!usage.mark(day, 28));
+        assert_eq!(usage.weights[28], 0.0);
+
+        assert!(usage.roll_day(day.succ_opt().unwrap()));
+        assert_eq!(usage.weights[28], 1.0);
+        assert!(!usage.today[28]);
+
+        //two days later without any demand: decayed twice
+        usage.mark(day.succ_opt().unwrap(), 28);
+        usage.roll_day(NaiveDate::from_ymd_opt(2021, 1, 7).unwrap());
+        let expected = (USAGE_DAILY_DECAY + 1.0) * USAGE_DAILY_DECAY;
+        assert!((usage.weights[28] - expected).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,1743 @@
+use crate::appliance::MeterKind;
+use crate::bus_watchdog::{
+    BUS_WATCHDOG_MAX_READ_ERRORS, BUS_WATCHDOG_MIN_RECOVERY_INTERVAL_SECS,
+    BUS_WATCHDOG_NOTIFY_SECS, BUS_WATCHDOG_POWER_OFF_SECS, BUS_WATCHDOG_RECOVERY_INTERVAL_SECS,
+};
+use crate::chaos::ChaosRates;
+use crate::device_config::DeviceSource;
+use crate::device_io::baud_speed;
+use crate::dsmr::{
+    DSMR_MIN_REPORT_INTERVAL_SECS, DSMR_REPORT_INTERVAL_SECS, DSMR_STATS_DUMP_INTERVAL_SECS,
+};
+use crate::energy_manager::{
+    ENERGY_LOAD_DEFAULT_PRIORITY, ENERGY_MANAGER_CHECK_INTERVAL_SECS,
+    ENERGY_MANAGER_DEFAULT_HYSTERESIS, ENERGY_MANAGER_MIN_CHECK_INTERVAL_SECS,
+};
+use crate::ethlcd::{Backlight, ETHLCD_DEFAULT_PAGE_SECS};
+use crate::gate::{
+    GATE_DEFAULT_NIGHT_OPEN_SECS, GATE_DEFAULT_PULSE_SECS, GATE_DEFAULT_TIMEOUT_SECS,
+};
+use crate::gpio::GpioBoard;
+use crate::heating::{
+    parse_time, HEATING_CHECK_INTERVAL_SECS, HEATING_DEFAULT_DAY_TEMP, HEATING_DEFAULT_HYSTERESIS,
+    HEATING_DEFAULT_NIGHT_TEMP, HEATING_MIN_CHECK_INTERVAL_SECS, HEATING_WINDOW_OPEN_SECS,
+};
+use crate::heating_season::HeatingSeason as HeatingSeasonState;
+use crate::jsonlog::LogFormat;
+use crate::logbuffer::LOG_BUFFER_LINES_PER_LEVEL;
+use crate::meter::{METER_MIN_POLL_INTERVAL_SECS, METER_POLL_INTERVAL_SECS};
+use crate::modbus::{
+    Register, Transport, MODBUS_DEFAULT_BAUD_RATE, MODBUS_DEFAULT_INFLUX_DATABASE,
+    MODBUS_MIN_POLL_INTERVAL_SECS, MODBUS_POLL_INTERVAL_SECS,
+};
+use crate::notify::{
+    Backend, NotifyTarget, Severity, NOTIFY_DEFAULT_MAX_PER_HOUR, NOTIFY_DEFAULT_RATE_LIMIT_SECS,
+    NOTIFY_SMTP_DEFAULT_PORT,
+};
+use crate::onewire::{
+    TaskCommand, ONEWIRE_LOOP_INTERVAL_MS, ONEWIRE_MIN_LOOP_INTERVAL_MS,
+    SENSOR_MIN_READ_TIMEOUT_MS, SENSOR_READ_TIMEOUT_MS,
+};
+use crate::opentherm::{
+    OPENTHERM_MIN_REPORT_INTERVAL_SECS, OPENTHERM_REPORT_INTERVAL_SECS,
+    OPENTHERM_STATS_DUMP_INTERVAL_SECS,
+};
+use crate::remeha::{
+    RemehaSetpoint, REMEHA_COUNTERS_POLL_INTERVAL_SECS, REMEHA_DEFAULT_INFLUX_DATABASE,
+    REMEHA_MIN_POLL_INTERVAL_SECS, REMEHA_POLL_INTERVAL_SECS, REMEHA_STATS_DUMP_INTERVAL_SECS,
+};
+use crate::rfid::{
+    parse_usb_id, WiegandIdFormat, DEFAULT_PIN_LOCKOUT_SECS, DEFAULT_PIN_MAX_ATTEMPTS,
+    DEFAULT_PIN_TIMEOUT_SECS,
+};
+use crate::scene::Scene;
+use crate::schedule::{CronExpr, CronJob};
+use crate::skymax::{
+    SkymaxSetting, SKYMAX_MIN_POLL_INTERVAL_SECS, SKYMAX_POLL_INTERVAL_SECS,
+    SKYMAX_STATS_DUMP_INTERVAL_SECS,
+};
+use crate::storage::{StorageBackend, DEFAULT_SQLITE_PATH};
+use crate::sun2000::{
+    SUN2000_MIN_POLL_INTERVAL_SECS, SUN2000_POLL_INTERVAL_SECS, SUN2000_RULE_DEFAULT_HOLD_SECS,
+    SUN2000_STATS_DUMP_INTERVAL_SECS,
+};
+use crate::systemd::{DEFAULT_HEARTBEAT_TIMEOUT_SECS, MIN_HEARTBEAT_TIMEOUT_SECS};
+use crate::units::{EnergyUnit, TemperatureUnit, Timezone};
+use crate::virtual_sensor::Expr;
+use chrono::NaiveTime;
+use ini::ini::Properties;
+use ini::Ini;
+use simplelog::LevelFilter;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const CONFIG_FILE: &str = "hard.conf";
+pub const DEFAULT_REBOOT_COMMAND: &str = "/sbin/reboot";
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10; //max time for draining the queues on exit
+pub const MIN_STATS_INTERVAL_SECS: f32 = 60.0; //lower bound of the *_stats_interval_secs options
+
+//skymax settings enforced on startup, as skymax_<name> options
+const SKYMAX_SETTINGS: [&str; 5] = [
+    "output_priority",
+    "charger_priority",
+    "battery_recharge_voltage",
+    "battery_redischarge_voltage",
+    "battery_cutoff_voltage",
+];
+
+/// Invalid option found while loading the config file
+#[derive(Debug)]
+pub struct ConfigError {
+    pub section: String,
+    pub key: String,
+    pub value: String,
+    pub reason: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {}={:?}: {}",
+            self.section, self.key, self.value, self.reason
+        )
+    }
+}
+
+/// Options from the `[general]` section
+pub struct General {
+    pub log: Option<String>,
+    pub log_format: LogFormat,
+    pub log_level: LevelFilter,
+    pub lat: f64,
+    pub lon: f64,
+    pub ethlcd_host: Option<String>,
+    pub ethlcd_display: bool,
+    pub ethlcd_rows: usize,
+    pub ethlcd_cols: usize,
+    pub ethlcd_page_interval: Duration,
+    pub ethlcd_night_backlight: Backlight,
+    pub rfid_event_path: Option<String>,
+    pub rfid_usbid: Option<(u16, u16)>,
+    pub rfid_hotplug: bool,
+    pub owserver: Option<String>,
+    pub skymax_device: Option<String>,
+    pub skymax_usbid: String,
+    pub skymax_mode_change_script: Option<String>,
+    pub skymax_fault_script: Option<String>,
+    pub skymax_settings: Vec<SkymaxSetting>,
+    pub skymax_poll_interval: Duration,
+    pub skymax_stats_interval: Duration,
+    pub skymax_status_lcd_line: Option<u8>,
+    pub influxdb_url: Option<String>,
+    pub influx_env_database: Option<String>,
+    pub influx_env_measurement: Option<String>,
+    pub lcdproc: Option<String>,
+    pub remeha_device: Option<String>,
+    pub remeha_state_change_script: Option<String>,
+    pub remeha_recovery_script: Option<String>,
+    pub remeha_alarm_script: Option<String>,
+    pub remeha_lcd_line: Option<u8>,
+    pub remeha_allow_write: bool,
+    pub remeha_poll_interval: Duration,
+    pub remeha_stats_interval: Duration,
+    pub remeha_counters_interval: Duration,
+    pub onewire_loop_interval: Duration,
+    pub sensor_read_timeout: Duration,
+    pub adaptive_hold_file: Option<String>,
+    pub meter_poll_interval: Duration,
+    pub frost_guard_script: Option<String>,
+    pub frost_guard_power: Option<f32>,
+    pub sensor_board_stale: Option<Duration>,
+    pub inverted_sensors: Vec<String>,
+    pub watchdog_script: Option<String>,
+    pub command_min_interval_ms: Option<u64>,
+    pub latency_trace: bool,
+    pub cesspool_notify_level: Option<u8>,
+    pub cesspool_critical_level: Option<u8>,
+    pub cesspool_notify_script: Option<String>,
+    pub api_token: Option<String>,
+    pub allow_reboot: bool,
+    pub reboot_command: String,
+    pub shutdown_timeout: Duration,
+    pub heartbeat_timeout: Duration,
+    pub log_buffer_lines: usize,
+    pub log_buffer_level: LevelFilter,
+    pub temperature_unit: TemperatureUnit,
+    pub energy_unit: EnergyUnit,
+    pub timezone: Option<Timezone>,
+    pub time_format: Option<String>,
+    pub decimal_comma: bool,
+    pub disable_postgres: bool,
+    pub storage: StorageBackend,
+    pub devices: DeviceSource,
+    pub disable_onewire: bool,
+    pub disable_webserver: bool,
+}
+
+/// `[postgres]` connection settings
+pub struct Postgres {
+    pub host: Option<String>,
+    pub dbname: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+/// `[sqlite]` database file, used with `storage=sqlite`
+pub struct Sqlite {
+    pub path: String,
+}
+
+/// `[influxdb]` spool and InfluxDB 2.x settings
+pub struct InfluxDb {
+    pub spool_dir: Option<String>,
+    pub org: Option<String>,
+    pub token: Option<String>,
+    pub bucket: Option<String>,
+    pub buckets: HashMap<String, String>,
+}
+
+pub struct Circulation {
+    pub enabled: bool,
+    pub run_secs: Option<u64>,
+    pub cooldown_secs: Option<u64>,
+    pub learning: bool,
+    pub night_suppress: bool,
+    pub double_tap_secs: Option<u64>,
+    pub usage_file: Option<String>,
+}
+
+pub struct Mqtt {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub topic_prefix: Option<String>,
+}
+
+/// `[notify]` rate-limit policy, the backends are in the `[notify:<name>]` sections
+pub struct Notify {
+    pub rate_limit: Duration,
+    pub max_per_hour: u32,
+}
+
+/// `[simulation]` fake 1-wire/GPIO boards for running without the hardware
+pub struct Simulation {
+    pub state_dir: Option<String>,
+}
+
+/// `[bus_watchdog]` recovery of the wedged 1-wire bus master
+pub struct BusWatchdog {
+    pub max_read_errors: u32,
+    pub recovery_interval: Duration,
+    pub notify_after: Duration,
+    pub master_driver: Option<String>,
+    pub master_device: Option<String>,
+    pub power_relay: Option<i32>,
+    pub power_off: Duration,
+}
+
+pub struct Energy {
+    pub import_price: Option<f64>,
+    pub export_price: Option<f64>,
+}
+
+/// `[energy_manager]` surplus PV load scheduler, the loads are in the `[energy_load:<name>]` sections
+pub struct EnergyManager {
+    pub check_interval: Duration,
+    pub hysteresis: f32,
+}
+
+/// `[heating]` controller options, the rooms are in the `[heating_zone:<name>]` sections
+pub struct Heating {
+    pub check_interval: Duration,
+    pub boiler: Option<String>,
+    pub ch_setpoint: Option<RemehaSetpoint>,
+    pub ch_setpoint_idle: Option<RemehaSetpoint>,
+    pub window_delay: Duration,
+}
+
+pub struct HeatingSeason {
+    pub outside_sensor: Option<String>,
+    pub on_below: Option<f32>,
+    pub off_above: Option<f32>,
+    pub average_hours: Option<f32>,
+    pub calendar: Option<((u32, u32), (u32, u32))>,
+    pub lcd_line: Option<u8>,
+}
+
+pub struct Alarm {
+    pub pet_immunity: Option<Duration>,
+    pub alarm_script: Option<String>,
+    pub exit_delay: Option<Duration>,
+    pub entry_delay: Option<Duration>,
+    pub siren_duration: Option<Duration>,
+}
+
+pub struct Mailbox {
+    pub enabled: bool,
+    pub notify_script: Option<String>,
+    pub lcd_line: Option<u8>,
+}
+
+pub struct Sun2000 {
+    pub host: Option<String>,
+    pub optimizers: bool,
+    pub battery_installed: bool,
+    pub dongle_connection: bool,
+    pub backup_box: bool,
+    pub load_shedding_tag: Option<String>,
+    pub mode_change_script: Option<String>,
+    pub poll_interval: Duration,
+    pub stats_interval: Duration,
+}
+
+/// `[dsmr]` smart electricity meter P1 port
+pub struct Dsmr {
+    pub device: Option<String>,
+    pub lcd_line: Option<u8>,
+    pub report_interval: Duration,
+    pub stats_interval: Duration,
+}
+
+/// `[opentherm]` gateway, the boiler setpoint requests are addressed to its `name`
+pub struct OpenTherm {
+    pub device: Option<String>,
+    pub name: Option<String>,
+    pub allow_write: bool,
+    pub influx_database: Option<String>,
+    pub report_interval: Duration,
+    pub stats_interval: Duration,
+}
+
+/// `[webserver]` listening address, credentials and TLS
+pub struct Webserver {
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub control_token: Option<String>,
+    pub control_user: Option<String>,
+    pub read_token: Option<String>,
+    pub read_user: Option<String>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+}
+
+/// `[wiegand]` reader on GPIO lines, an alternative to the USB reader (`rfid_event_path`)
+pub struct Wiegand {
+    pub chip: Option<String>,
+    pub d0: Option<u32>,
+    pub d1: Option<u32>,
+    pub facility_code: Option<u32>,
+    pub id_format: WiegandIdFormat,
+}
+
+/// `[keypad]` PIN entry: an evdev numeric keypad or a Wiegand one (`[wiegand]`)
+pub struct Keypad {
+    pub event_path: Option<String>,
+    pub usbid: Option<(u16, u16)>,
+    pub timeout: Duration,
+    pub max_attempts: u32,
+    pub lockout: Duration,
+}
+
+pub struct Geiger {
+    pub device: Option<String>,
+    pub baud_rate: Option<u32>,
+    pub mode: String,
+    pub factor: Option<f32>,
+    pub alarm_threshold: Option<f32>,
+    pub alarm_script: Option<String>,
+    pub lcd_line: Option<u8>,
+}
+
+/// `[appliance:<name>]` metered appliance, the missing thresholds are the detector defaults
+pub struct ApplianceSection {
+    pub name: String,
+    pub meter: MeterKind,
+    pub host: String,
+    pub slave: Option<u8>,
+    pub running_watts: Option<f32>,
+    pub idle_watts: Option<f32>,
+    pub start_time: Option<Duration>,
+    pub finish_time: Option<Duration>,
+}
+
+/// `[modbus:<name>]` generic device with the `reg_<name>` register table
+pub struct ModbusSection {
+    pub name: String,
+    pub transport: Transport,
+    pub slave: u8,
+    pub input_registers: bool,
+    pub registers: Vec<Register>,
+    pub poll_interval: Duration,
+    pub influx_database: String,
+}
+
+/// `[heating_zone:<name>]` room of the heating controller
+pub struct HeatingZoneSection {
+    pub name: String,
+    pub sensor: String,
+    pub relays: Vec<i32>,
+    pub day_temp: f32,
+    pub night_temp: f32,
+    pub day_start: NaiveTime,
+    pub night_start: NaiveTime,
+    pub hysteresis: f32,
+    pub cutback_temp: Option<f32>,
+}
+
+/// `[gate:<name>]` gate or garage door
+pub struct GateSection {
+    pub name: String,
+    pub id_relay: i32,
+    pub pulse: Duration,
+    pub timeout: Duration,
+    pub night_open: Duration,
+}
+
+/// `[energy_load:<name>]` load of the energy manager
+pub struct EnergyLoadSection {
+    pub name: String,
+    pub id_relay: i32,
+    pub watts: f32,
+    pub priority: u32,
+    pub min_runtime: Duration,
+    pub max_daily: Option<Duration>,
+}
+
+/// `[remeha:<name>]` additional boiler of a cascade
+pub struct RemehaSection {
+    pub name: String,
+    pub device: String,
+    pub influx_database: String,
+    pub state_change_script: Option<String>,
+    pub recovery_script: Option<String>,
+    pub alarm_script: Option<String>,
+    pub allow_write: bool,
+    pub poll_interval: Duration,
+    pub stats_interval: Duration,
+    pub counters_interval: Duration,
+}
+
+/// `[sun2000_rule:<name>]` energy surplus rule
+pub struct EnergyRuleSection {
+    pub name: String,
+    pub source: String,
+    pub condition: Expr,
+    pub id_relay: Option<i32>,
+    pub tag_group: Option<String>,
+    pub hold: Duration,
+}
+
+/// `[cron:<name>]` job
+pub struct CronSection {
+    pub name: String,
+    pub expression: String,
+    pub cron: CronExpr,
+    pub command: TaskCommand,
+    pub id_relay: Option<i32>,
+    pub tag_group: Option<String>,
+    pub id_yeelight: Option<i32>,
+    pub id_plug: Option<i32>,
+    pub duration: Option<Duration>,
+}
+
+/// Whole configuration, parsed once on startup (and again on a worker restart request).
+/// The named sections of the workers are validated here, the device ones
+/// (`[sensor:<name>]`, `[relay:<name>]`, ...) are read by their loaders.
+pub struct Config {
+    ini: Ini,
+    pub general: General,
+    pub postgres: Postgres,
+    pub sqlite: Sqlite,
+    pub influxdb: InfluxDb,
+    pub chaos: Option<ChaosRates>,
+    pub simulation: Option<Simulation>,
+    pub variables: HashMap<String, f32>,
+    pub log_levels: Vec<(String, LevelFilter)>,
+    pub circulation: Circulation,
+    pub mqtt: Mqtt,
+    pub notify: Notify,
+    pub energy: Energy,
+    pub energy_manager: EnergyManager,
+    pub bus_watchdog: BusWatchdog,
+    pub heating: Heating,
+    pub heating_season: HeatingSeason,
+    pub alarm: Alarm,
+    pub mailbox: Mailbox,
+    pub sun2000: Sun2000,
+    pub dsmr: Dsmr,
+    pub opentherm: OpenTherm,
+    pub geiger: Geiger,
+    pub wiegand: Wiegand,
+    pub keypad: Keypad,
+    pub webserver: Webserver,
+    pub appliances: Vec<ApplianceSection>,
+    pub modbus_devices: Vec<ModbusSection>,
+    pub heating_zones: Vec<HeatingZoneSection>,
+    pub notify_targets: Vec<NotifyTarget>,
+    pub gates: Vec<GateSection>,
+    pub gpio_boards: Vec<GpioBoard>,
+    pub energy_loads: Vec<EnergyLoadSection>,
+    pub remeha_boilers: Vec<RemehaSection>,
+    pub energy_rules: Vec<EnergyRuleSection>,
+    pub cron_jobs: Vec<CronSection>,
+    pub scenes: Vec<Scene>,
+}
+
+/// Option reader collecting the errors instead of failing on the first one
+struct Reader<'a> {
+    ini: &'a Ini,
+    pub errors: Vec<ConfigError>,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(ini: &'a Ini) -> Self {
+        Reader {
+            ini,
+            errors: vec![],
+        }
+    }
+
+    pub fn error(&mut self, section: &str, key: &str, value: &str, reason: &str) {
+        self.errors.push(ConfigError {
+            section: section.to_string(),
+            key: key.to_string(),
+            value: value.to_string(),
+            reason: reason.to_string(),
+        });
+    }
+
+    pub fn string(&self, section: &str, key: &str) -> Option<String> {
+        self.ini
+            .section(Some(section.to_owned()))
+            .and_then(|x| x.get(key).cloned())
+    }
+
+    pub fn bool(&mut self, section: &str, key: &str) -> bool {
+        match self.string(section, key) {
+            Some(value) => match value.trim() {
+                "yes" | "true" | "1" => true,
+                "no" | "false" | "0" => false,
+                _ => {
+                    self.error(section, key, &value, "expected true or false");
+                    false
+                }
+            },
+            None => false,
+        }
+    }
+
+    pub fn parse<T: FromStr>(&mut self, section: &str, key: &str) -> Option<T> {
+        let value = self.string(section, key)?;
+        match value.trim().parse() {
+            Ok(val) => Some(val),
+            Err(_) => {
+                self.error(section, key, &value, "invalid value");
+                None
+            }
+        }
+    }
+
+    /// Parses the value with a custom parser, eg. the unit names
+    pub fn parse_with<T>(
+        &mut self,
+        section: &str,
+        key: &str,
+        parser: impl Fn(&str) -> Option<T>,
+    ) -> Option<T> {
+        let value = self.string(section, key)?;
+        match parser(&value) {
+            Some(val) => Some(val),
+            None => {
+                self.error(section, key, &value, "invalid value");
+                None
+            }
+        }
+    }
+
+    /// Rate of the chaos mode fault, 0.0 - 1.0
+    fn rate(&mut self, key: &str) -> f32 {
+        match self.parse::<f32>("chaos", key) {
+            Some(rate) if (0.0..=1.0).contains(&rate) => rate,
+            Some(rate) => {
+                self.error("chaos", key, &rate.to_string(), "expected 0.0 - 1.0");
+                0.0
+            }
+            None => 0.0,
+        }
+    }
+
+    /// Numeric option with a lower bound, the default is used when missing or invalid
+    pub fn parse_min<T: FromStr + PartialOrd + fmt::Display>(
+        &mut self,
+        section: &str,
+        key: &str,
+        default: T,
+        min: T,
+    ) -> T {
+        match self.parse::<T>(section, key) {
+            Some(val) if val >= min => val,
+            Some(val) => {
+                self.error(
+                    section,
+                    key,
+                    &val.to_string(),
+                    &format!("minimum is {}", min),
+                );
+                default
+            }
+            None => default,
+        }
+    }
+
+    /// Interval in seconds with a lower bound
+    pub fn interval(&mut self, section: &str, key: &str, default: f32, min: f32) -> Duration {
+        Duration::from_secs_f32(self.parse_min(section, key, default, min))
+    }
+
+    /// Reports the missing mandatory option of a named section
+    pub fn required<T>(&mut self, section: &str, key: &str, value: Option<T>) -> Option<T> {
+        if value.is_none() && self.string(section, key).is_none() {
+            self.error(section, key, "", "missing option");
+        }
+        value
+    }
+
+    /// Comma separated list, empty when missing
+    pub fn list<T: FromStr>(&mut self, section: &str, key: &str) -> Vec<T> {
+        let value = match self.string(section, key) {
+            Some(value) => value,
+            None => return vec![],
+        };
+        let list: Option<Vec<T>> = value
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.parse().ok())
+            .collect();
+        list.unwrap_or_else(|| {
+            self.error(section, key, &value, "invalid list item");
+            vec![]
+        })
+    }
+
+    /// All sections with the given prefix as (section, name), eg. `("gate:garage", "garage")`
+    pub fn sections(&self, prefix: &str) -> Vec<(String, String)> {
+        self.ini
+            .iter()
+            .filter_map(|(section, _)| {
+                let section = section.as_ref()?;
+                section
+                    .strip_prefix(prefix)
+                    .map(|name| (section.clone(), name.to_string()))
+            })
+            .collect()
+    }
+
+    /// `[appliance:<name>]` sections
+    fn appliances(&mut self) -> Vec<ApplianceSection> {
+        let mut appliances = vec![];
+        for (s, name) in self.sections("appliance:") {
+            let meter = self.parse_with(&s, "meter", MeterKind::parse);
+            let meter = self.required(&s, "meter", meter);
+            let host = self.string(&s, "host");
+            let host = self.required(&s, "host", host);
+            let slave = self.parse(&s, "slave");
+            let secs = |r: &mut Self, key: &str| r.parse(&s, key).map(Duration::from_secs_f32);
+            let running_watts = self.parse(&s, "running_watts");
+            let idle_watts = self.parse(&s, "idle_watts");
+            let start_time = secs(self, "start_secs");
+            let finish_time = secs(self, "finish_secs");
+            if let (Some(meter), Some(host)) = (meter, host) {
+                appliances.push(ApplianceSection {
+                    name,
+                    meter,
+                    host,
+                    slave,
+                    running_watts,
+                    idle_watts,
+                    start_time,
+                    finish_time,
+                });
+            }
+        }
+        appliances
+    }
+
+    /// `[modbus:<name>]` sections, registers are defined as: `reg_<name>=ADDRESS:TYPE[:GAIN[:UNIT[:MEASUREMENT]]]`
+    fn modbus_devices(&mut self) -> Vec<ModbusSection> {
+        let mut devices = vec![];
+        for (s, name) in self.sections("modbus:") {
+            let transport = match (self.string(&s, "host"), self.string(&s, "device")) {
+                (Some(host), _) => Some(Transport::Tcp(host)),
+                (None, Some(device)) => Some(Transport::Rtu {
+                    device,
+                    baud_rate: self
+                        .parse(&s, "baud_rate")
+                        .unwrap_or(MODBUS_DEFAULT_BAUD_RATE),
+                }),
+                (None, None) => {
+                    self.error(&s, "host", "", "missing host or device");
+                    None
+                }
+            };
+            let mut registers = vec![];
+            let ini = self.ini;
+            if let Some(properties) = ini.section(Some(s.clone())) {
+                for (key, value) in properties.iter() {
+                    if let Some(reg_name) = key.strip_prefix("reg_") {
+                        match Register::parse(reg_name, value) {
+                            Some(register) => registers.push(register),
+                            None => self.error(
+                                &s,
+                                key,
+                                value,
+                                "expected ADDRESS:TYPE[:GAIN[:UNIT[:MEASUREMENT]]]",
+                            ),
+                        }
+                    }
+                }
+            }
+            if registers.is_empty() {
+                self.error(&s, "reg_*", "", "no registers defined");
+            }
+            let input_registers = self
+                .parse_with(&s, "register_kind", |kind| match kind.trim() {
+                    "input" => Some(true),
+                    "holding" => Some(false),
+                    _ => None,
+                })
+                .unwrap_or(false);
+            let slave = self.parse(&s, "slave").unwrap_or(1);
+            let poll_interval = self.interval(
+                &s,
+                "poll_interval_secs",
+                MODBUS_POLL_INTERVAL_SECS,
+                MODBUS_MIN_POLL_INTERVAL_SECS,
+            );
+            if let Some(transport) = transport {
+                devices.push(ModbusSection {
+                    name,
+                    transport,
+                    slave,
+                    input_registers,
+                    registers,
+                    poll_interval,
+                    influx_database: self
+                        .string(&s, "influx_database")
+                        .unwrap_or(MODBUS_DEFAULT_INFLUX_DATABASE.to_string()),
+                });
+            }
+        }
+        devices
+    }
+
+    /// `[heating_zone:<name>]` sections
+    fn heating_zones(&mut self) -> Vec<HeatingZoneSection> {
+        let mut zones = vec![];
+        for (s, name) in self.sections("heating_zone:") {
+            let sensor = self.string(&s, "sensor");
+            let sensor = self.required(&s, "sensor", sensor);
+            let relays = self.list(&s, "relays");
+            if self
+                .string(&s, "relays")
+                .map_or(true, |x| x.trim().is_empty())
+            {
+                self.error(&s, "relays", "", "missing option");
+            }
+            let zone = HeatingZoneSection {
+                name,
+                sensor: sensor.unwrap_or_default(),
+                relays,
+                day_temp: self
+                    .parse(&s, "day_temp")
+                    .unwrap_or(HEATING_DEFAULT_DAY_TEMP),
+                night_temp: self
+                    .parse(&s, "night_temp")
+                    .unwrap_or(HEATING_DEFAULT_NIGHT_TEMP),
+                day_start: self
+                    .parse_with(&s, "day_start", parse_time)
+                    .unwrap_or_else(|| NaiveTime::from_hms_opt(6, 0, 0).unwrap()),
+                night_start: self
+                    .parse_with(&s, "night_start", parse_time)
+                    .unwrap_or_else(|| NaiveTime::from_hms_opt(22, 0, 0).unwrap()),
+                hysteresis: self.parse_min(&s, "hysteresis", HEATING_DEFAULT_HYSTERESIS, 0.0),
+                cutback_temp: self.parse(&s, "window_cutback_temp"),
+            };
+            zones.push(zone);
+        }
+        zones
+    }
+
+    /// `[notify:<name>]` sections, the backend is selected by `kind`
+    fn notify_targets(&mut self) -> Vec<NotifyTarget> {
+        let mut targets = vec![];
+        for (s, name) in self.sections("notify:") {
+            let min_severity = self
+                .parse_with(&s, "min_severity", Severity::parse)
+                .unwrap_or(Severity::Info);
+            let required = |r: &mut Self, key: &str| {
+                let value = r.string(&s, key);
+                r.required(&s, key, value).unwrap_or_default()
+            };
+            let backend = match self.string(&s, "kind").as_deref().map(|x| x.trim()) {
+                Some("telegram") => Backend::Telegram {
+                    token: required(self, "token"),
+                    chat_id: required(self, "chat_id"),
+                },
+                Some("pushover") => Backend::Pushover {
+                    token: required(self, "token"),
+                    user: required(self, "user"),
+                },
+                Some("smtp") => Backend::Smtp {
+                    host: required(self, "host"),
+                    port: self.parse(&s, "port").unwrap_or(NOTIFY_SMTP_DEFAULT_PORT),
+                    username: self.string(&s, "username"),
+                    password: self.string(&s, "password"),
+                    from: required(self, "from"),
+                    to: required(self, "to")
+                        .split(',')
+                        .map(|x| x.trim().to_string())
+                        .filter(|x| !x.is_empty())
+                        .collect(),
+                    starttls: self.string(&s, "starttls").is_none() || self.bool(&s, "starttls"),
+                },
+                Some("webhook") => Backend::Webhook {
+                    url: required(self, "url"),
+                },
+                Some(kind) => {
+                    self.error(
+                        &s,
+                        "kind",
+                        kind,
+                        "expected telegram, pushover, smtp or webhook",
+                    );
+                    continue;
+                }
+                None => {
+                    self.error(&s, "kind", "", "missing option");
+                    continue;
+                }
+            };
+            targets.push(NotifyTarget {
+                name,
+                backend,
+                min_severity,
+            });
+        }
+        targets
+    }
+
+    /// `[gate:<name>]` sections
+    fn gates(&mut self) -> Vec<GateSection> {
+        let mut gates = vec![];
+        for (s, name) in self.sections("gate:") {
+            let id_relay = self.parse::<i32>(&s, "relay");
+            let id_relay = self.required(&s, "relay", id_relay);
+            let gate = GateSection {
+                name,
+                id_relay: id_relay.unwrap_or_default(),
+                pulse: self.interval(
+                    &s,
+                    "pulse_secs",
+                    GATE_DEFAULT_PULSE_SECS,
+                    GATE_DEFAULT_PULSE_SECS,
+                ),
+                timeout: self.interval(&s, "timeout_secs", GATE_DEFAULT_TIMEOUT_SECS, 1.0),
+                night_open: self.interval(&s, "night_open_secs", GATE_DEFAULT_NIGHT_OPEN_SECS, 0.0),
+            };
+            gates.push(gate);
+        }
+        gates
+    }
+
+    /// `[gpio:<name>]` sections: up to 2 inputs and 8 outputs, the address selects the board
+    fn gpio_boards(&mut self) -> Vec<GpioBoard> {
+        let mut boards: Vec<GpioBoard> = vec![];
+        for (s, name) in self.sections("gpio:") {
+            let address = self.parse::<u64>(&s, "address");
+            let address = self.required(&s, "address", address).unwrap_or_default();
+            if boards.iter().any(|b| b.address == address) {
+                self.error(&s, "address", &address.to_string(), "duplicated address");
+            }
+            let inputs = self.list(&s, "inputs");
+            if inputs.len() > 2 {
+                self.error(&s, "inputs", "", "up to 2 inputs expected");
+            }
+            let outputs = self.list(&s, "outputs");
+            if outputs.len() > 8 {
+                self.error(&s, "outputs", "", "up to 8 outputs expected");
+            }
+            let board = GpioBoard {
+                name,
+                chip: self
+                    .string(&s, "chip")
+                    .unwrap_or("/dev/gpiochip0".to_string()),
+                address,
+                inputs,
+                outputs,
+                active_low: self.bool(&s, "active_low"),
+            };
+            boards.push(board);
+        }
+        boards
+    }
+
+    /// `[energy_load:<name>]` sections
+    fn energy_loads(&mut self) -> Vec<EnergyLoadSection> {
+        let mut loads = vec![];
+        for (s, name) in self.sections("energy_load:") {
+            let id_relay = self.parse::<i32>(&s, "relay");
+            let id_relay = self.required(&s, "relay", id_relay);
+            let watts = self.parse::<f32>(&s, "watts");
+            let watts = self.required(&s, "watts", watts);
+            if let Some(watts) = watts.filter(|w| *w <= 0.0) {
+                self.error(
+                    &s,
+                    "watts",
+                    &watts.to_string(),
+                    "expected a positive number",
+                );
+            }
+            let max_daily = self.parse::<f32>(&s, "max_daily_secs");
+            if let Some(secs) = max_daily.filter(|x| *x < 0.0) {
+                self.error(&s, "max_daily_secs", &secs.to_string(), "minimum is 0");
+            }
+            let load = EnergyLoadSection {
+                name,
+                id_relay: id_relay.unwrap_or_default(),
+                watts: watts.unwrap_or_default(),
+                priority: self
+                    .parse(&s, "priority")
+                    .unwrap_or(ENERGY_LOAD_DEFAULT_PRIORITY),
+                min_runtime: self.interval(&s, "min_runtime_secs", 0.0, 0.0),
+                max_daily: max_daily.map(|x| Duration::from_secs_f32(x.max(0.0))),
+            };
+            loads.push(load);
+        }
+        loads
+    }
+
+    /// `[remeha:<name>]` sections
+    fn remeha_boilers(&mut self) -> Vec<RemehaSection> {
+        let mut boilers = vec![];
+        for (s, name) in self.sections("remeha:") {
+            let device = self.string(&s, "device");
+            let device = self.required(&s, "device", device);
+            let boiler = RemehaSection {
+                name,
+                device: device.unwrap_or_default(),
+                influx_database: self
+                    .string(&s, "influx_database")
+                    .unwrap_or(REMEHA_DEFAULT_INFLUX_DATABASE.to_string()),
+                state_change_script: self.string(&s, "state_change_script"),
+                recovery_script: self.string(&s, "recovery_script"),
+                alarm_script: self.string(&s, "alarm_script"),
+                allow_write: self.bool(&s, "allow_write"),
+                poll_interval: self.interval(
+                    &s,
+                    "poll_interval_secs",
+                    REMEHA_POLL_INTERVAL_SECS,
+                    REMEHA_MIN_POLL_INTERVAL_SECS,
+                ),
+                stats_interval: self.interval(
+                    &s,
+                    "stats_interval_secs",
+                    REMEHA_STATS_DUMP_INTERVAL_SECS,
+                    MIN_STATS_INTERVAL_SECS,
+                ),
+                counters_interval: self.interval(
+                    &s,
+                    "counters_interval_secs",
+                    REMEHA_COUNTERS_POLL_INTERVAL_SECS,
+                    MIN_STATS_INTERVAL_SECS,
+                ),
+            };
+            boilers.push(boiler);
+        }
+        boilers
+    }
+
+    /// `[sun2000_rule:<name>]` sections, driving a relay or a tag group
+    fn energy_rules(&mut self) -> Vec<EnergyRuleSection> {
+        let mut rules = vec![];
+        for (s, name) in self.sections("sun2000_rule:") {
+            let source = self.string(&s, "condition");
+            let source = self.required(&s, "condition", source);
+            let condition = match source.as_deref().map(Expr::parse) {
+                Some(Ok(condition)) => Some(condition),
+                Some(Err(e)) => {
+                    self.error(&s, "condition", source.as_deref().unwrap_or_default(), &e);
+                    None
+                }
+                None => None,
+            };
+            let id_relay = self.parse(&s, "relay");
+            let tag_group = self.string(&s, "tag");
+            if self.string(&s, "relay").is_none() && tag_group.is_none() {
+                self.error(&s, "relay", "", "missing relay or tag");
+            }
+            let hold = self.interval(&s, "hold_secs", SUN2000_RULE_DEFAULT_HOLD_SECS, 0.0);
+            if let (Some(source), Some(condition)) = (source, condition) {
+                rules.push(EnergyRuleSection {
+                    name,
+                    source,
+                    condition,
+                    id_relay,
+                    tag_group,
+                    hold,
+                });
+            }
+        }
+        rules
+    }
+
+    /// `[cron:<name>]` sections, sending the command to a relay, tag group, yeelight or plug
+    fn cron_jobs(&mut self) -> Vec<CronSection> {
+        let mut jobs = vec![];
+        for (s, name) in self.sections("cron:") {
+            let expression = self.string(&s, "cron");
+            let expression = self.required(&s, "cron", expression);
+            let cron = match expression.as_deref().map(CronExpr::parse) {
+                Some(Ok(cron)) => Some(cron),
+                Some(Err(e)) => {
+                    let expression = expression.as_deref().unwrap_or_default();
+                    self.error(&s, "cron", expression, &e.to_string());
+                    None
+                }
+                None => None,
+            };
+            //a prolonged turn on when not set
+            let command = self
+                .parse_with(&s, "command", CronJob::parse_command)
+                .unwrap_or(TaskCommand::TurnOnProlong);
+            let id_relay = self.parse(&s, "relay");
+            let tag_group = self.string(&s, "tag");
+            let id_yeelight = self.parse(&s, "yeelight");
+            let id_plug = self.parse(&s, "plug");
+            if ["relay", "tag", "yeelight", "plug"]
+                .iter()
+                .all(|key| self.string(&s, key).is_none())
+            {
+                self.error(&s, "relay", "", "missing relay, tag, yeelight or plug");
+            }
+            let duration = self.parse::<f32>(&s, "duration_secs");
+            if let Some(secs) = duration.filter(|x| *x < 0.0) {
+                self.error(&s, "duration_secs", &secs.to_string(), "minimum is 0");
+            }
+            if let (Some(expression), Some(cron)) = (expression, cron) {
+                jobs.push(CronSection {
+                    name,
+                    expression,
+                    cron,
+                    command,
+                    id_relay,
+                    tag_group,
+                    id_yeelight,
+                    id_plug,
+                    duration: duration.map(|x| Duration::from_secs_f32(x.max(0.0))),
+                });
+            }
+        }
+        jobs
+    }
+
+    /// `[scene:<name>]` sections
+    fn scenes(&mut self) -> Vec<Scene> {
+        let mut scenes = vec![];
+        for (s, name) in self.sections("scene:") {
+            let actions = self.string(&s, "actions").unwrap_or_default();
+            match Scene::parse(&name, &actions) {
+                Ok(scene) => scenes.push(scene),
+                Err(e) => self.error(&s, "actions", &actions, &e),
+            }
+        }
+        scenes
+    }
+}
+
+impl Config {
+    /// Loads and validates the config file, returns all invalid options on error
+    pub fn load(path: &str) -> Result<Config, Vec<ConfigError>> {
+        let ini = Ini::load_from_file(path).map_err(|e| {
+            vec![ConfigError {
+                section: "".into(),
+                key: "".into(),
+                value: path.into(),
+                reason: format!("cannot open config file: {}", e),
+            }]
+        })?;
+        let mut r = Reader::new(&ini);
+        let g = "general";
+
+        let general = General {
+            log: r.string(g, "log"),
+            log_format: r
+                .parse_with(g, "log_format", LogFormat::parse)
+                .unwrap_or(LogFormat::Text),
+            log_level: r.parse(g, "log_level").unwrap_or(LevelFilter::Info),
+            lat: r.parse(g, "lat").unwrap_or_default(),
+            lon: r.parse(g, "lon").unwrap_or_default(),
+            ethlcd_host: r.string(g, "ethlcd_host"),
+            ethlcd_display: r.bool(g, "ethlcd_display"),
+            ethlcd_rows: r.parse_min(g, "ethlcd_rows", 4, 1).min(4),
+            ethlcd_cols: r.parse_min(g, "ethlcd_cols", 20, 8),
+            ethlcd_page_interval: r.interval(g, "ethlcd_page_secs", ETHLCD_DEFAULT_PAGE_SECS, 1.0),
+            ethlcd_night_backlight: r
+                .parse_with(g, "ethlcd_night_backlight", Backlight::parse)
+                .unwrap_or(Backlight::Half),
+            rfid_event_path: r.string(g, "rfid_event_path"),
+            rfid_usbid: r.parse_with(g, "rfid_usbid", parse_usb_id),
+            rfid_hotplug: r.bool(g, "rfid_hotplug"),
+            owserver: r.string(g, "owserver"),
+            skymax_device: r.string(g, "skymax_device"),
+            skymax_usbid: r.string(g, "skymax_usbid").unwrap_or_default(),
+            skymax_mode_change_script: r.string(g, "skymax_mode_change_script"),
+            skymax_fault_script: r.string(g, "skymax_fault_script"),
+            skymax_settings: SKYMAX_SETTINGS
+                .iter()
+                .filter_map(|name| {
+                    r.parse_with(g, &format!("skymax_{}", name), |value| {
+                        SkymaxSetting::parse(name, value)
+                    })
+                })
+                .collect(),
+            skymax_poll_interval: r.interval(
+                g,
+                "skymax_poll_interval_secs",
+                SKYMAX_POLL_INTERVAL_SECS,
+                SKYMAX_MIN_POLL_INTERVAL_SECS,
+            ),
+            skymax_stats_interval: r.interval(
+                g,
+                "skymax_stats_interval_secs",
+                SKYMAX_STATS_DUMP_INTERVAL_SECS,
+                MIN_STATS_INTERVAL_SECS,
+            ),
+            skymax_status_lcd_line: r.parse(g, "skymax_status_lcd_line"),
+            influxdb_url: r.string(g, "influxdb_url"),
+            influx_env_database: r.string(g, "influx_env_database"),
+            influx_env_measurement: r.string(g, "influx_env_measurement"),
+            lcdproc: r.string(g, "lcdproc"),
+            remeha_device: r.string(g, "remeha_device"),
+            remeha_state_change_script: r.string(g, "remeha_state_change_script"),
+            remeha_recovery_script: r.string(g, "remeha_recovery_script"),
+            remeha_alarm_script: r.string(g, "remeha_alarm_script"),
+            remeha_lcd_line: r.parse(g, "remeha_lcd_line"),
+            remeha_allow_write: r.bool(g, "remeha_allow_write"),
+            remeha_poll_interval: r.interval(
+                g,
+                "remeha_poll_interval_secs",
+                REMEHA_POLL_INTERVAL_SECS,
+                REMEHA_MIN_POLL_INTERVAL_SECS,
+            ),
+            remeha_stats_interval: r.interval(
+                g,
+                "remeha_stats_interval_secs",
+                REMEHA_STATS_DUMP_INTERVAL_SECS,
+                MIN_STATS_INTERVAL_SECS,
+            ),
+            remeha_counters_interval: r.interval(
+                g,
+                "remeha_counters_interval_secs",
+                REMEHA_COUNTERS_POLL_INTERVAL_SECS,
+                MIN_STATS_INTERVAL_SECS,
+            ),
+            onewire_loop_interval: Duration::from_millis(r.parse_min(
+                g,
+                "onewire_loop_interval_ms",
+                ONEWIRE_LOOP_INTERVAL_MS,
+                ONEWIRE_MIN_LOOP_INTERVAL_MS,
+            )),
+            sensor_read_timeout: Duration::from_millis(r.parse_min(
+                g,
+                "sensor_read_timeout_ms",
+                SENSOR_READ_TIMEOUT_MS,
+                SENSOR_MIN_READ_TIMEOUT_MS,
+            )),
+            adaptive_hold_file: r.string(g, "adaptive_hold_file"),
+            meter_poll_interval: r.interval(
+                g,
+                "meter_poll_interval_secs",
+                METER_POLL_INTERVAL_SECS,
+                METER_MIN_POLL_INTERVAL_SECS,
+            ),
+            frost_guard_script: r.string(g, "frost_guard_script"),
+            frost_guard_power: r.parse(g, "frost_guard_power"),
+            sensor_board_stale: r
+                .parse(g, "sensor_board_stale_secs")
+                .map(Duration::from_secs),
+            inverted_sensors: r
+                .string(g, "inverted_sensors")
+                .map(|x| x.split(',').map(|name| name.trim().to_string()).collect())
+                .unwrap_or_default(),
+            watchdog_script: r.string(g, "watchdog_script"),
+            command_min_interval_ms: r.parse(g, "command_min_interval_ms"),
+            latency_trace: r.bool(g, "latency_trace"),
+            cesspool_notify_level: r.parse(g, "cesspool_notify_level"),
+            cesspool_critical_level: r.parse(g, "cesspool_critical_level"),
+            cesspool_notify_script: r.string(g, "cesspool_notify_script"),
+            api_token: r.string(g, "api_token"),
+            allow_reboot: r.bool(g, "allow_reboot"),
+            reboot_command: r
+                .string(g, "reboot_command")
+                .unwrap_or(DEFAULT_REBOOT_COMMAND.to_string()),
+            shutdown_timeout: Duration::from_secs(
+                r.parse(g, "shutdown_timeout_secs")
+                    .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            ),
+            heartbeat_timeout: r.interval(
+                g,
+                "heartbeat_timeout_secs",
+                DEFAULT_HEARTBEAT_TIMEOUT_SECS,
+                MIN_HEARTBEAT_TIMEOUT_SECS,
+            ),
+            log_buffer_lines: r
+                .parse(g, "log_buffer_lines")
+                .unwrap_or(LOG_BUFFER_LINES_PER_LEVEL),
+            log_buffer_level: r.parse(g, "log_buffer_level").unwrap_or(LevelFilter::Info),
+            temperature_unit: r
+                .parse_with(g, "temperature_unit", TemperatureUnit::parse)
+                .unwrap_or(TemperatureUnit::Celsius),
+            energy_unit: r
+                .parse_with(g, "energy_unit", EnergyUnit::parse)
+                .unwrap_or(EnergyUnit::Kwh),
+            timezone: r.parse_with(g, "timezone", Timezone::parse),
+            time_format: r.string(g, "time_format"),
+            decimal_comma: r.bool(g, "decimal_comma"),
+            disable_postgres: r.bool(g, "disable_postgres"),
+            storage: r
+                .parse_with(g, "storage", StorageBackend::parse)
+                .unwrap_or(StorageBackend::Postgres),
+            devices: r
+                .parse_with(g, "devices", DeviceSource::parse)
+                .unwrap_or(DeviceSource::Database),
+            disable_onewire: r.bool(g, "disable_onewire"),
+            disable_webserver: r.bool(g, "disable_webserver"),
+        };
+
+        let postgres = Postgres {
+            host: r.string("postgres", "host"),
+            dbname: r.string("postgres", "dbname"),
+            username: r.string("postgres", "username"),
+            password: r.string("postgres", "password"),
+        };
+        if !general.disable_postgres
+            && general.storage == StorageBackend::Postgres
+            && ini.section(Some("postgres".to_owned())).is_none()
+        {
+            r.error("postgres", "", "", "missing section");
+        }
+
+        let sqlite = Sqlite {
+            path: r
+                .string("sqlite", "path")
+                .unwrap_or(DEFAULT_SQLITE_PATH.to_string()),
+        };
+
+        let influxdb = InfluxDb {
+            spool_dir: r.string("influxdb", "spool_dir"),
+            org: r.string("influxdb", "org"),
+            token: r.string("influxdb", "token"),
+            bucket: r.string("influxdb", "bucket"),
+            buckets: ini
+                .section(Some("influxdb".to_owned()))
+                .map(|section| {
+                    section
+                        .iter()
+                        .filter_map(|(key, value)| {
+                            key.strip_prefix("bucket_")
+                                .map(|database| (database.to_string(), value.clone()))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+
+        let chaos = if r.bool("chaos", "enabled") {
+            Some(ChaosRates {
+                influx_write: r.rate("influx_error_rate"),
+                modbus_timeout: r.rate("modbus_timeout_rate"),
+                w1_read: r.rate("w1_read_error_rate"),
+                channel_delay: r.rate("channel_delay_rate"),
+            })
+        } else {
+            None
+        };
+
+        let simulation = if r.bool("simulation", "enabled") {
+            Some(Simulation {
+                state_dir: r.string("simulation", "state_dir"),
+            })
+        } else {
+            None
+        };
+
+        //constants for virtual sensor expressions, eg. a heating setpoint
+        let mut variables = HashMap::new();
+        if let Some(section) = ini.section(Some("variables".to_owned())) {
+            for (name, value) in section.iter() {
+                match value.trim().parse::<f32>() {
+                    Ok(val) => {
+                        variables.insert(name.clone(), val);
+                    }
+                    Err(_) => r.error("variables", name, value, "expected a number"),
+                }
+            }
+        }
+
+        //per module log verbosity, eg. sun2000=debug
+        let mut log_levels = vec![];
+        if let Some(section) = ini.section(Some("log_levels".to_owned())) {
+            for (module, _) in section.iter() {
+                if let Some(level) = r.parse("log_levels", module) {
+                    log_levels.push((module.clone(), level));
+                }
+            }
+        }
+
+        let circulation = Circulation {
+            enabled: r.bool("circulation", "enabled"),
+            run_secs: r.parse("circulation", "run_secs"),
+            cooldown_secs: r.parse("circulation", "cooldown_secs"),
+            learning: r.bool("circulation", "learning"),
+            night_suppress: r.bool("circulation", "night_suppress"),
+            double_tap_secs: r.parse("circulation", "double_tap_secs"),
+            usage_file: r.string("circulation", "usage_file"),
+        };
+
+        let mqtt = Mqtt {
+            host: r.string("mqtt", "host"),
+            port: r.parse("mqtt", "port"),
+            client_id: r.string("mqtt", "client_id"),
+            username: r.string("mqtt", "username"),
+            password: r.string("mqtt", "password"),
+            topic_prefix: r.string("mqtt", "topic_prefix"),
+        };
+
+        let notify = Notify {
+            rate_limit: r.interval(
+                "notify",
+                "rate_limit_secs",
+                NOTIFY_DEFAULT_RATE_LIMIT_SECS,
+                0.0,
+            ),
+            max_per_hour: r.parse_min("notify", "max_per_hour", NOTIFY_DEFAULT_MAX_PER_HOUR, 1),
+        };
+
+        let energy = Energy {
+            import_price: r.parse("energy", "import_price"),
+            export_price: r.parse("energy", "export_price"),
+        };
+
+        let energy_manager = EnergyManager {
+            check_interval: r.interval(
+                "energy_manager",
+                "check_interval_secs",
+                ENERGY_MANAGER_CHECK_INTERVAL_SECS,
+                ENERGY_MANAGER_MIN_CHECK_INTERVAL_SECS,
+            ),
+            hysteresis: r.parse_min(
+                "energy_manager",
+                "hysteresis_watts",
+                ENERGY_MANAGER_DEFAULT_HYSTERESIS,
+                0.0,
+            ),
+        };
+
+        let heating = Heating {
+            check_interval: r.interval(
+                "heating",
+                "check_interval_secs",
+                HEATING_CHECK_INTERVAL_SECS,
+                HEATING_MIN_CHECK_INTERVAL_SECS,
+            ),
+            boiler: r.string("heating", "boiler"),
+            ch_setpoint: r.parse_with("heating", "ch_setpoint", |v| RemehaSetpoint::parse("ch", v)),
+            ch_setpoint_idle: r.parse_with("heating", "ch_setpoint_idle", |v| {
+                RemehaSetpoint::parse("ch", v)
+            }),
+            window_delay: r.interval("heating", "window_open_secs", HEATING_WINDOW_OPEN_SECS, 0.0),
+        };
+
+        let hs = "heating_season";
+        let heating_season = HeatingSeason {
+            outside_sensor: r.string(hs, "outside_sensor"),
+            on_below: r.parse(hs, "on_below"),
+            off_above: r.parse(hs, "off_above"),
+            average_hours: r.parse(hs, "average_hours"),
+            calendar: match (
+                r.parse_with(hs, "start", HeatingSeasonState::parse_month_day),
+                r.parse_with(hs, "end", HeatingSeasonState::parse_month_day),
+            ) {
+                (Some(start), Some(end)) => Some((start, end)),
+                _ => None,
+            },
+            lcd_line: r.parse(hs, "lcd_line"),
+        };
+
+        let alarm = Alarm {
+            pet_immunity: r
+                .parse("alarm", "pet_immunity_secs")
+                .map(Duration::from_secs_f32),
+            alarm_script: r.string("alarm", "alarm_script"),
+            exit_delay: r
+                .parse("alarm", "exit_delay_secs")
+                .map(Duration::from_secs_f32),
+            entry_delay: r
+                .parse("alarm", "entry_delay_secs")
+                .map(Duration::from_secs_f32),
+            siren_duration: r.parse("alarm", "siren_secs").map(Duration::from_secs_f32),
+        };
+
+        let bus_watchdog = BusWatchdog {
+            max_read_errors: r.parse_min(
+                "bus_watchdog",
+                "max_read_errors",
+                BUS_WATCHDOG_MAX_READ_ERRORS,
+                1,
+            ),
+            recovery_interval: r.interval(
+                "bus_watchdog",
+                "recovery_interval_secs",
+                BUS_WATCHDOG_RECOVERY_INTERVAL_SECS,
+                BUS_WATCHDOG_MIN_RECOVERY_INTERVAL_SECS,
+            ),
+            notify_after: r.interval("bus_watchdog", "notify_secs", BUS_WATCHDOG_NOTIFY_SECS, 0.0),
+            master_driver: r.string("bus_watchdog", "master_driver"),
+            master_device: r.string("bus_watchdog", "master_device"),
+            power_relay: r.parse("bus_watchdog", "power_relay"),
+            power_off: r.interval(
+                "bus_watchdog",
+                "power_off_secs",
+                BUS_WATCHDOG_POWER_OFF_SECS,
+                1.0,
+            ),
+        };
+
+        let mailbox = Mailbox {
+            enabled: r.bool("mailbox", "enabled"),
+            notify_script: r.string("mailbox", "notify_script"),
+            lcd_line: r.parse("mailbox", "lcd_line"),
+        };
+
+        let sun2000 = Sun2000 {
+            host: r.string("sun2000", "host"),
+            optimizers: r.bool("sun2000", "optimizers"),
+            battery_installed: r.bool("sun2000", "battery_installed"),
+            dongle_connection: r.bool("sun2000", "dongle_connection"),
+            backup_box: r.bool("sun2000", "backup_box"),
+            load_shedding_tag: r.string("sun2000", "load_shedding_tag"),
+            mode_change_script: r.string("sun2000", "mode_change_script"),
+            poll_interval: r.interval(
+                "sun2000",
+                "poll_interval_secs",
+                SUN2000_POLL_INTERVAL_SECS,
+                SUN2000_MIN_POLL_INTERVAL_SECS,
+            ),
+            stats_interval: r.interval(
+                "sun2000",
+                "stats_interval_secs",
+                SUN2000_STATS_DUMP_INTERVAL_SECS,
+                MIN_STATS_INTERVAL_SECS,
+            ),
+        };
+
+        let dsmr = Dsmr {
+            device: r.string("dsmr", "device"),
+            lcd_line: r.parse("dsmr", "lcd_line"),
+            report_interval: r.interval(
+                "dsmr",
+                "report_interval_secs",
+                DSMR_REPORT_INTERVAL_SECS,
+                DSMR_MIN_REPORT_INTERVAL_SECS,
+            ),
+            stats_interval: r.interval(
+                "dsmr",
+                "stats_interval_secs",
+                DSMR_STATS_DUMP_INTERVAL_SECS,
+                MIN_STATS_INTERVAL_SECS,
+            ),
+        };
+
+        let opentherm = OpenTherm {
+            device: r.string("opentherm", "device"),
+            name: r.string("opentherm", "name"),
+            allow_write: r.bool("opentherm", "allow_write"),
+            influx_database: r.string("opentherm", "influx_database"),
+            report_interval: r.interval(
+                "opentherm",
+                "report_interval_secs",
+                OPENTHERM_REPORT_INTERVAL_SECS,
+                OPENTHERM_MIN_REPORT_INTERVAL_SECS,
+            ),
+            stats_interval: r.interval(
+                "opentherm",
+                "stats_interval_secs",
+                OPENTHERM_STATS_DUMP_INTERVAL_SECS,
+                MIN_STATS_INTERVAL_SECS,
+            ),
+        };
+
+        let geiger = Geiger {
+            device: r.string("geiger", "device"),
+            baud_rate: r.parse_with("geiger", "baud_rate", |v| {
+                v.trim().parse().ok().filter(|b| baud_speed(*b).is_some())
+            }),
+            mode: r.string("geiger", "mode").unwrap_or_default(),
+            factor: r.parse("geiger", "factor"),
+            alarm_threshold: r.parse("geiger", "alarm_threshold"),
+            alarm_script: r.string("geiger", "alarm_script"),
+            lcd_line: r.parse("geiger", "lcd_line"),
+        };
+
+        let wiegand = Wiegand {
+            chip: r.string("wiegand", "chip"),
+            d0: r.parse("wiegand", "d0"),
+            d1: r.parse("wiegand", "d1"),
+            facility_code: r.parse("wiegand", "facility_code"),
+            id_format: r
+                .parse_with("wiegand", "id_format", WiegandIdFormat::parse)
+                .unwrap_or(WiegandIdFormat::Full),
+        };
+        if wiegand.chip.is_some() && (wiegand.d0.is_none() || wiegand.d1.is_none()) {
+            r.error("wiegand", "d0", "", "both d0 and d1 lines are required");
+        }
+
+        let keypad = Keypad {
+            event_path: r.string("keypad", "event_path"),
+            usbid: r.parse_with("keypad", "usbid", parse_usb_id),
+            timeout: Duration::from_secs(r.parse_min(
+                "keypad",
+                "timeout_secs",
+                DEFAULT_PIN_TIMEOUT_SECS,
+                1,
+            )),
+            max_attempts: r.parse_min("keypad", "max_attempts", DEFAULT_PIN_MAX_ATTEMPTS, 1),
+            lockout: Duration::from_secs(
+                r.parse("keypad", "lockout_secs")
+                    .unwrap_or(DEFAULT_PIN_LOCKOUT_SECS),
+            ),
+        };
+
+        let ws = "webserver";
+        let mut user = |key: &str| {
+            let value = r.string(ws, key)?;
+            if value.contains(':') {
+                Some(value)
+            } else {
+                r.error(ws, key, &value, "expected user:password");
+                None
+            }
+        };
+        let control_user = user("control_user");
+        let read_user = user("read_user");
+        let webserver = Webserver {
+            address: r.string(ws, "address"),
+            port: r.parse(ws, "port"),
+            //the older [general] api_token is the control token
+            control_token: r.string(ws, "control_token").or(general.api_token.clone()),
+            control_user,
+            read_token: r.string(ws, "read_token"),
+            read_user,
+            tls_cert: r.string(ws, "tls_cert"),
+            tls_key: r.string(ws, "tls_key"),
+        };
+        if webserver.tls_cert.is_some() != webserver.tls_key.is_some() {
+            r.error(ws, "tls_cert", "", "both tls_cert and tls_key are required");
+        }
+
+        let appliances = r.appliances();
+        let modbus_devices = r.modbus_devices();
+        let heating_zones = r.heating_zones();
+        let notify_targets = r.notify_targets();
+        let gates = r.gates();
+        let gpio_boards = r.gpio_boards();
+        let energy_loads = r.energy_loads();
+        let remeha_boilers = r.remeha_boilers();
+        let energy_rules = r.energy_rules();
+        let cron_jobs = r.cron_jobs();
+        let scenes = r.scenes();
+
+        //a relay is switched either by the energy manager or by a sun2000 rule, never both
+        for rule in &energy_rules {
+            let id_relay = match rule.id_relay {
+                Some(id_relay) => id_relay,
+                None => continue,
+            };
+            if let Some(load) = energy_loads.iter().find(|l| l.id_relay == id_relay) {
+                r.error(
+                    &format!("sun2000_rule:{}", rule.name),
+                    "relay",
+                    &id_relay.to_string(),
+                    &format!("already switched by [energy_load:{}]", load.name),
+                );
+            }
+        }
+
+        let errors = r.errors;
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Config {
+            ini,
+            general,
+            postgres,
+            sqlite,
+            influxdb,
+            chaos,
+            simulation,
+            variables,
+            log_levels,
+            circulation,
+            mqtt,
+            notify,
+            energy,
+            energy_manager,
+            bus_watchdog,
+            heating,
+            heating_season,
+            alarm,
+            mailbox,
+            sun2000,
+            dsmr,
+            opentherm,
+            geiger,
+            wiegand,
+            keypad,
+            webserver,
+            appliances,
+            modbus_devices,
+            heating_zones,
+            notify_targets,
+            gates,
+            gpio_boards,
+            energy_loads,
+            remeha_boilers,
+            energy_rules,
+            cron_jobs,
+            scenes,
+        })
+    }
+
+    /// All config sections with the given prefix, eg. `[modbus:<name>]` as (name, options)
+    pub fn sections(&self, prefix: &str) -> Vec<(String, &Properties)> {
+        self.ini
+            .iter()
+            .filter_map(|(section, properties)| {
+                section
+                    .as_ref()?
+                    .strip_prefix(prefix)
+                    .map(|name| (name.to_string(), properties))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn load(content: &str) -> Result<Config, Vec<ConfigError>> {
+        let dir = TempDir::new("config");
+        Config::load(&dir.write("hard.conf", content))
+    }
+
+    #[test]
+    fn named_sections_are_parsed() {
+        let config = load(
+            "[general]\n\
+             [gate:garage]\nrelay=12\ntimeout_secs=30\n\
+             [energy_load:boiler]\nrelay=3\nwatts=2000\nmax_daily_secs=7200\n\
+             [modbus:meter]\nhost=10.0.0.5:502\nreg_power=0x10:f32\n\
+             [notify:phone]\nkind=telegram\ntoken=abc\nchat_id=42\n\
+             [remeha:second]\ndevice=10.0.0.6:502\npoll_interval_secs=10\n\
+             [sun2000_rule:surplus]\ncondition=grid_export > 2000\ntag=heaters\n\
+             [cron:night]\ncron=0 22 * * *\ncommand=off\nrelay=4\n\
+             [scene:movie]\nactions=relay:1:off, relay:2:on\n",
+        )
+        .unwrap_or_else(|e| panic!("{:?}", e));
+
+        assert_eq!(config.gates.len(), 1);
+        assert_eq!(config.gates[0].name, "garage");
+        assert_eq!(config.gates[0].id_relay, 12);
+        assert_eq!(config.gates[0].timeout, Duration::from_secs(30));
+        assert_eq!(
+            config.energy_loads[0].priority,
+            ENERGY_LOAD_DEFAULT_PRIORITY
+        );
+        assert_eq!(
+            config.energy_loads[0].max_daily,
+            Some(Duration::from_secs(7200))
+        );
+        assert_eq!(config.modbus_devices[0].registers[0].address, 0x10);
+        assert_eq!(config.notify_targets[0].min_severity, Severity::Info);
+        assert_eq!(config.remeha_boilers[0].device, "10.0.0.6:502");
+        assert_eq!(
+            config.remeha_boilers[0].poll_interval,
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            config.energy_rules[0].hold,
+            Duration::from_secs_f32(SUN2000_RULE_DEFAULT_HOLD_SECS)
+        );
+        assert_eq!(config.cron_jobs[0].command, TaskCommand::TurnOff);
+        assert_eq!(config.cron_jobs[0].id_relay, Some(4));
+        assert_eq!(config.scenes[0].actions.len(), 2);
+    }
+
+    #[test]
+    fn invalid_named_sections_are_rejected() {
+        let errors = match load(
+            "[general]\n\
+             [gate:garage]\npulse_secs=2\n\
+             [energy_load:boiler]\nrelay=3\nwatts=0\n\
+             [modbus:meter]\nhost=10.0.0.5:502\nreg_power=0x10:f64\n\
+             [notify:phone]\nkind=sms\n\
+             [gpio:a]\naddress=1\n[gpio:b]\naddress=1\n\
+             [remeha:second]\npoll_interval_secs=10\n\
+             [sun2000_rule:surplus]\ncondition=grid_export >\nrelay=5\n\
+             [cron:night]\ncron=0 25 * * *\n\
+             [scene:movie]\nactions=relay:1:of\n",
+        ) {
+            Ok(_) => panic!("invalid config loaded"),
+            Err(errors) => errors,
+        };
+        let failed: Vec<(&str, &str)> = errors
+            .iter()
+            .map(|e| (e.section.as_str(), e.key.as_str()))
+            .collect();
+
+        assert!(failed.contains(&("gate:garage", "relay")));
+        assert!(failed.contains(&("energy_load:boiler", "watts")));
+        assert!(failed.contains(&("modbus:meter", "reg_power")));
+        assert!(failed.contains(&("notify:phone", "kind")));
+        assert!(failed.contains(&("gpio:b", "address")));
+        assert!(failed.contains(&("remeha:second", "device")));
+        assert!(failed.contains(&("sun2000_rule:surplus", "condition")));
+        assert!(failed.contains(&("cron:night", "cron")));
+        assert!(failed.contains(&("cron:night", "relay")));
+        assert!(failed.contains(&("scene:movie", "actions")));
+    }
+
+    #[test]
+    fn relay_shared_by_energy_load_and_sun2000_rule_is_rejected() {
+        let errors = match load(
+            "[general]\n\
+             [energy_load:boiler]\nrelay=3\nwatts=2000\n\
+             [sun2000_rule:surplus]\ncondition=grid_export > 2000\nrelay=3\n",
+        ) {
+            Ok(_) => panic!("shared relay loaded"),
+            Err(errors) => errors,
+        };
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].section, "sun2000_rule:surplus");
+        assert_eq!(errors[0].key, "relay");
+    }
+}
@@ -1,38 +1,102 @@
-extern crate ini;
 extern crate postgres;
 extern crate postgres_openssl;
 
-use self::ini::Ini;
+use crate::config::Config;
+use crate::queue::{Receiver, Sender};
 use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
 use postgres_openssl::MakeTlsConnector;
 use simplelog::*;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::Receiver;
 use std::sync::{Arc, RwLock};
 
+use crate::cesspool::{CesspoolHistory, CESSPOOL_HISTORY_DAYS};
+use crate::circulation::CirculationTrigger;
+use crate::device_config::DeviceSource;
+use crate::energy::{DailyNetMetering, EnergyCosts, MonthlyCost, NET_METERING_HISTORY_DAYS};
+use crate::influx::{self, Client, InfluxDbWriteable, Timestamp, WriteQuery};
+use crate::lcdproc::{LcdTask, LcdTaskCommand, ScreenPriority};
+use crate::meter::MeterTotals;
+use crate::metrics::BusMetrics;
+use crate::notify::{Notification, Severity};
 use crate::onewire;
+use crate::onewire::StateMachine;
 use crate::onewire_env;
-use crate::rfid::RfidTag;
-use chrono::Utc;
-use influxdb::InfluxDbWriteable;
-use influxdb::{Client, Timestamp};
+use crate::ontime::{DailyOnTime, DeviceKind, OnTimeStats, ONTIME_HISTORY_DAYS};
+use crate::rfid::{PinHash, RfidRestrictions, RfidScan, RfidTag};
+use crate::schedule::{AllNight, CronExpr, CronJob, RelaySchedule, ScheduleTime};
+use crate::smart_plug::PlugKind;
+use crate::storage::{self, Row, Storage, StorageBackend};
+use crate::systemd;
+use crate::units;
+use crate::virtual_sensor::{Expr, VirtualSensor};
+use crate::worker_error::{ErrorReporter, WorkerError};
+use chrono::{Local, NaiveDate, Utc};
 use std::borrow::BorrowMut;
 use std::collections::HashMap;
-use std::time::{Duration, Instant};
-use tokio_compat_02::FutureExt;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
 
 // Just a generic Result type to ease error handling for us. Errors in multithreaded
 // async contexts needs some extra restrictions
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
+#[derive(Debug, Error)]
+pub enum DatabaseError {
+    #[error("SQLite database error: {0}")]
+    Sqlite(rusqlite::Error),
+    #[error("PostgreSQL connection error: {0}")]
+    Connect(postgres::Error),
+    #[error("postgres config is not OK, check the config file")]
+    Config,
+    #[error("influxdb write error: {0}")]
+    Influx(influx::Error),
+}
+
+impl WorkerError for DatabaseError {
+    fn kind(&self) -> &'static str {
+        match self {
+            DatabaseError::Sqlite(_) | DatabaseError::Connect(_) => "connect",
+            DatabaseError::Config => "config",
+            DatabaseError::Influx(_) => "influxdb",
+        }
+    }
+
+    //the connection is retried in the next loop and the data is kept until then
+    fn is_recoverable(&self) -> bool {
+        true
+    }
+}
+
+pub const DEFAULT_ENV_MEASUREMENT: &str = "environment"; //influx measurement for env sensor readings
+pub const RELOAD_CHANNEL: &str = "hard_reload"; //NOTIFY hard_reload makes the devices reload
+pub const CESSPOOL_CHECK_INTERVAL_SECS: u64 = 60; //secs between checking the daily cesspool alerts
+pub const CESSPOOL_LCD_SCREEN: &str = "cesspool";
+
+/// Value of a mandatory column of a device row, the whole row is skipped when it is
+/// missing or invalid (to be used in the loop over the rows)
+macro_rules! row_value {
+    ($name:expr, $row:expr, $column:expr) => {
+        match $row.get($column) {
+            Ok(value) => value,
+            Err(e) => {
+                error!("{}: skipping a row: {}", $name, e);
+                continue;
+            }
+        }
+    };
+}
+
 pub struct Database {
     pub name: String,
+    pub config: Arc<Config>,
     pub host: Option<String>,
     pub dbname: Option<String>,
     pub username: Option<String>,
     pub password: Option<String>,
     pub receiver: Receiver<DbTask>,
     pub conn: Option<postgres::Client>,
+    pub sqlite: Option<rusqlite::Connection>,
     pub disable_onewire: bool,
     pub sensor_devices: Arc<RwLock<onewire::SensorDevices>>,
     pub relay_devices: Arc<RwLock<onewire::RelayDevices>>,
@@ -42,12 +106,42 @@ pub struct Database {
     pub sensor_counters: HashMap<i32, u32>,
     pub relay_counters: HashMap<i32, u32>,
     pub yeelight_counters: HashMap<i32, u32>,
+    pub plug_counters: HashMap<i32, u32>,
     pub influx_sensor_counters: HashMap<i32, u32>,
     pub influxdb_url: Option<String>,
     pub influx_sensor_values: HashMap<i32, bool>,
     pub influx_relay_values: HashMap<i32, bool>,
+    pub influx_virtual_values: HashMap<i32, bool>,
+    pub influx_env_database: Option<String>,
+    pub influx_env_measurement: Option<String>,
     pub influx_cesspool_level: Option<u8>,
     pub daily_yield_energy: Option<i32>,
+    pub influx_frost_protection_secs: Option<i32>,
+    pub pg_alarm_events: Vec<(&'static str, Option<i32>)>,
+    pub influx_alarm_events: Vec<(&'static str, Option<i32>)>,
+    pub pg_rfid_scans: Vec<RfidScan>,
+    pub pg_circulation_runs: Vec<(SystemTime, &'static str, i32)>,
+    pub influx_sun_azimuth: Option<f64>,
+    pub influx_sun_elevation: Option<f64>,
+    pub cesspool_history: Arc<RwLock<CesspoolHistory>>,
+    pub cesspool_history_loaded: bool,
+    pub pg_cesspool_levels: Vec<(SystemTime, i16)>,
+    pub cesspool_notify_level: Option<u8>,
+    pub cesspool_critical_level: Option<u8>,
+    pub cesspool_notify_script: Option<String>,
+    //severity and day of the last threshold alert, repeated daily
+    pub cesspool_alerted: Option<(Severity, NaiveDate)>,
+    pub cesspool_screen: bool,
+    pub lcd_transmitter: Sender<LcdTask>,
+    pub notify_transmitter: Sender<Notification>,
+    pub bus_metrics: Arc<RwLock<BusMetrics>>,
+    pub energy_costs: Arc<RwLock<EnergyCosts>>,
+    pub energy_costs_loaded: bool,
+    pub ontime: Arc<RwLock<OnTimeStats>>,
+    pub ontime_loaded: bool,
+    pub meter_totals: Arc<RwLock<MeterTotals>>,
+    pub meter_totals_loaded: bool,
+    pub errors: ErrorReporter,
 }
 
 #[derive(Debug)]
@@ -56,13 +150,50 @@ pub enum CommandCode {
     IncrementSensorCounter,
     IncrementRelayCounter,
     IncrementYeelightCounter,
+    IncrementPlugCounter,
     UpdateSensorStateOn,
     UpdateSensorStateOff,
     UpdateRelayStateOn,
     UpdateRelayStateOff,
+    UpdateVirtualSensorStateOn,
+    UpdateVirtualSensorStateOff,
     UpdateCesspoolLevel,
     UpdateDailyEnergyYield,
+    IncrementFrostProtectionTime,
+    UpdateSunAzimuth,   //value in hundredths of a degree
+    UpdateSunElevation, //value in hundredths of a degree
+    StoreTemperature,   //value is the id_sensor, reading is taken from the env sensor
+    StoreHumidity,      //value is the id_sensor, reading is taken from the env sensor
+    AlarmArmed,
+    AlarmDisarmed,
+    AlarmTriggered, //value is the id_sensor
+    DeviceSwitched {
+        //value is the device id
+        kind: DeviceKind,
+        on: bool,
+        time: SystemTime,
+    },
+    RfidScanned(RfidScan),
+    CirculationRun {
+        //value is the run time in seconds
+        trigger: CirculationTrigger,
+        time: SystemTime,
+    },
 }
+//rows of the device views, the optional ones may be missing in older databases
+struct DeviceRows {
+    kinds: Vec<Row>,
+    sensors: Vec<Row>,
+    virtual_sensors: Result<Vec<Row>>,
+    env_sensors: Vec<Row>,
+    relays: Vec<Row>,
+    yeelights: Vec<Row>,
+    smart_plugs: Result<Vec<Row>>,
+    relay_schedules: Result<Vec<Row>>,
+    relay_cron: Result<Vec<Row>>,
+    rfid_tags: Vec<Row>,
+}
+
 pub struct DbTask {
     pub command: CommandCode,
     pub value: Option<i32>,
@@ -70,19 +201,63 @@ pub struct DbTask {
 
 impl Database {
     fn load_db_config(&mut self) {
-        let conf = Ini::load_from_file("hard.conf").expect("Cannot open config file");
-        let section = conf
-            .section(Some("postgres".to_owned()))
-            .expect("Cannot find postgres section in config");
-        self.host = section.get("host").cloned();
-        self.dbname = section.get("dbname").cloned();
-        self.username = section.get("username").cloned();
-        self.password = section.get("password").cloned();
+        let postgres = &self.config.postgres;
+        self.host = postgres.host.clone();
+        self.dbname = postgres.dbname.clone();
+        self.username = postgres.username.clone();
+        self.password = postgres.password.clone();
     }
 
+    fn connected(&self) -> bool {
+        self.conn.is_some() || self.sqlite.is_some()
+    }
+
+    //connection used for the device definitions, rfid tags and counters
+    fn storage(&mut self) -> Option<&mut dyn Storage> {
+        match (self.conn.as_mut(), self.sqlite.as_mut()) {
+            (Some(client), _) => Some(client),
+            (None, Some(sqlite)) => Some(sqlite),
+            _ => None,
+        }
+    }
+
+    //drops the failed connection, it is reopened in the next iteration
+    fn disconnect(&mut self) {
+        self.conn = None;
+        self.sqlite = None;
+    }
+
+    //all views are fetched first, so a failed query leaves the current devices untouched
+    fn fetch_devices(storage: &mut dyn Storage) -> Result<DeviceRows> {
+        Ok(DeviceRows {
+            kinds: storage.select_all("kinds")?,
+            sensors: storage.select_all("sensors")?,
+            virtual_sensors: storage.select_all("virtual_sensors"),
+            env_sensors: storage.select_all("env_sensors")?,
+            relays: storage.select_all("relays")?,
+            yeelights: storage.select_all("yeelights")?,
+            smart_plugs: storage.select_all("smart_plugs"),
+            relay_schedules: storage.select_all("relay_schedules"),
+            relay_cron: storage.select_all("relay_cron"),
+            rfid_tags: storage.select_all("rfid_tags")?,
+        })
+    }
+
+    /// (Re)loads the device definitions, the new set is applied at once while the
+    /// runtime state of the existing relays and lights is preserved
     fn load_devices(&mut self) {
-        match self.conn.borrow_mut() {
-            Some(client) => {
+        let rows = match self.storage() {
+            Some(storage) => Database::fetch_devices(storage),
+            None => {
+                error!(
+                    "{}: no active database connection -> cannot load config",
+                    self.name
+                );
+                return;
+            }
+        };
+        match rows {
+            Ok(rows) => {
                 let mut sensor_dev = self.sensor_devices.write().unwrap();
                 let mut env_sensor_dev = self.env_sensor_devices.write().unwrap();
                 let mut relay_dev = self.relay_devices.write().unwrap();
@@ -92,9 +267,9 @@ impl Database {
                 info!("🦏 {}: Loading data from view 'kinds'...", self.name);
                 sensor_dev.kinds.clear();
                 env_sensor_dev.kinds.clear();
-                for row in client.query("select * from kinds", &[]).unwrap() {
-                    let id_kind: i32 = row.get("id_kind");
-                    let name: String = row.get("name");
+                for row in rows.kinds {
+                    let id_kind: i32 = row_value!(self.name, row, "id_kind");
+                    let name: String = row_value!(self.name, row, "name");
                     debug!("Got kind: {}: {}", id_kind, name);
                     sensor_dev.kinds.insert(id_kind, name.clone());
                     env_sensor_dev.kinds.insert(id_kind, name);
@@ -102,18 +277,19 @@ impl Database {
 
                 info!("🦏 {}: Loading data from view 'sensors'...", self.name);
                 sensor_dev.sensor_boards.clear();
-                for row in client.query("select * from sensors", &[]).unwrap() {
-                    let id_sensor: i32 = row.get("id_sensor");
-                    let id_kind: i32 = row.get("id_kind");
-                    let name: String = row.get("name");
-                    let family_code: Option<i16> = row.get("family_code");
-                    let address: i32 = row.get("address");
-                    let bit: i16 = row.get("bit");
+                for row in rows.sensors {
+                    let id_sensor: i32 = row_value!(self.name, row, "id_sensor");
+                    let id_kind: i32 = row_value!(self.name, row, "id_kind");
+                    let name: String = row_value!(self.name, row, "name");
+                    let family_code: Option<i16> = row_value!(self.name, row, "family_code");
+                    let address: i32 = row_value!(self.name, row, "address");
+                    let bit: i16 = row_value!(self.name, row, "bit");
                     let relay_agg: Vec<i32> = row.try_get("relay_agg").unwrap_or(vec![]);
                     let yeelight_agg: Vec<i32> = row.try_get("yeelight_agg").unwrap_or(vec![]);
+                    let plug_agg: Vec<i32> = row.try_get("plug_agg").unwrap_or(vec![]);
                     let tags: Vec<String> = row.try_get("tags").unwrap_or(vec![]);
                     debug!(
-                        "Got sensor: id_sensor={} kind={:?} name={:?} family_code={:?} address={} bit={} relay_agg={:?} yeelight_agg={:?} tags={:?}",
+                        "Got sensor: id_sensor={} kind={:?} name={:?} family_code={:?} address={} bit={} relay_agg={:?} yeelight_agg={:?} plug_agg={:?} tags={:?}",
                         id_sensor,
                         sensor_dev.kinds.get(&id_kind).unwrap(),
                         name,
@@ -122,6 +298,7 @@ impl Database {
                         bit,
                         relay_agg,
                         yeelight_agg,
+                        plug_agg,
                         tags,
                     );
                     sensor_dev.add_sensor(
@@ -133,18 +310,76 @@ impl Database {
                         bit as u8,
                         relay_agg,
                         yeelight_agg,
+                        plug_agg,
                         tags,
                     );
                 }
 
+                info!(
+                    "🦏 {}: Loading data from view 'virtual_sensors'...",
+                    self.name
+                );
+                sensor_dev.virtual_sensors.clear();
+                match rows.virtual_sensors {
+                    Ok(rows) => {
+                        for row in rows {
+                            let id_sensor: i32 = row_value!(self.name, row, "id_sensor");
+                            let id_kind: i32 = row_value!(self.name, row, "id_kind");
+                            let name: String = row_value!(self.name, row, "name");
+                            let expression: String = row_value!(self.name, row, "expression");
+                            let relay_agg: Vec<i32> = row.try_get("relay_agg").unwrap_or(vec![]);
+                            let yeelight_agg: Vec<i32> =
+                                row.try_get("yeelight_agg").unwrap_or(vec![]);
+                            let plug_agg: Vec<i32> = row.try_get("plug_agg").unwrap_or(vec![]);
+                            let tags: Vec<String> = row.try_get("tags").unwrap_or(vec![]);
+                            debug!(
+                                "Got virtual sensor: id_sensor={} kind={:?} name={:?} expression={:?} relay_agg={:?} yeelight_agg={:?} plug_agg={:?} tags={:?}",
+                                id_sensor,
+                                sensor_dev.kinds.get(&id_kind),
+                                name,
+                                expression,
+                                relay_agg,
+                                yeelight_agg,
+                                plug_agg,
+                                tags,
+                            );
+                            match Expr::parse(&expression) {
+                                Ok(expr) => {
+                                    sensor_dev.virtual_sensors.push(VirtualSensor {
+                                        id_sensor,
+                                        id_kind,
+                                        name,
+                                        source: expression,
+                                        expression: expr,
+                                        tags,
+                                        associated_relays: relay_agg,
+                                        associated_yeelights: yeelight_agg,
+                                        associated_plugs: plug_agg,
+                                        last_value: None,
+                                    });
+                                }
+                                Err(e) => {
+                                    error!(
+                                        "{}: virtual sensor {}: invalid expression {:?}: {}",
+                                        self.name, name, expression, e
+                                    );
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        warn!("{}: unable to load virtual sensors: {}", self.name, e);
+                    }
+                }
+
                 info!("🦏 {}: Loading data from view 'env_sensors'...", self.name);
                 env_sensor_dev.env_sensors.clear();
-                for row in client.query("select * from env_sensors", &[]).unwrap() {
-                    let id_sensor: i32 = row.get("id_sensor");
-                    let id_kind: i32 = row.get("id_kind");
-                    let name: String = row.get("name");
-                    let family_code: Option<i16> = row.get("family_code");
-                    let address: i32 = row.get("address");
+                for row in rows.env_sensors {
+                    let id_sensor: i32 = row_value!(self.name, row, "id_sensor");
+                    let id_kind: i32 = row_value!(self.name, row, "id_kind");
+                    let name: String = row_value!(self.name, row, "name");
+                    let family_code: Option<i16> = row_value!(self.name, row, "family_code");
+                    let address: i32 = row_value!(self.name, row, "address");
                     let relay_agg: Vec<i32> = row.try_get("relay_agg").unwrap_or(vec![]);
                     let yeelight_agg: Vec<i32> = row.try_get("yeelight_agg").unwrap_or(vec![]);
                     let tags: Vec<String> = row.try_get("tags").unwrap_or(vec![]);
@@ -172,22 +407,33 @@ impl Database {
                 }
 
                 info!("🦏 {}: Loading data from view 'relays'...", self.name);
-                for row in client.query("select * from relays", &[]).unwrap() {
-                    let id_relay: i32 = row.get("id_relay");
-                    let name: String = row.get("name");
-                    let family_code: Option<i16> = row.get("family_code");
-                    let address: i32 = row.get("address");
-                    let bit: i16 = row.get("bit");
-                    let pir_exclude: bool = row.get("pir_exclude");
-                    let pir_hold_secs = row.get("pir_hold_secs");
-                    let switch_hold_secs = row.get("switch_hold_secs");
-                    let initial_state: bool = row.get("initial_state");
-                    let pir_all_day: bool = row.get("pir_all_day");
+                //the boards are kept (current outputs), the relays are assigned again
+                for rb in &mut relay_dev.relay_boards {
+                    rb.relay = Default::default();
+                }
+                let mut device_ids = vec![];
+                for row in rows.relays {
+                    let id_relay: i32 = row_value!(self.name, row, "id_relay");
+                    device_ids.push(id_relay);
+                    let name: String = row_value!(self.name, row, "name");
+                    let family_code: Option<i16> = row_value!(self.name, row, "family_code");
+                    let address: i32 = row_value!(self.name, row, "address");
+                    let bit: i16 = row_value!(self.name, row, "bit");
+                    let pir_exclude: bool = row_value!(self.name, row, "pir_exclude");
+                    let pir_hold_secs = row_value!(self.name, row, "pir_hold_secs");
+                    let switch_hold_secs = row_value!(self.name, row, "switch_hold_secs");
+                    let initial_state: bool = row_value!(self.name, row, "initial_state");
+                    let pir_all_day: bool = row_value!(self.name, row, "pir_all_day");
                     let tags: Vec<String> = row.try_get("tags").unwrap_or(vec![]);
                     debug!(
                         "Got relay: id_relay={} name={:?} family_code={:?} address={} bit={} pir_exclude={} pir_hold_secs={:?} switch_hold_secs={:?} initial_state={} pir_all_day={} tags={:?}",
                         id_relay, name, family_code, address, bit, pir_exclude, pir_hold_secs, switch_hold_secs, initial_state, pir_all_day, tags
                     );
+                    for tag in &tags {
+                        if let Some(Err(e)) = AllNight::parse(tag) {
+                            warn!("{}: relay {}: tag {:?}: {}", self.name, name, tag, e);
+                        }
+                    }
                     relay_dev.add_relay(
                         &mut relays.relay,
                         id_relay,
@@ -205,15 +451,15 @@ impl Database {
                 }
 
                 info!("🦏 {}: Loading data from view 'yeelights'...", self.name);
-                relay_dev.yeelight.clear();
-                for row in client.query("select * from yeelights", &[]).unwrap() {
-                    let id_yeelight: i32 = row.get("id_yeelight");
-                    let name: String = row.get("name");
-                    let ip_address: String = row.get("ip_address");
-                    let pir_exclude: bool = row.get("pir_exclude");
-                    let pir_hold_secs = row.get("pir_hold_secs");
-                    let switch_hold_secs = row.get("switch_hold_secs");
-                    let pir_all_day: bool = row.get("pir_all_day");
+                for row in rows.yeelights {
+                    let id_yeelight: i32 = row_value!(self.name, row, "id_yeelight");
+                    device_ids.push(id_yeelight);
+                    let name: String = row_value!(self.name, row, "name");
+                    let ip_address: String = row_value!(self.name, row, "ip_address");
+                    let pir_exclude: bool = row_value!(self.name, row, "pir_exclude");
+                    let pir_hold_secs = row_value!(self.name, row, "pir_hold_secs");
+                    let switch_hold_secs = row_value!(self.name, row, "switch_hold_secs");
+                    let pir_all_day: bool = row_value!(self.name, row, "pir_all_day");
                     let tags: Vec<String> = row.try_get("tags").unwrap_or(vec![]);
                     debug!(
                         "Got yeelight: id_yeelight={} name={:?} ip_address={} pir_exclude={} pir_hold_secs={:?} switch_hold_secs={:?} pir_all_day={} tags={:?}",
@@ -232,30 +478,207 @@ impl Database {
                     );
                 }
 
+                info!("🦏 {}: Loading data from view 'smart_plugs'...", self.name);
+                match rows.smart_plugs {
+                    Ok(rows) => {
+                        for row in rows {
+                            let id_plug: i32 = row_value!(self.name, row, "id_plug");
+                            device_ids.push(id_plug);
+                            let name: String = row_value!(self.name, row, "name");
+                            let kind: String = row_value!(self.name, row, "kind");
+                            let host: String = row_value!(self.name, row, "host");
+                            let channel: Option<i16> = row.try_get("channel").unwrap_or(None);
+                            let pir_exclude: bool = row_value!(self.name, row, "pir_exclude");
+                            let pir_hold_secs = row_value!(self.name, row, "pir_hold_secs");
+                            let switch_hold_secs = row_value!(self.name, row, "switch_hold_secs");
+                            let pir_all_day: bool = row_value!(self.name, row, "pir_all_day");
+                            let tags: Vec<String> = row.try_get("tags").unwrap_or(vec![]);
+                            debug!(
+                                "Got smart plug: id_plug={} name={:?} kind={} host={} channel={:?} pir_exclude={} pir_hold_secs={:?} switch_hold_secs={:?} pir_all_day={} tags={:?}",
+                                id_plug, name, kind, host, channel, pir_exclude, pir_hold_secs, switch_hold_secs, pir_all_day, tags
+                            );
+                            let kind = match PlugKind::parse(&kind) {
+                                Some(kind) => kind,
+                                None => {
+                                    error!(
+                                        "{}: smart plug {}: unknown kind: {:?}",
+                                        self.name, name, kind
+                                    );
+                                    continue;
+                                }
+                            };
+                            relay_dev.add_smart_plug(
+                                &mut relays.relay,
+                                id_plug,
+                                name,
+                                kind,
+                                host,
+                                channel.unwrap_or(0) as u8,
+                                pir_exclude,
+                                pir_hold_secs,
+                                switch_hold_secs,
+                                pir_all_day,
+                                tags,
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        warn!("{}: unable to load smart plugs: {}", self.name, e);
+                    }
+                }
+
+                //devices removed from the database
+                relay_dev.yeelight.retain(|y| device_ids.contains(&y.id));
+                relay_dev.smart_plugs.retain(|p| device_ids.contains(&p.id));
+                relays.relay.retain(|d| device_ids.contains(&d.id));
+
+                info!(
+                    "🦏 {}: Loading data from view 'relay_schedules'...",
+                    self.name
+                );
+                relay_dev.schedules.clear();
+                match rows.relay_schedules {
+                    Ok(rows) => {
+                        for row in rows {
+                            let id_schedule: i32 = row_value!(self.name, row, "id_schedule");
+                            let id_relay: i32 = row_value!(self.name, row, "id_relay");
+                            let on_time: String = row_value!(self.name, row, "on_time");
+                            let off_time: String = row_value!(self.name, row, "off_time");
+                            let weekdays: Vec<i32> = row.try_get("weekdays").unwrap_or(vec![]);
+                            debug!(
+                                "Got relay schedule: id_schedule={} id_relay={} on_time={:?} off_time={:?} weekdays={:?}",
+                                id_schedule, id_relay, on_time, off_time, weekdays
+                            );
+                            match (
+                                ScheduleTime::parse(&on_time),
+                                ScheduleTime::parse(&off_time),
+                            ) {
+                                (Ok(on_time), Ok(off_time)) => {
+                                    relay_dev.schedules.push(RelaySchedule {
+                                        id_schedule,
+                                        id_relay,
+                                        on_time,
+                                        off_time,
+                                        weekdays,
+                                        active: None,
+                                    });
+                                }
+                                (on, off) => {
+                                    error!(
+                                        "{}: relay schedule #{}: invalid time: on={:?} off={:?}",
+                                        self.name,
+                                        id_schedule,
+                                        on.err(),
+                                        off.err()
+                                    );
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        warn!("{}: unable to load relay schedules: {}", self.name, e);
+                    }
+                }
+
+                info!("🦏 {}: Loading data from view 'relay_cron'...", self.name);
+                //jobs from the config file are kept
+                relay_dev.cron_jobs.retain(|job| job.id_job.is_none());
+                match rows.relay_cron {
+                    Ok(rows) => {
+                        for row in rows {
+                            let id_job: i32 = row_value!(self.name, row, "id_job");
+                            let expression: String = row_value!(self.name, row, "cron");
+                            let id_relay: Option<i32> = row_value!(self.name, row, "id_relay");
+                            let tag_group: Option<String> = row_value!(self.name, row, "tag_group");
+                            let id_yeelight: Option<i32> =
+                                row_value!(self.name, row, "id_yeelight");
+                            let id_plug: Option<i32> = row.try_get("id_plug").unwrap_or(None);
+                            let command: String = row_value!(self.name, row, "command");
+                            let duration: Option<i32> = row_value!(self.name, row, "duration");
+                            debug!(
+                                "Got cron job: id_job={} cron={:?} id_relay={:?} tag_group={:?} id_yeelight={:?} id_plug={:?} command={:?} duration={:?}",
+                                id_job, expression, id_relay, tag_group, id_yeelight, id_plug, command, duration
+                            );
+                            match (
+                                CronExpr::parse(&expression),
+                                CronJob::parse_command(&command),
+                            ) {
+                                (Ok(cron), Some(command)) => {
+                                    relay_dev.cron_jobs.push(CronJob {
+                                        id_job: Some(id_job),
+                                        name: format!("#{}", id_job),
+                                        expression,
+                                        cron,
+                                        id_relay,
+                                        tag_group,
+                                        id_yeelight,
+                                        id_plug,
+                                        command,
+                                        duration: duration
+                                            .map(|secs| Duration::from_secs(secs as u64)),
+                                        last_run: None,
+                                    });
+                                }
+                                (cron, command) => {
+                                    error!(
+                                        "{}: cron job #{}: invalid entry: cron={:?} command={:?}",
+                                        self.name,
+                                        id_job,
+                                        cron.err(),
+                                        command
+                                    );
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        warn!("{}: unable to load cron jobs: {}", self.name, e);
+                    }
+                }
+
                 info!("🦏 {}: Loading data from view 'rfid_tags'...", self.name);
                 rfid_tag.clear();
-                for row in client.query("select * from rfid_tags", &[]).unwrap() {
-                    let id_tag: i32 = row.get("id_tag");
-                    let name: String = row.get("name");
+                for row in rows.rfid_tags {
+                    let id_tag: i32 = row_value!(self.name, row, "id_tag");
+                    let name: String = row_value!(self.name, row, "name");
                     let tags: Vec<String> = row.try_get("tags").unwrap_or(vec![]);
                     let relay_agg: Vec<i32> = row.try_get("relay_agg").unwrap_or(vec![]);
+                    //optional restriction columns
+                    let valid_weekdays: Option<String> =
+                        row.try_get("valid_weekdays").unwrap_or(None);
+                    let valid_hours: Option<String> = row.try_get("valid_hours").unwrap_or(None);
+                    let expires: Option<String> = row.try_get("expires").unwrap_or(None);
+                    let max_uses_per_day: Option<i32> =
+                        row.try_get("max_uses_per_day").unwrap_or(None);
+                    let pin: Option<String> = row.try_get("pin").unwrap_or(None);
+                    let restrictions = RfidRestrictions {
+                        weekdays: valid_weekdays
+                            .and_then(|w| RfidRestrictions::parse_weekdays(&w))
+                            .unwrap_or_default(),
+                        hours: valid_hours.and_then(|h| RfidRestrictions::parse_hours(&h)),
+                        expires: expires
+                            .and_then(|e| NaiveDate::parse_from_str(e.trim(), "%Y-%m-%d").ok()),
+                        max_uses_per_day: max_uses_per_day.map(|m| m.max(0) as u32),
+                    };
                     debug!(
-                        "Got RFID tag: id_tag={} name={:?}, tags={:?}, relay_agg={:?}",
-                        id_tag, name, tags, relay_agg
+                        "Got RFID tag: id_tag={} name={:?}, tags={:?}, relay_agg={:?}, restrictions={:?}",
+                        id_tag, name, tags, relay_agg, restrictions
                     );
                     let new_tag = RfidTag {
                         id_tag,
+                        pin: PinHash::load(&name, pin.as_deref()),
                         name,
                         tags,
                         associated_relays: relay_agg,
+                        restrictions,
                     };
                     rfid_tag.push(new_tag);
                 }
             }
-            None => {
+            Err(e) => {
                 error!(
-                    "{}: no active database connection -> cannot load config",
-                    self.name
+                    "{}: unable to load devices, the current ones are kept: {}",
+                    self.name, e
                 );
             }
         }
@@ -266,21 +689,43 @@ impl Database {
         let mut reload_devices = true;
         let mut flush_data = Instant::now();
         let mut influx_interval = Instant::now();
+        let mut influx_env_interval = Instant::now();
+        let mut cesspool_check = Instant::now();
 
         let mut builder =
             SslConnector::builder(SslMethod::tls()).expect("SslConnector::builder error");
         builder.set_verify(SslVerifyMode::NONE); //allow self-signed certificates
         let connector = MakeTlsConnector::new(builder.build());
 
+        //on exit all queued tasks are processed before the final flush
+        let mut shutdown = false;
+
         loop {
-            if worker_cancel_flag.load(Ordering::SeqCst) {
+            if worker_cancel_flag.load(Ordering::SeqCst) && !shutdown {
                 debug!("Got terminate signal from main");
-                self.flush_counter_data();
-                break;
+                info!(
+                    "{}: 🏁 processing the queued tasks before exit...",
+                    self.name
+                );
+                shutdown = true;
             }
+            systemd::heartbeat(&self.name);
 
-            match self.receiver.try_recv() {
-                Ok(t) => {
+            //wait for a task, the periodic work is done at least every 50ms
+            let task = if shutdown {
+                self.receiver.try_recv().ok()
+            } else {
+                tokio::select! {
+                    t = self.receiver.recv() => t,
+                    _ = tokio::time::sleep(Duration::from_millis(50)) => None,
+                }
+            };
+            match task {
+                None if shutdown => {
+                    self.flush_on_exit().await;
+                    break;
+                }
+                Some(t) => {
                     debug!(
                         "Received DbTask: command: {:?} value: {:?}",
                         t.command, t.value
@@ -288,6 +733,12 @@ impl Database {
                     match t.command {
                         CommandCode::ReloadDevices => {
                             info!("{}: Reload devices requested", self.name);
+                            if self.config.general.devices == DeviceSource::Config {
+                                warn!(
+                                    "{}: devices are defined in the config file, restart to reload them",
+                                    self.name
+                                );
+                            }
                             reload_devices = true;
                         }
                         CommandCode::IncrementSensorCounter => match t.value {
@@ -316,6 +767,33 @@ impl Database {
                             }
                             _ => {}
                         },
+                        CommandCode::IncrementPlugCounter => match t.value {
+                            Some(id) => {
+                                let counter = self.plug_counters.entry(id).or_insert(0 as u32);
+                                *counter += 1;
+                            }
+                            _ => {}
+                        },
+                        CommandCode::DeviceSwitched { kind, on, time } => match t.value {
+                            Some(id) => {
+                                self.ontime.write().unwrap().switched(kind, id, on, time);
+                            }
+                            _ => {}
+                        },
+                        CommandCode::RfidScanned(scan) => {
+                            if self.config.general.storage == StorageBackend::Postgres {
+                                self.pg_rfid_scans.push(scan);
+                            }
+                        }
+                        CommandCode::CirculationRun { trigger, time } => {
+                            if self.config.general.storage == StorageBackend::Postgres {
+                                self.pg_circulation_runs.push((
+                                    time,
+                                    trigger.as_str(),
+                                    t.value.unwrap_or_default(),
+                                ));
+                            }
+                        }
                         CommandCode::UpdateSensorStateOn => match t.value {
                             Some(id) => {
                                 if self.influxdb_url.is_some() {
@@ -354,8 +832,25 @@ impl Database {
                             }
                             _ => {}
                         },
+                        CommandCode::UpdateVirtualSensorStateOn => match t.value {
+                            Some(id) => {
+                                if self.influxdb_url.is_some() {
+                                    self.influx_virtual_values.insert(id, true);
+                                }
+                            }
+                            _ => {}
+                        },
+                        CommandCode::UpdateVirtualSensorStateOff => match t.value {
+                            Some(id) => {
+                                if self.influxdb_url.is_some() {
+                                    self.influx_virtual_values.insert(id, false);
+                                }
+                            }
+                            _ => {}
+                        },
                         CommandCode::UpdateCesspoolLevel => match t.value {
                             Some(level) => {
+                                self.cesspool_update(level as u8);
                                 if self.influxdb_url.is_some() {
                                     self.influx_cesspool_level = Some(level as u8);
                                 }
@@ -365,13 +860,92 @@ impl Database {
                         CommandCode::UpdateDailyEnergyYield => {
                             self.daily_yield_energy = t.value;
                         }
+                        CommandCode::IncrementFrostProtectionTime => match t.value {
+                            Some(secs) => {
+                                if self.influxdb_url.is_some() {
+                                    *self.influx_frost_protection_secs.get_or_insert(0) += secs;
+                                }
+                            }
+                            _ => {}
+                        },
+                        CommandCode::UpdateSunAzimuth => {
+                            if self.influxdb_url.is_some() {
+                                self.influx_sun_azimuth = t.value.map(|v| v as f64 / 100.0);
+                            }
+                        }
+                        CommandCode::UpdateSunElevation => {
+                            if self.influxdb_url.is_some() {
+                                self.influx_sun_elevation = t.value.map(|v| v as f64 / 100.0);
+                            }
+                        }
+                        CommandCode::StoreTemperature => match t.value {
+                            Some(id_sensor) => {
+                                let temp = self
+                                    .env_sensor_devices
+                                    .read()
+                                    .unwrap()
+                                    .env_sensors
+                                    .iter()
+                                    .find(|s| s.id_sensor == id_sensor)
+                                    .and_then(|s| s.last_temperature);
+                                if let Some(temp) = temp {
+                                    self.pg_insert_temperature(id_sensor, temp);
+                                }
+                            }
+                            _ => {}
+                        },
+                        CommandCode::AlarmArmed
+                        | CommandCode::AlarmDisarmed
+                        | CommandCode::AlarmTriggered => {
+                            let event = match t.command {
+                                CommandCode::AlarmArmed => "armed",
+                                CommandCode::AlarmDisarmed => "disarmed",
+                                _ => "triggered",
+                            };
+                            if self.config.general.storage == StorageBackend::Postgres {
+                                self.pg_alarm_events.push((event, t.value));
+                            }
+                            if self.influxdb_url.is_some() {
+                                self.influx_alarm_events.push((event, t.value));
+                            }
+                        }
+                        CommandCode::StoreHumidity => match t.value {
+                            Some(id_sensor) => {
+                                let humidity = self
+                                    .env_sensor_devices
+                                    .read()
+                                    .unwrap()
+                                    .env_sensors
+                                    .iter()
+                                    .find(|s| s.id_sensor == id_sensor)
+                                    .and_then(|s| s.last_humidity);
+                                if let Some(humidity) = humidity {
+                                    self.pg_insert_humidity(id_sensor, humidity);
+                                }
+                            }
+                            _ => {}
+                        },
                     }
                 }
                 _ => (),
             }
 
             //(re)connect / load config when necessary
-            if self.conn.is_none() {
+            if self.config.general.storage == StorageBackend::Sqlite {
+                if self.sqlite.is_none() {
+                    let path = &self.config.sqlite.path;
+                    info!("🪶 {}: Opening database: {}", self.name, path);
+                    match storage::open_sqlite(path) {
+                        Ok(conn) => {
+                            self.sqlite = Some(conn);
+                            info!("{}: Database opened successfully", self.name);
+                        }
+                        Err(e) => {
+                            self.errors.report(&DatabaseError::Sqlite(e));
+                        }
+                    }
+                }
+            } else if self.conn.is_none() {
                 debug!("Loading db config...");
                 self.load_db_config();
 
@@ -395,28 +969,51 @@ impl Database {
                         Ok(c) => {
                             self.conn = Some(c);
                             info!("{}: Connected successfully", self.name);
+                            self.pg_listen();
                         }
                         Err(e) => {
                             self.conn = None;
-                            error!("{}: PostgreSQL connection error: {:?}", self.name, e);
+                            self.errors.report(&DatabaseError::Connect(e));
                             info!("{}: Trying to reconnect...", self.name);
                         }
                     }
                 } else {
-                    error!(
-                        "{}: postgres config is not OK, check the config file",
-                        self.name
-                    );
+                    self.errors.report(&DatabaseError::Config);
                 }
             }
 
+            //device changes announced by a database trigger or a manual NOTIFY
+            if self.pg_reload_notified() {
+                info!("{}: Reload devices notified by the database", self.name);
+                reload_devices = true;
+            }
+
             //load devices / do idle SQL tasks
-            if self.conn.is_some() {
-                if reload_devices && !self.disable_onewire {
+            if self.connected() {
+                if reload_devices
+                    && !self.disable_onewire
+                    && self.config.general.devices == DeviceSource::Database
+                {
                     info!("{}: loading devices from database...", self.name);
                     self.load_devices();
                     reload_devices = false;
                 }
+                if !self.cesspool_history_loaded {
+                    self.load_cesspool_history();
+                    self.cesspool_history_loaded = true;
+                }
+                if !self.energy_costs_loaded {
+                    self.load_energy_costs();
+                    self.energy_costs_loaded = true;
+                }
+                if !self.ontime_loaded {
+                    self.load_ontime();
+                    self.ontime_loaded = true;
+                }
+                if !self.meter_totals_loaded {
+                    self.load_meter_totals();
+                    self.meter_totals_loaded = true;
+                }
                 if flush_data.elapsed().as_secs() > 10 {
                     //flush all data from hashmaps to database
                     debug!("flushing local data to db...");
@@ -429,6 +1026,27 @@ impl Database {
                         }
                     }
 
+                    //save current month energy costs
+                    if self.energy_costs.read().unwrap().dirty {
+                        self.pg_update_energy_costs();
+                    }
+
+                    //save today's grid import/export
+                    if self.energy_costs.read().unwrap().days_dirty {
+                        self.pg_update_net_metering();
+                    }
+
+                    //save relay/yeelight/plug on-time
+                    self.ontime.write().unwrap().checkpoint();
+                    if !self.ontime.read().unwrap().pending.is_empty() {
+                        self.pg_update_ontime();
+                    }
+
+                    //save water/gas meter totals
+                    if self.meter_totals.read().unwrap().dirty {
+                        self.pg_update_meter_totals();
+                    }
+
                     flush_data = Instant::now();
                 }
             }
@@ -439,38 +1057,662 @@ impl Database {
                 && influx_interval.elapsed().as_secs() > 10
             {
                 debug!("flushing sensor counters to influxdb...");
-                let _ = self.influx_flush_counter_data().compat().await;
+                let result = self.influx_flush_counter_data().await;
+                self.reported(result);
                 influx_interval = Instant::now();
             }
             //write monitored sensor/relay values to influxdb
             if self.influxdb_url.is_some()
-                && (!self.influx_sensor_values.is_empty() || !self.influx_relay_values.is_empty())
+                && (!self.influx_sensor_values.is_empty()
+                    || !self.influx_relay_values.is_empty()
+                    || !self.influx_virtual_values.is_empty())
             {
                 debug!("flushing sensor/relay values to influxdb...");
-                let _ = self.influx_flush_values_data().compat().await;
+                let result = self.influx_flush_values_data().await;
+                self.reported(result);
             }
-            //write cesspool level to postgres & influxdb
+            //write environment sensor readings to influxdb
+            if self.influxdb_url.is_some() && influx_env_interval.elapsed().as_secs() > 10 {
+                influx_env_interval = Instant::now();
+                let result = self.influx_flush_env_data().await;
+                self.reported(result);
+            }
+            //write cesspool level changes to postgres
+            if self.conn.is_some() && !self.pg_cesspool_levels.is_empty() {
+                debug!("flushing cesspool levels to postgres...");
+                self.pg_insert_cesspool_levels();
+            }
+            //repeat the threshold alerts on the next day
+            if cesspool_check.elapsed() > Duration::from_secs(CESSPOOL_CHECK_INTERVAL_SECS) {
+                cesspool_check = Instant::now();
+                self.cesspool_alert();
+            }
+            //write alarm event log to postgres
+            if self.conn.is_some() && !self.pg_alarm_events.is_empty() {
+                debug!("flushing alarm events to postgres...");
+                self.pg_insert_alarm_events();
+            }
+            //write rfid access log to postgres
+            if self.conn.is_some() && !self.pg_rfid_scans.is_empty() {
+                debug!("flushing rfid access log to postgres...");
+                self.pg_insert_rfid_scans();
+            }
+            //write circulation pump runs to postgres
+            if self.conn.is_some() && !self.pg_circulation_runs.is_empty() {
+                debug!("flushing circulation pump runs to postgres...");
+                self.pg_insert_circulation_runs();
+            }
+            //write alarm events to influxdb
+            if self.influxdb_url.is_some() && !self.influx_alarm_events.is_empty() {
+                debug!("flushing alarm events to influxdb...");
+                let result = self.influx_flush_alarm_events().await;
+                self.reported(result);
+            }
+            //write cesspool level to influxdb
             if self.influxdb_url.is_some() && self.influx_cesspool_level.is_some() {
-                debug!("flushing cesspool level to postgres...");
-                self.pg_update_cesspool_level(self.influx_cesspool_level.unwrap() as i16);
                 debug!("flushing cesspool level to influxdb...");
-                let _ = self.influx_flush_cesspool_level().compat().await;
+                let result = self.influx_flush_cesspool_level().await;
+                self.reported(result);
+            }
+            //write frost protection run time to influxdb
+            if self.influxdb_url.is_some() && self.influx_frost_protection_secs.is_some() {
+                debug!("flushing frost protection time to influxdb...");
+                let result = self.influx_flush_frost_protection().await;
+                self.reported(result);
+            }
+            //write sun position to influxdb
+            if self.influxdb_url.is_some()
+                && self.influx_sun_azimuth.is_some()
+                && self.influx_sun_elevation.is_some()
+            {
+                debug!("flushing sun position to influxdb...");
+                let result = self.influx_flush_sun_position().await;
+                self.reported(result);
+            }
+            //write 1-wire bus statistics to influxdb
+            if self.influxdb_url.is_some() && self.bus_metrics.read().unwrap().dirty {
+                debug!("flushing 1-wire bus statistics to influxdb...");
+                let result = self.influx_flush_bus_metrics().await;
+                self.reported(result);
             }
-
-            tokio::time::sleep(Duration::from_millis(50)).await;
         }
+        systemd::heartbeat_stop(&self.name);
         info!("{}: task stopped", self.name);
         Ok(())
     }
 
-    fn increment_cycles(&mut self, table_name: String, id_sensor: i32, counter: u32) -> bool {
+    /// Writes all buffered data and closes the database connection
+    async fn flush_on_exit(&mut self) {
+        if self.connected() {
+            debug!("final flush of local data to db...");
+            self.flush_counter_data();
+            if let Some(val) = self.daily_yield_energy {
+                if self.update_daily_energy_yield(val as f64 / 100.0) {
+                    self.daily_yield_energy = None;
+                }
+            }
+            if self.energy_costs.read().unwrap().dirty {
+                self.pg_update_energy_costs();
+            }
+            if self.energy_costs.read().unwrap().days_dirty {
+                self.pg_update_net_metering();
+            }
+            self.ontime.write().unwrap().checkpoint();
+            if !self.ontime.read().unwrap().pending.is_empty() {
+                self.pg_update_ontime();
+            }
+            if self.meter_totals.read().unwrap().dirty {
+                self.pg_update_meter_totals();
+            }
+            if !self.pg_cesspool_levels.is_empty() {
+                self.pg_insert_cesspool_levels();
+            }
+            if !self.pg_alarm_events.is_empty() {
+                self.pg_insert_alarm_events();
+            }
+            if !self.pg_rfid_scans.is_empty() {
+                self.pg_insert_rfid_scans();
+            }
+            if !self.pg_circulation_runs.is_empty() {
+                self.pg_insert_circulation_runs();
+            }
+        }
+
+        if self.influxdb_url.is_some() {
+            debug!("final flush of influxdb data...");
+            if !self.influx_sensor_counters.is_empty() {
+                let result = self.influx_flush_counter_data().await;
+                self.reported(result);
+            }
+            if !self.influx_sensor_values.is_empty()
+                || !self.influx_relay_values.is_empty()
+                || !self.influx_virtual_values.is_empty()
+            {
+                let result = self.influx_flush_values_data().await;
+                self.reported(result);
+            }
+            if !self.influx_alarm_events.is_empty() {
+                let result = self.influx_flush_alarm_events().await;
+                self.reported(result);
+            }
+            if self.influx_cesspool_level.is_some() {
+                let result = self.influx_flush_cesspool_level().await;
+                self.reported(result);
+            }
+            if self.influx_frost_protection_secs.is_some() {
+                let result = self.influx_flush_frost_protection().await;
+                self.reported(result);
+            }
+        }
+
+        //close the connection cleanly instead of dropping it
+        if let Some(client) = self.conn.take() {
+            match client.close() {
+                Ok(_) => info!("🦏 {}: PostgreSQL connection closed", self.name),
+                Err(e) => error!(
+                    "{}: error closing PostgreSQL connection: {:?}",
+                    self.name, e
+                ),
+            }
+        }
+        if let Some(conn) = self.sqlite.take() {
+            match conn.close() {
+                Ok(_) => info!("🪶 {}: SQLite database closed", self.name),
+                Err((_, e)) => error!("{}: error closing SQLite database: {:?}", self.name, e),
+            }
+        }
+    }
+
+    /// Reports a failed connection or write, the value is returned on success
+    fn reported<T>(&self, result: std::result::Result<T, DatabaseError>) -> Option<T> {
+        result.map_err(|e| self.errors.report(&e)).ok()
+    }
+
+    fn increment_cycles(&mut self, table_name: String, counters: &HashMap<i32, u32>) -> bool {
+        if counters.is_empty() {
+            return true;
+        }
+        let result = match self.storage() {
+            Some(storage) => storage.increment_cycles(&table_name, counters),
+            None => return false,
+        };
+        match result {
+            Ok(_) => true,
+            Err(e) => {
+                error!(
+                    "{}: error updating {} counters: {}",
+                    self.name, table_name, e
+                );
+                self.disconnect();
+                false
+            }
+        }
+    }
+
+    fn pg_listen(&mut self) {
+        if let Some(client) = self.conn.borrow_mut() {
+            let query = format!("listen {}", RELOAD_CHANNEL);
+            if let Err(e) = client.batch_execute(&query) {
+                warn!("{}: unable to listen for device reloads: {}", self.name, e);
+            }
+        }
+    }
+
+    //checks the pending notifications without blocking
+    fn pg_reload_notified(&mut self) -> bool {
+        let mut notified = false;
+        let mut failed = false;
+        if let Some(client) = self.conn.borrow_mut() {
+            for notification in client.notifications().iter() {
+                match notification {
+                    Ok(n) => notified |= n.channel() == RELOAD_CHANNEL,
+                    Err(e) => {
+                        error!("{}: notification error: {}", self.name, e);
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+        }
+        if failed {
+            self.conn = None;
+        }
+        notified
+    }
+
+    fn update_daily_energy_yield(&mut self, value: f64) -> bool {
         match self.conn.borrow_mut() {
             Some(client) => {
-                let query = format!(
-                    "update {} set cycles=cycles+$1 where id_{}=$2",
-                    table_name, table_name
+                let query = "select * from daily_energy_yield_upsert($1)";
+                let result = client.execute(query, &[&(value)]);
+                match result {
+                    Ok(_) => {
+                        return true;
+                    }
+                    Err(e) => {
+                        error!("{}: SQL error, query={:?}, error: {}", self.name, query, e);
+                        self.conn = None;
+                    }
+                }
+            }
+            _ => {}
+        }
+        false
+    }
+
+    fn cesspool_update(&mut self, level: u8) {
+        let (previous, emptying) = {
+            let mut history = self.cesspool_history.write().unwrap();
+            let previous = history.current_level();
+            (previous, history.add_level(Utc::now(), level))
+        };
+        if previous != Some(level) && self.config.general.storage == StorageBackend::Postgres {
+            self.pg_cesspool_levels
+                .push((SystemTime::now(), level as i16));
+        }
+
+        if let Some(emptying) = emptying {
+            info!(
+                "{}: 🛢️ cesspool emptied: {}% -> {}%",
+                self.name, emptying.level_before, emptying.level_after
+            );
+            self.cesspool_alerted = None;
+            self.pg_insert_cesspool_emptied(
+                emptying.level_before as i16,
+                emptying.level_after as i16,
+            );
+        }
+
+        self.cesspool_show_prediction(level);
+        self.cesspool_alert();
+    }
+
+    fn cesspool_severity(&self, level: u8) -> Option<Severity> {
+        match (self.cesspool_critical_level, self.cesspool_notify_level) {
+            (Some(critical), _) if level >= critical => Some(Severity::Critical),
+            (_, Some(warning)) if level >= warning => Some(Severity::Warning),
+            _ => None,
+        }
+    }
+
+    /// Days until full on the "cesspool" lcd screen
+    fn cesspool_show_prediction(&mut self, level: u8) {
+        let days = self
+            .cesspool_history
+            .read()
+            .unwrap()
+            .days_until_full(Utc::now());
+        if !self.cesspool_screen {
+            self.cesspool_screen = true;
+            let _ = self.lcd_transmitter.send(LcdTask {
+                command: LcdTaskCommand::AddScreen {
+                    screen: CESSPOOL_LCD_SCREEN.to_string(),
+                    priority: ScreenPriority::Info,
+                },
+                int_arg: 0,
+                string_arg: None,
+            });
+        }
+        let lines = [
+            format!("cesspool: {}%", level),
+            match days {
+                Some(days) => format!("full in {:.0} days", days),
+                None => "full in: unknown".to_string(),
+            },
+        ];
+        for (row, text) in lines.iter().enumerate() {
+            let _ = self.lcd_transmitter.send(LcdTask {
+                command: LcdTaskCommand::SetScreenLine {
+                    screen: CESSPOOL_LCD_SCREEN.to_string(),
+                },
+                int_arg: row as u8,
+                string_arg: Some(text.clone()),
+            });
+        }
+    }
+
+    /// Notifies about the need of booking the tanker: warning and critical
+    /// thresholds, repeated daily while the level is above them
+    fn cesspool_alert(&mut self) {
+        let (level, predicted) = {
+            let history = self.cesspool_history.read().unwrap();
+            match history.current_level() {
+                Some(level) => (level, history.predict_full()),
+                None => return,
+            }
+        };
+        let severity = match self.cesspool_severity(level) {
+            Some(severity) => severity,
+            None => {
+                self.cesspool_alerted = None;
+                return;
+            }
+        };
+        let today = Local::now().date_naive();
+        if let Some((alerted, day)) = self.cesspool_alerted {
+            if alerted >= severity && day == today {
+                return;
+            }
+        }
+        self.cesspool_alerted = Some((severity, today));
+
+        let threshold = match severity {
+            Severity::Critical => self.cesspool_critical_level,
+            _ => self.cesspool_notify_level,
+        }
+        .unwrap_or_default();
+        let predicted = match predicted {
+            Some(time) => units::date(time),
+            None => "unknown".to_string(),
+        };
+        warn!(
+            "{}: 🛢️ cesspool level {}% reached the {} {}% threshold, predicted full: {}",
+            self.name,
+            level,
+            severity.as_str(),
+            threshold,
+            predicted
+        );
+        let _ = self.notify_transmitter.send(Notification::new(
+            "cesspool",
+            severity,
+            format!("level {} threshold reached", severity.as_str()),
+            format!(
+                "cesspool level {}% reached the {}% threshold, predicted full: {}",
+                level, threshold, predicted
+            ),
+        ));
+        if let Some(ref cmd) = self.cesspool_notify_script {
+            let mut cmd = cmd.clone();
+            cmd = str::replace(&cmd, "%level%", &level.to_string());
+            cmd = str::replace(&cmd, "%predicted%", &predicted);
+            cmd = str::replace(&cmd, "%severity%", severity.as_str());
+            thread::spawn(move || StateMachine::run_shell_command(cmd));
+        }
+    }
+
+    fn load_cesspool_history(&mut self) {
+        match self.conn.borrow_mut() {
+            Some(client) => {
+                info!(
+                    "🦏 {}: Loading data from view 'cesspool_history'...",
+                    self.name
+                );
+                let mut loaded = CesspoolHistory::default();
+                match client.query(
+                    "select ts, val from cesspool_history where ts > now() - make_interval(days => $1::int) order by ts",
+                    &[&(CESSPOOL_HISTORY_DAYS as i32)],
+                ) {
+                    Ok(rows) => {
+                        for row in rows {
+                            let ts: SystemTime = row.get("ts");
+                            let val: i16 = row.get("val");
+                            let _ = loaded.add_level(ts.into(), val as u8);
+                        }
+                    }
+                    Err(e) => {
+                        warn!("{}: unable to load cesspool history: {}", self.name, e);
+                        return;
+                    }
+                }
+                //keep the levels which came before the history was loaded
+                let mut history = self.cesspool_history.write().unwrap();
+                for entry in history.levels.iter() {
+                    let _ = loaded.add_level(entry.time, entry.level);
+                }
+                *history = loaded;
+                //the current level may be already above threshold: don't notify twice a day
+                let level = history.current_level();
+                drop(history);
+                if let Some(severity) = level.and_then(|level| self.cesspool_severity(level)) {
+                    self.cesspool_alerted = Some((severity, Local::now().date_naive()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn load_energy_costs(&mut self) {
+        match self.conn.borrow_mut() {
+            Some(client) => {
+                info!(
+                    "🦏 {}: Loading data from table 'energy_costs'...",
+                    self.name
+                );
+                match client.query(
+                    "select month, imported_kwh, exported_kwh, pv_yield_kwh, relays_kwh::text from energy_costs order by month",
+                    &[],
+                ) {
+                    Ok(rows) => {
+                        let mut costs = self.energy_costs.write().unwrap();
+                        for row in rows {
+                            let relays_kwh: String = row.get("relays_kwh");
+                            costs.restore(MonthlyCost {
+                                month: row.get("month"),
+                                imported_kwh: row.get("imported_kwh"),
+                                exported_kwh: row.get("exported_kwh"),
+                                pv_yield_kwh: row.get("pv_yield_kwh"),
+                                relays_kwh: serde_json::from_str(&relays_kwh).unwrap_or_default(),
+                                ..Default::default()
+                            });
+                        }
+                    }
+                    Err(e) => {
+                        warn!("{}: unable to load energy costs: {}", self.name, e);
+                    }
+                }
+                info!(
+                    "🦏 {}: Loading data from table 'net_metering'...",
+                    self.name
+                );
+                match client.query(
+                    "select day::text, imported_kwh, exported_kwh, import_total_kwh, export_total_kwh from net_metering where day > current_date - $1::int order by day",
+                    &[&(NET_METERING_HISTORY_DAYS as i32)],
+                ) {
+                    Ok(rows) => {
+                        let mut costs = self.energy_costs.write().unwrap();
+                        for row in rows {
+                            costs.restore_day(DailyNetMetering {
+                                day: row.get("day"),
+                                imported_kwh: row.get("imported_kwh"),
+                                exported_kwh: row.get("exported_kwh"),
+                                import_total_kwh: row.get("import_total_kwh"),
+                                export_total_kwh: row.get("export_total_kwh"),
+                            });
+                        }
+                    }
+                    Err(e) => {
+                        warn!("{}: unable to load net metering: {}", self.name, e);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn pg_update_net_metering(&mut self) {
+        let day = {
+            let mut costs = self.energy_costs.write().unwrap();
+            costs.days_dirty = false;
+            match costs.days.last() {
+                Some(day) => day.clone(),
+                None => return,
+            }
+        };
+        match self.conn.borrow_mut() {
+            Some(client) => {
+                let query = "insert into net_metering (day, imported_kwh, exported_kwh, import_total_kwh, export_total_kwh) values ($1::text::date, $2, $3, $4, $5) on conflict (day) do update set imported_kwh=excluded.imported_kwh, exported_kwh=excluded.exported_kwh, import_total_kwh=excluded.import_total_kwh, export_total_kwh=excluded.export_total_kwh";
+                let result = client.execute(
+                    query,
+                    &[
+                        &day.day,
+                        &day.imported_kwh,
+                        &day.exported_kwh,
+                        &day.import_total_kwh,
+                        &day.export_total_kwh,
+                    ],
+                );
+                if let Err(e) = result {
+                    error!("{}: SQL error, query={:?}, error: {}", self.name, query, e);
+                    self.conn = None;
+                    self.energy_costs.write().unwrap().days_dirty = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn load_ontime(&mut self) {
+        match self.conn.borrow_mut() {
+            Some(client) => {
+                info!(
+                    "🦏 {}: Loading data from table 'device_ontime'...",
+                    self.name
+                );
+                match client.query(
+                    "select kind, id, day::text, on_secs from device_ontime where day > current_date - $1::int order by day",
+                    &[&(ONTIME_HISTORY_DAYS as i32)],
+                ) {
+                    Ok(rows) => {
+                        let mut ontime = self.ontime.write().unwrap();
+                        for row in rows {
+                            let kind: String = row.get("kind");
+                            if let Some(kind) = DeviceKind::parse(&kind) {
+                                ontime.restore_day(DailyOnTime {
+                                    kind,
+                                    id: row.get("id"),
+                                    day: row.get("day"),
+                                    on_secs: row.get("on_secs"),
+                                });
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        warn!("{}: unable to load device on-time: {}", self.name, e);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn pg_update_ontime(&mut self) {
+        let pending = std::mem::take(&mut self.ontime.write().unwrap().pending);
+        match self.conn.borrow_mut() {
+            Some(client) => {
+                //add the collected seconds to the daily rows in a single statement
+                let mut kinds: Vec<&str> = vec![];
+                let mut ids: Vec<i32> = vec![];
+                let mut days: Vec<String> = vec![];
+                let mut secs: Vec<f64> = vec![];
+                for ((kind, id, day), on_secs) in &pending {
+                    kinds.push(kind.as_str());
+                    ids.push(*id);
+                    days.push(day.clone());
+                    secs.push(*on_secs);
+                }
+                let query = "insert into device_ontime (kind, id, day, on_secs) select unnest($1::text[]), unnest($2::int[]), unnest($3::text[])::date, unnest($4::float8[]) on conflict (kind, id, day) do update set on_secs=device_ontime.on_secs+excluded.on_secs";
+                let result = client.execute(query, &[&kinds, &ids, &days, &secs]);
+                if let Err(e) = result {
+                    error!("{}: SQL error, query={:?}, error: {}", self.name, query, e);
+                    self.conn = None;
+                    //retry after reconnecting
+                    let mut ontime = self.ontime.write().unwrap();
+                    for (key, on_secs) in pending {
+                        *ontime.pending.entry(key).or_insert(0.0) += on_secs;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn load_meter_totals(&mut self) {
+        match self.conn.borrow_mut() {
+            Some(client) => {
+                info!(
+                    "🦏 {}: Loading data from table 'meter_totals'...",
+                    self.name
+                );
+                match client.query("select name, pulses from meter_totals", &[]) {
+                    Ok(rows) => {
+                        let mut totals = self.meter_totals.write().unwrap();
+                        for row in rows {
+                            totals.restore(row.get("name"), row.get("pulses"));
+                        }
+                    }
+                    Err(e) => {
+                        warn!("{}: unable to load meter totals: {}", self.name, e);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn pg_update_meter_totals(&mut self) {
+        let (names, pulses): (Vec<String>, Vec<i64>) = {
+            let mut totals = self.meter_totals.write().unwrap();
+            totals.dirty = false;
+            totals.pulses.iter().map(|(n, p)| (n.clone(), *p)).unzip()
+        };
+        match self.conn.borrow_mut() {
+            Some(client) => {
+                let query = "insert into meter_totals (name, pulses, updated) select unnest($1::text[]), unnest($2::int8[]), now() on conflict (name) do update set pulses=excluded.pulses, updated=excluded.updated";
+                if let Err(e) = client.execute(query, &[&names, &pulses]) {
+                    error!("{}: SQL error, query={:?}, error: {}", self.name, query, e);
+                    self.conn = None;
+                    self.meter_totals.write().unwrap().dirty = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn pg_update_energy_costs(&mut self) {
+        let month = {
+            let mut costs = self.energy_costs.write().unwrap();
+            costs.dirty = false;
+            match costs.months.last() {
+                Some(month) => month.clone(),
+                None => return,
+            }
+        };
+        match self.conn.borrow_mut() {
+            Some(client) => {
+                let query = "insert into energy_costs (month, imported_kwh, exported_kwh, pv_yield_kwh, self_consumed_kwh, import_cost, export_revenue, self_consumption_savings, net_cost, relays_kwh) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10::text::jsonb) on conflict (month) do update set imported_kwh=excluded.imported_kwh, exported_kwh=excluded.exported_kwh, pv_yield_kwh=excluded.pv_yield_kwh, self_consumed_kwh=excluded.self_consumed_kwh, import_cost=excluded.import_cost, export_revenue=excluded.export_revenue, self_consumption_savings=excluded.self_consumption_savings, net_cost=excluded.net_cost, relays_kwh=excluded.relays_kwh";
+                let relays_kwh = serde_json::to_string(&month.relays_kwh).unwrap_or_default();
+                let result = client.execute(
+                    query,
+                    &[
+                        &month.month,
+                        &month.imported_kwh,
+                        &month.exported_kwh,
+                        &month.pv_yield_kwh,
+                        &month.self_consumed_kwh,
+                        &month.import_cost,
+                        &month.export_revenue,
+                        &month.self_consumption_savings,
+                        &month.net_cost,
+                        &relays_kwh,
+                    ],
                 );
-                let result = client.execute(query.as_str(), &[&(counter as i64), &id_sensor]);
+                if let Err(e) = result {
+                    error!("{}: SQL error, query={:?}, error: {}", self.name, query, e);
+                    self.conn = None;
+                    self.energy_costs.write().unwrap().dirty = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn pg_insert_cesspool_emptied(&mut self, level_before: i16, level_after: i16) -> bool {
+        match self.conn.borrow_mut() {
+            Some(client) => {
+                let query =
+                    "insert into cesspool_emptied (level_before, level_after) values ($1, $2)";
+                let result = client.execute(query, &[&level_before, &level_after]);
                 match result {
                     Ok(_) => {
                         return true;
@@ -486,11 +1728,11 @@ impl Database {
         false
     }
 
-    fn update_daily_energy_yield(&mut self, value: f64) -> bool {
+    fn pg_insert_temperature(&mut self, id_sensor: i32, value: f32) -> bool {
         match self.conn.borrow_mut() {
             Some(client) => {
-                let query = "select * from daily_energy_yield_upsert($1)";
-                let result = client.execute(query, &[&(value)]);
+                let query = "insert into temperature (id_sensor, val) values ($1, $2)";
+                let result = client.execute(query, &[&id_sensor, &value]);
                 match result {
                     Ok(_) => {
                         return true;
@@ -506,11 +1748,11 @@ impl Database {
         false
     }
 
-    fn pg_update_cesspool_level(&mut self, value: i16) -> bool {
+    fn pg_insert_humidity(&mut self, id_sensor: i32, value: f32) -> bool {
         match self.conn.borrow_mut() {
             Some(client) => {
-                let query = "insert into cesspool (val) values ($1)";
-                let result = client.execute(query, &[&(value)]);
+                let query = "insert into humidity (id_sensor, val) values ($1, $2)";
+                let result = client.execute(query, &[&id_sensor, &value]);
                 match result {
                     Ok(_) => {
                         return true;
@@ -526,25 +1768,110 @@ impl Database {
         false
     }
 
+    fn pg_insert_alarm_events(&mut self) {
+        let events = std::mem::take(&mut self.pg_alarm_events);
+        match self.conn.borrow_mut() {
+            Some(client) => {
+                let query = "insert into alarm_log (event, id_sensor) values ($1, $2)";
+                for (i, (event, id_sensor)) in events.iter().enumerate() {
+                    if let Err(e) = client.execute(query, &[event, id_sensor]) {
+                        error!("{}: SQL error, query={:?}, error: {}", self.name, query, e);
+                        self.conn = None;
+                        //retry the rest after reconnecting
+                        self.pg_alarm_events = events[i..].to_vec();
+                        return;
+                    }
+                }
+            }
+            _ => self.pg_alarm_events = events,
+        }
+    }
+
+    fn pg_insert_rfid_scans(&mut self) {
+        let scans = std::mem::take(&mut self.pg_rfid_scans);
+        match self.conn.borrow_mut() {
+            Some(client) => {
+                let query =
+                    "insert into rfid_access_log (time, id_tag, name, action) values ($1, $2, $3, $4)";
+                for (i, scan) in scans.iter().enumerate() {
+                    if let Err(e) = client.execute(
+                        query,
+                        &[&scan.time, &(scan.id_tag as i64), &scan.name, &scan.action],
+                    ) {
+                        error!("{}: SQL error, query={:?}, error: {}", self.name, query, e);
+                        self.conn = None;
+                        //retry the rest after reconnecting
+                        self.pg_rfid_scans = scans[i..].to_vec();
+                        return;
+                    }
+                }
+            }
+            _ => self.pg_rfid_scans = scans,
+        }
+    }
+
+    fn pg_insert_circulation_runs(&mut self) {
+        let runs = std::mem::take(&mut self.pg_circulation_runs);
+        match self.conn.borrow_mut() {
+            Some(client) => {
+                let query =
+                    "insert into circulation_runs (ts, reason, run_secs) values ($1, $2, $3)";
+                for (i, (ts, reason, run_secs)) in runs.iter().enumerate() {
+                    if let Err(e) = client.execute(query, &[ts, reason, run_secs]) {
+                        error!("{}: SQL error, query={:?}, error: {}", self.name, query, e);
+                        self.conn = None;
+                        //retry the rest after reconnecting
+                        self.pg_circulation_runs = runs[i..].to_vec();
+                        return;
+                    }
+                }
+            }
+            _ => self.pg_circulation_runs = runs,
+        }
+    }
+
+    fn pg_insert_cesspool_levels(&mut self) {
+        let levels = std::mem::take(&mut self.pg_cesspool_levels);
+        match self.conn.borrow_mut() {
+            Some(client) => {
+                let query = "insert into cesspool (ts, val) values ($1, $2)";
+                for (i, (ts, val)) in levels.iter().enumerate() {
+                    if let Err(e) = client.execute(query, &[ts, val]) {
+                        error!("{}: SQL error, query={:?}, error: {}", self.name, query, e);
+                        self.conn = None;
+                        //retry the rest after reconnecting
+                        self.pg_cesspool_levels = levels[i..].to_vec();
+                        return;
+                    }
+                }
+            }
+            _ => self.pg_cesspool_levels = levels,
+        }
+    }
+
     fn flush_counter_data(&mut self) {
-        let mut flush_map = self.sensor_counters.clone();
-        flush_map
-            .retain(|&id, &mut counter| !self.increment_cycles("sensor".to_string(), id, counter));
-        self.sensor_counters = flush_map;
+        let counters = self.sensor_counters.clone();
+        if self.increment_cycles("sensor".to_string(), &counters) {
+            self.sensor_counters.clear();
+        }
+
+        let counters = self.relay_counters.clone();
+        if self.increment_cycles("relay".to_string(), &counters) {
+            self.relay_counters.clear();
+        }
 
-        flush_map = self.relay_counters.clone();
-        flush_map
-            .retain(|&id, &mut counter| !self.increment_cycles("relay".to_string(), id, counter));
-        self.relay_counters = flush_map;
+        let counters = self.yeelight_counters.clone();
+        if self.increment_cycles("yeelight".to_string(), &counters) {
+            self.yeelight_counters.clear();
+        }
 
-        flush_map = self.yeelight_counters.clone();
-        flush_map.retain(|&id, &mut counter| {
-            !self.increment_cycles("yeelight".to_string(), id, counter)
-        });
-        self.yeelight_counters = flush_map;
+        let counters = self.plug_counters.clone();
+        if self.increment_cycles("plug".to_string(), &counters) {
+            self.plug_counters.clear();
+        }
     }
 
-    async fn influx_flush_counter_data(&mut self) -> Result<()> {
+    async fn influx_flush_counter_data(&mut self) -> std::result::Result<(), DatabaseError> {
         // connect to influxdb
         let client = Client::new(self.influxdb_url.as_ref().unwrap(), "hard");
 
@@ -562,14 +1889,18 @@ impl Database {
                 self.influx_sensor_counters.clear();
             }
             Err(e) => {
-                error!("{}: influxdb write error: {:?}", self.name, e);
+                //the client re-sends a spooled batch itself
+                if e.is_spooled() {
+                    self.influx_sensor_counters.clear();
+                }
+                return Err(DatabaseError::Influx(e));
             }
         }
 
         Ok(())
     }
 
-    async fn influx_flush_values_data(&mut self) -> Result<()> {
+    async fn influx_flush_values_data(&mut self) -> std::result::Result<(), DatabaseError> {
         // connect to influxdb
         let client = Client::new(self.influxdb_url.as_ref().unwrap(), "hard");
 
@@ -583,6 +1914,10 @@ impl Database {
         for (id, state) in self.influx_relay_values.iter() {
             write_query = write_query.add_field(format!("relay-{}", id), state);
         }
+        // add virtual sensors
+        for (id, state) in self.influx_virtual_values.iter() {
+            write_query = write_query.add_field(format!("virtual-{}", id), state);
+        }
 
         // send query to influxdb
         let write_result = client.query(&write_query).await;
@@ -591,16 +1926,109 @@ impl Database {
                 debug!("{}: influxdb write success: {:?}", self.name, msg);
                 self.influx_sensor_values.clear();
                 self.influx_relay_values.clear();
+                self.influx_virtual_values.clear();
+            }
+            Err(e) => {
+                //the client re-sends a spooled batch itself
+                if e.is_spooled() {
+                    self.influx_sensor_values.clear();
+                    self.influx_relay_values.clear();
+                    self.influx_virtual_values.clear();
+                }
+                return Err(DatabaseError::Influx(e));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn influx_flush_env_data(&mut self) -> std::result::Result<(), DatabaseError> {
+        // take pending readings with their metadata, the lock can't be held across await
+        let mut readings = vec![];
+        {
+            let mut env_sensor_dev = self.env_sensor_devices.write().unwrap();
+            for sensor in env_sensor_dev.env_sensors.iter_mut() {
+                if sensor.influx_pending {
+                    sensor.influx_pending = false;
+                    readings.push((
+                        sensor.id_sensor,
+                        sensor.name.clone(),
+                        sensor.get_room(),
+                        onewire::get_w1_device_name(sensor.ow_family, sensor.ow_address),
+                        sensor.last_temperature,
+                        sensor.last_humidity,
+                        sensor.last_vdd,
+                        sensor.last_vad,
+                    ));
+                }
+            }
+        }
+        if readings.is_empty() {
+            return Ok(());
+        }
+        debug!("flushing environment sensor readings to influxdb...");
+
+        // connect to influxdb
+        let client = Client::new(
+            self.influxdb_url.as_ref().unwrap(),
+            self.influx_env_database.as_deref().unwrap_or("hard"),
+        );
+        let measurement = self
+            .influx_env_measurement
+            .clone()
+            .unwrap_or(DEFAULT_ENV_MEASUREMENT.to_string());
+
+        let mut ids = vec![];
+        let mut queries = vec![];
+        for (id_sensor, name, room, address, temperature, humidity, vdd, vad) in readings {
+            // construct a write query tagged with the sensor metadata
+            let mut write_query = Timestamp::from(Utc::now())
+                .into_query(measurement.clone())
+                .add_tag("name", name)
+                .add_tag("address", address);
+            if let Some(room) = room {
+                write_query = write_query.add_tag("room", room);
+            }
+            if let Some(temperature) = temperature {
+                write_query = write_query.add_field("temperature", temperature);
+            }
+            if let Some(humidity) = humidity {
+                write_query = write_query.add_field("humidity", humidity);
+            }
+            if let Some(vdd) = vdd {
+                write_query = write_query.add_field("vdd", vdd);
+            }
+            if let Some(vad) = vad {
+                write_query = write_query.add_field("vad", vad);
+            }
+            ids.push(id_sensor);
+            queries.push(write_query);
+        }
+
+        // send all readings in a single request
+        match client.write(&queries).await {
+            Ok(msg) => {
+                debug!("{}: influxdb write success: {:?}", self.name, msg);
             }
             Err(e) => {
-                error!("{}: influxdb write error: {:?}", self.name, e);
+                // retry failed writes with the next flush,
+                // the client re-sends a spooled batch itself
+                if !e.is_spooled() {
+                    let mut env_sensor_dev = self.env_sensor_devices.write().unwrap();
+                    for sensor in env_sensor_dev.env_sensors.iter_mut() {
+                        if ids.contains(&sensor.id_sensor) {
+                            sensor.influx_pending = true;
+                        }
+                    }
+                }
+                return Err(DatabaseError::Influx(e));
             }
         }
 
         Ok(())
     }
 
-    async fn influx_flush_cesspool_level(&mut self) -> Result<()> {
+    async fn influx_flush_cesspool_level(&mut self) -> std::result::Result<(), DatabaseError> {
         // connect to influxdb
         let client = Client::new(self.influxdb_url.as_ref().unwrap(), "hard");
 
@@ -618,7 +2046,156 @@ impl Database {
                 self.influx_cesspool_level = None;
             }
             Err(e) => {
-                error!("{}: influxdb write error: {:?}", self.name, e);
+                //the client re-sends a spooled batch itself
+                if e.is_spooled() {
+                    self.influx_cesspool_level = None;
+                }
+                return Err(DatabaseError::Influx(e));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn influx_flush_frost_protection(&mut self) -> std::result::Result<(), DatabaseError> {
+        // connect to influxdb
+        let client = Client::new(self.influxdb_url.as_ref().unwrap(), "hard");
+
+        // construct a write query with frost protection run time
+        let write_query = Timestamp::from(Utc::now()).into_query("counter").add_field(
+            format!("frost-protection-secs"),
+            self.influx_frost_protection_secs.unwrap(),
+        );
+
+        // send query to influxdb
+        let write_result = client.query(&write_query).await;
+        match write_result {
+            Ok(msg) => {
+                debug!("{}: influxdb write success: {:?}", self.name, msg);
+                self.influx_frost_protection_secs = None;
+            }
+            Err(e) => {
+                //the client re-sends a spooled batch itself
+                if e.is_spooled() {
+                    self.influx_frost_protection_secs = None;
+                }
+                return Err(DatabaseError::Influx(e));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn influx_flush_alarm_events(&mut self) -> std::result::Result<(), DatabaseError> {
+        // connect to influxdb
+        let client = Client::new(self.influxdb_url.as_ref().unwrap(), "hard");
+
+        // one point per event, tagged with the event kind
+        let queries: Vec<_> = self
+            .influx_alarm_events
+            .iter()
+            .map(|(event, id_sensor)| {
+                let mut write_query = Timestamp::from(Utc::now())
+                    .into_query("alarm")
+                    .add_tag("event", *event)
+                    .add_field("count", 1);
+                if let Some(id_sensor) = id_sensor {
+                    write_query = write_query.add_field("id_sensor", *id_sensor);
+                }
+                write_query
+            })
+            .collect();
+
+        // send query to influxdb
+        match client.write(&queries).await {
+            Ok(msg) => {
+                debug!("{}: influxdb write success: {:?}", self.name, msg);
+                self.influx_alarm_events.clear();
+            }
+            Err(e) => {
+                //the client re-sends a spooled batch itself
+                if e.is_spooled() {
+                    self.influx_alarm_events.clear();
+                }
+                return Err(DatabaseError::Influx(e));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn influx_flush_sun_position(&mut self) -> std::result::Result<(), DatabaseError> {
+        // connect to influxdb
+        let client = Client::new(self.influxdb_url.as_ref().unwrap(), "hard");
+
+        // construct a write query with sun position
+        let write_query = Timestamp::from(Utc::now())
+            .into_query("sun")
+            .add_field("azimuth", self.influx_sun_azimuth.unwrap())
+            .add_field("elevation", self.influx_sun_elevation.unwrap());
+
+        // send query to influxdb
+        let write_result = client.query(&write_query).await;
+        match write_result {
+            Ok(msg) => {
+                debug!("{}: influxdb write success: {:?}", self.name, msg);
+                self.influx_sun_azimuth = None;
+                self.influx_sun_elevation = None;
+            }
+            Err(e) => {
+                //the client re-sends a spooled batch itself
+                if e.is_spooled() {
+                    self.influx_sun_azimuth = None;
+                    self.influx_sun_elevation = None;
+                }
+                return Err(DatabaseError::Influx(e));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn influx_flush_bus_metrics(&mut self) -> std::result::Result<(), DatabaseError> {
+        // connect to influxdb
+        let client = Client::new(self.influxdb_url.as_ref().unwrap(), "hard");
+
+        // take a snapshot of the stats, the lock can't be held across await
+        let devices = {
+            let mut metrics = self.bus_metrics.write().unwrap();
+            metrics.dirty = false;
+            metrics.devices.clone()
+        };
+
+        // one point per device, tagged with the device name
+        let queries: Vec<WriteQuery> = devices
+            .iter()
+            .map(|(device, stats)| {
+                Timestamp::from(Utc::now())
+                    .into_query("onewire")
+                    .add_tag("device", device.clone())
+                    .add_field("reads", stats.reads)
+                    .add_field("read_errors", stats.read_errors)
+                    .add_field("invalid_values", stats.invalid_values)
+                    .add_field("crc_errors", stats.crc_errors)
+                    .add_field("write_errors", stats.write_errors)
+                    .add_field("reopens", stats.reopens)
+                    .add_field("read_timeouts", stats.read_timeouts)
+                    .add_field("avg_read_ms", stats.avg_read_ms())
+                    .add_field("max_read_ms", stats.read_time_max_us as f64 / 1000.0)
+            })
+            .collect();
+
+        // send all devices in a single request
+        match client.write(&queries).await {
+            Ok(msg) => {
+                debug!("{}: influxdb write success: {:?}", self.name, msg);
+            }
+            Err(e) => {
+                //retry with the next snapshot, a spooled one is re-sent by the client
+                if !e.is_spooled() {
+                    self.bus_metrics.write().unwrap().dirty = true;
+                }
+                return Err(DatabaseError::Influx(e));
             }
         }
 
@@ -0,0 +1,324 @@
+use crate::config::Config;
+use crate::onewire::{RelayDevices, Relays, SensorDevices};
+use crate::onewire_env::EnvSensorDevices;
+use crate::rfid::{PinHash, RfidRestrictions, RfidTag};
+use crate::smart_plug::PlugKind;
+use chrono::NaiveDate;
+use ini::ini::Properties;
+use simplelog::*;
+use std::collections::HashMap;
+
+/// Where the sensors, relays, lights and rfid tags are defined
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceSource {
+    /// views of the database, reloadable at runtime
+    Database,
+    /// `[sensor:<name>]`, `[relay:<name>]`, ... sections, read on startup
+    Config,
+}
+
+impl DeviceSource {
+    pub fn parse(source: &str) -> Option<DeviceSource> {
+        match source.trim().to_lowercase().as_str() {
+            "database" => Some(DeviceSource::Database),
+            "config" => Some(DeviceSource::Config),
+            _ => None,
+        }
+    }
+}
+
+/// Decimal or `0x` prefixed hex number, eg. a 1-wire address
+fn parse_number(value: &str) -> Option<u64> {
+    let value = value.trim();
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn number(properties: &Properties, key: &str) -> Option<u64> {
+    properties.get(key).and_then(|x| parse_number(x))
+}
+
+fn float(properties: &Properties, key: &str) -> Option<f32> {
+    properties.get(key).and_then(|x| x.trim().parse().ok())
+}
+
+fn flag(properties: &Properties, key: &str) -> bool {
+    properties.get(key).map_or(false, |x| {
+        matches!(x.trim().to_lowercase().as_str(), "true" | "yes" | "1")
+    })
+}
+
+fn list(properties: &Properties, key: &str) -> Vec<String> {
+    properties.get(key).map_or(vec![], |x| {
+        x.split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    })
+}
+
+fn ids(properties: &Properties, key: &str) -> Vec<i32> {
+    list(properties, key)
+        .iter()
+        .filter_map(|x| x.parse().ok())
+        .collect()
+}
+
+//mandatory id of the device, the sections without it are skipped
+fn id(kind: &str, name: &str, properties: &Properties) -> Option<i32> {
+    let id = number(properties, "id").map(|id| id as i32);
+    if id.is_none() {
+        warn!("{} {}: missing or invalid id", kind, name);
+    }
+    id
+}
+
+//1-wire address and family code of a board
+fn address(kind: &str, name: &str, properties: &Properties) -> Option<(u64, Option<i16>)> {
+    match number(properties, "address") {
+        Some(address) => Some((address, number(properties, "family_code").map(|f| f as i16))),
+        None => {
+            warn!("{} {}: missing or invalid address", kind, name);
+            None
+        }
+    }
+}
+
+/// Sensor kind id, the kinds are numbered in the order of appearance
+fn kind_id(kinds: &mut HashMap<i32, String>, kind: &str) -> i32 {
+    match kinds.iter().find(|(_, name)| name.as_str() == kind) {
+        Some((id, _)) => *id,
+        None => {
+            let id = kinds.len() as i32 + 1;
+            kinds.insert(id, kind.to_string());
+            id
+        }
+    }
+}
+
+/// Populates the devices from the config file sections, used with `devices=config`
+pub fn load(
+    config: &Config,
+    sensor_dev: &mut SensorDevices,
+    env_sensor_dev: &mut EnvSensorDevices,
+    relay_dev: &mut RelayDevices,
+    relays: &mut Relays,
+    rfid_tags: &mut Vec<RfidTag>,
+) {
+    info!("🗒️ Loading devices from the config file...");
+    let mut kinds: HashMap<i32, String> = HashMap::new();
+
+    for (name, properties) in config.sections("sensor:") {
+        let (id_sensor, (address, family_code)) = match (
+            id("sensor", &name, properties),
+            address("sensor", &name, properties),
+        ) {
+            (Some(id), Some(address)) => (id, address),
+            _ => continue,
+        };
+        let kind = match properties.get("kind") {
+            Some(kind) => kind.trim().to_string(),
+            None => {
+                warn!("sensor {}: missing kind", name);
+                continue;
+            }
+        };
+        let bit = number(properties, "bit").unwrap_or(0);
+        if bit > 7 {
+            warn!("sensor {}: invalid bit: {}", name, bit);
+            continue;
+        }
+        debug!(
+            "Got sensor: id_sensor={} kind={:?} name={:?} address={:#x} bit={}",
+            id_sensor, kind, name, address, bit
+        );
+        sensor_dev.add_sensor(
+            id_sensor,
+            kind_id(&mut kinds, &kind),
+            name,
+            family_code,
+            address,
+            bit as u8,
+            ids(properties, "relays"),
+            ids(properties, "yeelights"),
+            ids(properties, "plugs"),
+            list(properties, "tags"),
+        );
+    }
+
+    for (name, properties) in config.sections("env_sensor:") {
+        let (id_sensor, (address, family_code)) = match (
+            id("env sensor", &name, properties),
+            address("env sensor", &name, properties),
+        ) {
+            (Some(id), Some(address)) => (id, address),
+            _ => continue,
+        };
+        let kind = properties
+            .get("kind")
+            .map_or("Env".to_string(), |k| k.trim().to_string());
+        debug!(
+            "Got env sensor: id_sensor={} kind={:?} name={:?} address={:#x}",
+            id_sensor, kind, name, address
+        );
+        env_sensor_dev.add_sensor(
+            id_sensor,
+            kind_id(&mut kinds, &kind),
+            name,
+            family_code,
+            address,
+            ids(properties, "relays"),
+            ids(properties, "yeelights"),
+            list(properties, "tags"),
+        );
+    }
+    sensor_dev.kinds = kinds.clone();
+    env_sensor_dev.kinds = kinds;
+
+    for (name, properties) in config.sections("relay:") {
+        let (id_relay, (address, family_code)) = match (
+            id("relay", &name, properties),
+            address("relay", &name, properties),
+        ) {
+            (Some(id), Some(address)) => (id, address),
+            _ => continue,
+        };
+        let bit = match number(properties, "bit") {
+            Some(bit) if bit <= 7 => bit,
+            _ => {
+                warn!("relay {}: missing or invalid bit", name);
+                continue;
+            }
+        };
+        debug!(
+            "Got relay: id_relay={} name={:?} address={:#x} bit={}",
+            id_relay, name, address, bit
+        );
+        relay_dev.add_relay(
+            &mut relays.relay,
+            id_relay,
+            name,
+            family_code,
+            address,
+            bit as u8,
+            flag(properties, "pir_exclude"),
+            float(properties, "pir_hold_secs"),
+            float(properties, "switch_hold_secs"),
+            flag(properties, "initial_state"),
+            flag(properties, "pir_all_day"),
+            list(properties, "tags"),
+        );
+    }
+
+    for (name, properties) in config.sections("yeelight:") {
+        let id_yeelight = match id("yeelight", &name, properties) {
+            Some(id) => id,
+            None => continue,
+        };
+        let ip_address = match properties.get("ip_address") {
+            Some(ip) => ip.trim().to_string(),
+            None => {
+                warn!("yeelight {}: missing ip_address", name);
+                continue;
+            }
+        };
+        debug!(
+            "Got yeelight: id_yeelight={} name={:?} ip_address={}",
+            id_yeelight, name, ip_address
+        );
+        relay_dev.add_yeelight(
+            &mut relays.relay,
+            id_yeelight,
+            name,
+            ip_address,
+            flag(properties, "pir_exclude"),
+            float(properties, "pir_hold_secs"),
+            float(properties, "switch_hold_secs"),
+            flag(properties, "pir_all_day"),
+            list(properties, "tags"),
+        );
+    }
+
+    for (name, properties) in config.sections("plug:") {
+        let id_plug = match id("smart plug", &name, properties) {
+            Some(id) => id,
+            None => continue,
+        };
+        let kind = match properties.get("kind").and_then(|k| PlugKind::parse(k)) {
+            Some(kind) => kind,
+            None => {
+                warn!("smart plug {}: missing or unknown kind", name);
+                continue;
+            }
+        };
+        let host = match properties.get("host") {
+            Some(host) => host.trim().to_string(),
+            None => {
+                warn!("smart plug {}: missing host", name);
+                continue;
+            }
+        };
+        debug!(
+            "Got smart plug: id_plug={} name={:?} host={}",
+            id_plug, name, host
+        );
+        relay_dev.add_smart_plug(
+            &mut relays.relay,
+            id_plug,
+            name,
+            kind,
+            host,
+            number(properties, "channel").unwrap_or(0) as u8,
+            flag(properties, "pir_exclude"),
+            float(properties, "pir_hold_secs"),
+            float(properties, "switch_hold_secs"),
+            flag(properties, "pir_all_day"),
+            list(properties, "tags"),
+        );
+    }
+
+    for (name, properties) in config.sections("rfid_tag:") {
+        let id_tag = match id("rfid tag", &name, properties) {
+            Some(id) => id,
+            None => continue,
+        };
+        let restrictions = RfidRestrictions {
+            weekdays: properties
+                .get("valid_weekdays")
+                .and_then(|w| RfidRestrictions::parse_weekdays(w))
+                .unwrap_or_default(),
+            hours: properties
+                .get("valid_hours")
+                .and_then(|h| RfidRestrictions::parse_hours(h)),
+            expires: properties
+                .get("expires")
+                .and_then(|e| NaiveDate::parse_from_str(e.trim(), "%Y-%m-%d").ok()),
+            max_uses_per_day: number(properties, "max_uses_per_day").map(|m| m as u32),
+        };
+        debug!(
+            "Got RFID tag: id_tag={} name={:?} restrictions={:?}",
+            id_tag, name, restrictions
+        );
+        rfid_tags.push(RfidTag {
+            id_tag,
+            pin: PinHash::load(&name, properties.get("pin").map(|p| p.as_str())),
+            name,
+            tags: list(properties, "tags"),
+            associated_relays: ids(properties, "relays"),
+            restrictions,
+        });
+    }
+
+    info!(
+        "🗒️ Loaded {} sensor boards, {} env sensors, {} relays/lights/plugs, {} rfid tags",
+        sensor_dev.sensor_boards.len(),
+        env_sensor_dev.env_sensors.len(),
+        relays.relay.len(),
+        rfid_tags.len()
+    );
+}
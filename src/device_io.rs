@@ -0,0 +1,456 @@
+use simplelog::*;
+use std::fs;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+pub const DEVICE_OPEN_TIMEOUT_SECS: f32 = 5.0; //timeout for opening/connecting the device
+pub const DEVICE_RECONNECT_DELAY_SECS: f32 = 10.0; //delay between reconnection attempts
+pub const DEVICE_MAX_INVALID_REPLIES: u32 = 3; //invalid replies in a row before reopening the device
+pub const DEVICE_READ_CHUNK_SIZE: usize = 64; //at least the HID report size
+
+pub static DEV_DIR: &str = "/dev";
+
+trait AsyncReadWrite: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncReadWrite for T {}
+
+/// Where to find the device
+#[derive(Clone, Debug)]
+pub enum DeviceAddress {
+    /// hidraw device looked up by USB ID in a sysfs directory (survives re-enumeration),
+    /// its node is then opened in `dev_dir` (normally [`DEV_DIR`])
+    UsbHid {
+        sysfs_path: String,
+        usb_id: String,
+        dev_dir: String,
+    },
+    /// serial-over-IP converter, `host:port`
+    Tcp(String),
+    /// plain device file
+    Path(String),
+    /// serial port, set to raw mode with the baud rate on each open
+    Serial { path: String, baud_rate: u32 },
+}
+
+/// Termios speed of a baud rate, `None` when not supported
+pub fn baud_speed(baud_rate: u32) -> Option<libc::speed_t> {
+    Some(match baud_rate {
+        1200 => libc::B1200,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        _ => return None,
+    })
+}
+
+/// Raw mode (no echo, line editing nor CR/LF translation), 8N1 at the given baud rate
+fn set_serial_mode<F: AsRawFd>(file: &F, baud_rate: u32) -> io::Result<()> {
+    let speed = baud_speed(baud_rate).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("unsupported baud rate: {}", baud_rate),
+        )
+    })?;
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(file.as_raw_fd(), &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        //no modem control lines, receiver enabled
+        termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+        if libc::cfsetispeed(&mut termios, speed) != 0
+            || libc::cfsetospeed(&mut termios, speed) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::tcsetattr(file.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// How the end of a reply is recognized
+#[derive(Clone, Copy, Debug)]
+pub enum ReplyFrame {
+    /// up to and including the end byte, which is never a part of the frame data
+    Terminated { end: u8, max_size: usize },
+    /// fixed size, for the binary frames where the end byte may be in the data
+    Size(usize),
+}
+
+/// Reads up to the end byte, the rest of the last read (eg. the HID report padding) is dropped
+async fn read_terminated<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    end: u8,
+    max_size: usize,
+) -> io::Result<Vec<u8>> {
+    let mut frame = vec![];
+    let mut chunk = [0u8; DEVICE_READ_CHUNK_SIZE];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "end of stream"));
+        }
+        frame.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = frame.iter().position(|&b| b == end) {
+            frame.truncate(pos + 1);
+            return Ok(frame);
+        }
+        if frame.len() >= max_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("no frame end in {} bytes", frame.len()),
+            ));
+        }
+    }
+}
+
+/// A request/reply device with timeouts and automatic reconnection
+pub struct DeviceIo {
+    pub name: String,
+    pub address: DeviceAddress,
+    pub reconnects: u64,
+    invalid_replies: u32,
+    stream: Option<Box<dyn AsyncReadWrite>>,
+    last_attempt: Option<Instant>,
+    connected_before: bool,
+    on_reconnect: Option<Box<dyn Fn(&str) + Send>>,
+}
+
+impl DeviceIo {
+    pub fn new(name: String, address: DeviceAddress) -> Self {
+        DeviceIo {
+            name,
+            address,
+            reconnects: 0,
+            invalid_replies: 0,
+            stream: None,
+            last_attempt: None,
+            connected_before: false,
+            on_reconnect: None,
+        }
+    }
+
+    /// Callback called with the device path after the connection was re-established
+    pub fn on_reconnect<F: Fn(&str) + Send + 'static>(mut self, callback: F) -> Self {
+        self.on_reconnect = Some(Box::new(callback));
+        self
+    }
+
+    pub fn get_first_dir(dir: String) -> io::Result<String> {
+        //obtaining the first directory name from specified path
+        let name = fs::read_dir(&dir)?
+            .map(|res| res.map(|e| e.file_name()))
+            .collect::<std::result::Result<Vec<_>, io::Error>>()?
+            .get(0)
+            .ok_or(Error::new(ErrorKind::Other, "Empty dir"))?
+            .to_string_lossy()
+            .to_string();
+
+        Ok(name)
+    }
+
+    pub fn get_first_dir_with_mask(dir: String, mask: String) -> io::Result<String> {
+        let name = fs::read_dir(&dir)?
+            .map(|res| res.map(|e| e.file_name()))
+            .filter(|d| d.as_ref().unwrap().to_string_lossy().contains(&mask))
+            .collect::<std::result::Result<Vec<_>, io::Error>>()?
+            .get(0)
+            .ok_or(Error::new(ErrorKind::Other, "Empty dir"))?
+            .to_string_lossy()
+            .to_string();
+
+        Ok(name)
+    }
+
+    /// Resolves the current device path, USB devices are looked up again on each call
+    fn get_device_path(&self) -> io::Result<String> {
+        match &self.address {
+            DeviceAddress::UsbHid {
+                sysfs_path,
+                usb_id,
+                dev_dir,
+            } => {
+                //first get the device directory with its USB ID in it
+                let device_dir =
+                    DeviceIo::get_first_dir_with_mask(sysfs_path.clone(), usb_id.clone())?;
+
+                //now get the hidraw device name, like 'hidraw0'
+                let hidraw_name =
+                    DeviceIo::get_first_dir(format!("{}/{}/hidraw", sysfs_path, device_dir))?;
+
+                //create the full /dev/ path with obtained filename
+                Ok(format!("{}/{}", dev_dir, hidraw_name))
+            }
+            DeviceAddress::Tcp(host_port) => Ok(host_port.clone()),
+            DeviceAddress::Path(path) | DeviceAddress::Serial { path, .. } => Ok(path.clone()),
+        }
+    }
+
+    pub fn close(&mut self) {
+        if self.stream.take().is_some() {
+            warn!("{}: device closed", self.name);
+        }
+    }
+
+    /// Opens the device if not opened yet, retrying not more often than the reconnect delay
+    pub async fn open(&mut self) -> bool {
+        if self.stream.is_some() {
+            return true;
+        }
+        if let Some(last_attempt) = self.last_attempt {
+            if last_attempt.elapsed() < Duration::from_secs_f32(DEVICE_RECONNECT_DELAY_SECS) {
+                return false;
+            }
+        }
+        self.last_attempt = Some(Instant::now());
+
+        let path = match self.get_device_path() {
+            Ok(path) => path,
+            Err(e) => {
+                error!("{}: unable to obtain device path: {:?}", self.name, e);
+                return false;
+            }
+        };
+        info!("{}: opening device: <u>{}</>...", self.name, path);
+
+        let open_timeout = Duration::from_secs_f32(DEVICE_OPEN_TIMEOUT_SECS);
+        let result: io::Result<Box<dyn AsyncReadWrite>> = match &self.address {
+            DeviceAddress::Tcp(_) => match timeout(open_timeout, TcpStream::connect(&path)).await {
+                Ok(res) => res.map(|s| Box::new(s) as Box<dyn AsyncReadWrite>),
+                Err(e) => Err(Error::new(ErrorKind::TimedOut, e)),
+            },
+            DeviceAddress::Serial { baud_rate, .. } => {
+                let mut options = OpenOptions::new();
+                options.read(true).write(true).custom_flags(libc::O_NOCTTY);
+                match timeout(open_timeout, options.open(&path)).await {
+                    Ok(res) => res.and_then(|f| {
+                        set_serial_mode(&f, *baud_rate)?;
+                        Ok(Box::new(f) as Box<dyn AsyncReadWrite>)
+                    }),
+                    Err(e) => Err(Error::new(ErrorKind::TimedOut, e)),
+                }
+            }
+            _ => {
+                let mut options = OpenOptions::new();
+                match timeout(open_timeout, options.read(true).write(true).open(&path)).await {
+                    Ok(res) => res.map(|f| Box::new(f) as Box<dyn AsyncReadWrite>),
+                    Err(e) => Err(Error::new(ErrorKind::TimedOut, e)),
+                }
+            }
+        };
+
+        match result {
+            Ok(stream) => {
+                info!("{}: device opened", self.name);
+                self.stream = Some(stream);
+                self.invalid_replies = 0;
+                if self.connected_before {
+                    self.reconnects += 1;
+                    if let Some(callback) = &self.on_reconnect {
+                        callback(&path);
+                    }
+                }
+                self.connected_before = true;
+                true
+            }
+            Err(e) => {
+                error!("{}: error opening device: {:?}", self.name, e);
+                false
+            }
+        }
+    }
+
+    /// Sends a request and reads the reply frame, the device is closed on I/O errors
+    pub async fn transact(
+        &mut self,
+        request: &[u8],
+        reply: ReplyFrame,
+        reply_timeout: Duration,
+    ) -> Option<Vec<u8>> {
+        let stream = self.stream.as_mut()?;
+        if let Err(e) = stream.write_all(request).await {
+            error!("{}: write error: {:?}", self.name, e);
+            self.close();
+            return None;
+        }
+
+        let read = async {
+            match reply {
+                ReplyFrame::Terminated { end, max_size } => {
+                    read_terminated(stream, end, max_size).await
+                }
+                ReplyFrame::Size(size) => {
+                    let mut buffer = vec![0u8; size];
+                    stream.read_exact(&mut buffer).await.map(|_| buffer)
+                }
+            }
+        };
+        match timeout(reply_timeout, read).await {
+            Ok(Ok(buffer)) => Some(buffer),
+            Ok(Err(e)) => {
+                error!("{}: read error: {}", self.name, e);
+                self.close();
+                None
+            }
+            Err(e) => {
+                error!("{}: response timeout: {}", self.name, e);
+                self.close();
+                None
+            }
+        }
+    }
+
+    /// Called when the caller has accepted the reply
+    pub fn valid_reply(&mut self) {
+        self.invalid_replies = 0;
+    }
+
+    /// Called when the caller has rejected the reply (eg. a CRC error), the device is
+    /// reopened after a few in a row to get rid of the stale data or a wrong device
+    pub fn invalid_reply(&mut self) {
+        self.invalid_replies += 1;
+        if self.invalid_replies >= DEVICE_MAX_INVALID_REPLIES {
+            error!(
+                "{}: {} invalid replies in a row, reopening the device",
+                self.name, self.invalid_replies
+            );
+            self.close();
+        }
+    }
+
+    /// Writes a request without waiting for a reply, the device is closed on I/O errors
+    pub async fn send(&mut self, request: &[u8]) -> bool {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => return false,
+        };
+        match stream.write_all(request).await {
+            Ok(_) => true,
+            Err(e) => {
+                error!("{}: write error: {:?}", self.name, e);
+                self.close();
+                false
+            }
+        }
+    }
+
+    /// Reads whatever the device sends within the timeout, for devices streaming data on their own
+    pub async fn read_available(
+        &mut self,
+        max_size: usize,
+        read_timeout: Duration,
+    ) -> Option<Vec<u8>> {
+        let stream = self.stream.as_mut()?;
+        let mut buffer = vec![0u8; max_size];
+        match timeout(read_timeout, stream.read(&mut buffer)).await {
+            Ok(Ok(0)) => {
+                error!("{}: end of stream", self.name);
+                self.close();
+                None
+            }
+            Ok(Ok(n)) => {
+                buffer.truncate(n);
+                Some(buffer)
+            }
+            Ok(Err(e)) => {
+                error!("{}: read error: {}", self.name, e);
+                self.close();
+                None
+            }
+            //nothing received in time
+            Err(_) => Some(vec![]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::open_pty;
+
+    #[tokio::test]
+    async fn read_up_to_the_frame_end() {
+        //the rest of the HID report is padding
+        let mut hid: &[u8] = b"(ACK\x39\x20\r\0\0\0\0\0\0";
+        assert_eq!(
+            read_terminated(&mut hid, b'\r', 256).await.unwrap(),
+            b"(ACK\x39\x20\r".to_vec()
+        );
+
+        let mut garbage: &[u8] = &[b'x'; 300];
+        let err = read_terminated(&mut garbage, b'\r', 256).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let mut truncated: &[u8] = b"(NAK";
+        let err = read_terminated(&mut truncated, b'\r', 256)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn reopened_after_invalid_replies() {
+        let mut device = DeviceIo::new("test".to_string(), DeviceAddress::Path("/dev/null".into()));
+        let (stream, mut inverter) = tokio::io::duplex(64);
+        device.stream = Some(Box::new(stream));
+        inverter.write_all(b"(NAK\x73\x73\r").await.unwrap();
+        let reply = ReplyFrame::Terminated {
+            end: b'\r',
+            max_size: 256,
+        };
+        let reply = device
+            .transact(b"QMOD", reply, Duration::from_secs(1))
+            .await;
+        assert_eq!(reply, Some(b"(NAK\x73\x73\r".to_vec()));
+
+        for _ in 1..DEVICE_MAX_INVALID_REPLIES {
+            device.invalid_reply();
+        }
+        device.valid_reply();
+        device.invalid_reply();
+        assert!(device.stream.is_some());
+        for _ in 1..DEVICE_MAX_INVALID_REPLIES {
+            device.invalid_reply();
+        }
+        assert!(device.stream.is_none());
+    }
+
+    #[tokio::test]
+    async fn serial_port_in_raw_mode() {
+        let (_master, slave, path) = open_pty().unwrap();
+        let mut device = DeviceIo::new(
+            "test".to_string(),
+            DeviceAddress::Serial {
+                path: path.to_string_lossy().into_owned(),
+                baud_rate: 57600,
+            },
+        );
+        assert!(device.open().await);
+
+        let termios = unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            assert_eq!(libc::tcgetattr(slave.as_raw_fd(), &mut termios), 0);
+            termios
+        };
+        assert_eq!(unsafe { libc::cfgetispeed(&termios) }, libc::B57600);
+        assert_eq!(unsafe { libc::cfgetospeed(&termios) }, libc::B57600);
+        assert_eq!(termios.c_lflag & (libc::ICANON | libc::ECHO), 0);
+        assert_eq!(termios.c_oflag & libc::OPOST, 0);
+
+        assert_eq!(baud_speed(9600), Some(libc::B9600));
+        assert_eq!(baud_speed(9601), None);
+    }
+}
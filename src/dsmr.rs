@@ -0,0 +1,427 @@
+use crate::device_io::{DeviceAddress, DeviceIo};
+use crate::energy_manager::GridExport;
+use crate::influx::{Client, InfluxDbWriteable, Timestamp};
+use crate::lcdproc::{LcdTask, LcdTaskCommand};
+use crate::queue::Sender;
+use crate::systemd;
+use crate::virtual_sensor::SensorValues;
+use chrono::Utc;
+use crc16::*;
+use simplelog::*;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+pub const DSMR_REPORT_INTERVAL_SECS: f32 = 10.0; //secs between writing the telegram values to influxdb
+pub const DSMR_MIN_REPORT_INTERVAL_SECS: f32 = 1.0;
+pub const DSMR_STATS_DUMP_INTERVAL_SECS: f32 = 3600.0; //secs between showing stats
+pub const DSMR_MAX_TELEGRAM_SIZE: usize = 8192; //longer garbage is dropped while waiting for the end
+pub const DSMR_INFLUX_DATABASE: &str = "hard";
+
+//sensor values for the virtual sensors and the energy surplus rules (W)
+pub static DSMR_IMPORT_POWER_VALUE: &str = "p1_import_power";
+pub static DSMR_EXPORT_POWER_VALUE: &str = "p1_export_power";
+
+// Just a generic Result type to ease error handling for us. Errors in multithreaded
+// async contexts needs some extra restrictions
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Values of a P1 telegram, energy in kWh and power in W
+#[derive(Clone, Debug, Default)]
+pub struct Telegram {
+    pub meter_id: Option<String>,
+    pub import_t1: Option<f64>,
+    pub import_t2: Option<f64>,
+    pub export_t1: Option<f64>,
+    pub export_t2: Option<f64>,
+    pub tariff: Option<u8>,
+    pub import_power: Option<f32>,
+    pub export_power: Option<f32>,
+    pub phase_import_power: [Option<f32>; 3],
+    pub phase_export_power: [Option<f32>; 3],
+    pub phase_voltage: [Option<f32>; 3],
+    pub phase_current: [Option<f32>; 3],
+    pub gas: Option<f64>,
+}
+
+impl Telegram {
+    /// Number of the last `(...)` group of an OBIS line, without the unit
+    fn value(line: &str) -> Option<f64> {
+        let start = line.rfind('(')?;
+        let end = line[start..].find(')')? + start;
+        line[start + 1..end].split('*').next()?.trim().parse().ok()
+    }
+
+    /// The unit of a power value is kW in the telegram
+    fn watts(line: &str) -> Option<f32> {
+        Telegram::value(line).map(|kw| (kw * 1000.0) as f32)
+    }
+
+    /// Checks the CRC16 (ARC) of the telegram from `/` to `!` and parses the OBIS lines
+    pub fn parse(text: &str) -> std::result::Result<Telegram, String> {
+        let start = text.find('/').ok_or("missing telegram header")?;
+        let end = text[start..].find('!').ok_or("missing telegram end")? + start;
+        let expected = text[end + 1..].lines().next().unwrap_or_default().trim();
+        //DSMR 2.x/3.x telegrams have no CRC
+        if !expected.is_empty() {
+            let crc = State::<ARC>::calculate(text[start..=end].as_bytes());
+            match u16::from_str_radix(expected, 16) {
+                Ok(expected) if expected == crc => (),
+                _ => {
+                    return Err(format!(
+                        "CRC mismatch: expected {:?}, calculated {:04X}",
+                        expected, crc
+                    ))
+                }
+            }
+        }
+
+        let mut telegram = Telegram::default();
+        for line in text[start..end].lines() {
+            let obis = match line.find('(') {
+                Some(pos) => &line[..pos],
+                None => continue,
+            };
+            let value = Telegram::value(line);
+            //per phase values: L1, L2, L3
+            let phase = |base: u8| -> Option<usize> {
+                let code: u8 = obis.strip_prefix("1-0:")?.split('.').next()?.parse().ok()?;
+                match code.checked_sub(base)? {
+                    n @ 0 | n @ 20 | n @ 40 => Some(n as usize / 20),
+                    _ => None,
+                }
+            };
+            match obis {
+                "0-0:96.1.1" => {
+                    telegram.meter_id = line
+                        .split(|c: char| c == '(' || c == ')')
+                        .nth(1)
+                        .map(|s| s.to_string())
+                }
+                "1-0:1.8.1" => telegram.import_t1 = value,
+                "1-0:1.8.2" => telegram.import_t2 = value,
+                "1-0:2.8.1" => telegram.export_t1 = value,
+                "1-0:2.8.2" => telegram.export_t2 = value,
+                "0-0:96.14.0" => telegram.tariff = value.map(|t| t as u8),
+                "1-0:1.7.0" => telegram.import_power = Telegram::watts(line),
+                "1-0:2.7.0" => telegram.export_power = Telegram::watts(line),
+                //gas meter on the M-Bus: the last value is the reading
+                _ if obis.starts_with("0-") && obis.ends_with(":24.2.1") => telegram.gas = value,
+                _ if obis.ends_with(".7.0") => {
+                    if let Some(i) = phase(21) {
+                        telegram.phase_import_power[i] = Telegram::watts(line);
+                    } else if let Some(i) = phase(22) {
+                        telegram.phase_export_power[i] = Telegram::watts(line);
+                    } else if let Some(i) = phase(32) {
+                        telegram.phase_voltage[i] = value.map(|v| v as f32);
+                    } else if let Some(i) = phase(31) {
+                        telegram.phase_current[i] = value.map(|v| v as f32);
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(telegram)
+    }
+
+    /// Grid power, positive when importing
+    pub fn net_power(&self) -> Option<f32> {
+        match (self.import_power, self.export_power) {
+            (Some(import), export) => Some(import - export.unwrap_or_default()),
+            (None, Some(export)) => Some(-export),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Telegram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "⚡ import: {:?}/{:?} kWh, export: {:?}/{:?} kWh, tariff: {:?}, power: +{:?}/-{:?} W",
+            self.import_t1,
+            self.import_t2,
+            self.export_t1,
+            self.export_t2,
+            self.tariff,
+            self.import_power,
+            self.export_power,
+        )?;
+        if let Some(gas) = self.gas {
+            write!(f, ", gas: {:.3} m3", gas)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Dsmr {
+    pub name: String,
+    pub device: DeviceAddress,
+    pub influxdb_url: Option<String>,
+    pub sensor_values: Arc<RwLock<SensorValues>>,
+    pub grid_export: Arc<RwLock<Option<GridExport>>>,
+    pub lcd_transmitter: Sender<LcdTask>,
+    pub lcd_line: Option<u8>,
+    pub report_interval: Duration,
+    pub stats_interval: Duration,
+}
+
+impl Dsmr {
+    async fn save_to_influxdb(&self, telegram: &Telegram) {
+        let url = match &self.influxdb_url {
+            Some(url) => url,
+            None => return,
+        };
+        let client = Client::new(url, DSMR_INFLUX_DATABASE);
+        let mut query = Timestamp::from(Utc::now())
+            .into_query("dsmr")
+            .add_field_opt("import_t1", telegram.import_t1)
+            .add_field_opt("import_t2", telegram.import_t2)
+            .add_field_opt("export_t1", telegram.export_t1)
+            .add_field_opt("export_t2", telegram.export_t2)
+            .add_field_opt("tariff", telegram.tariff)
+            .add_field_opt("import_power", telegram.import_power)
+            .add_field_opt("export_power", telegram.export_power)
+            .add_field_opt("gas", telegram.gas);
+        if let Some(meter_id) = &telegram.meter_id {
+            query = query.add_tag("meter", meter_id.as_str());
+        }
+        for i in 0..3 {
+            query = query
+                .add_field_opt(
+                    format!("l{}_import_power", i + 1),
+                    telegram.phase_import_power[i],
+                )
+                .add_field_opt(
+                    format!("l{}_export_power", i + 1),
+                    telegram.phase_export_power[i],
+                )
+                .add_field_opt(format!("l{}_voltage", i + 1), telegram.phase_voltage[i])
+                .add_field_opt(format!("l{}_current", i + 1), telegram.phase_current[i]);
+        }
+        match client.query(&query).await {
+            Ok(msg) => debug!("{}: influxdb write success: {:?}", self.name, msg),
+            Err(e) => error!("{}: influxdb write error: {:?}", self.name, e),
+        }
+    }
+
+    async fn report(&self, telegram: &Telegram) {
+        debug!("{}: {}", self.name, telegram);
+        self.save_to_influxdb(telegram).await;
+
+        if let (Some(lcd_line), Some(power)) = (self.lcd_line, telegram.net_power()) {
+            let task = LcdTask {
+                command: LcdTaskCommand::SetLineText,
+                int_arg: lcd_line,
+                string_arg: Some(format!("Grid: {:+.2}kW", power / 1000.0)),
+            };
+            let _ = self.lcd_transmitter.send(task);
+        }
+    }
+
+    /// Current import/export for the virtual sensors, the energy surplus rules and the energy manager
+    fn update_sensor_values(&self, telegram: &Telegram) {
+        let mut values = self.sensor_values.write().unwrap();
+        if let Some(power) = telegram.import_power {
+            values.insert(DSMR_IMPORT_POWER_VALUE.to_string(), power);
+        }
+        if let Some(power) = telegram.export_power {
+            values.insert(DSMR_EXPORT_POWER_VALUE.to_string(), power);
+        }
+        if let Some(power) = telegram.net_power() {
+            GridExport::update(&self.grid_export, "dsmr", -power);
+        }
+    }
+
+    pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
+        info!("{}: Starting task", self.name);
+        let mut device = DeviceIo::new(self.name.clone(), self.device.clone());
+        let mut report_interval = Instant::now();
+        let mut stats_interval = Instant::now();
+        let mut buffer = String::new();
+        let mut last_telegram: Option<Telegram> = None;
+        let (mut telegrams_ok, mut telegrams_errors) = (0u64, 0u64);
+
+        loop {
+            if worker_cancel_flag.load(Ordering::SeqCst) {
+                debug!("{}: Got terminate signal from main", self.name);
+                break;
+            }
+            systemd::heartbeat(&self.name);
+
+            if stats_interval.elapsed() > self.stats_interval {
+                stats_interval = Instant::now();
+                info!(
+                    "{}: 📊 telegram statistics: ok: {}, errors: {}, reconnects: {}",
+                    self.name, telegrams_ok, telegrams_errors, device.reconnects
+                );
+            }
+
+            if !device.open().await {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+
+            if let Some(data) = device
+                .read_available(1024, Duration::from_millis(500))
+                .await
+            {
+                buffer.push_str(&String::from_utf8_lossy(&data));
+                //a telegram ends with the line of the `!` and the CRC
+                while let Some(end) = buffer
+                    .find('!')
+                    .and_then(|pos| buffer[pos..].find('\n').map(|n| pos + n))
+                {
+                    let text: String = buffer.drain(..=end).collect();
+                    match Telegram::parse(&text) {
+                        Ok(telegram) => {
+                            telegrams_ok += 1;
+                            self.update_sensor_values(&telegram);
+                            last_telegram = Some(telegram);
+                        }
+                        Err(e) => {
+                            telegrams_errors += 1;
+                            error!("{}: invalid telegram: {}", self.name, e);
+                        }
+                    }
+                }
+                if buffer.len() > DSMR_MAX_TELEGRAM_SIZE {
+                    warn!("{}: no telegram end, dropping the input", self.name);
+                    buffer.clear();
+                }
+            }
+
+            if report_interval.elapsed() > self.report_interval {
+                report_interval = Instant::now();
+                match last_telegram.take() {
+                    Some(telegram) => self.report(&telegram).await,
+                    None => warn!("{}: no telegram received from the meter", self.name),
+                }
+            }
+        }
+
+        info!(
+            "{}: 📊 telegram statistics: ok: {}, errors: {}, reconnects: {}",
+            self.name, telegrams_ok, telegrams_errors, device.reconnects
+        );
+        systemd::heartbeat_stop(&self.name);
+        info!("{}: task stopped", self.name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &str = "/ISk5\\2MT382-1000\r\n\
+        \r\n\
+        1-3:0.2.8(50)\r\n\
+        0-0:1.0.0(210412113020S)\r\n\
+        0-0:96.1.1(4B384547303034303436333935353037)\r\n\
+        1-0:1.8.1(012345.678*kWh)\r\n\
+        1-0:1.8.2(001234.567*kWh)\r\n\
+        1-0:2.8.1(000123.456*kWh)\r\n\
+        1-0:2.8.2(000012.345*kWh)\r\n\
+        0-0:96.14.0(0002)\r\n\
+        1-0:1.7.0(01.193*kW)\r\n\
+        1-0:2.7.0(00.000*kW)\r\n\
+        1-0:32.7.0(230.1*V)\r\n\
+        1-0:52.7.0(231.2*V)\r\n\
+        1-0:72.7.0(229.8*V)\r\n\
+        1-0:31.7.0(002*A)\r\n\
+        1-0:51.7.0(001*A)\r\n\
+        1-0:71.7.0(000*A)\r\n\
+        1-0:21.7.0(00.650*kW)\r\n\
+        1-0:41.7.0(00.443*kW)\r\n\
+        1-0:61.7.0(00.100*kW)\r\n\
+        1-0:22.7.0(00.000*kW)\r\n\
+        1-0:42.7.0(00.000*kW)\r\n\
+        1-0:62.7.0(00.000*kW)\r\n\
+        0-1:24.1.0(003)\r\n\
+        0-1:24.2.1(210412112500S)(01234.567*m3)\r\n\
+        !";
+
+    fn with_crc(body: &str) -> String {
+        format!(
+            "{}{:04X}\r\n",
+            body,
+            State::<ARC>::calculate(body.as_bytes())
+        )
+    }
+
+    #[test]
+    fn parse_telegram() {
+        let telegram = Telegram::parse(&with_crc(BODY)).unwrap();
+        assert_eq!(
+            telegram.meter_id.as_deref(),
+            Some("4B384547303034303436333935353037")
+        );
+        assert_eq!(telegram.import_t1, Some(12345.678));
+        assert_eq!(telegram.import_t2, Some(1234.567));
+        assert_eq!(telegram.export_t1, Some(123.456));
+        assert_eq!(telegram.export_t2, Some(12.345));
+        assert_eq!(telegram.tariff, Some(2));
+        assert_eq!(telegram.import_power, Some(1193.0));
+        assert_eq!(telegram.export_power, Some(0.0));
+        assert_eq!(
+            telegram.phase_import_power,
+            [Some(650.0), Some(443.0), Some(100.0)]
+        );
+        assert_eq!(telegram.phase_export_power, [Some(0.0); 3]);
+        assert_eq!(
+            telegram.phase_voltage,
+            [Some(230.1), Some(231.2), Some(229.8)]
+        );
+        assert_eq!(telegram.phase_current, [Some(2.0), Some(1.0), Some(0.0)]);
+        assert_eq!(telegram.gas, Some(1234.567));
+        assert_eq!(telegram.net_power(), Some(1193.0));
+    }
+
+    #[test]
+    fn parse_telegram_with_noise_around() {
+        //the rest of the previous telegram and the start of the next one
+        let text = format!("*kW)\r\n!1A2B\r\n{}/ISk5", with_crc(BODY));
+        assert_eq!(Telegram::parse(&text).unwrap().tariff, Some(2));
+    }
+
+    #[test]
+    fn parse_telegram_without_crc() {
+        //DSMR 2.x/3.x
+        let telegram = Telegram::parse(&format!("{}\r\n", BODY)).unwrap();
+        assert_eq!(telegram.import_power, Some(1193.0));
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let text = with_crc(BODY).replace("01.193*kW", "01.194*kW");
+        let err = Telegram::parse(&text).unwrap_err();
+        assert!(err.starts_with("CRC mismatch"), "{}", err);
+        //not a hex number
+        assert!(Telegram::parse(&format!("{}ZZZZ\r\n", BODY)).is_err());
+    }
+
+    #[test]
+    fn malformed_telegrams() {
+        assert!(Telegram::parse("").is_err());
+        assert!(Telegram::parse("1-0:1.7.0(01.193*kW)\r\n!").is_err());
+        assert!(Telegram::parse("/ISk5\\2MT382-1000\r\n1-0:1.7.0(01.193*kW)\r\n").is_err());
+
+        //unparsable values and broken lines are skipped
+        let telegram = Telegram::parse(&with_crc(
+            "/ISk5\\2MT382-1000\r\n\
+            1-0:1.7.0(abc*kW)\r\n\
+            1-0:2.7.0(00.250*kW\r\n\
+            1-0:1.8.1\r\n\
+            0-0:96.14.0(0001)\r\n\
+            !",
+        ))
+        .unwrap();
+        assert_eq!(telegram.import_power, None);
+        assert_eq!(telegram.export_power, None);
+        assert_eq!(telegram.import_t1, None);
+        assert_eq!(telegram.tariff, Some(1));
+        assert_eq!(telegram.net_power(), None);
+    }
+}
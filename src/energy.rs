@@ -0,0 +1,251 @@
+use chrono::{Datelike, Local, NaiveDate};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Instant;
+
+pub static ENERGY_POWER_TAG: &str = "power"; //relay tag with the load power for estimates: power:WATTS
+pub const NET_METERING_HISTORY_DAYS: usize = 400; //daily import/export records kept in memory
+
+/// Flat tariff, prices per kWh
+#[derive(Clone, Copy, Debug)]
+pub struct Tariff {
+    pub import_price: f64,
+    pub export_price: f64,
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct MonthlyCost {
+    pub month: String,
+    pub imported_kwh: f64,
+    pub exported_kwh: f64,
+    pub pv_yield_kwh: f64,
+    pub self_consumed_kwh: f64,
+    pub import_cost: f64,
+    pub export_revenue: f64,
+    pub self_consumption_savings: f64,
+    pub net_cost: f64,
+    pub relays_kwh: HashMap<String, f64>,
+    pub relays_cost: HashMap<String, f64>,
+}
+
+impl MonthlyCost {
+    fn recalculate(&mut self, tariff: &Tariff) {
+        self.self_consumed_kwh = (self.pv_yield_kwh - self.exported_kwh).max(0.0);
+        self.import_cost = self.imported_kwh * tariff.import_price;
+        self.export_revenue = self.exported_kwh * tariff.export_price;
+        self.self_consumption_savings = self.self_consumed_kwh * tariff.import_price;
+        self.net_cost = self.import_cost - self.export_revenue;
+        self.relays_cost = self
+            .relays_kwh
+            .iter()
+            .map(|(name, kwh)| (name.clone(), kwh * tariff.import_price))
+            .collect();
+    }
+}
+
+/// Grid energy of a single day from the power meter counters
+#[derive(Clone, Default, Serialize)]
+pub struct DailyNetMetering {
+    pub day: String,
+    pub imported_kwh: f64,
+    pub exported_kwh: f64,
+    //meter counters at the last reading of the day
+    pub import_total_kwh: f64,
+    pub export_total_kwh: f64,
+}
+
+/// Imported vs exported energy for a period, positive net means a net consumer
+#[derive(Clone, Default, Serialize)]
+pub struct NetMeteringPeriod {
+    pub imported_kwh: f64,
+    pub exported_kwh: f64,
+    pub net_kwh: f64,
+}
+
+impl NetMeteringPeriod {
+    fn add(&mut self, day: &DailyNetMetering) {
+        self.imported_kwh += day.imported_kwh;
+        self.exported_kwh += day.exported_kwh;
+        self.net_kwh = self.imported_kwh - self.exported_kwh;
+    }
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct NetMeteringSummary {
+    pub today: NetMeteringPeriod,
+    pub week: NetMeteringPeriod,
+    pub month: NetMeteringPeriod,
+    pub import_total_kwh: Option<f64>,
+    pub export_total_kwh: Option<f64>,
+}
+
+/// Monthly energy cost summaries computed from the inverter power meter counters
+/// and relay consumption estimates
+#[derive(Default)]
+pub struct EnergyCosts {
+    pub tariff: Option<Tariff>,
+    pub months: Vec<MonthlyCost>,
+    pub dirty: bool,
+    pub days: Vec<DailyNetMetering>,
+    pub days_dirty: bool,
+    //last meter counters: imported, exported, pv yield (kWh)
+    last_counters: Option<(f64, f64, f64)>,
+    //relays with power tag which are currently on
+    relays_on: HashMap<i32, Instant>,
+}
+
+impl EnergyCosts {
+    pub fn new(tariff: Option<Tariff>) -> Self {
+        EnergyCosts {
+            tariff,
+            ..Default::default()
+        }
+    }
+
+    fn current_month(&mut self) -> &mut MonthlyCost {
+        let now = Local::now();
+        let month = format!("{:04}-{:02}", now.year(), now.month());
+        if self.months.last().map_or(true, |m| m.month != month) {
+            self.months.push(MonthlyCost {
+                month,
+                ..Default::default()
+            });
+        }
+        self.months.last_mut().unwrap()
+    }
+
+    fn current_day(&mut self) -> &mut DailyNetMetering {
+        let day = Local::now().format("%Y-%m-%d").to_string();
+        if self.days.last().map_or(true, |d| d.day != day) {
+            self.days.push(DailyNetMetering {
+                day,
+                ..Default::default()
+            });
+            if self.days.len() > NET_METERING_HISTORY_DAYS {
+                self.days.remove(0);
+            }
+        }
+        self.days.last_mut().unwrap()
+    }
+
+    fn recalculate(&mut self) {
+        self.dirty = true;
+        if let Some(tariff) = self.tariff {
+            self.current_month().recalculate(&tariff);
+        }
+    }
+
+    /// New readings of the total energy counters, only the increase is accounted
+    pub fn update_meter(&mut self, imported: f64, exported: f64, pv_yield: f64) {
+        let last = self.last_counters.replace((imported, exported, pv_yield));
+        if let Some((last_imported, last_exported, last_pv_yield)) = last {
+            //counter reset or invalid reading: skip this sample
+            if imported < last_imported || exported < last_exported || pv_yield < last_pv_yield {
+                return;
+            }
+            if imported == last_imported && exported == last_exported && pv_yield == last_pv_yield {
+                return;
+            }
+            let month = self.current_month();
+            month.imported_kwh += imported - last_imported;
+            month.exported_kwh += exported - last_exported;
+            month.pv_yield_kwh += pv_yield - last_pv_yield;
+            let day = self.current_day();
+            day.imported_kwh += imported - last_imported;
+            day.exported_kwh += exported - last_exported;
+            day.import_total_kwh = imported;
+            day.export_total_kwh = exported;
+            self.days_dirty = true;
+            self.recalculate();
+        }
+    }
+
+    /// Relay state change, the consumption of the load is estimated from
+    /// the on-time and the power:WATTS tag
+    pub fn relay_switched(&mut self, id: i32, name: &str, tags: &Vec<String>, on: bool) {
+        let watts: f64 = match tags
+            .iter()
+            .find(|t| t.starts_with(ENERGY_POWER_TAG))
+            .and_then(|t| t.split(':').nth(1))
+            .and_then(|w| w.parse().ok())
+        {
+            Some(watts) => watts,
+            None => return,
+        };
+        if on {
+            self.relays_on.entry(id).or_insert(Instant::now());
+            return;
+        }
+        if let Some(since) = self.relays_on.remove(&id) {
+            let kwh = watts * since.elapsed().as_secs_f64() / 3600.0 / 1000.0;
+            *self
+                .current_month()
+                .relays_kwh
+                .entry(name.to_string())
+                .or_insert(0.0) += kwh;
+            self.recalculate();
+        }
+    }
+
+    /// Restores a month summary loaded from the database, adding what was
+    /// collected before the loading
+    pub fn restore(&mut self, mut month: MonthlyCost) {
+        if let Some(pos) = self.months.iter().position(|m| m.month == month.month) {
+            let current = self.months.remove(pos);
+            month.imported_kwh += current.imported_kwh;
+            month.exported_kwh += current.exported_kwh;
+            month.pv_yield_kwh += current.pv_yield_kwh;
+            for (name, kwh) in current.relays_kwh {
+                *month.relays_kwh.entry(name).or_insert(0.0) += kwh;
+            }
+        }
+        if let Some(tariff) = self.tariff {
+            month.recalculate(&tariff);
+        }
+        self.months.push(month);
+        self.months.sort_by(|a, b| a.month.cmp(&b.month));
+    }
+
+    /// Restores a day loaded from the database, adding what was collected before the loading
+    pub fn restore_day(&mut self, mut day: DailyNetMetering) {
+        if let Some(pos) = self.days.iter().position(|d| d.day == day.day) {
+            let current = self.days.remove(pos);
+            day.imported_kwh += current.imported_kwh;
+            day.exported_kwh += current.exported_kwh;
+            day.import_total_kwh = current.import_total_kwh.max(day.import_total_kwh);
+            day.export_total_kwh = current.export_total_kwh.max(day.export_total_kwh);
+        }
+        self.days.push(day);
+        self.days.sort_by(|a, b| a.day.cmp(&b.day));
+        if self.days.len() > NET_METERING_HISTORY_DAYS {
+            let excess = self.days.len() - NET_METERING_HISTORY_DAYS;
+            self.days.drain(..excess);
+        }
+    }
+
+    /// Imported/exported energy for today, the current (ISO) week and month
+    pub fn net_metering(&self) -> NetMeteringSummary {
+        let today = Local::now().date_naive();
+        let mut summary = NetMeteringSummary {
+            import_total_kwh: self.last_counters.map(|(imported, _, _)| imported),
+            export_total_kwh: self.last_counters.map(|(_, exported, _)| exported),
+            ..Default::default()
+        };
+        for day in &self.days {
+            let date = match NaiveDate::parse_from_str(&day.day, "%Y-%m-%d") {
+                Ok(date) => date,
+                Err(_) => continue,
+            };
+            if date == today {
+                summary.today.add(day);
+            }
+            if date.iso_week() == today.iso_week() {
+                summary.week.add(day);
+            }
+            if date.year() == today.year() && date.month() == today.month() {
+                summary.month.add(day);
+            }
+        }
+        summary
+    }
+}
@@ -0,0 +1,377 @@
+use crate::config::Config;
+use crate::onewire::{OneWireTask, TaskCommand};
+use crate::queue::Sender;
+use chrono::{Local, NaiveDate};
+use humantime::format_duration;
+use simplelog::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+pub const ENERGY_MANAGER_CHECK_INTERVAL_SECS: f32 = 30.0; //secs between the load decisions
+pub const ENERGY_MANAGER_MIN_CHECK_INTERVAL_SECS: f32 = 5.0;
+pub const ENERGY_MANAGER_DEFAULT_HYSTERESIS: f32 = 200.0; //W of extra surplus needed to turn a load on
+pub const ENERGY_MANAGER_MAX_READING_AGE_SECS: f32 = 120.0; //older export readings turn the loads off
+pub const ENERGY_LOAD_DEFAULT_PRIORITY: u32 = 100;
+pub const ENERGY_MANAGER_CANCEL_CHECK_MS: u64 = 500; //exit latency on a stop request between the checks
+
+// Just a generic Result type to ease error handling for us. Errors in multithreaded
+// async contexts needs some extra restrictions
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Latest grid power from the sun2000 power meter or the DSMR P1 port
+#[derive(Clone, Copy, Debug)]
+pub struct GridExport {
+    /// W, positive when exporting to the grid
+    pub power: f32,
+    pub source: &'static str,
+    pub time: Instant,
+}
+
+impl GridExport {
+    pub fn update(grid_export: &Arc<RwLock<Option<GridExport>>>, source: &'static str, power: f32) {
+        *grid_export.write().unwrap() = Some(GridExport {
+            power,
+            source,
+            time: Instant::now(),
+        });
+    }
+}
+
+/// Controllable load from a `[energy_load:<name>]` section, switched by its relay
+pub struct EnergyLoad {
+    pub name: String,
+    pub id_relay: i32,
+    /// rated power, the surplus is computed from it as the load power is not measured
+    pub watts: f32,
+    pub priority: u32,
+    pub min_runtime: Duration,
+    pub max_daily: Option<Duration>,
+    on_since: Option<Instant>,
+    runtime_today: Duration,
+    runtime_day: Option<NaiveDate>,
+}
+
+impl EnergyLoad {
+    fn runtime(&self) -> Duration {
+        self.runtime_today + self.on_since.map_or(Duration::ZERO, |t| t.elapsed())
+    }
+
+    fn cap_reached(&self) -> bool {
+        self.max_daily.map_or(false, |max| self.runtime() >= max)
+    }
+
+    //the daily runtime starts from zero after midnight
+    fn new_day(&mut self, today: NaiveDate) {
+        if self.runtime_day != Some(today) {
+            self.runtime_day = Some(today);
+            self.runtime_today = Duration::ZERO;
+            if let Some(since) = self.on_since.as_mut() {
+                *since = Instant::now();
+            }
+        }
+    }
+}
+
+pub struct EnergyManager {
+    pub name: String,
+    pub loads: Vec<EnergyLoad>,
+    pub grid_export: Arc<RwLock<Option<GridExport>>>,
+    pub ow_transmitter: Sender<OneWireTask>,
+    pub check_interval: Duration,
+    pub hysteresis: f32,
+}
+
+impl EnergyManager {
+    fn switch(&mut self, i: usize, on: bool, reason: &str) {
+        let load = &mut self.loads[i];
+        if on {
+            if load.on_since.is_none() {
+                info!(
+                    "{}: ☀️ turning on <b>{}</> ({} W): {}",
+                    self.name, load.name, load.watts, reason
+                );
+                load.on_since = Some(Instant::now());
+            }
+        } else if let Some(since) = load.on_since.take() {
+            load.runtime_today += since.elapsed();
+            info!(
+                "{}: turning off <b>{}</> after {}: {}, today: {}",
+                self.name,
+                load.name,
+                format_duration(Duration::from_secs(since.elapsed().as_secs())),
+                reason,
+                format_duration(Duration::from_secs(load.runtime_today.as_secs())),
+            );
+        }
+        //when on, keep prolonging until the next check, so a stopped manager
+        //can't leave the load running forever
+        let task = OneWireTask {
+            command: if on {
+                TaskCommand::TurnOnProlong
+            } else {
+                TaskCommand::TurnOff
+            },
+            id_relay: Some(self.loads[i].id_relay),
+            tag_group: None,
+            id_yeelight: None,
+            id_plug: None,
+            duration: if on {
+                Some(self.check_interval * 3)
+            } else {
+                None
+            },
+        };
+        let _ = self.ow_transmitter.send(task);
+    }
+
+    /// Switches the loads by priority so the grid export stays near zero
+    fn check(&mut self) {
+        let today = Local::now().date_naive();
+        for load in self.loads.iter_mut() {
+            load.new_day(today);
+        }
+        let reading = self
+            .grid_export
+            .read()
+            .unwrap()
+            .filter(|r| r.time.elapsed().as_secs_f32() < ENERGY_MANAGER_MAX_READING_AGE_SECS);
+        let reading = match reading {
+            Some(reading) => reading,
+            None => {
+                for i in 0..self.loads.len() {
+                    if self.loads[i].on_since.is_some() {
+                        self.switch(i, false, "no grid power reading");
+                    }
+                }
+                return;
+            }
+        };
+
+        //surplus as if none of the managed loads were running, from their rated power:
+        //a running load drawing less than its watts (eg. a heater at its set temperature)
+        //makes the real surplus higher than this
+        let running: f32 = self
+            .loads
+            .iter()
+            .filter(|l| l.on_since.is_some())
+            .map(|l| l.watts)
+            .sum();
+        let mut surplus = reading.power + running;
+        debug!(
+            "{}: grid export: {} W ({}), managed loads: {} W, surplus: {} W",
+            self.name, reading.power, reading.source, running, surplus
+        );
+
+        let mut turned_on = false;
+        for i in 0..self.loads.len() {
+            let load = &self.loads[i];
+            let on = load.on_since.is_some();
+            let min_runtime_left = load
+                .on_since
+                .map_or(false, |t| t.elapsed() < load.min_runtime);
+            let want = if load.cap_reached() {
+                false
+            } else if on {
+                min_runtime_left || surplus >= load.watts - self.hysteresis
+            } else {
+                //one load is turned on per check, the export has to settle first
+                !turned_on && surplus >= load.watts + self.hysteresis
+            };
+            if want {
+                surplus -= load.watts;
+                if !on {
+                    turned_on = true;
+                    let reason = format!("surplus {} W", surplus + load.watts);
+                    self.switch(i, true, &reason);
+                } else {
+                    //refreshing the prolonged relay
+                    self.switch(i, true, "");
+                }
+            } else if on {
+                let reason = if load.cap_reached() {
+                    "daily runtime cap reached".to_string()
+                } else {
+                    format!("surplus {} W", surplus)
+                };
+                self.switch(i, false, &reason);
+            }
+        }
+    }
+
+    pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
+        info!(
+            "{}: Starting task, loads by priority: {:?}",
+            self.name,
+            self.loads.iter().map(|l| &l.name).collect::<Vec<_>>()
+        );
+
+        loop {
+            self.check();
+            tokio::select! {
+                _ = tokio::time::sleep(self.check_interval) => (),
+                _ = cancelled(&worker_cancel_flag) => {
+                    debug!("{}: Got terminate signal from main", self.name);
+                    break;
+                }
+            }
+        }
+
+        for i in 0..self.loads.len() {
+            if self.loads[i].on_since.is_some() {
+                self.switch(i, false, "task stopped");
+            }
+        }
+        info!("{}: task stopped", self.name);
+        Ok(())
+    }
+}
+
+/// Resolves when the worker is requested to stop
+async fn cancelled(worker_cancel_flag: &AtomicBool) {
+    while !worker_cancel_flag.load(Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(ENERGY_MANAGER_CANCEL_CHECK_MS)).await;
+    }
+}
+
+/// Loads from the `[energy_load:<name>]` sections sorted by priority
+pub fn loads_from_config(config: &Config) -> Vec<EnergyLoad> {
+    let mut loads = vec![];
+    for load in &config.energy_loads {
+        debug!(
+            "Got energy load: {:?} relay={} watts={} priority={} min_runtime={:?} max_daily={:?}",
+            load.name, load.id_relay, load.watts, load.priority, load.min_runtime, load.max_daily
+        );
+        loads.push(EnergyLoad {
+            name: load.name.clone(),
+            id_relay: load.id_relay,
+            watts: load.watts,
+            priority: load.priority,
+            min_runtime: load.min_runtime,
+            max_daily: load.max_daily,
+            on_since: None,
+            runtime_today: Duration::ZERO,
+            runtime_day: None,
+        });
+    }
+    //stable sort: the config order for the same priority
+    loads.sort_by_key(|l| l.priority);
+    loads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{self, OverflowPolicy, QueueMetrics, Receiver};
+
+    fn load(name: &str, watts: f32, priority: u32) -> EnergyLoad {
+        EnergyLoad {
+            name: name.to_string(),
+            id_relay: priority as i32,
+            watts,
+            priority,
+            min_runtime: Duration::ZERO,
+            max_daily: None,
+            on_since: None,
+            runtime_today: Duration::ZERO,
+            runtime_day: None,
+        }
+    }
+
+    fn manager(loads: Vec<EnergyLoad>) -> (EnergyManager, Receiver<OneWireTask>) {
+        let metrics = Arc::new(RwLock::new(QueueMetrics::default()));
+        let (ow_tx, ow_rx) = queue::bounded("ow", 100, OverflowPolicy::DropNewest, &metrics);
+        let manager = EnergyManager {
+            name: "energy_manager".to_string(),
+            loads,
+            grid_export: Arc::new(RwLock::new(None)),
+            ow_transmitter: ow_tx,
+            check_interval: Duration::from_secs(30),
+            hysteresis: 200.0,
+        };
+        (manager, ow_rx)
+    }
+
+    fn check(manager: &mut EnergyManager, export: f32) -> Vec<&str> {
+        GridExport::update(&manager.grid_export, "test", export);
+        manager.check();
+        manager
+            .loads
+            .iter()
+            .filter(|l| l.on_since.is_some())
+            .map(|l| l.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn surplus_turns_on_loads_by_priority() {
+        let (mut manager, ow_rx) = manager(vec![load("heater", 1000.0, 1), load("ev", 2000.0, 2)]);
+        //below the watts + hysteresis of the first load
+        assert!(check(&mut manager, 1100.0).is_empty());
+
+        //one load per check
+        assert_eq!(check(&mut manager, 5000.0), ["heater"]);
+        let task = ow_rx.try_recv().unwrap();
+        assert_eq!(task.command, TaskCommand::TurnOnProlong);
+        assert_eq!(task.id_relay, Some(1));
+        assert_eq!(task.duration, Some(manager.check_interval * 3));
+
+        //the running heater is part of the surplus: 4000 + 1000 W
+        assert_eq!(check(&mut manager, 4000.0), ["heater", "ev"]);
+    }
+
+    #[test]
+    fn hysteresis_keeps_running_loads_on() {
+        let (mut manager, ow_rx) = manager(vec![load("heater", 1000.0, 1)]);
+        assert_eq!(check(&mut manager, 1200.0), ["heater"]);
+
+        //importing 100 W: 900 W surplus is still above the watts - hysteresis
+        assert_eq!(check(&mut manager, -100.0), ["heater"]);
+        assert!(check(&mut manager, -300.0).is_empty());
+        let commands: Vec<_> = std::iter::from_fn(|| ow_rx.try_recv().ok())
+            .map(|t| t.command)
+            .collect();
+        assert_eq!(
+            commands,
+            [
+                TaskCommand::TurnOnProlong,
+                TaskCommand::TurnOnProlong,
+                TaskCommand::TurnOff
+            ]
+        );
+    }
+
+    #[test]
+    fn min_runtime_and_daily_cap() {
+        let mut heater = load("heater", 1000.0, 1);
+        heater.min_runtime = Duration::from_secs(600);
+        let mut ev = load("ev", 2000.0, 2);
+        ev.max_daily = Some(Duration::from_secs(3600));
+        let (mut manager, _ow_rx) = manager(vec![heater, ev]);
+        assert_eq!(check(&mut manager, 1500.0), ["heater"]);
+
+        //kept on until the minimum runtime even when importing
+        assert_eq!(check(&mut manager, -5000.0), ["heater"]);
+
+        //the capped load is not turned on
+        manager.loads[1].runtime_day = Some(Local::now().date_naive());
+        manager.loads[1].runtime_today = Duration::from_secs(3600);
+        assert_eq!(check(&mut manager, 10000.0), ["heater"]);
+
+        //and a running one is turned off when reaching the cap
+        manager.loads[1].runtime_today = Duration::ZERO;
+        assert_eq!(check(&mut manager, 10000.0), ["heater", "ev"]);
+        manager.loads[1].runtime_today = Duration::from_secs(3600);
+        assert_eq!(check(&mut manager, 10000.0), ["heater"]);
+    }
+
+    #[test]
+    fn stale_reading_turns_loads_off() {
+        let (mut manager, _ow_rx) = manager(vec![load("heater", 1000.0, 1)]);
+        assert_eq!(check(&mut manager, 2000.0), ["heater"]);
+
+        *manager.grid_export.write().unwrap() = None;
+        manager.check();
+        assert!(manager.loads[0].on_since.is_none());
+    }
+}
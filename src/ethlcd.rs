@@ -1,15 +1,37 @@
+use crate::lcdproc::{LcdTask, LcdTaskCommand};
+use crate::onewire::DAYLIGHT_SUN_DEGREE;
+use crate::queue::Receiver;
+use chrono::Local;
 use simplelog::*;
-use std::io::Write;
-use std::net::TcpStream;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Just a generic Result type to ease error handling for us. Errors in multithreaded
+// async contexts needs some extra restrictions
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
 pub const ETHLCD_TCP_PORT: u16 = 2425;
+pub const ETHLCD_SEND_INSTR: u8 = 0x01;
+pub const ETHLCD_SEND_DATA: u8 = 0x02;
+pub const ETHLCD_SET_BACKLIGHT: u8 = 0x03;
 pub const ETHLCD_SET_BEEP: u8 = 0x05;
 pub const ETHLCD_BEEPSTATE_ON: u8 = 0x01;
 pub const ETHLCD_BEEPSTATE_OFF: u8 = 0x00;
+pub const ETHLCD_ACK_TIMEOUT_MS: u64 = 500;
+pub const ETHLCD_RECONNECT_SECS: u64 = 10;
+pub const ETHLCD_DEFAULT_PAGE_SECS: f32 = 5.0;
+pub const ETHLCD_BLINK_MS: u64 = 700; //emergency page backlight blinking
+
+//HD44780 instructions
+const HD44780_CLEAR: u8 = 0x01;
+const HD44780_ENTRY_MODE: u8 = 0x06; //increment, no shift
+const HD44780_DISPLAY_ON: u8 = 0x0c; //no cursor
+const HD44780_FUNCTION_SET: u8 = 0x38; //8-bit, 2 lines, 5x8 font
+const HD44780_SET_DDRAM: u8 = 0x80;
+const HD44780_ROW_OFFSETS: [u8; 4] = [0x00, 0x40, 0x14, 0x54];
 
 #[derive(Debug)]
 pub enum BeepMethod {
@@ -25,90 +47,77 @@ pub struct EthLcd {
 }
 
 impl EthLcd {
-    fn beep_sequence(
+    async fn beep_sequence(
         struct_name: &String,
         hostname: &String,
-        mut stream: &TcpStream,
+        stream: &mut TcpStream,
         beep_duration_ms: u64,
         pause_duration_ms: u64,
         repetitions: u8,
         end_pause_ms: u64,
     ) {
-        let mut command = vec![];
         for _ in 0..repetitions {
-            command.clear();
-            command.push(ETHLCD_SET_BEEP);
-            command.push(ETHLCD_BEEPSTATE_ON);
-            match stream.write_all(&command) {
-                Err(e) => {
-                    error!(
-                        "{} [{}]: cannot write to socket: {:?}",
-                        struct_name, hostname, e
-                    );
-                    return;
-                }
-                Ok(_) => (),
+            if let Err(e) = stream
+                .write_all(&[ETHLCD_SET_BEEP, ETHLCD_BEEPSTATE_ON])
+                .await
+            {
+                error!(
+                    "{} [{}]: cannot write to socket: {:?}",
+                    struct_name, hostname, e
+                );
+                return;
             }
-            thread::sleep(Duration::from_millis(beep_duration_ms));
-
-            command.clear();
-            command.push(ETHLCD_SET_BEEP);
-            command.push(ETHLCD_BEEPSTATE_OFF);
-            match stream.write_all(&command) {
-                Err(e) => {
-                    error!(
-                        "{} [{}]: cannot write to socket: {:?}",
-                        struct_name, hostname, e
-                    );
-                    return;
-                }
-                Ok(_) => (),
+            tokio::time::sleep(Duration::from_millis(beep_duration_ms)).await;
+
+            if let Err(e) = stream
+                .write_all(&[ETHLCD_SET_BEEP, ETHLCD_BEEPSTATE_OFF])
+                .await
+            {
+                error!(
+                    "{} [{}]: cannot write to socket: {:?}",
+                    struct_name, hostname, e
+                );
+                return;
             }
-            thread::sleep(Duration::from_millis(pause_duration_ms));
+            tokio::time::sleep(Duration::from_millis(pause_duration_ms)).await;
         }
-        thread::sleep(Duration::from_millis(end_pause_ms));
+        tokio::time::sleep(Duration::from_millis(end_pause_ms)).await;
     }
 
-    fn beep(
+    async fn beep(
         struct_name: String,
         hostname: String,
         beep_method: BeepMethod,
         in_progress: Arc<AtomicBool>,
     ) {
         debug!("{} [{}]: connecting...", struct_name, hostname);
-        match TcpStream::connect(format!("{}:{}", hostname, ETHLCD_TCP_PORT)) {
+        match TcpStream::connect(format!("{}:{}", hostname, ETHLCD_TCP_PORT)).await {
             Err(e) => {
                 error!("{} [{}]: connection error: {:?}", struct_name, hostname, e);
             }
-            Ok(stream) => {
+            Ok(mut stream) => {
                 info!(
                     "{} [{}]: 📟 connected, sending beep commands (beep method: {:?})...",
                     struct_name, hostname, beep_method
                 );
+                let (name, host) = (&struct_name, &hostname);
                 match beep_method {
                     BeepMethod::AlarmArming => {
                         //todo
                     }
                     BeepMethod::DoorBell => {
                         for _ in 0..3 {
-                            EthLcd::beep_sequence(&struct_name, &hostname, &stream, 400, 300, 1, 0);
+                            EthLcd::beep_sequence(name, host, &mut stream, 400, 300, 1, 0).await;
                             for _ in 0..3 {
-                                EthLcd::beep_sequence(
-                                    &struct_name,
-                                    &hostname,
-                                    &stream,
-                                    70,
-                                    70,
-                                    4,
-                                    150,
-                                );
+                                EthLcd::beep_sequence(name, host, &mut stream, 70, 70, 4, 150)
+                                    .await;
                             }
-                            EthLcd::beep_sequence(&struct_name, &hostname, &stream, 70, 270, 1, 0);
+                            EthLcd::beep_sequence(name, host, &mut stream, 70, 270, 1, 0).await;
                         }
-                        EthLcd::beep_sequence(&struct_name, &hostname, &stream, 400, 300, 3, 0);
+                        EthLcd::beep_sequence(name, host, &mut stream, 400, 300, 3, 0).await;
                     }
                     BeepMethod::Confirmation => {
-                        EthLcd::beep_sequence(&struct_name, &hostname, &stream, 70, 70, 3, 0);
+                        EthLcd::beep_sequence(name, host, &mut stream, 70, 70, 3, 0).await;
                     }
                 }
             }
@@ -122,8 +131,13 @@ impl EthLcd {
         let in_progress = self.in_progress.clone();
         if !self.in_progress.load(Ordering::SeqCst) {
             self.in_progress.store(true, Ordering::SeqCst);
-            debug!("{} [{}]: starting beep thread...", struct_name, hostname);
-            thread::spawn(move || EthLcd::beep(struct_name, hostname, beep_method, in_progress));
+            debug!("{} [{}]: starting beep task...", struct_name, hostname);
+            tokio::spawn(EthLcd::beep(
+                struct_name,
+                hostname,
+                beep_method,
+                in_progress,
+            ));
         } else {
             error!(
                 "{} [{}]: beep in progress, {:?} beep request ignored",
@@ -132,3 +146,245 @@ impl EthLcd {
         }
     }
 }
+
+/// Backlight level of the ethlcd display
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backlight {
+    Off = 0x00,
+    Half = 0x01,
+    On = 0x02,
+}
+
+impl Backlight {
+    pub fn parse(level: &str) -> Option<Backlight> {
+        match level.trim().to_lowercase().as_str() {
+            "off" => Some(Backlight::Off),
+            "half" => Some(Backlight::Half),
+            "on" => Some(Backlight::On),
+            _ => None,
+        }
+    }
+}
+
+/// Text pages on the ethlcd display, fed with the same LcdTasks as lcdproc:
+/// the lcd lines are shown in rotating pages, then the cesspool level.
+/// The emergency mode (inverter on battery, alarm) shows the first line with a blinking backlight.
+pub struct EthLcdDisplay {
+    pub name: String,
+    pub host: String,
+    pub lcd_receiver: Receiver<LcdTask>,
+    pub rows: usize,
+    pub cols: usize,
+    pub page_interval: Duration,
+    pub night_backlight: Backlight,
+    pub lat: f64,
+    pub lon: f64,
+    pub lcd_lines: Vec<String>,
+    pub level: Option<u8>,
+    pub emergency: bool,
+}
+
+impl EthLcdDisplay {
+    //every command is acknowledged by echoing the command byte
+    async fn send(stream: &mut TcpStream, command: u8, arg: u8) -> Result<()> {
+        stream.write_all(&[command, arg]).await?;
+        let mut ack = [0u8; 1];
+        tokio::time::timeout(
+            Duration::from_millis(ETHLCD_ACK_TIMEOUT_MS),
+            stream.read_exact(&mut ack),
+        )
+        .await??;
+        if ack[0] != command {
+            return Err(format!(
+                "unexpected reply: {:#04x} for command {:#04x}",
+                ack[0], command
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    async fn init(stream: &mut TcpStream) -> Result<()> {
+        for instr in &[
+            HD44780_FUNCTION_SET,
+            HD44780_DISPLAY_ON,
+            HD44780_ENTRY_MODE,
+            HD44780_CLEAR,
+        ] {
+            EthLcdDisplay::send(stream, ETHLCD_SEND_INSTR, *instr).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_row(&self, stream: &mut TcpStream, row: usize, text: &str) -> Result<()> {
+        let offset = HD44780_ROW_OFFSETS.get(row).copied().unwrap_or(0);
+        EthLcdDisplay::send(stream, ETHLCD_SEND_INSTR, HD44780_SET_DDRAM | offset).await?;
+        for byte in EthLcdDisplay::fit(text, self.cols).bytes() {
+            EthLcdDisplay::send(stream, ETHLCD_SEND_DATA, byte).await?;
+        }
+        Ok(())
+    }
+
+    //the display has only the ASCII characters, the row is padded to clear the old text
+    fn fit(text: &str, cols: usize) -> String {
+        let ascii: String = text
+            .chars()
+            .map(|c| {
+                if c.is_ascii() && !c.is_ascii_control() {
+                    c
+                } else {
+                    '?'
+                }
+            })
+            .take(cols)
+            .collect();
+        format!("{:<width$}", ascii, width = cols)
+    }
+
+    fn apply(&mut self, t: LcdTask) {
+        debug!(
+            "{}: received LcdTask: int_arg: {:?}, string_arg: {:?}",
+            self.name, t.int_arg, t.string_arg
+        );
+        match t.command {
+            LcdTaskCommand::SetLineText => {
+                let idx = t.int_arg as usize;
+                if self.lcd_lines.len() < idx + 1 {
+                    self.lcd_lines.resize(idx + 1, String::new());
+                }
+                self.lcd_lines[idx] = t.string_arg.unwrap_or_default();
+            }
+            LcdTaskCommand::SetCesspoolLevel => self.level = Some(t.int_arg),
+            LcdTaskCommand::SetEmergencyMode => self.emergency = t.int_arg == 1,
+            //the named screens are shown by lcdproc only
+            _ => (),
+        }
+    }
+
+    /// Pages to rotate: the lcd lines split by the display rows, then the cesspool level
+    fn pages(&self) -> Vec<Vec<String>> {
+        if self.emergency {
+            let mut page = vec!["!!! EMERGENCY !!!".to_string()];
+            page.extend(self.lcd_lines.iter().take(1).cloned());
+            return vec![page];
+        }
+        let lines: Vec<String> = self
+            .lcd_lines
+            .iter()
+            .filter(|l| !l.is_empty())
+            .cloned()
+            .collect();
+        let mut pages: Vec<Vec<String>> = lines.chunks(self.rows).map(|c| c.to_vec()).collect();
+        if let Some(level) = self.level {
+            pages.push(vec![
+                "cesspool:".to_string(),
+                "#".repeat(4 * level as usize),
+            ]);
+        }
+        pages
+    }
+
+    fn night(&self) -> bool {
+        if self.lat == 0.0 && self.lon == 0.0 {
+            return false;
+        }
+        let altitude = sun::pos(Local::now().timestamp_millis(), self.lat, self.lon)
+            .altitude
+            .to_degrees();
+        altitude < DAYLIGHT_SUN_DEGREE
+    }
+
+    //shows the pages until an I/O error or the cancel flag
+    async fn run(
+        &mut self,
+        stream: &mut TcpStream,
+        worker_cancel_flag: &Arc<AtomicBool>,
+    ) -> Result<()> {
+        EthLcdDisplay::init(stream).await?;
+        let mut shown: Vec<String> = vec![String::new(); self.rows];
+        let mut backlight: Option<Backlight> = None;
+        let mut page = 0;
+        let mut page_started = Instant::now();
+        let started = Instant::now();
+
+        while !worker_cancel_flag.load(Ordering::SeqCst) {
+            let task = tokio::select! {
+                t = self.lcd_receiver.recv() => t,
+                _ = tokio::time::sleep(Duration::from_millis(100)) => None,
+            };
+            if let Some(t) = task {
+                self.apply(t);
+            }
+            while let Ok(t) = self.lcd_receiver.try_recv() {
+                self.apply(t);
+            }
+
+            let pages = self.pages();
+            if page_started.elapsed() >= self.page_interval {
+                page_started = Instant::now();
+                page += 1;
+            }
+            if page >= pages.len() {
+                page = 0;
+            }
+            let content = pages.get(page).cloned().unwrap_or_default();
+            for row in 0..self.rows {
+                let text = content.get(row).cloned().unwrap_or_default();
+                if shown[row] != text {
+                    self.write_row(stream, row, &text).await?;
+                    shown[row] = text;
+                }
+            }
+
+            let wanted = if self.emergency {
+                let phase = started.elapsed().as_millis() / ETHLCD_BLINK_MS as u128;
+                if phase % 2 == 0 {
+                    Backlight::On
+                } else {
+                    Backlight::Off
+                }
+            } else if self.night() {
+                self.night_backlight
+            } else {
+                Backlight::On
+            };
+            if backlight != Some(wanted) {
+                EthLcdDisplay::send(stream, ETHLCD_SET_BACKLIGHT, wanted as u8).await?;
+                backlight = Some(wanted);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
+        info!("{}: Starting task", self.name);
+        let address = format!("{}:{}", self.host, ETHLCD_TCP_PORT);
+        while !worker_cancel_flag.load(Ordering::SeqCst) {
+            info!("{}: connecting to <u>{}</>...", self.name, address);
+            match TcpStream::connect(&address).await {
+                Ok(mut stream) => {
+                    info!("📟 {}: connected to {}", self.name, address);
+                    if let Err(e) = self.run(&mut stream, &worker_cancel_flag).await {
+                        error!("{}: display error: {}", self.name, e);
+                    }
+                }
+                Err(e) => error!("{}: {} connection error: {:?}", self.name, address, e),
+            }
+            if worker_cancel_flag.load(Ordering::SeqCst) {
+                break;
+            }
+            //keep the state up to date while disconnected
+            let reconnect = Instant::now();
+            while reconnect.elapsed() < Duration::from_secs(ETHLCD_RECONNECT_SECS)
+                && !worker_cancel_flag.load(Ordering::SeqCst)
+            {
+                while let Ok(t) = self.lcd_receiver.try_recv() {
+                    self.apply(t);
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+        info!("{}: task stopped", self.name);
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+pub const EVENT_QUEUE_CAPACITY: usize = 256; //events kept for slow websocket clients
+
+/// Real-time events for the dashboards
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Sensor {
+        name: String,
+        on: bool,
+    },
+    Relay {
+        name: String,
+        on: bool,
+    },
+    CesspoolLevel {
+        level: u8,
+    },
+    InverterMode {
+        inverter: String,
+        mode: String,
+    },
+    GridState {
+        inverter: String,
+        off_grid: bool,
+        voltage: Option<f32>,
+        frequency: Option<f32>,
+    },
+    SunPosition {
+        azimuth: f32,
+        elevation: f32,
+    },
+    InverterPower {
+        inverter: String,
+        active_power: Option<f32>,
+        input_power: Option<f32>,
+        soc: Option<f32>,
+    },
+    Boiler {
+        boiler: String,
+        status: String,
+        flow_temp: f32,
+        return_temp: f32,
+        dhw_temp: f32,
+        outside_temp: f32,
+        pressure: f32,
+    },
+}
+
+impl Event {
+    //events replacing each other, eg. the mode of the same inverter
+    fn key(&self) -> String {
+        match self {
+            Event::Sensor { name, .. } => format!("sensor:{}", name),
+            Event::Relay { name, .. } => format!("relay:{}", name),
+            Event::CesspoolLevel { .. } => "cesspool_level".to_string(),
+            Event::InverterMode { inverter, .. } => format!("inverter_mode:{}", inverter),
+            Event::GridState { inverter, .. } => format!("grid_state:{}", inverter),
+            Event::SunPosition { .. } => "sun_position".to_string(),
+            Event::InverterPower { inverter, .. } => format!("inverter_power:{}", inverter),
+            Event::Boiler { boiler, .. } => format!("boiler:{}", boiler),
+        }
+    }
+}
+
+/// Last event of every kind and device, the initial state for a dashboard
+#[derive(Default)]
+pub struct EventCache {
+    latest: BTreeMap<String, Event>,
+}
+
+impl EventCache {
+    pub fn update(&mut self, event: Event) {
+        self.latest.insert(event.key(), event);
+    }
+
+    pub fn latest(&self) -> Vec<&Event> {
+        self.latest.values().collect()
+    }
+}
+
+/// Keeps the cache up to date until the event sender is dropped
+pub async fn cache_events(sender: EventSender, cache: Arc<RwLock<EventCache>>) {
+    let mut receiver = sender.subscribe();
+    drop(sender);
+    loop {
+        match receiver.recv().await {
+            Ok(event) => {
+                if let Ok(mut cache) = cache.write() {
+                    cache.update(event);
+                }
+            }
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+pub type EventSender = broadcast::Sender<Event>;
+
+/// Pushes the event to all subscribers, there may be none
+pub fn publish(sender: &EventSender, event: Event) {
+    let _ = sender.send(event);
+}
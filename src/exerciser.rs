@@ -0,0 +1,153 @@
+use crate::onewire::{DeviceStatus, OneWireTask, RelayDevices, Relays, TaskCommand};
+use crate::queue::Sender;
+use serde::Serialize;
+use simplelog::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+pub const EXERCISER_CYCLE_SECS: u64 = 2; //how long each relay is kept in the flipped state
+pub const EXERCISER_PAUSE_SECS: u64 = 1; //pause between relays
+
+pub static EXERCISER_EXCLUDE_TAG: &str = "no_exercise"; //relay tag: never cycled (dangerous loads)
+
+#[derive(Clone, Serialize)]
+pub struct ExerciseResult {
+    pub id: i32,
+    pub name: String,
+    pub dest: String,
+    pub initially_on: bool,
+    pub switched: bool,
+    pub restored: bool,
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct ExerciseReport {
+    pub running: bool,
+    pub results: Vec<ExerciseResult>,
+    pub skipped: Vec<String>,
+}
+
+/// Maintenance helper: cycles every relay in sequence to verify the wiring
+/// after electrical work, restoring the prior states afterwards
+#[derive(Clone)]
+pub struct RelayExerciser {
+    pub relay_devices: Arc<RwLock<RelayDevices>>,
+    pub relays: Arc<RwLock<Relays>>,
+    pub report: Arc<Mutex<ExerciseReport>>,
+    running: Arc<AtomicBool>,
+}
+
+impl RelayExerciser {
+    pub fn new(relay_devices: Arc<RwLock<RelayDevices>>, relays: Arc<RwLock<Relays>>) -> Self {
+        RelayExerciser {
+            relay_devices,
+            relays,
+            report: Arc::new(Mutex::new(ExerciseReport::default())),
+            running: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn status(&self) -> Vec<DeviceStatus> {
+        match (self.relay_devices.read(), self.relays.read()) {
+            (Ok(devices), Ok(relays)) => devices.get_relay_status(&relays.relay),
+            _ => vec![],
+        }
+    }
+
+    fn is_on(&self, id: i32) -> Option<bool> {
+        self.status().iter().find(|s| s.id == id).map(|s| s.on)
+    }
+
+    /// Relays which would be cycled and the excluded ones
+    pub fn plan(&self) -> (Vec<DeviceStatus>, Vec<DeviceStatus>) {
+        self.status()
+            .into_iter()
+            .partition(|s| !s.tags.iter().any(|t| t == EXERCISER_EXCLUDE_TAG))
+    }
+
+    /// Starts the test sequence in a separate thread, returns false when already running
+    pub fn start(&self, ow_transmitter: Sender<OneWireTask>) -> bool {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        let exerciser = self.clone();
+        thread::spawn(move || {
+            exerciser.run(ow_transmitter);
+            exerciser.running.store(false, Ordering::SeqCst);
+        });
+        true
+    }
+
+    fn toggle(ow_transmitter: &Sender<OneWireTask>, id: i32) {
+        let _ = ow_transmitter.send(OneWireTask {
+            command: TaskCommand::Toggle,
+            id_relay: Some(id),
+            tag_group: None,
+            id_yeelight: None,
+            id_plug: None,
+            duration: None,
+        });
+    }
+
+    fn run(&self, ow_transmitter: Sender<OneWireTask>) {
+        let (relays, excluded) = self.plan();
+        let skipped: Vec<String> = excluded.into_iter().map(|s| s.name).collect();
+        warn!(
+            "exerciser: 🔧 starting relay test sequence: {} relays, skipped: {:?}",
+            relays.len(),
+            skipped
+        );
+        if let Ok(mut report) = self.report.lock() {
+            *report = ExerciseReport {
+                running: true,
+                results: vec![],
+                skipped,
+            };
+        }
+
+        for relay in relays {
+            Self::toggle(&ow_transmitter, relay.id);
+            thread::sleep(Duration::from_secs(EXERCISER_CYCLE_SECS));
+            let switched = self.is_on(relay.id) == Some(!relay.on);
+
+            Self::toggle(&ow_transmitter, relay.id);
+            thread::sleep(Duration::from_secs(EXERCISER_PAUSE_SECS));
+            let restored = self.is_on(relay.id) == Some(relay.on);
+
+            if switched && restored {
+                info!("exerciser: ✅ {} ({}): OK", relay.name, relay.dest);
+            } else {
+                error!(
+                    "exerciser: ❌ {} ({}): switched: {}, restored: {}",
+                    relay.name, relay.dest, switched, restored
+                );
+            }
+            if let Ok(mut report) = self.report.lock() {
+                report.results.push(ExerciseResult {
+                    id: relay.id,
+                    name: relay.name,
+                    dest: relay.dest,
+                    initially_on: relay.on,
+                    switched,
+                    restored,
+                });
+            }
+        }
+
+        if let Ok(mut report) = self.report.lock() {
+            report.running = false;
+            let failed = report
+                .results
+                .iter()
+                .filter(|r| !r.switched || !r.restored)
+                .count();
+            warn!(
+                "exerciser: 🔧 relay test sequence finished, {} tested, {} failed",
+                report.results.len(),
+                failed
+            );
+        }
+    }
+}
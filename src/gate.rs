@@ -0,0 +1,285 @@
+use crate::config::Config;
+use crate::notify::{Notification, Severity};
+use crate::onewire::{OneWireTask, TaskCommand};
+use crate::queue::Sender;
+use chrono::{DateTime, Local};
+use humantime::format_duration;
+use serde::Serialize;
+use simplelog::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+pub const GATE_DEFAULT_PULSE_SECS: f32 = 1.0; //motor controller button press, not shorter than the relay flip-flop protection
+pub const GATE_DEFAULT_TIMEOUT_SECS: f32 = 60.0; //full travel has to end in this time
+pub const GATE_DEFAULT_NIGHT_OPEN_SECS: f32 = 600.0; //open after dark for that long is notified
+
+pub static GATE_TAG_PREFIX: &str = "gate:"; //reed sensors tagged `gate:<name>:open` / `gate:<name>:closed`
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GateState {
+    Unknown,
+    Closed,
+    Opening,
+    Open,
+    Closing,
+    Error,
+}
+
+impl fmt::Display for GateState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self {
+            GateState::Unknown => "unknown",
+            GateState::Closed => "closed",
+            GateState::Opening => "opening",
+            GateState::Open => "open",
+            GateState::Closing => "closing",
+            GateState::Error => "error",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GateAction {
+    Open,
+    Close,
+}
+
+impl GateAction {
+    pub fn parse(action: &str) -> Option<Self> {
+        match action.trim() {
+            "open" | "up" => Some(GateAction::Open),
+            "close" | "down" => Some(GateAction::Close),
+            _ => None,
+        }
+    }
+
+    /// Task moving the gate, handled by the onewire worker
+    pub fn task(self, gate: &str) -> OneWireTask {
+        OneWireTask {
+            command: TaskCommand::Gate(gate.to_string(), self),
+            id_relay: None,
+            tag_group: None,
+            id_yeelight: None,
+            id_plug: None,
+            duration: None,
+        }
+    }
+}
+
+/// Gate state for the webserver
+#[derive(Clone, Debug, Serialize)]
+pub struct GateStatus {
+    pub state: GateState,
+    pub since: DateTime<Local>,
+    pub open_sensor: Option<bool>,
+    pub closed_sensor: Option<bool>,
+}
+
+pub type GateStates = Arc<RwLock<BTreeMap<String, GateStatus>>>;
+
+/// Gate or garage door with a pulse relay for the motor and the end position reed sensors
+pub struct Gate {
+    pub name: String,
+    pub id_relay: i32,
+    pub pulse: Duration,
+    pub timeout: Duration,
+    pub night_open: Duration,
+    pub state: GateState,
+    since: Instant,
+    changed: DateTime<Local>,
+    repulsed: bool,
+    open_sensor: Option<bool>,
+    closed_sensor: Option<bool>,
+    night_notified: bool,
+    status: GateStates,
+    notify_transmitter: Sender<Notification>,
+}
+
+impl Gate {
+    fn pulse_task(&self) -> OneWireTask {
+        OneWireTask {
+            command: TaskCommand::TurnOnProlong,
+            id_relay: Some(self.id_relay),
+            tag_group: None,
+            id_yeelight: None,
+            id_plug: None,
+            duration: Some(self.pulse),
+        }
+    }
+
+    fn notify(&self, severity: Severity, message: String) {
+        let _ = self.notify_transmitter.send(Notification::new(
+            "gate",
+            severity,
+            format!("{}: {}", self.name, self.state),
+            message,
+        ));
+    }
+
+    fn set_state(&mut self, state: GateState) {
+        if self.state != state {
+            info!("🚧 gate <b>{}</>: {} ➡️ {}", self.name, self.state, state);
+            self.state = state;
+            self.since = Instant::now();
+            self.changed = Local::now();
+        }
+        if state == GateState::Closed {
+            self.night_notified = false;
+        }
+        let status = GateStatus {
+            state,
+            since: self.changed,
+            open_sensor: self.open_sensor,
+            closed_sensor: self.closed_sensor,
+        };
+        self.status
+            .write()
+            .unwrap()
+            .insert(self.name.clone(), status);
+    }
+
+    /// End position sensor change, `kind` is `open` or `closed`
+    pub fn sensor(&mut self, kind: &str, on: bool) {
+        match kind {
+            "open" => self.open_sensor = Some(on),
+            "closed" => self.closed_sensor = Some(on),
+            _ => {
+                warn!("🚧 gate <b>{}</>: unknown sensor kind: {}", self.name, kind);
+                return;
+            }
+        }
+        let state = match (self.open_sensor, self.closed_sensor) {
+            (Some(true), Some(true)) => {
+                error!(
+                    "🚧 gate <b>{}</>: both end position sensors are on",
+                    self.name
+                );
+                GateState::Error
+            }
+            (_, Some(true)) => GateState::Closed,
+            (Some(true), _) => GateState::Open,
+            //left the end position: moved by our pulse, the remote or by hand
+            _ => match self.state {
+                GateState::Closed => {
+                    self.repulsed = true;
+                    GateState::Opening
+                }
+                GateState::Open => {
+                    self.repulsed = true;
+                    GateState::Closing
+                }
+                state => state,
+            },
+        };
+        self.set_state(state);
+    }
+
+    /// Pulses the motor relay when the gate is not in (or moving to) the requested position,
+    /// a pulse while moving would stop the motor instead
+    pub fn request(&mut self, action: GateAction, tasks: &mut Vec<OneWireTask>) {
+        let (target, moving) = match action {
+            GateAction::Open => (GateState::Open, GateState::Opening),
+            GateAction::Close => (GateState::Closed, GateState::Closing),
+        };
+        match self.state {
+            state if state == target || state == moving => {
+                debug!("🚧 gate <b>{}</>: already {}", self.name, state);
+            }
+            GateState::Opening | GateState::Closing => {
+                warn!(
+                    "🚧 gate <b>{}</>: {}, ignoring the {:?} request",
+                    self.name, self.state, action
+                );
+            }
+            _ => {
+                info!("🚧 gate <b>{}</>: {:?} requested", self.name, action);
+                tasks.push(self.pulse_task());
+                self.repulsed = false;
+                self.set_state(moving);
+            }
+        }
+    }
+
+    /// Safety timeout of the movement and the open-after-dark notification
+    pub fn check(&mut self, night: bool, tasks: &mut Vec<OneWireTask>) {
+        if matches!(self.state, GateState::Opening | GateState::Closing)
+            && self.since.elapsed() >= self.timeout
+        {
+            if !self.repulsed {
+                warn!(
+                    "🚧 gate <b>{}</>: still {} after {}, pulsing again",
+                    self.name,
+                    self.state,
+                    format_duration(self.timeout)
+                );
+                tasks.push(self.pulse_task());
+                self.repulsed = true;
+                self.since = Instant::now();
+            } else {
+                let message = format!(
+                    "did not finish {} in {}, open sensor: {:?}, closed sensor: {:?}",
+                    self.state,
+                    format_duration(self.timeout),
+                    self.open_sensor,
+                    self.closed_sensor
+                );
+                error!("🚧 gate <b>{}</>: {}", self.name, message);
+                self.set_state(GateState::Error);
+                self.notify(Severity::Critical, message);
+            }
+        }
+
+        if night
+            && !self.night_notified
+            && !matches!(self.state, GateState::Closed | GateState::Unknown)
+            && self.since.elapsed() >= self.night_open
+        {
+            self.night_notified = true;
+            let message = format!(
+                "{} for {} after dark",
+                self.state,
+                format_duration(Duration::from_secs(self.since.elapsed().as_secs()))
+            );
+            warn!("🚧 gate <b>{}</>: {}", self.name, message);
+            self.notify(Severity::Warning, message);
+        }
+    }
+}
+
+/// Gates from the `[gate:<name>]` sections
+pub fn gates_from_config(
+    config: &Config,
+    status: GateStates,
+    notify_transmitter: Sender<Notification>,
+) -> Vec<Gate> {
+    let mut gates = vec![];
+    for section in &config.gates {
+        debug!(
+            "Got gate: {:?} relay={} pulse={:?} timeout={:?} night_open={:?}",
+            section.name, section.id_relay, section.pulse, section.timeout, section.night_open
+        );
+        let mut gate = Gate {
+            name: section.name.clone(),
+            id_relay: section.id_relay,
+            pulse: section.pulse,
+            timeout: section.timeout,
+            night_open: section.night_open,
+            state: GateState::Unknown,
+            since: Instant::now(),
+            changed: Local::now(),
+            repulsed: false,
+            open_sensor: None,
+            closed_sensor: None,
+            night_notified: false,
+            status: status.clone(),
+            notify_transmitter: notify_transmitter.clone(),
+        };
+        gate.set_state(GateState::Unknown);
+        gates.push(gate);
+    }
+    gates
+}
@@ -0,0 +1,193 @@
+use crate::device_io::{DeviceAddress, DeviceIo};
+use crate::influx::{Client, InfluxDbWriteable, Timestamp};
+use crate::lcdproc::{LcdTask, LcdTaskCommand};
+use crate::onewire::StateMachine;
+use crate::queue::Sender;
+use chrono::Utc;
+use simplelog::*;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+pub const GEIGER_DEFAULT_FACTOR: f32 = 0.0057; //µSv/h per CPM (J305/M4011 tube)
+pub const GEIGER_REPORT_INTERVAL_SECS: f32 = 60.0; //secs between exporting the dose rate
+pub const GEIGER_CLICK_WINDOW_SECS: f32 = 60.0; //sliding window for counting clicks
+pub const GEIGER_DEFAULT_LCD_LINE: u8 = 3; //lcdproc line for the dose rate
+pub const GEIGER_DEFAULT_BAUD_RATE: u32 = 9600;
+pub const GEIGER_ALARM_HYSTERESIS: f32 = 0.9; //alarm is cleared below this part of the threshold
+
+/// How the board reports the radiation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GeigerMode {
+    /// text lines with CPM, eg. `CPS, 1, CPM, 18, uSv/hr, 0.10, SLOW` or just `18`
+    Cpm,
+    /// one byte is sent for every detected particle
+    Clicks,
+}
+
+impl GeigerMode {
+    pub fn parse(mode: &str) -> GeigerMode {
+        match mode.trim() {
+            "clicks" => GeigerMode::Clicks,
+            _ => GeigerMode::Cpm,
+        }
+    }
+}
+
+pub struct Geiger {
+    pub name: String,
+    pub device: DeviceAddress,
+    pub mode: GeigerMode,
+    pub factor: f32,
+    pub alarm_threshold: Option<f32>,
+    pub alarm_script: Option<String>,
+    pub influxdb_url: Option<String>,
+    pub lcd_transmitter: Sender<LcdTask>,
+    pub lcd_line: u8,
+}
+
+impl Geiger {
+    /// Gets the CPM value from a line sent by the board
+    fn parse_cpm(line: &str) -> Option<u32> {
+        let elements: Vec<&str> = line.split(',').map(|x| x.trim()).collect();
+        match elements.iter().position(|x| x.eq_ignore_ascii_case("CPM")) {
+            Some(pos) => elements.get(pos + 1).and_then(|x| x.parse().ok()),
+            None => line.trim().parse().ok(),
+        }
+    }
+
+    fn run_alarm_script(&self, state: &str, dose_rate: f32) {
+        if let Some(ref cmd) = self.alarm_script {
+            let mut cmd = cmd.clone();
+            cmd = str::replace(&cmd, "%state%", state);
+            cmd = str::replace(&cmd, "%dose%", &format!("{:.2}", dose_rate));
+            thread::spawn(move || StateMachine::run_shell_command(cmd));
+        }
+    }
+
+    async fn save_to_influxdb(&self, cpm: u32, dose_rate: f32) {
+        let url = match &self.influxdb_url {
+            Some(url) => url,
+            None => return,
+        };
+        let client = Client::new(url, "hard");
+        let query = Timestamp::from(Utc::now())
+            .into_query("radiation")
+            .add_field("cpm", cpm)
+            .add_field("dose_rate", dose_rate);
+        match client.query(&query).await {
+            Ok(msg) => {
+                debug!("{}: influxdb write success: {:?}", self.name, msg);
+            }
+            Err(e) => {
+                error!("{}: influxdb write error: {:?}", self.name, e);
+            }
+        }
+    }
+
+    async fn report(&self, cpm: u32, alarm: &mut bool) {
+        let dose_rate = cpm as f32 * self.factor;
+        debug!("{}: ☢️ {} CPM, {:.2} µSv/h", self.name, cpm, dose_rate);
+        self.save_to_influxdb(cpm, dose_rate).await;
+
+        let task = LcdTask {
+            command: LcdTaskCommand::SetLineText,
+            int_arg: self.lcd_line,
+            string_arg: Some(format!("Rad: {:.2}uSv/h", dose_rate)),
+        };
+        let _ = self.lcd_transmitter.send(task);
+
+        if let Some(threshold) = self.alarm_threshold {
+            if !*alarm && dose_rate > threshold {
+                *alarm = true;
+                warn!(
+                    "{}: ☢️ dose rate {:.2} µSv/h is above {:.2} µSv/h threshold",
+                    self.name, dose_rate, threshold
+                );
+                self.run_alarm_script("on", dose_rate);
+            } else if *alarm && dose_rate < threshold * GEIGER_ALARM_HYSTERESIS {
+                *alarm = false;
+                info!(
+                    "{}: dose rate {:.2} µSv/h is back to normal",
+                    self.name, dose_rate
+                );
+                self.run_alarm_script("off", dose_rate);
+            }
+        }
+    }
+
+    pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
+        info!(
+            "{}: Starting task, mode: {:?}, factor: {} µSv/h per CPM",
+            self.name, self.mode, self.factor
+        );
+        let mut device = DeviceIo::new(self.name.clone(), self.device.clone());
+        let mut report_interval = Instant::now();
+        let mut line = String::new();
+        let mut last_cpm: Option<u32> = None;
+        let mut clicks: VecDeque<Instant> = VecDeque::new();
+        let mut alarm = false;
+
+        loop {
+            if worker_cancel_flag.load(Ordering::SeqCst) {
+                debug!("{}: Got terminate signal from main", self.name);
+                break;
+            }
+
+            if !device.open().await {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+
+            if let Some(data) = device.read_available(256, Duration::from_millis(500)).await {
+                match self.mode {
+                    GeigerMode::Clicks => {
+                        for _ in data {
+                            clicks.push_back(Instant::now());
+                        }
+                    }
+                    GeigerMode::Cpm => {
+                        line.push_str(&String::from_utf8_lossy(&data));
+                        while let Some(pos) = line.find('\n') {
+                            let text: String = line.drain(..=pos).collect();
+                            match Geiger::parse_cpm(&text) {
+                                Some(cpm) => last_cpm = Some(cpm),
+                                None => debug!("{}: ignoring line: {:?}", self.name, text),
+                            }
+                        }
+                    }
+                }
+            }
+
+            //forget clicks which are out of the window
+            while let Some(click) = clicks.front() {
+                if click.elapsed() > Duration::from_secs_f32(GEIGER_CLICK_WINDOW_SECS) {
+                    clicks.pop_front();
+                } else {
+                    break;
+                }
+            }
+
+            if report_interval.elapsed() > Duration::from_secs_f32(GEIGER_REPORT_INTERVAL_SECS) {
+                report_interval = Instant::now();
+                let cpm = match self.mode {
+                    GeigerMode::Clicks => {
+                        Some((clicks.len() as f32 * 60.0 / GEIGER_CLICK_WINDOW_SECS).round() as u32)
+                    }
+                    GeigerMode::Cpm => last_cpm.take(),
+                };
+                match cpm {
+                    Some(cpm) => self.report(cpm, &mut alarm).await,
+                    None => warn!("{}: no data received from the counter", self.name),
+                }
+            }
+        }
+
+        info!("{}: task stopped", self.name);
+        Ok(())
+    }
+}
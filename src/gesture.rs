@@ -0,0 +1,182 @@
+use std::time::{Duration, Instant};
+
+pub const LONG_PRESS_SECS: f32 = 1.0; //minimum hold time of a long press
+pub const MULTI_CLICK_SECS: f32 = 0.4; //max delay between releases of a multi-click
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Gesture {
+    Short,
+    Long,
+    Double,
+}
+
+/// Tracks press timing of a single `Switch` sensor
+pub struct GestureDetector {
+    pub id_sensor: i32,
+    pub name: String,
+    pub tags: Vec<String>,
+    pub associated_relays: Vec<i32>,
+    pub associated_yeelights: Vec<i32>,
+    pressed_at: Option<Instant>,
+    released_at: Option<Instant>,
+    clicks: u8,
+    long_fired: bool,
+}
+
+impl GestureDetector {
+    pub fn new(
+        id_sensor: i32,
+        name: String,
+        tags: Vec<String>,
+        associated_relays: Vec<i32>,
+        associated_yeelights: Vec<i32>,
+    ) -> Self {
+        GestureDetector {
+            id_sensor,
+            name,
+            tags,
+            associated_relays,
+            associated_yeelights,
+            pressed_at: None,
+            released_at: None,
+            clicks: 0,
+            long_fired: false,
+        }
+    }
+
+    /// Returns the tag group configured for the gesture, eg. `long_press:scene_evening`
+    pub fn get_target(&self, gesture: Gesture) -> Option<String> {
+        let prefix = match gesture {
+            Gesture::Short => return None,
+            Gesture::Long => "long_press:",
+            Gesture::Double => "double_click:",
+        };
+        self.tags
+            .iter()
+            .find(|t| t.starts_with(prefix))
+            .map(|t| t[prefix.len()..].to_string())
+    }
+
+    pub fn is_gesture_sensor(tags: &Vec<String>) -> bool {
+        tags.iter()
+            .any(|t| t.starts_with("long_press:") || t.starts_with("double_click:"))
+    }
+
+    fn reset(&mut self) {
+        self.pressed_at = None;
+        self.released_at = None;
+        self.clicks = 0;
+        self.long_fired = false;
+    }
+
+    pub fn press(&mut self) {
+        self.pressed_at = Some(Instant::now());
+        self.long_fired = false;
+    }
+
+    pub fn release(&mut self) -> Option<Gesture> {
+        if self.pressed_at.take().is_none() {
+            return None;
+        }
+        if self.long_fired {
+            self.reset();
+            return None;
+        }
+        self.clicks += 1;
+        if self.clicks >= 2 {
+            self.reset();
+            return Some(Gesture::Double);
+        }
+        //without a double-click mapping there is no need to wait for the next click
+        if self.get_target(Gesture::Double).is_none() {
+            self.reset();
+            return Some(Gesture::Short);
+        }
+        self.released_at = Some(Instant::now());
+        None
+    }
+
+    /// Press of a momentary staircase button, which is acted on at once instead of
+    /// waiting for the next click: a press within `MULTI_CLICK_SECS` after the
+    /// release of a single click is a double-click
+    pub fn immediate_press(&mut self) -> Gesture {
+        let double = self.released_at.take().map_or(false, |released_at| {
+            released_at.elapsed() <= Duration::from_secs_f32(MULTI_CLICK_SECS)
+        });
+        self.pressed_at = Some(Instant::now());
+        if double {
+            self.clicks = 0;
+            Gesture::Double
+        } else {
+            self.clicks = 1;
+            Gesture::Short
+        }
+    }
+
+    /// Release of a momentary staircase button, only a single click can be followed by a double-click
+    pub fn immediate_release(&mut self) {
+        if self.pressed_at.take().is_some() && self.clicks == 1 {
+            self.released_at = Some(Instant::now());
+        }
+        self.clicks = 0;
+    }
+
+    /// Periodic check for gestures which are detected by a timeout
+    pub fn poll(&mut self) -> Option<Gesture> {
+        match self.pressed_at {
+            Some(pressed_at) => {
+                if !self.long_fired
+                    && pressed_at.elapsed() > Duration::from_secs_f32(LONG_PRESS_SECS)
+                {
+                    self.long_fired = true;
+                    self.clicks = 0;
+                    if self.get_target(Gesture::Long).is_some() {
+                        return Some(Gesture::Long);
+                    }
+                    //no long press mapping: treat as a normal click
+                    return Some(Gesture::Short);
+                }
+            }
+            None => {
+                if let Some(released_at) = self.released_at {
+                    if released_at.elapsed() > Duration::from_secs_f32(MULTI_CLICK_SECS) {
+                        self.reset();
+                        return Some(Gesture::Short);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector() -> GestureDetector {
+        GestureDetector::new(1, "stairs".to_string(), vec![], vec![], vec![])
+    }
+
+    #[test]
+    fn staircase_double_click() {
+        let mut clicks = detector();
+        assert_eq!(clicks.immediate_press(), Gesture::Short);
+        clicks.immediate_release();
+        assert_eq!(clicks.immediate_press(), Gesture::Double);
+        clicks.immediate_release();
+        //the third click starts over
+        assert_eq!(clicks.immediate_press(), Gesture::Short);
+    }
+
+    #[test]
+    fn staircase_clicks_too_far_apart() {
+        let mut clicks = detector();
+        assert_eq!(clicks.immediate_press(), Gesture::Short);
+        clicks.immediate_release();
+        clicks.released_at = Some(
+            Instant::now() - Duration::from_secs_f32(MULTI_CLICK_SECS) - Duration::from_millis(1),
+        );
+        assert_eq!(clicks.immediate_press(), Gesture::Short);
+    }
+}
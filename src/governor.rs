@@ -0,0 +1,247 @@
+use crate::onewire::{OneWireTask, TaskCommand};
+use simplelog::*;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_COMMAND_MIN_INTERVAL_MS: u64 = 2000; //minimum time between commands for a device
+pub const GOVERNOR_STATS_INTERVAL_SECS: f32 = 600.0; //secs between logging governor stats
+
+/// Coalesces bursts of external commands for the same device
+pub struct CommandGovernor {
+    pub name: String,
+    pub min_interval: Duration,
+    //send time, command and prolong duration of the last passed command
+    last_sent: HashMap<String, (Instant, TaskCommand, Option<Duration>)>,
+    deferred: HashMap<String, OneWireTask>,
+    pub passed: u64,
+    pub merged: u64,
+    pub dropped: u64,
+    stats_time: Instant,
+}
+
+impl CommandGovernor {
+    pub fn new(min_interval_ms: Option<u64>) -> Self {
+        CommandGovernor {
+            name: "governor".to_string(),
+            min_interval: Duration::from_millis(
+                min_interval_ms.unwrap_or(DEFAULT_COMMAND_MIN_INTERVAL_MS),
+            ),
+            last_sent: HashMap::new(),
+            deferred: HashMap::new(),
+            passed: 0,
+            merged: 0,
+            dropped: 0,
+            stats_time: Instant::now(),
+        }
+    }
+
+    fn get_key(task: &OneWireTask) -> String {
+        match (
+            task.id_relay,
+            task.id_yeelight,
+            task.id_plug,
+            &task.tag_group,
+        ) {
+            (Some(id), _, _, _) => format!("relay:{}", id),
+            (_, Some(id), _, _) => format!("yeelight:{}", id),
+            (_, _, Some(id), _) => format!("plug:{}", id),
+            (_, _, _, Some(tag)) => format!("tag:{}", tag),
+            _ => "".to_string(),
+        }
+    }
+
+    fn pass(&mut self, key: String, task: OneWireTask, pending_tasks: &mut Vec<OneWireTask>) {
+        self.passed += 1;
+        self.last_sent
+            .insert(key, (Instant::now(), task.command.clone(), task.duration));
+        pending_tasks.push(task);
+    }
+
+    /// Passes the task through, defers it or drops it when it is a repeat of the last command
+    pub fn submit(&mut self, task: OneWireTask, pending_tasks: &mut Vec<OneWireTask>) {
+        let key = CommandGovernor::get_key(&task);
+        let recent = match self.last_sent.get(&key) {
+            Some((sent, command, duration)) if sent.elapsed() < self.min_interval => {
+                Some((command.clone(), *duration))
+            }
+            _ => None,
+        };
+
+        match recent {
+            None => self.pass(key, task, pending_tasks),
+            Some((last_command, last_duration)) => {
+                let repeated = last_command == task.command
+                    && match task.command {
+                        TaskCommand::TurnOff => true,
+                        //a longer prolong is not covered by the last one
+                        TaskCommand::TurnOnProlong => match (task.duration, last_duration) {
+                            (Some(duration), Some(last_duration)) => duration <= last_duration,
+                            (duration, last_duration) => duration == last_duration,
+                        },
+                        _ => false,
+                    };
+                if repeated && !self.deferred.contains_key(&key) {
+                    self.dropped += 1;
+                    debug!(
+                        "{}: {}: dropped repeated {:?} command",
+                        self.name, key, task.command
+                    );
+                } else {
+                    //the latest request wins
+                    if self.deferred.insert(key.clone(), task).is_some() {
+                        self.merged += 1;
+                        debug!("{}: {}: merged with deferred command", self.name, key);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Releases all deferred commands right away, used on exit
+    pub fn flush(&mut self, pending_tasks: &mut Vec<OneWireTask>) {
+        let deferred: Vec<(String, OneWireTask)> = self.deferred.drain().collect();
+        for (key, task) in deferred {
+            self.pass(key, task, pending_tasks);
+        }
+    }
+
+    /// Releases deferred commands when the minimum interval has passed
+    pub fn poll(&mut self, pending_tasks: &mut Vec<OneWireTask>) {
+        if !self.deferred.is_empty() {
+            let ready: Vec<String> = self
+                .deferred
+                .keys()
+                .filter(|key| match self.last_sent.get(*key) {
+                    Some((sent, _)) => sent.elapsed() >= self.min_interval,
+                    None => true,
+                })
+                .cloned()
+                .collect();
+            for key in ready {
+                if let Some(task) = self.deferred.remove(&key) {
+                    self.pass(key, task, pending_tasks);
+                }
+            }
+        }
+
+        if self.stats_time.elapsed() > Duration::from_secs_f32(GOVERNOR_STATS_INTERVAL_SECS) {
+            self.stats_time = Instant::now();
+            if self.merged > 0 || self.dropped > 0 {
+                info!(
+                    "{}: 🚦 commands passed: {}, merged: {}, dropped: {}",
+                    self.name, self.passed, self.merged, self.dropped
+                );
+            }
+            //forget stale entries
+            let min_interval = self.min_interval;
+            self.last_sent
+                .retain(|_, (sent, _, _)| sent.elapsed() < min_interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn task(command: TaskCommand, duration_secs: Option<u64>) -> OneWireTask {
+        OneWireTask {
+            command,
+            id_relay: Some(1),
+            tag_group: None,
+            id_yeelight: None,
+            id_plug: None,
+            duration: duration_secs.map(Duration::from_secs),
+        }
+    }
+
+    fn commands(tasks: &[OneWireTask]) -> Vec<TaskCommand> {
+        tasks.iter().map(|t| t.command.clone()).collect()
+    }
+
+    #[test]
+    fn first_command_passes() {
+        let mut governor = CommandGovernor::new(Some(60_000));
+        let mut pending = vec![];
+        governor.submit(task(TaskCommand::Toggle, None), &mut pending);
+        //other devices are not limited
+        let mut other = task(TaskCommand::Toggle, None);
+        other.id_relay = Some(2);
+        governor.submit(other, &mut pending);
+
+        assert_eq!(pending.len(), 2);
+        assert_eq!(governor.passed, 2);
+    }
+
+    #[test]
+    fn repeated_command_is_dropped() {
+        let mut governor = CommandGovernor::new(Some(60_000));
+        let mut pending = vec![];
+        governor.submit(task(TaskCommand::TurnOff, None), &mut pending);
+        governor.submit(task(TaskCommand::TurnOff, None), &mut pending);
+        governor.submit(task(TaskCommand::TurnOnProlong, Some(600)), &mut pending);
+        governor.flush(&mut pending);
+        governor.submit(task(TaskCommand::TurnOnProlong, Some(600)), &mut pending);
+        governor.submit(task(TaskCommand::TurnOnProlong, Some(300)), &mut pending);
+
+        assert_eq!(
+            commands(&pending),
+            [TaskCommand::TurnOff, TaskCommand::TurnOnProlong]
+        );
+        assert_eq!(governor.dropped, 3);
+    }
+
+    #[test]
+    fn longer_prolong_is_deferred() {
+        let mut governor = CommandGovernor::new(Some(60_000));
+        let mut pending = vec![];
+        governor.submit(task(TaskCommand::TurnOnProlong, Some(300)), &mut pending);
+        governor.submit(task(TaskCommand::TurnOnProlong, Some(600)), &mut pending);
+        //the relay default time can be longer too
+        governor.submit(task(TaskCommand::TurnOnProlong, None), &mut pending);
+
+        assert_eq!(governor.dropped, 0);
+        assert_eq!(governor.merged, 1);
+        governor.flush(&mut pending);
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[1].duration, None);
+    }
+
+    #[test]
+    fn deferred_commands_are_merged() {
+        let mut governor = CommandGovernor::new(Some(60_000));
+        let mut pending = vec![];
+        governor.submit(task(TaskCommand::TurnOff, None), &mut pending);
+        governor.submit(task(TaskCommand::Toggle, None), &mut pending);
+        governor.submit(task(TaskCommand::TurnOnProlong, None), &mut pending);
+        //not released before the interval
+        governor.poll(&mut pending);
+        assert_eq!(commands(&pending), [TaskCommand::TurnOff]);
+
+        //the latest request wins
+        governor.flush(&mut pending);
+        assert_eq!(
+            commands(&pending),
+            [TaskCommand::TurnOff, TaskCommand::TurnOnProlong]
+        );
+        assert_eq!(governor.merged, 1);
+        assert_eq!(governor.passed, 2);
+    }
+
+    #[test]
+    fn deferred_command_is_released_after_the_interval() {
+        let mut governor = CommandGovernor::new(Some(20));
+        let mut pending = vec![];
+        governor.submit(task(TaskCommand::TurnOff, None), &mut pending);
+        governor.submit(task(TaskCommand::Toggle, None), &mut pending);
+        assert_eq!(pending.len(), 1);
+
+        thread::sleep(Duration::from_millis(30));
+        governor.poll(&mut pending);
+        assert_eq!(
+            commands(&pending),
+            [TaskCommand::TurnOff, TaskCommand::Toggle]
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use gpio_cdev::{Chip, LineRequestFlags, MultiLineHandle};
+use simplelog::*;
+use std::io::{self, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+
+/// Family code of the sensors/relays on a `[gpio:<name>]` board, the address selects the board
+pub const FAMILY_CODE_GPIO: u8 = 0x00;
+const GPIO_CONSUMER: &str = "hard";
+
+/// Raspberry Pi GPIO lines grouped as a 1-wire board: up to two inputs
+/// as the DS2413 PIOA/PIOB and up to eight outputs as the DS2408 bits
+#[derive(Clone, Debug)]
+pub struct GpioBoard {
+    pub name: String,
+    pub chip: String,
+    pub address: u64,
+    pub inputs: Vec<u32>,
+    pub outputs: Vec<u32>,
+    /// the output lines are low for a turned on relay
+    pub active_low: bool,
+}
+
+impl GpioBoard {
+    fn request(&self, lines: &[u32], flags: LineRequestFlags) -> io::Result<MultiLineHandle> {
+        let to_io = |e: gpio_cdev::Error| Error::new(ErrorKind::Other, e);
+        let mut chip = Chip::new(&self.chip).map_err(to_io)?;
+        //outputs start turned off, like the DS2408_INITIAL_STATE of the 1-wire boards
+        let defaults = vec![if self.active_low { 1 } else { 0 }; lines.len()];
+        chip.get_lines(lines)
+            .map_err(to_io)?
+            .request(flags, &defaults, GPIO_CONSUMER)
+            .map_err(to_io)
+    }
+
+    pub fn open_inputs(&self) -> io::Result<GpioFile> {
+        info!(
+            "gpio {}: requesting input lines {:?} of {}",
+            self.name, self.inputs, self.chip
+        );
+        Ok(GpioFile {
+            handle: self.request(&self.inputs, LineRequestFlags::INPUT)?,
+            output: false,
+            active_low: self.active_low,
+            position: 0,
+        })
+    }
+
+    pub fn open_outputs(&self) -> io::Result<GpioFile> {
+        info!(
+            "gpio {}: requesting output lines {:?} of {}",
+            self.name, self.outputs, self.chip
+        );
+        Ok(GpioFile {
+            handle: self.request(&self.outputs, LineRequestFlags::OUTPUT)?,
+            output: true,
+            active_low: self.active_low,
+            position: 0,
+        })
+    }
+}
+
+/// Requested GPIO lines presented as the one byte `state`/`output` file of a 1-wire board
+pub struct GpioFile {
+    handle: MultiLineHandle,
+    output: bool,
+    active_low: bool,
+    position: u64,
+}
+
+impl GpioFile {
+    /// DS2413 state byte: PIO states in bits 0/2, latches (always off) in bits 1/3
+    /// and the complement of the lower nibble in the upper one
+    fn state_byte(values: &[u8]) -> u8 {
+        let pio_a = values.get(0).map_or(1, |v| *v & 1);
+        let pio_b = values.get(1).map_or(1, |v| *v & 1);
+        let low = pio_a | 1 << 1 | pio_b << 2 | 1 << 3;
+        (!low << 4) | low
+    }
+
+    /// DS2408 output byte: bit set means the relay is off
+    fn output_byte(&self, values: &[u8]) -> u8 {
+        let mut byte = 0xff;
+        for (i, value) in values.iter().enumerate().take(8) {
+            let on = (*value != 0) != self.active_low;
+            if on {
+                byte &= !(1 << i);
+            }
+        }
+        byte
+    }
+}
+
+impl Read for GpioFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position > 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let values = self
+            .handle
+            .get_values()
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        buf[0] = if self.output {
+            self.output_byte(&values)
+        } else {
+            GpioFile::state_byte(&values)
+        };
+        self.position = 1;
+        Ok(1)
+    }
+}
+
+impl Write for GpioFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if !self.output {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "gpio input lines are read-only",
+            ));
+        }
+        let values: Vec<u8> = (0..self.handle.num_lines())
+            .map(|i| {
+                let on = buf[0] & (1 << i) == 0;
+                (on != self.active_low) as u8
+            })
+            .collect();
+        debug!("gpio: write: {:#04x} -> {:?}", buf[0], values);
+        self.handle
+            .set_values(&values)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        self.position = 1;
+        Ok(1)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for GpioFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            _ => 0,
+        };
+        Ok(self.position)
+    }
+}
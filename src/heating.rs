@@ -0,0 +1,316 @@
+use crate::config::Config;
+use crate::heating_season::HeatingSeason;
+use crate::onewire::{OneWireTask, TaskCommand};
+use crate::queue::Sender;
+use crate::remeha::{RemehaSetpoint, RemehaSetpointRequest};
+use crate::units;
+use crate::virtual_sensor::SensorValues;
+use chrono::{Local, NaiveTime};
+use simplelog::*;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+pub const HEATING_CHECK_INTERVAL_SECS: f32 = 60.0; //secs between the zone evaluations
+pub const HEATING_MIN_CHECK_INTERVAL_SECS: f32 = 10.0;
+pub const HEATING_DEFAULT_DAY_TEMP: f32 = 21.0;
+pub const HEATING_DEFAULT_NIGHT_TEMP: f32 = 18.0;
+pub const HEATING_DEFAULT_HYSTERESIS: f32 = 0.5; //°C around the target temperature
+pub static HEATING_DEFAULT_BOILER: &str = "remeha";
+pub const HEATING_WINDOW_OPEN_SECS: f32 = 300.0; //open window time before the zone cutback
+pub static WINDOW_TAG_PREFIX: &str = "window:"; //sensor tag: reed contact of a zone window/door
+
+// Just a generic Result type to ease error handling for us. Errors in multithreaded
+// async contexts needs some extra restrictions
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Room heated by the zone valves of its relays, from a `[heating_zone:<name>]` section
+pub struct HeatingZone {
+    pub name: String,
+    pub sensor: String,
+    pub relays: Vec<i32>,
+    pub day_temp: f32,
+    pub night_temp: f32,
+    pub day_start: NaiveTime,
+    pub night_start: NaiveTime,
+    pub hysteresis: f32,
+    /// target while a window is open, the valves are closed when not set
+    pub cutback_temp: Option<f32>,
+    pub demand: Option<bool>,
+}
+
+impl HeatingZone {
+    /// Target temperature for the time of day
+    pub fn target(&self, now: NaiveTime) -> f32 {
+        let day = if self.day_start <= self.night_start {
+            now >= self.day_start && now < self.night_start
+        } else {
+            //day period over midnight, eg. for a night shift worker
+            now >= self.day_start || now < self.night_start
+        };
+        if day {
+            self.day_temp
+        } else {
+            self.night_temp
+        }
+    }
+
+    /// Heat demand with the hysteresis band around the target
+    pub fn update(&mut self, temp: f32, target: f32) -> bool {
+        let on = match self.demand {
+            Some(true) => temp < target + self.hysteresis,
+            _ => temp < target - self.hysteresis,
+        };
+        self.demand = Some(on);
+        on
+    }
+}
+
+/// Open windows/doors of a heating zone, tracked by the state machine
+#[derive(Default)]
+pub struct OpenWindows {
+    pub open: HashSet<String>,
+    pub since: Option<Instant>,
+    pub cutback: bool,
+}
+
+impl OpenWindows {
+    /// Updates the sensor state, returns true when the last window was closed
+    pub fn update(&mut self, sensor_name: &str, open: bool) -> bool {
+        if open {
+            self.open.insert(sensor_name.to_string());
+            self.since.get_or_insert_with(Instant::now);
+            return false;
+        }
+        self.open.remove(sensor_name);
+        if !self.open.is_empty() {
+            return false;
+        }
+        self.since = None;
+        std::mem::replace(&mut self.cutback, false)
+    }
+
+    /// True when the cutback has to start now
+    pub fn check(&mut self, delay: Duration) -> bool {
+        match self.since {
+            Some(since) if !self.cutback && since.elapsed() >= delay => {
+                self.cutback = true;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Boiler CH setpoints written through the remeha task (needs `remeha_allow_write`)
+pub struct BoilerControl {
+    pub boiler: String,
+    pub demand_setpoint: RemehaSetpoint,
+    pub idle_setpoint: RemehaSetpoint,
+}
+
+pub struct HeatingController {
+    pub name: String,
+    pub zones: Vec<HeatingZone>,
+    pub boiler: Option<BoilerControl>,
+    pub check_interval: Duration,
+    pub sensor_values: Arc<RwLock<SensorValues>>,
+    pub heating_season: Arc<RwLock<HeatingSeason>>,
+    pub ow_transmitter: Sender<OneWireTask>,
+    pub setpoint_requests: Arc<Mutex<Vec<RemehaSetpointRequest>>>,
+    /// zones with a window open for too long
+    pub window_cutback: Arc<RwLock<HashSet<String>>>,
+}
+
+impl HeatingController {
+    fn set_valves(&self, zone: &HeatingZone, on: bool) {
+        for id_relay in &zone.relays {
+            //when on, keep prolonging until the next check, so a stopped controller
+            //can't leave the valve open forever
+            let task = OneWireTask {
+                command: if on {
+                    TaskCommand::TurnOnProlong
+                } else {
+                    TaskCommand::TurnOff
+                },
+                id_relay: Some(*id_relay),
+                tag_group: None,
+                id_yeelight: None,
+                id_plug: None,
+                duration: if on {
+                    Some(self.check_interval * 2)
+                } else {
+                    None
+                },
+            };
+            let _ = self.ow_transmitter.send(task);
+        }
+    }
+
+    fn write_boiler_setpoint(&self, demand: bool) {
+        let boiler = match &self.boiler {
+            Some(boiler) => boiler,
+            None => return,
+        };
+        let setpoint = if demand {
+            boiler.demand_setpoint
+        } else {
+            boiler.idle_setpoint
+        };
+        info!(
+            "{}: 🔥 heat demand {}, setting {} {}",
+            self.name,
+            if demand { "started" } else { "ended" },
+            boiler.boiler,
+            setpoint,
+        );
+        if let Ok(mut requests) = self.setpoint_requests.lock() {
+            requests.push(RemehaSetpointRequest {
+                boiler: boiler.boiler.clone(),
+                setpoint,
+            });
+        }
+    }
+
+    /// Evaluates all zones, returns true when any of them needs heat
+    fn check(&mut self) -> bool {
+        let season = self.heating_season.read().unwrap().is_active();
+        let now = Local::now().time();
+        let mut any_demand = false;
+        for i in 0..self.zones.len() {
+            let temp = self
+                .sensor_values
+                .read()
+                .unwrap()
+                .get(&self.zones[i].sensor)
+                .cloned();
+            let cutback = self
+                .window_cutback
+                .read()
+                .unwrap()
+                .contains(&self.zones[i].name);
+            let zone = &mut self.zones[i];
+            let previous = zone.demand;
+            let target = match (cutback, zone.cutback_temp) {
+                (true, Some(cutback_temp)) => cutback_temp,
+                _ => zone.target(now),
+            };
+            let on = match temp {
+                Some(_) if cutback && zone.cutback_temp.is_none() => {
+                    if previous != Some(false) {
+                        info!(
+                            "{}: {}: 🪟 window open, valves closed",
+                            self.name, zone.name
+                        );
+                    }
+                    false
+                }
+                Some(temp) => {
+                    let on = zone.update(temp, target) && season;
+                    if previous != Some(on) {
+                        info!(
+                            "{}: {}: 🌡️ temperature {}, target {} ±{}{}{}: valves {}",
+                            self.name,
+                            zone.name,
+                            units::temperature(temp),
+                            units::temperature(target),
+                            units::temperature_delta(zone.hysteresis),
+                            if cutback { " (window open)" } else { "" },
+                            if season {
+                                ""
+                            } else {
+                                " (out of heating season)"
+                            },
+                            if on { "open" } else { "closed" },
+                        );
+                    }
+                    on
+                }
+                None => {
+                    if previous == Some(true) {
+                        warn!(
+                            "{}: {}: no temperature from {:?}, closing valves",
+                            self.name, zone.name, zone.sensor
+                        );
+                    }
+                    false
+                }
+            };
+            zone.demand = Some(on);
+            any_demand |= on;
+            //valves are refreshed while open, closed once on the change
+            if on || previous != Some(false) {
+                self.set_valves(&self.zones[i], on);
+            }
+        }
+        any_demand
+    }
+
+    pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
+        info!(
+            "{}: Starting task, zones: {:?}",
+            self.name,
+            self.zones.iter().map(|z| &z.name).collect::<Vec<_>>()
+        );
+        let mut last_check: Option<Instant> = None;
+        let mut boiler_demand: Option<bool> = None;
+
+        loop {
+            if worker_cancel_flag.load(Ordering::SeqCst) {
+                debug!("{}: Got terminate signal from main", self.name);
+                break;
+            }
+
+            if last_check.map_or(true, |t| t.elapsed() >= self.check_interval) {
+                last_check = Some(Instant::now());
+                let demand = self.check();
+                if boiler_demand != Some(demand) {
+                    boiler_demand = Some(demand);
+                    self.write_boiler_setpoint(demand);
+                }
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        info!("{}: task stopped", self.name);
+        Ok(())
+    }
+}
+
+pub fn parse_time(value: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(value.trim(), "%H:%M").ok()
+}
+
+/// Heating zones from the `[heating_zone:<name>]` sections
+pub fn zones_from_config(config: &Config) -> Vec<HeatingZone> {
+    config
+        .heating_zones
+        .iter()
+        .map(|zone| {
+            debug!(
+                "Got heating zone: {:?} sensor={:?} relays={:?} day={}°C from {} night={}°C from {}",
+                zone.name,
+                zone.sensor,
+                zone.relays,
+                zone.day_temp,
+                zone.day_start,
+                zone.night_temp,
+                zone.night_start
+            );
+            HeatingZone {
+                name: zone.name.clone(),
+                sensor: zone.sensor.clone(),
+                relays: zone.relays.clone(),
+                day_temp: zone.day_temp,
+                night_temp: zone.night_temp,
+                day_start: zone.day_start,
+                night_start: zone.night_start,
+                hysteresis: zone.hysteresis,
+                cutback_temp: zone.cutback_temp,
+                demand: None,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,146 @@
+use crate::units;
+use chrono::{Datelike, Local};
+use serde::Serialize;
+use simplelog::*;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+pub const HEATING_SEASON_DEFAULT_ON_BELOW: f32 = 15.0; //°C average outside temperature to start the season
+pub const HEATING_SEASON_DEFAULT_OFF_ABOVE: f32 = 17.0; //°C average outside temperature to end the season
+pub const HEATING_SEASON_DEFAULT_WINDOW_HOURS: f32 = 72.0; //rolling average length
+pub const HEATING_SEASON_DEFAULT_LCD_LINE: u8 = 1;
+pub static HEATING_SEASON_VALUE_NAME: &str = "heating_season"; //variable name for the rules
+
+#[derive(Serialize)]
+pub struct HeatingSeasonStatus {
+    pub active: bool,
+    pub automatic: bool,
+    pub manual: Option<bool>,
+    pub average_temp: Option<f32>,
+    pub samples: usize,
+}
+
+/// Heating season derived from the rolling average of the outside temperature,
+/// or from the calendar dates when there is no outside sensor
+pub struct HeatingSeason {
+    pub outside_sensor: Option<String>,
+    pub on_below: f32,
+    pub off_above: f32,
+    pub window: Duration,
+    //(month, day) of the season start and end
+    pub calendar: Option<((u32, u32), (u32, u32))>,
+    pub manual: Option<bool>,
+    pub lcd_line: u8,
+    automatic: bool,
+    active: bool,
+    evaluated: bool,
+    samples: VecDeque<(Instant, f32)>,
+}
+
+impl HeatingSeason {
+    pub fn new(
+        outside_sensor: Option<String>,
+        on_below: Option<f32>,
+        off_above: Option<f32>,
+        window_hours: Option<f32>,
+        calendar: Option<((u32, u32), (u32, u32))>,
+        lcd_line: Option<u8>,
+    ) -> Self {
+        HeatingSeason {
+            outside_sensor,
+            on_below: on_below.unwrap_or(HEATING_SEASON_DEFAULT_ON_BELOW),
+            off_above: off_above.unwrap_or(HEATING_SEASON_DEFAULT_OFF_ABOVE),
+            window: Duration::from_secs_f32(
+                window_hours.unwrap_or(HEATING_SEASON_DEFAULT_WINDOW_HOURS) * 3600.0,
+            ),
+            calendar,
+            manual: None,
+            lcd_line: lcd_line.unwrap_or(HEATING_SEASON_DEFAULT_LCD_LINE),
+            //without any configuration nothing is gated
+            automatic: true,
+            active: true,
+            evaluated: false,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Parses a `MM-DD` date
+    pub fn parse_month_day(date: &str) -> Option<(u32, u32)> {
+        let mut v = date.trim().split('-');
+        let month = v.next()?.parse().ok()?;
+        let day = v.next()?.parse().ok()?;
+        Some((month, day))
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn add_sample(&mut self, temp: f32) {
+        self.samples.push_back((Instant::now(), temp));
+        while self
+            .samples
+            .front()
+            .map_or(false, |(t, _)| t.elapsed() > self.window)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn average(&self) -> Option<f32> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        Some(self.samples.iter().map(|(_, temp)| temp).sum::<f32>() / self.samples.len() as f32)
+    }
+
+    fn in_calendar_season(&self) -> Option<bool> {
+        let (start, end) = self.calendar?;
+        let now = Local::now();
+        let today = (now.month(), now.day());
+        Some(if start <= end {
+            today >= start && today <= end
+        } else {
+            //season over the new year
+            today >= start || today <= end
+        })
+    }
+
+    /// Re-evaluates the season, returns the new state when it has changed
+    pub fn evaluate(&mut self) -> Option<bool> {
+        self.automatic = match self.average() {
+            Some(avg) if self.automatic && avg > self.off_above => false,
+            Some(avg) if !self.automatic && avg < self.on_below => true,
+            Some(_) => self.automatic,
+            None => self.in_calendar_season().unwrap_or(self.automatic),
+        };
+        let active = self.manual.unwrap_or(self.automatic);
+        if active == self.active && self.evaluated {
+            return None;
+        }
+        self.active = active;
+        self.evaluated = true;
+        info!(
+            "heating_season: 🔥 heating season is now <b>{}</> (average outside temperature: {}{})",
+            if active { "on" } else { "off" },
+            self.average()
+                .map_or("unknown".to_string(), units::temperature),
+            if self.manual.is_some() {
+                ", manual override"
+            } else {
+                ""
+            },
+        );
+        Some(active)
+    }
+
+    pub fn status(&self) -> HeatingSeasonStatus {
+        HeatingSeasonStatus {
+            active: self.active,
+            automatic: self.automatic,
+            manual: self.manual,
+            average_temp: self.average(),
+            samples: self.samples.len(),
+        }
+    }
+}
@@ -0,0 +1,717 @@
+use crate::chaos::{self, Fault};
+use chrono::{DateTime, Utc};
+use simplelog::*;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex, RwLock};
+use std::thread;
+
+pub const INFLUX_TIMEOUT_SECS: u64 = 10; //http request timeout for influxdb writes
+pub const INFLUX_SPOOL_MAX_BATCHES: usize = 2000; //failed batches kept per server and database/bucket
+pub const INFLUX_RETRY_BATCHES: usize = 50; //max spooled batches re-sent after a successful write
+
+/// InfluxDB 2.x settings, when not set the v1 API is used
+#[derive(Clone, Debug)]
+pub struct InfluxV2 {
+    pub org: String,
+    pub token: String,
+    //default bucket, when not set the v1 database name is used as a bucket name
+    pub bucket: Option<String>,
+    //v1 database name -> bucket
+    pub buckets: HashMap<String, String>,
+}
+
+static INFLUX_V2: RwLock<Option<InfluxV2>> = RwLock::new(None);
+
+/// Switches all clients to the InfluxDB 2.x API, called once on startup
+pub fn init_v2(config: InfluxV2) {
+    *INFLUX_V2.write().unwrap() = Some(config);
+}
+
+/// Failed batches waiting for the database to be reachable again, per database/bucket
+/// and server, optionally persisted to a directory so they survive a restart
+#[derive(Default)]
+struct Spool {
+    queues: HashMap<String, SpoolQueue>,
+    writer: Option<mpsc::Sender<SpoolWrite>>,
+}
+
+#[derive(Default)]
+struct SpoolQueue {
+    batches: VecDeque<String>,
+    //lines in the spool file, the dropped and re-sent batches are removed by a rewrite
+    file_lines: usize,
+}
+
+/// Spool file changes, done in order by the spool writer thread
+enum SpoolWrite {
+    Append(String, String),
+    Rewrite(String, Vec<String>),
+}
+
+static SPOOL: Mutex<Option<Spool>> = Mutex::new(None);
+
+impl Spool {
+    fn file(dir: &Path, key: &str) -> PathBuf {
+        dir.join(format!("{}.spool", key))
+    }
+
+    /// Writes the spool files, so the file system is never touched by the async writes
+    fn writer(dir: PathBuf, receiver: mpsc::Receiver<SpoolWrite>) {
+        //every batch is stored as a json string per line
+        for write in receiver {
+            let (key, result) = match write {
+                SpoolWrite::Append(key, batch) => {
+                    let result = OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(Spool::file(&dir, &key))
+                        .and_then(|mut file| {
+                            writeln!(
+                                file,
+                                "{}",
+                                serde_json::to_string(&batch).unwrap_or_default()
+                            )
+                        });
+                    (key, result)
+                }
+                SpoolWrite::Rewrite(key, batches) if batches.is_empty() => {
+                    let _ = fs::remove_file(Spool::file(&dir, &key));
+                    (key, Ok(()))
+                }
+                SpoolWrite::Rewrite(key, batches) => {
+                    let result = fs::File::create(Spool::file(&dir, &key)).and_then(|mut file| {
+                        for batch in &batches {
+                            writeln!(file, "{}", serde_json::to_string(batch).unwrap_or_default())?;
+                        }
+                        Ok(())
+                    });
+                    (key, result)
+                }
+            };
+            if let Err(e) = result {
+                error!("influxdb: cannot write spool file for <i>{}</>: {}", key, e);
+            }
+        }
+    }
+
+    fn load(&mut self, dir: &Path) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("influxdb: cannot read spool directory {:?}: {}", dir, e);
+                return;
+            }
+        };
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            let key = match path.file_name().and_then(|n| n.to_str()) {
+                Some(name) => match name.strip_suffix(".spool") {
+                    Some(key) => key.to_string(),
+                    None => continue,
+                },
+                None => continue,
+            };
+            let lines: Vec<String> = fs::read_to_string(&path)
+                .unwrap_or_default()
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect();
+            let file_lines = lines.len();
+            //the file is appended beyond the limit until its next rewrite
+            let batches: VecDeque<String> = lines
+                .into_iter()
+                .skip(file_lines.saturating_sub(INFLUX_SPOOL_MAX_BATCHES))
+                .collect();
+            if !batches.is_empty() {
+                info!(
+                    "influxdb: 📦 {} spooled batches pending for <i>{}</>",
+                    batches.len(),
+                    key
+                );
+                self.queues.insert(
+                    key,
+                    SpoolQueue {
+                        batches,
+                        file_lines,
+                    },
+                );
+            }
+        }
+    }
+
+    fn write(&self, write: SpoolWrite) {
+        if let Some(writer) = &self.writer {
+            let _ = writer.send(write);
+        }
+    }
+
+    /// Rewrites the spool file with the batches still waiting
+    fn rewrite(&mut self, key: &str) {
+        if self.writer.is_none() {
+            return;
+        }
+        if let Some(queue) = self.queues.get_mut(key) {
+            queue.file_lines = queue.batches.len();
+            let batches = queue.batches.iter().cloned().collect();
+            self.write(SpoolWrite::Rewrite(key.to_string(), batches));
+        }
+    }
+}
+
+/// Enables the failed writes spool (persisted when the directory is given), called once on startup
+pub fn init_spool(dir: Option<String>) {
+    let mut spool = Spool::default();
+    if let Some(dir) = dir.map(PathBuf::from) {
+        spool.load(&dir);
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || Spool::writer(dir, receiver));
+        spool.writer = Some(sender);
+    }
+    *SPOOL.lock().unwrap() = Some(spool);
+}
+
+fn spool_push(key: &str, batch: String) {
+    if let Some(spool) = SPOOL.lock().unwrap().as_mut() {
+        let queue = spool.queues.entry(key.to_string()).or_default();
+        if queue.batches.len() >= INFLUX_SPOOL_MAX_BATCHES {
+            queue.batches.pop_front();
+            warn!(
+                "influxdb: spool for <i>{}</> is full, dropping the oldest batch",
+                key
+            );
+        }
+        queue.batches.push_back(batch.clone());
+        queue.file_lines += 1;
+        let compact = queue.file_lines >= 2 * INFLUX_SPOOL_MAX_BATCHES;
+        if compact {
+            spool.rewrite(key);
+        } else {
+            spool.write(SpoolWrite::Append(key.to_string(), batch));
+        }
+    }
+}
+
+fn spool_take(key: &str, count: usize) -> Vec<String> {
+    match SPOOL.lock().unwrap().as_mut() {
+        Some(spool) => match spool.queues.get_mut(key) {
+            Some(queue) => {
+                let count = count.min(queue.batches.len());
+                queue.batches.drain(..count).collect()
+            }
+            None => vec![],
+        },
+        None => vec![],
+    }
+}
+
+/// Puts back the batches which still couldn't be written, keeping their order
+fn spool_return(key: &str, mut returned: Vec<String>) {
+    if let Some(spool) = SPOOL.lock().unwrap().as_mut() {
+        let queue = spool.queues.entry(key.to_string()).or_default();
+        while let Some(batch) = returned.pop() {
+            queue.batches.push_front(batch);
+        }
+        queue.batches.truncate(INFLUX_SPOOL_MAX_BATCHES);
+        spool.rewrite(key);
+    }
+}
+
+#[derive(Clone)]
+enum Backend {
+    V1 {
+        database: String,
+    },
+    V2 {
+        org: String,
+        bucket: String,
+        token: String,
+    },
+}
+
+/// Minimal InfluxDB (line protocol over HTTP) client running on the main tokio runtime,
+/// writing to InfluxDB 1.x databases or 2.x buckets
+#[derive(Clone)]
+pub struct Client {
+    url: String,
+    backend: Backend,
+    http: reqwest::Client,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Connection(String),
+    Response(u16, String),
+    /// The write failed, but the batch is spooled and re-sent by the client:
+    /// the caller must not write the same points again
+    Spooled(Box<Error>),
+}
+
+impl Error {
+    /// The database is unreachable or overloaded, the write may succeed later
+    fn is_retryable(&self) -> bool {
+        match self {
+            Error::Connection(_) => true,
+            Error::Response(status, _) => *status >= 500 || *status == 429,
+            Error::Spooled(_) => false,
+        }
+    }
+
+    pub fn is_spooled(&self) -> bool {
+        matches!(self, Error::Spooled(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Connection(e) => write!(f, "connection error: {}", e),
+            Error::Response(status, body) => write!(f, "http status {}: {}", status, body),
+            Error::Spooled(e) => write!(f, "{}, spooled for retry", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Client {
+    pub fn new<U: Into<String>, D: Into<String>>(url: U, database: D) -> Self {
+        let database = database.into();
+        let backend = match INFLUX_V2.read().unwrap().as_ref() {
+            Some(v2) => Backend::V2 {
+                org: v2.org.clone(),
+                bucket: v2
+                    .buckets
+                    .get(&database)
+                    .or(v2.bucket.as_ref())
+                    .unwrap_or(&database)
+                    .clone(),
+                token: v2.token.clone(),
+            },
+            None => Backend::V1 { database },
+        };
+        Client {
+            url: url.into(),
+            backend,
+            http: reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(INFLUX_TIMEOUT_SECS))
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    fn target(&self) -> &str {
+        match &self.backend {
+            Backend::V1 { database } => database,
+            Backend::V2 { bucket, .. } => bucket,
+        }
+    }
+
+    /// Spool (and spool file) name of the server and database/bucket
+    fn spool_key(&self) -> String {
+        format!("{}_{}", self.url.trim_end_matches('/'), self.target())
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+
+    /// Sends the line protocol body, timestamps are always in nanoseconds
+    async fn post(&self, body: String) -> Result<String, Error> {
+        if chaos::inject(Fault::InfluxWrite) {
+            return Err(Error::Connection("chaos: injected write failure".into()));
+        }
+        let base_url = self.url.trim_end_matches('/');
+        let request = match &self.backend {
+            Backend::V1 { database } => self
+                .http
+                .post(&format!("{}/write", base_url))
+                .query(&[("db", database.as_str()), ("precision", "n")]),
+            Backend::V2 { org, bucket, token } => self
+                .http
+                .post(&format!("{}/api/v2/write", base_url))
+                .query(&[
+                    ("org", org.as_str()),
+                    ("bucket", bucket.as_str()),
+                    ("precision", "ns"),
+                ])
+                .header("Authorization", format!("Token {}", token)),
+        };
+        let response = request
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| Error::Connection(e.to_string()))?;
+
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        if status.is_success() {
+            Ok(body)
+        } else {
+            Err(Error::Response(status.as_u16(), body))
+        }
+    }
+
+    pub async fn query(&self, query: &WriteQuery) -> Result<String, Error> {
+        self.write(std::slice::from_ref(query)).await
+    }
+
+    /// Writes all points in a single request. When the database is unreachable
+    /// the batch is spooled and re-sent after the next successful write.
+    pub async fn write(&self, queries: &[WriteQuery]) -> Result<String, Error> {
+        let lines: Vec<String> = queries.iter().filter_map(|q| q.to_line()).collect();
+        if lines.is_empty() {
+            return Ok(String::new());
+        }
+        let body = lines.join("\n");
+        match self.post(body.clone()).await {
+            Ok(msg) => {
+                self.retry_spooled().await;
+                Ok(msg)
+            }
+            Err(e) if e.is_retryable() && SPOOL.lock().unwrap().is_some() => {
+                warn!(
+                    "influxdb: <i>{}</>: write failed, spooling {} points for retry: {}",
+                    self.target(),
+                    lines.len(),
+                    e
+                );
+                spool_push(&self.spool_key(), body);
+                Err(Error::Spooled(Box::new(e)))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn retry_spooled(&self) {
+        let key = self.spool_key();
+        let batches = spool_take(&key, INFLUX_RETRY_BATCHES);
+        if batches.is_empty() {
+            return;
+        }
+        let count = batches.len();
+        let mut pending = batches.into_iter();
+        while let Some(batch) = pending.next() {
+            if let Err(e) = self.post(batch.clone()).await {
+                if e.is_retryable() {
+                    let mut returned = vec![batch];
+                    returned.extend(pending);
+                    spool_return(&key, returned);
+                    return;
+                }
+                //rejected data will never be accepted
+                error!(
+                    "influxdb: <i>{}</>: dropping spooled batch: {}",
+                    self.target(),
+                    e
+                );
+            }
+        }
+        info!(
+            "influxdb: <i>{}</>: ✅ {} spooled batches written",
+            self.target(),
+            count
+        );
+        //removes the written batches from the file (or the file when all are written)
+        if let Some(spool) = SPOOL.lock().unwrap().as_mut() {
+            spool.rewrite(&key);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Type {
+    Boolean(bool),
+    Float(f64),
+    SignedInteger(i64),
+    UnsignedInteger(u64),
+    Text(String),
+}
+
+macro_rules! from_impl {
+    ($variant:ident, $target:ty, $($t:ty),*) => {
+        $(
+            impl From<$t> for Type {
+                fn from(value: $t) -> Self {
+                    Type::$variant(value as $target)
+                }
+            }
+        )*
+    };
+}
+from_impl!(Float, f64, f32, f64);
+from_impl!(SignedInteger, i64, i8, i16, i32, i64);
+from_impl!(UnsignedInteger, u64, u8, u16, u32, u64);
+
+impl From<bool> for Type {
+    fn from(value: bool) -> Self {
+        Type::Boolean(value)
+    }
+}
+
+impl From<String> for Type {
+    fn from(value: String) -> Self {
+        Type::Text(value)
+    }
+}
+
+impl From<&str> for Type {
+    fn from(value: &str) -> Self {
+        Type::Text(value.to_string())
+    }
+}
+
+macro_rules! from_ref_impl {
+    ($($t:ty),*) => {
+        $(
+            impl From<&$t> for Type {
+                fn from(value: &$t) -> Self {
+                    value.clone().into()
+                }
+            }
+        )*
+    };
+}
+from_ref_impl!(bool, f32, f64, i8, i16, i32, i64, u8, u16, u32, u64, String);
+
+impl Type {
+    /// NaN and infinity are not valid in the line protocol
+    fn is_writable(&self) -> bool {
+        match self {
+            Type::Float(v) => v.is_finite(),
+            _ => true,
+        }
+    }
+
+    fn to_field_value(&self) -> String {
+        match self {
+            Type::Boolean(v) => v.to_string(),
+            Type::Float(v) => v.to_string(),
+            Type::SignedInteger(v) => format!("{}i", v),
+            //influxdb 1.x has no unsigned fields: clamped to the signed range
+            Type::UnsignedInteger(v) => format!("{}i", (*v).min(i64::MAX as u64)),
+            Type::Text(v) => format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\"")),
+        }
+    }
+
+    fn to_tag_value(&self) -> String {
+        match self {
+            Type::Text(v) => escape_key(v),
+            other => other.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Boolean(v) => write!(f, "{}", v),
+            Type::Float(v) => write!(f, "{}", v),
+            Type::SignedInteger(v) => write!(f, "{}", v),
+            Type::UnsignedInteger(v) => write!(f, "{}", v),
+            Type::Text(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+//escaping for measurement names, tag keys/values and field keys
+fn escape_key(s: &str) -> String {
+    s.replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Timestamp {
+    Nanoseconds(u128),
+    Microseconds(u128),
+    Milliseconds(u128),
+    Seconds(u128),
+}
+
+impl Timestamp {
+    /// All points are written with the nanosecond precision, so they can be batched
+    fn nanos(&self) -> u128 {
+        match self {
+            Timestamp::Nanoseconds(v) => *v,
+            Timestamp::Microseconds(v) => v * 1_000,
+            Timestamp::Milliseconds(v) => v * 1_000_000,
+            Timestamp::Seconds(v) => v * 1_000_000_000,
+        }
+    }
+}
+
+impl From<DateTime<Utc>> for Timestamp {
+    fn from(time: DateTime<Utc>) -> Self {
+        //times outside of the i64 nanoseconds range (years 1677-2262) are written as the epoch
+        Timestamp::Nanoseconds(time.timestamp_nanos_opt().map_or(0, |n| n.max(0) as u128))
+    }
+}
+
+/// A single point in line protocol
+#[derive(Clone, Debug)]
+pub struct WriteQuery {
+    measurement: String,
+    timestamp: Timestamp,
+    tags: Vec<(String, Type)>,
+    fields: Vec<(String, Type)>,
+}
+
+impl WriteQuery {
+    pub fn add_tag<S: Into<String>, T: Into<Type>>(mut self, name: S, value: T) -> Self {
+        self.tags.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn add_field<S: Into<String>, T: Into<Type>>(mut self, name: S, value: T) -> Self {
+        self.fields.push((name.into(), value.into()));
+        self
+    }
+
+    /// Adds the field only when the value is known
+    pub fn add_field_opt<S: Into<String>, T: Into<Type>>(self, name: S, value: Option<T>) -> Self {
+        match value {
+            Some(value) => self.add_field(name, value),
+            None => self,
+        }
+    }
+
+    pub fn fields(&self) -> &Vec<(String, Type)> {
+        &self.fields
+    }
+
+    /// The point in line protocol, the non-finite fields are skipped and a point left
+    /// without fields is dropped (it would fail the whole batch)
+    pub fn to_line(&self) -> Option<String> {
+        let fields: Vec<String> = self
+            .fields
+            .iter()
+            .filter(|(_, value)| value.is_writable())
+            .map(|(name, value)| format!("{}={}", escape_key(name), value.to_field_value()))
+            .collect();
+        if fields.is_empty() {
+            debug!(
+                "influxdb: dropping <i>{}</> point without valid fields: {:?}",
+                self.measurement, self.fields
+            );
+            return None;
+        }
+        let mut line = escape_key(&self.measurement);
+        for (name, value) in &self.tags {
+            line.push_str(&format!(",{}={}", escape_key(name), value.to_tag_value()));
+        }
+        Some(format!(
+            "{} {} {}",
+            line,
+            fields.join(","),
+            self.timestamp.nanos()
+        ))
+    }
+}
+
+pub trait InfluxDbWriteable {
+    fn into_query<I: Into<String>>(self, name: I) -> WriteQuery;
+}
+
+impl InfluxDbWriteable for Timestamp {
+    fn into_query<I: Into<String>>(self, name: I) -> WriteQuery {
+        WriteQuery {
+            measurement: name.into(),
+            timestamp: self,
+            tags: vec![],
+            fields: vec![],
+        }
+    }
+}
+
+/// Implements `InfluxDbWriteable` for a struct with a `time: DateTime<Utc>` field,
+/// writing the listed fields (`Option` fields are skipped when `None`)
+#[macro_export]
+macro_rules! influx_writeable {
+    ($type:ty { $($field:ident),* $(,)? } $(optional { $($opt:ident),* $(,)? })?) => {
+        impl $crate::influx::InfluxDbWriteable for $type {
+            fn into_query<I: Into<String>>(self, name: I) -> $crate::influx::WriteQuery {
+                let query = <$crate::influx::Timestamp as $crate::influx::InfluxDbWriteable>::into_query(
+                    $crate::influx::Timestamp::from(self.time),
+                    name,
+                );
+                $(let query = query.add_field(stringify!($field), self.$field);)*
+                $($(let query = query.add_field_opt(stringify!($opt), self.$opt);)*)?
+                query
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn point() -> WriteQuery {
+        Timestamp::Seconds(1_600_000_000)
+            .into_query("skymax")
+            .add_tag("serial number", "9293")
+    }
+
+    #[test]
+    fn line_protocol() {
+        let query = point()
+            .add_field("voltage", 230.5)
+            .add_field("load", 12u8)
+            .add_field("mode", "line \"L\"")
+            .add_field("charging", true);
+        assert_eq!(
+            query.to_line().unwrap(),
+            "skymax,serial\\ number=9293 voltage=230.5,load=12i,mode=\"line \\\"L\\\"\",charging=true 1600000000000000000"
+        );
+    }
+
+    #[test]
+    fn unsigned_fields_are_clamped() {
+        let query = point().add_tag("port", 3u16).add_field("energy", u64::MAX);
+        assert_eq!(
+            query.to_line().unwrap(),
+            "skymax,serial\\ number=9293,port=3 energy=9223372036854775807i 1600000000000000000"
+        );
+    }
+
+    #[test]
+    fn timestamp_from_time() {
+        let time = Utc.timestamp_opt(1_600_000_000, 5).unwrap();
+        assert_eq!(Timestamp::from(time).nanos(), 1_600_000_000_000_000_005);
+    }
+
+    #[test]
+    fn non_finite_fields_are_skipped() {
+        let query = point()
+            .add_field("voltage", f64::NAN)
+            .add_field("power", 1200.0)
+            .add_field("efficiency", f32::INFINITY);
+        assert_eq!(
+            query.to_line().unwrap(),
+            "skymax,serial\\ number=9293 power=1200 1600000000000000000"
+        );
+
+        let query = point()
+            .add_field("voltage", f64::NAN)
+            .add_field("efficiency", f64::NEG_INFINITY);
+        assert_eq!(query.to_line(), None);
+        assert_eq!(point().to_line(), None);
+    }
+
+    #[tokio::test]
+    async fn points_without_fields_are_not_sent() {
+        //nothing is listening there
+        let client = Client::new("http://127.0.0.1:9", "hard");
+        let query = point().add_field("voltage", f64::NAN);
+        assert_eq!(client.write(&[query]).await.unwrap(), "");
+    }
+}
@@ -0,0 +1,156 @@
+use crate::logbuffer::{module_name, strip_markup};
+use chrono::{Local, SecondsFormat};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+use simplelog::{Config, SharedLogger};
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+
+//ids and addresses found in the messages (`id_relay=5`, `address: 0x1f`), logged as separate fields
+const KEY_FIELDS: [&str; 6] = [
+    "id_relay",
+    "id_sensor",
+    "id_yeelight",
+    "id_plug",
+    "register",
+    "address",
+];
+
+/// Output format of the log file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// same as the console, without colors
+    Text,
+    /// one JSON object per line, for Loki/Elasticsearch ingestion
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Option<LogFormat> {
+        match format.trim().to_lowercase().as_str() {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Worker name from the common `<name>: message` prefix
+fn worker_name(message: &str) -> Option<&str> {
+    let (name, _) = message.split_once(": ")?;
+    let name = name.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Value following `key=` or `key: ` in the message
+fn key_field<'a>(message: &'a str, key: &str) -> Option<&'a str> {
+    let mut rest = message;
+    while let Some(pos) = rest.find(key) {
+        let before = rest[..pos].chars().last();
+        let after = rest[pos + key.len()..]
+            .strip_prefix('=')
+            .or_else(|| rest[pos + key.len()..].strip_prefix(": "));
+        rest = &rest[pos + key.len()..];
+        //only whole words, `address` must not match `ip_address`
+        if before.map_or(false, |c| c.is_alphanumeric() || c == '_') {
+            continue;
+        }
+        if let Some(after) = after {
+            let value = after
+                .split(|c: char| c.is_whitespace() || c == ',' || c == ')' || c == ']')
+                .next()
+                .unwrap_or_default();
+            if !value.is_empty() {
+                return Some(value);
+            }
+        }
+    }
+    None
+}
+
+/// File logger writing a JSON record per line
+pub struct JsonLogger {
+    level: LevelFilter,
+    file: Mutex<File>,
+}
+
+impl JsonLogger {
+    pub fn new(level: LevelFilter, file: File) -> Box<JsonLogger> {
+        Box::new(JsonLogger {
+            level,
+            file: Mutex::new(file),
+        })
+    }
+
+    fn record(record: &Record) -> Value {
+        let message = strip_markup(&record.args().to_string());
+        let mut fields = Map::new();
+        fields.insert(
+            "timestamp".into(),
+            Local::now()
+                .to_rfc3339_opts(SecondsFormat::Millis, false)
+                .into(),
+        );
+        fields.insert(
+            "level".into(),
+            record.level().to_string().to_lowercase().into(),
+        );
+        fields.insert("module".into(), module_name(record).into());
+        if let Some(worker) = worker_name(&message) {
+            fields.insert("worker".into(), worker.into());
+        }
+        for key in KEY_FIELDS {
+            if let Some(value) = key_field(&message, key) {
+                //numbers stay numbers, so they can be filtered by range
+                let value = value
+                    .parse::<i64>()
+                    .map(Value::from)
+                    .unwrap_or_else(|_| value.into());
+                fields.insert(key.into(), value);
+            }
+        }
+        fields.insert("message".into(), message.into());
+        Value::Object(fields)
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = JsonLogger::record(record).to_string();
+        if let Ok(mut file) = self.file.lock() {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.flush();
+        }
+    }
+}
+
+impl SharedLogger for JsonLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
@@ -1,7 +1,8 @@
+use crate::config::Config;
+use crate::queue::Receiver;
 use simplelog::*;
 use std::io::{Error, ErrorKind};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::Receiver;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
@@ -9,16 +10,34 @@ use tokio::net::TcpStream;
 use tokio::time::timeout;
 
 pub const READ_INTERVAL_SECS: f32 = 1.0; //secs between reading data from TCP connection when idle
+pub const LCDPROC_RECONNECT_MIN_SECS: f32 = 1.0;
+pub const LCDPROC_RECONNECT_MAX_SECS: f32 = 60.0;
+pub const LCDPROC_DEFAULT_WIDTH: u8 = 20;
+pub const LCDPROC_DEFAULT_HEIGHT: u8 = 4;
 
 // Just a generic Result type to ease error handling for us. Errors in multithreaded
 // async contexts needs some extra restrictions
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
+pub const DEFAULT_SCREEN_DURATION_SECS: f32 = 5.0; //time a screen is shown in the rotation
+
 #[derive(Clone, Debug)]
 pub enum LcdTaskCommand {
     SetLineText,
     SetCesspoolLevel,
     SetEmergencyMode,
+    /// registers (or changes the priority of) a named screen of the sending worker
+    AddScreen {
+        screen: String,
+        priority: ScreenPriority,
+    },
+    /// int_arg is the line of the named screen, string_arg the text
+    SetScreenLine {
+        screen: String,
+    },
+    RemoveScreen {
+        screen: String,
+    },
 }
 #[derive(Clone)]
 pub struct LcdTask {
@@ -27,22 +46,148 @@ pub struct LcdTask {
     pub string_arg: Option<String>,
 }
 
+/// LCDd screen priority, the screens with the same priority are rotated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScreenPriority {
+    Background,
+    Info,
+    Foreground,
+    Alert,
+}
+
+impl ScreenPriority {
+    pub fn parse(priority: &str) -> Option<ScreenPriority> {
+        match priority.trim().to_lowercase().as_str() {
+            "background" => Some(ScreenPriority::Background),
+            "info" => Some(ScreenPriority::Info),
+            "foreground" => Some(ScreenPriority::Foreground),
+            "alert" => Some(ScreenPriority::Alert),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScreenPriority::Background => "background",
+            ScreenPriority::Info => "info",
+            ScreenPriority::Foreground => "foreground",
+            ScreenPriority::Alert => "alert",
+        }
+    }
+}
+
+/// Named screen besides the main one, defined in the config (`[lcd_screen:<name>]`)
+/// or registered by a worker with `LcdTaskCommand::AddScreen`
+#[derive(Clone, Debug)]
+pub struct LcdScreen {
+    pub name: String,
+    pub priority: ScreenPriority,
+    pub duration: Duration,
+    pub lines: Vec<String>,
+    pub source_lines: Vec<usize>, //lcd lines (SetLineText) mirrored on the screen rows
+}
+
+impl LcdScreen {
+    pub fn new(name: &str, priority: ScreenPriority) -> Self {
+        LcdScreen {
+            name: name.to_string(),
+            priority,
+            duration: Duration::from_secs_f32(DEFAULT_SCREEN_DURATION_SECS),
+            lines: vec![],
+            source_lines: vec![],
+        }
+    }
+
+    //LCDd screen id: only the safe characters of the name
+    fn id(&self) -> String {
+        let name: String = self
+            .name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        format!("hard_{}", name)
+    }
+
+    fn set_command(&self) -> String {
+        format!(
+            "screen_set {} -name {{{}}} -priority {} -duration {} -heartbeat off",
+            self.id(),
+            self.name,
+            self.priority.as_str(),
+            //in eighths of a second
+            (self.duration.as_secs_f32() * 8.0).round() as u32
+        )
+    }
+
+    fn line_command(&self, row: usize) -> String {
+        format!(
+            "widget_set {} l{} 1 {} {{{}}}",
+            self.id(),
+            row + 1,
+            row + 1,
+            self.lines[row]
+        )
+    }
+}
+
+/// Screens from the `[lcd_screen:<name>]` config sections
+pub fn screens_from_config(config: &Config) -> Vec<LcdScreen> {
+    let mut screens = vec![];
+    for (name, properties) in config.sections("lcd_screen:") {
+        let priority = match properties.get("priority") {
+            Some(p) => match ScreenPriority::parse(p) {
+                Some(priority) => priority,
+                None => {
+                    warn!("lcd screen {}: unknown priority: {}", name, p);
+                    continue;
+                }
+            },
+            None => ScreenPriority::Info,
+        };
+        let mut screen = LcdScreen::new(&name, priority);
+        if let Some(secs) = properties
+            .get("duration_secs")
+            .and_then(|d| d.trim().parse::<f32>().ok())
+        {
+            screen.duration = Duration::from_secs_f32(secs.max(1.0));
+        }
+        screen.source_lines = properties.get("lines").map_or(vec![], |lines| {
+            lines
+                .split(',')
+                .filter_map(|l| l.trim().parse().ok())
+                .collect()
+        });
+        screen.lines = vec![String::new(); screen.source_lines.len()];
+        debug!(
+            "Got lcd screen: {:?} priority={} lines={:?}",
+            screen.name,
+            screen.priority.as_str(),
+            screen.source_lines
+        );
+        screens.push(screen);
+    }
+    screens
+}
+
 pub struct Lcdproc {
     pub name: String,
     pub lcdproc_host_port: String,
     pub lcd_receiver: Receiver<LcdTask>,
     pub lcd_lines: Vec<String>,
     pub level: Option<u8>,
+    pub screens: Vec<LcdScreen>,
+    pub emergency: bool,
+    //display size reported by LCDd on connect
+    pub width: u8,
+    pub height: u8,
+    pub cell_width: u8,
 }
 
 impl Lcdproc {
     async fn send_command(stream: &mut TcpStream, command: &str) -> Result<bool> {
         stream.write(format!("{}\n", command).as_ref()).await?;
         let mut result = Lcdproc::read_result(stream, false).await?;
-        if command == "hello" && result.starts_with("connect ") {
-            info!("{}", result);
-            return Ok(true);
-        } else if result.starts_with("listen ") || result.starts_with("ignore ") {
+        if result.starts_with("listen ") || result.starts_with("ignore ") {
             //we've got listen/ignore instead of success ... try read result again
             result = Lcdproc::read_result(stream, false).await?;
         }
@@ -62,6 +207,12 @@ impl Lcdproc {
         loop {
             match timeout(Duration::from_millis(500), reader.read_line(&mut line)).await {
                 Ok(result) => match result {
+                    Ok(0) => {
+                        return Err(Box::new(Error::new(
+                            ErrorKind::UnexpectedEof,
+                            "connection closed by the server",
+                        )));
+                    }
                     Ok(_) => {
                         break;
                     }
@@ -102,73 +253,273 @@ impl Lcdproc {
         }
     }
 
-    async fn refresh_screen(
-        &mut self,
-        stream: &mut TcpStream,
-        line_no: Option<usize>,
-    ) -> Result<()> {
-        match line_no {
-            Some(idx) => {
-                if idx == 3 {
-                    match self.level {
-                        Some(lev) => {
-                            Lcdproc::send_command(
-                                stream,
-                                &format!("widget_set hard cesspool_bar 9 4 {}", 4 * 5 * lev),
-                            )
-                            .await?;
+    /// Updates the state of the named screens, returns the commands to send when connected
+    fn apply_screen_task(&mut self, t: &LcdTask) -> Vec<String> {
+        let mut commands = vec![];
+        match &t.command {
+            LcdTaskCommand::SetLineText => {
+                let idx = t.int_arg as usize;
+                let text = t.string_arg.clone().unwrap_or_default();
+                for screen in self.screens.iter_mut() {
+                    for row in 0..screen.source_lines.len() {
+                        if screen.source_lines[row] == idx {
+                            screen.lines[row] = text.clone();
+                            commands.push(screen.line_command(row));
                         }
-                        _ => (),
                     }
-                } else {
-                    Lcdproc::send_command(
-                        stream,
-                        &format!(
-                            "widget_set hard s{} 1 {} {{{}}}",
-                            idx + 1,
-                            idx + 1,
-                            self.lcd_lines[idx]
-                        ),
-                    )
-                    .await?;
                 }
             }
-            None => {
-                //refresh all data
-                for (idx, line) in self.lcd_lines.iter().enumerate() {
-                    Lcdproc::send_command(
-                        stream,
-                        &format!("widget_set hard s{} 1 {} {{{}}}", idx + 1, idx + 1, line),
-                    )
-                    .await?;
+            LcdTaskCommand::AddScreen { screen, priority } => {
+                match self.screens.iter_mut().find(|s| &s.name == screen) {
+                    Some(existing) => existing.priority = *priority,
+                    None => {
+                        let new_screen = LcdScreen::new(screen, *priority);
+                        commands.push(format!("screen_add {}", new_screen.id()));
+                        self.screens.push(new_screen);
+                    }
+                }
+                if let Some(screen) = self.screens.iter().find(|s| &s.name == screen) {
+                    commands.push(screen.set_command());
                 }
-                match self.level {
-                    Some(lev) => {
-                        Lcdproc::send_command(
-                            stream,
-                            &format!("widget_set hard cesspool_bar 9 4 {}", 4 * 5 * lev),
-                        )
-                        .await?;
+            }
+            LcdTaskCommand::SetScreenLine { screen } => {
+                match self.screens.iter_mut().find(|s| &s.name == screen) {
+                    Some(screen) => {
+                        let row = t.int_arg as usize;
+                        while screen.lines.len() < row + 1 {
+                            screen.lines.push(String::new());
+                            commands.push(format!(
+                                "widget_add {} l{} string",
+                                screen.id(),
+                                screen.lines.len()
+                            ));
+                        }
+                        screen.lines[row] = t.string_arg.clone().unwrap_or_default();
+                        commands.push(screen.line_command(row));
                     }
-                    _ => (),
+                    None => warn!("{}: line for unknown screen {:?}", self.name, screen),
+                }
+            }
+            LcdTaskCommand::RemoveScreen { screen } => {
+                if let Some(pos) = self.screens.iter().position(|s| &s.name == screen) {
+                    let removed = self.screens.remove(pos);
+                    commands.push(format!("screen_del {}", removed.id()));
                 }
             }
+            _ => (),
         }
+        commands
+    }
 
+    /// Creates the named screens with their widgets and content, after (re)connecting
+    async fn setup_screens(&mut self, stream: &mut TcpStream) -> Result<()> {
+        for screen in &self.screens {
+            Lcdproc::send_command(stream, &format!("screen_add {}", screen.id())).await?;
+            Lcdproc::send_command(stream, &screen.set_command()).await?;
+            for row in 0..screen.lines.len() {
+                Lcdproc::send_command(
+                    stream,
+                    &format!("widget_add {} l{} string", screen.id(), row + 1),
+                )
+                .await?;
+                Lcdproc::send_command(stream, &screen.line_command(row)).await?;
+            }
+        }
         Ok(())
     }
 
-    pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
-        info!("{}: Starting task", self.name);
-        let mut read_interval = Instant::now();
-        let mut terminated = false;
+    //rows of the main screen: the text lines, the last one is the cesspool level
+    fn text_rows(&self) -> usize {
+        (self.height as usize).saturating_sub(1).max(1)
+    }
+
+    async fn refresh_line(&mut self, stream: &mut TcpStream, idx: usize) -> Result<()> {
+        if idx >= self.text_rows() {
+            //not on the main screen, maybe mirrored on a named one
+            return Ok(());
+        }
+        let text: String = self.lcd_lines[idx]
+            .chars()
+            .take(self.width as usize)
+            .collect();
+        Lcdproc::send_command(
+            stream,
+            &format!("widget_set hard s{} 1 {} {{{}}}", idx + 1, idx + 1, text),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn refresh_cesspool(&mut self, stream: &mut TcpStream) -> Result<()> {
+        if let Some(lev) = self.level {
+            //the bar starts after the title, 4 characters per level
+            let max_len = self.width.saturating_sub(8) as u32 * self.cell_width as u32;
+            let len = (4 * self.cell_width as u32 * lev as u32).min(max_len);
+            Lcdproc::send_command(
+                stream,
+                &format!("widget_set hard cesspool_bar 9 {} {}", self.height, len),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Parses the display size from the `connect` response:
+    /// `connect LCDproc 0.5.9 protocol 0.4 lcd wid 20 hgt 4 cellwid 5 cellhgt 8`
+    fn parse_geometry(response: &str) -> Option<(u8, u8, u8)> {
+        let words: Vec<&str> = response.split_whitespace().collect();
+        let value = |key: &str| -> Option<u8> {
+            let pos = words.iter().position(|w| *w == key)?;
+            words.get(pos + 1)?.parse().ok()
+        };
+        Some((value("wid")?, value("hgt")?, value("cellwid").unwrap_or(5)))
+    }
+
+    /// Handshake and the creation of the screens, on every (re)connect
+    async fn setup(&mut self, stream: &mut TcpStream) -> Result<()> {
+        stream.write_all(b"hello\n").await?;
+        let response = Lcdproc::read_result(stream, false).await?;
+        if !response.starts_with("connect ") {
+            return Err(format!("unexpected hello response: {:?}", response).into());
+        }
+        info!("{}", response);
+        match Lcdproc::parse_geometry(&response) {
+            Some((width, height, cell_width)) if width > 0 && height > 0 => {
+                if (width, height) != (self.width, self.height) {
+                    info!("{}: display size: {}x{}", self.name, width, height);
+                }
+                self.width = width;
+                self.height = height;
+                self.cell_width = cell_width.max(1);
+            }
+            _ => warn!(
+                "{}: unknown display size, assuming {}x{}",
+                self.name, self.width, self.height
+            ),
+        }
 
+        //configure/initialize our screen
+        Lcdproc::send_command(stream, "client_set -name {hard_lcd}").await?;
+        Lcdproc::send_command(stream, "screen_add hard").await?;
+        Lcdproc::send_command(stream, "screen_set hard -priority 100 -heartbeat none").await?;
+        for row in 1..=self.text_rows() {
+            Lcdproc::send_command(stream, &format!("widget_add hard s{} string", row)).await?;
+        }
+        if self.height > 1 {
+            Lcdproc::send_command(stream, "widget_add hard cesspool_title string").await?;
+            Lcdproc::send_command(
+                stream,
+                &format!(
+                    "widget_set hard cesspool_title 1 {} {{c-pool:}}",
+                    self.height
+                ),
+            )
+            .await?;
+            Lcdproc::send_command(stream, "widget_add hard cesspool_bar hbar").await?;
+        }
+
+        //refreshing whole screen with previous data (if any)
+        for idx in 0..self.lcd_lines.len() {
+            self.refresh_line(stream, idx).await?;
+        }
+        if self.height > 1 {
+            self.refresh_cesspool(stream).await?;
+        }
+        self.setup_screens(stream).await?;
+        if self.emergency {
+            self.set_emergency_mode(stream, true).await?;
+        }
+        Ok(())
+    }
+
+    //state changes while disconnected, shown after the next setup
+    fn apply_offline(&mut self, t: LcdTask) {
+        match t.command {
+            LcdTaskCommand::SetLineText => {
+                self.apply_screen_task(&t);
+                let idx = t.int_arg as usize;
+                if self.lcd_lines.len() < idx + 1 {
+                    self.lcd_lines.resize(idx + 1, String::new());
+                }
+                self.lcd_lines[idx] = t.string_arg.unwrap_or_default();
+            }
+            LcdTaskCommand::SetCesspoolLevel => {
+                self.level = Some(t.int_arg);
+            }
+            LcdTaskCommand::SetEmergencyMode => self.emergency = t.int_arg == 1,
+            _ => {
+                //the screens are created with their current content by setup
+                self.apply_screen_task(&t);
+            }
+        }
+    }
+
+    //handles the tasks until an error or the cancel flag (Ok)
+    async fn run(
+        &mut self,
+        stream: &mut TcpStream,
+        worker_cancel_flag: &Arc<AtomicBool>,
+    ) -> Result<()> {
+        let mut read_interval = Instant::now();
         loop {
-            if terminated || worker_cancel_flag.load(Ordering::SeqCst) {
-                break;
+            //the queued tasks are still shown before exit
+            let draining = worker_cancel_flag.load(Ordering::SeqCst);
+
+            //waiting for external lcd tasks, idle input is read in between
+            let task = if draining {
+                self.lcd_receiver.try_recv().ok()
+            } else {
+                tokio::select! {
+                    t = self.lcd_receiver.recv() => t,
+                    _ = tokio::time::sleep(Duration::from_millis(30)) => None,
+                }
+            };
+            if draining && task.is_none() {
+                debug!("{}: Got terminate signal from main", self.name);
+                return Ok(());
+            }
+            if let Some(t) = task {
+                debug!(
+                    "{}: received LcdTask: int_arg: {:?}, string_arg: {:?}",
+                    self.name, t.int_arg, t.string_arg
+                );
+                for command in self.apply_screen_task(&t) {
+                    Lcdproc::send_command(stream, &command).await?;
+                }
+                match t.command {
+                    LcdTaskCommand::SetLineText => {
+                        let idx = t.int_arg as usize;
+                        if self.lcd_lines.len() < idx + 1 {
+                            self.lcd_lines.resize(idx + 1, String::new());
+                        }
+                        self.lcd_lines[idx] = t.string_arg.unwrap_or_default();
+                        self.refresh_line(stream, idx).await?;
+                    }
+                    LcdTaskCommand::SetCesspoolLevel => {
+                        self.level = Some(t.int_arg);
+                        self.refresh_cesspool(stream).await?;
+                    }
+                    LcdTaskCommand::SetEmergencyMode => {
+                        self.emergency = t.int_arg == 1;
+                        self.set_emergency_mode(stream, self.emergency).await?;
+                    }
+                    _ => (),
+                }
+            }
+
+            // reading the input data when idle, this also detects a closed connection
+            if read_interval.elapsed() > Duration::from_secs_f32(READ_INTERVAL_SECS) {
+                read_interval = Instant::now();
+                Lcdproc::read_result(stream, true).await?;
             }
+        }
+    }
+
+    pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
+        info!("{}: Starting task", self.name);
+        let mut backoff = Duration::from_secs_f32(LCDPROC_RECONNECT_MIN_SECS);
 
-            tokio::time::sleep(Duration::from_secs(12)).await;
+        while !worker_cancel_flag.load(Ordering::SeqCst) {
             info!(
                 "{}: connecting to <u>{}</>...",
                 self.name, self.lcdproc_host_port
@@ -179,7 +530,6 @@ impl Lcdproc {
                         "{}: {} connection error: {:?}",
                         self.name, self.lcdproc_host_port, e
                     );
-                    continue;
                 }
                 Ok(mut stream) => {
                     info!(
@@ -188,172 +538,37 @@ impl Lcdproc {
                     );
 
                     debug!("{}: reading pending LcdTasks...", self.name);
-                    loop {
-                        match self.lcd_receiver.try_recv() {
-                            Ok(t) => match t.command {
-                                LcdTaskCommand::SetLineText => {
-                                    let idx = t.int_arg as usize;
-                                    if self.lcd_lines.len() < idx + 1 {
-                                        self.lcd_lines.resize(idx + 1, String::new());
-                                    }
-                                    self.lcd_lines[idx] = t.string_arg.unwrap();
-                                }
-                                LcdTaskCommand::SetCesspoolLevel => {
-                                    self.level = Some(t.int_arg);
-                                }
-                                _ => (),
-                            },
-                            _ => {
-                                break;
-                            }
-                        }
+                    while let Ok(t) = self.lcd_receiver.try_recv() {
+                        self.apply_offline(t);
                     }
 
-                    if let Err(e) = Lcdproc::send_command(&mut stream, "hello").await {
-                        error!("{}: write error: {:?}", self.name, e);
-                        continue;
-                    }
-                    //configure/initialize our screen
-                    if let Err(e) =
-                        Lcdproc::send_command(&mut stream, "client_set -name {hard_lcd}").await
-                    {
-                        error!("{}: write error: {:?}", self.name, e);
-                        continue;
-                    }
-                    if let Err(e) = Lcdproc::send_command(&mut stream, "screen_add hard").await {
-                        error!("{}: write error: {:?}", self.name, e);
-                        continue;
-                    }
-                    if let Err(e) = Lcdproc::send_command(
-                        &mut stream,
-                        "screen_set hard -priority 100 -heartbeat none",
-                    )
-                    .await
-                    {
-                        error!("{}: write error: {:?}", self.name, e);
-                        continue;
-                    }
-                    if let Err(e) =
-                        Lcdproc::send_command(&mut stream, "widget_add hard s1 string").await
-                    {
-                        error!("{}: write error: {:?}", self.name, e);
-                        continue;
-                    }
-                    if let Err(e) =
-                        Lcdproc::send_command(&mut stream, "widget_add hard s2 string").await
-                    {
-                        error!("{}: write error: {:?}", self.name, e);
-                        continue;
-                    }
-                    if let Err(e) =
-                        Lcdproc::send_command(&mut stream, "widget_add hard s3 string").await
-                    {
-                        error!("{}: write error: {:?}", self.name, e);
-                        continue;
-                    }
-                    if let Err(e) =
-                        Lcdproc::send_command(&mut stream, "widget_add hard cesspool_title string")
-                            .await
-                    {
-                        error!("{}: write error: {:?}", self.name, e);
-                        continue;
-                    }
-                    if let Err(e) = Lcdproc::send_command(
-                        &mut stream,
-                        "widget_set hard cesspool_title 1 4 {c-pool:}",
-                    )
-                    .await
-                    {
-                        error!("{}: write error: {:?}", self.name, e);
-                        continue;
-                    }
-                    if let Err(e) =
-                        Lcdproc::send_command(&mut stream, "widget_add hard cesspool_bar hbar")
-                            .await
-                    {
-                        error!("{}: write error: {:?}", self.name, e);
-                        continue;
-                    }
-
-                    //refreshing whole screen with previous data (if any)
-                    if let Err(e) = self.refresh_screen(&mut stream, None).await {
-                        error!("{}: refresh_screen error: {:?}", self.name, e);
-                        continue;
-                    }
-
-                    loop {
-                        if worker_cancel_flag.load(Ordering::SeqCst) {
-                            debug!("{}: Got terminate signal from main", self.name);
-                            terminated = true;
-                            break;
-                        }
-
-                        //checking for external lcd tasks
-                        //fixme: read all tasks, not a single one at a call
-                        let task = self.lcd_receiver.try_recv();
-                        match task {
-                            Ok(t) => {
-                                debug!(
-                                    "{}: received LcdTask: int_arg: {:?}, string_arg: {:?}",
-                                    self.name, t.int_arg, t.string_arg
-                                );
-                                match t.command {
-                                    LcdTaskCommand::SetLineText => {
-                                        let idx = t.int_arg as usize;
-                                        if self.lcd_lines.len() < idx + 1 {
-                                            self.lcd_lines.resize(idx + 1, String::new());
-                                        }
-                                        self.lcd_lines[idx] = t.string_arg.unwrap();
-                                        if let Err(e) =
-                                            self.refresh_screen(&mut stream, Some(idx)).await
-                                        {
-                                            error!("{}: refresh_screen error: {:?}", self.name, e);
-                                            break;
-                                        }
-                                    }
-                                    LcdTaskCommand::SetCesspoolLevel => {
-                                        self.level = Some(t.int_arg);
-                                        if let Err(e) =
-                                            self.refresh_screen(&mut stream, Some(3)).await
-                                        {
-                                            error!("{}: refresh_screen error: {:?}", self.name, e);
-                                            break;
-                                        }
-                                    }
-                                    LcdTaskCommand::SetEmergencyMode => {
-                                        if let Err(e) = self
-                                            .set_emergency_mode(&mut stream, t.int_arg == 1)
-                                            .await
-                                        {
-                                            error!(
-                                                "{}: set_emergency_mode error: {:?}",
-                                                self.name, e
-                                            );
-                                            break;
-                                        }
-                                    }
-                                }
+                    match self.setup(&mut stream).await {
+                        Ok(()) => {
+                            backoff = Duration::from_secs_f32(LCDPROC_RECONNECT_MIN_SECS);
+                            match self.run(&mut stream, &worker_cancel_flag).await {
+                                Ok(()) => break,
+                                Err(e) => error!("{}: connection lost: {:?}", self.name, e),
                             }
-                            _ => (),
                         }
-
-                        // reading the input data when idle
-                        if read_interval.elapsed() > Duration::from_secs_f32(READ_INTERVAL_SECS) {
-                            read_interval = Instant::now();
-
-                            match Lcdproc::read_result(&mut stream, true).await {
-                                Ok(_) => (),
-                                Err(e) => {
-                                    error!("{}: read error: {:?}", self.name, e);
-                                    break;
-                                }
-                            };
-                        }
-                        tokio::time::sleep(Duration::from_millis(30)).await;
+                        Err(e) => error!("{}: setup error: {:?}", self.name, e),
                     }
                 }
             }
-            tokio::time::sleep(Duration::from_millis(30)).await;
+
+            //reconnect with a backoff, the state is kept up to date meanwhile
+            info!(
+                "{}: reconnecting in {}",
+                self.name,
+                humantime::format_duration(backoff)
+            );
+            let started = Instant::now();
+            while started.elapsed() < backoff && !worker_cancel_flag.load(Ordering::SeqCst) {
+                while let Ok(t) = self.lcd_receiver.try_recv() {
+                    self.apply_offline(t);
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            backoff = (backoff * 2).min(Duration::from_secs_f32(LCDPROC_RECONNECT_MAX_SECS));
         }
 
         info!("{}: task stopped", self.name);
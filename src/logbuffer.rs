@@ -0,0 +1,141 @@
+use chrono::{DateTime, Local};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use simplelog::{Config, SharedLogger};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+pub const LOG_BUFFER_LINES_PER_LEVEL: usize = 500; //lines kept for every log level
+
+#[derive(Clone, Serialize)]
+pub struct LogLine {
+    #[serde(skip)]
+    seq: u64,
+    pub time: DateTime<Local>,
+    pub level: String,
+    pub module: String,
+    pub message: String,
+}
+
+/// Last log lines, kept separately for every level so the debug flood
+/// doesn't push out the warnings and errors
+pub struct LogBuffer {
+    capacity: usize,
+    seq: u64,
+    //indexed by `Level as usize - 1`: error, warn, info, debug, trace
+    lines: Vec<VecDeque<LogLine>>,
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> Self {
+        LogBuffer {
+            capacity,
+            seq: 0,
+            lines: (0..5).map(|_| VecDeque::new()).collect(),
+        }
+    }
+
+    fn push(&mut self, level: Level, module: &str, message: String) {
+        self.seq += 1;
+        let lines = &mut self.lines[level as usize - 1];
+        if lines.len() >= self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(LogLine {
+            seq: self.seq,
+            time: Local::now(),
+            level: level.to_string().to_lowercase(),
+            module: module.to_string(),
+            message,
+        });
+    }
+
+    /// Lines of the given level and more severe, optionally only from a module
+    pub fn get(&self, max_level: Level, module: Option<&str>) -> Vec<LogLine> {
+        let mut result: Vec<LogLine> = self.lines[..max_level as usize]
+            .iter()
+            .flatten()
+            .filter(|line| module.map_or(true, |m| line.module == m))
+            .cloned()
+            .collect();
+        result.sort_by_key(|line| line.seq);
+        result
+    }
+}
+
+/// Removes the paris color/style markup (`<b>`, `<bright-black>`, `</>`) from the message
+pub fn strip_markup(message: &str) -> String {
+    let mut result = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(start) = rest.find('<') {
+        result.push_str(&rest[..start]);
+        let tag_len = rest[start + 1..].find('>').filter(|&len| {
+            rest[start + 1..start + 1 + len]
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c == '-' || c == '/')
+        });
+        match tag_len {
+            Some(len) => rest = &rest[start + len + 2..],
+            None => {
+                result.push('<');
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Module name without the crate prefix, eg. "sun2000"
+pub fn module_name<'a>(record: &Record<'a>) -> &'a str {
+    record
+        .module_path()
+        .unwrap_or_default()
+        .rsplit("::")
+        .next()
+        .unwrap_or_default()
+}
+
+/// Logger feeding the ring buffer, combined with the console and file loggers
+pub struct BufferLogger {
+    level: LevelFilter,
+    buffer: Arc<Mutex<LogBuffer>>,
+}
+
+impl BufferLogger {
+    pub fn new(level: LevelFilter, buffer: Arc<Mutex<LogBuffer>>) -> Box<BufferLogger> {
+        Box::new(BufferLogger { level, buffer })
+    }
+}
+
+impl Log for BufferLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = strip_markup(&record.args().to_string());
+        if let Ok(mut buffer) = self.buffer.lock() {
+            buffer.push(record.level(), module_name(record), message);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl SharedLogger for BufferLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
@@ -0,0 +1,133 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use simplelog::{Config, SharedLogger};
+use std::sync::RwLock;
+
+/// Log verbosity, global and overridden per module, changeable at runtime
+struct Levels {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+    //level of the loggers not filtered here (the web api log buffer)
+    unfiltered: LevelFilter,
+}
+
+static LEVELS: RwLock<Levels> = RwLock::new(Levels {
+    default: LevelFilter::Info,
+    modules: Vec::new(),
+    unfiltered: LevelFilter::Off,
+});
+
+#[derive(Serialize)]
+pub struct LogLevels {
+    pub default: String,
+    pub modules: Vec<(String, String)>,
+}
+
+//the log macros are skipped early when the level is above all configured ones
+fn update_max_level(levels: &Levels) {
+    let max = levels
+        .modules
+        .iter()
+        .map(|(_, level)| *level)
+        .chain([levels.default, levels.unfiltered])
+        .max()
+        .unwrap_or(LevelFilter::Info);
+    log::set_max_level(max);
+}
+
+/// Sets the levels from the config, called once after the loggers are initialized
+pub fn init(default: LevelFilter, modules: Vec<(String, LevelFilter)>, unfiltered: LevelFilter) {
+    let mut levels = LEVELS.write().unwrap();
+    levels.default = default;
+    levels.modules = modules;
+    levels.unfiltered = unfiltered;
+    update_max_level(&levels);
+}
+
+/// Changes the level of a single module, or the default one when `module` is `None`
+pub fn set_level(module: Option<&str>, level: LevelFilter) {
+    let mut levels = LEVELS.write().unwrap();
+    match module {
+        None => levels.default = level,
+        Some(module) => {
+            levels.modules.retain(|(name, _)| name != module);
+            levels.modules.push((module.to_string(), level));
+        }
+    }
+    update_max_level(&levels);
+}
+
+/// Removes the module override, the module is logged with the default level again
+pub fn reset_level(module: &str) {
+    let mut levels = LEVELS.write().unwrap();
+    levels.modules.retain(|(name, _)| name != module);
+    update_max_level(&levels);
+}
+
+pub fn get_levels() -> LogLevels {
+    let levels = LEVELS.read().unwrap();
+    LogLevels {
+        default: levels.default.to_string().to_lowercase(),
+        modules: levels
+            .modules
+            .iter()
+            .map(|(name, level)| (name.clone(), level.to_string().to_lowercase()))
+            .collect(),
+    }
+}
+
+fn enabled(target: &str, level: Level) -> bool {
+    //module name without the crate prefix, eg. "sun2000"
+    let module = target.rsplit("::").next().unwrap_or_default();
+    let levels = LEVELS.read().unwrap();
+    let max = levels
+        .modules
+        .iter()
+        .find(|(name, _)| name == module)
+        .map_or(levels.default, |(_, level)| *level);
+    level <= max
+}
+
+/// Wraps the console/file logger, so its verbosity follows the runtime levels
+pub struct FilteredLogger {
+    inner: Box<dyn Log>,
+}
+
+impl FilteredLogger {
+    /// The wrapped logger should be created with `LevelFilter::Trace`
+    pub fn new(inner: Box<dyn SharedLogger>) -> Box<FilteredLogger> {
+        Box::new(FilteredLogger {
+            inner: inner.as_log(),
+        })
+    }
+}
+
+impl Log for FilteredLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        enabled(metadata.target(), metadata.level()) && self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+impl SharedLogger for FilteredLogger {
+    fn level(&self) -> LevelFilter {
+        LevelFilter::Trace
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
@@ -0,0 +1,130 @@
+use crate::lcdproc::{LcdTask, LcdTaskCommand};
+use crate::onewire::StateMachine;
+use crate::queue::Sender;
+use chrono::{DateTime, Local, NaiveDate};
+use serde::Serialize;
+use simplelog::*;
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+pub const MAILBOX_DEFAULT_LCD_LINE: u8 = 2; //lcdproc line for the "mail waiting" info
+
+pub static MAILBOX_TAG: &str = "mailbox"; //contact sensor on the mailbox lid (delivery)
+pub static MAILBOX_DOOR_TAG: &str = "mailbox_door"; //contact sensor on the collecting door
+
+#[derive(Clone, Default, Serialize)]
+pub struct MailboxState {
+    pub mail_waiting: bool,
+    pub deliveries: u32,
+    pub last_delivery: Option<DateTime<Local>>,
+    pub last_collected: Option<DateTime<Local>>,
+}
+
+pub struct Mailbox {
+    pub name: String,
+    pub state: Arc<RwLock<MailboxState>>,
+    pub notify_script: Option<String>,
+    pub lcd_line: u8,
+    notified_day: Option<NaiveDate>,
+}
+
+impl Mailbox {
+    pub fn new(
+        state: Arc<RwLock<MailboxState>>,
+        notify_script: Option<String>,
+        lcd_line: Option<u8>,
+    ) -> Self {
+        Mailbox {
+            name: "mailbox".to_string(),
+            state,
+            notify_script,
+            lcd_line: lcd_line.unwrap_or(MAILBOX_DEFAULT_LCD_LINE),
+            notified_day: None,
+        }
+    }
+
+    fn update_lcd(&self, lcd_transmitter: &Sender<LcdTask>, mail_waiting: bool) {
+        let task = LcdTask {
+            command: LcdTaskCommand::SetLineText,
+            int_arg: self.lcd_line,
+            string_arg: Some(if mail_waiting {
+                "You've got mail".to_string()
+            } else {
+                String::new()
+            }),
+        };
+        let _ = lcd_transmitter.send(task);
+    }
+
+    /// The lid was opened - something was delivered
+    pub fn opened(&mut self, lcd_transmitter: &Sender<LcdTask>, sensor_name: &str) {
+        let now = Local::now();
+        {
+            let mut state = self.state.write().unwrap();
+            state.mail_waiting = true;
+            state.deliveries += 1;
+            state.last_delivery = Some(now);
+        }
+        self.update_lcd(lcd_transmitter, true);
+
+        //notify only on the first opening of the day (the postman, not the wind)
+        let today = now.date_naive();
+        if self.notified_day == Some(today) {
+            debug!("{}: {}: already notified today", self.name, sensor_name);
+            return;
+        }
+        self.notified_day = Some(today);
+        info!("{}: 📬 mail delivered ({})", self.name, sensor_name);
+        if let Some(ref cmd) = self.notify_script {
+            let mut cmd = cmd.clone();
+            cmd = str::replace(&cmd, "%name%", sensor_name);
+            cmd = str::replace(&cmd, "%state%", "delivered");
+            thread::spawn(move || StateMachine::run_shell_command(cmd));
+        }
+    }
+
+    /// The collecting door was opened - mailbox is empty now
+    pub fn collected(&mut self, lcd_transmitter: &Sender<LcdTask>, sensor_name: &str) {
+        {
+            let mut state = self.state.write().unwrap();
+            if !state.mail_waiting {
+                return;
+            }
+            state.mail_waiting = false;
+            state.deliveries = 0;
+            state.last_collected = Some(Local::now());
+        }
+        //the next delivery on the same day is notified again
+        self.notified_day = None;
+        info!("{}: 📭 mail collected ({})", self.name, sensor_name);
+        self.update_lcd(lcd_transmitter, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{self, OverflowPolicy, QueueMetrics};
+
+    #[test]
+    fn delivery_after_collecting_is_notified_again() {
+        let metrics = Arc::new(RwLock::new(QueueMetrics::default()));
+        let (lcd_tx, lcd_rx) = queue::bounded("lcd", 16, OverflowPolicy::DropOldest, &metrics);
+        let mut mailbox = Mailbox::new(Arc::new(RwLock::new(MailboxState::default())), None, None);
+        let today = Some(Local::now().date_naive());
+
+        mailbox.opened(&lcd_tx, "mailbox");
+        assert_eq!(mailbox.notified_day, today);
+        mailbox.collected(&lcd_tx, "mailbox_door");
+        assert_eq!(mailbox.notified_day, None);
+        assert!(!mailbox.state.read().unwrap().mail_waiting);
+
+        mailbox.opened(&lcd_tx, "mailbox");
+        assert_eq!(mailbox.notified_day, today);
+        assert_eq!(mailbox.state.read().unwrap().deliveries, 1);
+        let lines: Vec<_> = std::iter::from_fn(|| lcd_rx.try_recv().ok())
+            .map(|task| task.string_arg.unwrap_or_default())
+            .collect();
+        assert_eq!(lines, vec!["You've got mail", "", "You've got mail"]);
+    }
+}
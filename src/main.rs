@@ -1,89 +1,279 @@
-#![feature(proc_macro_hygiene, decl_macro)]
-
 extern crate ctrlc;
 extern crate simplelog;
 use simplelog::*;
 
-extern crate ini;
-use self::ini::Ini;
-
+use crate::config::Config;
 use crate::database::DbTask;
 use crate::ethlcd::EthLcd;
 use crate::lcdproc::LcdTask;
+use crate::mqtt::MqttEvent;
+use crate::notify::Notification;
 use crate::onewire::OneWireTask;
+use crate::queue::{OverflowPolicy, Receiver, Sender};
 use crate::rfid::RfidTag;
 use futures::future::join_all;
 use humantime::format_duration;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::fs::OpenOptions;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
 use tokio::task;
 use tokio::task::JoinSet;
-use tokio_compat_02::FutureExt;
 
+mod adaptive_hold;
+mod alarm;
+mod appliance;
+mod automation;
+mod bus_watchdog;
+mod cesspool;
+mod chaos;
+mod circulation;
+mod config;
 mod database;
+mod device_config;
+mod device_io;
+mod dsmr;
+mod energy;
+mod energy_manager;
 mod ethlcd;
+mod events;
+mod exerciser;
+mod gate;
+mod geiger;
+mod gesture;
+mod governor;
+mod gpio;
+mod heating;
+mod heating_season;
+mod influx;
+mod jsonlog;
 mod lcdproc;
+mod logbuffer;
+mod loglevel;
+mod mailbox;
+mod meter;
+mod metrics;
+mod modbus;
+mod mqtt;
+mod notify;
 mod onewire;
 mod onewire_env;
+mod ontime;
+mod opentherm;
+mod owserver;
+mod queue;
 mod remeha;
 mod rfid;
+mod scene;
+mod schedule;
+mod shutter;
+mod simulation;
 mod skymax;
+mod smart_plug;
+mod storage;
 mod sun2000;
+mod systemd;
+#[cfg(test)]
+mod test_support;
+mod units;
+mod virtual_sensor;
 mod webserver;
+mod worker_error;
+mod yeelight;
+
+//metered appliances from [appliance:<name>] sections
+fn load_appliances(config: &Config) -> Vec<appliance::Appliance> {
+    let mut appliances = vec![];
+    for section in &config.appliances {
+        let mut appliance =
+            appliance::Appliance::new(section.name.clone(), section.meter, section.host.clone());
+        if let Some(val) = section.slave {
+            appliance.slave = val;
+        }
+        if let Some(val) = section.running_watts {
+            appliance.running_watts = val;
+        }
+        if let Some(val) = section.idle_watts {
+            appliance.idle_watts = val;
+        }
+        if let Some(val) = section.start_time {
+            appliance.start_time = val;
+        }
+        if let Some(val) = section.finish_time {
+            appliance.finish_time = val;
+        }
+        appliances.push(appliance);
+    }
+    appliances
+}
 
-fn get_config_string(option_name: &str, section: Option<&str>) -> Option<String> {
-    let conf = Ini::load_from_file("hard.conf").expect("Cannot open config file");
-    conf.section(Some(section.unwrap_or("general").to_owned()))
-        .and_then(|x| x.get(option_name).cloned())
+fn load_energy_rules(config: &Config) -> Vec<sun2000::EnergyRule> {
+    config
+        .energy_rules
+        .iter()
+        .map(|rule| sun2000::EnergyRule {
+            name: rule.name.clone(),
+            source: rule.source.clone(),
+            condition: rule.condition.clone(),
+            id_relay: rule.id_relay,
+            tag_group: rule.tag_group.clone(),
+            hold: rule.hold,
+            active: false,
+            last_sent: None,
+        })
+        .collect()
 }
 
-fn get_config_bool(option_name: &str, section: Option<&str>) -> bool {
-    let conf = Ini::load_from_file("hard.conf").expect("Cannot open config file");
-    let value = conf
-        .section(Some(section.unwrap_or("general").to_owned()))
-        .and_then(|x| x.get(option_name).cloned());
-    match value {
-        Some(val) => match val.trim() {
-            "yes" => true,
-            "true" => true,
-            "1" => true,
-            _ => false,
-        },
-        _ => false,
+fn load_cron_jobs(config: &Config) -> Vec<schedule::CronJob> {
+    config
+        .cron_jobs
+        .iter()
+        .map(|job| schedule::CronJob {
+            id_job: None,
+            name: job.name.clone(),
+            expression: job.expression.clone(),
+            cron: job.cron.clone(),
+            id_relay: job.id_relay,
+            tag_group: job.tag_group.clone(),
+            id_yeelight: job.id_yeelight,
+            id_plug: job.id_plug,
+            command: job.command.clone(),
+            duration: job.duration,
+            last_run: None,
+        })
+        .collect()
+}
+
+fn load_modbus_device(
+    config: &Config,
+    name: &str,
+    mqtt_transmitter: Sender<MqttEvent>,
+) -> Option<modbus::ModbusDevice> {
+    let section = config.modbus_devices.iter().find(|d| d.name == name)?;
+    Some(modbus::ModbusDevice {
+        name: format!("modbus:{}", name),
+        transport: section.transport.clone(),
+        slave: section.slave,
+        input_registers: section.input_registers,
+        registers: section.registers.clone(),
+        poll_interval: section.poll_interval,
+        influxdb_url: config.general.influxdb_url.clone(),
+        influx_database: section.influx_database.clone(),
+        mqtt_transmitter,
+    })
+}
+
+type WorkerResult = std::result::Result<(), Box<dyn std::error::Error + Send + Sync>>;
+type WorkerFuture = Pin<Box<dyn Future<Output = WorkerResult> + Send>>;
+
+//async worker which can be stopped and created again from current config
+struct RestartableWorker {
+    name: String,
+    cancel_flag: Arc<AtomicBool>,
+    finished: Arc<AtomicBool>,
+    restart_pending: bool,
+    create: Box<dyn Fn(Arc<AtomicBool>, &Config) -> Option<WorkerFuture>>,
+}
+
+impl RestartableWorker {
+    fn new(
+        name: &str,
+        create: Box<dyn Fn(Arc<AtomicBool>, &Config) -> Option<WorkerFuture>>,
+    ) -> Self {
+        RestartableWorker {
+            name: name.to_string(),
+            cancel_flag: Arc::new(AtomicBool::new(false)),
+            finished: Arc::new(AtomicBool::new(true)),
+            restart_pending: false,
+            create,
+        }
     }
+
+    fn spawn(&mut self, futures: &mut JoinSet<WorkerResult>, config: &Config) {
+        self.cancel_flag = Arc::new(AtomicBool::new(false));
+        match (self.create)(self.cancel_flag.clone(), config) {
+            Some(worker_future) => {
+                self.finished.store(false, Ordering::SeqCst);
+                let finished = self.finished.clone();
+                futures.spawn(async move {
+                    let result = worker_future.await;
+                    finished.store(true, Ordering::SeqCst);
+                    result
+                });
+            }
+            None => debug!("{}: not configured, skipping", self.name),
+        }
+    }
+}
+
+fn units_init(config: &Config) {
+    let general = &config.general;
+    units::init(units::Units {
+        temperature: general.temperature_unit,
+        energy: general.energy_unit,
+        timezone: general.timezone,
+        time_format: general.time_format.clone(),
+        decimal_comma: general.decimal_comma,
+    });
 }
 
-fn logging_init() {
+fn influx_init(config: &Config) {
+    let influxdb = &config.influxdb;
+    influx::init_spool(influxdb.spool_dir.clone());
+    let (org, token) = match (&influxdb.org, &influxdb.token) {
+        (Some(org), Some(token)) => (org.clone(), token.clone()),
+        (None, None) => return,
+        _ => {
+            warn!("influxdb: org and token are needed for InfluxDB 2.x, using the v1 API");
+            return;
+        }
+    };
+    info!("influxdb: 📈 using InfluxDB 2.x API, org: {}", org);
+    influx::init_v2(influx::InfluxV2 {
+        org,
+        token,
+        bucket: influxdb.bucket.clone(),
+        buckets: influxdb.buckets.clone(),
+    });
+}
+
+fn logging_init(config: &Config) -> Arc<Mutex<logbuffer::LogBuffer>> {
     let conf = ConfigBuilder::new()
         .set_time_format("%F, %H:%M:%S%.3f".to_string())
+        .set_time_to_local(config.general.timezone == Some(units::Timezone::Local))
         .set_write_log_enable_colors(true)
         .build();
 
     let mut loggers = vec![];
 
-    let console_logger: Box<dyn SharedLogger> = TermLogger::new(
-        LevelFilter::Info,
+    //console and file verbosity is set by the runtime levels (config and web api)
+    let console_logger: Box<dyn SharedLogger> = loglevel::FilteredLogger::new(TermLogger::new(
+        LevelFilter::Trace,
         conf.clone(),
         TerminalMode::Mixed,
         ColorChoice::Auto,
-    );
+    ));
     loggers.push(console_logger);
 
     let mut logfile_error: Option<String> = None;
-    match get_config_string("log", None) {
+    match &config.general.log {
         Some(ref log_path) => {
             let logfile = OpenOptions::new().create(true).append(true).open(log_path);
             match logfile {
-                Ok(logfile) => {
-                    loggers.push(WriteLogger::new(LevelFilter::Info, conf, logfile));
-                }
+                Ok(logfile) => match config.general.log_format {
+                    jsonlog::LogFormat::Text => loggers.push(loglevel::FilteredLogger::new(
+                        WriteLogger::new(LevelFilter::Trace, conf, logfile),
+                    )),
+                    jsonlog::LogFormat::Json => loggers.push(loglevel::FilteredLogger::new(
+                        jsonlog::JsonLogger::new(LevelFilter::Trace, logfile),
+                    )),
+                },
                 Err(e) => {
                     logfile_error = Some(format!(
                         "Error creating/opening log file: {:?}: {:?}",
@@ -95,19 +285,52 @@ fn logging_init() {
         _ => {}
     };
 
+    //last log lines for the web api
+    let log_buffer = Arc::new(Mutex::new(logbuffer::LogBuffer::new(
+        config.general.log_buffer_lines,
+    )));
+    loggers.push(logbuffer::BufferLogger::new(
+        config.general.log_buffer_level,
+        log_buffer.clone(),
+    ));
+
     CombinedLogger::init(loggers).expect("Cannot initialize logging subsystem");
+    loglevel::init(
+        config.general.log_level,
+        config.log_levels.clone(),
+        config.general.log_buffer_level,
+    );
     if logfile_error.is_some() {
         error!("{}", logfile_error.unwrap());
         warn!("Will do console logging only...");
     }
+    log_buffer
 }
 
 #[tokio::main]
 async fn main() {
     env::set_var("RUST_BACKTRACE", "full");
     let started = Instant::now();
-    logging_init();
+    let mut config = match Config::load(config::CONFIG_FILE) {
+        Ok(config) => Arc::new(config),
+        Err(errors) => {
+            //logging is configured from the same file, so only stderr is available here
+            for e in errors {
+                eprintln!("config error: {}", e);
+            }
+            std::process::exit(1);
+        }
+    };
+    units_init(&config);
+    let log_buffer = logging_init(&config);
     info!("🛡️ Welcome to hard (home automation rust-daemon)");
+    influx_init(&config);
+    if let Some(rates) = &config.chaos {
+        chaos::init(rates.clone());
+    }
+    if let Some(simulation) = &config.simulation {
+        simulation::init(simulation.state_dir.clone());
+    }
 
     //Ctrl-C / SIGTERM support
     let running = Arc::new(AtomicBool::new(true));
@@ -118,25 +341,50 @@ async fn main() {
     .expect("Error setting Ctrl-C handler");
 
     //common thread stuff
-    let influxdb_url = get_config_string("influxdb_url", None);
+    let influxdb_url = config.general.influxdb_url.clone();
     let mut threads = vec![];
     let mut futures = JoinSet::new();
     let cancel_flag = Arc::new(AtomicBool::new(false));
-    let sensor_devices = onewire::SensorDevices {
+    //consumers of the queues are stopped after the producers, so nothing queued is lost
+    let drain_cancel_flag = Arc::new(AtomicBool::new(false));
+    let mut sensor_devices = onewire::SensorDevices {
         kinds: HashMap::new(),
         sensor_boards: vec![],
         max_cesspool_level: 0,
+        virtual_sensors: vec![],
+        inverted_sensors: config.general.inverted_sensors.clone(),
+        owserver: config.general.owserver.clone(),
+        gpio_boards: config.gpio_boards.clone(),
     };
-    let relay_devices = onewire::RelayDevices {
+    let shutters = shutter::shutters_from_config(&config);
+    let mut relay_devices = onewire::RelayDevices {
         relay_boards: vec![],
+        owserver: config.general.owserver.clone(),
+        gpio_boards: config.gpio_boards.clone(),
         yeelight: vec![],
+        smart_plugs: vec![],
+        schedules: vec![],
+        cron_jobs: load_cron_jobs(&config),
+        scenes: config.scenes.clone(),
+        shutter_rules: shutter::rules_from_config(&config, &shutters),
+        shutters,
     };
-    let relays = onewire::Relays { relay: vec![] };
-    let env_sensor_devices = onewire_env::EnvSensorDevices {
+    let mut relays = onewire::Relays { relay: vec![] };
+    let mut env_sensor_devices = onewire_env::EnvSensorDevices {
         kinds: HashMap::new(),
         env_sensors: vec![],
     };
-    let rfid_tags: Vec<RfidTag> = vec![];
+    let mut rfid_tags: Vec<RfidTag> = vec![];
+    if config.general.devices == device_config::DeviceSource::Config {
+        device_config::load(
+            &config,
+            &mut sensor_devices,
+            &mut env_sensor_devices,
+            &mut relay_devices,
+            &mut relays,
+            &mut rfid_tags,
+        );
+    }
     let rfid_pending_tags: Vec<u32> = vec![];
     let onewire_sensor_devices = Arc::new(RwLock::new(sensor_devices));
     let onewire_relay_devices = Arc::new(RwLock::new(relay_devices));
@@ -144,31 +392,104 @@ async fn main() {
     let onewire_env_sensor_devices = Arc::new(RwLock::new(env_sensor_devices));
     let onewire_rfid_tags = Arc::new(RwLock::new(rfid_tags));
     let onewire_rfid_pending_tags = Arc::new(RwLock::new(rfid_pending_tags));
-    let (tx, rx): (Sender<DbTask>, Receiver<DbTask>) = mpsc::channel(); //database thread comm channel
-    let (ow_tx, ow_rx): (Sender<OneWireTask>, Receiver<OneWireTask>) = mpsc::channel(); //onewire thread comm channel
-    let (lcd_tx, lcd_rx): (Sender<LcdTask>, Receiver<LcdTask>) = mpsc::channel(); //lcdproc comm channel
+    let onewire_rfid_pending_pins: rfid::PendingPins = Arc::new(RwLock::new(vec![]));
+    let cesspool_history = Arc::new(RwLock::new(cesspool::CesspoolHistory::default()));
+    let mailbox_state = Arc::new(RwLock::new(mailbox::MailboxState::default()));
+    let gate_states: gate::GateStates = Arc::new(RwLock::new(BTreeMap::new()));
+    let alarm_armed = Arc::new(AtomicBool::new(false));
+    let backup_soc_request = Arc::new(Mutex::new(None));
+    let heating_season = Arc::new(RwLock::new(heating_season::HeatingSeason::new(
+        config.heating_season.outside_sensor.clone(),
+        config.heating_season.on_below,
+        config.heating_season.off_above,
+        config.heating_season.average_hours,
+        config.heating_season.calendar,
+        config.heating_season.lcd_line,
+    )));
+    let energy_costs = Arc::new(RwLock::new(energy::EnergyCosts::new(
+        match (config.energy.import_price, config.energy.export_price) {
+            (Some(import_price), export_price) => Some(energy::Tariff {
+                import_price,
+                export_price: export_price.unwrap_or_default(),
+            }),
+            _ => None,
+        },
+    )));
+    let window_cutback = Arc::new(RwLock::new(HashSet::new()));
+    let grid_export = Arc::new(RwLock::new(None));
+    let ontime = Arc::new(RwLock::new(ontime::OnTimeStats::default()));
+    let meter_totals = Arc::new(RwLock::new(meter::MeterTotals::default()));
+    let bus_metrics = Arc::new(RwLock::new(metrics::BusMetrics::default()));
+    let sensor_values = Arc::new(RwLock::new(config.variables.clone()));
+    let adaptive_hold_file = config.general.adaptive_hold_file.clone();
+    let adaptive_hold = Arc::new(RwLock::new(match &adaptive_hold_file {
+        Some(path) => adaptive_hold::AdaptiveHold::load(path),
+        None => adaptive_hold::AdaptiveHold::default(),
+    }));
+    let latency = Arc::new(RwLock::new(metrics::LatencyMetrics {
+        targets: HashMap::new(),
+        trace: config.general.latency_trace,
+    }));
+    let queue_metrics = Arc::new(RwLock::new(queue::QueueMetrics::default()));
+    let worker_errors = Arc::new(RwLock::new(metrics::WorkerErrors::default()));
+    let (tx, rx): (Sender<DbTask>, Receiver<DbTask>) = queue::bounded(
+        "database",
+        queue::DB_QUEUE_CAPACITY,
+        OverflowPolicy::DropNewest,
+        &queue_metrics,
+    ); //database thread comm channel
+    let (ow_tx, ow_rx): (Sender<OneWireTask>, Receiver<OneWireTask>) = queue::bounded(
+        "onewire",
+        queue::ONEWIRE_QUEUE_CAPACITY,
+        OverflowPolicy::DropNewest,
+        &queue_metrics,
+    ); //onewire thread comm channel
+    let (lcd_tx, lcd_rx): (Sender<LcdTask>, Receiver<LcdTask>) = queue::bounded(
+        "lcdproc",
+        queue::LCD_QUEUE_CAPACITY,
+        OverflowPolicy::DropOldest,
+        &queue_metrics,
+    ); //lcdproc comm channel
+    let (mqtt_tx, mqtt_rx): (Sender<MqttEvent>, Receiver<MqttEvent>) = queue::bounded(
+        "mqtt",
+        queue::MQTT_QUEUE_CAPACITY,
+        OverflowPolicy::DropOldest,
+        &queue_metrics,
+    ); //mqtt publishing channel
+    let (notify_tx, notify_rx): (Sender<Notification>, Receiver<Notification>) = queue::bounded(
+        "notify",
+        notify::NOTIFY_QUEUE_CAPACITY,
+        OverflowPolicy::DropOldest,
+        &queue_metrics,
+    ); //notifications for a human
+    let (event_tx, _) = broadcast::channel(events::EVENT_QUEUE_CAPACITY); //real-time events for websocket clients
 
     //ethlcd struct
-    let ethlcd = match get_config_string("ethlcd_host", None) {
+    let ethlcd = match &config.general.ethlcd_host {
         Some(hostname) => Some(EthLcd {
             struct_name: "ethlcd".to_string(),
-            host: hostname,
+            host: hostname.clone(),
             in_progress: Arc::new(AtomicBool::new(false)),
         }),
         _ => None,
     };
 
-    if !get_config_bool("disable_postgres", None) {
+    if !config.general.disable_postgres {
         //creating db task
         let mut db = database::Database {
-            name: "postgres".to_string(),
+            name: match config.general.storage {
+                storage::StorageBackend::Postgres => "postgres".to_string(),
+                storage::StorageBackend::Sqlite => "sqlite".to_string(),
+            },
+            config: config.clone(),
             host: None,
             dbname: None,
             username: None,
             password: None,
             receiver: rx,
             conn: None,
-            disable_onewire: get_config_bool("disable_onewire", None),
+            sqlite: None,
+            disable_onewire: config.general.disable_onewire,
             sensor_devices: onewire_sensor_devices.clone(),
             relay_devices: onewire_relay_devices.clone(),
             relays: onewire_relays.clone(),
@@ -177,40 +498,160 @@ async fn main() {
             sensor_counters: Default::default(),
             relay_counters: Default::default(),
             yeelight_counters: Default::default(),
+            plug_counters: Default::default(),
             influx_sensor_counters: Default::default(),
             influxdb_url: influxdb_url.clone(),
             influx_sensor_values: Default::default(),
             influx_relay_values: Default::default(),
+            influx_virtual_values: Default::default(),
+            influx_env_database: config.general.influx_env_database.clone(),
+            influx_env_measurement: config.general.influx_env_measurement.clone(),
             influx_cesspool_level: None,
             daily_yield_energy: None,
+            influx_frost_protection_secs: None,
+            pg_alarm_events: vec![],
+            pg_rfid_scans: vec![],
+            pg_circulation_runs: vec![],
+            influx_alarm_events: vec![],
+            influx_sun_azimuth: None,
+            influx_sun_elevation: None,
+            cesspool_history: cesspool_history.clone(),
+            cesspool_history_loaded: false,
+            pg_cesspool_levels: vec![],
+            cesspool_notify_level: config.general.cesspool_notify_level,
+            cesspool_critical_level: config.general.cesspool_critical_level,
+            cesspool_notify_script: config.general.cesspool_notify_script.clone(),
+            cesspool_alerted: None,
+            cesspool_screen: false,
+            lcd_transmitter: lcd_tx.clone(),
+            notify_transmitter: notify_tx.clone(),
+            bus_metrics: bus_metrics.clone(),
+            energy_costs: energy_costs.clone(),
+            energy_costs_loaded: false,
+            ontime: ontime.clone(),
+            ontime_loaded: false,
+            meter_totals: meter_totals.clone(),
+            meter_totals_loaded: false,
+            errors: worker_error::ErrorReporter {
+                worker: "database".to_string(),
+                metrics: worker_errors.clone(),
+                notify_transmitter: notify_tx.clone(),
+            },
         };
-        let worker_cancel_flag = cancel_flag.clone();
+        let worker_cancel_flag = drain_cancel_flag.clone();
         let db_future = async move { db.worker(worker_cancel_flag).await };
         futures.spawn(db_future);
     }
 
-    if !get_config_bool("disable_onewire", None) {
+    if !config.general.disable_onewire {
+        //yeelight connection pool async task
+        let yeelight_requests = Arc::new(Mutex::new(vec![]));
+        let mut yeelight_pool = yeelight::YeelightPool {
+            name: "yeelight".to_string(),
+            relay_devices: onewire_relay_devices.clone(),
+            relays: onewire_relays.clone(),
+            requests: yeelight_requests.clone(),
+            latency: latency.clone(),
+        };
+        let worker_cancel_flag = drain_cancel_flag.clone();
+        let yeelight_future = async move { yeelight_pool.worker(worker_cancel_flag).await };
+        futures.spawn(yeelight_future);
+
         //creating onewire thread
         let onewire = onewire::OneWire {
             name: "onewire".to_string(),
+            config: config.clone(),
             transmitter: tx.clone(),
             ow_receiver: ow_rx,
             lcd_transmitter: lcd_tx.clone(),
+            mqtt_transmitter: mqtt_tx.clone(),
             sensor_devices: onewire_sensor_devices.clone(),
             relay_devices: onewire_relay_devices.clone(),
             relays: onewire_relays.clone(),
+            sensor_board_stale: config.general.sensor_board_stale,
+            watchdog_script: config.general.watchdog_script.clone(),
+            command_min_interval_ms: config.general.command_min_interval_ms,
+            bus_metrics: bus_metrics.clone(),
+            sensor_values: sensor_values.clone(),
+            latency: latency.clone(),
+            edge_time: Mutex::new(None),
+            yeelight_requests: yeelight_requests.clone(),
+            adaptive_hold: adaptive_hold.clone(),
+            adaptive_hold_file: adaptive_hold_file.clone(),
+            energy_costs: energy_costs.clone(),
+            window_cutback: window_cutback.clone(),
+            events: event_tx.clone(),
+            notify_transmitter: notify_tx.clone(),
+            errors: worker_error::ErrorReporter {
+                worker: "onewire".to_string(),
+                metrics: worker_errors.clone(),
+                notify_transmitter: notify_tx.clone(),
+            },
+        };
+        //circulation pump controller
+        let circulation = if config.circulation.enabled {
+            Some(circulation::CirculationPump::new(
+                config.circulation.run_secs,
+                config.circulation.cooldown_secs,
+                config.circulation.learning,
+                config.circulation.night_suppress,
+                config.circulation.double_tap_secs,
+                config.circulation.usage_file.clone(),
+                tx.clone(),
+            ))
+        } else {
+            None
         };
+        //mailbox delivery/collecting workflow
+        let mailbox = if config.mailbox.enabled {
+            Some(mailbox::Mailbox::new(
+                mailbox_state.clone(),
+                config.mailbox.notify_script.clone(),
+                config.mailbox.lcd_line,
+            ))
+        } else {
+            None
+        };
+        //gates and garage doors
+        let gates = gate::gates_from_config(&config, gate_states.clone(), notify_tx.clone());
+        //intrusion alarm
+        let mut alarm = alarm::Alarm::new(
+            alarm_armed.clone(),
+            config.alarm.pet_immunity,
+            config.alarm.alarm_script.clone(),
+            lcd_tx.clone(),
+            mqtt_tx.clone(),
+            tx.clone(),
+        );
+        if let Some(delay) = config.alarm.exit_delay {
+            alarm.exit_delay = delay;
+        }
+        if let Some(delay) = config.alarm.entry_delay {
+            alarm.entry_delay = delay;
+        }
+        if let Some(duration) = config.alarm.siren_duration {
+            alarm.siren_duration = duration;
+        }
         let worker_cancel_flag = cancel_flag.clone();
         let thread_builder = thread::Builder::new().name("onewire".into()); //thread name
         let rfid_pending_tags_cloned = onewire_rfid_pending_tags.clone();
+        let rfid_pending_pins_cloned = onewire_rfid_pending_pins.clone();
+        //the worker does blocking bus reads between its awaits: it is driven by its own thread,
+        //but on the main runtime, so the spawned tasks, timers and sockets share one executor
+        let runtime = tokio::runtime::Handle::current();
         let thread_handler = thread_builder
             .spawn(move || {
-                onewire.worker(
+                runtime.block_on(onewire.worker(
                     worker_cancel_flag,
                     ethlcd,
                     onewire_rfid_tags.clone(),
                     rfid_pending_tags_cloned,
-                );
+                    rfid_pending_pins_cloned,
+                    circulation,
+                    mailbox,
+                    gates,
+                    alarm,
+                ));
             })
             .unwrap();
         threads.push(thread_handler);
@@ -219,7 +660,14 @@ async fn main() {
         let onewire_env = onewire_env::OneWireEnv {
             name: "onewire_env".to_string(),
             ow_transmitter: ow_tx.clone(),
+            transmitter: tx.clone(),
             env_sensor_devices: onewire_env_sensor_devices.clone(),
+            frost_guard_script: config.general.frost_guard_script.clone(),
+            frost_guard_power: config.general.frost_guard_power,
+            bus_metrics: bus_metrics.clone(),
+            sensor_values: sensor_values.clone(),
+            heating_season: heating_season.clone(),
+            lcd_transmitter: lcd_tx.clone(),
         };
         let worker_cancel_flag = cancel_flag.clone();
         let thread_builder = thread::Builder::new().name("onewire_env".into()); //thread name
@@ -231,86 +679,650 @@ async fn main() {
         threads.push(thread_handler);
     }
 
-    if !get_config_bool("disable_webserver", None) {
-        //creating webserver task
-        let mut webserver = webserver::WebServer {
-            name: "webserver".to_string(),
-            ow_transmitter: ow_tx,
-            db_transmitter: tx.clone(),
-        };
-        let worker_cancel_flag = cancel_flag.clone();
-        let webserver_future = async move { webserver.worker(worker_cancel_flag).await };
-        futures.spawn(webserver_future);
-    }
+    //workers which can be restarted remotely (with config reloading)
+    let restart_requests: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
+    let mut restartable: Vec<RestartableWorker> = vec![];
 
     //rfid task
-    match get_config_string("rfid_event_path", None) {
-        Some(event_path) => {
+    let rfid_pending_tags = onewire_rfid_pending_tags.clone();
+    let rfid_pending_pins = onewire_rfid_pending_pins.clone();
+    restartable.push(RestartableWorker::new(
+        "rfid",
+        Box::new(move |worker_cancel_flag, config: &Config| {
+            if config.general.rfid_event_path.is_none() && config.general.rfid_usbid.is_none() {
+                return None;
+            }
             let rfid = rfid::Rfid {
                 name: "rfid".to_string(),
-                event_path,
-                rfid_pending_tags: onewire_rfid_pending_tags.clone(),
+                event_path: config.general.rfid_event_path.clone(),
+                usb_id: config.general.rfid_usbid,
+                hotplug: config.general.rfid_hotplug,
+                pin_timeout: None,
+                rfid_pending_tags: rfid_pending_tags.clone(),
+                rfid_pending_pins: rfid_pending_pins.clone(),
             };
-            let worker_cancel_flag = cancel_flag.clone();
-            let rfid_future = async move { rfid.worker(worker_cancel_flag).await };
-            futures.spawn(rfid_future);
-        }
-        _ => {}
-    };
+            Some(Box::pin(async move { rfid.worker(worker_cancel_flag).await }) as WorkerFuture)
+        }),
+    ));
+
+    //keypad task, the PINs are checked by the onewire state machine
+    let keypad_pending_tags = onewire_rfid_pending_tags.clone();
+    let keypad_pending_pins = onewire_rfid_pending_pins.clone();
+    restartable.push(RestartableWorker::new(
+        "keypad",
+        Box::new(move |worker_cancel_flag, config: &Config| {
+            if config.keypad.event_path.is_none() && config.keypad.usbid.is_none() {
+                return None;
+            }
+            let keypad = rfid::Rfid {
+                name: "keypad".to_string(),
+                event_path: config.keypad.event_path.clone(),
+                usb_id: config.keypad.usbid,
+                hotplug: config.general.rfid_hotplug,
+                pin_timeout: Some(config.keypad.timeout),
+                rfid_pending_tags: keypad_pending_tags.clone(),
+                rfid_pending_pins: keypad_pending_pins.clone(),
+            };
+            Some(Box::pin(async move { keypad.worker(worker_cancel_flag).await }) as WorkerFuture)
+        }),
+    ));
+
+    //wiegand rfid reader task
+    let wiegand_pending_tags = onewire_rfid_pending_tags.clone();
+    let wiegand_pending_pins = onewire_rfid_pending_pins.clone();
+    restartable.push(RestartableWorker::new(
+        "wiegand",
+        Box::new(move |worker_cancel_flag, config: &Config| {
+            let wiegand = rfid::WiegandReader {
+                name: "wiegand".to_string(),
+                chip: config.wiegand.chip.clone()?,
+                d0: config.wiegand.d0?,
+                d1: config.wiegand.d1?,
+                facility_code: config.wiegand.facility_code,
+                id_format: config.wiegand.id_format,
+                pin_timeout: config.keypad.timeout,
+                rfid_pending_tags: wiegand_pending_tags.clone(),
+                rfid_pending_pins: wiegand_pending_pins.clone(),
+            };
+            Some(Box::pin(async move { wiegand.worker(worker_cancel_flag).await }) as WorkerFuture)
+        }),
+    ));
 
     //skymax async task
-    match get_config_string("skymax_device", None) {
-        Some(path) => {
-            let worker_cancel_flag = cancel_flag.clone();
+    let skymax_lcd_tx = lcd_tx.clone();
+    let skymax_mqtt_tx = mqtt_tx.clone();
+    let skymax_notify_tx = notify_tx.clone();
+    let skymax_event_tx = event_tx.clone();
+    let skymax_worker_errors = worker_errors.clone();
+    let skymax_setting_requests = Arc::new(Mutex::new(vec![]));
+    let skymax_setting_requests_cloned = skymax_setting_requests.clone();
+    restartable.push(RestartableWorker::new(
+        "skymax",
+        Box::new(move |worker_cancel_flag, config: &Config| {
             let mut skymax = skymax::Skymax {
                 name: "skymax".to_string(),
-                device_path: path,
-                device_usbid: get_config_string("skymax_usbid", None).unwrap_or_default(),
+                device_path: config.general.skymax_device.clone()?,
+                device_usbid: config.general.skymax_usbid.clone(),
+                device_dir: device_io::DEV_DIR.to_string(),
+                status_lcd_line: config.general.skymax_status_lcd_line,
                 poll_ok: 0,
                 poll_errors: 0,
-                influxdb_url: influxdb_url.clone(),
-                lcd_transmitter: lcd_tx.clone(),
-                mode_change_script: get_config_string("skymax_mode_change_script", None),
+                influxdb_url: config.general.influxdb_url.clone(),
+                lcd_transmitter: skymax_lcd_tx.clone(),
+                mqtt_transmitter: skymax_mqtt_tx.clone(),
+                notify_transmitter: skymax_notify_tx.clone(),
+                events: skymax_event_tx.clone(),
+                mode_change_script: config.general.skymax_mode_change_script.clone(),
+                fault_script: config.general.skymax_fault_script.clone(),
+                settings: config.general.skymax_settings.clone(),
+                setting_requests: skymax_setting_requests_cloned.clone(),
+                poll_interval: config.general.skymax_poll_interval,
+                stats_interval: config.general.skymax_stats_interval,
+                errors: worker_error::ErrorReporter {
+                    worker: "skymax".to_string(),
+                    metrics: skymax_worker_errors.clone(),
+                    notify_transmitter: skymax_notify_tx.clone(),
+                },
             };
-            let skymax_future = async move { skymax.worker(worker_cancel_flag).await };
-            futures.spawn(skymax_future);
-        }
-        _ => {}
-    }
+            Some(Box::pin(async move { skymax.worker(worker_cancel_flag).await }) as WorkerFuture)
+        }),
+    ));
 
     //sun2000 async task
-    match get_config_string("host", Some("sun2000")) {
-        Some(host) => {
-            let worker_cancel_flag = cancel_flag.clone();
+    let sun2000_lcd_tx = lcd_tx.clone();
+    let sun2000_tx = tx.clone();
+    let sun2000_mqtt_tx = mqtt_tx.clone();
+    let sun2000_notify_tx = notify_tx.clone();
+    let sun2000_energy_costs = energy_costs.clone();
+    let sun2000_event_tx = event_tx.clone();
+    let sun2000_ow_tx = ow_tx.clone();
+    let sun2000_backup_soc_request = backup_soc_request.clone();
+    let sun2000_sensor_values = sensor_values.clone();
+    let sun2000_grid_export = grid_export.clone();
+    let sun2000_worker_errors = worker_errors.clone();
+    restartable.push(RestartableWorker::new(
+        "sun2000",
+        Box::new(move |worker_cancel_flag, config: &Config| {
             let mut sun2000 = sun2000::Sun2000 {
                 name: "sun2000".to_string(),
-                host_port: host,
+                host_port: config.sun2000.host.clone()?,
+                poll_ok: 0,
+                poll_errors: 0,
+                influxdb_url: config.general.influxdb_url.clone(),
+                lcd_transmitter: sun2000_lcd_tx.clone(),
+                db_transmitter: sun2000_tx.clone(),
+                mqtt_transmitter: sun2000_mqtt_tx.clone(),
+                notify_transmitter: sun2000_notify_tx.clone(),
+                energy_costs: sun2000_energy_costs.clone(),
+                events: sun2000_event_tx.clone(),
+                ow_transmitter: sun2000_ow_tx.clone(),
+                mode_change_script: config.sun2000.mode_change_script.clone(),
+                optimizers: config.sun2000.optimizers,
+                battery_installed: config.sun2000.battery_installed,
+                dongle_connection: config.sun2000.dongle_connection,
+                backup_box: config.sun2000.backup_box,
+                load_shedding_tag: config.sun2000.load_shedding_tag.clone(),
+                backup_soc_request: sun2000_backup_soc_request.clone(),
+                rules: load_energy_rules(config),
+                sensor_values: sun2000_sensor_values.clone(),
+                grid_export: sun2000_grid_export.clone(),
+                poll_interval: config.sun2000.poll_interval,
+                stats_interval: config.sun2000.stats_interval,
+                errors: worker_error::ErrorReporter {
+                    worker: "sun2000".to_string(),
+                    metrics: sun2000_worker_errors.clone(),
+                    notify_transmitter: sun2000_notify_tx.clone(),
+                },
+            };
+            Some(Box::pin(async move { sun2000.worker(worker_cancel_flag).await }) as WorkerFuture)
+        }),
+    ));
+
+    //remeha async task
+    let remeha_cascade = Arc::new(RwLock::new(remeha::RemehaCascade {
+        lcd_line: config.general.remeha_lcd_line,
+        ..Default::default()
+    }));
+    let remeha_setpoint_requests = Arc::new(Mutex::new(vec![]));
+    let remeha_cascade_cloned = remeha_cascade.clone();
+    let remeha_lcd_tx = lcd_tx.clone();
+    let remeha_setpoint_requests_cloned = remeha_setpoint_requests.clone();
+    let remeha_mqtt_tx = mqtt_tx.clone();
+    let remeha_notify_tx = notify_tx.clone();
+    let remeha_event_tx = event_tx.clone();
+    let remeha_worker_errors = worker_errors.clone();
+    restartable.push(RestartableWorker::new(
+        "remeha",
+        Box::new(move |worker_cancel_flag, config: &Config| {
+            let mut remeha = remeha::Remeha {
+                name: "remeha".to_string(),
+                display_name: "<i><bright-black>remeha:</>".to_string(),
+                device_host_port: config.general.remeha_device.clone()?,
                 poll_ok: 0,
                 poll_errors: 0,
-                influxdb_url: influxdb_url.clone(),
-                lcd_transmitter: lcd_tx.clone(),
-                db_transmitter: tx.clone(),
-                mode_change_script: get_config_string("mode_change_script", Some("sun2000")),
-                optimizers: get_config_bool("optimizers", Some("sun2000")),
-                battery_installed: get_config_bool("battery_installed", Some("sun2000")),
-                dongle_connection: get_config_bool("dongle_connection", Some("sun2000")),
+                influxdb_url: config.general.influxdb_url.clone(),
+                influx_database: remeha::REMEHA_DEFAULT_INFLUX_DATABASE.to_string(),
+                influx_boiler_tag: false,
+                state_change_script: config.general.remeha_state_change_script.clone(),
+                recovery_script: config.general.remeha_recovery_script.clone(),
+                alarm_script: config.general.remeha_alarm_script.clone(),
+                mqtt_transmitter: remeha_mqtt_tx.clone(),
+                notify_transmitter: remeha_notify_tx.clone(),
+                cascade: remeha_cascade_cloned.clone(),
+                lcd_transmitter: remeha_lcd_tx.clone(),
+                allow_write: config.general.remeha_allow_write,
+                setpoint_requests: remeha_setpoint_requests_cloned.clone(),
+                events: remeha_event_tx.clone(),
+                poll_interval: config.general.remeha_poll_interval,
+                stats_interval: config.general.remeha_stats_interval,
+                counters_interval: config.general.remeha_counters_interval,
+                errors: worker_error::ErrorReporter {
+                    worker: "remeha".to_string(),
+                    metrics: remeha_worker_errors.clone(),
+                    notify_transmitter: remeha_notify_tx.clone(),
+                },
             };
-            let sun2000_future = async move { sun2000.worker(worker_cancel_flag).compat().await };
-            futures.spawn(sun2000_future);
+            Some(Box::pin(async move { remeha.worker(worker_cancel_flag).await }) as WorkerFuture)
+        }),
+    ));
+
+    //additional boilers of a cascade, each from its own [remeha:<name>] section
+    for name in config.remeha_boilers.iter().map(|b| b.name.clone()) {
+        let remeha_cascade_cloned = remeha_cascade.clone();
+        let remeha_lcd_tx = lcd_tx.clone();
+        let remeha_setpoint_requests_cloned = remeha_setpoint_requests.clone();
+        let remeha_mqtt_tx = mqtt_tx.clone();
+        let remeha_notify_tx = notify_tx.clone();
+        let remeha_event_tx = event_tx.clone();
+        let remeha_worker_errors = worker_errors.clone();
+        restartable.push(RestartableWorker::new(
+            &format!("remeha:{}", name),
+            Box::new(move |worker_cancel_flag, config: &Config| {
+                let boiler = config.remeha_boilers.iter().find(|b| b.name == name)?;
+                let mut remeha = remeha::Remeha {
+                    name: name.clone(),
+                    display_name: format!("<i><bright-black>remeha:{}:</>", name),
+                    device_host_port: boiler.device.clone(),
+                    poll_ok: 0,
+                    poll_errors: 0,
+                    influxdb_url: config.general.influxdb_url.clone(),
+                    influx_database: boiler.influx_database.clone(),
+                    influx_boiler_tag: true,
+                    state_change_script: boiler.state_change_script.clone(),
+                    recovery_script: boiler.recovery_script.clone(),
+                    alarm_script: boiler.alarm_script.clone(),
+                    mqtt_transmitter: remeha_mqtt_tx.clone(),
+                    notify_transmitter: remeha_notify_tx.clone(),
+                    cascade: remeha_cascade_cloned.clone(),
+                    lcd_transmitter: remeha_lcd_tx.clone(),
+                    allow_write: boiler.allow_write,
+                    setpoint_requests: remeha_setpoint_requests_cloned.clone(),
+                    events: remeha_event_tx.clone(),
+                    poll_interval: boiler.poll_interval,
+                    stats_interval: boiler.stats_interval,
+                    counters_interval: boiler.counters_interval,
+                    errors: worker_error::ErrorReporter {
+                        worker: format!("remeha:{}", name),
+                        metrics: remeha_worker_errors.clone(),
+                        notify_transmitter: remeha_notify_tx.clone(),
+                    },
+                };
+                Some(
+                    Box::pin(async move { remeha.worker(worker_cancel_flag).await })
+                        as WorkerFuture,
+                )
+            }),
+        ));
+    }
+
+    //P1 port smart electricity meter async task
+    let dsmr_lcd_tx = lcd_tx.clone();
+    let dsmr_sensor_values = sensor_values.clone();
+    let dsmr_grid_export = grid_export.clone();
+    restartable.push(RestartableWorker::new(
+        "dsmr",
+        Box::new(move |worker_cancel_flag, config: &Config| {
+            let device = config.dsmr.device.clone()?;
+            let mut dsmr = dsmr::Dsmr {
+                name: "dsmr".to_string(),
+                device: if device.contains(':') && !device.starts_with('/') {
+                    device_io::DeviceAddress::Tcp(device)
+                } else {
+                    device_io::DeviceAddress::Path(device)
+                },
+                influxdb_url: config.general.influxdb_url.clone(),
+                sensor_values: dsmr_sensor_values.clone(),
+                grid_export: dsmr_grid_export.clone(),
+                lcd_transmitter: dsmr_lcd_tx.clone(),
+                lcd_line: config.dsmr.lcd_line,
+                report_interval: config.dsmr.report_interval,
+                stats_interval: config.dsmr.stats_interval,
+            };
+            Some(Box::pin(async move { dsmr.worker(worker_cancel_flag).await }) as WorkerFuture)
+        }),
+    ));
+
+    //surplus PV load scheduler async task
+    let energy_manager_ow_tx = ow_tx.clone();
+    let energy_manager_grid_export = grid_export.clone();
+    restartable.push(RestartableWorker::new(
+        "energy_manager",
+        Box::new(move |worker_cancel_flag, config: &Config| {
+            let loads = energy_manager::loads_from_config(config);
+            if loads.is_empty() {
+                return None;
+            }
+            let mut manager = energy_manager::EnergyManager {
+                name: "energy_manager".to_string(),
+                loads,
+                grid_export: energy_manager_grid_export.clone(),
+                ow_transmitter: energy_manager_ow_tx.clone(),
+                check_interval: config.energy_manager.check_interval,
+                hysteresis: config.energy_manager.hysteresis,
+            };
+            Some(Box::pin(async move { manager.worker(worker_cancel_flag).await }) as WorkerFuture)
+        }),
+    ));
+
+    //OpenTherm gateway async task, an alternative boiler interface
+    let opentherm_setpoint_requests = remeha_setpoint_requests.clone();
+    let opentherm_event_tx = event_tx.clone();
+    restartable.push(RestartableWorker::new(
+        "opentherm",
+        Box::new(move |worker_cancel_flag, config: &Config| {
+            let device = config.opentherm.device.clone()?;
+            let name = config
+                .opentherm
+                .name
+                .clone()
+                .unwrap_or(opentherm::OPENTHERM_DEFAULT_BOILER.to_string());
+            let mut opentherm = opentherm::OpenTherm {
+                display_name: format!("<i><bright-black>{}:</>", name),
+                name,
+                device: if device.contains(':') && !device.starts_with('/') {
+                    device_io::DeviceAddress::Tcp(device)
+                } else {
+                    device_io::DeviceAddress::Path(device)
+                },
+                influxdb_url: config.general.influxdb_url.clone(),
+                influx_database: config
+                    .opentherm
+                    .influx_database
+                    .clone()
+                    .unwrap_or(opentherm::OPENTHERM_DEFAULT_INFLUX_DATABASE.to_string()),
+                allow_write: config.opentherm.allow_write,
+                setpoint_requests: opentherm_setpoint_requests.clone(),
+                events: opentherm_event_tx.clone(),
+                report_interval: config.opentherm.report_interval,
+                stats_interval: config.opentherm.stats_interval,
+            };
+            Some(
+                Box::pin(async move { opentherm.worker(worker_cancel_flag).await }) as WorkerFuture,
+            )
+        }),
+    ));
+
+    //geiger counter async task
+    let geiger_lcd_tx = lcd_tx.clone();
+    restartable.push(RestartableWorker::new(
+        "geiger",
+        Box::new(move |worker_cancel_flag, config: &Config| {
+            let device = config.geiger.device.clone()?;
+            let mut geiger = geiger::Geiger {
+                name: "geiger".to_string(),
+                device: if device.contains(':') && !device.starts_with('/') {
+                    device_io::DeviceAddress::Tcp(device)
+                } else {
+                    device_io::DeviceAddress::Serial {
+                        path: device,
+                        baud_rate: config
+                            .geiger
+                            .baud_rate
+                            .unwrap_or(geiger::GEIGER_DEFAULT_BAUD_RATE),
+                    }
+                },
+                mode: geiger::GeigerMode::parse(&config.geiger.mode),
+                factor: config
+                    .geiger
+                    .factor
+                    .unwrap_or(geiger::GEIGER_DEFAULT_FACTOR),
+                alarm_threshold: config.geiger.alarm_threshold,
+                alarm_script: config.geiger.alarm_script.clone(),
+                influxdb_url: config.general.influxdb_url.clone(),
+                lcd_transmitter: geiger_lcd_tx.clone(),
+                lcd_line: config
+                    .geiger
+                    .lcd_line
+                    .unwrap_or(geiger::GEIGER_DEFAULT_LCD_LINE),
+            };
+            Some(Box::pin(async move { geiger.worker(worker_cancel_flag).await }) as WorkerFuture)
+        }),
+    ));
+
+    //washer/dryer power monitoring async task
+    let appliance_mqtt_tx = mqtt_tx.clone();
+    let appliance_notify_tx = notify_tx.clone();
+    let appliance_sensor_values = sensor_values.clone();
+    restartable.push(RestartableWorker::new(
+        "appliance",
+        Box::new(move |worker_cancel_flag, config: &Config| {
+            let appliances = load_appliances(config);
+            if appliances.is_empty() {
+                return None;
+            }
+            let mut monitor = appliance::ApplianceMonitor {
+                name: "appliance".to_string(),
+                appliances,
+                notify_transmitter: appliance_notify_tx.clone(),
+                sensor_values: appliance_sensor_values.clone(),
+                mqtt_transmitter: appliance_mqtt_tx.clone(),
+            };
+            Some(Box::pin(async move { monitor.worker(worker_cancel_flag).await }) as WorkerFuture)
+        }),
+    ));
+
+    //heating zones controller async task
+    let heating_sensor_values = sensor_values.clone();
+    let heating_season_cloned = heating_season.clone();
+    let heating_ow_tx = ow_tx.clone();
+    let heating_setpoint_requests = remeha_setpoint_requests.clone();
+    let heating_window_cutback = window_cutback.clone();
+    restartable.push(RestartableWorker::new(
+        "heating",
+        Box::new(move |worker_cancel_flag, config: &Config| {
+            let zones = heating::zones_from_config(config);
+            if zones.is_empty() {
+                return None;
+            }
+            let mut controller = heating::HeatingController {
+                name: "heating".to_string(),
+                zones,
+                boiler: config
+                    .heating
+                    .ch_setpoint
+                    .map(|demand_setpoint| heating::BoilerControl {
+                        boiler: config
+                            .heating
+                            .boiler
+                            .clone()
+                            .unwrap_or(heating::HEATING_DEFAULT_BOILER.to_string()),
+                        demand_setpoint,
+                        idle_setpoint: config
+                            .heating
+                            .ch_setpoint_idle
+                            .unwrap_or(remeha::RemehaSetpoint::Ch(20)),
+                    }),
+                check_interval: config.heating.check_interval,
+                sensor_values: heating_sensor_values.clone(),
+                heating_season: heating_season_cloned.clone(),
+                ow_transmitter: heating_ow_tx.clone(),
+                setpoint_requests: heating_setpoint_requests.clone(),
+                window_cutback: heating_window_cutback.clone(),
+            };
+            Some(
+                Box::pin(async move { controller.worker(worker_cancel_flag).await })
+                    as WorkerFuture,
+            )
+        }),
+    ));
+
+    //water/gas pulse meters async task
+    let meter_totals_cloned = meter_totals.clone();
+    restartable.push(RestartableWorker::new(
+        "meter",
+        Box::new(move |worker_cancel_flag, config: &Config| {
+            let meters = meter::meters_from_config(config);
+            if meters.is_empty() {
+                return None;
+            }
+            let mut monitor = meter::MeterMonitor {
+                name: "meter".to_string(),
+                meters,
+                owserver: config.general.owserver.clone(),
+                totals: meter_totals_cloned.clone(),
+                influxdb_url: config.general.influxdb_url.clone(),
+                poll_interval: config.general.meter_poll_interval,
+            };
+            Some(Box::pin(async move { monitor.worker(worker_cancel_flag).await }) as WorkerFuture)
+        }),
+    ));
+
+    //generic modbus devices async tasks
+    for name in config.modbus_devices.iter().map(|d| d.name.clone()) {
+        let modbus_mqtt_tx = mqtt_tx.clone();
+        let worker_name = format!("modbus:{}", name);
+        restartable.push(RestartableWorker::new(
+            &worker_name,
+            Box::new(move |worker_cancel_flag, config: &Config| {
+                let mut device = load_modbus_device(config, &name, modbus_mqtt_tx.clone())?;
+                Some(
+                    Box::pin(async move { device.worker(worker_cancel_flag).await })
+                        as WorkerFuture,
+                )
+            }),
+        ));
+    }
+
+    for worker in &mut restartable {
+        worker.spawn(&mut futures, &config);
+    }
+
+    //mqtt async task
+    match &config.mqtt.host {
+        Some(host) => {
+            let worker_cancel_flag = drain_cancel_flag.clone();
+            let mut mqtt = mqtt::Mqtt {
+                name: "mqtt".to_string(),
+                host: host.clone(),
+                port: config.mqtt.port.unwrap_or(mqtt::MQTT_DEFAULT_PORT),
+                client_id: config.mqtt.client_id.clone().unwrap_or("hard".to_string()),
+                username: config.mqtt.username.clone(),
+                password: config.mqtt.password.clone(),
+                topic_prefix: config
+                    .mqtt
+                    .topic_prefix
+                    .clone()
+                    .unwrap_or(mqtt::MQTT_DEFAULT_TOPIC_PREFIX.to_string()),
+                receiver: mqtt_rx,
+                ow_transmitter: ow_tx.clone(),
+            };
+            let mqtt_future = async move { mqtt.worker(worker_cancel_flag).await };
+            futures.spawn(mqtt_future);
         }
         _ => {}
     }
 
+    //notifications async task, without any [notify:<name>] section they are only dropped
+    let mut notifier = notify::Notifier {
+        name: "notify".to_string(),
+        receiver: notify_rx,
+        targets: config.notify_targets.clone(),
+        rate_limit: notify::RateLimit::new(config.notify.rate_limit, config.notify.max_per_hour),
+    };
+    let worker_cancel_flag = drain_cancel_flag.clone();
+    let notify_future = async move { notifier.worker(worker_cancel_flag).await };
+    futures.spawn(notify_future);
+
+    if !config.general.disable_webserver {
+        //creating webserver task
+        let mut webserver = webserver::WebServer {
+            name: "webserver".to_string(),
+            ow_transmitter: ow_tx,
+            db_transmitter: tx.clone(),
+            sensor_devices: onewire_sensor_devices.clone(),
+            relay_devices: onewire_relay_devices.clone(),
+            relays: onewire_relays.clone(),
+            sensor_values: sensor_values.clone(),
+            cesspool_history: cesspool_history.clone(),
+            mailbox_state: mailbox_state.clone(),
+            gate_states: gate_states.clone(),
+            adaptive_hold: adaptive_hold.clone(),
+            alarm_armed: alarm_armed.clone(),
+            backup_soc_request: backup_soc_request.clone(),
+            skymax_setting_requests: skymax_setting_requests.clone(),
+            remeha_setpoint_requests: remeha_setpoint_requests.clone(),
+            heating_season: heating_season.clone(),
+            energy_costs: energy_costs.clone(),
+            ontime: ontime.clone(),
+            events: event_tx.clone(),
+            bus_metrics: bus_metrics.clone(),
+            latency: latency.clone(),
+            queue_metrics: queue_metrics.clone(),
+            worker_errors: worker_errors.clone(),
+            log_buffer: log_buffer.clone(),
+            service_control: webserver::ServiceControl {
+                allow_reboot: config.general.allow_reboot,
+                reboot_command: config.general.reboot_command.clone(),
+                workers: restartable.iter().map(|w| w.name.clone()).collect(),
+                restart_requests: restart_requests.clone(),
+            },
+            auth: webserver::WebAuth {
+                control_token: config.webserver.control_token.clone(),
+                control_user: config.webserver.control_user.clone(),
+                read_token: config.webserver.read_token.clone(),
+                read_user: config.webserver.read_user.clone(),
+            },
+            address: config.webserver.address.clone(),
+            port: config.webserver.port,
+            tls_cert: config.webserver.tls_cert.clone(),
+            tls_key: config.webserver.tls_key.clone(),
+        };
+        let worker_cancel_flag = cancel_flag.clone();
+        let webserver_future = async move { webserver.worker(worker_cancel_flag).await };
+        futures.spawn(webserver_future);
+    }
+
+    //the lcd tasks are shown by lcdproc and/or the ethlcd display
+    let ethlcd_display = config.general.ethlcd_display && config.general.ethlcd_host.is_some();
+    let (lcdproc_rx, ethlcd_rx) = match (&config.general.lcdproc, ethlcd_display) {
+        (Some(_), true) => {
+            let (lcdproc_tx, lcdproc_rx) = queue::bounded(
+                "lcdproc_out",
+                queue::LCD_QUEUE_CAPACITY,
+                OverflowPolicy::DropOldest,
+                &queue_metrics,
+            );
+            let (ethlcd_tx, ethlcd_rx) = queue::bounded(
+                "ethlcd",
+                queue::LCD_QUEUE_CAPACITY,
+                OverflowPolicy::DropOldest,
+                &queue_metrics,
+            );
+            let worker_cancel_flag = drain_cancel_flag.clone();
+            futures.spawn(async move {
+                while !worker_cancel_flag.load(Ordering::SeqCst) {
+                    let task = tokio::select! {
+                        t = lcd_rx.recv() => t,
+                        _ = tokio::time::sleep(Duration::from_millis(100)) => None,
+                    };
+                    if let Some(t) = task {
+                        let _ = lcdproc_tx.send(t.clone());
+                        let _ = ethlcd_tx.send(t);
+                    }
+                }
+                Ok(())
+            });
+            (Some(lcdproc_rx), Some(ethlcd_rx))
+        }
+        (_, true) => (None, Some(lcd_rx)),
+        _ => (Some(lcd_rx), None),
+    };
+
+    //ethlcd display async task
+    if let (Some(host), Some(lcd_receiver)) = (&config.general.ethlcd_host, ethlcd_rx) {
+        let worker_cancel_flag = drain_cancel_flag.clone();
+        let mut display = ethlcd::EthLcdDisplay {
+            name: "ethlcd_display".to_string(),
+            host: host.clone(),
+            lcd_receiver,
+            rows: config.general.ethlcd_rows,
+            cols: config.general.ethlcd_cols,
+            page_interval: config.general.ethlcd_page_interval,
+            night_backlight: config.general.ethlcd_night_backlight,
+            lat: config.general.lat,
+            lon: config.general.lon,
+            lcd_lines: vec![],
+            level: None,
+            emergency: false,
+        };
+        futures.spawn(async move { display.worker(worker_cancel_flag).await });
+    }
+
     //lcdproc async task
-    match get_config_string("lcdproc", None) {
-        Some(host) => {
-            let worker_cancel_flag = cancel_flag.clone();
+    match (&config.general.lcdproc, lcdproc_rx) {
+        (Some(host), Some(lcd_receiver)) => {
+            let worker_cancel_flag = drain_cancel_flag.clone();
             let mut lcdproc = lcdproc::Lcdproc {
                 name: "lcdproc".to_string(),
-                lcdproc_host_port: host,
-                lcd_receiver: lcd_rx,
+                lcdproc_host_port: host.clone(),
+                lcd_receiver,
                 lcd_lines: vec![],
                 level: None,
+                screens: lcdproc::screens_from_config(&config),
+                emergency: false,
+                width: lcdproc::LCDPROC_DEFAULT_WIDTH,
+                height: lcdproc::LCDPROC_DEFAULT_HEIGHT,
+                cell_width: 5,
             };
             let lcdproc_future = async move { lcdproc.worker(worker_cancel_flag).await };
             futures.spawn(lcdproc_future);
@@ -318,23 +1330,17 @@ async fn main() {
         _ => {}
     }
 
-    //remeha async task
-    match get_config_string("remeha_device", None) {
-        Some(host) => {
-            let worker_cancel_flag = cancel_flag.clone();
-            let mut remeha = remeha::Remeha {
-                display_name: "<i><bright-black>remeha:</>".to_string(),
-                device_host_port: host,
-                poll_ok: 0,
-                poll_errors: 0,
-                influxdb_url: influxdb_url.clone(),
-                state_change_script: get_config_string("remeha_state_change_script", None),
-            };
-            let remeha_future = async move { remeha.worker(worker_cancel_flag).await };
-            futures.spawn(remeha_future);
-        }
-        _ => {}
+    //all configured workers are started
+    systemd::notify("READY=1");
+    let watchdog_interval = systemd::watchdog_interval();
+    if let Some(interval) = watchdog_interval {
+        info!(
+            "systemd: 🐕 watchdog enabled, ping interval: {}",
+            format_duration(interval)
+        );
     }
+    let mut heartbeat_check = Instant::now();
+    let mut stalled_workers: Vec<String> = vec![];
 
     debug!("Entering main loop...");
     loop {
@@ -343,27 +1349,99 @@ async fn main() {
             break;
         }
 
+        //the watchdog is pinged only when all workers are alive, so a hung one gets us restarted
+        if heartbeat_check.elapsed()
+            > watchdog_interval.unwrap_or(Duration::from_secs_f32(
+                systemd::HEARTBEAT_CHECK_INTERVAL_SECS,
+            ))
+        {
+            heartbeat_check = Instant::now();
+            let stalled = systemd::stalled(config.general.heartbeat_timeout);
+            if stalled.is_empty() {
+                if !stalled_workers.is_empty() {
+                    info!("🐕 all workers are responding again");
+                    systemd::notify("STATUS=running");
+                }
+                if watchdog_interval.is_some() {
+                    systemd::notify("WATCHDOG=1");
+                }
+            } else if stalled != stalled_workers {
+                error!(
+                    "🐕 workers not responding for {}: {}",
+                    format_duration(config.general.heartbeat_timeout),
+                    stalled.join(", ")
+                );
+                systemd::notify(&format!("STATUS=stalled: {}", stalled.join(", ")));
+            }
+            stalled_workers = stalled;
+        }
+
+        //handle remote restart requests
+        let requests: Vec<String> = restart_requests.lock().unwrap().drain(..).collect();
+        if !requests.is_empty() {
+            //restarted workers are created from the current config file
+            match Config::load(config::CONFIG_FILE) {
+                Ok(new_config) => config = Arc::new(new_config),
+                Err(errors) => {
+                    for e in errors {
+                        error!("config error: {}", e);
+                    }
+                    warn!("🔄 invalid config, restarting with the previous one");
+                }
+            }
+        }
+        for name in requests {
+            if let Some(worker) = restartable.iter_mut().find(|w| w.name == name) {
+                info!("🔄 restarting worker: {}", name);
+                worker.cancel_flag.store(true, Ordering::SeqCst);
+                worker.restart_pending = true;
+            }
+        }
+        for worker in &mut restartable {
+            //wait for the previous instance to stop before spawning a new one
+            if worker.restart_pending && worker.finished.load(Ordering::SeqCst) {
+                worker.restart_pending = false;
+                worker.spawn(&mut futures, &config);
+            }
+        }
+
         tokio::time::sleep(Duration::from_millis(50)).await;
     }
 
     info!("🏁 Stopping all threads...");
-    //inform all threads about termination
+    systemd::notify("STOPPING=1");
+    let shutdown_deadline = Instant::now() + config.general.shutdown_timeout;
+    //first the producers: no new tasks are accepted, the onewire thread processes the queued ones
     cancel_flag.store(true, Ordering::SeqCst);
+    for worker in &restartable {
+        worker.cancel_flag.store(true, Ordering::SeqCst);
+    }
     //wait for termination
     for t in threads {
-        // Wait for the thread to finish. Returns a result.
-        let _ = t.join();
+        let remaining = shutdown_deadline.saturating_duration_since(Instant::now());
+        if tokio::time::timeout(remaining, task::spawn_blocking(move || t.join()))
+            .await
+            .is_err()
+        {
+            error!("Unable to gracefully stop all threads, continuing...");
+            break;
+        }
     }
-    //wait for tokio async tasks
-    let mut cnt = 2;
-    while let f = futures.join_next() {
-        if let Err(r) = tokio::time::timeout(Duration::from_secs(10), f).await {
-            cnt = cnt - 1;
-            if cnt == 0 {
-                error!("Unable to gracefully stop all tasks, forcing stop...");
+    //then the consumers: database, lcd and mqtt queues are drained and the data flushed
+    drain_cancel_flag.store(true, Ordering::SeqCst);
+    loop {
+        let remaining = shutdown_deadline.saturating_duration_since(Instant::now());
+        match tokio::time::timeout(remaining, futures.join_next()).await {
+            Ok(Some(_)) => continue,
+            Ok(None) => break,
+            Err(_) => {
+                error!(
+                    "Unable to gracefully stop all tasks within {}, forcing stop...",
+                    format_duration(config.general.shutdown_timeout)
+                );
+                futures.shutdown().await;
                 break;
-            };
-            warn!("Still waiting for task(s) to stop...");
+            }
         }
     }
 
@@ -0,0 +1,338 @@
+use crate::config::Config;
+use crate::influx::{Client, InfluxDbWriteable, Timestamp, WriteQuery};
+use crate::onewire::{get_w1_device_name, w1_root};
+use crate::owserver::{get_owfs_device_name, OwServer};
+use chrono::Utc;
+use futures::StreamExt;
+use gpio_cdev::{AsyncLineEventHandle, Chip, EventRequestFlags, LineRequestFlags};
+use simplelog::*;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+pub const FAMILY_CODE_DS2423: u8 = 0x1d;
+pub const METER_POLL_INTERVAL_SECS: f32 = 10.0; //secs between reading the counters
+pub const METER_MIN_POLL_INTERVAL_SECS: f32 = 1.0;
+pub const METER_GPIO_RETRY_SECS: u64 = 5; //delay before reopening a failed GPIO line
+pub const METER_INFLUX_DATABASE: &str = "hard";
+
+// Just a generic Result type to ease error handling for us. Errors in multithreaded
+// async contexts needs some extra restrictions
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Where the pulses of a `[meter:<name>]` come from
+#[derive(Clone, Debug)]
+pub enum MeterSource {
+    /// DS2423 counter chip on the 1-Wire bus, counter A or B
+    Ds2423 { address: u64, channel: char },
+    /// falling edges on a GPIO line
+    Gpio { chip: String, line: u32 },
+}
+
+/// Water/gas meter with a pulse output
+pub struct PulseMeter {
+    pub name: String,
+    pub source: MeterSource,
+    pub pulses_per_unit: f64,
+    pub unit: String,
+    //last raw DS2423 counter value, the pulses are the difference
+    last_counter: Option<u64>,
+    //pulses counted by the GPIO task since the last poll
+    gpio_pulses: Arc<AtomicU64>,
+}
+
+impl PulseMeter {
+    pub fn new(name: String, source: MeterSource, pulses_per_unit: f64, unit: String) -> Self {
+        PulseMeter {
+            name,
+            source,
+            pulses_per_unit,
+            unit,
+            last_counter: None,
+            gpio_pulses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// DS2423 counter: `counter.A`/`counter.B` through owserver or the `c=` value of
+    /// the pages 14 and 15 in the w1 `w1_slave` file
+    fn read_ds2423(owserver: &Option<String>, address: u64, channel: char) -> io::Result<u64> {
+        let (text, origin) = match owserver {
+            Some(server) => {
+                let path = format!(
+                    "/uncached/{}/counter.{}",
+                    get_owfs_device_name(FAMILY_CODE_DS2423, address),
+                    channel
+                );
+                let data = OwServer::new(server).read(&path)?;
+                (String::from_utf8_lossy(&data).trim().to_string(), path)
+            }
+            None => {
+                let path = format!(
+                    "{}/{}/w1_slave",
+                    w1_root(),
+                    get_w1_device_name(FAMILY_CODE_DS2423, address)
+                );
+                let data = fs::read_to_string(&path)?;
+                let line = data
+                    .lines()
+                    .filter(|l| l.contains("c="))
+                    .nth(if channel == 'A' { 2 } else { 3 })
+                    .unwrap_or_default()
+                    .to_string();
+                if !line.contains("crc=YES") {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("{}: CRC error or missing counter: {:?}", path, line),
+                    ));
+                }
+                let value = line.rsplit("c=").next().unwrap_or_default().trim();
+                (value.to_string(), path)
+            }
+        };
+        text.parse().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{}: invalid counter value: {:?}", origin, text),
+            )
+        })
+    }
+
+    /// Pulses since the previous call
+    async fn pulses(&mut self, owserver: &Option<String>) -> io::Result<u64> {
+        match &self.source {
+            MeterSource::Ds2423 { address, channel } => {
+                let (owserver, address, channel) = (owserver.clone(), *address, *channel);
+                let counter = tokio::task::spawn_blocking(move || {
+                    PulseMeter::read_ds2423(&owserver, address, channel)
+                })
+                .await
+                .map_err(|e| Error::new(ErrorKind::Other, e))??;
+                let pulses = match self.last_counter {
+                    Some(last) if counter >= last => counter - last,
+                    //the chip was powered off and counts from zero again
+                    Some(_) => counter,
+                    None => 0,
+                };
+                self.last_counter = Some(counter);
+                Ok(pulses)
+            }
+            MeterSource::Gpio { .. } => Ok(self.gpio_pulses.swap(0, Ordering::SeqCst)),
+        }
+    }
+}
+
+/// Pulses counted per meter, loaded from and saved to the `meter_totals` table
+#[derive(Default)]
+pub struct MeterTotals {
+    pub pulses: HashMap<String, i64>,
+    pub dirty: bool,
+}
+
+impl MeterTotals {
+    pub fn add(&mut self, name: &str, pulses: u64) {
+        *self.pulses.entry(name.to_string()).or_insert(0) += pulses as i64;
+        self.dirty = true;
+    }
+
+    /// Restores a total loaded from the database, adding what was counted before the loading
+    pub fn restore(&mut self, name: String, pulses: i64) {
+        *self.pulses.entry(name).or_insert(0) += pulses;
+    }
+}
+
+//counts the falling edges of a GPIO line, reopened after errors
+async fn count_edges(
+    name: String,
+    chip: String,
+    line: u32,
+    pulses: Arc<AtomicU64>,
+    worker_cancel_flag: Arc<AtomicBool>,
+) {
+    while !worker_cancel_flag.load(Ordering::SeqCst) {
+        let events = Chip::new(&chip).and_then(|mut chip| {
+            chip.get_line(line)?.events(
+                LineRequestFlags::INPUT,
+                EventRequestFlags::FALLING_EDGE,
+                "hard-meter",
+            )
+        });
+        match events.and_then(AsyncLineEventHandle::new) {
+            Ok(mut events) => {
+                info!("meter {}: GPIO line {}:{} opened", name, chip, line);
+                while let Some(event) = events.next().await {
+                    if let Err(e) = event {
+                        error!("meter {}: GPIO event error: {}", name, e);
+                        break;
+                    }
+                    pulses.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            Err(e) => error!("meter {}: unable to open GPIO line: {}", name, e),
+        }
+        tokio::time::sleep(Duration::from_secs(METER_GPIO_RETRY_SECS)).await;
+    }
+}
+
+pub struct MeterMonitor {
+    pub name: String,
+    pub meters: Vec<PulseMeter>,
+    pub owserver: Option<String>,
+    pub totals: Arc<RwLock<MeterTotals>>,
+    pub influxdb_url: Option<String>,
+    pub poll_interval: Duration,
+}
+
+impl MeterMonitor {
+    async fn save_to_influxdb(&self, queries: &[WriteQuery]) {
+        let url = match &self.influxdb_url {
+            Some(url) => url,
+            None => return,
+        };
+        let client = Client::new(url, METER_INFLUX_DATABASE);
+        match client.write(queries).await {
+            Ok(msg) => debug!("{}: influxdb write success: {:?}", self.name, msg),
+            Err(e) => error!("{}: influxdb write error: {:?}", self.name, e),
+        }
+    }
+
+    async fn poll(&mut self, elapsed: Duration) {
+        let mut queries = vec![];
+        for meter in self.meters.iter_mut() {
+            let pulses = match meter.pulses(&self.owserver).await {
+                Ok(pulses) => pulses,
+                Err(e) => {
+                    error!("{}: {}: read error: {}", self.name, meter.name, e);
+                    continue;
+                }
+            };
+            let total_pulses = {
+                let mut totals = self.totals.write().unwrap();
+                if pulses > 0 {
+                    totals.add(&meter.name, pulses);
+                }
+                totals.pulses.get(&meter.name).copied().unwrap_or(0)
+            };
+            let total = total_pulses as f64 / meter.pulses_per_unit;
+            //units per hour
+            let flow = pulses as f64 / meter.pulses_per_unit / elapsed.as_secs_f64() * 3600.0;
+            debug!(
+                "{}: {}: {} pulses, total: {:.3} {}, flow: {:.3} {}/h",
+                self.name, meter.name, pulses, total, meter.unit, flow, meter.unit
+            );
+            queries.push(
+                Timestamp::from(Utc::now())
+                    .into_query("meter")
+                    .add_tag("name", meter.name.clone())
+                    .add_tag("unit", meter.unit.clone())
+                    .add_field("total", total)
+                    .add_field("flow", flow)
+                    .add_field("pulses", pulses),
+            );
+        }
+        if !queries.is_empty() {
+            self.save_to_influxdb(&queries).await;
+        }
+    }
+
+    pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
+        info!(
+            "{}: Starting task, meters: {:?}",
+            self.name,
+            self.meters.iter().map(|m| &m.name).collect::<Vec<_>>()
+        );
+        let mut gpio_tasks = JoinSet::new();
+        for meter in &self.meters {
+            if let MeterSource::Gpio { chip, line } = &meter.source {
+                gpio_tasks.spawn(count_edges(
+                    meter.name.clone(),
+                    chip.clone(),
+                    *line,
+                    meter.gpio_pulses.clone(),
+                    worker_cancel_flag.clone(),
+                ));
+            }
+        }
+        //the first DS2423 read only sets the starting counter values
+        let mut last_poll = Instant::now();
+        self.poll(self.poll_interval).await;
+
+        loop {
+            if worker_cancel_flag.load(Ordering::SeqCst) {
+                debug!("{}: Got terminate signal from main", self.name);
+                break;
+            }
+            if last_poll.elapsed() >= self.poll_interval {
+                let elapsed = last_poll.elapsed();
+                last_poll = Instant::now();
+                self.poll(elapsed).await;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        gpio_tasks.shutdown().await;
+        info!("{}: task stopped", self.name);
+        Ok(())
+    }
+}
+
+/// Reads the `[meter:<name>]` sections, the ones with missing options are skipped
+pub fn meters_from_config(config: &Config) -> Vec<PulseMeter> {
+    let mut meters = vec![];
+    for (name, properties) in config.sections("meter:") {
+        let get = |key: &str| properties.get(key).map(|v| v.trim().to_string());
+        let source = match get("source").as_deref() {
+            Some("ds2423") => {
+                let address = get("address").and_then(|a| {
+                    match a.strip_prefix("0x").or_else(|| a.strip_prefix("0X")) {
+                        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                        None => a.parse().ok(),
+                    }
+                });
+                let channel = match get("channel").as_deref() {
+                    None | Some("A") | Some("a") => 'A',
+                    Some("B") | Some("b") => 'B',
+                    Some(other) => {
+                        warn!("meter {}: invalid channel: {:?}", name, other);
+                        continue;
+                    }
+                };
+                match address {
+                    Some(address) => MeterSource::Ds2423 { address, channel },
+                    None => {
+                        warn!("meter {}: missing or invalid address", name);
+                        continue;
+                    }
+                }
+            }
+            Some("gpio") => match (get("chip"), get("line").and_then(|l| l.parse().ok())) {
+                (Some(chip), Some(line)) => MeterSource::Gpio { chip, line },
+                _ => {
+                    warn!("meter {}: gpio needs chip and line", name);
+                    continue;
+                }
+            },
+            other => {
+                warn!("meter {}: missing or unknown source: {:?}", name, other);
+                continue;
+            }
+        };
+        let pulses_per_unit = match get("pulses_per_unit").and_then(|p| p.parse::<f64>().ok()) {
+            Some(ppu) if ppu > 0.0 => ppu,
+            _ => {
+                warn!("meter {}: missing or invalid pulses_per_unit", name);
+                continue;
+            }
+        };
+        let unit = get("unit").unwrap_or("m3".to_string());
+        debug!(
+            "Got meter: {:?} source={:?} pulses_per_unit={} unit={}",
+            name, source, pulses_per_unit, unit
+        );
+        meters.push(PulseMeter::new(name, source, pulses_per_unit, unit));
+    }
+    meters
+}
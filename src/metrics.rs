@@ -0,0 +1,246 @@
+use serde::Serialize;
+use simplelog::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::time::Duration;
+
+pub const METRICS_INTERVAL_SECS: f32 = 60.0; //secs between publishing device statistics
+pub const ERROR_RATE_WARN_PERCENT: u64 = 5; //warn when errors exceed this percentage of reads
+pub const ERROR_RATE_MIN_READS: u64 = 20; //minimum reads in the interval to compute error rate
+pub const LATENCY_BUCKETS_MS: [u64; 9] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500]; //histogram upper bounds
+
+/// Per-device 1-Wire bus statistics
+#[derive(Clone, Default, Serialize)]
+pub struct DeviceStats {
+    pub reads: u64,
+    pub read_errors: u64,
+    pub invalid_values: u64,
+    pub crc_errors: u64,
+    pub write_errors: u64,
+    pub reopens: u64,
+    pub read_timeouts: u64,
+    /// sum and maximum of the read times in microseconds
+    pub read_time_us: u64,
+    pub read_time_max_us: u64,
+}
+
+impl DeviceStats {
+    pub fn errors(&self) -> u64 {
+        self.read_errors
+            + self.invalid_values
+            + self.crc_errors
+            + self.write_errors
+            + self.read_timeouts
+    }
+
+    pub fn observe_read(&mut self, elapsed: Duration) {
+        let us = elapsed.as_micros() as u64;
+        self.read_time_us += us;
+        self.read_time_max_us = self.read_time_max_us.max(us);
+    }
+
+    /// Average read time in milliseconds
+    pub fn avg_read_ms(&self) -> f64 {
+        if self.reads == 0 {
+            return 0.0;
+        }
+        self.read_time_us as f64 / self.reads as f64 / 1000.0
+    }
+}
+
+#[derive(Default)]
+pub struct BusMetrics {
+    pub devices: HashMap<String, DeviceStats>,
+    pub dirty: bool,
+}
+
+impl BusMetrics {
+    /// Stores a new snapshot of device stats and warns when the error rate is rising
+    pub fn update(&mut self, name: String, stats: &DeviceStats) {
+        if let Some(previous) = self.devices.get(&name) {
+            let reads = stats.reads.saturating_sub(previous.reads);
+            let errors = stats.errors().saturating_sub(previous.errors());
+            if reads >= ERROR_RATE_MIN_READS && errors * 100 > reads * ERROR_RATE_WARN_PERCENT {
+                warn!(
+                    "{}: ⚡ high 1-wire error rate: {} errors in {} reads (read: {}, invalid: {}, crc: {}, write: {}, timeouts: {}, reopens: {})",
+                    name,
+                    errors,
+                    reads,
+                    stats.read_errors,
+                    stats.invalid_values,
+                    stats.crc_errors,
+                    stats.write_errors,
+                    stats.read_timeouts,
+                    stats.reopens,
+                );
+            }
+        }
+        self.devices.insert(name, stats.clone());
+        self.dirty = true;
+    }
+
+    /// Renders the stats in Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let metrics: [(&str, fn(&DeviceStats) -> u64); 7] = [
+            ("reads", |s| s.reads),
+            ("read_errors", |s| s.read_errors),
+            ("invalid_values", |s| s.invalid_values),
+            ("crc_errors", |s| s.crc_errors),
+            ("write_errors", |s| s.write_errors),
+            ("reopens", |s| s.reopens),
+            ("read_timeouts", |s| s.read_timeouts),
+        ];
+        for (metric, value) in metrics.iter() {
+            let _ = writeln!(out, "# TYPE hard_onewire_{}_total counter", metric);
+            for (device, stats) in self.devices.iter() {
+                let _ = writeln!(
+                    out,
+                    "hard_onewire_{}_total{{device=\"{}\"}} {}",
+                    metric,
+                    device,
+                    value(stats)
+                );
+            }
+        }
+        let _ = writeln!(out, "# TYPE hard_onewire_read_seconds summary");
+        for (device, stats) in self.devices.iter() {
+            let _ = writeln!(
+                out,
+                "hard_onewire_read_seconds_sum{{device=\"{}\"}} {}",
+                device,
+                stats.read_time_us as f64 / 1_000_000.0
+            );
+            let _ = writeln!(
+                out,
+                "hard_onewire_read_seconds_count{{device=\"{}\"}} {}",
+                device, stats.reads
+            );
+        }
+        let _ = writeln!(out, "# TYPE hard_onewire_read_max_seconds gauge");
+        for (device, stats) in self.devices.iter() {
+            let _ = writeln!(
+                out,
+                "hard_onewire_read_max_seconds{{device=\"{}\"}} {}",
+                device,
+                stats.read_time_max_us as f64 / 1_000_000.0
+            );
+        }
+        out
+    }
+}
+
+/// Histogram of the time from a sensor edge read to the output command completion
+#[derive(Clone, Default)]
+pub struct LatencyHistogram {
+    pub count: u64,
+    pub sum: Duration,
+    pub max: Duration,
+    pub buckets: [u64; LATENCY_BUCKETS_MS.len()],
+}
+
+impl LatencyHistogram {
+    pub fn observe(&mut self, elapsed: Duration) {
+        self.count += 1;
+        self.sum += elapsed;
+        if elapsed > self.max {
+            self.max = elapsed;
+        }
+        for (i, bound) in LATENCY_BUCKETS_MS.iter().enumerate() {
+            if elapsed <= Duration::from_millis(*bound) {
+                self.buckets[i] += 1;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct LatencyMetrics {
+    pub targets: HashMap<&'static str, LatencyHistogram>,
+    pub trace: bool,
+}
+
+impl LatencyMetrics {
+    /// Records a switching latency, `target` is the kind of output, eg. `relay` or `yeelight`
+    pub fn observe(&mut self, target: &'static str, name: &str, edge: Duration, elapsed: Duration) {
+        self.targets.entry(target).or_default().observe(elapsed);
+        if self.trace {
+            info!(
+                "{}: ⏱️ {} switched {:.1} ms after the edge (sensor read: {:.1} ms)",
+                name,
+                target,
+                elapsed.as_secs_f64() * 1000.0,
+                edge.as_secs_f64() * 1000.0,
+            );
+        } else {
+            debug!(
+                "{}: {} switching latency: {:?}, sensor read: {:?}",
+                name, target, elapsed, edge
+            );
+        }
+    }
+
+    /// Renders the histograms in Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# TYPE hard_switch_latency_seconds histogram");
+        for (target, histogram) in self.targets.iter() {
+            for (i, bound) in LATENCY_BUCKETS_MS.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "hard_switch_latency_seconds_bucket{{target=\"{}\",le=\"{}\"}} {}",
+                    target,
+                    *bound as f64 / 1000.0,
+                    histogram.buckets[i]
+                );
+            }
+            let _ = writeln!(
+                out,
+                "hard_switch_latency_seconds_bucket{{target=\"{}\",le=\"+Inf\"}} {}",
+                target, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "hard_switch_latency_seconds_sum{{target=\"{}\"}} {}",
+                target,
+                histogram.sum.as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "hard_switch_latency_seconds_count{{target=\"{}\"}} {}",
+                target, histogram.count
+            );
+        }
+        out
+    }
+}
+
+/// Error counters of the workers, by the error kind
+#[derive(Default)]
+pub struct WorkerErrors {
+    /// (worker, kind, recoverable) -> count
+    pub counts: BTreeMap<(String, &'static str, bool), u64>,
+}
+
+impl WorkerErrors {
+    pub fn observe(&mut self, worker: &str, kind: &'static str, recoverable: bool) {
+        *self
+            .counts
+            .entry((worker.to_string(), kind, recoverable))
+            .or_default() += 1;
+    }
+
+    /// Renders the counters in Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# TYPE hard_worker_errors_total counter");
+        for ((worker, kind, recoverable), count) in self.counts.iter() {
+            let _ = writeln!(
+                out,
+                "hard_worker_errors_total{{worker=\"{}\",kind=\"{}\",recoverable=\"{}\"}} {}",
+                worker, kind, recoverable, count
+            );
+        }
+        out
+    }
+}
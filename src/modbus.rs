@@ -0,0 +1,494 @@
+use crate::chaos::{self, Fault};
+use crate::influx::{Client, InfluxDbWriteable, Timestamp, Type, WriteQuery};
+use crate::mqtt::MqttEvent;
+use crate::queue::Sender;
+use io::ErrorKind;
+use simplelog::*;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::timeout;
+use tokio_modbus::client::Context;
+use tokio_modbus::prelude::*;
+use tokio_serial::SerialPortBuilderExt;
+
+pub const MODBUS_POLL_INTERVAL_SECS: f32 = 10.0; //default secs between polling
+pub const MODBUS_MIN_POLL_INTERVAL_SECS: f32 = 1.0;
+pub const MODBUS_ATTEMPTS_PER_BLOCK: u8 = 3; //max read attempts per register block
+pub const MODBUS_MAX_BLOCK_LEN: u16 = 64; //max registers read in a single request
+pub const MODBUS_READ_TIMEOUT_SECS: f32 = 5.0;
+pub const MODBUS_READ_LAG_SECS: f32 = 3.5; //warn when the device is responding slower
+pub const MODBUS_DEFAULT_BAUD_RATE: u32 = 9600;
+pub const MODBUS_DEFAULT_INFLUX_DATABASE: &str = "modbus";
+
+// Just a generic Result type to ease error handling for us. Errors in multithreaded
+// async contexts needs some extra restrictions
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Groups registers (sorted by address) into blocks which can be read at once
+pub fn group_register_blocks<T>(items: Vec<T>, span: impl Fn(&T) -> (u16, u16)) -> Vec<Vec<T>> {
+    let mut reg_block = vec![];
+    let mut all_blocks = vec![];
+    let mut start_addr = None;
+    for item in items {
+        let (reg_address, len) = span(&item);
+        match start_addr {
+            None => start_addr = Some(reg_address),
+            Some(start) => {
+                //in u32: the end of a register at the top of the address space doesn't fit u16
+                if reg_address as u32 + len as u32 - start as u32 > MODBUS_MAX_BLOCK_LEN as u32 {
+                    start_addr = Some(reg_address);
+                    all_blocks.push(reg_block);
+                    reg_block = vec![];
+                }
+            }
+        }
+        reg_block.push(item);
+    }
+    //add remainder
+    if !reg_block.is_empty() {
+        all_blocks.push(reg_block);
+    }
+    all_blocks
+}
+
+pub enum BlockRead {
+    Data(Vec<u16>),
+    Failed,
+    Disconnected,
+}
+
+/// Reads a register block with retries and a timeout for every attempt
+pub async fn read_register_block(
+    ctx: &mut Context,
+    name: &str,
+    input_registers: bool,
+    start_addr: u16,
+    len: u16,
+    max_attempts: u8,
+) -> BlockRead {
+    let mut attempts = 0;
+    while attempts < max_attempts {
+        attempts = attempts + 1;
+        debug!(
+            "-> obtaining register block start={:#x}, len={}, attempt={}",
+            start_addr, len, attempts
+        );
+        if chaos::inject(Fault::ModbusTimeout) {
+            warn!(
+                "<i>{}</i>: read timeout (attempt #{} of {}), register: <green><i>{:#x}+{}</>, error: <b>chaos: injected timeout</>",
+                name, attempts, max_attempts, start_addr, len
+            );
+            continue;
+        }
+        let retval = if input_registers {
+            ctx.read_input_registers(start_addr, len)
+        } else {
+            ctx.read_holding_registers(start_addr, len)
+        };
+        let start = Instant::now();
+        let read_res = match timeout(Duration::from_secs_f32(MODBUS_READ_TIMEOUT_SECS), retval)
+            .await
+        {
+            Ok(res) => res,
+            Err(e) => {
+                let msg = format!(
+                    "<i>{}</i>: read timeout (attempt #{} of {}), register: <green><i>{:#x}+{}</>, error: <b>{}</>",
+                    name, attempts, max_attempts, start_addr, len, e
+                );
+                if attempts == max_attempts {
+                    error!("{}", msg);
+                } else {
+                    warn!("{}", msg);
+                }
+                continue;
+            }
+        };
+        let read_time = start.elapsed();
+        match read_res {
+            Ok(data) => {
+                if read_time > Duration::from_secs_f32(MODBUS_READ_LAG_SECS) {
+                    warn!(
+                        "<i>{}</i>: device has lagged during read, register: <green><i>{:#x}+{}</>, read time: <b>{:?}</>",
+                        name, start_addr, len, read_time
+                    );
+                }
+                return BlockRead::Data(data);
+            }
+            Err(e) => {
+                let msg = format!(
+                    "<i>{}</i>: read error (attempt #{} of {}), register: <green><i>{:#x}+{}</>, error: <b>{}</>, read time: <b>{:?}</>",
+                    name, attempts, max_attempts, start_addr, len, e, read_time
+                );
+                match e.kind() {
+                    ErrorKind::BrokenPipe | ErrorKind::ConnectionReset => {
+                        error!("{}", msg);
+                        return BlockRead::Disconnected;
+                    }
+                    _ => {
+                        if attempts == max_attempts {
+                            error!("{}", msg);
+                        } else {
+                            warn!("{}", msg);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    BlockRead::Failed
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegisterType {
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl RegisterType {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim() {
+            "u16" => Some(RegisterType::U16),
+            "i16" => Some(RegisterType::I16),
+            "u32" => Some(RegisterType::U32),
+            "i32" => Some(RegisterType::I32),
+            "f32" => Some(RegisterType::F32),
+            _ => None,
+        }
+    }
+
+    fn len(&self) -> u16 {
+        match self {
+            RegisterType::U16 | RegisterType::I16 => 1,
+            _ => 2,
+        }
+    }
+
+    /// Decodes big-endian register words
+    fn decode(&self, data: &[u16]) -> f64 {
+        let dword = || ((data[0] as u32) << 16) | data[1] as u32;
+        match self {
+            RegisterType::U16 => data[0] as f64,
+            RegisterType::I16 => data[0] as i16 as f64,
+            RegisterType::U32 => dword() as f64,
+            RegisterType::I32 => dword() as i32 as f64,
+            RegisterType::F32 => f32::from_bits(dword()) as f64,
+        }
+    }
+}
+
+/// Single register definition from the config
+#[derive(Clone, Debug)]
+pub struct Register {
+    pub name: String,
+    pub address: u16,
+    pub kind: RegisterType,
+    pub gain: f64,
+    pub unit: Option<String>,
+    pub measurement: Option<String>,
+}
+
+impl Register {
+    /// Parses the register definition: `ADDRESS:TYPE[:GAIN[:UNIT[:MEASUREMENT]]]`,
+    /// the values are divided by the gain, so it has to be a positive number
+    pub fn parse(name: &str, definition: &str) -> Option<Self> {
+        let v: Vec<&str> = definition.split(':').map(|x| x.trim()).collect();
+        let address = match v.get(0)?.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16).ok()?,
+            None => v.get(0)?.parse().ok()?,
+        };
+        let kind = RegisterType::parse(v.get(1)?)?;
+        if address as u32 + kind.len() as u32 > u16::MAX as u32 + 1 {
+            return None;
+        }
+        let gain = match v.get(2).filter(|x| !x.is_empty()) {
+            Some(gain) => gain
+                .parse::<f64>()
+                .ok()
+                .filter(|g| g.is_finite() && *g > 0.0)?,
+            None => 1.0,
+        };
+        Some(Register {
+            name: name.to_string(),
+            address,
+            kind,
+            gain,
+            unit: v.get(3).filter(|x| !x.is_empty()).map(|x| x.to_string()),
+            measurement: v.get(4).filter(|x| !x.is_empty()).map(|x| x.to_string()),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Transport {
+    Tcp(String),
+    Rtu { device: String, baud_rate: u32 },
+}
+
+/// Generic Modbus-TCP/RTU device polled using the register table from the config
+#[derive(Clone)]
+pub struct ModbusDevice {
+    pub name: String,
+    pub transport: Transport,
+    pub slave: u8,
+    pub input_registers: bool,
+    pub registers: Vec<Register>,
+    pub poll_interval: Duration,
+    pub influxdb_url: Option<String>,
+    pub influx_database: String,
+    pub mqtt_transmitter: Sender<MqttEvent>,
+}
+
+impl ModbusDevice {
+    /// Name from the config section, without the `modbus:` prefix
+    fn device_name(&self) -> &str {
+        self.name.strip_prefix("modbus:").unwrap_or(&self.name)
+    }
+
+    async fn connect(&self) -> Result<Context> {
+        let slave = Slave(self.slave);
+        let ctx = match &self.transport {
+            Transport::Tcp(host_port) => {
+                let socket_addr = host_port.parse()?;
+                timeout(
+                    Duration::from_secs(5),
+                    tcp::connect_slave(socket_addr, slave),
+                )
+                .await??
+            }
+            Transport::Rtu { device, baud_rate } => {
+                let port = tokio_serial::new(device, *baud_rate).open_native_async()?;
+                rtu::connect_slave(port, slave).await?
+            }
+        };
+        Ok(ctx)
+    }
+
+    /// Reads all registers, returns the values or `None` when the device has disconnected
+    async fn read_registers(&self, ctx: &mut Context) -> Option<Vec<(Register, f64)>> {
+        let mut registers = self.registers.clone();
+        registers.sort_by(|a, b| a.address.cmp(&b.address));
+        let mut values = vec![];
+        for reg_block in group_register_blocks(registers, |r| (r.address, r.kind.len())) {
+            let last = reg_block.last().unwrap();
+            let start_addr = reg_block[0].address;
+            let len = (last.address as u32 + last.kind.len() as u32 - start_addr as u32) as u16;
+            match read_register_block(
+                ctx,
+                &self.name,
+                self.input_registers,
+                start_addr,
+                len,
+                MODBUS_ATTEMPTS_PER_BLOCK,
+            )
+            .await
+            {
+                BlockRead::Data(data) => {
+                    for reg in reg_block {
+                        let offset = (reg.address - start_addr) as usize;
+                        let words = match data.get(offset..offset + reg.kind.len() as usize) {
+                            Some(words) => words,
+                            None => {
+                                error!(
+                                    "<i>{}</>: short reply: {} of {} registers, missing <b>{}</>",
+                                    self.name,
+                                    data.len(),
+                                    len,
+                                    reg.name
+                                );
+                                continue;
+                            }
+                        };
+                        let value = reg.kind.decode(words) / reg.gain;
+                        debug!(
+                            "{}: {} = {} {}",
+                            self.name,
+                            reg.name,
+                            value,
+                            reg.unit.clone().unwrap_or_default()
+                        );
+                        values.push((reg, value));
+                    }
+                }
+                BlockRead::Failed => {}
+                BlockRead::Disconnected => return None,
+            }
+        }
+        Some(values)
+    }
+
+    async fn save_to_influxdb(&self, client: &Client, values: &Vec<(Register, f64)>) {
+        let since_the_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis();
+
+        //every measurement is a single point with all its fields
+        let mut queries: HashMap<&str, WriteQuery> = HashMap::new();
+        for (reg, value) in values {
+            let measurement = reg.measurement.as_deref().unwrap_or(&self.name);
+            let query = queries.remove(measurement).unwrap_or_else(|| {
+                Timestamp::Milliseconds(since_the_epoch).into_query(measurement)
+            });
+            queries.insert(measurement, query.add_field(&reg.name, Type::Float(*value)));
+        }
+        let queries: Vec<WriteQuery> = queries.into_iter().map(|(_, query)| query).collect();
+        match client.write(&queries).await {
+            Ok(msg) => {
+                debug!("{}: influxdb write success: {:?}", self.name, msg);
+            }
+            Err(e) => {
+                error!("<i>{}</>: influxdb write error: <b>{:?}</>", self.name, e);
+            }
+        }
+    }
+
+    pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
+        info!(
+            "<i>{}</>: Starting task, {} registers via {:?}",
+            self.name,
+            self.registers.len(),
+            self.transport
+        );
+        let client = self
+            .influxdb_url
+            .as_ref()
+            .map(|url| Client::new(url, &self.influx_database));
+
+        'connection: loop {
+            if worker_cancel_flag.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let mut ctx = match self.connect().await {
+                Ok(ctx) => {
+                    info!("<i>{}</>: connected successfully", self.name);
+                    ctx
+                }
+                Err(e) => {
+                    error!("<i>{}</>: connection error: <b>{}</>", self.name, e);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+
+            let mut poll_interval: Option<Instant> = None;
+            loop {
+                if worker_cancel_flag.load(Ordering::SeqCst) {
+                    debug!("{}: Got terminate signal from main", self.name);
+                    break 'connection;
+                }
+
+                if poll_interval.map_or(true, |t| t.elapsed() > self.poll_interval) {
+                    poll_interval = Some(Instant::now());
+                    let values = match self.read_registers(&mut ctx).await {
+                        Some(values) => values,
+                        None => {
+                            tokio::time::sleep(Duration::from_secs(2)).await;
+                            continue 'connection;
+                        }
+                    };
+                    for (reg, value) in &values {
+                        let _ = self.mqtt_transmitter.send(MqttEvent::new(
+                            format!("modbus/{}/{}", self.device_name(), reg.name),
+                            value,
+                            false,
+                        ));
+                    }
+                    if let Some(ref c) = client {
+                        self.save_to_influxdb(c, &values).await;
+                    }
+                }
+
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+
+        info!("{}: task stopped", self.name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_definitions_are_parsed() {
+        let reg = Register::parse("power", "0x10:i32:10:W:energy").unwrap();
+        assert_eq!(reg.address, 0x10);
+        assert_eq!(reg.kind, RegisterType::I32);
+        assert_eq!(reg.gain, 10.0);
+        assert_eq!(reg.unit.as_deref(), Some("W"));
+        assert_eq!(reg.measurement.as_deref(), Some("energy"));
+
+        let reg = Register::parse("voltage", " 100 : u16 ").unwrap();
+        assert_eq!(reg.address, 100);
+        assert_eq!(reg.kind, RegisterType::U16);
+        assert_eq!(reg.gain, 1.0);
+        assert_eq!(reg.unit, None);
+        assert_eq!(reg.measurement, None);
+
+        let reg = Register::parse("current", "5:u16::A").unwrap();
+        assert_eq!(reg.gain, 1.0);
+        assert_eq!(reg.unit.as_deref(), Some("A"));
+    }
+
+    #[test]
+    fn invalid_register_definitions_are_rejected() {
+        assert!(Register::parse("r", "").is_none());
+        assert!(Register::parse("r", "10").is_none());
+        assert!(Register::parse("r", "10:u64").is_none());
+        assert!(Register::parse("r", "0xzz:u16").is_none());
+        assert!(Register::parse("r", "70000:u16").is_none());
+        //a 32-bit register past the end of the address space
+        assert!(Register::parse("r", "0xffff:u32").is_none());
+        assert!(Register::parse("r", "0xffff:u16").is_some());
+        //the value is divided by the gain
+        assert!(Register::parse("r", "10:u16:0").is_none());
+        assert!(Register::parse("r", "10:u16:-10").is_none());
+        assert!(Register::parse("r", "10:u16:NaN").is_none());
+        assert!(Register::parse("r", "10:u16:inf").is_none());
+        assert!(Register::parse("r", "10:u16:x").is_none());
+    }
+
+    #[test]
+    fn registers_are_decoded_big_endian() {
+        assert_eq!(RegisterType::U16.decode(&[0xfffe]), 65534.0);
+        assert_eq!(RegisterType::I16.decode(&[0xfffe]), -2.0);
+        assert_eq!(RegisterType::U32.decode(&[0x0001, 0x0002]), 65538.0);
+        assert_eq!(RegisterType::I32.decode(&[0xffff, 0xfffe]), -2.0);
+        assert_eq!(RegisterType::F32.decode(&[0x4148, 0x0000]), 12.5);
+    }
+
+    #[test]
+    fn registers_are_grouped_into_blocks() {
+        let spans = vec![(0u16, 1u16), (1, 2), (10, 2), (63, 1), (64, 1), (100, 2)];
+        let blocks = group_register_blocks(spans, |s| *s);
+        assert_eq!(
+            blocks,
+            vec![
+                vec![(0, 1), (1, 2), (10, 2), (63, 1)],
+                vec![(64, 1), (100, 2)],
+            ]
+        );
+        assert!(group_register_blocks(vec![], |s: &(u16, u16)| *s).is_empty());
+    }
+
+    #[test]
+    fn blocks_at_the_end_of_the_address_space_dont_overflow() {
+        let spans = vec![(0xffbfu16, 2u16), (0xfffe, 2)];
+        let blocks = group_register_blocks(spans, |s| *s);
+        assert_eq!(blocks, vec![vec![(0xffbf, 2)], vec![(0xfffe, 2)]]);
+
+        let spans = vec![(0xffc0u16, 2u16), (0xfffe, 2)];
+        let blocks = group_register_blocks(spans, |s| *s);
+        assert_eq!(blocks, vec![vec![(0xffc0, 2), (0xfffe, 2)]]);
+    }
+}
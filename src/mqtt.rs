@@ -0,0 +1,189 @@
+use crate::onewire::{OneWireTask, TaskCommand};
+use crate::queue::{Receiver, Sender};
+use crate::shutter::ShutterAction;
+use crate::systemd;
+use rumqttc::{AsyncClient, Event, MqttOptions, Outgoing, Packet, QoS};
+use simplelog::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const MQTT_DEFAULT_PORT: u16 = 1883;
+pub const MQTT_DEFAULT_TOPIC_PREFIX: &str = "hard";
+pub const MQTT_KEEP_ALIVE_SECS: u64 = 30;
+pub const MQTT_RECONNECT_DELAY_SECS: f32 = 10.0; //delay after a broker connection error
+pub const MQTT_QUEUE_CAPACITY: usize = 100; //outgoing requests buffered by the client
+pub const MQTT_SHUTDOWN_FLUSH_SECS: f32 = 2.0; //max time for sending the queued events on exit
+
+// Just a generic Result type to ease error handling for us. Errors in multithreaded
+// async contexts needs some extra restrictions
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// State change to be published, the topic is relative to the configured prefix
+#[derive(Clone, Debug)]
+pub struct MqttEvent {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+impl MqttEvent {
+    pub fn new<T: Into<String>, P: ToString>(topic: T, payload: P, retain: bool) -> Self {
+        MqttEvent {
+            topic: topic.into(),
+            payload: payload.to_string(),
+            retain,
+        }
+    }
+}
+
+pub struct Mqtt {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub topic_prefix: String,
+    pub receiver: Receiver<MqttEvent>,
+    pub ow_transmitter: Sender<OneWireTask>,
+}
+
+impl Mqtt {
+    /// Parses `<prefix>/cmd/<relay|yeelight|plug|tag>/<id>` with payload `on[:secs]`, `off` or `toggle`,
+    /// scenes are triggered by `<prefix>/cmd/scene/<name>` (with any of the payloads),
+    /// shutters are moved by `<prefix>/cmd/shutter/<name>` with `up`, `down`, `stop` or the position
+    fn parse_command(&self, topic: &str, payload: &str) -> Option<OneWireTask> {
+        let path = topic.strip_prefix(&format!("{}/cmd/", self.topic_prefix))?;
+        let mut target = path.splitn(2, '/');
+        let kind = target.next()?;
+        let id = target.next()?;
+        if kind == "shutter" {
+            return Some(ShutterAction::parse(payload)?.task(id));
+        }
+
+        let mut args = payload.trim().splitn(2, ':');
+        let command = match args.next()? {
+            "on" | "prolong" => TaskCommand::TurnOnProlong,
+            "off" => TaskCommand::TurnOff,
+            "toggle" => TaskCommand::Toggle,
+            _ => return None,
+        };
+        let duration = args
+            .next()
+            .and_then(|x| x.parse().ok())
+            .map(Duration::from_secs);
+
+        let mut task = OneWireTask {
+            command,
+            id_relay: None,
+            tag_group: None,
+            id_yeelight: None,
+            id_plug: None,
+            duration,
+        };
+        match kind {
+            "relay" => task.id_relay = Some(id.parse().ok()?),
+            "yeelight" => task.id_yeelight = Some(id.parse().ok()?),
+            "plug" => task.id_plug = Some(id.parse().ok()?),
+            "tag" => task.tag_group = Some(id.to_string()),
+            "scene" => task.command = TaskCommand::Scene(id.to_string()),
+            _ => return None,
+        }
+        Some(task)
+    }
+
+    /// Queues the event in the client, it is sent out by the event loop
+    fn publish(&self, client: &AsyncClient, event: MqttEvent) {
+        let topic = format!("{}/{}", self.topic_prefix, event.topic);
+        if let Err(e) = client.try_publish(&topic, QoS::AtLeastOnce, event.retain, event.payload) {
+            error!("{}: publish error for {}: {:?}", self.name, topic, e);
+        }
+    }
+
+    fn publish_pending(&self, client: &AsyncClient) {
+        while let Ok(event) = self.receiver.try_recv() {
+            self.publish(client, event);
+        }
+    }
+
+    fn handle_command(&self, topic: &str, payload: &str) {
+        match self.parse_command(topic, payload) {
+            Some(task) => {
+                info!("{}: received command: {} = {}", self.name, topic, payload);
+                let _ = self.ow_transmitter.send(task);
+            }
+            None => {
+                warn!("{}: invalid command: {} = {:?}", self.name, topic, payload);
+            }
+        }
+    }
+
+    pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
+        info!(
+            "{}: Starting task, broker: <b>{}:{}</>, topic prefix: <b>{}</>",
+            self.name, self.host, self.port, self.topic_prefix
+        );
+        let mut options = MqttOptions::new(&self.client_id, &self.host, self.port);
+        options.set_keep_alive(Duration::from_secs(MQTT_KEEP_ALIVE_SECS));
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            options.set_credentials(username, password);
+        }
+        let (client, mut eventloop) = AsyncClient::new(options, MQTT_QUEUE_CAPACITY);
+        let command_topic = format!("{}/cmd/#", self.topic_prefix);
+
+        loop {
+            if worker_cancel_flag.load(Ordering::SeqCst) {
+                debug!("{}: Got terminate signal from main", self.name);
+                //send out the remaining events and disconnect cleanly
+                self.publish_pending(&client);
+                let _ = client.try_disconnect();
+                let _ = tokio::time::timeout(
+                    Duration::from_secs_f32(MQTT_SHUTDOWN_FLUSH_SECS),
+                    async {
+                        while let Ok(event) = eventloop.poll().await {
+                            if let Event::Outgoing(Outgoing::Disconnect) = event {
+                                break;
+                            }
+                        }
+                    },
+                )
+                .await;
+                break;
+            }
+            systemd::heartbeat(&self.name);
+
+            //wait for an event to publish or for the connection to make progress,
+            //the timer is only for checking the terminate flag
+            let polled = tokio::select! {
+                Some(event) = self.receiver.recv() => {
+                    self.publish(&client, event);
+                    continue;
+                }
+                polled = eventloop.poll() => polled,
+                _ = tokio::time::sleep(Duration::from_millis(500)) => continue,
+            };
+            match polled {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("{}: connected to broker", self.name);
+                    //subscriptions are lost on reconnect
+                    if let Err(e) = client.try_subscribe(&command_topic, QoS::AtLeastOnce) {
+                        error!("{}: subscribe error: {:?}", self.name, e);
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(msg))) => {
+                    self.handle_command(&msg.topic, &String::from_utf8_lossy(&msg.payload));
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("{}: connection error: {}", self.name, e);
+                    tokio::time::sleep(Duration::from_secs_f32(MQTT_RECONNECT_DELAY_SECS)).await;
+                }
+            }
+        }
+
+        systemd::heartbeat_stop(&self.name);
+        info!("{}: task stopped", self.name);
+        Ok(())
+    }
+}
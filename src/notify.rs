@@ -0,0 +1,389 @@
+use crate::queue::Receiver;
+use crate::systemd;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use simplelog::*;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const NOTIFY_QUEUE_CAPACITY: usize = 64;
+pub const NOTIFY_HTTP_TIMEOUT_SECS: u64 = 10;
+pub const NOTIFY_DEFAULT_RATE_LIMIT_SECS: f32 = 900.0; //the same source/title is repeated at most this often
+pub const NOTIFY_DEFAULT_MAX_PER_HOUR: u32 = 20; //non-critical notifications sent per hour
+pub const NOTIFY_SMTP_DEFAULT_PORT: u16 = 587;
+const TELEGRAM_API_URL: &str = "https://api.telegram.org";
+const PUSHOVER_API_URL: &str = "https://api.pushover.net/1/messages.json";
+
+// Just a generic Result type to ease error handling for us. Errors in multithreaded
+// async contexts needs some extra restrictions
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn parse(severity: &str) -> Option<Severity> {
+        match severity.trim().to_lowercase().as_str() {
+            "info" => Some(Severity::Info),
+            "warning" => Some(Severity::Warning),
+            "critical" => Some(Severity::Critical),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+/// Message for a human, sent by any worker over the notify queue
+#[derive(Clone, Debug, Serialize)]
+pub struct Notification {
+    pub source: String,
+    pub severity: Severity,
+    pub title: String,
+    pub message: String,
+}
+
+impl Notification {
+    pub fn new<S: Into<String>, T: Into<String>, M: Into<String>>(
+        source: S,
+        severity: Severity,
+        title: T,
+        message: M,
+    ) -> Self {
+        Notification {
+            source: source.into(),
+            severity,
+            title: title.into(),
+            message: message.into(),
+        }
+    }
+
+    fn text(&self) -> String {
+        format!("{}: {}\n{}", self.source, self.title, self.message)
+    }
+}
+
+/// Removes the log markup tags (`<b>`, `<red>`, `</>`, ...) from a message
+pub fn plain(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Delivery method of a `[notify:<name>]` section, selected by `kind`
+#[derive(Clone)]
+pub enum Backend {
+    Telegram {
+        token: String,
+        chat_id: String,
+    },
+    Pushover {
+        token: String,
+        user: String,
+    },
+    Smtp {
+        host: String,
+        port: u16,
+        username: Option<String>,
+        password: Option<String>,
+        from: String,
+        to: Vec<String>,
+        starttls: bool,
+    },
+    Webhook {
+        url: String,
+    },
+}
+
+#[derive(Clone)]
+pub struct NotifyTarget {
+    pub name: String,
+    pub backend: Backend,
+    pub min_severity: Severity,
+}
+
+impl NotifyTarget {
+    async fn send(&self, client: &reqwest::Client, notification: &Notification) -> Result<()> {
+        match &self.backend {
+            Backend::Telegram { token, chat_id } => {
+                client
+                    .post(&format!("{}/bot{}/sendMessage", TELEGRAM_API_URL, token))
+                    .form(&[
+                        ("chat_id", chat_id.as_str()),
+                        ("text", notification.text().as_str()),
+                    ])
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Backend::Pushover { token, user } => {
+                let priority = match notification.severity {
+                    Severity::Info => "-1",
+                    Severity::Warning => "0",
+                    Severity::Critical => "1",
+                };
+                let title = format!("{}: {}", notification.source, notification.title);
+                client
+                    .post(PUSHOVER_API_URL)
+                    .form(&[
+                        ("token", token.as_str()),
+                        ("user", user.as_str()),
+                        ("title", title.as_str()),
+                        ("message", notification.message.as_str()),
+                        ("priority", priority),
+                    ])
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Backend::Smtp {
+                host,
+                port,
+                username,
+                password,
+                from,
+                to,
+                starttls,
+            } => {
+                let mut builder =
+                    Message::builder()
+                        .from(from.parse::<Mailbox>()?)
+                        .subject(format!(
+                            "[hard] {}: {}",
+                            notification.source, notification.title
+                        ));
+                for address in to {
+                    builder = builder.to(address.parse::<Mailbox>()?);
+                }
+                let email = builder.body(notification.message.clone())?;
+                let mut transport = if *starttls {
+                    AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?
+                } else {
+                    AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+                }
+                .port(*port)
+                .timeout(Some(Duration::from_secs(NOTIFY_HTTP_TIMEOUT_SECS)));
+                if let (Some(username), Some(password)) = (username, password) {
+                    transport =
+                        transport.credentials(Credentials::new(username.clone(), password.clone()));
+                }
+                transport.build().send(email).await?;
+            }
+            Backend::Webhook { url } => {
+                client
+                    .post(url)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(serde_json::to_string(notification)?)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keeps a flapping source from flooding the phone: the same source/title
+/// is repeated after `interval` only, and besides the critical ones at most
+/// `max_per_hour` notifications are sent
+pub struct RateLimit {
+    pub interval: Duration,
+    pub max_per_hour: u32,
+    last_sent: HashMap<(String, String), Instant>,
+    sent: VecDeque<Instant>,
+}
+
+impl RateLimit {
+    pub fn new(interval: Duration, max_per_hour: u32) -> Self {
+        RateLimit {
+            interval,
+            max_per_hour,
+            last_sent: HashMap::new(),
+            sent: VecDeque::new(),
+        }
+    }
+
+    pub fn allow(&mut self, notification: &Notification, now: Instant) -> bool {
+        let key = (notification.source.clone(), notification.title.clone());
+        if let Some(last) = self.last_sent.get(&key) {
+            if now.duration_since(*last) < self.interval {
+                return false;
+            }
+        }
+        while let Some(oldest) = self.sent.front() {
+            if now.duration_since(*oldest) >= Duration::from_secs(3600) {
+                self.sent.pop_front();
+            } else {
+                break;
+            }
+        }
+        if notification.severity < Severity::Critical
+            && self.sent.len() >= self.max_per_hour as usize
+        {
+            return false;
+        }
+        self.last_sent.insert(key, now);
+        self.sent.push_back(now);
+        true
+    }
+}
+
+pub struct Notifier {
+    pub name: String,
+    pub receiver: Receiver<Notification>,
+    pub targets: Vec<NotifyTarget>,
+    pub rate_limit: RateLimit,
+}
+
+impl Notifier {
+    async fn deliver(&mut self, client: &reqwest::Client, notification: Notification) {
+        if !self.rate_limit.allow(&notification, Instant::now()) {
+            debug!(
+                "{}: rate limited: {}: {}",
+                self.name, notification.source, notification.title
+            );
+            return;
+        }
+        for target in &self.targets {
+            if notification.severity < target.min_severity {
+                continue;
+            }
+            match target.send(client, &notification).await {
+                Ok(()) => debug!(
+                    "{}: {}: sent {} notification: {}: {}",
+                    self.name,
+                    target.name,
+                    notification.severity.as_str(),
+                    notification.source,
+                    notification.title
+                ),
+                Err(e) => error!("{}: {}: sending error: {}", self.name, target.name, e),
+            }
+        }
+    }
+
+    pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
+        info!(
+            "{}: Starting task, targets: {:?}",
+            self.name,
+            self.targets.iter().map(|t| &t.name).collect::<Vec<_>>()
+        );
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(NOTIFY_HTTP_TIMEOUT_SECS))
+            .build()?;
+
+        loop {
+            if worker_cancel_flag.load(Ordering::SeqCst) {
+                debug!("{}: Got terminate signal from main", self.name);
+                //the queued notifications are sent before exiting
+                while let Ok(notification) = self.receiver.try_recv() {
+                    self.deliver(&client, notification).await;
+                }
+                break;
+            }
+            systemd::heartbeat(&self.name);
+
+            let notification = tokio::select! {
+                notification = self.receiver.recv() => notification,
+                _ = tokio::time::sleep(Duration::from_millis(500)) => None,
+            };
+            if let Some(notification) = notification {
+                self.deliver(&client, notification).await;
+            }
+        }
+
+        systemd::heartbeat_stop(&self.name);
+        info!("{}: task stopped", self.name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notification(source: &str, severity: Severity, title: &str) -> Notification {
+        Notification::new(source, severity, title, "details")
+    }
+
+    #[test]
+    fn repeats_are_limited_per_source_and_title() {
+        let mut limit = RateLimit::new(Duration::from_secs(900), 20);
+        let start = Instant::now();
+        let fault = notification("skymax", Severity::Warning, "inverter fault");
+        assert!(limit.allow(&fault, start));
+        assert!(!limit.allow(&fault, start + Duration::from_secs(899)));
+        //other title or source
+        let other = notification("skymax", Severity::Warning, "battery low");
+        assert!(limit.allow(&other, start + Duration::from_secs(1)));
+        let other = notification("dsmr", Severity::Warning, "inverter fault");
+        assert!(limit.allow(&other, start + Duration::from_secs(1)));
+        //the interval is applied to the critical ones too
+        let critical = notification("skymax", Severity::Critical, "inverter fault");
+        assert!(!limit.allow(&critical, start + Duration::from_secs(2)));
+        assert!(limit.allow(&fault, start + Duration::from_secs(900)));
+    }
+
+    #[test]
+    fn hourly_cap() {
+        let mut limit = RateLimit::new(Duration::from_secs(900), 3);
+        let start = Instant::now();
+        for i in 0..3 {
+            let n = notification("gate", Severity::Info, &format!("opened {}", i));
+            assert!(limit.allow(&n, start + Duration::from_secs(i)));
+        }
+        let n = notification("gate", Severity::Warning, "left open");
+        assert!(!limit.allow(&n, start + Duration::from_secs(10)));
+        //critical ones are not capped
+        let n = notification("gate", Severity::Critical, "forced");
+        assert!(limit.allow(&n, start + Duration::from_secs(10)));
+        //the window slides, the critical one counts too
+        let n = notification("gate", Severity::Warning, "left open");
+        assert!(!limit.allow(&n, start + Duration::from_secs(3600)));
+        assert!(limit.allow(&n, start + Duration::from_secs(3601)));
+    }
+
+    #[test]
+    fn zero_per_hour_sends_only_critical() {
+        let mut limit = RateLimit::new(Duration::from_secs(0), 0);
+        let now = Instant::now();
+        assert!(!limit.allow(&notification("ups", Severity::Warning, "on battery"), now));
+        assert!(limit.allow(&notification("ups", Severity::Critical, "shutdown"), now));
+        //no interval: repeated right away
+        assert!(limit.allow(&notification("ups", Severity::Critical, "shutdown"), now));
+    }
+
+    #[test]
+    fn severity_parse() {
+        assert_eq!(Severity::parse("info"), Some(Severity::Info));
+        assert_eq!(Severity::parse(" Warning "), Some(Severity::Warning));
+        assert_eq!(Severity::parse("CRITICAL"), Some(Severity::Critical));
+        for malformed in &["", "warn", "error", "critical!"] {
+            assert_eq!(Severity::parse(malformed), None);
+        }
+    }
+}
@@ -1,28 +1,57 @@
+use crate::adaptive_hold::AdaptiveHold;
+use crate::alarm::{Alarm, ALARM_RFID_TAG};
+use crate::automation::{self, Action, DeviceState, IgnoreReason, ProlongKind, Trigger};
+use crate::bus_watchdog::BusWatchdog;
+use crate::chaos::{self, Fault};
+use crate::circulation::CirculationPump;
+use crate::config::Config;
 use crate::database::{CommandCode, DbTask};
+use crate::energy::EnergyCosts;
 use crate::ethlcd::{BeepMethod, EthLcd};
+use crate::events::{self, Event, EventSender};
+use crate::gate::{Gate, GateAction, GATE_TAG_PREFIX};
+use crate::gesture::{Gesture, GestureDetector};
+use crate::governor::CommandGovernor;
+use crate::gpio::{GpioBoard, GpioFile, FAMILY_CODE_GPIO};
+use crate::heating::{OpenWindows, WINDOW_TAG_PREFIX};
 use crate::lcdproc::{LcdTask, LcdTaskCommand};
-use crate::rfid::RfidTag;
+use crate::mailbox::{Mailbox, MAILBOX_DOOR_TAG, MAILBOX_TAG};
+use crate::metrics::{BusMetrics, DeviceStats, LatencyMetrics, METRICS_INTERVAL_SECS};
+use crate::mqtt::MqttEvent;
+use crate::notify::Notification;
+use crate::ontime::DeviceKind;
+use crate::owserver::{get_owfs_device_name, OwFile};
+use crate::queue::Receiver;
+use crate::queue::Sender;
+use crate::rfid::{PendingPins, PinLockout, RfidScan, RfidTag};
+use crate::scene::{self, Scene};
+use crate::schedule::{
+    AllNight, CronJob, RelaySchedule, SunTimes, CRON_CHECK_INTERVAL_SECS,
+    SCHEDULE_CHECK_INTERVAL_SECS,
+};
+use crate::shutter::{Shutter, ShutterAction, ShutterRule};
+use crate::simulation::{self, SimFile};
+use crate::smart_plug::{PlugKind, SmartPlug};
+use crate::systemd;
+use crate::virtual_sensor::{SensorValues, VirtualSensor, VIRTUAL_SENSOR_CHECK_INTERVAL_SECS};
+use crate::worker_error::{ErrorReporter, WorkerError};
+use crate::yeelight::YeelightRequest;
+use chrono::{Local, NaiveDate};
 use humantime::format_duration;
-use ini::Ini;
-use serde::ser::SerializeSeq;
-use serde::{Deserialize, Serialize, Serializer};
+use serde::Serialize;
 use simplelog::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
-use std::io::BufReader;
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::net::TcpStream;
-use std::ops::Add;
 use std::path::Path;
 use std::process::Command;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::Receiver;
-use std::sync::mpsc::Sender;
-use std::sync::{Arc, RwLock};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::thread;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
 
 //family codes for devices
 pub const FAMILY_CODE_DS2413: u8 = 0x3a;
@@ -41,34 +70,37 @@ pub const MIN_TOGGLE_DELAY_SECS: f32 = 1.0; //1sec flip-flop protection: minimum
 pub const ENTRY_LIGHT_PROLONG_SECS: f32 = 600.0; //10min prolonging for entry lights
 
 pub static W1_ROOT_PATH: &str = "/sys/bus/w1/devices";
-
-//yeelight consts
-pub const YEELIGHT_TCP_PORT: u16 = 55443;
-static YEELIGHT_METHOD_SET_POWER: &str = "set_power"; //method value name for powering on/off
-static YEELIGHT_EFFECT: &str = "smooth"; //default effect for turning on/off
-pub const YEELIGHT_DURATION_MS: u32 = 500; //duration of above effect
+#[cfg(test)]
+pub static W1_ROOT_OVERRIDE: RwLock<Option<String>> = RwLock::new(None);
 
 pub const DAYLIGHT_SUN_DEGREE: f64 = 3.0; //sun elevation for day/night switching
 pub const SUN_POS_CHECK_INTERVAL_SECS: f32 = 60.0; //secs between calculating sun position
+pub static SUN_AZIMUTH_VALUE_NAME: &str = "sun_azimuth"; //variable names for the rules
+pub static SUN_ELEVATION_VALUE_NAME: &str = "sun_elevation";
+pub const SENSOR_BOARD_FAILURE_SECS: f32 = 30.0; //no successful read for this time marks board degraded
+pub const SENSOR_BOARD_REOPEN_SECS: f32 = 60.0; //secs between re-opening of degraded board file
+pub const RELAY_VERIFY_INTERVAL_SECS: f32 = 60.0; //secs between relay output latch verification
+pub const ONEWIRE_LOOP_INTERVAL_MS: u64 = 10; //default time between bus scans when no task is waiting
+pub const ONEWIRE_MIN_LOOP_INTERVAL_MS: u64 = 1;
+pub const SENSOR_READ_TIMEOUT_MS: u64 = 500; //a slower board is read in the background, not delaying the others
+pub const SENSOR_MIN_READ_TIMEOUT_MS: u64 = 10;
+pub static INVERT_STATE_TAG: &str = "invert_state"; //sensor tag: active on low input (also as a tag modifier)
+pub static NORMALLY_CLOSED_TAG: &str = "nc"; //sensor tag: normally-closed contact, same as invert_state
 
-#[derive(Debug, PartialEq)]
-pub enum ProlongKind {
-    PIR,
-    Remote,
-    Switch,
-    AutoOff,
-    DayNight,
-}
 pub enum Operation {
     On,
     Off,
     Toggle,
 }
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum TaskCommand {
     TurnOnProlong,
     TurnOnProlongNight,
     TurnOff,
+    Toggle,
+    Scene(String),
+    Shutter(String, ShutterAction),
+    Gate(String, GateAction),
 }
 #[derive(Clone)]
 pub struct OneWireTask {
@@ -76,13 +108,88 @@ pub struct OneWireTask {
     pub id_relay: Option<i32>,
     pub tag_group: Option<String>,
     pub id_yeelight: Option<i32>,
+    pub id_plug: Option<i32>,
     pub duration: Option<Duration>,
 }
 
+/// Root of the w1 sysfs tree, replaced by a temporary fake tree in the tests
+pub fn w1_root() -> String {
+    #[cfg(test)]
+    {
+        if let Some(root) = W1_ROOT_OVERRIDE.read().unwrap().clone() {
+            return root;
+        }
+    }
+    W1_ROOT_PATH.to_string()
+}
+
 pub fn get_w1_device_name(family_code: u8, address: u64) -> String {
     format!("{:02x}-{:012x}", family_code, address)
 }
 
+//GPIO lines behind the FAMILY_CODE_GPIO board address, if configured
+fn gpio_board(gpio_boards: &Vec<GpioBoard>, family_code: u8, address: u64) -> Option<GpioBoard> {
+    if family_code != FAMILY_CODE_GPIO {
+        return None;
+    }
+    let board = gpio_boards.iter().find(|b| b.address == address).cloned();
+    if board.is_none() {
+        error!("gpio: no [gpio:<name>] section with address={}", address);
+    }
+    board
+}
+
+/// PIO state/output of a board: w1 sysfs file, the same value through owserver
+/// or the GPIO lines of a `[gpio:<name>]` board
+pub enum W1File {
+    Sysfs(File),
+    OwServer(OwFile),
+    Gpio(GpioFile),
+    Sim(SimFile),
+}
+
+impl Read for W1File {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            W1File::Sysfs(file) => file.read(buf),
+            W1File::OwServer(file) => file.read(buf),
+            W1File::Gpio(file) => file.read(buf),
+            W1File::Sim(file) => file.read(buf),
+        }
+    }
+}
+
+impl Write for W1File {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            W1File::Sysfs(file) => file.write(buf),
+            W1File::OwServer(file) => file.write(buf),
+            W1File::Gpio(file) => file.write(buf),
+            W1File::Sim(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            W1File::Sysfs(file) => file.flush(),
+            W1File::OwServer(file) => file.flush(),
+            W1File::Gpio(file) => file.flush(),
+            W1File::Sim(file) => file.flush(),
+        }
+    }
+}
+
+impl Seek for W1File {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            W1File::Sysfs(file) => file.seek(pos),
+            W1File::OwServer(file) => file.seek(pos),
+            W1File::Gpio(file) => file.seek(pos),
+            W1File::Sim(file) => file.seek(pos),
+        }
+    }
+}
+
 pub struct Sensor {
     pub id_sensor: i32,
     pub id_kind: i32,
@@ -90,21 +197,266 @@ pub struct Sensor {
     pub tags: Vec<String>,
     pub associated_relays: Vec<i32>,
     pub associated_yeelights: Vec<i32>,
+    pub associated_plugs: Vec<i32>,
+    pub inverted: bool,
+    pub debounce: Option<Debounce>,
+}
+
+/// Stability required before a sensor state change is processed, from the
+/// `debounce:<reads>` (consecutive reads) or `debounce:<duration>` (eg. `200ms`) tag
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Debounce {
+    Reads(u32),
+    Time(Duration),
+}
+
+impl Debounce {
+    pub fn from_tags(tags: &Vec<String>) -> Option<Debounce> {
+        let value = tags
+            .iter()
+            .find(|t| t.starts_with("debounce:"))
+            .map(|t| &t["debounce:".len()..])?;
+        match value.parse::<u32>() {
+            Ok(reads) if reads > 1 => Some(Debounce::Reads(reads)),
+            Ok(_) => None,
+            Err(_) => match humantime::parse_duration(value) {
+                Ok(duration) => Some(Debounce::Time(duration)),
+                Err(e) => {
+                    error!("invalid debounce tag value: {:?}: {}", value, e);
+                    None
+                }
+            },
+        }
+    }
+}
+
+impl Sensor {
+    /// Normally-closed contacts and active-low outputs: from the `invert_state`/`nc` tags,
+    /// the legacy `<tag>:invert_state` modifiers or the `inverted_sensors` config option
+    pub fn is_inverted(name: &str, tags: &Vec<String>, inverted_sensors: &Vec<String>) -> bool {
+        inverted_sensors.iter().any(|x| x == name)
+            || tags.iter().any(|t| {
+                t == NORMALLY_CLOSED_TAG
+                    || t.split(':').any(|modifier| modifier == INVERT_STATE_TAG)
+            })
+    }
 }
+
 pub struct SensorBoard {
     pub pio_a: Option<Sensor>,
     pub pio_b: Option<Sensor>,
     pub ow_family: u8,
     pub ow_address: u64,
     pub last_value: Option<u8>,
-    pub file: Option<File>,
+    pub reader: Option<BoardReader>,
+    //bumped on every open, the results of the readers of the closed files are thrown away
+    pub generation: u64,
+    pub results: ReadResults,
+    pub owserver: Option<String>,
+    pub gpio: Option<GpioBoard>,
+    pub read_failures: u32,
+    pub last_success: Instant,
+    pub last_change: Instant,
+    pub last_reopen: Option<Instant>,
+    pub degraded: bool,
+    pub stats: DeviceStats,
+    //(reads, since) of a not yet stable change of the debounced PIOA/PIOB
+    pub debounce_pending: [Option<(u32, Instant)>; 2],
+    pub pending_read: Option<PendingRead>,
+}
+
+/// Long-lived thread owning the opened state file of a board and reading it on request,
+/// it ends when the board is closed and its last read is done
+pub struct BoardReader {
+    requests: mpsc::Sender<Instant>,
+}
+
+impl BoardReader {
+    fn spawn(
+        name: String,
+        mut file: W1File,
+        generation: u64,
+        results: mpsc::Sender<ReadResult>,
+    ) -> Self {
+        let (requests, request_receiver) = mpsc::channel::<Instant>();
+        thread::spawn(move || {
+            for started in request_receiver {
+                let result = SensorBoard::read_file(&name, &mut file);
+                if results
+                    .send((generation, result, started.elapsed()))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        BoardReader { requests }
+    }
+}
+
+/// Reader generation, read result and read time
+pub type ReadResult = (u64, std::io::Result<u8>, Duration);
+
+/// Results of all the readers of a board
+pub struct ReadResults {
+    sender: mpsc::Sender<ReadResult>,
+    receiver: Arc<Mutex<mpsc::Receiver<ReadResult>>>,
+}
+
+impl Default for ReadResults {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        ReadResults {
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+        }
+    }
+}
+
+/// Started read of a board, waited for without holding the board locks
+pub struct ReadWait {
+    receiver: Arc<Mutex<mpsc::Receiver<ReadResult>>>,
+    generation: u64,
+    name: String,
+}
+
+impl ReadWait {
+    /// The read result and time, None when not done until the deadline
+    fn wait(&self, deadline: Instant) -> Option<(std::io::Result<u8>, Duration)> {
+        let receiver = self.receiver.lock().unwrap();
+        loop {
+            //the sender is kept in the board, so an error can only be a timeout
+            let (generation, result, elapsed) = receiver
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .ok()?;
+            if generation == self.generation {
+                return Some((result, elapsed));
+            }
+            debug!("{}: discarding the result of a closed file read", self.name);
+        }
+    }
+}
+
+/// State file read requested from the board reader
+pub struct PendingRead {
+    started: Instant,
+    timed_out: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum OneWireError {
+    #[error("{board}: error opening the state file: {source}")]
+    Open {
+        board: String,
+        source: std::io::Error,
+    },
+    #[error("{board}: reader thread failed")]
+    ReaderFailed { board: String },
+    #[error("{board}: error reading: {source}")]
+    Read {
+        board: String,
+        source: std::io::Error,
+    },
+    #[error("{board}: ⏱️ read still running after {elapsed:?}, not waiting for it")]
+    ReadTimeout { board: String, elapsed: Duration },
+    #[error("{board}: reading state file gives invalid byte value: {value:#04x}, ignoring")]
+    InvalidValue { board: String, value: u8 },
+}
+
+impl WorkerError for OneWireError {
+    fn kind(&self) -> &'static str {
+        match self {
+            OneWireError::Open { .. } => "open",
+            OneWireError::ReaderFailed { .. } => "reader",
+            OneWireError::Read { .. } => "read",
+            OneWireError::ReadTimeout { .. } => "read_timeout",
+            OneWireError::InvalidValue { .. } => "invalid_data",
+        }
+    }
+
+    //a failing board is reopened and reported by the bus watchdog, the worker goes on
+    fn is_recoverable(&self) -> bool {
+        true
+    }
+}
+
+/// Outcome of a scan of a sensor board
+#[derive(Debug)]
+pub enum BoardRead {
+    Value(u8),
+    //no error when the board is not opened, the open error was reported already
+    Failed(Option<OneWireError>),
+    //a read is still running after its deadline and was already counted as failed
+    Pending,
+}
+
+impl BoardRead {
+    fn value(self) -> Option<u8> {
+        match self {
+            BoardRead::Value(value) => Some(value),
+            _ => None,
+        }
+    }
 }
 
 impl SensorBoard {
-    fn open(&mut self) {
+    /// Opens the state file and starts its reader
+    fn open(&mut self) -> Result<(), OneWireError> {
+        let name = get_w1_device_name(self.ow_family, self.ow_address);
+        self.generation += 1;
+        let (generation, results) = (self.generation, self.results.sender.clone());
+        self.reader = None;
+        let file = self.open_file().map_err(|source| OneWireError::Open {
+            board: name.clone(),
+            source,
+        })?;
+        self.reader = Some(BoardReader::spawn(name, file, generation, results));
+        Ok(())
+    }
+
+    /// Closes the state file, it is opened again on the next read
+    pub fn close(&mut self) {
+        self.reader = None;
+        self.pending_read = None;
+        self.last_reopen = None;
+    }
+
+    fn open_file(&mut self) -> std::io::Result<W1File> {
+        if simulation::enabled() {
+            self.stats.reopens += 1;
+            return simulation::open(
+                &get_w1_device_name(self.ow_family, self.ow_address),
+                "state",
+            )
+            .ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, "no simulated state file")
+            });
+        }
+        if let Some(gpio) = &self.gpio {
+            self.stats.reopens += 1;
+            return gpio.open_inputs().map(W1File::Gpio).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("gpio {}: error requesting input lines: {}", gpio.name, e),
+                )
+            });
+        }
+        if let Some(server) = &self.owserver {
+            let device = get_owfs_device_name(self.ow_family, self.ow_address);
+            info!(
+                "{}: using owserver {}: {}/piostate",
+                get_w1_device_name(self.ow_family, self.ow_address),
+                server,
+                device
+            );
+            self.stats.reopens += 1;
+            return Ok(W1File::OwServer(OwFile::new(
+                server, device, "piostate", false,
+            )));
+        }
         let path = format!(
             "{}/{}/state",
-            W1_ROOT_PATH,
+            w1_root(),
             get_w1_device_name(self.ow_family, self.ow_address)
         );
         let data_path = Path::new(&path);
@@ -113,63 +465,279 @@ impl SensorBoard {
             get_w1_device_name(self.ow_family, self.ow_address),
             data_path.display()
         );
-        self.file = File::open(data_path).ok();
+        self.stats.reopens += 1;
+        File::open(data_path).map(W1File::Sysfs)
     }
 
-    fn read_state(&mut self) -> Option<u8> {
-        if self.file.is_none() {
-            self.open();
+    fn read_file(name: &str, file: &mut W1File) -> std::io::Result<u8> {
+        let mut new_value = [0u8; 1];
+        if let Err(e) = file.seek(SeekFrom::Start(0)) {
+            error!("{}: file seek error: {:?}", name, e);
+        }
+        if chaos::inject(Fault::W1Read) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "chaos: injected read error",
+            ));
         }
+        file.read_exact(&mut new_value)?;
+        Ok(new_value[0])
+    }
 
-        match &mut self.file {
-            Some(file) => {
-                let mut new_value = [0u8; 1];
-                match file.seek(SeekFrom::Start(0)) {
-                    Err(e) => {
-                        error!(
-                            "{}: file seek error: {:?}",
-                            get_w1_device_name(self.ow_family, self.ow_address),
-                            e,
-                        );
+    /// Requests a read of the state file from the board reader,
+    /// unless the previous read is still running
+    fn start_read(&mut self) -> Result<(), OneWireError> {
+        if self.pending_read.is_some() {
+            return Ok(());
+        }
+        if self.reader.is_none() {
+            //don't hammer the bus when the board is gone
+            if self.degraded {
+                if let Some(reopen) = self.last_reopen {
+                    if reopen.elapsed() < Duration::from_secs_f32(SENSOR_BOARD_REOPEN_SECS) {
+                        return Ok(());
                     }
-                    _ => {}
                 }
-                let result = file.read_exact(&mut new_value);
-                match result {
-                    Ok(_) => {
-                        debug!(
-                            "{}: read byte: {:#04x}",
-                            get_w1_device_name(self.ow_family, self.ow_address),
-                            new_value[0]
-                        );
-                        //in this application only the following values are valid
-                        if new_value[0] == 0x5a
-                            || new_value[0] == 0x4b
-                            || new_value[0] == 0x1e
-                            || new_value[0] == 0x0f
-                        {
-                            return Some(new_value[0]);
-                        } else {
-                            error!(
-                                "{}: reading state file gives invalid byte value: {:#04x}, ignoring",
-                                get_w1_device_name(self.ow_family, self.ow_address),
-                                new_value[0]
-                            );
-                        }
-                    }
-                    Err(e) => {
-                        error!(
-                            "{}: error reading: {:?}",
-                            get_w1_device_name(self.ow_family, self.ow_address),
-                            e,
-                        );
-                    }
+                self.last_reopen = Some(Instant::now());
+            }
+            match self.open() {
+                Ok(()) => (),
+                //reported once per outage, the board is retried on each scan
+                Err(_) if self.read_failures > 0 => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(()),
+        };
+        let started = Instant::now();
+        if reader.requests.send(started).is_err() {
+            //the file is gone with the thread, it is opened again on the next read
+            self.reader = None;
+            return Err(OneWireError::ReaderFailed {
+                board: get_w1_device_name(self.ow_family, self.ow_address),
+            });
+        }
+        self.pending_read = Some(PendingRead {
+            started,
+            timed_out: false,
+        });
+        Ok(())
+    }
+
+    /// The wait for the started read, None when there is no read running
+    fn read_wait(&self) -> Option<ReadWait> {
+        self.pending_read.as_ref().map(|_| ReadWait {
+            receiver: self.results.receiver.clone(),
+            generation: self.generation,
+            name: get_w1_device_name(self.ow_family, self.ow_address),
+        })
+    }
+
+    /// Applies the result of the started read, a slow read (no result) is left running
+    /// and checked again in the next loop, it is counted as failed only once
+    fn finish_read(&mut self, received: Option<(std::io::Result<u8>, Duration)>) -> BoardRead {
+        let pending = match self.pending_read.as_mut() {
+            Some(pending) => pending,
+            None => return BoardRead::Failed(None),
+        };
+        let (result, elapsed) = match received {
+            Some(received) => received,
+            None => {
+                if pending.timed_out {
+                    return BoardRead::Pending;
                 }
+                pending.timed_out = true;
+                self.stats.read_timeouts += 1;
+                return BoardRead::Failed(Some(OneWireError::ReadTimeout {
+                    board: get_w1_device_name(self.ow_family, self.ow_address),
+                    elapsed: pending.started.elapsed(),
+                }));
+            }
+        };
+        self.pending_read = None;
+        self.stats.reads += 1;
+        self.stats.observe_read(elapsed);
+        let board = get_w1_device_name(self.ow_family, self.ow_address);
+        let error = match result {
+            Ok(value) => {
+                debug!(
+                    "{}: read byte: {:#04x} in {:?}",
+                    get_w1_device_name(self.ow_family, self.ow_address),
+                    value,
+                    elapsed
+                );
+                //in this application only the following values are valid
+                if value == 0x5a || value == 0x4b || value == 0x1e || value == 0x0f {
+                    //inversion is applied here, so all consumers see the logical state
+                    return BoardRead::Value(value ^ self.invert_mask());
+                }
+                self.stats.invalid_values += 1;
+                OneWireError::InvalidValue { board, value }
+            }
+            Err(source) => {
+                self.stats.read_errors += 1;
+                OneWireError::Read { board, source }
+            }
+        };
+        BoardRead::Failed(Some(error))
+    }
+
+    /// Holds back the changes of debounced sensors until the new state is stable
+    fn debounce(&mut self, value: u8) -> u8 {
+        let last_value = match self.last_value {
+            Some(last_value) => last_value,
+            None => return value,
+        };
+        let mut result = value;
+        for (i, bit) in [0u8, 2].iter().enumerate() {
+            let mask = 1 << bit;
+            let sensor = if i == 0 { &self.pio_a } else { &self.pio_b };
+            let debounce = match sensor.as_ref().and_then(|s| s.debounce) {
+                Some(debounce) => debounce,
+                None => continue,
+            };
+            if value & mask == last_value & mask {
+                if self.debounce_pending[i].take().is_some() {
+                    debug!(
+                        "{}: glitch filtered on bit {}",
+                        get_w1_device_name(self.ow_family, self.ow_address),
+                        bit
+                    );
+                }
+                continue;
+            }
+            let (reads, since) = self.debounce_pending[i].get_or_insert((0, Instant::now()));
+            *reads += 1;
+            let stable = match debounce {
+                Debounce::Reads(required) => *reads >= required,
+                Debounce::Time(duration) => since.elapsed() >= duration,
+            };
+            if stable {
+                self.debounce_pending[i] = None;
+            } else {
+                result = (result & !mask) | (last_value & mask);
             }
-            None => (),
         }
+        result
+    }
 
-        return None;
+    /// PIO state bits to be flipped for the inverted sensors
+    fn invert_mask(&self) -> u8 {
+        let mut mask = 0;
+        if self.pio_a.as_ref().map_or(false, |s| s.inverted) {
+            mask |= 1 << 0;
+        }
+        if self.pio_b.as_ref().map_or(false, |s| s.inverted) {
+            mask |= 1 << 2;
+        }
+        mask
+    }
+
+    /// Updates the read statistics and the degraded state, the recovery and reporting
+    /// of the degraded boards is done by the bus watchdog
+    fn watchdog(&mut self, read: &BoardRead, stale: Option<Duration>) {
+        match *read {
+            BoardRead::Value(val) => {
+                self.read_failures = 0;
+                self.last_success = Instant::now();
+                if self.last_value.is_some() && self.last_value != Some(val) {
+                    self.last_change = Instant::now();
+                }
+            }
+            BoardRead::Failed(_) => {
+                self.read_failures = self.read_failures.saturating_add(1);
+            }
+            //a hung read was counted when it timed out
+            BoardRead::Pending => (),
+        }
+
+        let failing =
+            self.last_success.elapsed() > Duration::from_secs_f32(SENSOR_BOARD_FAILURE_SECS);
+        let stalled = match stale {
+            Some(stale) => self.last_change.elapsed() > stale,
+            None => false,
+        };
+        let degraded = failing || stalled;
+        if degraded == self.degraded {
+            return;
+        }
+        self.degraded = degraded;
+
+        if degraded {
+            error!(
+                "{}: 🩺 sensor board degraded: {}, failed reads: {}",
+                get_w1_device_name(self.ow_family, self.ow_address),
+                if failing {
+                    format!(
+                        "no valid read for {}",
+                        format_duration(Duration::from_secs(self.last_success.elapsed().as_secs()))
+                    )
+                } else {
+                    format!(
+                        "no state change for {}",
+                        format_duration(Duration::from_secs(self.last_change.elapsed().as_secs()))
+                    )
+                },
+                self.read_failures,
+            );
+        } else {
+            info!(
+                "{}: 🩺 sensor board recovered",
+                get_w1_device_name(self.ow_family, self.ow_address),
+            );
+            //a stale board which has just changed value starts counting again
+            self.last_change = Instant::now();
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SensorBoardHealth {
+    pub board: String,
+    pub degraded: bool,
+    pub read_failures: u32,
+    pub last_success_secs: u64,
+    pub last_change_secs: u64,
+}
+
+#[derive(Serialize)]
+pub struct DeviceStatus {
+    pub id: i32,
+    pub name: String,
+    pub dest: String,
+    pub on: bool,
+    pub override_mode: bool,
+    pub last_toggled_secs: Option<u64>,
+    pub stop_after_secs: Option<u64>,
+    pub remaining_secs: Option<u64>,
+    pub tags: Vec<String>,
+}
+
+impl DeviceStatus {
+    fn new(dev: &Device, dest: String, on: bool) -> Self {
+        let elapsed = dev.last_toggled.map(|t| t.elapsed());
+        DeviceStatus {
+            id: dev.id,
+            name: dev.name.clone(),
+            dest,
+            on,
+            override_mode: dev.override_mode,
+            last_toggled_secs: elapsed.map(|e| e.as_secs()),
+            stop_after_secs: dev.stop_after.map(|d| d.as_secs()),
+            remaining_secs: match (dev.stop_after, elapsed) {
+                (Some(stop_after), Some(elapsed)) => Some(
+                    stop_after
+                        .checked_sub(elapsed)
+                        .unwrap_or_default()
+                        .as_secs(),
+                ),
+                _ => None,
+            },
+            tags: dev.tags.clone(),
+        }
     }
 }
 
@@ -184,9 +752,23 @@ pub struct Device {
     pub override_mode: bool,
     pub last_toggled: Option<Instant>,
     pub stop_after: Option<Duration>,
+    //the last_toggled time of the automatic turn-off (hold time elapsed)
+    pub auto_off: Option<Instant>,
 }
 
 impl Device {
+    fn automation_state(&self) -> DeviceState {
+        DeviceState {
+            override_mode: self.override_mode,
+            pir_exclude: self.pir_exclude,
+            pir_all_day: self.pir_all_day,
+            pir_hold_secs: self.pir_hold_secs,
+            switch_hold_secs: self.switch_hold_secs,
+            toggled_elapsed: self.last_toggled.map(|t| t.elapsed()),
+        }
+    }
+
+    /// Decides and applies a turn-on/prolong request, returns true when the output has to change
     fn turn_on_prolong(
         &mut self,
         kind: ProlongKind,
@@ -196,55 +778,30 @@ impl Device {
         currently_off: bool,
         duration: Option<Duration>,
     ) -> bool {
-        if (kind == ProlongKind::PIR
-            && !(self.override_mode && on
-                || (!self.pir_exclude && on && (night || self.pir_all_day))))
-            || ((kind == ProlongKind::Remote
-                || (kind == ProlongKind::AutoOff && !self.override_mode))
-                && !on
-                && currently_off)
-        {
-            return false;
-        }
-        let d = match duration {
-            Some(d) => {
-                //if we have a duration pass it directly
-                d
-            }
-            None => {
-                //otherwise take a switch_hold_secs or pir_hold_secs
-                let mut prolong_secs = match kind {
-                    ProlongKind::Switch => self.switch_hold_secs,
-                    _ => self.pir_hold_secs,
-                };
-                if kind != ProlongKind::Switch {
-                    if !self.override_mode && currently_off {
-                        if kind == ProlongKind::Remote
-                            && self.switch_hold_secs != DEFAULT_SWITCH_HOLD_SECS
-                        {
-                            prolong_secs = self.switch_hold_secs
-                        }
-                    } else if self.override_mode {
-                        if DEFAULT_PIR_PROLONG_SECS > prolong_secs {
-                            prolong_secs = DEFAULT_PIR_PROLONG_SECS;
-                        };
-                    }
-                }
-                Duration::from_secs_f32(prolong_secs)
-            }
+        let trigger = Trigger {
+            kind,
+            night,
+            on,
+            currently_off,
+            duration,
         };
+        let action = automation::decide(&self.automation_state(), &trigger);
+        self.apply(&trigger, action, dest_name)
+    }
 
+    /// Applies the automation decision to the device state
+    fn apply(&mut self, trigger: &Trigger, action: Action, dest_name: String) -> bool {
         //visual
-        let mode = match kind {
+        let mode = match trigger.kind {
             ProlongKind::Switch => format!("🔲 Switch toggle {}", {
-                if currently_off {
+                if trigger.currently_off {
                     "💡"
                 } else {
                     "◼️"
                 }
             }),
             ProlongKind::Remote => format!("🧩 Remote turn-{}", {
-                if on {
+                if trigger.on {
                     "on"
                 } else {
                     "off"
@@ -253,7 +810,7 @@ impl Device {
             ProlongKind::PIR => "💡 PIR turn-on".to_string(),
             ProlongKind::AutoOff => "⌛ Auto turn-off".to_string(),
             ProlongKind::DayNight => format!("🌄 Day/night auto turn-{}", {
-                if on {
+                if trigger.on {
                     "on"
                 } else {
                     "off"
@@ -261,102 +818,99 @@ impl Device {
             }),
         };
 
-        //checking if device is currently OFF
-        if kind == ProlongKind::Switch
-            || ((kind == ProlongKind::Remote || kind == ProlongKind::AutoOff) && !on)
-            || (!self.override_mode && currently_off)
-            || kind == ProlongKind::DayNight
-        {
-            //flip-flop protection for too fast state changes
-            let mut flipflop_block = false;
-            match self.last_toggled {
-                Some(toggled) => {
-                    if toggled.elapsed() < Duration::from_secs_f32(MIN_TOGGLE_DELAY_SECS) {
-                        flipflop_block = true;
-                    }
-                }
-                _ => {}
-            }
-
-            if flipflop_block {
+        let duration = match action {
+            Action::Ignore {
+                reason: IgnoreReason::FlipFlop,
+            } => {
                 warn!(
-                        "<d>- - -</> 🚫 flip-flop protection: <b>{}</> <cyan>(</><magenta>{}</><cyan>)</>, {} request ignored",
-                        self.name,
-                        dest_name,
-                        mode,
-                    );
-            } else {
-                let mut duration;
-                if (kind == ProlongKind::Remote && !on)
-                    || kind == ProlongKind::AutoOff
-                    || kind == ProlongKind::DayNight
-                {
-                    duration = "".to_string();
-                    self.stop_after = None;
-                    if kind == ProlongKind::AutoOff && currently_off && self.override_mode {
-                        info!(
-                        "<d>- - -</> 🔓 End of override mode: <b>{}</> <cyan>(</><magenta>{}</><cyan>)</>{}",
-                        self.name, dest_name, duration,
-                    );
-                        self.last_toggled = None;
-                        self.override_mode = false;
-                        return false;
-                    }
-                    //mark that we was in override
-                    if self.override_mode {
-                        duration.push_str(" 🔓");
-                    }
-                    self.override_mode = false;
-                } else {
-                    duration = format!(", duration: <yellow>{}</>", format_duration(d));
-                    if kind == ProlongKind::Switch {
-                        self.override_mode = true;
-                        duration.push_str(" 🔒");
+                    "<d>- - -</> 🚫 flip-flop protection: <b>{}</> <cyan>(</><magenta>{}</><cyan>)</>, {} request ignored",
+                    self.name,
+                    dest_name,
+                    mode,
+                );
+                return false;
+            }
+            Action::Ignore { reason } => {
+                debug!(
+                    "{}: {:?} request ignored: {}",
+                    self.name, trigger.kind, reason
+                );
+                return false;
+            }
+            Action::EndOverride => {
+                info!(
+                    "<d>- - -</> 🔓 End of override mode: <b>{}</> <cyan>(</><magenta>{}</><cyan>)</>",
+                    self.name, dest_name,
+                );
+                self.last_toggled = None;
+                self.override_mode = false;
+                self.stop_after = None;
+                return false;
+            }
+            Action::Prolong {
+                duration,
+                stop_after,
+            } => {
+                let mut added = match stop_after {
+                    Some(_) => {
+                        format!(", duration added: <yellow>{}</>", format_duration(duration))
                     }
-                    self.stop_after = Some(d);
+                    None => "".to_string(),
+                };
+                if self.override_mode {
+                    //mark that we are in override mode
+                    added.push_str(" 🔒");
+                }
+                if stop_after.is_some() {
+                    self.stop_after = stop_after;
                 }
                 info!(
-                    "<d>- - -</> {}: <b>{}</> <cyan>(</><magenta>{}</><cyan>)</>{}",
-                    mode, self.name, dest_name, duration,
+                    "<d>- - -</> ♾️ {:?} prolonged{}: <b>{}</> <cyan>(</><magenta>{}</><cyan>)</>{}",
+                    trigger.kind,
+                    {
+                        if !self.override_mode {
+                            ""
+                        } else if !trigger.currently_off {
+                            " 💡"
+                        } else {
+                            " ◼️"
+                        }
+                    },
+                    self.name,
+                    dest_name,
+                    added,
                 );
-                self.last_toggled = Some(Instant::now());
-                return true;
+                return false;
             }
-        } else {
-            let toggled_elapsed = self.last_toggled.unwrap_or(Instant::now()).elapsed();
-            let mut duration = format!(", duration added: <yellow>{}</>", format_duration(d));
-            if self.override_mode {
-                if self.switch_hold_secs > d.as_secs_f32()
-                    && toggled_elapsed
-                        > Duration::from_secs_f32(self.switch_hold_secs - d.as_secs_f32())
-                {
-                    self.stop_after = Some(toggled_elapsed.add(d));
-                } else {
-                    duration = "".into();
-                }
-                //mark that we are in override mode
-                duration.push_str(" 🔒");
-            } else {
-                self.stop_after = Some(toggled_elapsed.add(d));
+            Action::Toggle { duration } => {
+                self.override_mode = true;
+                self.stop_after = Some(duration);
+                format!(", duration: <yellow>{}</> 🔒", format_duration(duration))
             }
-            info!(
-                "<d>- - -</> ♾️ {:?} prolonged{}: <b>{}</> <cyan>(</><magenta>{}</><cyan>)</>{}",
-                kind,
-                {
-                    if !self.override_mode {
-                        ""
-                    } else if !currently_off {
-                        " 💡"
-                    } else {
-                        " ◼️"
-                    }
-                },
-                self.name,
-                dest_name,
-                duration,
-            );
+            Action::TurnOn {
+                duration: Some(duration),
+            } => {
+                self.stop_after = Some(duration);
+                format!(", duration: <yellow>{}</>", format_duration(duration))
+            }
+            Action::TurnOn { duration: None } | Action::TurnOff => {
+                self.stop_after = None;
+                //mark that we was in override
+                let unlocked = if self.override_mode { " 🔓" } else { "" };
+                self.override_mode = false;
+                unlocked.to_string()
+            }
+        };
+        info!(
+            "<d>- - -</> {}: <b>{}</> <cyan>(</><magenta>{}</><cyan>)</>{}",
+            mode, self.name, dest_name, duration,
+        );
+        let now = Instant::now();
+        self.last_toggled = Some(now);
+        if trigger.kind == ProlongKind::AutoOff {
+            self.auto_off = Some(now);
         }
-        false
+        action.changes_output()
     }
 }
 
@@ -394,13 +948,27 @@ trait OnOff {
 
             match kind_code.as_ref() {
                 "PIR_Trigger" => {
+                    //learned hold time for the current hour (relays tagged with adaptive_hold)
+                    let duration = if on
+                        && !device.override_mode
+                        && !device.pir_exclude
+                        && (night || device.pir_all_day)
+                    {
+                        state_machine
+                            .adaptive_hold
+                            .write()
+                            .unwrap()
+                            .hold_time(device, currently_off)
+                    } else {
+                        None
+                    };
                     if device.turn_on_prolong(
                         ProlongKind::PIR,
                         night,
                         dest_name,
                         on,
                         currently_off,
-                        None,
+                        duration,
                     ) {
                         self.set_new_value(Operation::On, index, onewire, device);
                     }
@@ -429,14 +997,50 @@ pub struct RelayBoard {
     pub ow_address: u64,
     pub new_value: Option<u8>,
     pub last_value: Option<u8>,
-    pub file: Option<File>,
+    pub file: Option<W1File>,
+    pub owserver: Option<String>,
+    pub gpio: Option<GpioBoard>,
+    pub stats: DeviceStats,
 }
 
 impl RelayBoard {
     fn open(&mut self) {
+        if simulation::enabled() {
+            self.stats.reopens += 1;
+            self.file = simulation::open(
+                &get_w1_device_name(self.ow_family, self.ow_address),
+                "output",
+            );
+            return;
+        }
+        if let Some(gpio) = &self.gpio {
+            self.stats.reopens += 1;
+            self.file = match gpio.open_outputs() {
+                Ok(file) => Some(W1File::Gpio(file)),
+                Err(e) => {
+                    error!("gpio {}: error requesting output lines: {}", gpio.name, e);
+                    None
+                }
+            };
+            return;
+        }
+        if let Some(server) = &self.owserver {
+            let device = get_owfs_device_name(self.ow_family, self.ow_address);
+            info!(
+                "{}: using owserver {}: {}/PIO.BYTE",
+                get_w1_device_name(self.ow_family, self.ow_address),
+                server,
+                device
+            );
+            self.stats.reopens += 1;
+            self.file = Some(W1File::OwServer(OwFile::new(
+                server, device, "PIO.BYTE", true,
+            )));
+            return;
+        }
         let path = format!(
             "{}/{}/output",
-            W1_ROOT_PATH,
+            w1_root(),
             get_w1_device_name(self.ow_family, self.ow_address)
         );
         let data_path = Path::new(&path);
@@ -445,10 +1049,16 @@ impl RelayBoard {
             get_w1_device_name(self.ow_family, self.ow_address),
             data_path.display()
         );
-        let file = OpenOptions::new().write(true).open(data_path);
+        self.stats.reopens += 1;
+        //open for reading too, so we can read back the output latch
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(data_path)
+            .or_else(|_| OpenOptions::new().write(true).open(data_path));
         match file {
             Ok(file) => {
-                self.file = Some(file);
+                self.file = Some(W1File::Sysfs(file));
             }
             Err(e) => {
                 error!(
@@ -489,8 +1099,22 @@ impl RelayBoard {
                         Ok(_) => {
                             self.last_value = Some(val);
                             self.new_value = None;
+                            //verify and retry once when the latch doesn't match
+                            if let Some(latch) = self.read_output() {
+                                if latch != val {
+                                    error!(
+                                        "{}: output latch mismatch after write: {:#04x} (expected {:#04x}), rewriting",
+                                        get_w1_device_name(self.ow_family, self.ow_address),
+                                        latch,
+                                        val
+                                    );
+                                    self.new_value = Some(val);
+                                    self.write_retry();
+                                }
+                            }
                         }
                         Err(e) => {
+                            self.stats.write_errors += 1;
                             error!(
                                 "{}: error writing output byte: {:?}",
                                 get_w1_device_name(self.ow_family, self.ow_address),
@@ -505,12 +1129,144 @@ impl RelayBoard {
         }
     }
 
+    fn read_output(&mut self) -> Option<u8> {
+        match &mut self.file {
+            Some(file) => {
+                let mut latch = [0u8; 1];
+                if file.seek(SeekFrom::Start(0)).is_err() {
+                    return None;
+                }
+                self.stats.reads += 1;
+                match file.read_exact(&mut latch) {
+                    Ok(_) => Some(latch[0]),
+                    Err(e) => {
+                        self.stats.read_errors += 1;
+                        debug!(
+                            "{}: unable to read back output latch: {:?}",
+                            get_w1_device_name(self.ow_family, self.ow_address),
+                            e,
+                        );
+                        None
+                    }
+                }
+            }
+            None => None,
+        }
+    }
+
+    fn write_retry(&mut self) {
+        if let (Some(file), Some(val)) = (&mut self.file, self.new_value) {
+            let _ = file.seek(SeekFrom::Start(0));
+            match file.write_all(&[val; 1]) {
+                Ok(_) => {
+                    self.new_value = None;
+                }
+                Err(e) => {
+                    self.stats.write_errors += 1;
+                    error!(
+                        "{}: error rewriting output byte: {:?}",
+                        get_w1_device_name(self.ow_family, self.ow_address),
+                        e,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Compares the output latch with the intended state and rewrites it on drift.
+    /// Returns true when a drift was detected (eg. the chip was reset by a power blip).
+    fn verify_output(&mut self) -> bool {
+        if self.new_value.is_some() {
+            //pending write: nothing to verify yet
+            return false;
+        }
+        let expected = match self.last_value {
+            Some(val) => val,
+            None => return false,
+        };
+        match self.read_output() {
+            Some(latch) if latch != expected => {
+                error!(
+                    "{}: 🔀 output latch drift detected: {:#04x} (expected {:#04x}), restoring",
+                    get_w1_device_name(self.ow_family, self.ow_address),
+                    latch,
+                    expected
+                );
+                self.new_value = Some(expected);
+                self.save_state();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Applies the `on_failure:keep|off|on` policy of attached relays and writes the result
+    fn apply_failure_policy(&mut self, relays: &Vec<Device>) {
+        let mut new_state: u8 = self.get_actual_state();
+        for i in 0..=7 {
+            if let Some(id) = self.relay[i] {
+                if let Some(relay) = relays.iter().find(|r| r.id == id) {
+                    let policy = relay
+                        .tags
+                        .iter()
+                        .find(|t| t.starts_with("on_failure:"))
+                        .map(|t| &t["on_failure:".len()..]);
+                    match policy {
+                        Some("off") => new_state = new_state | (1 << i as u8),
+                        Some("on") => new_state = new_state & !(1 << i as u8),
+                        Some("keep") | None => continue,
+                        Some(other) => {
+                            error!(
+                                "{}: {}: unknown on_failure policy: {:?}",
+                                self.get_dest_name(Some(i)),
+                                relay.name,
+                                other
+                            );
+                            continue;
+                        }
+                    }
+                    warn!(
+                        "{}: {}: 🛟 applying on_failure policy: {}",
+                        self.get_dest_name(Some(i)),
+                        relay.name,
+                        policy.unwrap()
+                    );
+                }
+            }
+        }
+        if new_state != self.get_actual_state() {
+            self.new_value = Some(new_state);
+            self.save_state();
+        }
+    }
+
     fn get_actual_state(&self) -> u8 {
         //we will be computing new output byte for a relay board
         //so first of all get the base/previous value
         self.new_value
             .unwrap_or(self.last_value.unwrap_or(DS2408_INITIAL_STATE))
     }
+
+    /// Switches the relay right away, bypassing the task processing,
+    /// returns false when the relay is not on this board
+    pub fn switch_relay(&mut self, id: i32, on: bool) -> bool {
+        let i = match self.relay.iter().position(|r| *r == Some(id)) {
+            Some(i) => i,
+            None => return false,
+        };
+        let state = self.get_actual_state();
+        //a cleared bit turns the relay on
+        let new_state = if on {
+            state & !(1 << i as u8)
+        } else {
+            state | (1 << i as u8)
+        };
+        if new_state != state {
+            self.new_value = Some(new_state);
+            self.save_state();
+        }
+        true
+    }
 }
 
 impl OnOff for RelayBoard {
@@ -554,136 +1310,18 @@ pub struct Yeelight {
     pub powered_on: bool,
 }
 
-#[derive(Serialize)]
-struct YeelightCommand {
-    id: u32,
-    method: String,
-    #[serde(serialize_with = "Yeelight::params_serialize")]
-    params: Vec<String>,
-}
-#[derive(Deserialize)]
-struct YeelightResult {
-    id: u32,
-    result: Vec<String>,
-}
-
 impl Yeelight {
-    fn params_serialize<S>(params: &Vec<String>, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let mut seq = serializer.serialize_seq(Some(params.len()))?;
-        for (pos, elem) in params.iter().enumerate() {
-            if pos == 2 {
-                //converting last parameter (duration of effect) to integer
-                let duration: u32 = elem.parse().unwrap_or_default();
-                seq.serialize_element(&duration)?;
-            } else {
-                //leaving as String
-                seq.serialize_element(&elem)?;
-            }
-        }
-        seq.end()
-    }
-
-    fn yeelight_tcp_command(yeelight_name: String, ip_addr: String, turn_on: bool) {
-        let on_off = if turn_on { "on" } else { "off" };
-        let id = 1;
-        let cmd = YeelightCommand {
-            id: id,
-            method: YEELIGHT_METHOD_SET_POWER.to_owned(),
-            params: vec![
-                on_off.to_owned(),
-                YEELIGHT_EFFECT.to_owned(),
-                YEELIGHT_DURATION_MS.to_string(),
-            ],
-        };
-
-        // serialize command to a JSON string
-        let mut json_cmd = serde_json::to_string(&cmd).unwrap();
-        debug!(
-            "Yeelight: {}: generated JSON command={:?}",
-            yeelight_name, json_cmd
-        );
-
-        for _ in 1..=3 {
-            debug!("Yeelight: {}: connecting...", yeelight_name);
-            match TcpStream::connect(format!("{}:{}", ip_addr, YEELIGHT_TCP_PORT)) {
-                Err(e) => {
-                    error!("Yeelight: {}: connection error: {:?}", yeelight_name, e);
-                }
-                Ok(mut stream) => {
-                    debug!("Yeelight: {}: connected, sending command", yeelight_name);
-                    json_cmd.push_str("\r\n"); //specs requirement
-                    match stream.write_all(json_cmd.as_bytes()) {
-                        Ok(_) => {
-                            let _ = stream.set_read_timeout(Some(Duration::from_secs_f32(1.5)));
-                            let mut reader = BufReader::new(stream.try_clone().unwrap());
-
-                            //read a line with json result from yeelight
-                            let mut raw_result = String::new();
-                            let _ = reader.read_line(&mut raw_result);
-
-                            //try to parse json
-                            match serde_json::from_str::<YeelightResult>(&raw_result) {
-                                Ok(json_res) => {
-                                    //check for correct command result
-                                    if json_res.id == id && json_res.result == vec!["ok"] {
-                                        break;
-                                    }
-                                }
-                                Err(e) => {
-                                    error!(
-                                        "Yeelight: {}: error parsing result JSON: {:?}\nraw input data: {:?}",
-                                        yeelight_name, e, raw_result
-                                    );
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            error!(
-                                "Yeelight: {}: cannot write to socket: {:?}",
-                                yeelight_name, e
-                            );
-                        }
-                    }
-                }
-            }
-        }
-    }
-
-    fn tasmota_command(yeelight_name: String, ip_addr: String, turn_on: bool) -> bool {
-        let cmd = if turn_on { "Power On" } else { "Power off" };
-        let url =
-            reqwest::Url::parse_with_params(&format!("http://{}/cm", ip_addr), &[("cmnd", cmd)])
-                .unwrap();
-        debug!("URL = {:?}", url.as_str());
-
-        for _ in 1..=3 {
-            debug!(
-                "Tasmota: {}: sending <blue>{}</> command...",
-                yeelight_name, cmd
-            );
-            let resp = reqwest::blocking::get(url.clone()).unwrap();
-            if resp.status() == reqwest::StatusCode::OK {
-                return true;
-            } else {
-                thread::sleep(Duration::from_secs(1));
-            }
-        }
-        false
-    }
-
-    fn turn_on_off(&mut self, turn_on: bool, dev: &Device) {
-        let yeelight_name = dev.name.clone();
-        let ip_address = self.ip_address.clone();
-        if yeelight_name.starts_with("Nous") {
-            thread::spawn(move || Yeelight::tasmota_command(yeelight_name, ip_address, turn_on));
-        } else {
-            thread::spawn(move || {
-                Yeelight::yeelight_tcp_command(yeelight_name, ip_address, turn_on)
+    fn turn_on_off(&mut self, turn_on: bool, onewire: &OneWire) {
+        //sent by the yeelight connection pool
+        onewire
+            .yeelight_requests
+            .lock()
+            .unwrap()
+            .push(YeelightRequest {
+                id: self.id,
+                turn_on,
+                edge: onewire.edge(),
             });
-        }
 
         self.powered_on = turn_on;
     }
@@ -710,9 +1348,39 @@ impl OnOff for Yeelight {
             Operation::Off => false,
             Operation::Toggle => !self.powered_on,
         };
-        self.turn_on_off(new_state, dev);
+        self.turn_on_off(new_state, onewire.unwrap());
         dev.last_toggled = Some(Instant::now());
         onewire.unwrap().increment_yeelight_counter(self.id);
+        onewire
+            .unwrap()
+            .device_switched(DeviceKind::Yeelight, self.id, self.powered_on);
+    }
+}
+
+impl OnOff for SmartPlug {
+    fn currently_off(&self, _index: Option<usize>) -> bool {
+        !self.powered_on
+    }
+
+    fn get_dest_name(&self, _index: Option<usize>) -> String {
+        format!("plug:{}", self.host)
+    }
+
+    fn set_new_value(
+        &mut self,
+        op: Operation,
+        _index: Option<usize>,
+        onewire: Option<&OneWire>,
+        dev: &mut Device,
+    ) {
+        let new_state = match op {
+            Operation::On => true,
+            Operation::Off => false,
+            Operation::Toggle => !self.powered_on,
+        };
+        //counted when the plug has confirmed the command
+        self.turn_on_off(new_state, dev, onewire.unwrap());
+        dev.last_toggled = Some(Instant::now());
     }
 }
 
@@ -720,18 +1388,95 @@ pub struct SensorDevices {
     pub kinds: HashMap<i32, String>,
     pub sensor_boards: Vec<SensorBoard>,
     pub max_cesspool_level: usize,
+    pub virtual_sensors: Vec<VirtualSensor>,
+    pub inverted_sensors: Vec<String>,
+    pub owserver: Option<String>,
+    pub gpio_boards: Vec<GpioBoard>,
 }
 
 pub struct RelayDevices {
     pub relay_boards: Vec<RelayBoard>,
+    pub owserver: Option<String>,
+    pub gpio_boards: Vec<GpioBoard>,
     pub yeelight: Vec<Yeelight>,
+    pub smart_plugs: Vec<SmartPlug>,
+    pub schedules: Vec<RelaySchedule>,
+    pub cron_jobs: Vec<CronJob>,
+    pub scenes: Vec<Scene>,
+    pub shutters: Vec<Shutter>,
+    pub shutter_rules: Vec<ShutterRule>,
 }
 
 pub struct Relays {
     pub relay: Vec<Device>,
 }
 
-impl SensorDevices {
+/// True when the relay is turned on, for the shutter interlock
+fn relay_is_on(relay_boards: &Vec<RelayBoard>, id_relay: i32) -> bool {
+    relay_boards.iter().any(|rb| {
+        rb.relay
+            .iter()
+            .position(|r| *r == Some(id_relay))
+            .map_or(false, |i| !rb.currently_off(Some(i)))
+    })
+}
+
+impl RelayDevices {
+    pub fn get_relay_status(&self, relays: &Vec<Device>) -> Vec<DeviceStatus> {
+        let mut status = vec![];
+        for rb in &self.relay_boards {
+            for i in 0..=7 {
+                if let Some(dev) = rb.relay[i].and_then(|id| relays.iter().find(|r| r.id == id)) {
+                    status.push(DeviceStatus::new(
+                        dev,
+                        rb.get_dest_name(Some(i)),
+                        !rb.currently_off(Some(i)),
+                    ));
+                }
+            }
+        }
+        status
+    }
+
+    pub fn get_yeelight_status(&self, relays: &Vec<Device>) -> Vec<DeviceStatus> {
+        self.yeelight
+            .iter()
+            .filter_map(|y| {
+                relays
+                    .iter()
+                    .find(|d| d.id == y.id)
+                    .map(|dev| DeviceStatus::new(dev, y.get_dest_name(None), y.powered_on))
+            })
+            .collect()
+    }
+
+    pub fn get_smart_plug_status(&self, relays: &Vec<Device>) -> Vec<DeviceStatus> {
+        self.smart_plugs
+            .iter()
+            .filter_map(|p| {
+                relays
+                    .iter()
+                    .find(|d| d.id == p.id)
+                    .map(|dev| DeviceStatus::new(dev, p.get_dest_name(None), p.powered_on))
+            })
+            .collect()
+    }
+}
+
+impl SensorDevices {
+    pub fn get_health(&self) -> Vec<SensorBoardHealth> {
+        self.sensor_boards
+            .iter()
+            .map(|sb| SensorBoardHealth {
+                board: get_w1_device_name(sb.ow_family, sb.ow_address),
+                degraded: sb.degraded,
+                read_failures: sb.read_failures,
+                last_success_secs: sb.last_success.elapsed().as_secs(),
+                last_change_secs: sb.last_change.elapsed().as_secs(),
+            })
+            .collect()
+    }
+
     pub fn add_sensor(
         &mut self,
         id_sensor: i32,
@@ -742,6 +1487,7 @@ impl SensorDevices {
         bit: u8,
         associated_relays: Vec<i32>,
         associated_yeelights: Vec<i32>,
+        associated_plugs: Vec<i32>,
         tags: Vec<String>,
     ) {
         //find or create a sensor board
@@ -752,18 +1498,33 @@ impl SensorDevices {
         {
             Some(b) => b,
             None => {
+                let ow_family = match family_code {
+                    Some(family) => family as u8,
+                    None => FAMILY_CODE_DS2413,
+                };
                 let mut sens_board = SensorBoard {
                     pio_a: None,
                     pio_b: None,
-                    ow_family: match family_code {
-                        Some(family) => family as u8,
-                        None => FAMILY_CODE_DS2413,
-                    },
+                    ow_family,
                     ow_address: address,
                     last_value: None,
-                    file: None,
+                    reader: None,
+                    generation: 0,
+                    results: Default::default(),
+                    owserver: self.owserver.clone(),
+                    gpio: gpio_board(&self.gpio_boards, ow_family, address),
+                    read_failures: 0,
+                    last_success: Instant::now(),
+                    last_change: Instant::now(),
+                    last_reopen: None,
+                    degraded: false,
+                    stats: Default::default(),
+                    debounce_pending: [None; 2],
+                    pending_read: None,
                 };
-                sens_board.open();
+                if let Err(e) = sens_board.open() {
+                    error!("{}", e);
+                }
                 self.sensor_boards.push(sens_board);
                 self.sensor_boards.last_mut().unwrap()
             }
@@ -790,6 +1551,14 @@ impl SensorDevices {
         }
 
         //create and attach a sensor
+        let inverted = Sensor::is_inverted(&name, &tags, &self.inverted_sensors);
+        if inverted {
+            debug!("{}: sensor state is inverted", name);
+        }
+        let debounce = Debounce::from_tags(&tags);
+        if let Some(debounce) = debounce {
+            debug!("{}: debounce: {:?}", name, debounce);
+        }
         let sensor = Sensor {
             id_sensor,
             id_kind,
@@ -797,6 +1566,9 @@ impl SensorDevices {
             tags,
             associated_relays,
             associated_yeelights,
+            associated_plugs,
+            inverted,
+            debounce,
         };
         match bit {
             0 => {
@@ -834,16 +1606,20 @@ impl RelayDevices {
         {
             Some(b) => b,
             None => {
+                let ow_family = match family_code {
+                    Some(family) => family as u8,
+                    None => FAMILY_CODE_DS2408,
+                };
                 let mut relay_board = RelayBoard {
                     relay: Default::default(),
-                    ow_family: match family_code {
-                        Some(family) => family as u8,
-                        None => FAMILY_CODE_DS2408,
-                    },
+                    ow_family,
                     ow_address: address,
                     new_value: None,
                     last_value: None,
                     file: None,
+                    owserver: self.owserver.clone(),
+                    gpio: gpio_board(&self.gpio_boards, ow_family, address),
+                    stats: Default::default(),
                 };
 
                 //we probably can read the current state of relays but due to safety reasons
@@ -937,6 +1713,7 @@ impl RelayDevices {
                     None
                 }
             },
+            auto_off: old_relay.and_then(|r| r.auto_off),
         };
         relay_board.relay[bit as usize] = Some(id_relay);
         relays.retain(|r| r.id != id_relay);
@@ -955,7 +1732,8 @@ impl RelayDevices {
         pir_all_day: bool,
         tags: Vec<String>,
     ) {
-        //create and add a yeelight
+        //create and add a yeelight, the state of a reloaded one is preserved
+        let old_dev = relays.iter().find(|r| r.id == id_yeelight);
         let dev = Device {
             id: id_yeelight,
             name,
@@ -964,20 +1742,72 @@ impl RelayDevices {
             pir_hold_secs: pir_hold_secs.unwrap_or(DEFAULT_PIR_HOLD_SECS),
             switch_hold_secs: switch_hold_secs.unwrap_or(DEFAULT_SWITCH_HOLD_SECS),
             pir_all_day,
-            override_mode: false,
-            last_toggled: None,
-            stop_after: None,
+            override_mode: old_dev.map_or(false, |d| d.override_mode),
+            last_toggled: old_dev.and_then(|d| d.last_toggled),
+            stop_after: old_dev.and_then(|d| d.stop_after),
+            auto_off: old_dev.and_then(|d| d.auto_off),
         };
         let light = Yeelight {
             id: id_yeelight,
             ip_address,
-            powered_on: false,
+            powered_on: self
+                .yeelight
+                .iter()
+                .find(|y| y.id == id_yeelight)
+                .map_or(false, |y| y.powered_on),
         };
+        self.yeelight.retain(|y| y.id != id_yeelight);
         self.yeelight.push(light);
         relays.retain(|r| r.id != id_yeelight);
         relays.push(dev);
     }
 
+    pub fn add_smart_plug(
+        &mut self,
+        relays: &mut Vec<Device>,
+        id_plug: i32,
+        name: String,
+        kind: PlugKind,
+        host: String,
+        channel: u8,
+        pir_exclude: bool,
+        pir_hold_secs: Option<f32>,
+        switch_hold_secs: Option<f32>,
+        pir_all_day: bool,
+        tags: Vec<String>,
+    ) {
+        //create and add a smart plug, the state of a reloaded one is preserved
+        let old_dev = relays.iter().find(|r| r.id == id_plug);
+        let dev = Device {
+            id: id_plug,
+            name,
+            tags,
+            pir_exclude,
+            pir_hold_secs: pir_hold_secs.unwrap_or(DEFAULT_PIR_HOLD_SECS),
+            switch_hold_secs: switch_hold_secs.unwrap_or(DEFAULT_SWITCH_HOLD_SECS),
+            pir_all_day,
+            override_mode: old_dev.map_or(false, |d| d.override_mode),
+            last_toggled: old_dev.and_then(|d| d.last_toggled),
+            stop_after: old_dev.and_then(|d| d.stop_after),
+            auto_off: old_dev.and_then(|d| d.auto_off),
+        };
+        let plug = SmartPlug {
+            id: id_plug,
+            kind,
+            host,
+            channel,
+            powered_on: self
+                .smart_plugs
+                .iter()
+                .find(|p| p.id == id_plug)
+                .map_or(false, |p| p.powered_on),
+        };
+        self.smart_plugs.retain(|p| p.id != id_plug);
+        self.smart_plugs.push(plug);
+        relays.retain(|r| r.id != id_plug);
+        relays.push(dev);
+    }
+
     pub fn relay_sensor_trigger(
         &mut self,
         relays: &mut Vec<Device>,
@@ -1043,6 +1873,32 @@ impl RelayDevices {
             }
         }
     }
+
+    pub fn plug_sensor_trigger(
+        &mut self,
+        relays: &mut Vec<Device>,
+        state_machine: &mut StateMachine,
+        onewire: &OneWire,
+        associated_plugs: &Vec<i32>,
+        kind_code: &str,
+        on: bool,
+        night: bool,
+    ) {
+        for plug in &mut self.smart_plugs {
+            if let Some(dev) = relays.iter_mut().find(|d| d.id == plug.id) {
+                plug.sensor_trigger(
+                    dev,
+                    None,
+                    state_machine,
+                    Some(onewire),
+                    associated_plugs,
+                    kind_code,
+                    on,
+                    night,
+                );
+            }
+        }
+    }
 }
 
 pub struct CesspoolLevel {
@@ -1084,7 +1940,7 @@ impl fmt::Display for CesspoolLevel {
 
 pub struct StateMachine {
     pub name: String,
-    pub alarm_armed: bool,
+    pub alarm: Alarm,
     pub bedroom_mode: bool,
     pub wicket_gate_started: Option<Instant>,
     pub wicket_gate_delay: Option<Duration>,
@@ -1092,18 +1948,40 @@ pub struct StateMachine {
     pub ethlcd: Option<EthLcd>,
     pub rfid_tags: Arc<RwLock<Vec<RfidTag>>>,
     pub rfid_pending_tags: Arc<RwLock<Vec<u32>>>,
+    pub rfid_pending_pins: PendingPins,
+    pub keypad_lockouts: HashMap<String, PinLockout>, //per keypad
+    pub keypad_max_attempts: u32,
+    pub keypad_lockout: Duration,
     pub cesspool_level: CesspoolLevel,
     pub lcd_transmitter: Sender<LcdTask>,
     pub db_transmitter: Sender<DbTask>,
+    pub mqtt_transmitter: Sender<MqttEvent>,
+    pub events: EventSender,
+    pub circulation: Option<CirculationPump>,
+    pub mailbox: Option<Mailbox>,
+    pub adaptive_hold: Arc<RwLock<AdaptiveHold>>,
+    pub staircase_clicks: HashMap<i32, GestureDetector>, //double-click detection of the staircase switches
+    pub floor_off_pending: Vec<(Instant, String)>,
+    pub gestures: HashMap<i32, GestureDetector>,
+    pub rfid_uses: HashMap<i32, (NaiveDate, u32)>, //granted scans per tag, for max_uses_per_day
+    pub windows: HashMap<String, OpenWindows>,     //open windows per heating zone
+    pub window_delay: Duration,
+    pub window_cutback: Arc<RwLock<HashSet<String>>>,
+    pub gates: Vec<Gate>,
 }
 
 impl StateMachine {
     pub fn run_shell_command(cmd: String) {
+        StateMachine::run_shell_command_env(cmd, vec![]);
+    }
+
+    pub fn run_shell_command_env(cmd: String, envs: Vec<(String, String)>) {
         info!("StateMachine: about to call external command: {}", cmd);
         //we have a command and args in one string, split it by first space
         let mut args: Vec<&str> = cmd.splitn(2, " ").collect();
         let output = Command::new(args.remove(0))
             .args(args)
+            .envs(envs)
             .output()
             .expect("Error calling script");
         info!(
@@ -1128,6 +2006,43 @@ impl StateMachine {
         pending_tasks: &mut Vec<OneWireTask>,
         id_sensor: i32,
     ) -> bool {
+        //publish every sensor change
+        let _ = self.mqtt_transmitter.send(MqttEvent::new(
+            format!("sensor/{}", sensor_name),
+            if sensor_on { "on" } else { "off" },
+            true,
+        ));
+        events::publish(
+            &self.events,
+            Event::Sensor {
+                name: sensor_name.to_string(),
+                on: sensor_on,
+            },
+        );
+
+        //scenes triggered by the sensor
+        if !initial_read && sensor_on {
+            for name in Scene::from_tags(sensor_tags) {
+                info!(
+                    "{}: {}: 🎬 triggering scene {}",
+                    self.name, sensor_name, name
+                );
+                pending_tasks.push(Scene::task(&name));
+            }
+        }
+
+        //intrusion alarm zones
+        if !initial_read {
+            self.alarm.sensor_event(
+                sensor_name,
+                id_sensor,
+                sensor_tags,
+                sensor_on,
+                self.ethlcd.as_mut(),
+                pending_tasks,
+            );
+        }
+
         //bedroom mode handling during the night
         if !initial_read && sensor_kind_code == "PIR_Trigger" && sensor_on && night {
             for tag in sensor_tags {
@@ -1152,78 +2067,71 @@ impl StateMachine {
             }
         }
 
+        //staircase (multi-way) switches
+        let mut continue_processing = true;
+        if !initial_read
+            && sensor_kind_code == "Switch"
+            && sensor_tags.iter().any(|t| t == "staircase")
+        {
+            continue_processing =
+                self.staircase_hook(sensor_name, sensor_on, sensor_tags, id_sensor);
+        }
+
         //wicket gate mode opening
         //doing it in separate block as this tag has to be processed with highest priority
-        if !initial_read {
-            for tag in sensor_tags.iter().find(|&x| x.starts_with("wicket_gate")) {
-                //shadow the outer variable
-                let mut sensor_on = sensor_on;
-                //check for inverted sensor logic
-                if tag.contains("invert_state") {
-                    sensor_on = !sensor_on;
-                }
-                if sensor_on {
-                    match self.wicket_gate_started {
-                        Some(started) => {
-                            match self.wicket_gate_delay {
-                                Some(delay) => {
-                                    self.wicket_gate_started = None; //processed => clear
-                                    if started.elapsed() < delay {
-                                        info!("{}: opening wicket gate", self.name);
-                                        for id_relay in &self.wicket_gate_relays {
-                                            let new_task = OneWireTask {
-                                                command: TaskCommand::TurnOnProlong,
-                                                id_relay: Some(*id_relay),
-                                                tag_group: None,
-                                                id_yeelight: None,
-                                                duration: None,
-                                            };
-                                            pending_tasks.push(new_task);
-                                        }
-
-                                        //confirmation beep
-                                        match self.ethlcd.as_mut() {
-                                            Some(ethlcd) => {
-                                                ethlcd.async_beep(BeepMethod::Confirmation)
-                                            }
-                                            _ => {}
-                                        }
+        if !initial_read && sensor_on && sensor_tags.iter().any(|x| x.starts_with("wicket_gate")) {
+            match self.wicket_gate_started {
+                Some(started) => {
+                    match self.wicket_gate_delay {
+                        Some(delay) => {
+                            self.wicket_gate_started = None; //processed => clear
+                            if started.elapsed() < delay {
+                                info!("{}: opening wicket gate", self.name);
+                                for id_relay in &self.wicket_gate_relays {
+                                    let new_task = OneWireTask {
+                                        command: TaskCommand::TurnOnProlong,
+                                        id_relay: Some(*id_relay),
+                                        tag_group: None,
+                                        id_yeelight: None,
+                                        id_plug: None,
+                                        duration: None,
+                                    };
+                                    pending_tasks.push(new_task);
+                                }
 
-                                        if night {
-                                            info!("{}: turning on entry lights...", self.name);
-                                            let new_task = OneWireTask {
-                                                command: TaskCommand::TurnOnProlongNight,
-                                                id_relay: None,
-                                                tag_group: Some("entry_light".to_owned()),
-                                                id_yeelight: None,
-                                                duration: Some(Duration::from_secs_f32(
-                                                    ENTRY_LIGHT_PROLONG_SECS,
-                                                )),
-                                            };
-                                            pending_tasks.push(new_task);
-                                        }
+                                //confirmation beep
+                                match self.ethlcd.as_mut() {
+                                    Some(ethlcd) => ethlcd.async_beep(BeepMethod::Confirmation),
+                                    _ => {}
+                                }
 
-                                        return false; //stop further processing this sensor
-                                    }
+                                if night {
+                                    info!("{}: turning on entry lights...", self.name);
+                                    let new_task = OneWireTask {
+                                        command: TaskCommand::TurnOnProlongNight,
+                                        id_relay: None,
+                                        tag_group: Some("entry_light".to_owned()),
+                                        id_yeelight: None,
+                                        id_plug: None,
+                                        duration: Some(Duration::from_secs_f32(
+                                            ENTRY_LIGHT_PROLONG_SECS,
+                                        )),
+                                    };
+                                    pending_tasks.push(new_task);
                                 }
-                                _ => {}
+
+                                return false; //stop further processing this sensor
                             }
                         }
                         _ => {}
                     }
                 }
+                _ => {}
             }
         }
 
         //processing other tags
         for tag in sensor_tags {
-            //shadow the outer variable
-            let mut sensor_on = sensor_on;
-            //check for inverted sensor logic
-            if tag.contains("invert_state") {
-                sensor_on = !sensor_on;
-            }
-
             //if the sensor is tagged with 'monitor_in_influxdb' we are saving
             //all changes to influx for such sensor
             if tag.starts_with("monitor_in_influxdb") {
@@ -1261,6 +2169,22 @@ impl StateMachine {
                         _ => (),
                     };
                 }
+                //hot water demand => run the circulation pump
+                else if sensor_on && CirculationPump::is_demand_tag(tag) {
+                    if let Some(circulation) = self.circulation.as_mut() {
+                        circulation.demand(pending_tasks, sensor_name, night);
+                    }
+                }
+                //mailbox lid / collecting door
+                else if sensor_on && (tag == MAILBOX_TAG || tag == MAILBOX_DOOR_TAG) {
+                    if let Some(mailbox) = self.mailbox.as_mut() {
+                        if tag == MAILBOX_TAG {
+                            mailbox.opened(&self.lcd_transmitter, sensor_name);
+                        } else {
+                            mailbox.collected(&self.lcd_transmitter, sensor_name);
+                        }
+                    }
+                }
                 //doorbell => make a beep using ethlcd device
                 else if self.ethlcd.is_some() && tag.starts_with("doorbell") {
                     self.ethlcd
@@ -1270,6 +2194,21 @@ impl StateMachine {
                 }
             }
 
+            //window/door of a heating zone
+            if let Some(zone) = tag.strip_prefix(WINDOW_TAG_PREFIX) {
+                self.window_hook(zone, sensor_name, sensor_on);
+            }
+
+            //gate/garage door end position
+            if let Some(gate_tag) = tag.strip_prefix(GATE_TAG_PREFIX) {
+                let mut v = gate_tag.splitn(2, ':');
+                let (name, kind) = (v.next().unwrap_or_default(), v.next().unwrap_or_default());
+                match self.gates.iter_mut().find(|g| g.name == name) {
+                    Some(gate) => gate.sensor(kind, sensor_on),
+                    None => warn!("{}: {}: unknown gate: {}", self.name, sensor_name, name),
+                }
+            }
+
             //cesspool level sensor
             if tag.starts_with("cesspool") {
                 let v: Vec<&str> = tag.split(":").collect();
@@ -1299,6 +2238,18 @@ impl StateMachine {
                                     value: Some(self.cesspool_level.get_level_percentage() as i32),
                                 };
                                 let _ = self.db_transmitter.send(task);
+
+                                let _ = self.mqtt_transmitter.send(MqttEvent::new(
+                                    "cesspool/level",
+                                    self.cesspool_level.get_level_percentage(),
+                                    true,
+                                ));
+                                events::publish(
+                                    &self.events,
+                                    Event::CesspoolLevel {
+                                        level: self.cesspool_level.get_level_percentage(),
+                                    },
+                                );
                             }
                         }
                         Err(_) => (),
@@ -1308,9 +2259,179 @@ impl StateMachine {
             }
         }
 
+        continue_processing
+    }
+
+    fn staircase_hook(
+        &mut self,
+        sensor_name: &str,
+        sensor_on: bool,
+        sensor_tags: &Vec<String>,
+        id_sensor: i32,
+    ) -> bool {
+        //staircase switches are momentary push-buttons: only the press is toggling
+        //the relay, so many buttons can drive the same relay and the relay state
+        //itself is the shared state between them, without a floor there is no double-click
+        let floor = match sensor_tags.iter().find(|t| t.starts_with("floor:")) {
+            Some(floor) => floor,
+            None => return sensor_on,
+        };
+        let clicks = self.staircase_clicks.entry(id_sensor).or_insert_with(|| {
+            GestureDetector::new(
+                id_sensor,
+                sensor_name.to_string(),
+                sensor_tags.clone(),
+                vec![],
+                vec![],
+            )
+        });
+        if !sensor_on {
+            clicks.immediate_release();
+            return false;
+        }
+
+        //double-click: turn off everything on the same floor
+        if clicks.immediate_press() == Gesture::Double {
+            info!(
+                "{}: {}: ⏬ double-click, turning off all devices on {}",
+                self.name, sensor_name, floor
+            );
+            //the first click just toggled a relay, so wait for flip-flop protection
+            self.floor_off_pending.push((
+                Instant::now() + Duration::from_secs_f32(MIN_TOGGLE_DELAY_SECS),
+                floor.clone(),
+            ));
+            return false;
+        }
+
         true
     }
 
+    fn process_floor_off(&mut self, pending_tasks: &mut Vec<OneWireTask>) {
+        let now = Instant::now();
+        for (_, floor) in self.floor_off_pending.iter().filter(|(due, _)| *due <= now) {
+            let new_task = OneWireTask {
+                command: TaskCommand::TurnOff,
+                id_relay: None,
+                tag_group: Some(floor.clone()),
+                id_yeelight: None,
+                id_plug: None,
+                duration: None,
+            };
+            pending_tasks.push(new_task);
+        }
+        self.floor_off_pending.retain(|(due, _)| *due > now);
+    }
+
+    fn window_hook(&mut self, zone: &str, sensor_name: &str, open: bool) {
+        let windows = self.windows.entry(zone.to_string()).or_default();
+        if windows.update(sensor_name, open) {
+            info!(
+                "{}: 🪟 {}: all windows closed, restoring the heating",
+                self.name, zone
+            );
+            self.window_cutback.write().unwrap().remove(zone);
+        }
+    }
+
+    //heating cutback of the zones with a window open for too long
+    fn process_windows(&mut self) {
+        for (zone, windows) in self.windows.iter_mut() {
+            if windows.check(self.window_delay) {
+                info!(
+                    "{}: 🪟 {}: window open for {} ({:?}), heating cutback",
+                    self.name,
+                    zone,
+                    format_duration(self.window_delay),
+                    windows.open,
+                );
+                self.window_cutback.write().unwrap().insert(zone.clone());
+            }
+        }
+    }
+
+    fn gesture_hook(
+        &mut self,
+        sensor: &Sensor,
+        sensor_on: bool,
+        pending_tasks: &mut Vec<OneWireTask>,
+    ) {
+        let detector = self.gestures.entry(sensor.id_sensor).or_insert_with(|| {
+            GestureDetector::new(
+                sensor.id_sensor,
+                sensor.name.clone(),
+                sensor.tags.clone(),
+                sensor.associated_relays.clone(),
+                sensor.associated_yeelights.clone(),
+            )
+        });
+        if sensor_on {
+            detector.press();
+        } else if let Some(gesture) = detector.release() {
+            StateMachine::gesture_tasks(&self.name, detector, gesture, pending_tasks);
+        }
+    }
+
+    fn process_gestures(&mut self, pending_tasks: &mut Vec<OneWireTask>) {
+        for detector in self.gestures.values_mut() {
+            if let Some(gesture) = detector.poll() {
+                StateMachine::gesture_tasks(&self.name, detector, gesture, pending_tasks);
+            }
+        }
+    }
+
+    fn gesture_tasks(
+        name: &str,
+        detector: &GestureDetector,
+        gesture: Gesture,
+        pending_tasks: &mut Vec<OneWireTask>,
+    ) {
+        info!(
+            "{}: {}: 👆 {:?} press detected",
+            name, detector.name, gesture
+        );
+        match gesture {
+            Gesture::Short => {
+                for id_relay in &detector.associated_relays {
+                    pending_tasks.push(OneWireTask {
+                        command: TaskCommand::Toggle,
+                        id_relay: Some(*id_relay),
+                        tag_group: None,
+                        id_yeelight: None,
+                        id_plug: None,
+                        duration: None,
+                    });
+                }
+                for id_yeelight in &detector.associated_yeelights {
+                    pending_tasks.push(OneWireTask {
+                        command: TaskCommand::Toggle,
+                        id_relay: None,
+                        tag_group: None,
+                        id_yeelight: Some(*id_yeelight),
+                        id_plug: None,
+                        duration: None,
+                    });
+                }
+            }
+            Gesture::Long | Gesture::Double => {
+                if let Some(tag_group) = detector.get_target(gesture) {
+                    pending_tasks.push(OneWireTask {
+                        command: if gesture == Gesture::Long {
+                            TaskCommand::TurnOnProlong
+                        } else {
+                            TaskCommand::TurnOff
+                        },
+                        id_relay: None,
+                        tag_group: Some(tag_group),
+                        id_yeelight: None,
+                        id_plug: None,
+                        duration: None,
+                    });
+                }
+            }
+        }
+    }
+
     fn device_hook(
         &mut self,
         sensor_kind_code: &str,
@@ -1349,107 +2470,354 @@ impl StateMachine {
         true
     }
 
+    //access log entry of a scanned tag
+    fn rfid_scanned(&self, id_tag: u32, name: Option<String>, action: String) {
+        let task = DbTask {
+            command: CommandCode::RfidScanned(RfidScan {
+                time: SystemTime::now(),
+                id_tag,
+                name,
+                action,
+            }),
+            value: None,
+        };
+        let _ = self.db_transmitter.send(task);
+    }
+
     fn process_rfid_tags(&mut self, pending_tasks: &mut Vec<OneWireTask>, night: bool) {
-        let rfid_tags = self.rfid_tags.read().unwrap();
-        let mut rfid_pending_tags = self.rfid_pending_tags.write().unwrap();
-        if !rfid_pending_tags.is_empty() {
-            //todo
-            for id in rfid_pending_tags.iter() {
-                debug!("{}: rfid_pending_tags: {:?}", self.name, id);
-                for rfid_tag in rfid_tags.iter().find(|&x| x.id_tag as u32 == *id) {
+        let rfid_pending_tags = std::mem::take(&mut *self.rfid_pending_tags.write().unwrap());
+        let rfid_pending_pins = std::mem::take(&mut *self.rfid_pending_pins.write().unwrap());
+        if rfid_pending_tags.is_empty() && rfid_pending_pins.is_empty() {
+            return;
+        }
+        let rfid_tags_lock = self.rfid_tags.clone();
+        let rfid_tags = rfid_tags_lock.read().unwrap();
+        for id in rfid_pending_tags {
+            debug!("{}: rfid_pending_tags: {:?}", self.name, id);
+            match rfid_tags.iter().find(|&x| x.id_tag as u32 == id) {
+                Some(rfid_tag) => {
                     info!("{}: 🆔 matched rfid_tag: {:?}", self.name, rfid_tag.name);
+                    self.rfid_tag_used(rfid_tag, false, pending_tasks, night);
+                }
+                None => {
+                    warn!("{}: 🆔 unknown rfid tag: {}", self.name, id);
+                    self.rfid_scanned(id, None, "unknown".to_string());
+                }
+            }
+        }
+        for (keypad, pin) in rfid_pending_pins {
+            self.process_pin(&rfid_tags, &keypad, &pin, pending_tasks, night);
+        }
+    }
 
-                    if !rfid_tag.tags.is_empty() {
-                        //handle tags
-                        for tag in &rfid_tag.tags {
-                            //handle wicket_gate mode
-                            if tag.starts_with("wicket_gate") {
-                                let v: Vec<&str> = tag.split(":").collect();
-                                match v.get(1) {
-                                    Some(&delay_str) => {
-                                        match delay_str.parse::<f32>() {
-                                            Ok(val) => {
-                                                let delay = Duration::from_secs_f32(val);
-                                                self.wicket_gate_started = Some(Instant::now());
-                                                self.wicket_gate_delay = Some(delay);
-                                                self.wicket_gate_relays =
-                                                    rfid_tag.associated_relays.clone();
-                                                info!(
-                                                    "{}: ⏹️ enabling wicket gate mode for {:?}",
-                                                    self.name, delay
-                                                );
+    //PIN entered on the keypad, wrong ones are counted for the lockout of this keypad
+    fn process_pin(
+        &mut self,
+        rfid_tags: &[RfidTag],
+        keypad: &str,
+        pin: &str,
+        pending_tasks: &mut Vec<OneWireTask>,
+        night: bool,
+    ) {
+        let lockout = self.keypad_lockouts.entry(keypad.to_string()).or_default();
+        if let Some(until) = lockout.locked_until {
+            if Instant::now() < until {
+                warn!("{}: 🔒 {} locked out, PIN ignored", self.name, keypad);
+                self.rfid_scanned(0, None, "pin denied: locked out".to_string());
+                return;
+            }
+            lockout.locked_until = None;
+        }
+        //all tags are checked, so the time doesn't depend on the matching one
+        let matched = rfid_tags.iter().fold(None, |matched, tag| {
+            let verified = tag.pin.as_ref().map_or(false, |hash| hash.verify(pin));
+            if verified && matched.is_none() {
+                Some(tag)
+            } else {
+                matched
+            }
+        });
+        match matched {
+            Some(rfid_tag) => {
+                info!(
+                    "{}: 🔢 PIN matched rfid_tag: {:?}",
+                    self.name, rfid_tag.name
+                );
+                lockout.failures = 0;
+                self.rfid_tag_used(rfid_tag, true, pending_tasks, night);
+            }
+            None => {
+                lockout.failures += 1;
+                warn!(
+                    "{}: 🔢 wrong PIN on {}, attempt {}/{}",
+                    self.name, keypad, lockout.failures, self.keypad_max_attempts
+                );
+                let mut action = format!(
+                    "pin denied: wrong pin ({}/{})",
+                    lockout.failures, self.keypad_max_attempts
+                );
+                if lockout.failures >= self.keypad_max_attempts {
+                    warn!(
+                        "{}: 🔒 {} locked out for {}",
+                        self.name,
+                        keypad,
+                        format_duration(self.keypad_lockout)
+                    );
+                    lockout.locked_until = Some(Instant::now() + self.keypad_lockout);
+                    lockout.failures = 0;
+                    action.push_str(", locked out");
+                }
+                self.rfid_scanned(0, None, action);
+            }
+        }
+    }
 
-                                                //confirmation beep
-                                                match self.ethlcd.as_mut() {
-                                                    Some(ethlcd) => {
-                                                        ethlcd.async_beep(BeepMethod::Confirmation)
-                                                    }
-                                                    _ => {}
-                                                }
+    //checks the tag restrictions and runs the tag actions
+    fn rfid_tag_used(
+        &mut self,
+        rfid_tag: &RfidTag,
+        pin: bool,
+        pending_tasks: &mut Vec<OneWireTask>,
+        night: bool,
+    ) {
+        let prefix = if pin { "pin " } else { "" };
+        let now = Local::now();
+        let today = now.date_naive();
+        let uses_today = match self.rfid_uses.get(&rfid_tag.id_tag) {
+            Some((day, uses)) if *day == today => *uses,
+            _ => 0,
+        };
+        if let Some(reason) = rfid_tag.restrictions.check(now, uses_today) {
+            warn!(
+                "{}: 🆔 rfid tag {:?} denied: {}",
+                self.name, rfid_tag.name, reason
+            );
+            self.rfid_scanned(
+                rfid_tag.id_tag as u32,
+                Some(rfid_tag.name.clone()),
+                format!("{}denied: {}", prefix, reason),
+            );
+            return;
+        }
+        self.rfid_uses
+            .insert(rfid_tag.id_tag, (today, uses_today + 1));
+        let mut actions: Vec<String> = vec![];
+
+        if !rfid_tag.tags.is_empty() {
+            //handle tags
+            for tag in &rfid_tag.tags {
+                //scene triggered by the tag
+                if let Some(name) = Scene::from_tag(tag) {
+                    info!("{}: 🎬 triggering scene {}", self.name, name);
+                    pending_tasks.push(Scene::task(name));
+                    actions.push(format!("scene:{}", name));
+                }
+                //arming/disarming the intrusion alarm
+                if tag == ALARM_RFID_TAG {
+                    self.alarm.toggle(&format!("rfid tag {}", rfid_tag.name));
+                    actions.push("alarm_toggle".to_string());
+                }
+                //handle wicket_gate mode
+                if tag.starts_with("wicket_gate") {
+                    let v: Vec<&str> = tag.split(":").collect();
+                    match v.get(1) {
+                        Some(&delay_str) => {
+                            match delay_str.parse::<f32>() {
+                                Ok(val) => {
+                                    let delay = Duration::from_secs_f32(val);
+                                    self.wicket_gate_started = Some(Instant::now());
+                                    self.wicket_gate_delay = Some(delay);
+                                    self.wicket_gate_relays = rfid_tag.associated_relays.clone();
+                                    actions.push("wicket_gate".to_string());
+                                    info!(
+                                        "{}: ⏹️ enabling wicket gate mode for {:?}",
+                                        self.name, delay
+                                    );
 
-                                                if night {
-                                                    info!(
-                                                        "{}: 🏡 turning on entry lights...",
-                                                        self.name
-                                                    );
-                                                    let new_task = OneWireTask {
-                                                        command: TaskCommand::TurnOnProlongNight,
-                                                        id_relay: None,
-                                                        tag_group: Some("entry_light".to_owned()),
-                                                        id_yeelight: None,
-                                                        duration: Some(Duration::from_secs_f32(
-                                                            ENTRY_LIGHT_PROLONG_SECS,
-                                                        )),
-                                                    };
-                                                    pending_tasks.push(new_task);
-                                                }
-                                            }
-                                            Err(e) => {
-                                                error!("{}: delay parse error: {:?}", self.name, e);
-                                            }
-                                        }
+                                    //confirmation beep
+                                    match self.ethlcd.as_mut() {
+                                        Some(ethlcd) => ethlcd.async_beep(BeepMethod::Confirmation),
+                                        _ => {}
                                     }
-                                    None => {
-                                        error!(
-                                            "{}: wicket gate mode: missing delay parameter",
-                                            self.name
-                                        );
+
+                                    if night {
+                                        info!("{}: 🏡 turning on entry lights...", self.name);
+                                        let new_task = OneWireTask {
+                                            command: TaskCommand::TurnOnProlongNight,
+                                            id_relay: None,
+                                            tag_group: Some("entry_light".to_owned()),
+                                            id_yeelight: None,
+                                            id_plug: None,
+                                            duration: Some(Duration::from_secs_f32(
+                                                ENTRY_LIGHT_PROLONG_SECS,
+                                            )),
+                                        };
+                                        pending_tasks.push(new_task);
                                     }
-                                };
+                                }
+                                Err(e) => {
+                                    error!("{}: delay parse error: {:?}", self.name, e);
+                                }
                             }
                         }
-                    } else {
-                        //turn on associated relay
-                        for id_relay in &rfid_tag.associated_relays {
-                            info!("{}: 🔗 associated relay: {:?}", self.name, id_relay);
-                            let new_task = OneWireTask {
-                                command: TaskCommand::TurnOnProlong,
-                                id_relay: Some(*id_relay),
-                                tag_group: None,
-                                id_yeelight: None,
-                                duration: None,
-                            };
-                            pending_tasks.push(new_task);
+                        None => {
+                            error!("{}: wicket gate mode: missing delay parameter", self.name);
                         }
-                    }
+                    };
                 }
             }
-            rfid_pending_tags.clear();
+        } else {
+            //turn on associated relay
+            for id_relay in &rfid_tag.associated_relays {
+                info!("{}: 🔗 associated relay: {:?}", self.name, id_relay);
+                let new_task = OneWireTask {
+                    command: TaskCommand::TurnOnProlong,
+                    id_relay: Some(*id_relay),
+                    tag_group: None,
+                    id_yeelight: None,
+                    id_plug: None,
+                    duration: None,
+                };
+                pending_tasks.push(new_task);
+                actions.push(format!("relay:{}", id_relay));
+            }
+        }
+        if actions.is_empty() {
+            actions.push("none".to_string());
         }
+        if actions.is_empty() {
+            actions.push("none".to_string());
+        }
+        self.rfid_scanned(
+            rfid_tag.id_tag as u32,
+            Some(rfid_tag.name.clone()),
+            format!("{}{}", prefix, actions.join(",")),
+        );
     }
 }
 
 pub struct OneWire {
     pub name: String,
+    pub config: Arc<Config>,
     pub transmitter: Sender<DbTask>,
     pub ow_receiver: Receiver<OneWireTask>,
     pub lcd_transmitter: Sender<LcdTask>,
+    pub mqtt_transmitter: Sender<MqttEvent>,
     pub sensor_devices: Arc<RwLock<SensorDevices>>,
     pub relay_devices: Arc<RwLock<RelayDevices>>,
     pub relays: Arc<RwLock<Relays>>,
+    pub sensor_board_stale: Option<Duration>,
+    pub watchdog_script: Option<String>,
+    pub command_min_interval_ms: Option<u64>,
+    pub bus_metrics: Arc<RwLock<BusMetrics>>,
+    pub sensor_values: Arc<RwLock<SensorValues>>,
+    pub latency: Arc<RwLock<LatencyMetrics>>,
+    //sensor edge (read start, read time) of the current switching, also read by the devices
+    pub edge_time: Mutex<Option<(Instant, Duration)>>,
+    pub yeelight_requests: Arc<Mutex<Vec<YeelightRequest>>>,
+    pub adaptive_hold: Arc<RwLock<AdaptiveHold>>,
+    pub adaptive_hold_file: Option<String>,
+    pub energy_costs: Arc<RwLock<EnergyCosts>>,
+    pub window_cutback: Arc<RwLock<HashSet<String>>>,
+    pub events: EventSender,
+    pub notify_transmitter: Sender<Notification>,
+    pub errors: ErrorReporter,
 }
 
-impl OneWire {
+impl OneWire {
+    pub fn edge(&self) -> Option<(Instant, Duration)> {
+        *self.edge_time.lock().unwrap()
+    }
+
+    fn set_edge(&self, edge: Option<(Instant, Duration)>) {
+        *self.edge_time.lock().unwrap() = edge;
+    }
+
+    fn run_watchdog_script(&self, name: String, state: &str) {
+        if let Some(ref cmd) = self.watchdog_script {
+            let mut cmd = cmd.clone();
+            cmd = str::replace(&cmd, "%name%", &name);
+            cmd = str::replace(&cmd, "%state%", state);
+            thread::spawn(move || StateMachine::run_shell_command(cmd));
+        }
+    }
+
+    fn update_sensor_value(&self, name: &String, on: bool) {
+        if let Ok(mut values) = self.sensor_values.write() {
+            values.insert(name.clone(), if on { 1.0 } else { 0.0 });
+        }
+    }
+
+    //iteration over all boards that has changed state and needs a save_state()
+    fn save_changed_relay_boards(
+        &self,
+        relay_boards: &mut Vec<RelayBoard>,
+        relays: &mut Vec<Device>,
+    ) {
+        for rb in relay_boards {
+            match rb.new_value {
+                Some(new_value) => {
+                    let old_value = rb.last_value.unwrap_or(DS2408_INITIAL_STATE);
+                    if new_value != old_value {
+                        //checking all changed bits (relays) and set last_toggled Instant
+                        for i in 0..=7 {
+                            if new_value & (1 << i as u8) != old_value & (1 << i as u8) {
+                                match rb.relay[i] {
+                                    Some(id) => {
+                                        let r = relays.iter_mut().find(|r| r.id == id);
+                                        match r {
+                                            Some(relay) => {
+                                                relay.last_toggled = Some(Instant::now());
+                                                self.increment_relay_counter(id);
+                                                //bit set means relay is off
+                                                let on = new_value & (1 << i as u8) == 0;
+                                                let _ = self.mqtt_transmitter.send(MqttEvent::new(
+                                                    format!("relay/{}", relay.name),
+                                                    if on { "on" } else { "off" },
+                                                    true,
+                                                ));
+                                                events::publish(
+                                                    &self.events,
+                                                    Event::Relay {
+                                                        name: relay.name.clone(),
+                                                        on,
+                                                    },
+                                                );
+                                                self.energy_costs.write().unwrap().relay_switched(
+                                                    relay.id,
+                                                    &relay.name,
+                                                    &relay.tags,
+                                                    on,
+                                                );
+                                                self.device_switched(
+                                                    DeviceKind::Relay,
+                                                    relay.id,
+                                                    on,
+                                                );
+                                            }
+                                            None => (),
+                                        }
+                                    }
+                                    _ => {}
+                                }
+                            }
+                        }
+                        rb.save_state();
+                        if let Some((edge, read_time)) = self.edge() {
+                            self.latency.write().unwrap().observe(
+                                "relay",
+                                &get_w1_device_name(rb.ow_family, rb.ow_address),
+                                read_time,
+                                edge.elapsed(),
+                            );
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
     fn increment_relay_counter(&self, id_relay: i32) {
         let task = DbTask {
             command: CommandCode::IncrementRelayCounter,
@@ -1466,29 +2834,30 @@ impl OneWire {
         let _ = self.transmitter.send(task);
     }
 
-    fn load_geolocation_config(&self, lat: &mut f64, lon: &mut f64) {
-        let conf = Ini::load_from_file("hard.conf").expect("Cannot open config file");
-        let section = conf
-            .section(Some("general".to_owned()))
-            .expect("Cannot find general section in config");
-        *lat = section
-            .get("lat")
-            .unwrap_or(&"0.0".to_owned())
-            .parse()
-            .unwrap_or_default();
-        *lon = section
-            .get("lon")
-            .unwrap_or(&"0.0".to_owned())
-            .parse()
-            .unwrap_or_default();
-    }
-
-    pub fn worker(
+    //on/off timestamp for the on-time accounting
+    fn device_switched(&self, kind: DeviceKind, id: i32, on: bool) {
+        let task = DbTask {
+            command: CommandCode::DeviceSwitched {
+                kind,
+                on,
+                time: SystemTime::now(),
+            },
+            value: Some(id),
+        };
+        let _ = self.transmitter.send(task);
+    }
+
+    pub async fn worker(
         &self,
         worker_cancel_flag: Arc<AtomicBool>,
         ethlcd: Option<EthLcd>,
         rfid_tags: Arc<RwLock<Vec<RfidTag>>>,
         rfid_pending_tags: Arc<RwLock<Vec<u32>>>,
+        rfid_pending_pins: PendingPins,
+        circulation: Option<CirculationPump>,
+        mailbox: Option<Mailbox>,
+        gates: Vec<Gate>,
+        alarm: Alarm,
     ) {
         info!("{}: Starting thread", self.name);
 
@@ -1505,7 +2874,7 @@ impl OneWire {
 
         let mut state_machine = StateMachine {
             name: "statemachine".to_owned(),
-            alarm_armed: false,
+            alarm,
             bedroom_mode: false,
             wicket_gate_started: None,
             wicket_gate_delay: None,
@@ -1513,19 +2882,36 @@ impl OneWire {
             ethlcd,
             rfid_tags,
             rfid_pending_tags,
+            rfid_pending_pins,
+            keypad_lockouts: HashMap::new(),
+            keypad_max_attempts: self.config.keypad.max_attempts,
+            keypad_lockout: self.config.keypad.lockout,
             cesspool_level: CesspoolLevel { level: vec![] },
             lcd_transmitter: self.lcd_transmitter.clone(),
             db_transmitter: self.transmitter.clone(),
+            mqtt_transmitter: self.mqtt_transmitter.clone(),
+            events: self.events.clone(),
+            circulation,
+            mailbox,
+            adaptive_hold: self.adaptive_hold.clone(),
+            staircase_clicks: HashMap::new(),
+            floor_off_pending: vec![],
+            gestures: HashMap::new(),
+            rfid_uses: HashMap::new(),
+            windows: HashMap::new(),
+            window_delay: self.config.heating.window_delay,
+            window_cutback: self.window_cutback.clone(),
+            gates,
         };
 
         let mut pending_tasks = vec![];
+        let mut governor = CommandGovernor::new(self.command_min_interval_ms);
 
         //geo location for sun calculation
-        let mut lat: f64 = 0.0;
-        let mut lon: f64 = 0.0;
+        let lat = self.config.general.lat;
+        let lon = self.config.general.lon;
         let mut night_check = None;
         let mut night = false;
-        self.load_geolocation_config(&mut lat, &mut lon);
         if lat != 0.0 && lon != 0.0 {
             night_check = Some(Instant::now());
             info!(
@@ -1534,41 +2920,113 @@ impl OneWire {
             );
         }
 
+        //relay schedules
+        let mut schedule_check = Instant::now();
+        let mut sun_times = SunTimes::new();
+        let mut cron_check = Instant::now();
+
+        //day/night state of relays with their own all_night offsets
+        let mut twilight_times = HashMap::new();
+        let mut relay_night_state: HashMap<i32, bool> = HashMap::new();
+
+        //1-wire bus failure state
+        let mut bus_failure = false;
+        let mut bus_watchdog = BusWatchdog::new(
+            &self.config.bus_watchdog,
+            self.watchdog_script.clone(),
+            self.notify_transmitter.clone(),
+        );
+        let mut relay_verify = Instant::now();
+        let mut metrics_time = Instant::now();
+        let mut virtual_check = Instant::now();
+
         let bits = vec![0, 2];
         let names = &["PIOA", "PIOB"];
 
+        //last loop iteration processes all queued tasks before exit
+        let mut shutdown = false;
+        let mut next_scan = Instant::now();
+
         loop {
+            if shutdown {
+                break;
+            }
+
+            //wait for a task or the next bus scan, whichever comes first
+            let first_task = tokio::select! {
+                t = self.ow_receiver.recv() => t,
+                _ = tokio::time::sleep_until(next_scan.into()) => None,
+            };
             let loop_start = Instant::now();
+            next_scan = loop_start + self.config.general.onewire_loop_interval;
             if worker_cancel_flag.load(Ordering::SeqCst) {
                 debug!("Got terminate signal from main");
-                break;
+                info!(
+                    "{}: 🏁 processing the queued tasks before exit...",
+                    self.name
+                );
+                shutdown = true;
             }
-
-            //checking for external relay tasks
-            //fixme: read all tasks, not a single one at a call
-            match self.ow_receiver.try_recv() {
-                Ok(mut t) => {
-                    debug!(
-                        "Received OneWireTask: id_relay: {:?}, tag_group: {:?}, duration: {:?}",
-                        t.id_relay, t.tag_group, t.duration
-                    );
-                    match t.command {
-                        TaskCommand::TurnOnProlongNight => {
-                            if night {
-                                //change to normal prolong command
-                                t.command = TaskCommand::TurnOnProlong;
-                                pending_tasks.push(t);
-                            }
-                        }
-                        _ => {
-                            pending_tasks.push(t);
+            systemd::heartbeat(&self.name);
+
+            //processing all queued external relay tasks
+            let queued = std::iter::from_fn(|| self.ow_receiver.try_recv().ok());
+            for mut t in first_task.into_iter().chain(queued) {
+                debug!(
+                    "Received OneWireTask: id_relay: {:?}, tag_group: {:?}, duration: {:?}",
+                    t.id_relay, t.tag_group, t.duration
+                );
+                match t.command {
+                    TaskCommand::TurnOnProlongNight => {
+                        if night {
+                            //change to normal prolong command
+                            t.command = TaskCommand::TurnOnProlong;
+                            governor.submit(t, &mut pending_tasks);
                         }
                     }
+                    //scenes are expanded and applied as a whole, not rate-limited
+                    TaskCommand::Scene(_) => pending_tasks.push(t),
+                    //shutter movements are tracked by the shutters themselves
+                    TaskCommand::Shutter(..) => pending_tasks.push(t),
+                    //gate pulses are sent by the gates after checking their state
+                    TaskCommand::Gate(..) => pending_tasks.push(t),
+                    _ => {
+                        governor.submit(t, &mut pending_tasks);
+                    }
                 }
-                _ => (),
+            }
+            if shutdown {
+                governor.flush(&mut pending_tasks);
+            } else {
+                governor.poll(&mut pending_tasks);
             }
 
             debug!("doing stuff");
+
+            //read all boards concurrently, waiting for the results without holding the locks,
+            //a board slower than the timeout is left reading in the background
+            let read_start = Instant::now();
+            let waits: Vec<_> = {
+                let mut sensor_dev = self.sensor_devices.write().unwrap();
+                sensor_dev
+                    .sensor_boards
+                    .iter_mut()
+                    .map(|sb| {
+                        if let Err(e) = sb.start_read() {
+                            self.errors.report(&e);
+                        }
+                        ((sb.ow_family, sb.ow_address), sb.read_wait())
+                    })
+                    .collect()
+            };
+            let deadline = read_start + self.config.general.sensor_read_timeout;
+            let mut received: HashMap<_, _> = waits
+                .into_iter()
+                .filter_map(|(board, wait)| {
+                    wait.map(|wait| (board, (wait.wait(deadline), read_start.elapsed())))
+                })
+                .collect();
+
             {
                 let mut sensor_dev = self.sensor_devices.write().unwrap();
                 let mut relay_dev = self.relay_devices.write().unwrap();
@@ -1585,14 +3043,36 @@ impl OneWire {
                 //fixme: do we really need to clone this HashMap to use it below?
                 let kinds_cloned = sensor_dev.kinds.clone();
 
-                for sb in &mut sensor_dev.sensor_boards {
-                    match sb.read_state() {
+                //the results are processed in the bus order, the boards reloaded meanwhile
+                //have no read running and are read in the next loop
+                let reads: Vec<_> = sensor_dev
+                    .sensor_boards
+                    .iter_mut()
+                    .map(|sb| match received.remove(&(sb.ow_family, sb.ow_address)) {
+                        Some((result, read_time)) => {
+                            (sb.finish_read(result), read_start, read_time)
+                        }
+                        None => (sb.finish_read(None), read_start, read_start.elapsed()),
+                    })
+                    .collect();
+
+                for (sb, (state, read_start, read_time)) in
+                    sensor_dev.sensor_boards.iter_mut().zip(reads)
+                {
+                    if let BoardRead::Failed(Some(e)) = &state {
+                        self.errors.report(e);
+                    }
+                    sb.watchdog(&state, self.sensor_board_stale);
+                    let state = state.value().map(|value| sb.debounce(value));
+                    match state {
                         //we have a read value to process
                         Some(new_value) => {
                             match sb.last_value {
                                 Some(last_value) => {
                                     //we have last value to compare with
                                     if last_value != new_value {
+                                        //start of the end-to-end switching latency measurement
+                                        self.set_edge(Some((read_start, read_time)));
                                         debug!(
                                             "{}: change detected, old: {:#04x} new: {:#04x}",
                                             get_w1_device_name(sb.ow_family, sb.ow_address),
@@ -1628,6 +3108,7 @@ impl OneWire {
                                                             .get(&sensor.id_kind)
                                                             .unwrap();
                                                         let on: bool = new_value & (1 << bit) != 0;
+                                                        self.update_sensor_value(&sensor.name, on);
 
                                                         //check hook function result and stop processing when needed
                                                         let stop_processing = !state_machine
@@ -1658,6 +3139,20 @@ impl OneWire {
                                                             continue;
                                                         }
 
+                                                        //switches with gesture mappings are handled by the detector
+                                                        if kind_code == "Switch"
+                                                            && GestureDetector::is_gesture_sensor(
+                                                                &sensor.tags,
+                                                            )
+                                                        {
+                                                            state_machine.gesture_hook(
+                                                                sensor,
+                                                                on,
+                                                                &mut pending_tasks,
+                                                            );
+                                                            continue;
+                                                        }
+
                                                         //trigger actions for relays
                                                         let associated_relays =
                                                             &sensor.associated_relays;
@@ -1686,6 +3181,21 @@ impl OneWire {
                                                                 night,
                                                             );
                                                         }
+
+                                                        //trigger actions for smart plugs
+                                                        let associated_plugs =
+                                                            &sensor.associated_plugs;
+                                                        if !associated_plugs.is_empty() {
+                                                            relay_dev.plug_sensor_trigger(
+                                                                &mut relays.relay,
+                                                                &mut state_machine,
+                                                                self,
+                                                                associated_plugs,
+                                                                kind_code,
+                                                                on,
+                                                                night,
+                                                            );
+                                                        }
                                                     }
                                                     _ => {}
                                                 }
@@ -1693,42 +3203,11 @@ impl OneWire {
                                         }
 
                                         //iteration over all boards that has changed state and needs a save_state()
-                                        for rb in &mut relay_dev.relay_boards {
-                                            match rb.new_value {
-                                                Some(new_value) => {
-                                                    let old_value = rb
-                                                        .last_value
-                                                        .unwrap_or(DS2408_INITIAL_STATE);
-                                                    if new_value != old_value {
-                                                        //checking all changed bits (relays) and set last_toggled Instant
-                                                        for i in 0..=7 {
-                                                            if new_value & (1 << i as u8)
-                                                                != old_value & (1 << i as u8)
-                                                            {
-                                                                match rb.relay[i] {
-                                                                    Some(id) => {
-                                                                        let r = relays
-                                                                            .relay
-                                                                            .iter_mut()
-                                                                            .find(|r| r.id == id);
-                                                                        match r {
-                                                                            Some(relay) => {
-                                                                                relay.last_toggled = Some(Instant::now());
-                                                                                self.increment_relay_counter(id);
-                                                                            }
-                                                                            None => (),
-                                                                        }
-                                                                    }
-                                                                    _ => {}
-                                                                }
-                                                            }
-                                                        }
-                                                        rb.save_state();
-                                                    }
-                                                }
-                                                _ => {}
-                                            }
-                                        }
+                                        self.save_changed_relay_boards(
+                                            &mut relay_dev.relay_boards,
+                                            &mut relays.relay,
+                                        );
+                                        self.set_edge(None);
                                     }
                                 }
                                 None => {
@@ -1756,6 +3235,7 @@ impl OneWire {
                                                 let kind_code =
                                                     kinds_cloned.get(&sensor.id_kind).unwrap();
                                                 let on: bool = new_value & (1 << bit) != 0;
+                                                self.update_sensor_value(&sensor.name, on);
 
                                                 let _ = !state_machine.sensor_hook(
                                                     &kind_code,
@@ -1787,9 +3267,144 @@ impl OneWire {
                         }
                         None => (),
                     }
-                    thread::sleep(Duration::from_micros(500));
                 }
 
+                //evaluate virtual sensors
+                if !sensor_dev.virtual_sensors.is_empty()
+                    && virtual_check.elapsed()
+                        > Duration::from_secs_f32(VIRTUAL_SENSOR_CHECK_INTERVAL_SECS)
+                {
+                    virtual_check = Instant::now();
+                    let mut values = self.sensor_values.write().unwrap();
+                    for vs in &mut sensor_dev.virtual_sensors {
+                        let on = match vs.evaluate(&mut values) {
+                            Some(on) => on,
+                            None => continue,
+                        };
+                        if vs.last_value == Some(on) {
+                            continue;
+                        }
+                        let initial_read = vs.last_value.is_none();
+                        vs.last_value = Some(on);
+                        let kind_code = match kinds_cloned.get(&vs.id_kind) {
+                            Some(kind_code) => kind_code,
+                            None => continue,
+                        };
+                        let _ = self.transmitter.send(DbTask {
+                            command: if on {
+                                CommandCode::UpdateVirtualSensorStateOn
+                            } else {
+                                CommandCode::UpdateVirtualSensorStateOff
+                            },
+                            value: Some(vs.id_sensor),
+                        });
+
+                        //check hook function result and stop processing when needed
+                        let stop_processing = !state_machine.sensor_hook(
+                            &kind_code,
+                            &vs.name,
+                            on,
+                            &vs.tags,
+                            night,
+                            initial_read,
+                            &mut pending_tasks,
+                            vs.id_sensor,
+                        );
+                        info!(
+                            "<green>{}</>: <b>{}</> <cyan>(</><magenta>virtual:{}</><cyan>)</>, {}</>{}",
+                            kind_code,
+                            vs.name,
+                            vs.source,
+                            {if on {"<bold><green>active"} else {"<bright-black>inactive"}},
+                            {if stop_processing {", <yellow>stopped processing</>"} else {""}},
+                        );
+                        if stop_processing || initial_read {
+                            continue;
+                        }
+
+                        //trigger actions exactly like for the physical sensors
+                        if !vs.associated_relays.is_empty() {
+                            relay_dev.relay_sensor_trigger(
+                                &mut relays.relay,
+                                &mut state_machine,
+                                &vs.associated_relays,
+                                kind_code,
+                                on,
+                                night,
+                            );
+                        }
+                        if !vs.associated_yeelights.is_empty() {
+                            relay_dev.yeelight_sensor_trigger(
+                                &mut relays.relay,
+                                &mut state_machine,
+                                self,
+                                &vs.associated_yeelights,
+                                kind_code,
+                                on,
+                                night,
+                            );
+                        }
+                        if !vs.associated_plugs.is_empty() {
+                            relay_dev.plug_sensor_trigger(
+                                &mut relays.relay,
+                                &mut state_machine,
+                                self,
+                                &vs.associated_plugs,
+                                kind_code,
+                                on,
+                                night,
+                            );
+                        }
+                    }
+                    self.save_changed_relay_boards(&mut relay_dev.relay_boards, &mut relays.relay);
+                }
+
+                //publish bus statistics
+                if metrics_time.elapsed() > Duration::from_secs_f32(METRICS_INTERVAL_SECS) {
+                    metrics_time = Instant::now();
+                    let mut metrics = self.bus_metrics.write().unwrap();
+                    for sb in &sensor_dev.sensor_boards {
+                        metrics.update(get_w1_device_name(sb.ow_family, sb.ow_address), &sb.stats);
+                    }
+                    for rb in &relay_dev.relay_boards {
+                        metrics.update(get_w1_device_name(rb.ow_family, rb.ow_address), &rb.stats);
+                    }
+                }
+
+                //periodic relay output verification
+                if relay_verify.elapsed() > Duration::from_secs_f32(RELAY_VERIFY_INTERVAL_SECS) {
+                    relay_verify = Instant::now();
+                    for rb in &mut relay_dev.relay_boards {
+                        if rb.verify_output() {
+                            self.run_watchdog_script(
+                                get_w1_device_name(rb.ow_family, rb.ow_address),
+                                "drift",
+                            );
+                        }
+                    }
+                }
+
+                //all sensor boards degraded: we have most probably lost the bus
+                let new_bus_failure = !sensor_dev.sensor_boards.is_empty()
+                    && sensor_dev
+                        .sensor_boards
+                        .iter()
+                        .all(|sb| sb.degraded && sb.read_failures > 0);
+                if bus_failure != new_bus_failure {
+                    bus_failure = new_bus_failure;
+                    if bus_failure {
+                        error!("{}: 🚨 1-wire bus failure detected", self.name);
+                        for rb in &mut relay_dev.relay_boards {
+                            rb.apply_failure_policy(&relays.relay);
+                        }
+                    } else {
+                        info!("{}: 1-wire bus is back", self.name);
+                    }
+                }
+
+                //recovery and reporting of the degraded boards
+                bus_watchdog.check(&mut sensor_dev.sensor_boards, &mut relay_dev.relay_boards);
+
                 //checking day/night
                 if night_check.is_some()
                     && night_check.unwrap().elapsed()
@@ -1805,72 +3420,280 @@ impl OneWire {
                     let az = pos.azimuth.to_degrees();
                     let alt = pos.altitude.to_degrees();
                     debug!("the position of the sun is az: {} / alt: {}", az, alt);
+
+                    //export the sun position for shading rules, influxdb and the dashboards
+                    if let Ok(mut values) = self.sensor_values.write() {
+                        values.insert(SUN_AZIMUTH_VALUE_NAME.to_string(), az as f32);
+                        values.insert(SUN_ELEVATION_VALUE_NAME.to_string(), alt as f32);
+                    }
+                    //DbTask values are integers: passing hundredths of a degree
+                    let _ = self.transmitter.send(DbTask {
+                        command: CommandCode::UpdateSunAzimuth,
+                        value: Some((az * 100.0) as i32),
+                    });
+                    let _ = self.transmitter.send(DbTask {
+                        command: CommandCode::UpdateSunElevation,
+                        value: Some((alt * 100.0) as i32),
+                    });
+                    events::publish(
+                        &self.events,
+                        Event::SunPosition {
+                            azimuth: az as f32,
+                            elevation: alt as f32,
+                        },
+                    );
                     let new_night = alt < DAYLIGHT_SUN_DEGREE;
 
-                    if night != new_night {
+                    //roller shutters: closing in the sun window, opening afterwards
+                    if !relay_dev.shutter_rules.is_empty() {
+                        let values = self.sensor_values.read().unwrap().clone();
+                        for rule in &mut relay_dev.shutter_rules {
+                            if let Some(active) = rule.evaluate(az as f32, alt as f32, &values) {
+                                let position = if active { rule.position } else { 0 };
+                                for name in &rule.shutters {
+                                    pending_tasks.push(ShutterAction::Move(position).task(name));
+                                }
+                            }
+                        }
+                    }
+                    if night && !new_night {
+                        for shutter in relay_dev.shutters.iter().filter(|s| s.open_at_sunrise) {
+                            info!(
+                                "{}: 🌅 sunrise: opening shutter {}",
+                                self.name, shutter.name
+                            );
+                            pending_tasks.push(ShutterAction::Move(0).task(&shutter.name));
+                        }
+                    }
+
+                    let night_changed = night != new_night;
+                    if night_changed {
                         night = new_night;
                         if night {
                             info!("{}: Enabling night mode 🌙", self.name);
                         } else {
                             info!("{}: Disabling night mode 🌞", self.name);
                         }
+                    }
 
-                        for rb in &mut relay_dev.relay_boards {
-                            let mut new_state: u8 = rb.get_actual_state();
+                    let now = Local::now();
+                    for rb in &mut relay_dev.relay_boards {
+                        let mut new_state: u8 = rb.get_actual_state();
 
-                            //iteration on all relays and check 'all night' tag
-                            for i in 0..=7 {
-                                match rb.relay[i] {
-                                    Some(id) => {
-                                        let r = relays.relay.iter_mut().find(|r| r.id == id);
-                                        match r {
-                                            Some(relay) => {
-                                                let mut relay_marked: bool = false;
-                                                for tag in &relay.tags {
-                                                    match tag.as_ref() {
-                                                        "all_night" => {
-                                                            relay_marked = true;
-                                                        }
-                                                        _ => {}
+                        //iteration on all relays and check 'all night' tag
+                        for i in 0..=7 {
+                            match rb.relay[i] {
+                                Some(id) => {
+                                    let r = relays.relay.iter_mut().find(|r| r.id == id);
+                                    match r {
+                                        Some(relay) => {
+                                            //plain 'all_night' follows the global day/night,
+                                            //'all_night:...' has its own twilight and offsets
+                                            let mut relay_night: Option<bool> = None;
+                                            for tag in &relay.tags {
+                                                if tag == "all_night" {
+                                                    if night_changed {
+                                                        relay_night = Some(night);
+                                                    }
+                                                } else if let Some(Ok(all_night)) =
+                                                    AllNight::parse(tag)
+                                                {
+                                                    let sun = twilight_times
+                                                        .entry(all_night.twilight)
+                                                        .or_insert_with(|| {
+                                                            SunTimes::with_degree(
+                                                                all_night.twilight.degree(),
+                                                            )
+                                                        });
+                                                    sun.update(now, lat, lon);
+                                                    //polar day/night: fall back to global state
+                                                    let is_night = all_night
+                                                        .is_night(now, sun)
+                                                        .unwrap_or(night);
+                                                    if relay_night_state.insert(id, is_night)
+                                                        != Some(is_night)
+                                                    {
+                                                        relay_night = Some(is_night);
                                                     }
                                                 }
-                                                if relay_marked {
-                                                    if relay.turn_on_prolong(
-                                                        ProlongKind::DayNight,
-                                                        night,
-                                                        rb.get_dest_name(Some(i)),
-                                                        night,
-                                                        false,
-                                                        None,
-                                                    ) {
-                                                        if night {
-                                                            //turn ON relay
-                                                            new_state = new_state & !(1 << i as u8);
-                                                        } else {
-                                                            //turn OFF relay
-                                                            new_state = new_state | (1 << i as u8);
-                                                        }
-                                                        rb.new_value = Some(new_state);
-                                                        self.increment_relay_counter(relay.id);
+                                            }
+                                            if let Some(relay_night) = relay_night {
+                                                if relay.turn_on_prolong(
+                                                    ProlongKind::DayNight,
+                                                    relay_night,
+                                                    rb.get_dest_name(Some(i)),
+                                                    relay_night,
+                                                    false,
+                                                    None,
+                                                ) {
+                                                    if relay_night {
+                                                        //turn ON relay
+                                                        new_state = new_state & !(1 << i as u8);
+                                                    } else {
+                                                        //turn OFF relay
+                                                        new_state = new_state | (1 << i as u8);
                                                     }
+                                                    rb.new_value = Some(new_state);
+                                                    self.increment_relay_counter(relay.id);
                                                 }
                                             }
-                                            None => (),
                                         }
+                                        None => (),
                                     }
-                                    _ => {}
                                 }
+                                _ => {}
                             }
+                        }
+
+                        //save output state when needed
+                        rb.save_state();
+                    }
+                }
+
+                //evaluate relay schedules
+                if !relay_dev.schedules.is_empty()
+                    && schedule_check.elapsed()
+                        > Duration::from_secs_f32(SCHEDULE_CHECK_INTERVAL_SECS)
+                {
+                    schedule_check = Instant::now();
+                    let now = Local::now();
+                    sun_times.update(now, lat, lon);
+                    for schedule in &mut relay_dev.schedules {
+                        let (active, remaining) = match schedule.evaluate(now, &sun_times) {
+                            Some(result) => result,
+                            None => continue,
+                        };
+                        //act on edges only, and on startup when inside the window
+                        if schedule.active == Some(active) || (schedule.active.is_none() && !active)
+                        {
+                            schedule.active = Some(active);
+                            continue;
+                        }
+                        schedule.active = Some(active);
+                        info!(
+                            "{}: 🕒 schedule #{} ({} - {}) {} relay id={}",
+                            self.name,
+                            schedule.id_schedule,
+                            schedule.on_time,
+                            schedule.off_time,
+                            if active { "turning on" } else { "turning off" },
+                            schedule.id_relay,
+                        );
+                        let new_task = OneWireTask {
+                            command: if active {
+                                TaskCommand::TurnOnProlong
+                            } else {
+                                TaskCommand::TurnOff
+                            },
+                            id_relay: Some(schedule.id_relay),
+                            tag_group: None,
+                            id_yeelight: None,
+                            id_plug: None,
+                            duration: remaining,
+                        };
+                        pending_tasks.push(new_task);
+                    }
+                }
 
-                            //save output state when needed
-                            rb.save_state();
+                //cron jobs
+                if !relay_dev.cron_jobs.is_empty()
+                    && cron_check.elapsed() > Duration::from_secs_f32(CRON_CHECK_INTERVAL_SECS)
+                {
+                    cron_check = Instant::now();
+                    let now = Local::now();
+                    for job in &mut relay_dev.cron_jobs {
+                        if let Some(new_task) = job.check(now) {
+                            info!(
+                                "{}: 🕒 cron job <i>{}</> ({}): {:?} relay={:?} tag={:?} yeelight={:?} duration={:?} next run: {:?}",
+                                self.name,
+                                job.name,
+                                job.expression,
+                                job.command,
+                                job.id_relay,
+                                job.tag_group,
+                                job.id_yeelight,
+                                job.duration,
+                                job.cron.next_after(now).map(|t| t.to_string()),
+                            );
+                            pending_tasks.push(new_task);
                         }
                     }
                 }
 
+                //circulation pump pre-run based on learned usage
+                if let Some(circulation) = state_machine.circulation.as_mut() {
+                    circulation.check(&mut pending_tasks);
+                }
+
+                //alarm arming/disarming, entry delay, clearing after disarming
+                state_machine
+                    .alarm
+                    .check(state_machine.ethlcd.as_mut(), &mut pending_tasks);
+
+                //persist learned PIR hold times
+                if let Some(ref path) = self.adaptive_hold_file {
+                    self.adaptive_hold.write().unwrap().save(path);
+                }
+
+                //process double-click floor turn-off, if any
+                state_machine.process_floor_off(&mut pending_tasks);
+
+                //heating cutback for the open windows
+                state_machine.process_windows();
+
+                //process timed-out switch gestures
+                state_machine.process_gestures(&mut pending_tasks);
+
                 //process rfid pending tags, if any
                 state_machine.process_rfid_tags(&mut pending_tasks, night);
 
+                //replace scenes with their actions
+                if !pending_tasks.is_empty() {
+                    pending_tasks = scene::expand(pending_tasks, &relay_dev.scenes);
+                }
+
+                //roller shutters: new targets, finished and pending movements
+                {
+                    let devices = &mut *relay_dev;
+                    let mut shutter_tasks = vec![];
+                    let shutters = &mut devices.shutters;
+                    pending_tasks.retain(|t| match &t.command {
+                        TaskCommand::Shutter(name, action) => {
+                            match shutters.iter_mut().find(|s| &s.name == name) {
+                                Some(shutter) => shutter.request(*action, &mut shutter_tasks),
+                                None => error!("🪟 unknown shutter: {}", name),
+                            }
+                            false
+                        }
+                        _ => true,
+                    });
+                    let relay_boards = &devices.relay_boards;
+                    for shutter in shutters.iter_mut() {
+                        shutter.process(|id| relay_is_on(relay_boards, id), &mut shutter_tasks);
+                    }
+                    pending_tasks.extend(shutter_tasks);
+                }
+
+                //gates: requested movements, safety timeouts and open after dark
+                {
+                    let mut gate_tasks = vec![];
+                    let gates = &mut state_machine.gates;
+                    pending_tasks.retain(|t| match &t.command {
+                        TaskCommand::Gate(name, action) => {
+                            match gates.iter_mut().find(|g| &g.name == name) {
+                                Some(gate) => gate.request(*action, &mut gate_tasks),
+                                None => error!("🚧 unknown gate: {}", name),
+                            }
+                            false
+                        }
+                        _ => true,
+                    });
+                    for gate in gates.iter_mut() {
+                        gate.check(night, &mut gate_tasks);
+                    }
+                    pending_tasks.extend(gate_tasks);
+                }
+
                 //checking for pending tasks
                 if !pending_tasks.is_empty() {
                     //Yeelights
@@ -1903,9 +3726,14 @@ impl OneWire {
                                                 !yeelight.powered_on,
                                                 t.duration,
                                             ) {
-                                                yeelight.turn_on_off(true, &dev);
+                                                yeelight.turn_on_off(true, self);
                                                 dev.last_toggled = Some(Instant::now());
                                                 self.increment_yeelight_counter(dev.id);
+                                                self.device_switched(
+                                                    DeviceKind::Yeelight,
+                                                    dev.id,
+                                                    yeelight.powered_on,
+                                                );
                                             }
                                         }
                                         TaskCommand::TurnOff => {
@@ -1917,9 +3745,33 @@ impl OneWire {
                                                 !yeelight.powered_on,
                                                 t.duration,
                                             ) {
-                                                yeelight.turn_on_off(false, &dev);
+                                                yeelight.turn_on_off(false, self);
+                                                dev.last_toggled = Some(Instant::now());
+                                                self.increment_yeelight_counter(dev.id);
+                                                self.device_switched(
+                                                    DeviceKind::Yeelight,
+                                                    dev.id,
+                                                    yeelight.powered_on,
+                                                );
+                                            }
+                                        }
+                                        TaskCommand::Toggle => {
+                                            if dev.turn_on_prolong(
+                                                ProlongKind::Switch,
+                                                night,
+                                                yeelight.get_dest_name(None),
+                                                true,
+                                                !yeelight.powered_on,
+                                                t.duration,
+                                            ) {
+                                                yeelight.turn_on_off(!yeelight.powered_on, self);
                                                 dev.last_toggled = Some(Instant::now());
                                                 self.increment_yeelight_counter(dev.id);
+                                                self.device_switched(
+                                                    DeviceKind::Yeelight,
+                                                    dev.id,
+                                                    yeelight.powered_on,
+                                                );
                                             }
                                         }
                                         _ => {}
@@ -1930,6 +3782,52 @@ impl OneWire {
                         }
                     }
 
+                    //Smart plugs
+                    for plug in &mut relay_dev.smart_plugs {
+                        let d = relays.relay.iter_mut().find(|d| d.id == plug.id);
+                        match d {
+                            Some(dev) => {
+                                let plug_tasks: Vec<OneWireTask> = pending_tasks
+                                    .clone()
+                                    .into_iter()
+                                    .filter(|t| match t.id_plug {
+                                        Some(id) => dev.id == id,
+                                        None => match &t.tag_group {
+                                            Some(tag_name) => dev.tags.contains(tag_name),
+                                            None => false,
+                                        },
+                                    })
+                                    .collect();
+                                for t in &plug_tasks {
+                                    debug!("Processing OneWireTask: command={:?}, matched id_plug={}, duration={:?}", t.command, dev.id, t.duration);
+
+                                    let (kind, turn_on) = match t.command {
+                                        TaskCommand::TurnOnProlong => (ProlongKind::Remote, true),
+                                        TaskCommand::TurnOff => (ProlongKind::Remote, false),
+                                        TaskCommand::Toggle => (ProlongKind::Switch, true),
+                                        _ => continue,
+                                    };
+                                    if dev.turn_on_prolong(
+                                        kind,
+                                        night,
+                                        plug.get_dest_name(None),
+                                        turn_on,
+                                        !plug.powered_on,
+                                        t.duration,
+                                    ) {
+                                        let new_state = match t.command {
+                                            TaskCommand::Toggle => !plug.powered_on,
+                                            _ => turn_on,
+                                        };
+                                        plug.turn_on_off(new_state, &dev, self);
+                                        dev.last_toggled = Some(Instant::now());
+                                    }
+                                }
+                            }
+                            _ => (),
+                        }
+                    }
+
                     //Relays
                     for rb in &mut relay_dev.relay_boards {
                         let mut new_state: u8 = rb.get_actual_state();
@@ -1992,6 +3890,21 @@ impl OneWire {
                                                             self.increment_relay_counter(relay.id);
                                                         }
                                                     }
+                                                    TaskCommand::Toggle => {
+                                                        if relay.turn_on_prolong(
+                                                            ProlongKind::Switch,
+                                                            night,
+                                                            rb.get_dest_name(Some(i)),
+                                                            true,
+                                                            currently_off,
+                                                            t.duration,
+                                                        ) {
+                                                            //flip the bit -> toggle relay
+                                                            new_state = new_state ^ (1 << i as u8);
+                                                            rb.new_value = Some(new_state);
+                                                            self.increment_relay_counter(relay.id);
+                                                        }
+                                                    }
                                                     _ => {}
                                                 }
                                             }
@@ -2078,9 +3991,13 @@ impl OneWire {
                                             !yeelight.powered_on,
                                             None,
                                         ) {
-                                            yeelight.turn_on_off(false, &dev);
-                                            dev.last_toggled = Some(Instant::now());
+                                            yeelight.turn_on_off(false, self);
                                             self.increment_yeelight_counter(yeelight.id);
+                                            self.device_switched(
+                                                DeviceKind::Yeelight,
+                                                yeelight.id,
+                                                yeelight.powered_on,
+                                            );
                                         }
                                     }
                                 }
@@ -2091,6 +4008,29 @@ impl OneWire {
                         _ => (),
                     }
                 }
+
+                //checking for auto turn-off of necessary smart plugs
+                for plug in &mut relay_dev.smart_plugs {
+                    let d = relays.relay.iter_mut().find(|d| d.id == plug.id);
+                    if let Some(dev) = d {
+                        if let (Some(toggled), Some(stop_after)) =
+                            (dev.last_toggled, dev.stop_after)
+                        {
+                            if toggled.elapsed() > stop_after
+                                && dev.turn_on_prolong(
+                                    ProlongKind::AutoOff,
+                                    night,
+                                    plug.get_dest_name(None),
+                                    false,
+                                    !plug.powered_on,
+                                    None,
+                                )
+                            {
+                                plug.turn_on_off(false, &dev, self);
+                            }
+                        }
+                    }
+                }
             }
 
             debug!(
@@ -2098,6 +4038,186 @@ impl OneWire {
                 loop_start.elapsed().as_millis()
             );
         }
+
+        //write the outputs not saved yet and leave relays in a safe state
+        {
+            let mut relay_dev = self.relay_devices.write().unwrap();
+            let relays = self.relays.read().unwrap();
+            for rb in &mut relay_dev.relay_boards {
+                if rb.new_value.is_some() {
+                    rb.save_state();
+                }
+                rb.apply_failure_policy(&relays.relay);
+            }
+        }
+        systemd::heartbeat_stop(&self.name);
         info!("{}: thread stopped", self.name);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::WorkerErrors;
+    use crate::queue::{self, OverflowPolicy, QueueMetrics};
+    use crate::test_support::{ds2413_state, wait_until, FakeW1Tree, TempDir, TEST_TIMEOUT};
+    use tokio::sync::broadcast;
+
+    #[test]
+    fn worker_switches_the_relay_on_a_sensor_change() {
+        let tree = FakeW1Tree::new();
+        let sensor_board = tree.add(
+            FAMILY_CODE_DS2413,
+            0x1234,
+            "state",
+            &[ds2413_state(false, false)],
+        );
+        let relay_board = tree.add_relay_board(FAMILY_CODE_DS2408, 0x5678);
+        let dir = TempDir::new("config");
+        let config = Config::load(&dir.write("hard.conf", "[general]\n")).unwrap();
+
+        let mut sensor_devices = SensorDevices {
+            kinds: HashMap::new(),
+            sensor_boards: vec![],
+            max_cesspool_level: 0,
+            virtual_sensors: vec![],
+            inverted_sensors: vec![],
+            owserver: None,
+            gpio_boards: vec![],
+        };
+        sensor_devices.kinds.insert(1, "Switch".to_string());
+        sensor_devices.add_sensor(
+            1,
+            1,
+            "hall_switch".to_string(),
+            Some(FAMILY_CODE_DS2413 as i16),
+            0x1234,
+            0,
+            vec![1],
+            vec![],
+            vec![],
+            vec![],
+        );
+        let mut relay_devices = RelayDevices {
+            relay_boards: vec![],
+            owserver: None,
+            gpio_boards: vec![],
+            yeelight: vec![],
+            smart_plugs: vec![],
+            schedules: vec![],
+            cron_jobs: vec![],
+            scenes: vec![],
+            shutters: vec![],
+            shutter_rules: vec![],
+        };
+        let mut relays = Relays { relay: vec![] };
+        relay_devices.add_relay(
+            &mut relays.relay,
+            1,
+            "hall_light".to_string(),
+            Some(FAMILY_CODE_DS2408 as i16),
+            0x5678,
+            0,
+            false,
+            None,
+            None,
+            false,
+            false,
+            vec![],
+        );
+
+        let metrics = Arc::new(RwLock::new(QueueMetrics::default()));
+        let (_ow_tx, ow_rx) = queue::bounded("ow", 100, OverflowPolicy::DropNewest, &metrics);
+        let (db_tx, db_rx) = queue::bounded("db", 100, OverflowPolicy::DropOldest, &metrics);
+        let (lcd_tx, _lcd_rx) = queue::bounded("lcd", 100, OverflowPolicy::DropOldest, &metrics);
+        let (mqtt_tx, _mqtt_rx) = queue::bounded("mqtt", 100, OverflowPolicy::DropOldest, &metrics);
+        let (notify_tx, _notify_rx) =
+            queue::bounded("notify", 100, OverflowPolicy::DropOldest, &metrics);
+        let sensor_devices = Arc::new(RwLock::new(sensor_devices));
+        let sensor_values = Arc::new(RwLock::new(SensorValues::new()));
+        let onewire = OneWire {
+            name: "onewire".to_string(),
+            config: Arc::new(config),
+            transmitter: db_tx.clone(),
+            ow_receiver: ow_rx,
+            lcd_transmitter: lcd_tx.clone(),
+            mqtt_transmitter: mqtt_tx.clone(),
+            sensor_devices: sensor_devices.clone(),
+            relay_devices: Arc::new(RwLock::new(relay_devices)),
+            relays: Arc::new(RwLock::new(relays)),
+            sensor_board_stale: None,
+            watchdog_script: None,
+            command_min_interval_ms: None,
+            bus_metrics: Arc::new(RwLock::new(BusMetrics::default())),
+            sensor_values: sensor_values.clone(),
+            latency: Arc::new(RwLock::new(LatencyMetrics::default())),
+            edge_time: Mutex::new(None),
+            yeelight_requests: Arc::new(Mutex::new(vec![])),
+            adaptive_hold: Arc::new(RwLock::new(AdaptiveHold::default())),
+            adaptive_hold_file: None,
+            energy_costs: Arc::new(RwLock::new(EnergyCosts::default())),
+            window_cutback: Arc::new(RwLock::new(HashSet::new())),
+            events: broadcast::channel(16).0,
+            notify_transmitter: notify_tx.clone(),
+            errors: ErrorReporter {
+                worker: "onewire".to_string(),
+                metrics: Arc::new(RwLock::new(WorkerErrors::default())),
+                notify_transmitter: notify_tx,
+            },
+        };
+        let alarm = Alarm::new(
+            Arc::new(AtomicBool::new(false)),
+            None,
+            None,
+            lcd_tx,
+            mqtt_tx,
+            db_tx,
+        );
+
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+        let worker_cancel_flag = cancel_flag.clone();
+        let worker = thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(onewire.worker(
+                worker_cancel_flag,
+                None,
+                Arc::new(RwLock::new(vec![])),
+                Arc::new(RwLock::new(vec![])),
+                Arc::new(RwLock::new(vec![])),
+                None,
+                None,
+                vec![],
+                alarm,
+            ));
+        });
+
+        //the initial state has to be read before the switch is pressed
+        assert!(wait_until(TEST_TIMEOUT, || {
+            sensor_devices.read().unwrap().sensor_boards[0]
+                .last_value
+                .is_some()
+        }));
+        tree.write(&sensor_board, "state", &[ds2413_state(true, false)]);
+
+        //the relay is turned on (active low output)
+        let switched = wait_until(TEST_TIMEOUT, || {
+            tree.read(&relay_board, "output") == [DS2408_INITIAL_STATE & !1]
+        });
+        cancel_flag.store(true, Ordering::SeqCst);
+        worker.join().unwrap();
+        assert!(
+            switched,
+            "output: {:02x?}",
+            tree.read(&relay_board, "output")
+        );
+
+        assert_eq!(sensor_values.read().unwrap().get("hall_switch"), Some(&1.0));
+        let tasks: Vec<DbTask> = std::iter::from_fn(|| db_rx.try_recv().ok()).collect();
+        assert!(tasks.iter().any(
+            |t| matches!(t.command, CommandCode::IncrementSensorCounter) && t.value == Some(1)
+        ));
+    }
+}
@@ -1,20 +1,33 @@
+use crate::chaos::{self, Fault};
+use crate::database::{CommandCode, DbTask};
+use crate::heating_season::{HeatingSeason, HEATING_SEASON_VALUE_NAME};
+use crate::lcdproc::{LcdTask, LcdTaskCommand};
+use crate::metrics::{BusMetrics, DeviceStats};
 use crate::onewire::{
-    get_w1_device_name, OneWireTask, TaskCommand, FAMILY_CODE_DS18B20, FAMILY_CODE_DS18S20,
-    FAMILY_CODE_DS2438, W1_ROOT_PATH,
+    get_w1_device_name, w1_root, OneWireTask, StateMachine, TaskCommand, FAMILY_CODE_DS18B20,
+    FAMILY_CODE_DS18S20, FAMILY_CODE_DS2438,
 };
+use crate::queue::Sender;
+use crate::systemd;
+use crate::units;
+use crate::virtual_sensor::SensorValues;
+use humantime::format_duration;
 use simplelog::*;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::Sender;
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 use std::{fs, thread};
 
 pub const TEMP_CHECK_INTERVAL_SECS: f32 = 300.0; //secs between measuring temperature
 pub const HUMID_CHECK_INTERVAL_SECS: f32 = 60.0; //secs between measuring humidity
+pub const FROST_GUARD_HYSTERESIS: f32 = 1.0; //°C above threshold to stop frost protection
+pub static FROST_PROTECT_TAG: &str = "frost_protect";
+pub const THERMOSTAT_DEFAULT_HYSTERESIS: f32 = 0.5; //°C when not given in the thermostat tag
+pub const TEMP_ALARM_HYSTERESIS: f32 = 0.5; //°C back from the threshold to clear the alarm
 
 pub struct EnvSensor {
     pub id_sensor: i32,
@@ -26,9 +39,69 @@ pub struct EnvSensor {
     pub ow_family: u8,
     pub ow_address: u64,
     pub file: Option<File>,
+    pub stats: DeviceStats,
+    pub thermostat_on: Option<bool>,
+    pub last_temperature: Option<f32>,
+    pub last_humidity: Option<f32>,
+    pub last_vdd: Option<f32>,
+    pub last_vad: Option<f32>,
+    pub influx_pending: bool,
+    pub temp_alarm_active: bool,
+}
+
+/// VAD to relative humidity conversion of the sensor attached to a DS2438,
+/// selected by the `humidity_sensor:hih4000|hih5030|linear:<slope>:<offset>` tag
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HumidityConversion {
+    Hih4000,
+    Hih5030,
+    Linear { slope: f32, offset: f32 },
+}
+
+impl HumidityConversion {
+    pub fn from_tags(tags: &Vec<String>) -> HumidityConversion {
+        let tag = match tags.iter().find(|t| t.starts_with("humidity_sensor:")) {
+            Some(tag) => tag,
+            None => return HumidityConversion::Hih4000,
+        };
+        let v: Vec<&str> = tag.split(":").collect();
+        match v.get(1) {
+            Some(&"hih5030") => HumidityConversion::Hih5030,
+            Some(&"linear") => match (
+                v.get(2).and_then(|x| x.parse::<f32>().ok()),
+                v.get(3).and_then(|x| x.parse::<f32>().ok()),
+            ) {
+                (Some(slope), Some(offset)) => HumidityConversion::Linear { slope, offset },
+                _ => {
+                    error!("invalid humidity sensor tag: {:?}, using hih4000", tag);
+                    HumidityConversion::Hih4000
+                }
+            },
+            _ => HumidityConversion::Hih4000,
+        }
+    }
+
+    /// Relative humidity from the supply and output voltage, temperature compensated
+    pub fn humidity(&self, vdd: f32, vad: f32, temp: f32) -> f32 {
+        match self {
+            //see the HIH-4000-003 pdf for details
+            HumidityConversion::Hih4000 => (vad / vdd - 0.16) / 0.0062 / (1.0546 - 0.00216 * temp),
+            HumidityConversion::Hih5030 => {
+                (vad / vdd - 0.1515) / 0.00636 / (1.0546 - 0.00216 * temp)
+            }
+            HumidityConversion::Linear { slope, offset } => vad * slope + offset,
+        }
+    }
 }
 
 impl EnvSensor {
+    pub fn get_room(&self) -> Option<String> {
+        self.tags
+            .iter()
+            .find(|t| t.starts_with("room:"))
+            .map(|t| t["room:".len()..].to_string())
+    }
+
     fn is_temp_sensor(&self) -> bool {
         self.ow_family == FAMILY_CODE_DS18B20 || self.ow_family == FAMILY_CODE_DS18S20
     }
@@ -41,7 +114,7 @@ impl EnvSensor {
         if self.is_temp_sensor() {
             let path = format!(
                 "{}/{}/w1_slave",
-                W1_ROOT_PATH,
+                w1_root(),
                 get_w1_device_name(self.ow_family, self.ow_address)
             );
             let data_path = Path::new(&path);
@@ -50,6 +123,7 @@ impl EnvSensor {
                 get_w1_device_name(self.ow_family, self.ow_address),
                 data_path.display()
             );
+            self.stats.reopens += 1;
             self.file = File::open(data_path).ok();
         } else {
             info!(
@@ -66,8 +140,10 @@ impl EnvSensor {
 
         match &mut self.file {
             Some(file) => {
+                self.stats.reads += 1;
                 match file.seek(SeekFrom::Start(0)) {
                     Err(e) => {
+                        self.stats.read_errors += 1;
                         error!(
                             "{}: file seek error: {:?}",
                             get_w1_device_name(self.ow_family, self.ow_address),
@@ -77,7 +153,15 @@ impl EnvSensor {
                     _ => {}
                 }
                 let mut data = String::new();
-                match file.read_to_string(&mut data) {
+                let result = if chaos::inject(Fault::W1Read) {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "chaos: injected read error",
+                    ))
+                } else {
+                    file.read_to_string(&mut data)
+                };
+                match result {
                     Ok(_) => {
                         debug!(
                             "{}: temperature data: {}",
@@ -89,6 +173,7 @@ impl EnvSensor {
                                 if line.contains("YES") {
                                     continue;
                                 } else if line.contains("NO") {
+                                    self.stats.crc_errors += 1;
                                     error!(
                                         "{}: got CRC error in temperature data",
                                         get_w1_device_name(self.ow_family, self.ow_address),
@@ -106,6 +191,7 @@ impl EnvSensor {
                         }
                     }
                     Err(e) => {
+                        self.stats.read_errors += 1;
                         error!(
                             "{}: error reading: {:?}",
                             get_w1_device_name(self.ow_family, self.ow_address),
@@ -127,21 +213,30 @@ impl EnvSensor {
 
         let temp_path = format!(
             "{}/{}/temperature",
-            W1_ROOT_PATH,
+            w1_root(),
             get_w1_device_name(self.ow_family, self.ow_address)
         );
         let vdd_path = format!(
             "{}/{}/vdd",
-            W1_ROOT_PATH,
+            w1_root(),
             get_w1_device_name(self.ow_family, self.ow_address)
         );
         let vad_path = format!(
             "{}/{}/vad",
-            W1_ROOT_PATH,
+            w1_root(),
             get_w1_device_name(self.ow_family, self.ow_address)
         );
 
-        match fs::read_to_string(temp_path) {
+        self.stats.reads += 1;
+        let result = if chaos::inject(Fault::W1Read) {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "chaos: injected read error",
+            ))
+        } else {
+            fs::read_to_string(temp_path)
+        };
+        match result {
             Ok(data) => {
                 temp_data = data.trim().parse::<f32>().ok();
                 debug!(
@@ -152,6 +247,7 @@ impl EnvSensor {
                 );
             }
             Err(e) => {
+                self.stats.read_errors += 1;
                 error!(
                     "{}: error reading: {:?}",
                     get_w1_device_name(self.ow_family, self.ow_address),
@@ -170,6 +266,7 @@ impl EnvSensor {
                 );
             }
             Err(e) => {
+                self.stats.read_errors += 1;
                 error!(
                     "{}: error reading: {:?}",
                     get_w1_device_name(self.ow_family, self.ow_address),
@@ -188,6 +285,7 @@ impl EnvSensor {
                 );
             }
             Err(e) => {
+                self.stats.read_errors += 1;
                 error!(
                     "{}: error reading: {:?}",
                     get_w1_device_name(self.ow_family, self.ow_address),
@@ -200,17 +298,102 @@ impl EnvSensor {
             let temp = temp_data.unwrap() / 256.0;
             let vdd = vdd_data.unwrap() / 100.0;
             let vad = vad_data.unwrap() / 100.0;
+            self.last_vdd = Some(vdd);
+            self.last_vad = Some(vad);
 
-            //magic computation here, see the HIH-4000-003 pdf for details
-            let humid = (vad / vdd - 0.16) / 0.0062 / (1.0546 - 0.00216 * temp);
+            let humid = HumidityConversion::from_tags(&self.tags).humidity(vdd, vad, temp);
+            if !(0.0..=100.0).contains(&humid) {
+                debug!(
+                    "{}: humidity out of range: {:.1} %RH (vdd: {} V, vad: {} V), clamping",
+                    get_w1_device_name(self.ow_family, self.ow_address),
+                    humid,
+                    vdd,
+                    vad
+                );
+            }
 
-            return Some((humid, temp));
+            return Some((humid.max(0.0).min(100.0), temp));
         }
 
         return None;
     }
 }
 
+/// Threshold action configured by a `temp_alarm:<>|<><THRESHOLD>[:cmd:<command>]` tag,
+/// without a command the associated relays are triggered
+pub struct TempAlarm {
+    pub above: bool,
+    pub threshold: f32,
+    pub command: Option<String>,
+}
+
+impl TempAlarm {
+    pub fn from_tags(tags: &Vec<String>) -> Option<TempAlarm> {
+        let tag = tags.iter().find(|t| t.starts_with("temp_alarm:"))?;
+        let v: Vec<&str> = tag["temp_alarm:".len()..].splitn(3, ":").collect();
+        let (above, threshold) = match v.get(0) {
+            Some(x) if x.starts_with('>') => (true, &x[1..]),
+            Some(x) if x.starts_with('<') => (false, &x[1..]),
+            _ => return None,
+        };
+        Some(TempAlarm {
+            above,
+            threshold: threshold.parse::<f32>().ok()?,
+            command: match (v.get(1), v.get(2)) {
+                (Some(&"cmd"), Some(cmd)) => Some(cmd.to_string()),
+                _ => None,
+            },
+        })
+    }
+
+    /// Returns the new alarm state, it is cleared with a hysteresis
+    pub fn check(&self, temp: f32, active: bool) -> bool {
+        let hysteresis = if active { TEMP_ALARM_HYSTERESIS } else { 0.0 };
+        if self.above {
+            temp > self.threshold - hysteresis
+        } else {
+            temp < self.threshold + hysteresis
+        }
+    }
+}
+
+/// Simple on/off regulator configured by a `thermostat:SETPOINT:HYSTERESIS[:cool]` tag
+pub struct Thermostat {
+    pub setpoint: f32,
+    pub hysteresis: f32,
+    pub cooling: bool,
+}
+
+impl Thermostat {
+    pub fn from_tags(tags: &Vec<String>) -> Option<Thermostat> {
+        let tag = tags.iter().find(|t| t.starts_with("thermostat:"))?;
+        let v: Vec<&str> = tag.split(":").collect();
+        Some(Thermostat {
+            setpoint: v.get(1).and_then(|x| x.parse::<f32>().ok())?,
+            hysteresis: v
+                .get(2)
+                .and_then(|x| x.parse::<f32>().ok())
+                .unwrap_or(THERMOSTAT_DEFAULT_HYSTERESIS),
+            cooling: v.get(3) == Some(&"cool"),
+        })
+    }
+
+    /// Returns the new output state, switching at setpoint ± hysteresis
+    pub fn demand(&self, temp: f32, currently_on: Option<bool>) -> bool {
+        //positive error means the temperature is on the "needs action" side
+        let error = if self.cooling {
+            temp - self.setpoint
+        } else {
+            self.setpoint - temp
+        };
+        match currently_on {
+            None => error > 0.0,
+            Some(true) => error > -self.hysteresis,
+            Some(false) => error >= self.hysteresis,
+        }
+    }
+}
+
 pub struct EnvSensorDevices {
     pub kinds: HashMap<i32, String>,
     pub env_sensors: Vec<EnvSensor>,
@@ -242,6 +425,14 @@ impl EnvSensorDevices {
             },
             ow_address: address,
             file: None,
+            stats: Default::default(),
+            thermostat_on: None,
+            last_temperature: None,
+            last_humidity: None,
+            last_vdd: None,
+            last_vad: None,
+            influx_pending: false,
+            temp_alarm_active: false,
         };
         env_sensor.open();
         self.env_sensors.push(env_sensor);
@@ -251,20 +442,267 @@ impl EnvSensorDevices {
 pub struct OneWireEnv {
     pub name: String,
     pub ow_transmitter: Sender<OneWireTask>,
+    pub transmitter: Sender<DbTask>,
     pub env_sensor_devices: Arc<RwLock<EnvSensorDevices>>,
+    pub frost_guard_script: Option<String>,
+    pub frost_guard_power: Option<f32>,
+    pub bus_metrics: Arc<RwLock<BusMetrics>>,
+    pub sensor_values: Arc<RwLock<SensorValues>>,
+    pub heating_season: Arc<RwLock<HeatingSeason>>,
+    pub lcd_transmitter: Sender<LcdTask>,
 }
 
 impl OneWireEnv {
+    fn get_frost_threshold(tags: &Vec<String>) -> Option<f32> {
+        for tag in tags {
+            if tag.starts_with("frost_guard:") {
+                let v: Vec<&str> = tag.split(":").collect();
+                return v.get(1).and_then(|x| x.parse::<f32>().ok());
+            }
+        }
+        None
+    }
+
+    fn thermostat(&self, sensor: &mut EnvSensor, temp: f32) {
+        let thermostat = match Thermostat::from_tags(&sensor.tags) {
+            Some(thermostat) => thermostat,
+            None => return,
+        };
+        //heating is only allowed in the heating season
+        let on = thermostat.demand(temp, sensor.thermostat_on)
+            && (thermostat.cooling || self.heating_season.read().unwrap().is_active());
+        if sensor.thermostat_on != Some(on) {
+            info!(
+                "{}: {}: 🌡️ thermostat: temperature {}, setpoint {} ±{}{}: turning {}",
+                self.name,
+                sensor.name,
+                units::temperature(temp),
+                units::temperature(thermostat.setpoint),
+                units::temperature_delta(thermostat.hysteresis),
+                if thermostat.cooling { " (cooling)" } else { "" },
+                if on { "on" } else { "off" },
+            );
+        } else if !on {
+            return;
+        }
+        sensor.thermostat_on = Some(on);
+
+        for id_relay in &sensor.associated_relays {
+            //when on, keep prolonging until the next measurement, so a dead sensor can't
+            //leave the output on forever
+            let task = OneWireTask {
+                command: if on {
+                    TaskCommand::TurnOnProlong
+                } else {
+                    TaskCommand::TurnOff
+                },
+                id_relay: Some(*id_relay),
+                tag_group: None,
+                id_yeelight: None,
+                id_plug: None,
+                duration: if on {
+                    Some(Duration::from_secs_f32(TEMP_CHECK_INTERVAL_SECS * 2.0))
+                } else {
+                    None
+                },
+            };
+            let _ = self.ow_transmitter.send(task);
+        }
+    }
+
+    fn temp_alarm(&self, sensor: &mut EnvSensor, temp: f32) {
+        let alarm = match TempAlarm::from_tags(&sensor.tags) {
+            Some(alarm) => alarm,
+            None => return,
+        };
+        let active = alarm.check(temp, sensor.temp_alarm_active);
+        if active == sensor.temp_alarm_active {
+            return;
+        }
+        sensor.temp_alarm_active = active;
+        if !active {
+            info!(
+                "{}: {}: 🌡️ temperature {} is back within the {} {} limit",
+                self.name,
+                sensor.name,
+                units::temperature(temp),
+                if alarm.above { "<" } else { ">" },
+                units::temperature(alarm.threshold),
+            );
+            return;
+        }
+
+        warn!(
+            "{}: {}: 🚨 temperature {} is {} {}",
+            self.name,
+            sensor.name,
+            units::temperature(temp),
+            if alarm.above { "above" } else { "below" },
+            units::temperature(alarm.threshold),
+        );
+        match alarm.command {
+            Some(cmd) => {
+                let mut cmd = cmd;
+                cmd = str::replace(&cmd, "%name%", &sensor.name);
+                cmd = str::replace(&cmd, "%temp%", &format!("{:.1}", temp));
+                cmd = str::replace(&cmd, "%threshold%", &format!("{:.1}", alarm.threshold));
+                thread::spawn(move || StateMachine::run_shell_command(cmd));
+            }
+            None => {
+                for id_relay in &sensor.associated_relays {
+                    let task = OneWireTask {
+                        command: TaskCommand::TurnOnProlong,
+                        id_relay: Some(*id_relay),
+                        tag_group: None,
+                        id_yeelight: None,
+                        id_plug: None,
+                        duration: None, //take default
+                    };
+                    let _ = self.ow_transmitter.send(task);
+                }
+            }
+        }
+    }
+
+    /// Shows the reading on the LCD line given by the `lcd:<line>` tag
+    fn show_on_lcd(&self, sensor: &EnvSensor, text: String) {
+        let line = sensor
+            .tags
+            .iter()
+            .find(|t| t.starts_with("lcd:"))
+            .and_then(|t| t["lcd:".len()..].parse::<u8>().ok());
+        if let Some(line) = line {
+            let task = LcdTask {
+                command: LcdTaskCommand::SetLineText,
+                int_arg: line,
+                string_arg: Some(format!("{}: {}", sensor.name, text)),
+            };
+            let _ = self.lcd_transmitter.send(task);
+        }
+    }
+
+    fn run_frost_guard_script(&self, state: &str, name: &str, temp: f32) {
+        if let Some(ref cmd) = self.frost_guard_script {
+            let mut cmd = cmd.clone();
+            cmd = str::replace(&cmd, "%state%", state);
+            cmd = str::replace(&cmd, "%name%", name);
+            cmd = str::replace(&cmd, "%temp%", &format!("{:.1}", temp));
+            thread::spawn(move || StateMachine::run_shell_command(cmd));
+        }
+    }
+
+    fn frost_guard(&self, frost_active: &mut Option<Instant>, readings: &Vec<(String, f32, f32)>) {
+        if readings.is_empty() {
+            return;
+        }
+
+        //the coldest sensor (relative to its threshold) decides
+        let (name, temp, threshold) = readings
+            .iter()
+            .min_by(|a, b| {
+                (a.1 - a.2)
+                    .partial_cmp(&(b.1 - b.2))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+            .unwrap();
+
+        if temp < threshold {
+            if frost_active.is_none() {
+                warn!(
+                    "{}: ❄️ {}: temperature {} is below {}, enabling frost protection",
+                    self.name,
+                    name,
+                    units::temperature(*temp),
+                    units::temperature(threshold)
+                );
+                *frost_active = Some(Instant::now());
+                self.run_frost_guard_script("on", name, *temp);
+            }
+            //turn on/prolong the protection until the next measurement
+            let task = OneWireTask {
+                command: TaskCommand::TurnOnProlong,
+                id_relay: None,
+                tag_group: Some(FROST_PROTECT_TAG.to_owned()),
+                id_yeelight: None,
+                id_plug: None,
+                duration: Some(Duration::from_secs_f32(TEMP_CHECK_INTERVAL_SECS * 2.0)),
+            };
+            let _ = self.ow_transmitter.send(task);
+        } else if *temp >= threshold + FROST_GUARD_HYSTERESIS {
+            if let Some(started) = frost_active.take() {
+                let run_time = started.elapsed();
+                let energy = self
+                    .frost_guard_power
+                    .map(|power| power * run_time.as_secs_f32() / 3600.0 / 1000.0);
+                info!(
+                    "{}: ☀️ {}: temperature {}, disabling frost protection, run time: {}{}",
+                    self.name,
+                    name,
+                    units::temperature(*temp),
+                    format_duration(Duration::from_secs(run_time.as_secs())),
+                    match energy {
+                        Some(kwh) =>
+                            format!(", estimated energy: {}", units::energy(kwh as f64, 2)),
+                        None => "".to_string(),
+                    }
+                );
+                let task = OneWireTask {
+                    command: TaskCommand::TurnOff,
+                    id_relay: None,
+                    tag_group: Some(FROST_PROTECT_TAG.to_owned()),
+                    id_yeelight: None,
+                    id_plug: None,
+                    duration: None,
+                };
+                let _ = self.ow_transmitter.send(task);
+                let task = DbTask {
+                    command: CommandCode::IncrementFrostProtectionTime,
+                    value: Some(run_time.as_secs() as i32),
+                };
+                let _ = self.transmitter.send(task);
+                self.run_frost_guard_script("off", name, *temp);
+            }
+        }
+    }
+
+    fn update_metrics(&self, env_sensors: &Vec<EnvSensor>) {
+        let mut metrics = self.bus_metrics.write().unwrap();
+        for sensor in env_sensors {
+            metrics.update(
+                get_w1_device_name(sensor.ow_family, sensor.ow_address),
+                &sensor.stats,
+            );
+        }
+    }
+
+    fn check_heating_season(&self) {
+        let mut heating_season = self.heating_season.write().unwrap();
+        if let Some(active) = heating_season.evaluate() {
+            self.sensor_values.write().unwrap().insert(
+                HEATING_SEASON_VALUE_NAME.to_string(),
+                if active { 1.0 } else { 0.0 },
+            );
+            let task = LcdTask {
+                command: LcdTaskCommand::SetLineText,
+                int_arg: heating_season.lcd_line,
+                string_arg: Some(format!("Heating: {}", if active { "on" } else { "off" })),
+            };
+            let _ = self.lcd_transmitter.send(task);
+        }
+    }
+
     pub fn worker(&self, worker_cancel_flag: Arc<AtomicBool>) {
         info!("{}: Starting thread", self.name);
         let mut last_temp_check = Instant::now();
         let mut last_humid_check = Instant::now();
+        let mut frost_active: Option<Instant> = None;
 
         loop {
             if worker_cancel_flag.load(Ordering::SeqCst) {
                 debug!("Got terminate signal from main");
                 break;
             }
+            systemd::heartbeat(&self.name);
 
             if last_temp_check.elapsed() > Duration::from_secs_f32(TEMP_CHECK_INTERVAL_SECS) {
                 last_temp_check = Instant::now();
@@ -276,21 +714,54 @@ impl OneWireEnv {
                     //fixme: do we really need to clone this HashMap to use it below?
                     let _kinds_cloned = env_sensor_dev.kinds.clone();
 
+                    let mut frost_readings = vec![];
                     for sensor in &mut env_sensor_dev.env_sensors {
                         if sensor.is_temp_sensor() {
                             match sensor.read_temperature() {
                                 Some(temp) => {
                                     info!(
-                                        "{}: {}: 🌡️ temperature: {} °C",
+                                        "{}: {}: 🌡️ temperature: {}",
                                         get_w1_device_name(sensor.ow_family, sensor.ow_address),
                                         sensor.name,
-                                        temp,
+                                        units::temperature(temp),
                                     );
+                                    self.sensor_values
+                                        .write()
+                                        .unwrap()
+                                        .insert(sensor.name.clone(), temp);
+                                    sensor.last_temperature = Some(temp);
+                                    sensor.influx_pending = true;
+                                    let _ = self.transmitter.send(DbTask {
+                                        command: CommandCode::StoreTemperature,
+                                        value: Some(sensor.id_sensor),
+                                    });
+                                    self.show_on_lcd(sensor, units::temperature(temp));
+                                    self.thermostat(sensor, temp);
+                                    self.temp_alarm(sensor, temp);
+                                    if let Some(threshold) =
+                                        OneWireEnv::get_frost_threshold(&sensor.tags)
+                                    {
+                                        frost_readings.push((sensor.name.clone(), temp, threshold));
+                                    }
                                 }
                                 _ => {}
                             }
                         }
                     }
+                    self.frost_guard(&mut frost_active, &frost_readings);
+
+                    //outside temperature for the heating season average
+                    {
+                        let mut heating_season = self.heating_season.write().unwrap();
+                        let temp = heating_season
+                            .outside_sensor
+                            .as_ref()
+                            .and_then(|name| self.sensor_values.read().unwrap().get(name).cloned());
+                        if let Some(temp) = temp {
+                            heating_season.add_sample(temp);
+                        }
+                    }
+                    self.update_metrics(&env_sensor_dev.env_sensors);
                 }
             }
 
@@ -309,11 +780,36 @@ impl OneWireEnv {
                             match sensor.read_humidity() {
                                 Some(humid) => {
                                     info!(
-                                        "{}: {}: 💧 humidity: {} %RH, 🌡️ temperature: {} °C",
+                                        "{}: {}: 💧 humidity: {:.1} %RH, 🌡️ temperature: {}, vdd: {} V, vad: {} V",
                                         get_w1_device_name(sensor.ow_family, sensor.ow_address),
                                         sensor.name,
                                         humid.0,
-                                        humid.1,
+                                        units::temperature(humid.1),
+                                        sensor.last_vdd.unwrap_or_default(),
+                                        sensor.last_vad.unwrap_or_default(),
+                                    );
+                                    self.sensor_values
+                                        .write()
+                                        .unwrap()
+                                        .insert(sensor.name.clone(), humid.0);
+                                    sensor.last_humidity = Some(humid.0);
+                                    sensor.last_temperature = Some(humid.1);
+                                    sensor.influx_pending = true;
+                                    let _ = self.transmitter.send(DbTask {
+                                        command: CommandCode::StoreHumidity,
+                                        value: Some(sensor.id_sensor),
+                                    });
+                                    let _ = self.transmitter.send(DbTask {
+                                        command: CommandCode::StoreTemperature,
+                                        value: Some(sensor.id_sensor),
+                                    });
+                                    self.show_on_lcd(
+                                        sensor,
+                                        format!(
+                                            "{:.0}%RH {}",
+                                            humid.0,
+                                            units::temperature(humid.1)
+                                        ),
                                     );
                                     for tag in &sensor.tags {
                                         if tag.starts_with("humid_threshold:") {
@@ -338,6 +834,7 @@ impl OneWireEnv {
                                                                         id_relay: Some(*id_relay),
                                                                         tag_group: None,
                                                                         id_yeelight: None,
+                                                                        id_plug: None,
                                                                         duration: None, //take default
                                                                     };
                                                                     let _ = self
@@ -358,11 +855,15 @@ impl OneWireEnv {
                             }
                         }
                     }
+                    self.update_metrics(&env_sensor_dev.env_sensors);
                 }
             }
 
+            self.check_heating_season();
+
             thread::sleep(Duration::from_millis(100));
         }
+        systemd::heartbeat_stop(&self.name);
         info!("{}: thread stopped", self.name);
     }
 }
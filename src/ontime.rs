@@ -0,0 +1,193 @@
+use chrono::{DateTime, Duration, Local, NaiveDate, TimeZone};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+pub const ONTIME_HISTORY_DAYS: usize = 400; //daily on-time records kept in memory
+pub const DEFAULT_ONTIME_DAYS: usize = 31; //days returned by the web api by default
+
+/// Switched device with the on-time accounted
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKind {
+    Relay,
+    Yeelight,
+    Plug,
+}
+
+impl DeviceKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceKind::Relay => "relay",
+            DeviceKind::Yeelight => "yeelight",
+            DeviceKind::Plug => "plug",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<DeviceKind> {
+        match kind {
+            "relay" => Some(DeviceKind::Relay),
+            "yeelight" => Some(DeviceKind::Yeelight),
+            "plug" => Some(DeviceKind::Plug),
+            _ => None,
+        }
+    }
+}
+
+/// Time a device was on during a single day
+#[derive(Clone, Serialize)]
+pub struct DailyOnTime {
+    pub kind: DeviceKind,
+    pub id: i32,
+    pub day: String,
+    pub on_secs: f64,
+}
+
+/// Time a device was on during a month, summed from the days
+#[derive(Clone, Serialize)]
+pub struct MonthlyOnTime {
+    pub kind: DeviceKind,
+    pub id: i32,
+    pub month: String,
+    pub on_secs: f64,
+    pub on_hours: f64,
+}
+
+/// Cumulative ON time of relays, yeelights and plugs, from the on/off
+/// timestamps sent by the onewire worker
+#[derive(Default)]
+pub struct OnTimeStats {
+    pub days: Vec<DailyOnTime>,
+    //seconds collected since the last flush to the database, per kind/id/day
+    pub pending: HashMap<(DeviceKind, i32, String), f64>,
+    //devices which are currently on
+    on_since: HashMap<(DeviceKind, i32), DateTime<Local>>,
+}
+
+impl OnTimeStats {
+    /// Device state change, the time between on and off is accounted
+    pub fn switched(&mut self, kind: DeviceKind, id: i32, on: bool, time: SystemTime) {
+        let time = DateTime::<Local>::from(time);
+        if on {
+            self.on_since.entry((kind, id)).or_insert(time);
+            return;
+        }
+        if let Some(since) = self.on_since.remove(&(kind, id)) {
+            self.add(kind, id, since, time);
+        }
+    }
+
+    /// Accounts the time of the devices still on up to now, so long runs
+    /// show up in the current day and are not lost on a crash
+    pub fn checkpoint(&mut self) {
+        let now = Local::now();
+        let running: Vec<_> = self
+            .on_since
+            .iter_mut()
+            .map(|(key, since)| (*key, std::mem::replace(since, now)))
+            .collect();
+        for ((kind, id), since) in running {
+            self.add(kind, id, since, now);
+        }
+    }
+
+    //adds the interval, split on the local midnights
+    fn add(&mut self, kind: DeviceKind, id: i32, mut start: DateTime<Local>, end: DateTime<Local>) {
+        while start < end {
+            let next_day = start.date_naive() + Duration::days(1);
+            let midnight = Local
+                .from_local_datetime(&next_day.and_hms_opt(0, 0, 0).unwrap())
+                .earliest()
+                .unwrap_or(end);
+            let segment_end = midnight.min(end);
+            let secs = (segment_end - start).num_milliseconds() as f64 / 1000.0;
+            self.add_day(kind, id, &start.format("%Y-%m-%d").to_string(), secs);
+            start = segment_end;
+        }
+    }
+
+    fn add_day(&mut self, kind: DeviceKind, id: i32, day: &str, secs: f64) {
+        if secs <= 0.0 {
+            return;
+        }
+        *self
+            .pending
+            .entry((kind, id, day.to_string()))
+            .or_insert(0.0) += secs;
+        match self
+            .days
+            .iter_mut()
+            .find(|d| d.kind == kind && d.id == id && d.day == day)
+        {
+            Some(entry) => entry.on_secs += secs,
+            None => {
+                self.days.push(DailyOnTime {
+                    kind,
+                    id,
+                    day: day.to_string(),
+                    on_secs: secs,
+                });
+                self.trim();
+            }
+        }
+    }
+
+    /// Restores a day loaded from the database, adding what was collected before the loading
+    pub fn restore_day(&mut self, day: DailyOnTime) {
+        match self
+            .days
+            .iter_mut()
+            .find(|d| d.kind == day.kind && d.id == day.id && d.day == day.day)
+        {
+            Some(entry) => entry.on_secs += day.on_secs,
+            None => self.days.push(day),
+        }
+        self.trim();
+    }
+
+    //keeps only the last ONTIME_HISTORY_DAYS days
+    fn trim(&mut self) {
+        let oldest = Local::now().date_naive() - Duration::days(ONTIME_HISTORY_DAYS as i64);
+        self.days.retain(|d| {
+            NaiveDate::parse_from_str(&d.day, "%Y-%m-%d").map_or(false, |date| date > oldest)
+        });
+        self.days.sort_by(|a, b| a.day.cmp(&b.day));
+    }
+
+    /// Daily on-time of the last `days` days
+    pub fn daily(&self, days: usize) -> Vec<DailyOnTime> {
+        let oldest = Local::now().date_naive() - Duration::days(days as i64);
+        self.days
+            .iter()
+            .filter(|d| {
+                NaiveDate::parse_from_str(&d.day, "%Y-%m-%d").map_or(false, |date| date > oldest)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// On-time summed per month
+    pub fn monthly(&self) -> Vec<MonthlyOnTime> {
+        let mut months: Vec<MonthlyOnTime> = vec![];
+        for day in &self.days {
+            let month = day.day[..7].to_string();
+            match months
+                .iter_mut()
+                .find(|m| m.kind == day.kind && m.id == day.id && m.month == month)
+            {
+                Some(entry) => entry.on_secs += day.on_secs,
+                None => months.push(MonthlyOnTime {
+                    kind: day.kind,
+                    id: day.id,
+                    month,
+                    on_secs: day.on_secs,
+                    on_hours: 0.0,
+                }),
+            }
+        }
+        for month in &mut months {
+            month.on_hours = (month.on_secs / 36.0).round() / 100.0;
+        }
+        months
+    }
+}
@@ -0,0 +1,366 @@
+use crate::device_io::{DeviceAddress, DeviceIo};
+use crate::events::{self, Event, EventSender};
+use crate::influx::{Client, InfluxDbWriteable, Timestamp};
+use crate::remeha::{RemehaSetpoint, RemehaSetpointRequest};
+use crate::systemd;
+use chrono::Utc;
+use simplelog::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const OPENTHERM_REPORT_INTERVAL_SECS: f32 = 30.0; //secs between writing the values to influxdb
+pub const OPENTHERM_MIN_REPORT_INTERVAL_SECS: f32 = 5.0;
+pub const OPENTHERM_STATS_DUMP_INTERVAL_SECS: f32 = 3600.0; //secs between showing stats
+pub const OPENTHERM_DEFAULT_INFLUX_DATABASE: &str = "opentherm";
+pub static OPENTHERM_DEFAULT_BOILER: &str = "opentherm";
+
+//standard OpenTherm data IDs
+pub const OT_ID_STATUS: u8 = 0;
+//data IDs with a f8.8 value, as (id, influxdb field)
+const OT_F88_VALUES: &[(u8, &str)] = &[
+    (1, "control_setpoint"),
+    (16, "room_setpoint"),
+    (17, "modulation"),
+    (18, "ch_pressure"),
+    (19, "dhw_flow"),
+    (24, "room_temp"),
+    (25, "flow_temp"),
+    (26, "dhw_temp"),
+    (27, "outside_temp"),
+    (28, "return_temp"),
+    (56, "dhw_setpoint"),
+    (57, "max_ch_setpoint"),
+];
+
+// Just a generic Result type to ease error handling for us. Errors in multithreaded
+// async contexts needs some extra restrictions
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// OpenTherm message type, the 3 upper bits of the frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageType {
+    ReadData,
+    WriteData,
+    InvalidData,
+    Reserved,
+    ReadAck,
+    WriteAck,
+    DataInvalid,
+    UnknownDataId,
+}
+
+impl MessageType {
+    fn from_bits(bits: u32) -> Self {
+        match bits & 0x7 {
+            0 => MessageType::ReadData,
+            1 => MessageType::WriteData,
+            2 => MessageType::InvalidData,
+            3 => MessageType::Reserved,
+            4 => MessageType::ReadAck,
+            5 => MessageType::WriteAck,
+            6 => MessageType::DataInvalid,
+            _ => MessageType::UnknownDataId,
+        }
+    }
+}
+
+/// A frame reported by the OpenTherm Gateway, eg. `B40190000`
+#[derive(Clone, Copy, Debug)]
+pub struct Message {
+    /// T: thermostat, B: boiler, R: request of the gateway, A: answer of the gateway
+    pub source: char,
+    pub msg_type: MessageType,
+    pub data_id: u8,
+    pub value: u16,
+}
+
+impl Message {
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        let source = line.chars().next()?;
+        if !matches!(source, 'T' | 'B' | 'R' | 'A') || line.len() != 9 {
+            return None;
+        }
+        let frame = u32::from_str_radix(&line[1..], 16).ok()?;
+        Some(Message {
+            source,
+            msg_type: MessageType::from_bits(frame >> 28),
+            data_id: (frame >> 16) as u8,
+            value: frame as u16,
+        })
+    }
+
+    /// Value of the data IDs in the signed fixed point 8.8 format
+    pub fn f88(&self) -> f32 {
+        self.value as i16 as f32 / 256.0
+    }
+
+    /// True for the values valid for the boiler: the boiler replies and the setpoints
+    /// written by the thermostat (or by the gateway instead of it)
+    fn is_valid_data(&self) -> bool {
+        match self.source {
+            'B' | 'A' => matches!(self.msg_type, MessageType::ReadAck | MessageType::WriteAck),
+            _ => self.msg_type == MessageType::WriteData,
+        }
+    }
+}
+
+/// Last values decoded from the OpenTherm traffic
+#[derive(Clone, Default)]
+pub struct OpenThermData {
+    pub status: Option<u16>,
+    pub values: BTreeMap<&'static str, f32>,
+}
+
+impl OpenThermData {
+    /// Stores the value of a known data ID, returns false for the other ones
+    pub fn update(&mut self, message: &Message) -> bool {
+        if !message.is_valid_data() {
+            return false;
+        }
+        if message.data_id == OT_ID_STATUS {
+            //the master flags from the thermostat are kept in the high byte
+            let status = self.status.unwrap_or(0);
+            self.status = Some(match message.source {
+                'B' | 'A' => message.value,
+                _ => (message.value & 0xff00) | (status & 0x00ff),
+            });
+            return true;
+        }
+        match OT_F88_VALUES.iter().find(|(id, _)| *id == message.data_id) {
+            Some((_, field)) => {
+                self.values.insert(field, message.f88());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn flag(&self, bit: u16) -> Option<bool> {
+        self.status.map(|status| status & (1 << bit) != 0)
+    }
+
+    pub fn fault(&self) -> Option<bool> {
+        self.flag(0)
+    }
+
+    pub fn ch_mode(&self) -> Option<bool> {
+        self.flag(1)
+    }
+
+    pub fn dhw_mode(&self) -> Option<bool> {
+        self.flag(2)
+    }
+
+    pub fn flame(&self) -> Option<bool> {
+        self.flag(3)
+    }
+
+    pub fn get(&self, field: &str) -> Option<f32> {
+        self.values.get(field).copied()
+    }
+
+    fn status_description(&self) -> &'static str {
+        match (self.fault(), self.flame(), self.dhw_mode(), self.ch_mode()) {
+            (Some(true), _, _, _) => "Fault ⚠️",
+            (_, Some(true), Some(true), _) => "Burning DHW 🔥 ◾ domestic hot water 🚰",
+            (_, Some(true), _, Some(true)) => "Burning CH 🔥 ◾ central heating 🛖",
+            (_, Some(true), _, _) => "Burning 🔥",
+            (Some(false), _, _, _) => "Standby 💤",
+            _ => "Unknown State",
+        }
+    }
+}
+
+impl fmt::Display for OpenThermData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.status_description())?;
+        for (field, value) in &self.values {
+            write!(f, ", {}: {:.2}", field, value)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct OpenTherm {
+    pub name: String,
+    pub display_name: String,
+    pub device: DeviceAddress,
+    pub influxdb_url: Option<String>,
+    pub influx_database: String,
+    pub allow_write: bool,
+    pub setpoint_requests: Arc<Mutex<Vec<RemehaSetpointRequest>>>,
+    pub events: EventSender,
+    pub report_interval: Duration,
+    pub stats_interval: Duration,
+}
+
+impl OpenTherm {
+    /// Gateway command overriding the setpoint: CS (control setpoint) or SW (DHW setpoint)
+    fn setpoint_command(setpoint: RemehaSetpoint) -> String {
+        match setpoint {
+            RemehaSetpoint::Ch(value) => format!("CS={}\r\n", value),
+            RemehaSetpoint::Dhw(value) => format!("SW={}\r\n", value),
+        }
+    }
+
+    /// Takes the pending setpoint requests addressed to this gateway
+    fn take_setpoint_requests(&self) -> Vec<RemehaSetpoint> {
+        let mut taken = vec![];
+        if let Ok(mut requests) = self.setpoint_requests.lock() {
+            requests.retain(|request| {
+                if request.boiler == self.name {
+                    taken.push(request.setpoint);
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        taken
+    }
+
+    async fn save_to_influxdb(&self, data: &OpenThermData) {
+        let url = match &self.influxdb_url {
+            Some(url) => url,
+            None => return,
+        };
+        let client = Client::new(url, self.influx_database.as_str());
+        let mut query = Timestamp::from(Utc::now())
+            .into_query("opentherm")
+            .add_tag("boiler", self.name.as_str())
+            .add_field_opt("fault", data.fault())
+            .add_field_opt("ch_mode", data.ch_mode())
+            .add_field_opt("dhw_mode", data.dhw_mode())
+            .add_field_opt("flame", data.flame());
+        for (field, value) in &data.values {
+            query = query.add_field(*field, *value);
+        }
+        match client.query(&query).await {
+            Ok(msg) => debug!("{} influxdb write success: {:?}", self.display_name, msg),
+            Err(e) => error!("{} influxdb write error: {:?}", self.display_name, e),
+        }
+    }
+
+    //lines which aren't OpenTherm frames are the gateway replies to our commands
+    fn process_line(&self, line: &str, data: &mut OpenThermData, messages: &mut u64) {
+        let line = line.trim();
+        if line.is_empty() {
+            return;
+        }
+        match Message::parse(line) {
+            Some(message) => {
+                *messages += 1;
+                if data.update(&message) {
+                    debug!("{} {:?}", self.display_name, message);
+                }
+            }
+            None if line.len() == 2 || line.starts_with("Error") => {
+                //NG, SE, BV, OR, NS, NF: the command was not accepted
+                error!("{} ⚙️ gateway error: {}", self.display_name, line);
+            }
+            None if line.contains(':') => info!("{} ⚙️ gateway: {}", self.display_name, line),
+            None => debug!("{} ignoring line: {:?}", self.display_name, line),
+        }
+    }
+
+    pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
+        info!("{} Starting task", self.display_name);
+        let mut device = DeviceIo::new(self.display_name.clone(), self.device.clone());
+        let mut report_interval = Instant::now();
+        let mut stats_interval = Instant::now();
+        let mut line = String::new();
+        let mut data = OpenThermData::default();
+        let mut last_status: Option<&'static str> = None;
+        let mut messages: u64 = 0;
+
+        loop {
+            if worker_cancel_flag.load(Ordering::SeqCst) {
+                debug!("{} Got terminate signal from main", self.display_name);
+                break;
+            }
+            systemd::heartbeat(&self.name);
+
+            if stats_interval.elapsed() > self.stats_interval {
+                stats_interval = Instant::now();
+                info!(
+                    "{} 📊 statistics: messages: {}, reconnects: {}",
+                    self.display_name, messages, device.reconnects
+                );
+            }
+
+            if !device.open().await {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+
+            for setpoint in self.take_setpoint_requests() {
+                if self.allow_write {
+                    info!("{} ⚙️ overriding {}", self.display_name, setpoint);
+                    device
+                        .send(OpenTherm::setpoint_command(setpoint).as_bytes())
+                        .await;
+                } else {
+                    warn!(
+                        "{} ⚙️ ignoring {}: setpoint overrides are disabled in the config",
+                        self.display_name, setpoint
+                    );
+                }
+            }
+
+            if let Some(received) = device.read_available(256, Duration::from_millis(500)).await {
+                line.push_str(&String::from_utf8_lossy(&received));
+                while let Some(pos) = line.find('\n') {
+                    let text: String = line.drain(..=pos).collect();
+                    self.process_line(&text, &mut data, &mut messages);
+                }
+            }
+
+            if data.status.is_some() {
+                let status = data.status_description();
+                if last_status != Some(status) {
+                    info!(
+                        "{} boiler status: {} ➡️ {}",
+                        self.display_name,
+                        last_status.unwrap_or("-"),
+                        status
+                    );
+                    last_status = Some(status);
+                }
+            }
+
+            if report_interval.elapsed() > self.report_interval {
+                report_interval = Instant::now();
+                if data.status.is_none() && data.values.is_empty() {
+                    warn!("{} no OpenTherm data received", self.display_name);
+                    continue;
+                }
+                debug!("{} {}", self.display_name, data);
+                self.save_to_influxdb(&data).await;
+                events::publish(
+                    &self.events,
+                    Event::Boiler {
+                        boiler: self.name.clone(),
+                        status: data.status_description().to_string(),
+                        flow_temp: data.get("flow_temp").unwrap_or_default(),
+                        return_temp: data.get("return_temp").unwrap_or_default(),
+                        dhw_temp: data.get("dhw_temp").unwrap_or_default(),
+                        outside_temp: data.get("outside_temp").unwrap_or_default(),
+                        pressure: data.get("ch_pressure").unwrap_or_default(),
+                    },
+                );
+            }
+        }
+
+        info!(
+            "{} 📊 statistics: messages: {}, reconnects: {}",
+            self.display_name, messages, device.reconnects
+        );
+        systemd::heartbeat_stop(&self.name);
+        info!("{} task stopped", self.display_name);
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+use simplelog::*;
+use std::io::{self, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+pub const OWSERVER_DEFAULT_PORT: u16 = 4304;
+pub const OWSERVER_TIMEOUT_SECS: f32 = 2.0;
+pub const OWSERVER_MAX_READ_SIZE: i32 = 8192;
+
+//owserver message types
+const MSG_READ: i32 = 2;
+const MSG_WRITE: i32 = 3;
+//control flags: ownet client, owfs addresses in the f.i format
+const OWSERVER_FLAGS: i32 = 0x0000_0100;
+
+/// owserver (owfs TCP protocol) client, a new connection for every message
+pub struct OwServer {
+    pub address: String,
+}
+
+impl OwServer {
+    pub fn new(address: &str) -> Self {
+        let address = if address.contains(':') {
+            address.to_string()
+        } else {
+            format!("{}:{}", address, OWSERVER_DEFAULT_PORT)
+        };
+        OwServer { address }
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let addr =
+            self.address.to_socket_addrs()?.next().ok_or_else(|| {
+                Error::new(ErrorKind::NotFound, "unable to resolve owserver address")
+            })?;
+        let timeout = Duration::from_secs_f32(OWSERVER_TIMEOUT_SECS);
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(stream)
+    }
+
+    fn transact(&self, msg_type: i32, payload: Vec<u8>, size: i32) -> io::Result<Vec<u8>> {
+        let mut stream = self.connect()?;
+
+        //header: version, payload length, type, flags, size, offset
+        let mut message = vec![];
+        for field in [0, payload.len() as i32, msg_type, OWSERVER_FLAGS, size, 0] {
+            message.extend_from_slice(&field.to_be_bytes());
+        }
+        message.extend(payload);
+        stream.write_all(&message)?;
+
+        loop {
+            let mut header = [0u8; 24];
+            stream.read_exact(&mut header)?;
+            let field = |i: usize| {
+                i32::from_be_bytes([
+                    header[i * 4],
+                    header[i * 4 + 1],
+                    header[i * 4 + 2],
+                    header[i * 4 + 3],
+                ])
+            };
+            let (payload_len, ret, size) = (field(1), field(2), field(4));
+
+            //keepalive sent by the server during long operations
+            if payload_len < 0 {
+                continue;
+            }
+            if ret < 0 {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("owserver error code: {}", -ret),
+                ));
+            }
+            let mut data = vec![0u8; payload_len as usize];
+            stream.read_exact(&mut data)?;
+            data.truncate(size.max(0) as usize);
+            return Ok(data);
+        }
+    }
+
+    pub fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let mut payload = path.as_bytes().to_vec();
+        payload.push(0);
+        self.transact(MSG_READ, payload, OWSERVER_MAX_READ_SIZE)
+    }
+
+    pub fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        let mut payload = path.as_bytes().to_vec();
+        payload.push(0);
+        payload.extend_from_slice(data);
+        self.transact(MSG_WRITE, payload, data.len() as i32)
+            .map(|_| ())
+    }
+}
+
+/// Device name as used by owfs: family and the ROM bytes in bus order, eg. `3A.0B4C1D000000`
+pub fn get_owfs_device_name(family_code: u8, address: u64) -> String {
+    let rom: Vec<String> = address.to_le_bytes()[..6]
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    format!("{:02X}.{}", family_code, rom.concat())
+}
+
+/// Single byte owfs property, read and written like the sysfs `state`/`output` files
+pub struct OwFile {
+    server: OwServer,
+    path: String,
+    //owfs PIO bits are 1 for a conducting output, sysfs has the raw (active low) latch
+    inverted: bool,
+    position: u64,
+}
+
+impl OwFile {
+    pub fn new(server: &str, device: String, property: &str, inverted: bool) -> Self {
+        OwFile {
+            server: OwServer::new(server),
+            path: format!("/uncached/{}/{}", device, property),
+            inverted,
+            position: 0,
+        }
+    }
+
+    fn convert(&self, value: u8) -> u8 {
+        if self.inverted {
+            !value
+        } else {
+            value
+        }
+    }
+}
+
+impl Read for OwFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position > 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let data = self.server.read(&self.path)?;
+        let text = String::from_utf8_lossy(&data);
+        let value: u8 = text.trim().parse().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{}: invalid value: {:?}", self.path, text),
+            )
+        })?;
+        debug!("owserver: {}: read: {}", self.path, value);
+        buf[0] = self.convert(value);
+        self.position = 1;
+        Ok(1)
+    }
+}
+
+impl Write for OwFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let value = self.convert(buf[0]).to_string();
+        debug!("owserver: {}: write: {}", self.path, value);
+        self.server.write(&self.path, value.as_bytes())?;
+        self.position = 1;
+        Ok(1)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for OwFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            _ => 0,
+        };
+        Ok(self.position)
+    }
+}
@@ -0,0 +1,200 @@
+use crate::chaos::{self, Fault};
+use simplelog::*;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{SendError, TryRecvError};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::Notify;
+
+pub const QUEUE_DROP_WARN_EVERY: u64 = 100; //log every n-th dropped message only
+pub const DB_QUEUE_CAPACITY: usize = 1024;
+pub const ONEWIRE_QUEUE_CAPACITY: usize = 256;
+pub const LCD_QUEUE_CAPACITY: usize = 64;
+pub const MQTT_QUEUE_CAPACITY: usize = 1024;
+
+/// What to do with a new message when the queue is full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// the new message is discarded (commands which are obsolete when late)
+    DropNewest,
+    /// the oldest queued message is discarded (state updates where the latest wins)
+    DropOldest,
+}
+
+/// Queue statistics shared with the metrics endpoint
+pub struct QueueStats {
+    pub name: &'static str,
+    pub capacity: usize,
+    pub depth: AtomicUsize,
+    pub max_depth: AtomicUsize,
+    pub sent: AtomicU64,
+    pub dropped: AtomicU64,
+}
+
+#[derive(Default)]
+pub struct QueueMetrics {
+    pub queues: Vec<Arc<QueueStats>>,
+}
+
+impl QueueMetrics {
+    /// Renders the queue stats in Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let metrics: [(&str, &str, fn(&QueueStats) -> u64); 5] = [
+            ("queue_depth", "gauge", |s| {
+                s.depth.load(Ordering::Relaxed) as u64
+            }),
+            ("queue_max_depth", "gauge", |s| {
+                s.max_depth.load(Ordering::Relaxed) as u64
+            }),
+            ("queue_capacity", "gauge", |s| s.capacity as u64),
+            ("queue_sent_total", "counter", |s| {
+                s.sent.load(Ordering::Relaxed)
+            }),
+            ("queue_dropped_total", "counter", |s| {
+                s.dropped.load(Ordering::Relaxed)
+            }),
+        ];
+        for (metric, kind, value) in metrics.iter() {
+            let _ = writeln!(out, "# TYPE hard_{} {}", metric, kind);
+            for stats in &self.queues {
+                let _ = writeln!(
+                    out,
+                    "hard_{}{{queue=\"{}\"}} {}",
+                    metric,
+                    stats.name,
+                    value(stats)
+                );
+            }
+        }
+        out
+    }
+}
+
+struct Shared<T> {
+    queue: Mutex<VecDeque<T>>,
+    policy: OverflowPolicy,
+    stats: Arc<QueueStats>,
+    //wakes up the receiver waiting in recv()
+    notify: Notify,
+}
+
+/// Producer side of a bounded queue, a drop-in for `std::sync::mpsc::Sender`
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// Consumer side of a bounded queue
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Creates a bounded queue and registers its stats in the metrics
+pub fn bounded<T>(
+    name: &'static str,
+    capacity: usize,
+    policy: OverflowPolicy,
+    metrics: &Arc<RwLock<QueueMetrics>>,
+) -> (Sender<T>, Receiver<T>) {
+    let stats = Arc::new(QueueStats {
+        name,
+        capacity,
+        depth: AtomicUsize::new(0),
+        max_depth: AtomicUsize::new(0),
+        sent: AtomicU64::new(0),
+        dropped: AtomicU64::new(0),
+    });
+    metrics.write().unwrap().queues.push(stats.clone());
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::with_capacity(capacity)),
+        policy,
+        stats,
+        notify: Notify::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Queues the message, never blocks: when full the overflow policy applies
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        let stats = &self.shared.stats;
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.len() >= stats.capacity {
+            let dropped = stats.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped % QUEUE_DROP_WARN_EVERY == 1 {
+                warn!(
+                    "queue {}: full ({} messages), dropping the {} message (dropped so far: {})",
+                    stats.name,
+                    stats.capacity,
+                    match self.shared.policy {
+                        OverflowPolicy::DropNewest => "newest",
+                        OverflowPolicy::DropOldest => "oldest",
+                    },
+                    dropped
+                );
+            }
+            match self.shared.policy {
+                OverflowPolicy::DropNewest => return Err(SendError(t)),
+                OverflowPolicy::DropOldest => {
+                    queue.pop_front();
+                }
+            }
+        }
+        queue.push_back(t);
+        stats.sent.fetch_add(1, Ordering::Relaxed);
+        stats.depth.store(queue.len(), Ordering::Relaxed);
+        stats.max_depth.fetch_max(queue.len(), Ordering::Relaxed);
+        drop(queue);
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        if chaos::inject(Fault::ChannelDelay) {
+            return Err(TryRecvError::Empty);
+        }
+        let mut queue = self.shared.queue.lock().unwrap();
+        match queue.pop_front() {
+            Some(t) => {
+                self.shared
+                    .stats
+                    .depth
+                    .store(queue.len(), Ordering::Relaxed);
+                Ok(t)
+            }
+            //only the receiver is holding the queue: all senders are gone
+            None if Arc::strong_count(&self.shared) == 1 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Waits for the next message, `None` when all senders are gone.
+    /// Dropping the senders doesn't wake the receiver, so it is meant to be used
+    /// in `select!` together with a timer.
+    pub async fn recv(&self) -> Option<T> {
+        loop {
+            match self.try_recv() {
+                Ok(t) => return Some(t),
+                Err(TryRecvError::Disconnected) => return None,
+                //a message sent in between is not lost: notify_one() keeps a permit
+                Err(TryRecvError::Empty) => self.shared.notify.notified().await,
+            }
+        }
+    }
+}
@@ -1,22 +1,38 @@
+use crate::device_io::{DeviceAddress, DeviceIo, ReplyFrame};
+use crate::events::{self, Event, EventSender};
+use crate::influx::{self, Client, InfluxDbWriteable};
+use crate::lcdproc::{LcdTask, LcdTaskCommand};
+use crate::mqtt::MqttEvent;
+use crate::notify::{Notification, Severity};
 use crate::onewire::StateMachine;
+use crate::queue::Sender;
+use crate::systemd;
+use crate::worker_error::{ErrorReporter, WorkerError};
 use chrono::{DateTime, Utc};
 use crc16::*;
-use influxdb::{Client, InfluxDbWriteable};
 use simplelog::*;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
-use std::io;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time::{Duration, Instant};
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use tokio::time::timeout;
-use tokio_compat_02::FutureExt;
+use thiserror::Error;
 
 pub const REMEHA_POLL_INTERVAL_SECS: f32 = 5.0; //secs between polling
 pub const REMEHA_STATS_DUMP_INTERVAL_SECS: f32 = 3600.0; //secs between showing stats
+pub const REMEHA_COUNTERS_POLL_INTERVAL_SECS: f32 = 600.0; //secs between polling the counters
+pub const REMEHA_MIN_POLL_INTERVAL_SECS: f32 = 2.0; //the gateway is slow, don't queue up the queries
+pub const REMEHA_ALARM_REPEAT_SECS: f32 = 3600.0; //same failure/error is notified once per this time
+pub const REMEHA_COUNTERS_REPLY_SIZE: usize = 42; //7 bytes header + 16 words + crc + end
+
+pub const REMEHA_DEFAULT_INFLUX_DATABASE: &str = "remeha";
+
+//recom parameter write: function code and parameter numbers
+pub const REMEHA_WRITE_FUNCTION_CODE: u16 = 0x0106;
+pub const REMEHA_WRITE_REPLY_SIZE: usize = 10; //the boiler echoes the whole frame
+pub const REMEHA_PARAM_CH_SETPOINT: u8 = 0x01;
+pub const REMEHA_PARAM_DHW_SETPOINT: u8 = 0x02;
 
 pub const FRAME_BEGIN: u8 = 0x02;
 pub const FRAME_END: u8 = 0x03;
@@ -25,7 +41,32 @@ pub const FRAME_END: u8 = 0x03;
 // async contexts needs some extra restrictions
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
-#[derive(Clone, InfluxDbWriteable)]
+#[derive(Debug, Error)]
+pub enum RemehaError {
+    #[error("no reply to function code {0:04x}")]
+    NoReply(u16),
+    #[error("invalid reply to function code {function_code:04x}: {reason}")]
+    InvalidReply { function_code: u16, reason: String },
+    #[error("influxdb write error: {0}")]
+    Influx(influx::Error),
+}
+
+impl WorkerError for RemehaError {
+    fn kind(&self) -> &'static str {
+        match self {
+            RemehaError::NoReply(_) => "no_reply",
+            RemehaError::InvalidReply { .. } => "invalid_reply",
+            RemehaError::Influx(_) => "influxdb",
+        }
+    }
+
+    //the connection is reopened on a missing reply, nothing is fatal here
+    fn is_recoverable(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone)]
 pub struct SampleData {
     time: DateTime<Utc>,
 
@@ -64,6 +105,37 @@ pub struct SampleData {
     required_output: u8,
 }
 
+crate::influx_writeable!(SampleData {
+    status_code,
+    failure_code,
+    error_code,
+    substatus_code,
+    flow_temp,
+    return_temp,
+    calorifier_temp,
+    outside_temp,
+    control_temp,
+    internal_setpoint,
+    ch_setpoint,
+    dhw_setpoint,
+    dhw_in_temp,
+    room_temp,
+    room_temp_setpoint,
+    dhw_setpoint_hmi,
+    boiler_control_temp,
+    ch_setpoint_hmi,
+    solar_temp,
+    airflow_setpoint,
+    airflow,
+    ionisation_current,
+    pump_power,
+    hydr_pressure,
+    dhw_flow,
+    actual_power,
+    available_power,
+    required_output,
+});
+
 impl SampleData {
     pub fn new(data: Vec<u8>) -> Self {
         Self {
@@ -251,19 +323,22 @@ impl SampleData {
         }
     }
 
-    async fn save_to_influxdb(&self, influxdb_url: &String, display_name: &String) -> Result<()> {
+    async fn save_to_influxdb(
+        &self,
+        influxdb_url: &String,
+        database: &str,
+        boiler_tag: Option<&String>,
+        display_name: &String,
+    ) -> std::result::Result<(), RemehaError> {
         // connect to influxdb
-        let client = Client::new(influxdb_url, "remeha");
+        let client = Client::new(influxdb_url, database);
 
-        match client.query(&self.clone().into_query("sample_data")).await {
-            Ok(msg) => {
-                debug!("{} influxdb write success: {:?}", display_name, msg);
-            }
-            Err(e) => {
-                error!("{} influxdb write error: {:?}", display_name, e);
-            }
+        let mut query = self.clone().into_query("sample_data");
+        if let Some(boiler) = boiler_tag {
+            query = query.add_tag("boiler", boiler.as_str());
         }
-
+        let msg = client.query(&query).await.map_err(RemehaError::Influx)?;
+        debug!("{} influxdb write success: {:?}", display_name, msg);
         Ok(())
     }
 }
@@ -327,6 +402,146 @@ impl fmt::Display for SampleData {
     }
 }
 
+/// Counters group (0x005/0x101c): operating hours and starts
+#[derive(Clone)]
+pub struct Counters {
+    time: DateTime<Utc>,
+    pump_hours: u32,
+    dhw_valve_hours: u32,
+    ch_burner_hours: u32,
+    dhw_burner_hours: u32,
+    power_supply_hours: u32,
+    pump_starts: u32,
+    dhw_valve_switches: u32,
+    ch_burner_starts: u32,
+    dhw_burner_starts: u32,
+    failed_burner_starts: u32,
+    flame_losses: u32,
+}
+
+crate::influx_writeable!(Counters {
+    pump_hours,
+    dhw_valve_hours,
+    ch_burner_hours,
+    dhw_burner_hours,
+    power_supply_hours,
+    pump_starts,
+    dhw_valve_switches,
+    ch_burner_starts,
+    dhw_burner_starts,
+    failed_burner_starts,
+    flame_losses,
+});
+
+impl Counters {
+    pub fn new(data: Vec<u8>) -> Self {
+        //little endian words, some of them are stored scaled down
+        let word = |index: usize| ((data[index * 2 + 1] as u32) << 8) + data[index * 2] as u32;
+        Self {
+            time: Utc::now(),
+            pump_hours: word(0) * 2,
+            dhw_valve_hours: word(1) * 2,
+            ch_burner_hours: word(2) * 2,
+            dhw_burner_hours: word(3) * 2,
+            power_supply_hours: word(4) * 2,
+            pump_starts: word(5) * 8,
+            dhw_valve_switches: word(6) * 8,
+            ch_burner_starts: word(7) * 8,
+            dhw_burner_starts: word(8) * 8,
+            failed_burner_starts: word(9),
+            flame_losses: word(10),
+        }
+    }
+
+    /// Total burner starts (CH and DHW)
+    pub fn burner_starts(&self) -> u32 {
+        self.ch_burner_starts + self.dhw_burner_starts
+    }
+
+    async fn save_to_influxdb(
+        &self,
+        influxdb_url: &String,
+        database: &str,
+        boiler_tag: Option<&String>,
+        display_name: &String,
+    ) -> std::result::Result<(), RemehaError> {
+        let client = Client::new(influxdb_url, database);
+
+        let mut query = self.clone().into_query("counters");
+        if let Some(boiler) = boiler_tag {
+            query = query.add_tag("boiler", boiler.as_str());
+        }
+        let msg = client.query(&query).await.map_err(RemehaError::Influx)?;
+        debug!("{} influxdb write success: {:?}", display_name, msg);
+        Ok(())
+    }
+}
+
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "burner starts: {} (CH: {}, DHW: {}, failed: {}, flame losses: {}), hours: CH: {} h, DHW: {} h, pump: {} h ({} starts), DHW valve: {} h, powered: {} h",
+            self.burner_starts(),
+            self.ch_burner_starts,
+            self.dhw_burner_starts,
+            self.failed_burner_starts,
+            self.flame_losses,
+            self.ch_burner_hours,
+            self.dhw_burner_hours,
+            self.pump_hours,
+            self.pump_starts,
+            self.dhw_valve_hours,
+            self.power_supply_hours,
+        )
+    }
+}
+
+/// Boiler setpoints which can be changed remotely (°C)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RemehaSetpoint {
+    Ch(u8),
+    Dhw(u8),
+}
+
+impl RemehaSetpoint {
+    /// Parses the setpoint from the API/config name and value, checking the allowed range
+    pub fn parse(name: &str, value: &str) -> Option<Self> {
+        let value: u8 = value.trim().parse().ok()?;
+        match name {
+            "ch" | "ch_setpoint" if (20..=90).contains(&value) => Some(RemehaSetpoint::Ch(value)),
+            "dhw" | "dhw_setpoint" if (40..=65).contains(&value) => {
+                Some(RemehaSetpoint::Dhw(value))
+            }
+            _ => None,
+        }
+    }
+
+    /// Data word of the write frame: parameter number and the new value
+    fn data(&self) -> u16 {
+        match self {
+            RemehaSetpoint::Ch(value) => ((REMEHA_PARAM_CH_SETPOINT as u16) << 8) + *value as u16,
+            RemehaSetpoint::Dhw(value) => ((REMEHA_PARAM_DHW_SETPOINT as u16) << 8) + *value as u16,
+        }
+    }
+}
+
+impl fmt::Display for RemehaSetpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RemehaSetpoint::Ch(value) => write!(f, "CH setpoint: {} °C", value),
+            RemehaSetpoint::Dhw(value) => write!(f, "DHW setpoint: {} °C", value),
+        }
+    }
+}
+
+/// Setpoint change requested for a boiler (by its name, `remeha` for the main one)
+#[derive(Clone, Debug)]
+pub struct RemehaSetpointRequest {
+    pub boiler: String,
+    pub setpoint: RemehaSetpoint,
+}
+
 pub struct RemehaState {
     pub status_code: u8,
     pub failure_code: u8,
@@ -413,16 +628,255 @@ impl RemehaState {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlarmNotification {
+    Raised,
+    Recovered,
+}
+
+/// Failure/error alarm tracking with deduplication of the notifications
+#[derive(Default)]
+pub struct RemehaAlarm {
+    //(failure code, error code) of the currently notified alarm
+    active: Option<(u8, u8)>,
+    last_notified: HashMap<(u8, u8), Instant>,
+}
+
+impl RemehaAlarm {
+    /// Returns the notification to be sent for the new failure/error codes.
+    /// A fault which persists or reappears within the repeat window is notified only once
+    /// and the recovery is notified only after a notified alarm.
+    fn update(&mut self, failure_code: u8, error_code: u8) -> Option<AlarmNotification> {
+        if failure_code == 255 && error_code == 255 {
+            return self.active.take().map(|_| AlarmNotification::Recovered);
+        }
+        let codes = (failure_code, error_code);
+        if self.active == Some(codes) {
+            return None;
+        }
+        let repeated = self.last_notified.get(&codes).map_or(false, |t| {
+            t.elapsed() < Duration::from_secs_f32(REMEHA_ALARM_REPEAT_SECS)
+        });
+        if repeated {
+            return None;
+        }
+        self.last_notified.insert(codes, Instant::now());
+        self.active = Some(codes);
+        Some(AlarmNotification::Raised)
+    }
+}
+
+/// Aggregated state of all boilers in a cascade (or a boiler and a separate DHW unit)
+#[derive(Default)]
+pub struct RemehaCascade {
+    //boiler name -> (status code, failure/error active)
+    pub boilers: BTreeMap<String, (u8, bool)>,
+    pub lcd_line: Option<u8>,
+    lcd_text: Option<String>,
+}
+
+impl RemehaCascade {
+    /// Updates the boiler state, returns the new LCD text when it has changed
+    fn update(&mut self, name: &String, status_code: u8, failure: bool) -> Option<String> {
+        self.boilers.insert(name.clone(), (status_code, failure));
+        self.lcd_line?;
+        let text = self
+            .boilers
+            .iter()
+            .map(|(name, (status, failure))| {
+                format!("{}:{}{}", name, status, if *failure { "!" } else { "" })
+            })
+            .collect::<Vec<String>>()
+            .join(" ");
+        if self.lcd_text.as_ref() == Some(&text) {
+            return None;
+        }
+        self.lcd_text = Some(text.clone());
+        Some(text)
+    }
+}
+
 pub struct Remeha {
+    pub name: String,
     pub display_name: String,
     pub device_host_port: String,
     pub poll_ok: u64,
     pub poll_errors: u64,
     pub influxdb_url: Option<String>,
+    pub influx_database: String,
+    pub influx_boiler_tag: bool,
     pub state_change_script: Option<String>,
+    pub recovery_script: Option<String>,
+    pub alarm_script: Option<String>,
+    pub mqtt_transmitter: Sender<MqttEvent>,
+    pub notify_transmitter: Sender<Notification>,
+    pub cascade: Arc<RwLock<RemehaCascade>>,
+    pub lcd_transmitter: Sender<LcdTask>,
+    pub allow_write: bool,
+    pub setpoint_requests: Arc<Mutex<Vec<RemehaSetpointRequest>>>,
+    pub events: EventSender,
+    pub poll_interval: Duration,
+    pub stats_interval: Duration,
+    pub counters_interval: Duration,
+    pub errors: ErrorReporter,
 }
 
 impl Remeha {
+    fn get_failure_text(sample: &SampleData) -> String {
+        format!(
+            "{}{}",
+            {
+                if sample.failure_code != 255 {
+                    format!(
+                        "\nFailure/Locking: {}: {}",
+                        sample.failure_code,
+                        SampleData::get_failure_code_description(sample.failure_code),
+                    )
+                } else {
+                    "".to_string()
+                }
+            },
+            {
+                if sample.error_code != 255 {
+                    format!(
+                        "\nError/Blocking: {}: {}",
+                        sample.error_code,
+                        SampleData::get_error_code_description(sample.error_code),
+                    )
+                } else {
+                    "".to_string()
+                }
+            },
+        )
+    }
+
+    fn get_script_env(sample: &SampleData) -> Vec<(String, String)> {
+        vec![
+            ("REMEHA_STATUS_CODE".into(), sample.status_code.to_string()),
+            (
+                "REMEHA_STATUS".into(),
+                SampleData::get_status_code_description(sample.status_code).into(),
+            ),
+            (
+                "REMEHA_SUBSTATUS_CODE".into(),
+                sample.substatus_code.to_string(),
+            ),
+            (
+                "REMEHA_SUBSTATUS".into(),
+                SampleData::get_substatus_code_description(sample.substatus_code).into(),
+            ),
+            (
+                "REMEHA_FAILURE_CODE".into(),
+                sample.failure_code.to_string(),
+            ),
+            (
+                "REMEHA_FAILURE".into(),
+                SampleData::get_failure_code_description(sample.failure_code).into(),
+            ),
+            ("REMEHA_ERROR_CODE".into(), sample.error_code.to_string()),
+            (
+                "REMEHA_ERROR".into(),
+                SampleData::get_error_code_description(sample.error_code).into(),
+            ),
+        ]
+    }
+
+    fn run_script(command: &String, sample: &SampleData) {
+        let mut cmd = command.to_string().clone();
+        cmd = str::replace(&cmd, "%state%", &Remeha::get_failure_text(sample));
+        cmd = str::replace(&cmd, "%status%", &sample.status_code.to_string());
+        cmd = str::replace(&cmd, "%substatus%", &sample.substatus_code.to_string());
+        cmd = str::replace(&cmd, "%failure%", &sample.failure_code.to_string());
+        cmd = str::replace(&cmd, "%error%", &sample.error_code.to_string());
+        let envs = Remeha::get_script_env(sample);
+        thread::spawn(move || StateMachine::run_shell_command_env(cmd, envs));
+    }
+
+    fn get_alarm_description(sample: &SampleData) -> String {
+        let mut description = vec![];
+        if sample.failure_code != 255 {
+            description.push(SampleData::get_failure_code_description(
+                sample.failure_code,
+            ));
+        }
+        if sample.error_code != 255 {
+            description.push(SampleData::get_error_code_description(sample.error_code));
+        }
+        description.join(", ")
+    }
+
+    /// Sends the failure/error alarm or recovery notification (script and MQTT)
+    fn notify_alarm(&self, notification: AlarmNotification, sample: &SampleData) {
+        let description = match notification {
+            AlarmNotification::Raised => {
+                let description = Remeha::get_alarm_description(sample);
+                error!(
+                    "{} 🚨 boiler alarm: <b><red>{}</>",
+                    self.display_name, description
+                );
+                description
+            }
+            AlarmNotification::Recovered => {
+                info!("{} ✅ boiler alarm cleared", self.display_name);
+                "ok".to_string()
+            }
+        };
+        let _ = self.mqtt_transmitter.send(MqttEvent::new(
+            format!("remeha/{}/alarm", self.name),
+            &description,
+            true,
+        ));
+        let _ = self.notify_transmitter.send(match notification {
+            AlarmNotification::Raised => Notification::new(
+                self.name.clone(),
+                Severity::Critical,
+                "boiler alarm",
+                &description,
+            ),
+            AlarmNotification::Recovered => Notification::new(
+                self.name.clone(),
+                Severity::Info,
+                "boiler alarm cleared",
+                "ok",
+            ),
+        });
+        if let Some(command) = &self.alarm_script {
+            let mut cmd = command.clone();
+            cmd = str::replace(&cmd, "%state%", &Remeha::get_failure_text(sample));
+            cmd = str::replace(&cmd, "%description%", &description);
+            cmd = str::replace(&cmd, "%failure%", &sample.failure_code.to_string());
+            cmd = str::replace(&cmd, "%error%", &sample.error_code.to_string());
+            let mut envs = Remeha::get_script_env(sample);
+            envs.push((
+                "REMEHA_ALARM".into(),
+                match notification {
+                    AlarmNotification::Raised => "raised".into(),
+                    AlarmNotification::Recovered => "recovered".into(),
+                },
+            ));
+            envs.push(("REMEHA_BOILER".into(), self.name.clone()));
+            thread::spawn(move || StateMachine::run_shell_command_env(cmd, envs));
+        }
+    }
+
+    fn update_cascade(&self, sample: &SampleData) {
+        let failure = sample.failure_code != 255 || sample.error_code != 255;
+        let text = match self.cascade.write() {
+            Ok(mut cascade) => cascade
+                .update(&self.name, sample.status_code, failure)
+                .map(|text| (cascade.lcd_line, text)),
+            Err(_) => None,
+        };
+        if let Some((Some(line), text)) = text {
+            let task = LcdTask {
+                command: LcdTaskCommand::SetLineText,
+                int_arg: line,
+                string_arg: Some(text),
+            };
+            let _ = self.lcd_transmitter.send(task);
+        }
+    }
+
     fn verify_input_data(mut data: Vec<u8>) -> std::result::Result<(), String> {
         debug!("input data={:02X?}", data);
 
@@ -461,14 +915,12 @@ impl Remeha {
 
     pub async fn query_boiler(
         &mut self,
-        stream: &mut TcpStream,
+        device: &mut DeviceIo,
         function_code: u16,
         data: u16,
         reply_size: usize,
-    ) -> io::Result<Option<Vec<u8>>> {
-        let mut buffer = vec![0u8; reply_size];
+    ) -> std::result::Result<Vec<u8>, RemehaError> {
         let mut output_cmd: Vec<u8> = vec![];
-        let mut out: Option<Vec<u8>> = None;
 
         //the protocol looks like a modbus
         output_cmd.push(0xfe); //slave ID?
@@ -492,220 +944,287 @@ impl Remeha {
             "{} sending function_code={:04x} data={:04x} crc=0x{:04X} frame={:02X?}",
             self.display_name, function_code, data, crc, output_cmd
         );
-        if let Err(e) = stream.write_all(&output_cmd).await {
-            error!("{} write error: {:?}", self.display_name, e);
-            return Ok(out);
-        }
         let now = Instant::now();
+        let buffer = device
+            .transact(
+                &output_cmd,
+                ReplyFrame::Size(reply_size),
+                Duration::from_secs_f32(2.5),
+            )
+            .await
+            .ok_or(RemehaError::NoReply(function_code))?;
+        let elapsed = now.elapsed();
 
-        let retval = stream.read_exact(&mut buffer);
-        match timeout(Duration::from_secs_f32(2.5), retval).await {
-            Ok(res) => match res {
-                Ok(_) => {
-                    let elapsed = now.elapsed();
-                    match Remeha::verify_input_data(buffer.clone()) {
-                        Ok(_) => {
-                            self.poll_ok = self.poll_ok + 1;
-                            debug!(
-                                "{} got reply [⏱️ {} ms]: {:02X?}, ok: {}, errors: {}",
-                                self.display_name,
-                                (elapsed.as_secs() * 1_000)
-                                    + (elapsed.subsec_nanos() / 1_000_000) as u64,
-                                &buffer,
-                                self.poll_ok,
-                                self.poll_errors
-                            );
-                            out = Some(buffer)
-                        }
-                        Err(e) => {
-                            self.poll_errors = self.poll_errors + 1;
-                            error!("{} data verify failed: {}", self.display_name, e);
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("{} file read error: {}", self.display_name, e);
-                }
-            },
-            Err(e) => {
-                error!("{} response timeout: {}", self.display_name, e);
+        match Remeha::verify_input_data(buffer.clone()) {
+            Ok(_) => {
+                device.valid_reply();
+                self.poll_ok = self.poll_ok + 1;
+                debug!(
+                    "{} got reply [⏱️ {} ms]: {:02X?}, ok: {}, errors: {}",
+                    self.display_name,
+                    (elapsed.as_secs() * 1_000) + (elapsed.subsec_nanos() / 1_000_000) as u64,
+                    &buffer,
+                    self.poll_ok,
+                    self.poll_errors
+                );
+                Ok(buffer)
+            }
+            Err(reason) => {
+                device.invalid_reply();
+                self.poll_errors = self.poll_errors + 1;
+                Err(RemehaError::InvalidReply {
+                    function_code,
+                    reason,
+                })
             }
         }
+    }
 
-        Ok(out)
+    /// Reports a failed query or write, the value is returned on success
+    fn reported<T>(&self, result: std::result::Result<T, RemehaError>) -> Option<T> {
+        result.map_err(|e| self.errors.report(&e)).ok()
+    }
+
+    /// Writes a new setpoint, returns true when the boiler echoed the written value
+    pub async fn write_setpoint(
+        &mut self,
+        device: &mut DeviceIo,
+        setpoint: RemehaSetpoint,
+    ) -> bool {
+        let data = setpoint.data();
+        let reply = self
+            .query_boiler(
+                device,
+                REMEHA_WRITE_FUNCTION_CODE,
+                data,
+                REMEHA_WRITE_REPLY_SIZE,
+            )
+            .await;
+        match self.reported(reply) {
+            Some(reply) if reply[5] == (data >> 8) as u8 && reply[6] == (data & 0xff) as u8 => {
+                info!("{} ⚙️ {} set", self.display_name, setpoint);
+                true
+            }
+            Some(reply) => {
+                error!(
+                    "{} ⚙️ boiler didn't confirm {}, reply: {:02X?}",
+                    self.display_name, setpoint, reply
+                );
+                false
+            }
+            None => {
+                error!("{} ⚙️ no reply for {}", self.display_name, setpoint);
+                false
+            }
+        }
+    }
+
+    /// Takes the pending setpoint requests addressed to this boiler
+    fn take_setpoint_requests(&self) -> Vec<RemehaSetpoint> {
+        let mut taken = vec![];
+        if let Ok(mut requests) = self.setpoint_requests.lock() {
+            requests.retain(|request| {
+                if request.boiler == self.name {
+                    taken.push(request.setpoint);
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        taken
     }
 
     pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
         info!("{} Starting task", self.display_name);
         let mut poll_interval = Instant::now();
         let mut stats_interval = Instant::now();
-        let mut terminated = false;
         let mut remeha_state: Option<RemehaState> = None;
+        let mut counters_interval: Option<Instant> = None;
+        let mut counters: Option<Counters> = None;
+        let mut alarm = RemehaAlarm::default();
+
+        let mut device = DeviceIo::new(
+            self.display_name.clone(),
+            DeviceAddress::Tcp(self.device_host_port.clone()),
+        );
 
         loop {
-            if terminated || worker_cancel_flag.load(Ordering::SeqCst) {
+            if worker_cancel_flag.load(Ordering::SeqCst) {
+                debug!("{} Got terminate signal from main", self.display_name);
                 break;
             }
+            systemd::heartbeat(&self.name);
 
-            info!(
-                "{} connecting to <u>{}</>...",
-                self.display_name, self.device_host_port
-            );
-            let future = TcpStream::connect(&self.device_host_port);
-            match timeout(Duration::from_secs(5), future).await {
-                Ok(res) => {
-                    match res {
-                        Ok(mut stream) => {
-                            info!(
-                                "{} connected, poll interval: {}s",
-                                self.display_name, REMEHA_POLL_INTERVAL_SECS
-                            );
-
-                            loop {
-                                if worker_cancel_flag.load(Ordering::SeqCst) {
-                                    debug!("{} Got terminate signal from main", self.display_name);
-                                    terminated = true;
-                                }
+            if stats_interval.elapsed() > self.stats_interval {
+                stats_interval = Instant::now();
+                info!(
+                    "{} 📊 boiler query statistics: ok: {}, errors: {}, reconnects: {}",
+                    self.display_name, self.poll_ok, self.poll_errors, device.reconnects
+                );
+                if let Some(counters) = &counters {
+                    info!("{} 📊 {}", self.display_name, counters);
+                }
+            }
 
-                                if terminated
-                                    || stats_interval.elapsed()
-                                        > Duration::from_secs_f32(REMEHA_STATS_DUMP_INTERVAL_SECS)
-                                {
-                                    stats_interval = Instant::now();
-                                    info!(
-                                        "{} 📊 boiler query statistics: ok: {}, errors: {}",
-                                        self.display_name, self.poll_ok, self.poll_errors
-                                    );
-
-                                    if terminated {
-                                        break;
-                                    }
-                                }
+            if !device.open().await {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+
+            for setpoint in self.take_setpoint_requests() {
+                if self.allow_write {
+                    self.write_setpoint(&mut device, setpoint).await;
+                } else {
+                    warn!(
+                        "{} ⚙️ ignoring {}: parameter writing is disabled in the config",
+                        self.display_name, setpoint
+                    );
+                }
+            }
+
+            //counters are changing slowly, polled first right after the start
+            if counters_interval.map_or(true, |t| t.elapsed() > self.counters_interval) {
+                counters_interval = Some(Instant::now());
+                let buffer = self
+                    .query_boiler(&mut device, 0x005, 0x101c, REMEHA_COUNTERS_REPLY_SIZE)
+                    .await;
+                match self.reported(buffer) {
+                    Some(mut data) => {
+                        //remove protocol overhead bytes:
+                        data.drain(0..=6);
+                        let new_counters = Counters::new(data);
+                        debug!("{} counters: {}", self.display_name, new_counters);
+                        if let Some(url) = &self.influxdb_url {
+                            let result = new_counters
+                                .save_to_influxdb(
+                                    url,
+                                    &self.influx_database,
+                                    Some(&self.name).filter(|_| self.influx_boiler_tag),
+                                    &self.display_name,
+                                )
+                                .await;
+                            self.reported(result);
+                        }
+                        if counters.is_none() {
+                            info!("{} 📊 {}", self.display_name, new_counters);
+                        }
+                        counters = Some(new_counters);
+                    }
+                    None => {
+                        //reconnect
+                        device.close();
+                        continue;
+                    }
+                }
+            }
+
+            if poll_interval.elapsed() > self.poll_interval {
+                poll_interval = Instant::now();
+
+                //query for sample data
+                let buffer = self.query_boiler(&mut device, 0x105, 0x201, 74).await;
+                match self.reported(buffer) {
+                    Some(mut data) => {
+                        //remove protocol overhead bytes:
+                        data.drain(0..=6);
+
+                        //parse data
+                        let sample = SampleData::new(data);
+                        debug!("{} {}", self.display_name, sample);
+
+                        //write data to influxdb if configured
+                        match &self.influxdb_url {
+                            Some(url) => {
+                                let result = sample
+                                    .save_to_influxdb(
+                                        url,
+                                        &self.influx_database,
+                                        Some(&self.name).filter(|_| self.influx_boiler_tag),
+                                        &self.display_name,
+                                    )
+                                    .await;
+                                self.reported(result);
+                            }
+                            None => (),
+                        }
 
-                                if poll_interval.elapsed()
-                                    > Duration::from_secs_f32(REMEHA_POLL_INTERVAL_SECS)
-                                {
-                                    poll_interval = Instant::now();
-
-                                    //query for sample data
-                                    let buffer =
-                                        self.query_boiler(&mut stream, 0x105, 0x201, 74).await?;
-                                    match buffer {
-                                        Some(mut data) => {
-                                            //remove protocol overhead bytes:
-                                            data.drain(0..=6);
-
-                                            //parse data
-                                            let sample = SampleData::new(data);
-                                            debug!("{} {}", self.display_name, sample);
-
-                                            //write data to influxdb if configured
-                                            match &self.influxdb_url {
-                                                Some(url) => {
-                                                    // By calling compat on the async function, everything inside it is able
-                                                    // to use Tokio 0.2 features.
-                                                    let _ = sample
-                                                        .save_to_influxdb(url, &self.display_name)
-                                                        .compat()
-                                                        .await;
-                                                }
-                                                None => (),
-                                            }
-
-                                            remeha_state = Some(match remeha_state {
-                                                Some(mut current_state) => {
-                                                    if current_state.set_new_status(
-                                                        &self.display_name,
-                                                        sample.status_code,
-                                                        sample.substatus_code,
-                                                        sample.failure_code,
-                                                        sample.error_code,
-                                                    ) && (sample.failure_code != 255
-                                                        || sample.error_code != 255)
-                                                    {
-                                                        // run a shell script when mode has changed
-                                                        // and we have failure or error
-                                                        match &self.state_change_script {
-                                                            Some(command) => {
-                                                                let mut cmd =
-                                                                    command.to_string().clone();
-                                                                cmd = str::replace(
-                                                                    &cmd,
-                                                                    "%state%",
-                                                                    &format!(
-                                                                        "{}{}",
-                                                                        {
-                                                                            if sample.failure_code
-                                                                                != 255
-                                                                            {
-                                                                                format!("\nFailure/Locking: {}: {}",
-                                                                                        sample.failure_code,
-                                                                                        SampleData::get_failure_code_description(sample.failure_code),
-                                                                                )
-                                                                            } else {
-                                                                                "".to_string()
-                                                                            }
-                                                                        },
-                                                                        {
-                                                                            if sample.error_code
-                                                                                != 255
-                                                                            {
-                                                                                format!("\nError/Blocking: {}: {}",
-                                                                                        sample.error_code,
-                                                                                        SampleData::get_error_code_description(sample.error_code),
-                                                                                )
-                                                                            } else {
-                                                                                "".to_string()
-                                                                            }
-                                                                        },
-                                                                    ),
-                                                                );
-                                                                thread::spawn(move || {
-                                                                    StateMachine::run_shell_command(
-                                                                        cmd,
-                                                                    )
-                                                                });
-                                                            }
-                                                            _ => (),
-                                                        };
-                                                    }
-                                                    current_state
-                                                }
-                                                None => {
-                                                    let new_state = RemehaState {
-                                                        status_code: sample.status_code,
-                                                        substatus_code: sample.substatus_code,
-                                                        failure_code: sample.failure_code,
-                                                        error_code: sample.error_code,
-                                                    };
-                                                    new_state.show_status(&self.display_name);
-                                                    new_state
-                                                }
-                                            });
+                        events::publish(
+                            &self.events,
+                            Event::Boiler {
+                                boiler: self.name.clone(),
+                                status: SampleData::get_status_code_description(sample.status_code)
+                                    .to_string(),
+                                flow_temp: sample.flow_temp,
+                                return_temp: sample.return_temp,
+                                dhw_temp: sample.calorifier_temp,
+                                outside_temp: sample.outside_temp,
+                                pressure: sample.hydr_pressure,
+                            },
+                        );
+
+                        remeha_state = Some(match remeha_state {
+                            Some(mut current_state) => {
+                                if current_state.set_new_status(
+                                    &self.display_name,
+                                    sample.status_code,
+                                    sample.substatus_code,
+                                    sample.failure_code,
+                                    sample.error_code,
+                                ) {
+                                    if sample.failure_code != 255 || sample.error_code != 255 {
+                                        // run a shell script when mode has changed
+                                        // and we have failure or error
+                                        if let Some(command) = &self.state_change_script {
+                                            Remeha::run_script(command, &sample);
                                         }
-                                        None => {
-                                            break;
+                                    } else {
+                                        // failure/error has been cleared
+                                        info!(
+                                            "{} ✅ boiler recovered from failure/error state",
+                                            self.display_name
+                                        );
+                                        if let Some(command) = &self.recovery_script {
+                                            Remeha::run_script(command, &sample);
                                         }
                                     }
                                 }
-
-                                tokio::time::sleep(Duration::from_millis(30)).await;
+                                current_state
                             }
+                            None => {
+                                let new_state = RemehaState {
+                                    status_code: sample.status_code,
+                                    substatus_code: sample.substatus_code,
+                                    failure_code: sample.failure_code,
+                                    error_code: sample.error_code,
+                                };
+                                new_state.show_status(&self.display_name);
+                                new_state
+                            }
+                        });
+                        if let Some(notification) =
+                            alarm.update(sample.failure_code, sample.error_code)
+                        {
+                            self.notify_alarm(notification, &sample);
                         }
-                        Err(e) => {
-                            error!("{} error opening device: {:?}", self.display_name, e);
-                            tokio::time::sleep(Duration::from_secs(10)).await;
-                            continue;
-                        }
+                        self.update_cascade(&sample);
+                    }
+                    None => {
+                        //reconnect
+                        device.close();
+                        continue;
                     }
-                }
-                Err(e) => {
-                    error!("{} file open timeout: {}", self.display_name, e);
                 }
             }
+
             tokio::time::sleep(Duration::from_millis(30)).await;
         }
 
+        info!(
+            "{} 📊 boiler query statistics: ok: {}, errors: {}, reconnects: {}",
+            self.display_name, self.poll_ok, self.poll_errors, device.reconnects
+        );
+        systemd::heartbeat_stop(&self.name);
         info!("{} task stopped", self.display_name);
         Ok(())
     }
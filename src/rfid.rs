@@ -1,25 +1,356 @@
+use crate::webserver::secure_eq;
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveTime};
 use evdev::Key;
+use futures::StreamExt;
+use gpio_cdev::{AsyncLineEventHandle, Chip, EventRequestFlags, LineRequestFlags};
+use openssl::sha::sha256;
 use simplelog::*;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::unix::AsyncFd;
 
 // Just a generic Result type to ease error handling for us. Errors in multithreaded
 // async contexts needs some extra restrictions
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
+pub const RFID_REOPEN_MIN_SECS: u64 = 1; //reopen backoff after a read error
+pub const RFID_REOPEN_MAX_SECS: u64 = 60;
+pub const RFID_HOTPLUG_SETTLE_MS: u64 = 500;
+pub const WIEGAND_FRAME_GAP_MS: u64 = 25; //no pulse for this long ends the frame
+pub const WIEGAND_RETRY_SECS: u64 = 10;
+pub const PIN_MAX_DIGITS: usize = 12;
+pub const DEFAULT_PIN_TIMEOUT_SECS: u64 = 10; //max pause between the keys
+pub const DEFAULT_PIN_MAX_ATTEMPTS: u32 = 3; //wrong PINs before the lockout
+pub const DEFAULT_PIN_LOCKOUT_SECS: u64 = 300;
+
+/// Complete PINs waiting for the onewire state machine as (keypad, PIN)
+pub type PendingPins = Arc<RwLock<Vec<(String, String)>>>;
+
 pub struct RfidTag {
     pub id_tag: i32,
     pub name: String,
     pub tags: Vec<String>,
     pub associated_relays: Vec<i32>,
+    pub restrictions: RfidRestrictions,
+    pub pin: Option<PinHash>, //keypad PIN triggering the same actions as the tag
+}
+
+/// Keypad PIN stored as a salted SHA-256: `sha256$<salt>$<hex digest of the salt followed by the PIN>`
+#[derive(Clone, Debug)]
+pub struct PinHash {
+    salt: String,
+    digest: String,
+}
+
+impl PinHash {
+    pub fn parse(stored: &str) -> Option<Self> {
+        let parts: Vec<&str> = stored.trim().split('$').collect();
+        match parts[..] {
+            ["sha256", salt, digest]
+                if !salt.is_empty()
+                    && digest.len() == 64
+                    && digest.bytes().all(|b| b.is_ascii_hexdigit()) =>
+            {
+                Some(PinHash {
+                    salt: salt.to_string(),
+                    digest: digest.to_lowercase(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// PIN of the tag from the config or database, the plain text ones are rejected
+    pub fn load(tag_name: &str, stored: Option<&str>) -> Option<Self> {
+        let stored = stored.map(|p| p.trim()).filter(|p| !p.is_empty())?;
+        let pin = PinHash::parse(stored);
+        if pin.is_none() {
+            error!(
+                "rfid tag {:?}: the PIN is not a sha256$<salt>$<digest> hash, ignored",
+                tag_name
+            );
+        }
+        pin
+    }
+
+    fn digest(salt: &str, pin: &str) -> String {
+        sha256(format!("{}{}", salt, pin).as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub fn verify(&self, pin: &str) -> bool {
+        secure_eq(&self.digest, &PinHash::digest(&self.salt, pin))
+    }
+}
+
+/// Wrong PINs in a row typed on a single keypad
+#[derive(Default)]
+pub struct PinLockout {
+    pub failures: u32,
+    pub locked_until: Option<Instant>,
+}
+
+/// When a tag is allowed to be used, everything is allowed by default
+#[derive(Clone, Debug, Default)]
+pub struct RfidRestrictions {
+    pub weekdays: Vec<u32>, //ISO numbering: 1 = Monday .. 7 = Sunday, empty = every day
+    pub hours: Option<(NaiveTime, NaiveTime)>, //valid from..to local time, may wrap midnight
+    pub expires: Option<NaiveDate>, //last day the tag is valid
+    pub max_uses_per_day: Option<u32>,
+}
+
+impl RfidRestrictions {
+    /// Parses weekdays: "1-5", "mon-fri", "sat,sun"
+    pub fn parse_weekdays(input: &str) -> Option<Vec<u32>> {
+        fn day(name: &str) -> Option<u32> {
+            const NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+            let name = name.trim().to_lowercase();
+            match name.parse::<u32>() {
+                Ok(n) if (1..=7).contains(&n) => Some(n),
+                Ok(_) => None,
+                Err(_) => NAMES
+                    .iter()
+                    .position(|n| name.starts_with(n))
+                    .map(|i| i as u32 + 1),
+            }
+        }
+        let mut weekdays = vec![];
+        for part in input.split(',').filter(|p| !p.trim().is_empty()) {
+            match part.split_once('-') {
+                Some((from, to)) => {
+                    let (from, to) = (day(from)?, day(to)?);
+                    if from > to {
+                        return None;
+                    }
+                    weekdays.extend(from..=to);
+                }
+                None => weekdays.push(day(part)?),
+            }
+        }
+        Some(weekdays)
+    }
+
+    /// Parses a time window: "8-16", "07:30-16:00", "22:00-06:00"
+    pub fn parse_hours(input: &str) -> Option<(NaiveTime, NaiveTime)> {
+        fn time(value: &str) -> Option<NaiveTime> {
+            let value = value.trim();
+            match value.parse::<u32>() {
+                Ok(24) => NaiveTime::from_hms_opt(23, 59, 59),
+                Ok(hour) => NaiveTime::from_hms_opt(hour, 0, 0),
+                Err(_) => NaiveTime::parse_from_str(value, "%H:%M").ok(),
+            }
+        }
+        let (from, to) = input.split_once('-')?;
+        Some((time(from)?, time(to)?))
+    }
+
+    /// Checks the restrictions, returns the reason when the tag is not allowed now
+    pub fn check(&self, now: DateTime<Local>, uses_today: u32) -> Option<&'static str> {
+        if let Some(expires) = self.expires {
+            if now.date_naive() > expires {
+                return Some("expired");
+            }
+        }
+        if !self.weekdays.is_empty() && !self.weekdays.contains(&now.weekday().number_from_monday())
+        {
+            return Some("weekday");
+        }
+        if let Some((from, to)) = self.hours {
+            let time = now.time();
+            let inside = if from <= to {
+                time >= from && time < to
+            } else {
+                time >= from || time < to
+            };
+            if !inside {
+                return Some("hours");
+            }
+        }
+        if let Some(max) = self.max_uses_per_day {
+            if uses_today >= max {
+                return Some("max_uses");
+            }
+        }
+        None
+    }
+}
+
+/// Single tag scan for the access log
+#[derive(Clone, Debug)]
+pub struct RfidScan {
+    pub time: SystemTime,
+    pub id_tag: u32,
+    pub name: Option<String>, //none for unknown tags
+    pub action: String,
+}
+
+/// Key of a numeric keypad or of the USB reader
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeypadKey {
+    Digit(char),
+    Enter,
+    Clear,
+}
+
+impl KeypadKey {
+    fn from_code(code: u16) -> Option<KeypadKey> {
+        const DIGITS: [(Key, Key, char); 10] = [
+            (Key::KEY_0, Key::KEY_KP0, '0'),
+            (Key::KEY_1, Key::KEY_KP1, '1'),
+            (Key::KEY_2, Key::KEY_KP2, '2'),
+            (Key::KEY_3, Key::KEY_KP3, '3'),
+            (Key::KEY_4, Key::KEY_KP4, '4'),
+            (Key::KEY_5, Key::KEY_KP5, '5'),
+            (Key::KEY_6, Key::KEY_KP6, '6'),
+            (Key::KEY_7, Key::KEY_KP7, '7'),
+            (Key::KEY_8, Key::KEY_KP8, '8'),
+            (Key::KEY_9, Key::KEY_KP9, '9'),
+        ];
+        if let Some((_, _, digit)) = DIGITS
+            .iter()
+            .find(|(key, kp, _)| key.code() == code || kp.code() == code)
+        {
+            return Some(KeypadKey::Digit(*digit));
+        }
+        if code == Key::KEY_ENTER.code() || code == Key::KEY_KPENTER.code() {
+            return Some(KeypadKey::Enter);
+        }
+        if code == Key::KEY_ESC.code()
+            || code == Key::KEY_BACKSPACE.code()
+            || code == Key::KEY_KPASTERISK.code()
+        {
+            return Some(KeypadKey::Clear);
+        }
+        None
+    }
+
+    /// Key of a Wiegand keypad: 4 bit frames, or 8 bit ones with the inverted
+    /// value in the high nibble; 10 is '*', 11 is '#'
+    fn from_wiegand(bits: &[bool]) -> Option<KeypadKey> {
+        let value = bits.iter().fold(0u8, |acc, &b| (acc << 1) | b as u8);
+        let key = match bits.len() {
+            4 => value,
+            8 if (value >> 4) == (!value & 0x0f) => value & 0x0f,
+            _ => return None,
+        };
+        match key {
+            0..=9 => Some(KeypadKey::Digit((b'0' + key) as char)),
+            10 => Some(KeypadKey::Clear),
+            11 => Some(KeypadKey::Enter),
+            _ => None,
+        }
+    }
+}
+
+/// PIN typed on a keypad, the digits are dropped when the next key comes after the timeout
+pub struct PinEntry {
+    digits: String,
+    last_key: Option<Instant>,
+    timeout: Duration,
+}
+
+impl PinEntry {
+    pub fn new(timeout: Duration) -> Self {
+        PinEntry {
+            digits: String::new(),
+            last_key: None,
+            timeout,
+        }
+    }
+
+    /// Handles a key press, returns the PIN completed with enter
+    pub fn key(&mut self, key: KeypadKey) -> Option<String> {
+        if self.last_key.map_or(false, |t| t.elapsed() > self.timeout) {
+            self.digits.clear();
+        }
+        self.last_key = Some(Instant::now());
+        match key {
+            KeypadKey::Digit(digit) => {
+                if self.digits.len() < PIN_MAX_DIGITS {
+                    self.digits.push(digit);
+                }
+                None
+            }
+            KeypadKey::Clear => {
+                self.digits.clear();
+                None
+            }
+            KeypadKey::Enter if self.digits.is_empty() => None,
+            KeypadKey::Enter => Some(std::mem::take(&mut self.digits)),
+        }
+    }
+}
+
+fn push_pin(name: &str, rfid_pending_pins: &PendingPins, pin: String) {
+    //the PIN itself is never logged
+    info!("{}: 🔢 got complete PIN", name);
+    match rfid_pending_pins.write() {
+        Ok(mut pins) => pins.push((name.to_string(), pin)),
+        Err(e) => error!("{}: unable to pass the PIN: {}", name, e),
+    }
 }
 
 pub struct Rfid {
     pub name: String,
-    pub event_path: String,
+    pub event_path: Option<String>,
+    pub usb_id: Option<(u16, u16)>, //vendor, product: found again after re-plugging
+    pub hotplug: bool,              //udev monitor waking up the reopen wait
+    pub pin_timeout: Option<Duration>, //keypad: the digits are a PIN instead of a tag ID
     pub rfid_pending_tags: Arc<RwLock<Vec<u32>>>,
+    pub rfid_pending_pins: PendingPins,
+}
+
+/// Parses a USB ID: "08ff:0009" (hex vendor:product)
+pub fn parse_usb_id(input: &str) -> Option<(u16, u16)> {
+    let (vendor, product) = input.trim().split_once(':')?;
+    Some((
+        u16::from_str_radix(vendor, 16).ok()?,
+        u16::from_str_radix(product, 16).ok()?,
+    ))
+}
+
+/// udev monitor of the input subsystem
+struct Hotplug {
+    socket: AsyncFd<udev::MonitorSocket>,
+}
+
+impl Hotplug {
+    fn new() -> Result<Self> {
+        let socket = udev::MonitorBuilder::new()?
+            .match_subsystem("input")?
+            .listen()?;
+        Ok(Hotplug {
+            socket: AsyncFd::new(socket)?,
+        })
+    }
+
+    /// Waits until an input device is added or the timeout elapses
+    async fn wait(&mut self, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            match tokio::time::timeout_at(deadline, self.socket.readable_mut()).await {
+                Ok(Ok(mut guard)) => {
+                    let added = guard.get_inner_mut().fold(false, |added, e| {
+                        added || e.event_type() == udev::EventType::Add
+                    });
+                    guard.clear_ready();
+                    if added {
+                        //let udev finish the device node setup
+                        tokio::time::sleep(Duration::from_millis(RFID_HOTPLUG_SETTLE_MS)).await;
+                        return true;
+                    }
+                }
+                Ok(Err(_)) => {
+                    tokio::time::sleep_until(deadline).await;
+                    return false;
+                }
+                Err(_) => return false,
+            }
+        }
+    }
 }
 
 impl Rfid {
@@ -32,118 +363,345 @@ impl Rfid {
             Err(_) => false,
         }
     }
+
+    //looks the reader up by the physical path or by the USB ID
+    fn find_device(&self) -> Option<evdev::Device> {
+        evdev::enumerate().map(|t| t.1).find(|d| {
+            let by_path = match &self.event_path {
+                Some(path) => d.physical_path() == Some(path.as_str()),
+                None => false,
+            };
+            let by_id = match self.usb_id {
+                Some((vendor, product)) => {
+                    d.input_id().vendor() == vendor
+                        && d.input_id().product() == product
+                        && d.supported_keys()
+                            .map_or(false, |keys| keys.contains(Key::KEY_ENTER))
+                }
+                None => false,
+            };
+            by_path || by_id
+        })
+    }
+
     pub async fn worker(&self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
         info!("{}: Starting task", self.name);
-        let mut terminated = false;
-
-        loop {
-            if terminated || worker_cancel_flag.load(Ordering::SeqCst) {
-                break;
-            }
+        let mut hotplug = match self.hotplug {
+            true => match Hotplug::new() {
+                Ok(hotplug) => Some(hotplug),
+                Err(e) => {
+                    error!("{}: unable to start the udev monitor: {}", self.name, e);
+                    None
+                }
+            },
+            false => None,
+        };
+        let mut backoff = Duration::from_secs(RFID_REOPEN_MIN_SECS);
 
+        while !worker_cancel_flag.load(Ordering::SeqCst) {
             info!(
-                "{}: trying to open device with physical path: {:?}",
-                self.name, self.event_path
+                "{}: trying to open device with physical path: {:?}, usb id: {:?}",
+                self.name, self.event_path, self.usb_id
             );
-            let dev = evdev::enumerate().map(|t| t.1).into_iter().find(|x| {
-                x.physical_path().is_some()
-                    && (x.physical_path().as_ref().unwrap().to_string()) == self.event_path
-            });
-
-            match dev {
-                Some(mut d) => {
+            match self.find_device() {
+                Some(d) => {
                     info!("{}: device {:?} opened", self.name, d.name());
-                    let mut tag_id: String = "".to_string();
-                    let mut local_pending_tags: Vec<u32> = vec![];
-                    let mut events = d.into_event_stream()?;
-
-                    loop {
-                        if worker_cancel_flag.load(Ordering::SeqCst) {
-                            debug!("Got terminate signal from main");
-                            terminated = true;
-                            break;
-                        }
+                    backoff = Duration::from_secs(RFID_REOPEN_MIN_SECS);
+                    match self.read_tags(d, &worker_cancel_flag).await {
+                        Ok(()) => break,
+                        Err(e) => error!("{}: read error, reopening: {}", self.name, e),
+                    }
+                }
+                None => error!("{}: device not found", self.name),
+            }
 
-                        let ev = events.next_event().await?;
-                        /* ev.value=1 is for key_down */
-                        if ev.event_type() == evdev::EventType::KEY && ev.value() == 1 {
-                            debug!("{}: got event: {:?}", self.name, ev);
-                            let mut tag_complete = false;
-
-                            //fixme - fix somehow the following ugly code
-                            //cannot do it using 'match' because 'as u16'
-                            //cannot be used inside the match statement
-                            let mut val = ' ';
-                            if ev.code() == Key::KEY_0.code() {
-                                val = '0';
-                            } else if ev.code() == Key::KEY_1.code() {
-                                val = '1';
-                            } else if ev.code() == Key::KEY_2.code() {
-                                val = '2';
-                            } else if ev.code() == Key::KEY_3.code() {
-                                val = '3';
-                            } else if ev.code() == Key::KEY_4.code() {
-                                val = '4';
-                            } else if ev.code() == Key::KEY_5.code() {
-                                val = '5';
-                            } else if ev.code() == Key::KEY_6.code() {
-                                val = '6';
-                            } else if ev.code() == Key::KEY_7.code() {
-                                val = '7';
-                            } else if ev.code() == Key::KEY_8.code() {
-                                val = '8';
-                            } else if ev.code() == Key::KEY_9.code() {
-                                val = '9';
-                            } else if ev.code() == Key::KEY_ENTER.code() {
-                                tag_complete = true;
-                            }
+            //reopen with a backoff, or as soon as the device is plugged in
+            match hotplug.as_mut() {
+                Some(hotplug) => {
+                    if hotplug.wait(backoff).await {
+                        info!("{}: input device added", self.name);
+                    }
+                }
+                None => tokio::time::sleep(backoff).await,
+            }
+            backoff = (backoff * 2).min(Duration::from_secs(RFID_REOPEN_MAX_SECS));
+        }
+        info!("{}: task stopped", self.name);
+        Ok(())
+    }
 
-                            if tag_complete {
-                                match tag_id.parse::<u32>() {
-                                    Ok(tag) => {
-                                        info!("{}: 🏷️ got complete tag ID: {}", self.name, tag);
-
-                                        if !self.push_tag_upstream(tag) {
-                                            //unable to obtain a write lock, keep it locally
-                                            local_pending_tags.push(tag);
-                                        }
-                                    }
-                                    Err(e) => {
-                                        error!(
-                                            "{}: error parsing tag ID {:?}: {:?}",
-                                            self.name, tag_id, e
-                                        );
-                                    }
-                                }
-                                tag_id.clear();
-                            } else {
-                                tag_id.push(val);
-                            }
-                        }
+    //reads the tags until cancelled (Ok) or the device is gone (Err)
+    async fn read_tags(
+        &self,
+        d: evdev::Device,
+        worker_cancel_flag: &Arc<AtomicBool>,
+    ) -> Result<()> {
+        let mut tag_id: String = "".to_string();
+        let mut local_pending_tags: Vec<u32> = vec![];
+        let mut pin_entry = self.pin_timeout.map(PinEntry::new);
+        let mut events = d.into_event_stream()?;
+
+        loop {
+            if worker_cancel_flag.load(Ordering::SeqCst) {
+                debug!("Got terminate signal from main");
+                return Ok(());
+            }
 
-                        //if there was a problem to push a tag, try again now
-                        match local_pending_tags.pop() {
-                            Some(tag) => {
-                                if !self.push_tag_upstream(tag) {
-                                    //still unable to obtain a write lock, re-push
-                                    local_pending_tags.push(tag);
-                                } else {
-                                    warn!("{}: delayed process of tag ID: {}", self.name, tag);
-                                }
+            //wake up from time to time for the cancel flag
+            let ev = match tokio::time::timeout(Duration::from_secs(1), events.next_event()).await {
+                Ok(ev) => ev?,
+                Err(_) => continue,
+            };
+            /* ev.value=1 is for key_down */
+            if ev.event_type() == evdev::EventType::KEY && ev.value() == 1 {
+                debug!("{}: got event: {:?}", self.name, ev);
+                let key = match KeypadKey::from_code(ev.code()) {
+                    Some(key) => key,
+                    None => continue,
+                };
+                if let Some(pin_entry) = pin_entry.as_mut() {
+                    if let Some(pin) = pin_entry.key(key) {
+                        push_pin(&self.name, &self.rfid_pending_pins, pin);
+                    }
+                    continue;
+                }
+
+                if key == KeypadKey::Enter {
+                    match tag_id.parse::<u32>() {
+                        Ok(tag) => {
+                            info!("{}: 🏷️ got complete tag ID: {}", self.name, tag);
+
+                            if !self.push_tag_upstream(tag) {
+                                //unable to obtain a write lock, keep it locally
+                                local_pending_tags.push(tag);
                             }
-                            _ => {}
                         }
+                        Err(e) => {
+                            error!("{}: error parsing tag ID {:?}: {:?}", self.name, tag_id, e);
+                        }
+                    }
+                    tag_id.clear();
+                } else if let KeypadKey::Digit(digit) = key {
+                    tag_id.push(digit);
+                }
+            }
+
+            //if there was a problem to push a tag, try again now
+            match local_pending_tags.pop() {
+                Some(tag) => {
+                    if !self.push_tag_upstream(tag) {
+                        //still unable to obtain a write lock, re-push
+                        local_pending_tags.push(tag);
+                    } else {
+                        warn!("{}: delayed process of tag ID: {}", self.name, tag);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Tag ID built from a Wiegand frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WiegandIdFormat {
+    /// all data bits: facility code << 16 | card number
+    Full,
+    /// only the 16-bit card number
+    Card,
+}
+
+impl WiegandIdFormat {
+    pub fn parse(format: &str) -> Option<WiegandIdFormat> {
+        match format.trim().to_lowercase().as_str() {
+            "full" => Some(WiegandIdFormat::Full),
+            "card" => Some(WiegandIdFormat::Card),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes a Wiegand 26 or 34 bit frame into the facility code and the card number.
+/// The first bit is the even parity of the first half, the last one the odd parity
+/// of the second half.
+pub fn decode_wiegand(bits: &[bool]) -> std::result::Result<(u32, u32), String> {
+    let len = bits.len();
+    if len != 26 && len != 34 {
+        return Err(format!("unsupported frame length: {} bits", len));
+    }
+    let ones = |bits: &[bool]| bits.iter().filter(|&&b| b).count();
+    if ones(&bits[..len / 2]) % 2 != 0 {
+        return Err("even parity error".to_string());
+    }
+    if ones(&bits[len / 2..]) % 2 != 1 {
+        return Err("odd parity error".to_string());
+    }
+    let data = bits[1..len - 1]
+        .iter()
+        .fold(0u32, |acc, &b| (acc << 1) | b as u32);
+    Ok((data >> 16, data & 0xffff))
+}
+
+/// Wiegand reader connected to two GPIO lines, D0 pulses are zeros and D1 pulses are ones
+pub struct WiegandReader {
+    pub name: String,
+    pub chip: String,
+    pub d0: u32,
+    pub d1: u32,
+    pub facility_code: Option<u32>, //frames with another facility code are rejected
+    pub id_format: WiegandIdFormat,
+    pub pin_timeout: Duration,
+    pub rfid_pending_tags: Arc<RwLock<Vec<u32>>>,
+    pub rfid_pending_pins: PendingPins,
+}
 
-                        thread::sleep(Duration::from_millis(30));
+impl WiegandReader {
+    fn open(&self) -> Result<(AsyncLineEventHandle, AsyncLineEventHandle)> {
+        let mut chip = Chip::new(&self.chip)?;
+        let mut line = |offset: u32| -> Result<AsyncLineEventHandle> {
+            let events = chip.get_line(offset)?.events(
+                LineRequestFlags::INPUT,
+                EventRequestFlags::FALLING_EDGE,
+                "hard-wiegand",
+            )?;
+            Ok(AsyncLineEventHandle::new(events)?)
+        };
+        Ok((line(self.d0)?, line(self.d1)?))
+    }
+
+    //validates the frame and passes the tag ID the same way as the USB reader
+    fn frame(&self, bits: &[bool], pin_entry: &mut PinEntry) {
+        debug!("{}: got {} bit frame: {:?}", self.name, bits.len(), bits);
+        //keypad readers send every key press as a short frame
+        if bits.len() == 4 || bits.len() == 8 {
+            match KeypadKey::from_wiegand(bits) {
+                Some(key) => {
+                    if let Some(pin) = pin_entry.key(key) {
+                        push_pin(&self.name, &self.rfid_pending_pins, pin);
                     }
                 }
-                None => {
-                    error!("{}: device not found", self.name);
-                    thread::sleep(Duration::from_secs(10));
+                None => warn!("{}: invalid keypad frame: {:?}", self.name, bits),
+            }
+            return;
+        }
+        let (facility, card) = match decode_wiegand(bits) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                warn!("{}: invalid frame: {}", self.name, e);
+                return;
+            }
+        };
+        if let Some(expected) = self.facility_code {
+            if facility != expected {
+                warn!(
+                    "{}: card {} rejected, facility code {} does not match {}",
+                    self.name, card, facility, expected
+                );
+                return;
+            }
+        }
+        let tag = match self.id_format {
+            WiegandIdFormat::Full => (facility << 16) | card,
+            WiegandIdFormat::Card => card,
+        };
+        info!("{}: 🏷️ got complete tag ID: {}", self.name, tag);
+        match self.rfid_pending_tags.write() {
+            Ok(mut rfid_pending_tags) => rfid_pending_tags.push(tag),
+            Err(e) => error!("{}: unable to pass tag ID {}: {}", self.name, tag, e),
+        }
+    }
+
+    async fn read_frames(
+        &self,
+        mut d0: AsyncLineEventHandle,
+        mut d1: AsyncLineEventHandle,
+        worker_cancel_flag: &Arc<AtomicBool>,
+    ) -> Result<()> {
+        let mut bits: Vec<bool> = vec![];
+        let mut pin_entry = PinEntry::new(self.pin_timeout);
+        loop {
+            if worker_cancel_flag.load(Ordering::SeqCst) {
+                debug!("Got terminate signal from main");
+                return Ok(());
+            }
+            //a short gap completes the frame, otherwise just wake up for the cancel flag
+            let timeout = match bits.is_empty() {
+                true => Duration::from_millis(500),
+                false => Duration::from_millis(WIEGAND_FRAME_GAP_MS),
+            };
+            tokio::select! {
+                event = d0.next() => match event {
+                    Some(event) => {
+                        event?;
+                        bits.push(false);
+                    }
+                    None => return Err("D0 event stream closed".into()),
+                },
+                event = d1.next() => match event {
+                    Some(event) => {
+                        event?;
+                        bits.push(true);
+                    }
+                    None => return Err("D1 event stream closed".into()),
+                },
+                _ = tokio::time::sleep(timeout) => {
+                    if !bits.is_empty() {
+                        self.frame(&std::mem::take(&mut bits), &mut pin_entry);
+                    }
                 }
             }
         }
+    }
+
+    pub async fn worker(&self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
+        info!(
+            "{}: Starting task, chip: {}, D0: {}, D1: {}",
+            self.name, self.chip, self.d0, self.d1
+        );
+        while !worker_cancel_flag.load(Ordering::SeqCst) {
+            let result = match self.open() {
+                Ok((d0, d1)) => {
+                    info!("{}: GPIO lines opened", self.name);
+                    self.read_frames(d0, d1, &worker_cancel_flag).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!("{}: {}", self.name, e);
+                tokio::time::sleep(Duration::from_secs(WIEGAND_RETRY_SECS)).await;
+            }
+        }
         info!("{}: task stopped", self.name);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STORED: &str =
+        "sha256$a1b2c3d4$d7111b7c6921b3bbffdb55c7845e581ad648165853daa6bd6811d76e8554f004";
+
+    #[test]
+    fn pin_hash_verify() {
+        let pin = PinHash::parse(STORED).unwrap();
+        assert!(pin.verify("4711"));
+        assert!(!pin.verify("4712"));
+        assert!(!pin.verify(""));
+        //the digest may be uppercase
+        let (prefix, digest) = STORED.rsplit_once('$').unwrap();
+        let uppercase = format!("{}${}", prefix, digest.to_uppercase());
+        assert!(PinHash::parse(&uppercase).unwrap().verify("4711"));
+    }
+
+    #[test]
+    fn plain_pin_is_rejected() {
+        assert!(PinHash::parse("4711").is_none());
+        assert!(PinHash::parse("sha256$$d7111b7c").is_none());
+        assert!(PinHash::parse(&STORED.replace("a1b2c3d4$", "")).is_none());
+        assert!(PinHash::load("card", Some("4711")).is_none());
+        assert!(PinHash::load("card", Some(" ")).is_none());
+        assert!(PinHash::load("card", Some(STORED)).is_some());
+    }
+}
@@ -0,0 +1,107 @@
+use crate::onewire::{OneWireTask, TaskCommand};
+use crate::schedule::CronJob;
+use crate::shutter::ShutterAction;
+use serde::Serialize;
+use simplelog::*;
+use std::time::Duration;
+
+pub static SCENE_TAG: &str = "scene"; //sensor/rfid tag: scene:<name>
+
+/// Named list of relay/yeelight/plug actions, triggered together
+#[derive(Clone)]
+pub struct Scene {
+    pub name: String,
+    pub actions: Vec<OneWireTask>,
+}
+
+#[derive(Serialize)]
+pub struct SceneStatus {
+    pub name: String,
+    pub actions: usize,
+}
+
+impl Scene {
+    /// Parses a single action: `<relay|yeelight|plug|tag>:<id>:<on|off|toggle>[:secs]`
+    /// or `shutter:<name>:<up|down|stop|position>`
+    pub fn parse_action(action: &str) -> Option<OneWireTask> {
+        let v: Vec<&str> = action.trim().split(':').collect();
+        if v.get(0) == Some(&"shutter") {
+            return Some(ShutterAction::parse(v.get(2)?)?.task(v.get(1)?));
+        }
+        let mut task = OneWireTask {
+            command: CronJob::parse_command(v.get(2)?)?,
+            id_relay: None,
+            tag_group: None,
+            id_yeelight: None,
+            id_plug: None,
+            duration: v
+                .get(3)
+                .and_then(|x| x.parse().ok())
+                .map(Duration::from_secs_f32),
+        };
+        match *v.get(0)? {
+            "relay" => task.id_relay = Some(v.get(1)?.parse().ok()?),
+            "yeelight" => task.id_yeelight = Some(v.get(1)?.parse().ok()?),
+            "plug" => task.id_plug = Some(v.get(1)?.parse().ok()?),
+            "tag" => task.tag_group = Some(v.get(1)?.to_string()),
+            _ => return None,
+        }
+        Some(task)
+    }
+
+    /// Parses the comma separated action list of the `[scene:<name>]` config section
+    pub fn parse(name: &str, actions: &str) -> Result<Scene, String> {
+        let actions = actions
+            .split(',')
+            .filter(|a| !a.trim().is_empty())
+            .map(|a| Scene::parse_action(a).ok_or(format!("invalid action: {:?}", a.trim())))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Scene {
+            name: name.to_string(),
+            actions,
+        })
+    }
+
+    /// Task triggering the scene, expanded by the onewire worker
+    pub fn task(name: &str) -> OneWireTask {
+        OneWireTask {
+            command: TaskCommand::Scene(name.to_string()),
+            id_relay: None,
+            tag_group: None,
+            id_yeelight: None,
+            id_plug: None,
+            duration: None,
+        }
+    }
+
+    /// Scene name from a `scene:<name>` tag
+    pub fn from_tag(tag: &str) -> Option<&str> {
+        tag.strip_prefix(SCENE_TAG)?.strip_prefix(':')
+    }
+
+    /// Scene names from the `scene:<name>` tags
+    pub fn from_tags(tags: &Vec<String>) -> Vec<String> {
+        tags.iter()
+            .filter_map(|t| Scene::from_tag(t))
+            .map(|name| name.to_string())
+            .collect()
+    }
+}
+
+/// Replaces the scene tasks with their actions, so all of them are processed in one go
+pub fn expand(tasks: Vec<OneWireTask>, scenes: &Vec<Scene>) -> Vec<OneWireTask> {
+    let mut expanded = vec![];
+    for task in tasks {
+        match &task.command {
+            TaskCommand::Scene(name) => match scenes.iter().find(|s| &s.name == name) {
+                Some(scene) => {
+                    info!("🎬 scene {}: {} actions", scene.name, scene.actions.len());
+                    expanded.extend(scene.actions.iter().cloned());
+                }
+                None => error!("🎬 unknown scene: {}", name),
+            },
+            _ => expanded.push(task),
+        }
+    }
+    expanded
+}
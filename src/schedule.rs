@@ -0,0 +1,636 @@
+use crate::onewire::{OneWireTask, TaskCommand};
+use chrono::{
+    DateTime, Datelike, Duration as ChronoDuration, Local, LocalResult, NaiveDate, NaiveTime,
+    TimeZone, Timelike,
+};
+use humantime::parse_duration;
+use std::fmt;
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+pub const SUNRISE_SUN_DEGREE: f64 = -0.833; //sun elevation for sunrise/sunset (with refraction)
+pub const SCHEDULE_CHECK_INTERVAL_SECS: f32 = 30.0; //secs between evaluating relay schedules
+
+/// Parses a signed offset like "+30m" or "-1h", returns seconds
+fn parse_offset(input: &str) -> Result<i64> {
+    if let Some(duration) = input.strip_prefix('+') {
+        Ok(parse_duration(duration)?.as_secs() as i64)
+    } else if let Some(duration) = input.strip_prefix('-') {
+        Ok(-(parse_duration(duration)?.as_secs() as i64))
+    } else {
+        Err(format!("invalid offset sign in: {:?}", input).into())
+    }
+}
+
+/// Wall clock time of the day in the timezone: the earlier one when repeated
+/// (autumn DST change), shifted by an hour when skipped (spring DST change)
+fn wall_clock<Tz: TimeZone>(tz: &Tz, day: NaiveDate, time: NaiveTime) -> Option<DateTime<Tz>> {
+    let naive = day.and_time(time);
+    match tz.from_local_datetime(&naive) {
+        LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => Some(t),
+        LocalResult::None => tz
+            .from_local_datetime(&(naive + ChronoDuration::hours(1)))
+            .earliest(),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScheduleTime {
+    Fixed(NaiveTime),
+    Sunrise(i64), //offset in seconds
+    Sunset(i64),  //offset in seconds
+}
+
+impl ScheduleTime {
+    /// Parses a schedule time: "06:30", "22:15:00", "sunrise", "sunset -30min", "sunrise+1h"
+    pub fn parse(input: &str) -> Result<ScheduleTime> {
+        let s: String = input
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>()
+            .to_lowercase();
+
+        for (prefix, sunrise) in &[("sunrise", true), ("sunset", false)] {
+            if s.starts_with(prefix) {
+                let rest = &s[prefix.len()..];
+                let offset = if rest.is_empty() {
+                    0
+                } else {
+                    parse_offset(rest)?
+                };
+                return Ok(if *sunrise {
+                    ScheduleTime::Sunrise(offset)
+                } else {
+                    ScheduleTime::Sunset(offset)
+                });
+            }
+        }
+
+        match NaiveTime::parse_from_str(&s, "%H:%M:%S") {
+            Ok(t) => Ok(ScheduleTime::Fixed(t)),
+            Err(_) => Ok(ScheduleTime::Fixed(NaiveTime::parse_from_str(&s, "%H:%M")?)),
+        }
+    }
+
+    /// Resolves the time for the day of given sun times
+    fn resolve(&self, sun: &SunTimes) -> Option<DateTime<Local>> {
+        match self {
+            ScheduleTime::Fixed(t) => wall_clock(&Local, sun.day?, *t),
+            ScheduleTime::Sunrise(offset) => {
+                sun.sunrise.map(|t| t + ChronoDuration::seconds(*offset))
+            }
+            ScheduleTime::Sunset(offset) => {
+                sun.sunset.map(|t| t + ChronoDuration::seconds(*offset))
+            }
+        }
+    }
+}
+
+impl fmt::Display for ScheduleTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScheduleTime::Fixed(t) => write!(f, "{}", t.format("%H:%M:%S")),
+            ScheduleTime::Sunrise(offset) => write!(f, "sunrise {:+}s", offset),
+            ScheduleTime::Sunset(offset) => write!(f, "sunset {:+}s", offset),
+        }
+    }
+}
+
+/// Sun elevation used as the day/night boundary
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Twilight {
+    Official,
+    Civil,
+    Nautical,
+    Astronomical,
+}
+
+impl Twilight {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "official" => Some(Twilight::Official),
+            "civil" => Some(Twilight::Civil),
+            "nautical" => Some(Twilight::Nautical),
+            "astronomical" => Some(Twilight::Astronomical),
+            _ => None,
+        }
+    }
+
+    pub fn degree(&self) -> f64 {
+        match self {
+            Twilight::Official => SUNRISE_SUN_DEGREE,
+            Twilight::Civil => -6.0,
+            Twilight::Nautical => -12.0,
+            Twilight::Astronomical => -18.0,
+        }
+    }
+}
+
+/// Sunrise and sunset times for a single local day
+pub struct SunTimes {
+    pub degree: f64,
+    pub day: Option<NaiveDate>,
+    pub sunrise: Option<DateTime<Local>>,
+    pub sunset: Option<DateTime<Local>>,
+}
+
+impl SunTimes {
+    pub fn new() -> Self {
+        SunTimes::with_degree(SUNRISE_SUN_DEGREE)
+    }
+
+    /// Sun times for a custom elevation, eg. the end of the civil twilight
+    pub fn with_degree(degree: f64) -> Self {
+        SunTimes {
+            degree,
+            day: None,
+            sunrise: None,
+            sunset: None,
+        }
+    }
+
+    /// Recalculates the times when the local day has changed
+    pub fn update(&mut self, now: DateTime<Local>, lat: f64, lon: f64) {
+        let today = now.date_naive();
+        if self.day == Some(today) {
+            return;
+        }
+        self.day = Some(today);
+        self.sunrise = None;
+        self.sunset = None;
+        let midnight = match wall_clock(&Local, today, NaiveTime::from_hms_opt(0, 0, 0).unwrap()) {
+            Some(midnight) => midnight,
+            None => return,
+        };
+
+        //no geolocation set: only fixed times can be used
+        if lat == 0.0 && lon == 0.0 {
+            return;
+        }
+
+        //scan the whole day with 1 minute resolution
+        let mut prev_alt: Option<f64> = None;
+        for minute in 0..=24 * 60 {
+            let t = midnight + ChronoDuration::minutes(minute);
+            let alt = sun::pos(t.timestamp_millis(), lat, lon)
+                .altitude
+                .to_degrees();
+            if let Some(prev) = prev_alt {
+                if self.sunrise.is_none() && prev < self.degree && alt >= self.degree {
+                    self.sunrise = Some(t);
+                }
+                if self.sunset.is_none() && prev >= self.degree && alt < self.degree {
+                    self.sunset = Some(t);
+                }
+            }
+            prev_alt = Some(alt);
+        }
+    }
+}
+
+/// Night window of a relay tagged `all_night:[twilight][:on/off]`, eg. `all_night:+30m/-45m`
+/// is on 30 minutes after sunset and off 45 minutes before sunrise
+#[derive(Clone, Debug, PartialEq)]
+pub struct AllNight {
+    pub twilight: Twilight,
+    pub on_offset: i64,  //secs relative to sunset
+    pub off_offset: i64, //secs relative to sunrise
+}
+
+impl AllNight {
+    /// Parses the tag parameters, returns None for other tags (and the plain `all_night`)
+    pub fn parse(tag: &str) -> Option<Result<AllNight>> {
+        let params = tag.strip_prefix("all_night:")?;
+        let mut all_night = AllNight {
+            twilight: Twilight::Official,
+            on_offset: 0,
+            off_offset: 0,
+        };
+        for param in params.split(':') {
+            let param: String = param.chars().filter(|c| !c.is_whitespace()).collect();
+            if let Some(twilight) = Twilight::parse(&param) {
+                all_night.twilight = twilight;
+                continue;
+            }
+            let offsets = match param.split_once('/') {
+                Some(offsets) => offsets,
+                None => {
+                    return Some(Err(
+                        format!("invalid all_night parameter: {:?}", param).into()
+                    ))
+                }
+            };
+            match (parse_offset(offsets.0), parse_offset(offsets.1)) {
+                (Ok(on), Ok(off)) => {
+                    all_night.on_offset = on;
+                    all_night.off_offset = off;
+                }
+                (Err(e), _) | (_, Err(e)) => return Some(Err(e)),
+            }
+        }
+        Some(Ok(all_night))
+    }
+
+    /// Checks if the relay should be on at the given time,
+    /// None when the sun doesn't cross the twilight elevation this day
+    pub fn is_night(&self, now: DateTime<Local>, sun: &SunTimes) -> Option<bool> {
+        let off = sun.sunrise? + ChronoDuration::seconds(self.off_offset);
+        let on = sun.sunset? + ChronoDuration::seconds(self.on_offset);
+        Some(now < off || now >= on)
+    }
+}
+
+pub struct RelaySchedule {
+    pub id_schedule: i32,
+    pub id_relay: i32,
+    pub on_time: ScheduleTime,
+    pub off_time: ScheduleTime,
+    pub weekdays: Vec<i32>, //ISO numbering: 1 = Monday .. 7 = Sunday, empty = every day
+    pub active: Option<bool>,
+}
+
+impl RelaySchedule {
+    fn weekday_enabled(&self, day: DateTime<Local>) -> bool {
+        self.weekdays.is_empty()
+            || self
+                .weekdays
+                .contains(&(day.weekday().number_from_monday() as i32))
+    }
+
+    /// Checks if the schedule window is active at the given time.
+    /// Returns the remaining time until the window ends for an active window,
+    /// or None when the times cannot be resolved (eg. no sunset in polar regions).
+    pub fn evaluate(
+        &self,
+        now: DateTime<Local>,
+        sun: &SunTimes,
+    ) -> Option<(bool, Option<Duration>)> {
+        let on = self.on_time.resolve(sun)?;
+        let off = self.off_time.resolve(sun)?;
+
+        let remaining = |end: DateTime<Local>| (end - now).to_std().ok();
+        if on <= off {
+            if now >= on && now < off && self.weekday_enabled(now) {
+                return Some((true, remaining(off)));
+            }
+        } else {
+            //overnight window, eg. 22:00 - 06:00
+            if now >= on && self.weekday_enabled(now) {
+                return Some((true, remaining(off + ChronoDuration::days(1))));
+            }
+            if now < off && self.weekday_enabled(now - ChronoDuration::days(1)) {
+                return Some((true, remaining(off)));
+            }
+        }
+        Some((false, None))
+    }
+}
+
+pub const CRON_CHECK_INTERVAL_SECS: f32 = 5.0; //secs between evaluating cron jobs
+pub const CRON_MAX_LOOKAHEAD_DAYS: i64 = 5 * 366; //next run search limit, covers Feb 29
+
+/// Parsed five-field cron expression: minute hour day-of-month month day-of-week
+#[derive(Clone, Debug, PartialEq)]
+pub struct CronExpr {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl CronExpr {
+    /// Parses a single field: `*`, `5`, `1-5`, `*/15`, `0-30/10` and comma-separated lists
+    fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+        let mut mask = 0u64;
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u32>()?),
+                None => (part, 1),
+            };
+            let (from, to) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((from, to)) => (from.parse()?, to.parse()?),
+                    None => {
+                        let value = range.parse()?;
+                        //a single value with a step means "from value to the end"
+                        (value, if part.contains('/') { max } else { value })
+                    }
+                },
+            };
+            if step == 0 || from < min || to > max || from > to {
+                return Err(format!("invalid cron field: {:?}", field).into());
+            }
+            for value in (from..=to).step_by(step as usize) {
+                mask |= 1 << value;
+            }
+        }
+        Ok(mask)
+    }
+
+    pub fn parse(input: &str) -> Result<CronExpr> {
+        let fields: Vec<&str> = input.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("cron expression needs 5 fields: {:?}", input).into());
+        }
+        let mut weekdays = CronExpr::parse_field(fields[4], 0, 7)?;
+        //both 0 and 7 are Sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        let days = CronExpr::parse_field(fields[2], 1, 31)?;
+        Ok(CronExpr {
+            minutes: CronExpr::parse_field(fields[0], 0, 59)?,
+            hours: CronExpr::parse_field(fields[1], 0, 23)?,
+            days,
+            months: CronExpr::parse_field(fields[3], 1, 12)?,
+            weekdays,
+            //a field is unrestricted when all its values are set, eg. `*` or `1-31`
+            any_day: days == CronExpr::parse_field("*", 1, 31)?,
+            any_weekday: weekdays & 0x7f == 0x7f,
+        })
+    }
+
+    fn bit(mask: u64, value: u32) -> bool {
+        mask & (1 << value) != 0
+    }
+
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        let day = CronExpr::bit(self.days, date.day());
+        let weekday = CronExpr::bit(self.weekdays, date.weekday().num_days_from_sunday());
+        //like in cron: when both day fields are restricted, either of them may match
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        CronExpr::bit(self.months, date.month()) && day_matches
+    }
+
+    pub fn matches(&self, t: DateTime<Local>) -> bool {
+        CronExpr::bit(self.minutes, t.minute())
+            && CronExpr::bit(self.hours, t.hour())
+            && self.day_matches(t.date_naive())
+    }
+
+    /// The first matching minute after the given time, None when there is no such day
+    /// in [`CRON_MAX_LOOKAHEAD_DAYS`] (eg. Feb 30). The times skipped by a DST change never match.
+    pub fn next_after<Tz: TimeZone>(&self, t: DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let start = t.naive_local().date();
+        for days in 0..CRON_MAX_LOOKAHEAD_DAYS {
+            let date = start.checked_add_signed(ChronoDuration::days(days))?;
+            if !self.day_matches(date) {
+                continue;
+            }
+            for hour in (0..24).filter(|h| CronExpr::bit(self.hours, *h)) {
+                for minute in (0..60).filter(|m| CronExpr::bit(self.minutes, *m)) {
+                    let next = t
+                        .timezone()
+                        .from_local_datetime(&date.and_hms_opt(hour, minute, 0).unwrap())
+                        .earliest();
+                    match next {
+                        Some(next) if next > t => return Some(next),
+                        _ => continue,
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Cron-triggered relay/yeelight/plug task, from the config (no id) or the database.
+/// Unlike a [`RelaySchedule`] window, which holds a relay state between two (also sun
+/// relative) times and restores it after a restart, a job only sends its command
+/// in the matching minutes: eg. a toggle, a timed prolong or a yeelight/plug command.
+pub struct CronJob {
+    pub id_job: Option<i32>,
+    pub name: String,
+    pub expression: String,
+    pub cron: CronExpr,
+    pub id_relay: Option<i32>,
+    pub tag_group: Option<String>,
+    pub id_yeelight: Option<i32>,
+    pub id_plug: Option<i32>,
+    pub command: TaskCommand,
+    pub duration: Option<Duration>,
+    pub last_run: Option<DateTime<Local>>,
+}
+
+impl CronJob {
+    pub fn parse_command(command: &str) -> Option<TaskCommand> {
+        match command.trim() {
+            "on" | "prolong" => Some(TaskCommand::TurnOnProlong),
+            "off" => Some(TaskCommand::TurnOff),
+            "toggle" => Some(TaskCommand::Toggle),
+            _ => None,
+        }
+    }
+
+    /// Returns the task when the job is due, only once in a matching minute
+    pub fn check(&mut self, now: DateTime<Local>) -> Option<OneWireTask> {
+        let minute = now.with_second(0)?.with_nanosecond(0)?;
+        if self.last_run == Some(minute) || !self.cron.matches(now) {
+            return None;
+        }
+        self.last_run = Some(minute);
+        Some(OneWireTask {
+            command: self.command.clone(),
+            id_relay: self.id_relay,
+            tag_group: self.tag_group.clone(),
+            id_yeelight: self.id_yeelight,
+            id_plug: self.id_plug,
+            duration: self.duration,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{FixedOffset, NaiveDateTime};
+
+    /// Central European time with the 2021 DST changes only
+    #[derive(Clone, Copy, Debug)]
+    struct Warsaw2021;
+
+    impl TimeZone for Warsaw2021 {
+        type Offset = FixedOffset;
+
+        fn from_offset(_offset: &FixedOffset) -> Self {
+            Warsaw2021
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_hms_opt(12, 0, 0).unwrap())
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            let valid: Vec<FixedOffset> = [
+                FixedOffset::east_opt(7200).unwrap(),
+                FixedOffset::east_opt(3600).unwrap(),
+            ]
+            .iter()
+            .copied()
+            .filter(|offset| {
+                let utc = *local - ChronoDuration::seconds(offset.local_minus_utc() as i64);
+                self.offset_from_utc_datetime(&utc) == *offset
+            })
+            .collect();
+            match valid[..] {
+                [offset] => LocalResult::Single(offset),
+                [earlier, later] => LocalResult::Ambiguous(earlier, later),
+                _ => LocalResult::None,
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_hms_opt(0, 0, 0).unwrap())
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            let summer = NaiveDate::from_ymd_opt(2021, 3, 28)
+                .unwrap()
+                .and_hms_opt(1, 0, 0)
+                .unwrap()
+                ..NaiveDate::from_ymd_opt(2021, 10, 31)
+                    .unwrap()
+                    .and_hms_opt(1, 0, 0)
+                    .unwrap();
+            if summer.contains(utc) {
+                FixedOffset::east_opt(7200).unwrap()
+            } else {
+                FixedOffset::east_opt(3600).unwrap()
+            }
+        }
+    }
+
+    /// UTC time of the wall clock time of the day
+    fn utc_time(day: (i32, u32, u32), time: (u32, u32)) -> Option<NaiveTime> {
+        wall_clock(
+            &Warsaw2021,
+            NaiveDate::from_ymd_opt(day.0, day.1, day.2).unwrap(),
+            NaiveTime::from_hms_opt(time.0, time.1, 0).unwrap(),
+        )
+        .map(|t| t.naive_utc().time())
+    }
+
+    #[test]
+    fn schedule_time_offset_sign() {
+        assert_eq!(
+            ScheduleTime::parse("sunset -30min").unwrap(),
+            ScheduleTime::Sunset(-1800)
+        );
+        assert_eq!(
+            ScheduleTime::parse("sunrise+1h").unwrap(),
+            ScheduleTime::Sunrise(3600)
+        );
+        assert_eq!(
+            ScheduleTime::parse("Sunrise").unwrap(),
+            ScheduleTime::Sunrise(0)
+        );
+        assert_eq!(
+            ScheduleTime::parse("22:15").unwrap(),
+            ScheduleTime::Fixed(NaiveTime::from_hms_opt(22, 15, 0).unwrap())
+        );
+        assert!(ScheduleTime::parse("sunset 30min").is_err());
+        assert!(ScheduleTime::parse("sunset+").is_err());
+        //multi-byte sign is an error, not a panic
+        assert!(ScheduleTime::parse("sunset−30min").is_err());
+        assert!(ScheduleTime::parse("sunriseł1h").is_err());
+    }
+
+    #[test]
+    fn fixed_time_on_dst_days() {
+        let utc = |h, m| Some(NaiveTime::from_hms_opt(h, m, 0).unwrap());
+        //spring: 06:30 CEST, not 07:30 (midnight CET + 6:30)
+        assert_eq!(utc_time((2021, 3, 28), (6, 30)), utc(4, 30));
+        //the skipped 02:30 is run after the change
+        assert_eq!(utc_time((2021, 3, 28), (2, 30)), utc(1, 30));
+        //autumn: 06:30 CET, not 05:30 (midnight CEST + 6:30)
+        assert_eq!(utc_time((2021, 10, 31), (6, 30)), utc(5, 30));
+        //the repeated 02:30 is run once, the first time
+        assert_eq!(utc_time((2021, 10, 31), (2, 30)), utc(0, 30));
+        //regular day
+        assert_eq!(utc_time((2021, 7, 1), (6, 30)), utc(4, 30));
+    }
+
+    fn warsaw(day: (i32, u32, u32), time: (u32, u32)) -> DateTime<Warsaw2021> {
+        Warsaw2021
+            .with_ymd_and_hms(day.0, day.1, day.2, time.0, time.1, 0)
+            .unwrap()
+    }
+
+    fn next(expression: &str, from: DateTime<Warsaw2021>) -> Option<DateTime<Warsaw2021>> {
+        CronExpr::parse(expression).unwrap().next_after(from)
+    }
+
+    #[test]
+    fn cron_expression_parser() {
+        let cron = CronExpr::parse("*/15 0-6/2 1,15 * 7").unwrap();
+        assert_eq!(cron.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(cron.hours, 1 | 1 << 2 | 1 << 4 | 1 << 6);
+        assert_eq!(cron.days, 1 << 1 | 1 << 15);
+        //both 0 and 7 are Sunday
+        assert_eq!(cron.weekdays & 1, 1);
+        assert!(!cron.any_day && !cron.any_weekday);
+
+        assert_eq!(
+            CronExpr::parse("5/20 * * * *").unwrap().minutes,
+            1 << 5 | 1 << 25 | 1 << 45
+        );
+        assert!(CronExpr::parse("0 0 1-31 * 0-6").unwrap().any_day);
+        assert!(CronExpr::parse("0 0 * * 0-6").unwrap().any_weekday);
+        assert!(!CronExpr::parse("0 0 */2 * *").unwrap().any_day);
+
+        assert!(CronExpr::parse("* * * *").is_err());
+        assert!(CronExpr::parse("60 * * * *").is_err());
+        assert!(CronExpr::parse("*/0 * * * *").is_err());
+        assert!(CronExpr::parse("0 5-1 * * *").is_err());
+        assert!(CronExpr::parse("0 0 0 * *").is_err());
+        assert!(CronExpr::parse("0 0 * 13 *").is_err());
+        assert!(CronExpr::parse("0 0 * * mon").is_err());
+    }
+
+    #[test]
+    fn cron_next_fire() {
+        //2021-01-08 is a Friday
+        let friday = warsaw((2021, 1, 8), (10, 7));
+        assert_eq!(
+            next("*/15 * * * *", friday),
+            Some(warsaw((2021, 1, 8), (10, 15)))
+        );
+        assert_eq!(
+            next("0 9 * * 1-5", friday),
+            Some(warsaw((2021, 1, 11), (9, 0)))
+        );
+        //a full day of month range doesn't enable every day
+        assert_eq!(
+            next("0 9 1-31 * 1-5", friday),
+            Some(warsaw((2021, 1, 11), (9, 0)))
+        );
+        //stepped day of month with any weekday
+        assert_eq!(
+            next("0 0 */2 * *", friday),
+            Some(warsaw((2021, 1, 9), (0, 0)))
+        );
+        //both day fields restricted: either of them
+        assert_eq!(
+            next("0 0 20 * 1", friday),
+            Some(warsaw((2021, 1, 11), (0, 0)))
+        );
+        assert_eq!(
+            next("0 0 29 2 *", friday),
+            Some(warsaw((2024, 2, 29), (0, 0)))
+        );
+        assert_eq!(next("0 0 30 2 *", friday), None);
+        //02:30 doesn't exist on the spring DST day
+        assert_eq!(
+            next("30 2 * * *", warsaw((2021, 3, 27), (3, 0))),
+            Some(warsaw((2021, 3, 29), (2, 30)))
+        );
+    }
+}
@@ -0,0 +1,402 @@
+use crate::config::Config;
+use crate::onewire::{OneWireTask, TaskCommand};
+use crate::virtual_sensor::SensorValues;
+use simplelog::*;
+use std::time::{Duration, Instant};
+
+pub const SHUTTER_DEFAULT_TRAVEL_SECS: f32 = 30.0; //full travel time from open to closed
+pub const SHUTTER_REVERSE_DELAY_SECS: f32 = 1.5; //both relays are off that long before reversing the motor
+pub const SHUTTER_OVERRUN_SECS: f32 = 2.0; //extra run into the end stop, recalibrating the position
+pub const SHUTTER_RULE_TEMP_HYSTERESIS: f32 = 0.5; //°C below the threshold to open again
+
+/// Requested shutter movement, position in percent: 0 is open, 100 is closed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutterAction {
+    Move(u8),
+    Stop,
+}
+
+impl ShutterAction {
+    /// `up`, `down`, `stop` or the position in percent
+    pub fn parse(action: &str) -> Option<Self> {
+        match action.trim() {
+            "up" | "open" => Some(ShutterAction::Move(0)),
+            "down" | "close" => Some(ShutterAction::Move(100)),
+            "stop" => Some(ShutterAction::Stop),
+            position => match position.parse::<u8>() {
+                Ok(position) if position <= 100 => Some(ShutterAction::Move(position)),
+                _ => None,
+            },
+        }
+    }
+
+    /// Task moving the shutter, handled by the onewire worker
+    pub fn task(self, shutter: &str) -> OneWireTask {
+        OneWireTask {
+            command: TaskCommand::Shutter(shutter.to_string(), self),
+            id_relay: None,
+            tag_group: None,
+            id_yeelight: None,
+            id_plug: None,
+            duration: None,
+        }
+    }
+}
+
+struct Motion {
+    up: bool,
+    from: f32,
+    target: f32,
+    started: Instant,
+    duration: Duration,
+}
+
+/// Roller shutter driven by a pair of relays with the up/down interlock,
+/// the position is tracked from the travel time
+pub struct Shutter {
+    pub name: String,
+    pub up_relay: i32,
+    pub down_relay: i32,
+    pub travel: Duration,
+    pub open_at_sunrise: bool,
+    /// percent closed, unknown until the first full travel
+    pub position: Option<f32>,
+    motion: Option<Motion>,
+    pending: Option<(u8, Instant)>,
+}
+
+impl Shutter {
+    fn relay_task(id_relay: i32, on: bool, duration: Option<Duration>) -> OneWireTask {
+        OneWireTask {
+            command: if on {
+                TaskCommand::TurnOnProlong
+            } else {
+                TaskCommand::TurnOff
+            },
+            id_relay: Some(id_relay),
+            tag_group: None,
+            id_yeelight: None,
+            id_plug: None,
+            duration,
+        }
+    }
+
+    fn current_position(&self) -> Option<f32> {
+        match &self.motion {
+            Some(motion) => {
+                let done = (motion.started.elapsed().as_secs_f32() / motion.duration.as_secs_f32())
+                    .min(1.0);
+                Some(motion.from + (motion.target - motion.from) * done)
+            }
+            None => self.position,
+        }
+    }
+
+    fn stop(&mut self, tasks: &mut Vec<OneWireTask>) {
+        if let Some(motion) = &self.motion {
+            tasks.push(Shutter::relay_task(
+                if motion.up {
+                    self.up_relay
+                } else {
+                    self.down_relay
+                },
+                false,
+                None,
+            ));
+            //a stopped calibration run leaves the position unknown
+            self.position = if motion.from.is_nan() {
+                None
+            } else {
+                self.current_position()
+            };
+            self.motion = None;
+        }
+    }
+
+    /// Stops the shutter or sets the new target, the movement is started by `process`
+    pub fn request(&mut self, action: ShutterAction, tasks: &mut Vec<OneWireTask>) {
+        let delay = if self.motion.is_some() {
+            Duration::from_secs_f32(SHUTTER_REVERSE_DELAY_SECS)
+        } else {
+            Duration::ZERO
+        };
+        self.stop(tasks);
+        match action {
+            ShutterAction::Move(target) => {
+                info!("🪟 shutter <b>{}</>: moving to {}%", self.name, target);
+                self.pending = Some((target, Instant::now() + delay));
+            }
+            ShutterAction::Stop => {
+                info!(
+                    "🪟 shutter <b>{}</>: stopped at {}",
+                    self.name,
+                    self.position
+                        .map_or("unknown position".to_string(), |p| format!("{:.0}%", p))
+                );
+                self.pending = None;
+            }
+        }
+    }
+
+    /// Ends the finished movement and starts the pending one when the other relay is off
+    pub fn process(&mut self, relay_on: impl Fn(i32) -> bool, tasks: &mut Vec<OneWireTask>) {
+        if let Some(motion) = &self.motion {
+            if motion.started.elapsed() >= motion.duration {
+                let (id_relay, target) = (
+                    if motion.up {
+                        self.up_relay
+                    } else {
+                        self.down_relay
+                    },
+                    motion.target,
+                );
+                //the relay is switched off on its own after the duration, just to be sure
+                tasks.push(Shutter::relay_task(id_relay, false, None));
+                self.position = Some(target);
+                self.motion = None;
+                debug!("🪟 shutter <b>{}</>: at {}%", self.name, target);
+            }
+        }
+        let (target, not_before) = match self.pending {
+            Some(pending) if self.motion.is_none() => pending,
+            _ => return,
+        };
+        if Instant::now() < not_before {
+            return;
+        }
+        //unknown position: the first run goes to the end stop
+        let (from, target_pos) = match self.position {
+            Some(position) => (position, target as f32),
+            None if target >= 50 => (f32::NAN, 100.0),
+            None => (f32::NAN, 0.0),
+        };
+        if from == target_pos {
+            self.pending = None;
+            return;
+        }
+        let up = target_pos < from || (from.is_nan() && target_pos == 0.0);
+        let (id_relay, other_relay) = if up {
+            (self.up_relay, self.down_relay)
+        } else {
+            (self.down_relay, self.up_relay)
+        };
+        //interlock: never drive both directions at once
+        if relay_on(other_relay) {
+            warn!(
+                "🪟 shutter <b>{}</>: relay {} still on, waiting",
+                self.name, other_relay
+            );
+            tasks.push(Shutter::relay_task(other_relay, false, None));
+            self.pending = Some((
+                target,
+                Instant::now() + Duration::from_secs_f32(SHUTTER_REVERSE_DELAY_SECS),
+            ));
+            return;
+        }
+        let travel = self.travel.as_secs_f32();
+        let mut secs = if from.is_nan() {
+            travel
+        } else {
+            (target_pos - from).abs() / 100.0 * travel
+        };
+        if target_pos == 0.0 || target_pos == 100.0 {
+            secs += SHUTTER_OVERRUN_SECS;
+        }
+        let duration = Duration::from_secs_f32(secs);
+        debug!(
+            "🪟 shutter <b>{}</>: {} for {:?}",
+            self.name,
+            if up { "up" } else { "down" },
+            duration
+        );
+        tasks.push(Shutter::relay_task(id_relay, true, Some(duration)));
+        self.motion = Some(Motion {
+            up,
+            from,
+            target: target_pos,
+            started: Instant::now(),
+            duration,
+        });
+        //after the calibration run the real target is still pending
+        self.pending = if from.is_nan() && target as f32 != target_pos {
+            Some((target, Instant::now()))
+        } else {
+            None
+        };
+    }
+}
+
+/// Closes the shutters while the sun is in the window (and the room is warm),
+/// opens them again afterwards
+pub struct ShutterRule {
+    pub name: String,
+    pub shutters: Vec<String>,
+    pub azimuth: (f32, f32),
+    pub elevation: (f32, f32),
+    pub sensor: Option<String>,
+    pub temp_above: Option<f32>,
+    pub position: u8,
+    pub active: Option<bool>,
+}
+
+impl ShutterRule {
+    /// Returns the new state on a change
+    pub fn evaluate(
+        &mut self,
+        azimuth: f32,
+        elevation: f32,
+        values: &SensorValues,
+    ) -> Option<bool> {
+        let sun = azimuth >= self.azimuth.0
+            && azimuth <= self.azimuth.1
+            && elevation >= self.elevation.0
+            && elevation <= self.elevation.1;
+        let warm = match (&self.sensor, self.temp_above) {
+            (Some(sensor), Some(threshold)) => match values.get(sensor) {
+                Some(temp) if self.active == Some(true) => {
+                    *temp > threshold - SHUTTER_RULE_TEMP_HYSTERESIS
+                }
+                Some(temp) => *temp > threshold,
+                //keep the current state without the temperature
+                None => self.active.unwrap_or(false),
+            },
+            _ => true,
+        };
+        let active = sun && warm;
+        if self.active == Some(active) || (self.active.is_none() && !active) {
+            self.active = Some(active);
+            return None;
+        }
+        self.active = Some(active);
+        info!(
+            "🪟 shutter rule <b>{}</>: sun az: {:.1}° el: {:.1}°{}: {}",
+            self.name,
+            azimuth,
+            elevation,
+            match &self.sensor {
+                Some(sensor) => format!(
+                    ", {}: {:?}",
+                    sensor,
+                    values.get(sensor).map(|t| format!("{:.1}°C", t))
+                ),
+                None => "".to_string(),
+            },
+            if active { "closing" } else { "opening" },
+        );
+        Some(active)
+    }
+}
+
+fn range(value: Option<&String>, default: (f32, f32)) -> Option<(f32, f32)> {
+    match value {
+        Some(value) => {
+            let mut v = value.split('-').map(|x| x.trim().parse::<f32>());
+            match (v.next(), v.next(), v.next()) {
+                (Some(Ok(min)), Some(Ok(max)), None) if min <= max => Some((min, max)),
+                _ => None,
+            }
+        }
+        None => Some(default),
+    }
+}
+
+/// Reads the `[shutter:<name>]` sections, the ones with missing options are skipped
+pub fn shutters_from_config(config: &Config) -> Vec<Shutter> {
+    let mut shutters = vec![];
+    for (name, properties) in config.sections("shutter:") {
+        let get = |key: &str| properties.get(key).map(|v| v.trim().to_string());
+        let (up_relay, down_relay) = match (
+            get("up_relay").and_then(|r| r.parse::<i32>().ok()),
+            get("down_relay").and_then(|r| r.parse::<i32>().ok()),
+        ) {
+            (Some(up), Some(down)) if up != down => (up, down),
+            _ => {
+                warn!("shutter {}: missing or invalid up_relay/down_relay", name);
+                continue;
+            }
+        };
+        let travel = match get("travel_secs").map(|t| t.parse::<f32>()) {
+            Some(Ok(secs)) if secs > 0.0 => secs,
+            None => SHUTTER_DEFAULT_TRAVEL_SECS,
+            _ => {
+                warn!("shutter {}: invalid travel_secs", name);
+                continue;
+            }
+        };
+        debug!(
+            "Got shutter: {:?} up_relay={} down_relay={} travel={}s",
+            name, up_relay, down_relay, travel
+        );
+        shutters.push(Shutter {
+            name,
+            up_relay,
+            down_relay,
+            travel: Duration::from_secs_f32(travel),
+            open_at_sunrise: get("open_at_sunrise").map_or(false, |s| {
+                matches!(s.to_lowercase().as_str(), "true" | "yes" | "1")
+            }),
+            position: None,
+            motion: None,
+            pending: None,
+        });
+    }
+    shutters
+}
+
+/// Reads the `[shutter_rule:<name>]` sections, the ones with missing options are skipped
+pub fn rules_from_config(config: &Config, shutters: &[Shutter]) -> Vec<ShutterRule> {
+    let mut rules = vec![];
+    for (name, properties) in config.sections("shutter_rule:") {
+        let get = |key: &str| properties.get(key).map(|v| v.trim().to_string());
+        let names: Vec<String> = get("shutters").map_or(vec![], |s| {
+            s.split(',')
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty())
+                .collect()
+        });
+        if names.is_empty() || !names.iter().all(|n| shutters.iter().any(|s| &s.name == n)) {
+            warn!("shutter rule {}: missing or unknown shutters", name);
+            continue;
+        }
+        let (azimuth, elevation) = match (
+            range(properties.get("azimuth"), (0.0, 360.0)),
+            range(properties.get("elevation"), (0.0, 90.0)),
+        ) {
+            (Some(azimuth), Some(elevation)) => (azimuth, elevation),
+            _ => {
+                warn!(
+                    "shutter rule {}: invalid azimuth/elevation, expected MIN-MAX",
+                    name
+                );
+                continue;
+            }
+        };
+        let temp_above = match get("temp_above").map(|t| t.parse::<f32>()) {
+            Some(Ok(temp)) => Some(temp),
+            None => None,
+            Some(Err(_)) => {
+                warn!("shutter rule {}: invalid temp_above", name);
+                continue;
+            }
+        };
+        let position = match get("position").map(|p| p.parse::<u8>()) {
+            Some(Ok(position)) if position <= 100 => position,
+            None => 100,
+            _ => {
+                warn!("shutter rule {}: invalid position, expected 0-100", name);
+                continue;
+            }
+        };
+        rules.push(ShutterRule {
+            name,
+            shutters: names,
+            azimuth,
+            elevation,
+            sensor: get("sensor"),
+            temp_above,
+            position,
+            active: None,
+        });
+    }
+    rules
+}
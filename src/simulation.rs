@@ -0,0 +1,186 @@
+use crate::onewire::{W1File, DS2408_INITIAL_STATE};
+use simplelog::*;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::RwLock;
+
+pub const SIM_DS2413_IDLE_STATE: u8 = 0x5a; //both PIO inputs low
+
+/// Fake device files, either in memory or as plain files in `state_dir`
+struct SimState {
+    state_dir: Option<String>,
+    values: BTreeMap<String, u8>,
+}
+
+static STATE: RwLock<Option<SimState>> = RwLock::new(None);
+
+/// Replaces the 1-wire/GPIO boards with the fakes for the whole process, called once on startup
+pub fn init(state_dir: Option<String>) {
+    warn!(
+        "simulation: 🧪 <b><yellow>SIMULATION MODE</> - no hardware is used, device files: {}",
+        state_dir.as_deref().unwrap_or("in memory")
+    );
+    *STATE.write().unwrap() = Some(SimState {
+        state_dir,
+        values: BTreeMap::new(),
+    });
+}
+
+pub fn enabled() -> bool {
+    STATE.read().unwrap().is_some()
+}
+
+fn key(device: &str, file: &str) -> String {
+    format!("{}/{}", device, file)
+}
+
+/// Opens the fake `state` (sensor board) or `output` (relay board) file of the device
+pub fn open(device: &str, file: &str) -> Option<W1File> {
+    let state = STATE.read().unwrap();
+    let state_dir = state.as_ref()?.state_dir.clone();
+    let default = default_value(file);
+    match state_dir {
+        Some(dir) => {
+            let path = Path::new(&dir).join(device).join(file);
+            if !path.exists() {
+                let created = path
+                    .parent()
+                    .map_or(Ok(()), fs::create_dir_all)
+                    .and_then(|_| fs::write(&path, [default]));
+                if let Err(e) = created {
+                    error!("simulation: unable to create {}: {}", path.display(), e);
+                    return None;
+                }
+            }
+            info!("simulation: {}: using file {}", device, path.display());
+            match OpenOptions::new().read(true).write(true).open(&path) {
+                Ok(file) => Some(W1File::Sysfs(file)),
+                Err(e) => {
+                    error!("simulation: error opening {}: {}", path.display(), e);
+                    None
+                }
+            }
+        }
+        None => {
+            info!("simulation: {}: using in-memory {}", device, file);
+            Some(W1File::Sim(SimFile {
+                key: key(device, file),
+                default,
+            }))
+        }
+    }
+}
+
+fn default_value(file: &str) -> u8 {
+    match file {
+        "output" => DS2408_INITIAL_STATE,
+        _ => SIM_DS2413_IDLE_STATE,
+    }
+}
+
+fn read(device: &str, file: &str) -> Option<u8> {
+    let state = STATE.read().unwrap();
+    let state = state.as_ref()?;
+    match &state.state_dir {
+        Some(dir) => fs::read(Path::new(dir).join(device).join(file))
+            .ok()
+            .and_then(|data| data.first().copied()),
+        None => state.values.get(&key(device, file)).copied(),
+    }
+}
+
+fn write(device: &str, file: &str, value: u8) -> std::io::Result<()> {
+    let mut state = STATE.write().unwrap();
+    let state = match state.as_mut() {
+        Some(state) => state,
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "simulation is disabled",
+            ))
+        }
+    };
+    match &state.state_dir {
+        Some(dir) => {
+            let path = Path::new(dir).join(device);
+            fs::create_dir_all(&path)?;
+            fs::write(path.join(file), [value])
+        }
+        None => {
+            state.values.insert(key(device, file), value);
+            Ok(())
+        }
+    }
+}
+
+/// Sets the PIO input of a fake DS2413 board (bit 0: PIOA, bit 2: PIOB), the other
+/// input and the complemented upper nibble are kept consistent
+pub fn set_input(device: &str, bit: u8, high: bool) -> std::io::Result<u8> {
+    let current = read(device, "state").unwrap_or(SIM_DS2413_IDLE_STATE);
+    //latches are off, only the pin bits are changing
+    let mut low = (current & 0x0f) | 0b1010;
+    if high {
+        low |= 1 << bit;
+    } else {
+        low &= !(1 << bit);
+    }
+    let value = (!low << 4) | low;
+    write(device, "state", value)?;
+    info!(
+        "simulation: {}: PIO{} {}: {:#04x}",
+        device,
+        if bit == 0 { "A" } else { "B" },
+        if high { "high" } else { "low" },
+        value
+    );
+    Ok(value)
+}
+
+/// Current values of the in-memory device files
+pub fn values() -> BTreeMap<String, u8> {
+    match &*STATE.read().unwrap() {
+        Some(state) => state.values.clone(),
+        None => BTreeMap::new(),
+    }
+}
+
+/// In-memory device file, a single byte like the w1 `state`/`output` files
+pub struct SimFile {
+    key: String,
+    default: u8,
+}
+
+impl Read for SimFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let state = STATE.read().unwrap();
+        buf[0] = state
+            .as_ref()
+            .and_then(|s| s.values.get(&self.key).copied())
+            .unwrap_or(self.default);
+        Ok(1)
+    }
+}
+
+impl Write for SimFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let (Some(value), Some(state)) = (buf.first(), STATE.write().unwrap().as_mut()) {
+            state.values.insert(self.key.clone(), *value);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SimFile {
+    fn seek(&mut self, _pos: SeekFrom) -> std::io::Result<u64> {
+        Ok(0)
+    }
+}
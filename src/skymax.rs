@@ -1,27 +1,30 @@
+use crate::device_io::{DeviceAddress, DeviceIo, ReplyFrame};
+use crate::events::{self, Event, EventSender};
+use crate::influx::{self, Client, InfluxDbWriteable, WriteQuery};
 use crate::lcdproc::{LcdTask, LcdTaskCommand};
+use crate::mqtt::MqttEvent;
+use crate::notify::{Notification, Severity};
 use crate::onewire::StateMachine;
+use crate::queue::Sender;
+use crate::systemd;
+use crate::units;
+use crate::worker_error::{ErrorReporter, WorkerError};
 use chrono::{DateTime, Utc};
 use crc16::*;
 use humantime::format_duration;
-use influxdb::{Client, InfluxDbWriteable};
 use simplelog::*;
 use std::fmt;
-use std::fs;
-use std::io;
-use std::io::{Error, ErrorKind};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::Sender;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
-use tokio::fs::File;
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::time::timeout;
-use tokio_compat_02::FutureExt;
+use thiserror::Error;
 
 pub const SKYMAX_POLL_INTERVAL_SECS: f32 = 10.0; //secs between polling
 pub const SKYMAX_STATS_DUMP_INTERVAL_SECS: f32 = 3600.0; //secs between showing stats
+pub const SKYMAX_MIN_POLL_INTERVAL_SECS: f32 = 2.0; //a single query takes up to a second
+pub const SKYMAX_FRAME_END: u8 = 0x0d; //the crc bytes are adjusted to never contain it
+pub const SKYMAX_MAX_REPLY_SIZE: usize = 256; //the longest reply (QPIGS) is about 110 bytes
 
 //masks for status bits
 pub const STATUS1_AC_CHARGE: u8 = 1 << 0;
@@ -34,7 +37,39 @@ pub const STATUS2_SWITCH: u8 = 1 << 1;
 // async contexts needs some extra restrictions
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
-#[derive(Clone, InfluxDbWriteable)]
+#[derive(Debug, Error)]
+pub enum SkymaxError {
+    #[error("no reply to {0}")]
+    NoReply(String),
+    #[error("invalid reply to {command}: {reason}")]
+    InvalidReply { command: String, reason: String },
+    #[error("{command}: error parsing {what}: {data:?}")]
+    Parse {
+        command: &'static str,
+        what: &'static str,
+        data: String,
+    },
+    #[error("influxdb write error: {0}")]
+    Influx(influx::Error),
+}
+
+impl WorkerError for SkymaxError {
+    fn kind(&self) -> &'static str {
+        match self {
+            SkymaxError::NoReply(_) => "no_reply",
+            SkymaxError::InvalidReply { .. } => "invalid_reply",
+            SkymaxError::Parse { .. } => "invalid_data",
+            SkymaxError::Influx(_) => "influxdb",
+        }
+    }
+
+    //the device is reopened on a missing reply, nothing is fatal here
+    fn is_recoverable(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone)]
 pub struct GeneralStatusParameters {
     time: DateTime<Utc>,
     voltage_grid: Option<f32>,
@@ -60,6 +95,30 @@ pub struct GeneralStatusParameters {
     device_status2: Option<u8>,
 }
 
+crate::influx_writeable!(GeneralStatusParameters {} optional {
+    voltage_grid,
+    freq_grid,
+    voltage_out,
+    freq_out,
+    load_va,
+    load_watt,
+    load_percent,
+    voltage_bus,
+    voltage_batt,
+    batt_charge_current,
+    batt_capacity,
+    temp_heatsink,
+    pv_input_current,
+    pv_input_voltage,
+    scc_voltage,
+    batt_discharge_current,
+    device_status,
+    batt_voltage_offset_for_fans_on,
+    eeprom_version,
+    pv_charging_power,
+    device_status2,
+});
+
 impl GeneralStatusParameters {
     fn binary_to_u8(input: String) -> Option<u8> {
         let mut out_byte: u8 = 0;
@@ -113,22 +172,18 @@ impl GeneralStatusParameters {
         })
     }
 
-    async fn save_to_influxdb(&self, influxdb_url: &String, thread_name: &String) -> Result<()> {
+    async fn save_to_influxdb(
+        &self,
+        influxdb_url: &String,
+        thread_name: &String,
+        identity: &SkymaxIdentity,
+    ) -> std::result::Result<(), SkymaxError> {
         // connect to influxdb
         let client = Client::new(influxdb_url, "skymax");
 
-        match client
-            .query(&self.clone().into_query("status_params"))
-            .await
-        {
-            Ok(msg) => {
-                debug!("{}: influxdb write success: {:?}", thread_name, msg);
-            }
-            Err(e) => {
-                error!("{}: influxdb write error: {:?}", thread_name, e);
-            }
-        }
-
+        let query = identity.add_tags(self.clone().into_query("status_params"));
+        let msg = client.query(&query).await.map_err(SkymaxError::Influx)?;
+        debug!("{}: influxdb write success: {:?}", thread_name, msg);
         Ok(())
     }
 }
@@ -185,8 +240,8 @@ impl fmt::Display for GeneralStatusParameters {
         if self.temp_heatsink.is_some() {
             write!(
                 f,
-                "  Heatsink temperature: {:?} °C\n",
-                self.temp_heatsink.unwrap()
+                "  Heatsink temperature: {}\n",
+                units::temperature(self.temp_heatsink.unwrap() as f32)
             )?;
         };
         if self.batt_capacity.is_some() {
@@ -269,6 +324,256 @@ impl fmt::Display for GeneralStatusParameters {
     }
 }
 
+/// Settings commands of the inverter, acknowledged with ACK/NAK
+#[derive(Clone, Debug, PartialEq)]
+pub enum SkymaxSetting {
+    /// POP: 0 - utility first, 1 - solar first, 2 - SBU (solar, battery, utility)
+    OutputPriority(u8),
+    /// PCP: 0 - utility first, 1 - solar first, 2 - solar and utility, 3 - only solar
+    ChargerPriority(u8),
+    /// PBCV: battery voltage for switching back to the utility
+    BatteryRechargeVoltage(f32),
+    /// PBDV: battery voltage for switching back to the battery
+    BatteryRedischargeVoltage(f32),
+    /// PSDV: battery cut-off voltage
+    BatteryCutoffVoltage(f32),
+}
+
+impl SkymaxSetting {
+    /// Parses a setting from its name and value, eg. `output_priority` and `solar`
+    pub fn parse(name: &str, value: &str) -> Option<Self> {
+        let value = value.trim();
+        let voltage = || {
+            value
+                .parse::<f32>()
+                .ok()
+                .filter(|v| (40.0..=60.0).contains(v))
+        };
+        match name {
+            "output_priority" => match value {
+                "utility" => Some(SkymaxSetting::OutputPriority(0)),
+                "solar" => Some(SkymaxSetting::OutputPriority(1)),
+                "sbu" => Some(SkymaxSetting::OutputPriority(2)),
+                _ => None,
+            },
+            "charger_priority" => match value {
+                "utility" => Some(SkymaxSetting::ChargerPriority(0)),
+                "solar" => Some(SkymaxSetting::ChargerPriority(1)),
+                "solar_utility" => Some(SkymaxSetting::ChargerPriority(2)),
+                "solar_only" => Some(SkymaxSetting::ChargerPriority(3)),
+                _ => None,
+            },
+            "battery_recharge_voltage" => voltage().map(SkymaxSetting::BatteryRechargeVoltage),
+            "battery_redischarge_voltage" => {
+                voltage().map(SkymaxSetting::BatteryRedischargeVoltage)
+            }
+            "battery_cutoff_voltage" => voltage().map(SkymaxSetting::BatteryCutoffVoltage),
+            _ => None,
+        }
+    }
+
+    fn command(&self) -> String {
+        match self {
+            SkymaxSetting::OutputPriority(x) => format!("POP{:02}", x),
+            SkymaxSetting::ChargerPriority(x) => format!("PCP{:02}", x),
+            SkymaxSetting::BatteryRechargeVoltage(v) => format!("PBCV{:.1}", v),
+            SkymaxSetting::BatteryRedischargeVoltage(v) => format!("PBDV{:.1}", v),
+            SkymaxSetting::BatteryCutoffVoltage(v) => format!("PSDV{:.1}", v),
+        }
+    }
+}
+
+impl fmt::Display for SkymaxSetting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SkymaxSetting::OutputPriority(x) => write!(
+                f,
+                "output source priority: {}",
+                ["utility first", "solar first", "SBU"][*x as usize]
+            ),
+            SkymaxSetting::ChargerPriority(x) => write!(
+                f,
+                "charger priority: {}",
+                [
+                    "utility first",
+                    "solar first",
+                    "solar and utility",
+                    "only solar"
+                ][*x as usize]
+            ),
+            SkymaxSetting::BatteryRechargeVoltage(v) => {
+                write!(f, "battery recharge voltage: {} V", v)
+            }
+            SkymaxSetting::BatteryRedischargeVoltage(v) => {
+                write!(f, "battery re-discharge voltage: {} V", v)
+            }
+            SkymaxSetting::BatteryCutoffVoltage(v) => write!(f, "battery cut-off voltage: {} V", v),
+        }
+    }
+}
+
+/// Inverter identity and rated information, queried on (re)connection
+#[derive(Clone, Default)]
+pub struct SkymaxIdentity {
+    pub serial_number: Option<String>,
+    pub firmware: Option<String>,
+    pub rated_output_power: Option<u32>,
+    pub rated_battery_voltage: Option<f32>,
+}
+
+impl SkymaxIdentity {
+    /// Parses the rated information (QPIRI), fields are separated by spaces
+    fn set_rated_info(&mut self, data: &str) -> bool {
+        let elements: Vec<_> = data.split(' ').collect();
+        if elements.len() < 8 {
+            return false;
+        }
+        self.rated_output_power = elements[6].parse().ok();
+        self.rated_battery_voltage = elements[7].parse().ok();
+        true
+    }
+
+    /// Firmware version from the QVFW reply, eg. `VERFW:00072.70`
+    fn set_firmware(&mut self, data: &str) {
+        self.firmware = Some(data.trim_start_matches("VERFW:").trim().to_string());
+    }
+
+    /// Makes the measurements of many inverters distinguishable
+    fn add_tags(&self, query: WriteQuery) -> WriteQuery {
+        let mut query = query;
+        if let Some(serial_number) = &self.serial_number {
+            query = query.add_tag("serial_number", serial_number.as_str());
+        }
+        if let Some(firmware) = &self.firmware {
+            query = query.add_tag("firmware", firmware.as_str());
+        }
+        if let Some(power) = self.rated_output_power {
+            query = query.add_tag("rated_power", power);
+        }
+        if let Some(voltage) = self.rated_battery_voltage {
+            query = query.add_tag("rated_battery_voltage", voltage);
+        }
+        query
+    }
+}
+
+//QPIWS warning bits: (index, description, fault)
+#[rustfmt::skip]
+const WARNING_BITS: [(usize, &str, bool); 27] = [
+    (1, "inverter fault", true),
+    (2, "bus over", true),
+    (3, "bus under", true),
+    (4, "bus soft fail", true),
+    (5, "line fail", false),
+    (6, "OPV short", false),
+    (7, "inverter voltage too low", true),
+    (8, "inverter voltage too high", true),
+    (9, "over temperature", false),
+    (10, "fan locked", false),
+    (11, "battery voltage high", false),
+    (12, "battery low", false),
+    (14, "battery under shutdown", false),
+    (16, "overload", false),
+    (17, "EEPROM fault", false),
+    (18, "inverter over current", true),
+    (19, "inverter soft fail", true),
+    (20, "self test fail", true),
+    (21, "OP DC voltage over", true),
+    (22, "battery open", true),
+    (23, "current sensor fail", true),
+    (24, "battery short", true),
+    (25, "power limit", false),
+    (26, "PV voltage high", false),
+    (27, "MPPT overload fault", false),
+    (28, "MPPT overload warning", false),
+    (29, "battery too low to charge", false),
+];
+//warnings which become faults when the inverter fault bit is set
+const WARNING_FAULT_WITH_INVERTER_FAULT: [usize; 4] = [9, 10, 11, 16];
+
+/// Device warning status (QPIWS), bit N is the N-th character of the reply
+#[derive(Clone)]
+pub struct WarningStatus {
+    time: DateTime<Utc>,
+    warning_bits: u32,
+    fault: bool,
+}
+
+crate::influx_writeable!(WarningStatus {
+    warning_bits,
+    fault,
+});
+
+impl WarningStatus {
+    pub fn new(data: &str) -> Option<Self> {
+        let mut warning_bits: u32 = 0;
+        for (index, bit) in data.trim().chars().take(32).enumerate() {
+            match bit {
+                '1' => warning_bits |= 1 << index,
+                '0' => (),
+                _ => return None,
+            }
+        }
+        let mut status = WarningStatus {
+            time: Utc::now(),
+            warning_bits,
+            fault: false,
+        };
+        status.fault = WARNING_BITS
+            .iter()
+            .any(|(index, _, _)| status.is_set(*index) && status.is_fault(*index));
+        Some(status)
+    }
+
+    fn is_set(&self, index: usize) -> bool {
+        self.warning_bits & (1 << index) != 0
+    }
+
+    fn is_fault(&self, index: usize) -> bool {
+        WARNING_BITS
+            .iter()
+            .any(|(i, _, fault)| *i == index && *fault)
+            || (self.is_set(1) && WARNING_FAULT_WITH_INVERTER_FAULT.contains(&index))
+    }
+
+    /// Logs the warnings which appeared or cleared since the previous status,
+    /// returns the descriptions of the new faults
+    fn log_changes(
+        &self,
+        previous: Option<&WarningStatus>,
+        thread_name: &str,
+    ) -> Vec<&'static str> {
+        let mut new_faults = vec![];
+        for (index, description, _) in WARNING_BITS.iter() {
+            let was_set = previous.map_or(false, |p| p.is_set(*index));
+            if self.is_set(*index) && !was_set {
+                if self.is_fault(*index) {
+                    error!("{}: ⚠️ fault: <b><red>{}</>", thread_name, description);
+                    new_faults.push(*description);
+                } else {
+                    warn!("{}: ⚠️ warning: <b><yellow>{}</>", thread_name, description);
+                }
+            } else if !self.is_set(*index) && was_set {
+                info!("{}: ✅ cleared: <b>{}</>", thread_name, description);
+            }
+        }
+        new_faults
+    }
+
+    async fn save_to_influxdb(
+        &self,
+        influxdb_url: &String,
+        thread_name: &String,
+        identity: &SkymaxIdentity,
+    ) -> std::result::Result<(), SkymaxError> {
+        let client = Client::new(influxdb_url, "skymax");
+        let query = identity.add_tags(self.clone().into_query("warnings"));
+        let msg = client.query(&query).await.map_err(SkymaxError::Influx)?;
+        debug!("{}: influxdb write success: {:?}", thread_name, msg);
+        Ok(())
+    }
+}
+
 pub struct InverterMode {
     pub last_change: Instant,
     pub mode: char,
@@ -329,17 +634,30 @@ pub struct Skymax {
     pub name: String,
     pub device_path: String,
     pub device_usbid: String,
+    /// directory of the hidraw nodes, `/dev` outside of the tests
+    pub device_dir: String,
+    /// lcd line for the connection status, not shown when unset
+    pub status_lcd_line: Option<u8>,
     pub poll_ok: u64,
     pub poll_errors: u64,
     pub influxdb_url: Option<String>,
     pub lcd_transmitter: Sender<LcdTask>,
+    pub mqtt_transmitter: Sender<MqttEvent>,
+    pub notify_transmitter: Sender<Notification>,
+    pub events: EventSender,
     pub mode_change_script: Option<String>,
+    pub fault_script: Option<String>,
+    pub settings: Vec<SkymaxSetting>,
+    pub setting_requests: Arc<Mutex<Vec<SkymaxSetting>>>,
+    pub poll_interval: Duration,
+    pub stats_interval: Duration,
+    pub errors: ErrorReporter,
 }
 
 impl Skymax {
-    fn fix_crc16_byte(input: u8) -> u8 {
+    pub fn fix_crc16_byte(input: u8) -> u8 {
         /* function for adjusting CRC values to not cover "special" bytes */
-        if input == 0x28 || input == 0x0d || input == 0x0a {
+        if input == 0x28 || input == SKYMAX_FRAME_END || input == 0x0a {
             input + 1
         } else {
             input
@@ -350,7 +668,7 @@ impl Skymax {
         debug!("input data={:02X?}", data);
 
         //check for start/stop sequence
-        if data.pop().unwrap() != 0x0d {
+        if data.pop() != Some(SKYMAX_FRAME_END) {
             return Err("received data is not properly terminated".to_string());
         }
         if data.get(0).unwrap() != &('(' as u8) {
@@ -385,13 +703,10 @@ impl Skymax {
 
     pub async fn query_inverter(
         &mut self,
-        mut device: File,
+        device: &mut DeviceIo,
         command: String,
-        reply_size: usize,
-    ) -> Result<(Option<String>, File)> {
-        let mut buffer = vec![0u8; reply_size];
+    ) -> std::result::Result<String, SkymaxError> {
         let mut output_cmd: Vec<u8> = vec![];
-        let mut out: Option<String> = None;
 
         //add main command string
         output_cmd.append(&mut command.clone().into_bytes());
@@ -401,402 +716,577 @@ impl Skymax {
         output_cmd.push(Skymax::fix_crc16_byte((crc >> 8) as u8));
         output_cmd.push(Skymax::fix_crc16_byte((crc & 0xff) as u8));
         //terminate command
-        output_cmd.push(0x0d);
+        output_cmd.push(SKYMAX_FRAME_END);
 
         debug!(
             "{}: sending cmd={} crc=0x{:04X} data={:02X?}",
             self.name, command, crc, output_cmd
         );
-        if let Err(e) = device.write_all(&output_cmd).await {
-            error!("{}: write error: {:?}", self.name, e);
-            return Ok((out, device));
-        }
         let now = Instant::now();
+        let buffer = device
+            .transact(
+                &output_cmd,
+                ReplyFrame::Terminated {
+                    end: SKYMAX_FRAME_END,
+                    max_size: SKYMAX_MAX_REPLY_SIZE,
+                },
+                Duration::from_secs(5),
+            )
+            .await
+            .ok_or_else(|| SkymaxError::NoReply(command.clone()))?;
+        let elapsed = now.elapsed();
+        let reply_size = buffer.len();
 
-        let retval = device.read_exact(&mut buffer);
-        match timeout(Duration::from_secs(5), retval).await {
-            Ok(res) => {
-                match res {
-                    Ok(n) => {
-                        let elapsed = now.elapsed();
-                        if n != reply_size {
-                            error!("{}: received data is not complete: read {} bytes, expected {} bytes", self.name, n, reply_size);
-                        } else {
-                            match Skymax::verify_input_data(buffer) {
-                                Ok(data) => {
-                                    self.poll_ok = self.poll_ok + 1;
-                                    debug!(
-                                        "{}: read {} bytes [⏱️ {} ms]: {:?}, ok: {}, errors: {}",
-                                        self.name,
-                                        n,
-                                        (elapsed.as_secs() * 1_000)
-                                            + (elapsed.subsec_nanos() / 1_000_000) as u64,
-                                        &data,
-                                        self.poll_ok,
-                                        self.poll_errors
-                                    );
-                                    out = Some(data);
-                                }
-                                Err(e) => {
-                                    self.poll_errors = self.poll_errors + 1;
-                                    error!("{}: data verify failed: {}", self.name, e);
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        error!("{}: file read error: {}", self.name, e);
-                    }
-                }
+        match Skymax::verify_input_data(buffer) {
+            Ok(data) => {
+                device.valid_reply();
+                self.poll_ok = self.poll_ok + 1;
+                debug!(
+                    "{}: read {} bytes [⏱️ {} ms]: {:?}, ok: {}, errors: {}",
+                    self.name,
+                    reply_size,
+                    (elapsed.as_secs() * 1_000) + (elapsed.subsec_nanos() / 1_000_000) as u64,
+                    &data,
+                    self.poll_ok,
+                    self.poll_errors
+                );
+                Ok(data)
             }
-            Err(e) => {
-                error!("{}: response timeout: {}", self.name, e);
+            Err(reason) => {
+                device.invalid_reply();
+                self.poll_errors = self.poll_errors + 1;
+                Err(SkymaxError::InvalidReply { command, reason })
             }
         }
-
-        Ok((out, device))
     }
 
-    pub fn get_first_dir(dir: String) -> io::Result<String> {
-        //obtaining the first directory name from specified path
-        let name = fs::read_dir(&dir)?
-            .map(|res| res.map(|e| e.file_name()))
-            .collect::<std::result::Result<Vec<_>, io::Error>>()?
-            .get(0)
-            .ok_or(Error::new(ErrorKind::Other, "Empty dir"))?
-            .to_string_lossy()
-            .to_string();
-
-        Ok(name)
+    /// Reports a failed query or write, the value is returned on success
+    fn reported<T>(&self, result: std::result::Result<T, SkymaxError>) -> Option<T> {
+        result.map_err(|e| self.errors.report(&e)).ok()
     }
 
-    pub fn get_first_dir_with_mask(dir: String, mask: String) -> io::Result<String> {
-        let name = fs::read_dir(&dir)?
-            .map(|res| res.map(|e| e.file_name()))
-            .filter(|d| d.as_ref().unwrap().to_string_lossy().contains(&mask))
-            .collect::<std::result::Result<Vec<_>, io::Error>>()?
-            .get(0)
-            .ok_or(Error::new(ErrorKind::Other, "Empty dir"))?
-            .to_string_lossy()
-            .to_string();
-
-        Ok(name)
+    /// Queries the identity and rated information, logged once per connection
+    async fn query_identity(&mut self, device: &mut DeviceIo) -> SkymaxIdentity {
+        let mut identity = SkymaxIdentity::default();
+        let reply = self.query_inverter(device, "QID".into()).await;
+        if let Some(data) = self.reported(reply) {
+            info!("{}: serial number: <b><cyan>{}</>", self.name, data);
+            identity.serial_number = Some(data);
+        }
+        let reply = self.query_inverter(device, "QVFW".into()).await;
+        if let Some(data) = self.reported(reply) {
+            identity.set_firmware(&data);
+            info!(
+                "{}: firmware version: <b><cyan>{}</>",
+                self.name,
+                identity.firmware.clone().unwrap_or_default()
+            );
+        }
+        let reply = self.query_inverter(device, "QPIRI".into()).await;
+        if let Some(data) = self.reported(reply) {
+            if identity.set_rated_info(&data) {
+                info!(
+                    "{}: rated output power: <b><cyan>{} W</>, rated battery voltage: <b><cyan>{} V</>",
+                    self.name,
+                    identity.rated_output_power.unwrap_or_default(),
+                    identity.rated_battery_voltage.unwrap_or_default()
+                );
+            } else {
+                self.errors.report(&SkymaxError::Parse {
+                    command: "QPIRI",
+                    what: "rated information",
+                    data,
+                });
+            }
+        }
+        identity
     }
 
-    fn get_device_path(&self) -> Result<String> {
-        //first get the device directory with its USB ID in it
-        let device_dir =
-            Skymax::get_first_dir_with_mask(self.device_path.clone(), self.device_usbid.clone())?;
-
-        //now get the hidraw device name, like 'hidraw0'
-        let hidraw_name =
-            Skymax::get_first_dir(format!("{}/{}/hidraw", &self.device_path, device_dir))?;
-
-        //create the full /dev/ path with obtained filename
-        let mut full_path = "/dev/".to_string();
-        full_path.push_str(&hidraw_name);
-
-        Ok(full_path)
+    /// Sends a settings command, returns true when the inverter acknowledged it
+    pub async fn apply_setting(&mut self, device: &mut DeviceIo, setting: &SkymaxSetting) -> bool {
+        let reply = self.query_inverter(device, setting.command()).await;
+        let reply = self.reported(reply);
+        match reply.as_deref() {
+            Some("ACK") => {
+                info!("{}: ⚙️ {} set", self.name, setting);
+                true
+            }
+            Some("NAK") => {
+                error!(
+                    "{}: ⚙️ inverter refused {} (<b>NAK</> for {})",
+                    self.name,
+                    setting,
+                    setting.command()
+                );
+                false
+            }
+            other => {
+                error!(
+                    "{}: ⚙️ unexpected reply for {}: {:?}",
+                    self.name,
+                    setting.command(),
+                    other
+                );
+                false
+            }
+        }
     }
 
     pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
         info!("{}: Starting task", self.name);
         let mut poll_interval = Instant::now();
         let mut stats_interval = Instant::now();
-        let mut terminated = false;
         let mut inverter_mode: Option<InverterMode> = None;
+        let mut settings_applied = false;
+        let mut warning_status: Option<WarningStatus> = None;
+        let mut identity: Option<SkymaxIdentity> = None;
+
+        let lcd_transmitter = self.lcd_transmitter.clone();
+        let status_lcd_line = self.status_lcd_line;
+        let mut device = DeviceIo::new(
+            self.name.clone(),
+            DeviceAddress::UsbHid {
+                sysfs_path: self.device_path.clone(),
+                usb_id: self.device_usbid.clone(),
+                dev_dir: self.device_dir.clone(),
+            },
+        )
+        .on_reconnect(move |_| {
+            //line 0 is shared with the other workers, the status has its own line
+            if let Some(line) = status_lcd_line {
+                let task = LcdTask {
+                    command: LcdTaskCommand::SetLineText,
+                    int_arg: line,
+                    string_arg: Some("inverter reconnected".into()),
+                };
+                let _ = lcd_transmitter.send(task);
+            }
+        });
+        let mut reconnects = device.reconnects;
 
         loop {
-            if terminated || worker_cancel_flag.load(Ordering::SeqCst) {
+            if worker_cancel_flag.load(Ordering::SeqCst) {
+                debug!("{}: Got terminate signal from main", self.name);
                 break;
             }
+            systemd::heartbeat(&self.name);
 
-            //obtain device path from sysfs
-            let mut disconnected = false;
-            let device_path = match self.get_device_path() {
-                Ok(path) => path,
-                Err(e) => {
-                    error!("{}: unable to obtain device path: {:?}", self.name, e);
-                    disconnected = true;
-                    "".into()
-                }
-            };
-            if disconnected {
-                tokio::time::sleep(Duration::from_secs(10)).await;
+            if stats_interval.elapsed() > self.stats_interval {
+                stats_interval = Instant::now();
+                info!(
+                    "{}: 📊 inverter query statistics: ok: {}, errors: {}, reconnects: {}",
+                    self.name, self.poll_ok, self.poll_errors, device.reconnects
+                );
+            }
+
+            //(re)open the device, the USB path is looked up again on each attempt
+            if !device.open().await {
+                tokio::time::sleep(Duration::from_millis(100)).await;
                 continue;
             }
 
-            info!(
-                "{}: opening device: {:?}, obtained from physical path: {:?}",
-                self.name, device_path, self.device_path
-            );
-            let mut options = OpenOptions::new();
-            let future = options.read(true).write(true).open(&device_path);
-            match timeout(Duration::from_secs(5), future).await {
-                Ok(res) => {
-                    match res {
-                        Ok(mut file) => {
-                            info!(
-                                "{}: device opened, poll interval: {}s",
-                                self.name, SKYMAX_POLL_INTERVAL_SECS
-                            );
-                            loop {
-                                if worker_cancel_flag.load(Ordering::SeqCst) {
-                                    debug!("{}: Got terminate signal from main", self.name);
-                                    terminated = true;
-                                }
+            //identity is queried again after a reconnection (the device may be replaced)
+            if identity.is_none() || device.reconnects != reconnects {
+                reconnects = device.reconnects;
+                identity = Some(self.query_identity(&mut device).await);
+            }
+            let identity = identity.clone().unwrap_or_default();
 
-                                if terminated
-                                    || stats_interval.elapsed()
-                                        > Duration::from_secs_f32(SKYMAX_STATS_DUMP_INTERVAL_SECS)
-                                {
-                                    stats_interval = Instant::now();
-                                    info!(
-                                        "{}: 📊 inverter query statistics: ok: {}, errors: {}",
-                                        self.name, self.poll_ok, self.poll_errors
-                                    );
+            //desired settings from the config are enforced on startup
+            if !settings_applied {
+                settings_applied = true;
+                for setting in self.settings.clone() {
+                    self.apply_setting(&mut device, &setting).await;
+                }
+            }
 
-                                    if terminated {
-                                        break;
+            //settings requested remotely
+            let requests: Vec<SkymaxSetting> =
+                self.setting_requests.lock().unwrap().drain(..).collect();
+            for setting in requests {
+                self.apply_setting(&mut device, &setting).await;
+            }
+
+            if poll_interval.elapsed() > self.poll_interval {
+                poll_interval = Instant::now();
+
+                //get general status parameters
+                let buffer = self.query_inverter(&mut device, "QPIGS".into()).await;
+                match self.reported(buffer) {
+                    Some(data) => {
+                        let params = GeneralStatusParameters::new(data.clone());
+                        match params {
+                            Some(parameters) => {
+                                debug!("{}: {}", self.name, parameters);
+
+                                //write data to influxdb if configured
+                                match &self.influxdb_url {
+                                    Some(url) => {
+                                        let result = parameters
+                                            .save_to_influxdb(url, &self.name, &identity)
+                                            .await;
+                                        self.reported(result);
                                     }
+                                    None => (),
                                 }
 
-                                if poll_interval.elapsed()
-                                    > Duration::from_secs_f32(SKYMAX_POLL_INTERVAL_SECS)
-                                {
-                                    poll_interval = Instant::now();
-
-                                    //get general status parameters
-                                    let (buffer, new_handle) =
-                                        self.query_inverter(file, "QPIGS".into(), 110).await?;
-                                    file = new_handle;
-                                    match buffer {
-                                        Some(data) => {
-                                            let params = GeneralStatusParameters::new(data.clone());
-                                            match params {
-                                                Some(parameters) => {
-                                                    debug!("{}: {}", self.name, parameters);
-
-                                                    //write data to influxdb if configured
-                                                    match &self.influxdb_url {
-                                                        Some(url) => {
-                                                            // By calling compat on the async function, everything inside it is able
-                                                            // to use Tokio 0.2 features.
-                                                            let _ = parameters
-                                                                .save_to_influxdb(url, &self.name)
-                                                                .compat()
-                                                                .await;
-                                                        }
-                                                        None => (),
-                                                    }
-
-                                                    //update lcd with new inverter data
-                                                    //line 1: mode + ac voltage
-                                                    let task = LcdTask {
-                                                        command: LcdTaskCommand::SetLineText,
-                                                        int_arg: 1,
-                                                        string_arg: Some(format!(
-                                                            "{}: {}V",
-                                                            match &inverter_mode {
-                                                                Some(inv_mode) => {
-                                                                    InverterMode::get_mode_description_lcd(
-                                                                        inv_mode.mode,
-                                                                    )
-                                                                }
-                                                                None => {
-                                                                    "Unknown Mode".into()
-                                                                }
-                                                            },
-                                                            parameters
-                                                                .voltage_grid
-                                                                .unwrap_or_default()
-                                                        )),
-                                                    };
-                                                    let _ = self.lcd_transmitter.send(task);
-
-                                                    //line 2: load info
-                                                    let task = LcdTask {
-                                                        command: LcdTaskCommand::SetLineText,
-                                                        int_arg: 2,
-                                                        string_arg: Some(format!(
-                                                            "Load: {}%, {}W",
-                                                            parameters
-                                                                .load_percent
-                                                                .unwrap_or_default(),
-                                                            parameters
-                                                                .load_watt
-                                                                .unwrap_or_default()
-                                                        )),
-                                                    };
-                                                    let _ = self.lcd_transmitter.send(task);
-
-                                                    /*
-                                                    //line 2: battery info
-                                                    let task = LcdTask {
-                                                        command: LcdTaskCommand::SetLineText,
-                                                        int_arg: 2,
-                                                        string_arg: Some(format!(
-                                                            "Batt: {}%, {}V",
-                                                            parameters
-                                                                .batt_capacity
-                                                                .unwrap_or_default(),
-                                                            parameters
-                                                                .voltage_batt
-                                                                .unwrap_or_default()
-                                                        )),
-                                                    };
-                                                    let _ = self.lcd_transmitter.send(task);
-                                                    */
-                                                }
-                                                _ => {
-                                                    error!(
-                                                        "{}: QPIGS: error parsing values for data: {:02X?}",
-                                                        self.name, data
-                                                    );
-                                                }
-                                            }
-                                        }
-                                        None => {
-                                            break;
-                                        }
-                                    }
+                                //publish all known values to mqtt
+                                let query = parameters.clone().into_query("status_params");
+                                for (field, value) in query.fields() {
+                                    let _ = self.mqtt_transmitter.send(MqttEvent::new(
+                                        format!("skymax/{}", field),
+                                        value,
+                                        false,
+                                    ));
+                                }
 
-                                    //get mode
-                                    let (buffer, new_handle) =
-                                        self.query_inverter(file, "QMOD".into(), 5).await?;
-                                    file = new_handle;
-                                    match buffer {
-                                        Some(data) => match data.chars().nth(0) {
-                                            Some(current_mode) => {
-                                                inverter_mode = Some(match inverter_mode {
-                                                    Some(mut inv_mode) => {
-                                                        if inv_mode
-                                                            .set_new_mode(current_mode, &self.name)
-                                                        {
-                                                            //run a shell script when mode has changed
-                                                            match &self.mode_change_script {
-                                                                Some(command) => {
-                                                                    let mut cmd =
-                                                                        command.to_string().clone();
-                                                                    cmd = str::replace(
-                                                                        &cmd,
-                                                                        "%mode%",
-                                                                        InverterMode::get_mode_description(
-                                                                            current_mode,
-                                                                        ),
-                                                                    );
-                                                                    thread::spawn(move || {
-                                                                        StateMachine::run_shell_command(cmd)
-                                                                    });
-                                                                }
-                                                                _ => (),
-                                                            };
-
-                                                            //update lcd with new inverter data
-                                                            let task = LcdTask {
-                                                                command: LcdTaskCommand::SetLineText,
-                                                                int_arg: 0,
-                                                                string_arg: Some(format!(
-                                                                    "new mode: {}",
-                                                                    InverterMode::get_mode_description_lcd(
-                                                                        current_mode
-                                                                    )
-                                                                )),
-                                                            };
-                                                            let _ = self.lcd_transmitter.send(task);
-
-                                                            //if we are on battery, set emergency mode
-                                                            let task = LcdTask {
-                                                                command:
-                                                                    LcdTaskCommand::SetEmergencyMode,
-                                                                int_arg: {
-                                                                    if current_mode == 'B' {
-                                                                        1
-                                                                    } else {
-                                                                        0
-                                                                    }
-                                                                },
-                                                                string_arg: None,
-                                                            };
-                                                            let _ = self.lcd_transmitter.send(task);
-                                                        }
-                                                        inv_mode
-                                                    }
-                                                    None => {
-                                                        info!(
-                                                            "{}: inverter mode: {}",
-                                                            self.name,
-                                                            InverterMode::get_mode_description(
-                                                                current_mode
-                                                            )
-                                                        );
-
-                                                        //update lcd with new inverter data
-                                                        let task = LcdTask {
-                                                            command: LcdTaskCommand::SetLineText,
-                                                            int_arg: 0,
-                                                            string_arg: Some(format!(
-                                                                "new mode: {}",
-                                                                InverterMode::get_mode_description_lcd(
-                                                                    current_mode
-                                                                )
-                                                            )),
-                                                        };
-                                                        let _ = self.lcd_transmitter.send(task);
-
-                                                        //enable/disable emergency mode
-                                                        let task = LcdTask {
-                                                            command:
-                                                                LcdTaskCommand::SetEmergencyMode,
-                                                            int_arg: {
-                                                                if current_mode == 'B' {
-                                                                    1
-                                                                } else {
-                                                                    0
-                                                                }
-                                                            },
-                                                            string_arg: None,
-                                                        };
-                                                        let _ = self.lcd_transmitter.send(task);
-
-                                                        InverterMode {
-                                                            last_change: Instant::now(),
-                                                            mode: current_mode,
-                                                        }
-                                                    }
-                                                });
+                                //update lcd with new inverter data
+                                //line 1: mode + ac voltage
+                                let task = LcdTask {
+                                    command: LcdTaskCommand::SetLineText,
+                                    int_arg: 1,
+                                    string_arg: Some(format!(
+                                        "{}: {}V",
+                                        match &inverter_mode {
+                                            Some(inv_mode) => {
+                                                InverterMode::get_mode_description_lcd(
+                                                    inv_mode.mode,
+                                                )
                                             }
                                             None => {
-                                                error!(
-                                                    "{}: error parsing mode (no input data)",
-                                                    self.name
-                                                );
+                                                "Unknown Mode".into()
                                             }
                                         },
-                                        None => {
-                                            break;
-                                        }
+                                        parameters.voltage_grid.unwrap_or_default()
+                                    )),
+                                };
+                                let _ = self.lcd_transmitter.send(task);
+
+                                //line 2: load info
+                                let task = LcdTask {
+                                    command: LcdTaskCommand::SetLineText,
+                                    int_arg: 2,
+                                    string_arg: Some(format!(
+                                        "Load: {}%, {}W",
+                                        parameters.load_percent.unwrap_or_default(),
+                                        parameters.load_watt.unwrap_or_default()
+                                    )),
+                                };
+                                let _ = self.lcd_transmitter.send(task);
+
+                                /*
+                                //line 2: battery info
+                                let task = LcdTask {
+                                    command: LcdTaskCommand::SetLineText,
+                                    int_arg: 2,
+                                    string_arg: Some(format!(
+                                        "Batt: {}%, {}V",
+                                        parameters
+                                            .batt_capacity
+                                            .unwrap_or_default(),
+                                        parameters
+                                            .voltage_batt
+                                            .unwrap_or_default()
+                                    )),
+                                };
+                                let _ = self.lcd_transmitter.send(task);
+                                */
+                            }
+                            _ => {
+                                self.errors.report(&SkymaxError::Parse {
+                                    command: "QPIGS",
+                                    what: "values",
+                                    data,
+                                });
+                            }
+                        }
+                    }
+                    None => {
+                        //reopen the device
+                        device.close();
+                        continue;
+                    }
+                }
+
+                //get warning status
+                let buffer = self.query_inverter(&mut device, "QPIWS".into()).await;
+                match self.reported(buffer) {
+                    Some(data) => match WarningStatus::new(&data) {
+                        Some(status) => {
+                            let changed = warning_status
+                                .as_ref()
+                                .map_or(true, |w| w.warning_bits != status.warning_bits);
+                            if changed {
+                                let new_faults =
+                                    status.log_changes(warning_status.as_ref(), &self.name);
+
+                                //run a shell script when a fault appears
+                                if let Some(command) = &self.fault_script {
+                                    for fault in new_faults {
+                                        let cmd = str::replace(command, "%fault%", fault);
+                                        thread::spawn(move || StateMachine::run_shell_command(cmd));
                                     }
                                 }
 
-                                tokio::time::sleep(Duration::from_millis(30)).await;
+                                if let Some(url) = &self.influxdb_url {
+                                    let result =
+                                        status.save_to_influxdb(url, &self.name, &identity).await;
+                                    self.reported(result);
+                                }
                             }
+                            warning_status = Some(status);
                         }
-                        Err(e) => {
-                            error!("{}: error opening device: {:?}", self.name, e);
-                            tokio::time::sleep(Duration::from_secs(10)).await;
-                            continue;
+                        None => {
+                            self.errors.report(&SkymaxError::Parse {
+                                command: "QPIWS",
+                                what: "warning status",
+                                data,
+                            });
                         }
+                    },
+                    None => {
+                        //reopen the device
+                        device.close();
+                        continue;
                     }
                 }
-                Err(e) => {
-                    error!("{}: file open timeout: {}", self.name, e);
+
+                //get mode
+                let buffer = self.query_inverter(&mut device, "QMOD".into()).await;
+                match self.reported(buffer) {
+                    Some(data) => match data.chars().nth(0) {
+                        Some(current_mode) => {
+                            inverter_mode = Some(match inverter_mode {
+                                Some(mut inv_mode) => {
+                                    if inv_mode.set_new_mode(current_mode, &self.name) {
+                                        events::publish(
+                                            &self.events,
+                                            Event::InverterMode {
+                                                inverter: self.name.clone(),
+                                                mode: InverterMode::get_mode_description(
+                                                    current_mode,
+                                                )
+                                                .to_string(),
+                                            },
+                                        );
+
+                                        //running on battery needs attention
+                                        let _ = self.notify_transmitter.send(Notification::new(
+                                            self.name.clone(),
+                                            if current_mode == 'B' {
+                                                Severity::Warning
+                                            } else {
+                                                Severity::Info
+                                            },
+                                            format!(
+                                                "mode: {}",
+                                                InverterMode::get_mode_description(current_mode)
+                                            ),
+                                            "inverter mode changed",
+                                        ));
+
+                                        //run a shell script when mode has changed
+                                        match &self.mode_change_script {
+                                            Some(command) => {
+                                                let mut cmd = command.to_string().clone();
+                                                cmd = str::replace(
+                                                    &cmd,
+                                                    "%mode%",
+                                                    InverterMode::get_mode_description(
+                                                        current_mode,
+                                                    ),
+                                                );
+                                                thread::spawn(move || {
+                                                    StateMachine::run_shell_command(cmd)
+                                                });
+                                            }
+                                            _ => (),
+                                        };
+
+                                        //update lcd with new inverter data
+                                        let task = LcdTask {
+                                            command: LcdTaskCommand::SetLineText,
+                                            int_arg: 0,
+                                            string_arg: Some(format!(
+                                                "new mode: {}",
+                                                InverterMode::get_mode_description_lcd(
+                                                    current_mode
+                                                )
+                                            )),
+                                        };
+                                        let _ = self.lcd_transmitter.send(task);
+
+                                        //if we are on battery, set emergency mode
+                                        let task = LcdTask {
+                                            command: LcdTaskCommand::SetEmergencyMode,
+                                            int_arg: {
+                                                if current_mode == 'B' {
+                                                    1
+                                                } else {
+                                                    0
+                                                }
+                                            },
+                                            string_arg: None,
+                                        };
+                                        let _ = self.lcd_transmitter.send(task);
+                                    }
+                                    inv_mode
+                                }
+                                None => {
+                                    info!(
+                                        "{}: inverter mode: {}",
+                                        self.name,
+                                        InverterMode::get_mode_description(current_mode)
+                                    );
+
+                                    //update lcd with new inverter data
+                                    let task = LcdTask {
+                                        command: LcdTaskCommand::SetLineText,
+                                        int_arg: 0,
+                                        string_arg: Some(format!(
+                                            "new mode: {}",
+                                            InverterMode::get_mode_description_lcd(current_mode)
+                                        )),
+                                    };
+                                    let _ = self.lcd_transmitter.send(task);
+
+                                    //enable/disable emergency mode
+                                    let task = LcdTask {
+                                        command: LcdTaskCommand::SetEmergencyMode,
+                                        int_arg: {
+                                            if current_mode == 'B' {
+                                                1
+                                            } else {
+                                                0
+                                            }
+                                        },
+                                        string_arg: None,
+                                    };
+                                    let _ = self.lcd_transmitter.send(task);
+
+                                    InverterMode {
+                                        last_change: Instant::now(),
+                                        mode: current_mode,
+                                    }
+                                }
+                            });
+                        }
+                        None => {
+                            self.errors.report(&SkymaxError::Parse {
+                                command: "QMOD",
+                                what: "mode",
+                                data,
+                            });
+                        }
+                    },
+                    None => {
+                        //reopen the device
+                        device.close();
+                        continue;
+                    }
                 }
             }
+
             tokio::time::sleep(Duration::from_millis(30)).await;
         }
 
+        info!(
+            "{}: 📊 inverter query statistics: ok: {}, errors: {}, reconnects: {}",
+            self.name, self.poll_ok, self.poll_errors, device.reconnects
+        );
+        systemd::heartbeat_stop(&self.name);
         info!("{}: task stopped", self.name);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::WorkerErrors;
+    use crate::queue::{self, OverflowPolicy, QueueMetrics};
+    use crate::test_support::{skymax_replies, FakeHidraw, FakeInfluxDb, TEST_TIMEOUT};
+    use std::sync::RwLock;
+    use tokio::sync::broadcast;
+
+    #[tokio::test]
+    async fn worker_polls_the_inverter_and_writes_to_influxdb() {
+        let hidraw = FakeHidraw::start(skymax_replies());
+        let influx = FakeInfluxDb::start().await;
+        let metrics = Arc::new(RwLock::new(QueueMetrics::default()));
+        let (lcd_tx, lcd_rx) = queue::bounded("lcd", 100, OverflowPolicy::DropOldest, &metrics);
+        let (mqtt_tx, mqtt_rx) = queue::bounded("mqtt", 1000, OverflowPolicy::DropOldest, &metrics);
+        let (notify_tx, _notify_rx) =
+            queue::bounded("notify", 100, OverflowPolicy::DropOldest, &metrics);
+        let mut skymax = Skymax {
+            name: "skymax".to_string(),
+            device_path: hidraw.sysfs_path.clone(),
+            device_usbid: hidraw.usb_id.clone(),
+            device_dir: hidraw.dev_dir.clone(),
+            status_lcd_line: None,
+            poll_ok: 0,
+            poll_errors: 0,
+            influxdb_url: Some(influx.url.clone()),
+            lcd_transmitter: lcd_tx,
+            mqtt_transmitter: mqtt_tx,
+            notify_transmitter: notify_tx.clone(),
+            events: broadcast::channel(16).0,
+            mode_change_script: None,
+            fault_script: None,
+            settings: vec![],
+            setting_requests: Arc::new(Mutex::new(vec![])),
+            poll_interval: Duration::from_millis(100),
+            stats_interval: Duration::from_secs(3600),
+            errors: ErrorReporter {
+                worker: "skymax".to_string(),
+                metrics: Arc::new(RwLock::new(WorkerErrors::default())),
+                notify_transmitter: notify_tx,
+            },
+        };
+
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+        let stop = cancel_flag.clone();
+        let watcher = influx.clone();
+        tokio::spawn(async move {
+            watcher.wait_for("status_params", TEST_TIMEOUT).await;
+            //let the worker finish the poll cycle
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            stop.store(true, Ordering::SeqCst);
+        });
+        skymax.worker(cancel_flag).await.unwrap();
+
+        //the identity is queried once, before the status
+        let commands = hidraw.commands();
+        assert_eq!(
+            commands[..6],
+            ["QID", "QVFW", "QPIRI", "QPIGS", "QPIWS", "QMOD"]
+        );
+        assert_eq!(skymax.poll_errors, 0);
+
+        assert!(influx
+            .writes()
+            .iter()
+            .all(|w| w.target.starts_with("/write?db=skymax")));
+        let line = influx.lines("status_params").pop().unwrap();
+        assert!(line.contains(",serial_number=92931509101901"), "{}", line);
+        assert!(line.contains(",firmware=00072.70"), "{}", line);
+        assert!(line.contains(",rated_power=4000"), "{}", line);
+        assert!(line.contains(" voltage_grid=230,"), "{}", line);
+        assert!(line.contains(",load_watt=119i,"), "{}", line);
+        assert!(line.contains(",device_status=54i,"), "{}", line);
+
+        //values published to mqtt, the mode shown on the lcd
+        let topics: Vec<String> = std::iter::from_fn(|| mqtt_rx.try_recv().ok())
+            .map(|event| event.topic)
+            .collect();
+        assert!(topics.contains(&"skymax/voltage_batt".to_string()));
+        let lcd: Vec<String> = std::iter::from_fn(|| lcd_rx.try_recv().ok())
+            .filter_map(|task| task.string_arg)
+            .collect();
+        assert!(
+            lcd.contains(&"new mode: Line Mode".to_string()),
+            "{:?}",
+            lcd
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use crate::database::{CommandCode, DbTask};
+use crate::onewire::{Device, OneWire};
+use crate::ontime::DeviceKind;
+use crate::queue::Sender;
+use simplelog::*;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+pub const SMART_PLUG_HTTP_TIMEOUT_SECS: f32 = 3.0;
+pub const SMART_PLUG_COMMAND_ATTEMPTS: u8 = 3;
+
+//shared by all the plugs, so the connections are reused between the commands
+static HTTP_CLIENT: Mutex<Option<reqwest::Client>> = Mutex::new(None);
+
+fn http_client() -> reqwest::Result<reqwest::Client> {
+    let mut client = HTTP_CLIENT.lock().unwrap();
+    if let Some(client) = client.as_ref() {
+        return Ok(client.clone());
+    }
+    let new_client = reqwest::Client::builder()
+        .timeout(Duration::from_secs_f32(SMART_PLUG_HTTP_TIMEOUT_SECS))
+        .build()?;
+    *client = Some(new_client.clone());
+    Ok(new_client)
+}
+
+/// Firmware of a WiFi plug, selects the HTTP API used for switching
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlugKind {
+    /// `/cm?cmnd=Power<n> On`
+    Tasmota,
+    /// Shelly Gen1: `/relay/<n>?turn=on`
+    Shelly,
+    /// Shelly Gen2/Plus RPC: `/rpc/Switch.Set?id=<n>&on=true`
+    ShellyRpc,
+}
+
+impl PlugKind {
+    pub fn parse(kind: &str) -> Option<PlugKind> {
+        match kind.trim().to_lowercase().as_str() {
+            "tasmota" => Some(PlugKind::Tasmota),
+            "shelly" => Some(PlugKind::Shelly),
+            "shelly_rpc" | "shelly2" => Some(PlugKind::ShellyRpc),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PlugKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            PlugKind::Tasmota => "tasmota",
+            PlugKind::Shelly => "shelly",
+            PlugKind::ShellyRpc => "shelly_rpc",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// HTTP controlled relay (Tasmota/Shelly WiFi plug), switched like a yeelight
+pub struct SmartPlug {
+    pub id: i32,
+    pub kind: PlugKind,
+    pub host: String,
+    pub channel: u8,
+    pub powered_on: bool,
+}
+
+impl SmartPlug {
+    fn command_url(&self, turn_on: bool) -> Option<reqwest::Url> {
+        let base = format!("http://{}", self.host);
+        let on_off = if turn_on { "on" } else { "off" };
+        let url = match self.kind {
+            PlugKind::Tasmota => reqwest::Url::parse_with_params(
+                &format!("{}/cm", base),
+                &[("cmnd", format!("Power{} {}", self.channel + 1, on_off))],
+            ),
+            PlugKind::Shelly => reqwest::Url::parse_with_params(
+                &format!("{}/relay/{}", base, self.channel),
+                &[("turn", on_off)],
+            ),
+            PlugKind::ShellyRpc => reqwest::Url::parse_with_params(
+                &format!("{}/rpc/Switch.Set", base),
+                &[
+                    ("id", self.channel.to_string()),
+                    ("on", turn_on.to_string()),
+                ],
+            ),
+        };
+        match url {
+            Ok(url) => Some(url),
+            Err(e) => {
+                error!("plug: {}: invalid address: {:?}", self.host, e);
+                None
+            }
+        }
+    }
+
+    async fn http_command(plug_name: &str, url: reqwest::Url) -> bool {
+        debug!("plug: {}: URL = {:?}", plug_name, url.as_str());
+        let client = match http_client() {
+            Ok(client) => client,
+            Err(e) => {
+                error!("plug: {}: unable to create HTTP client: {:?}", plug_name, e);
+                return false;
+            }
+        };
+
+        for _ in 0..SMART_PLUG_COMMAND_ATTEMPTS {
+            match client.get(url.clone()).send().await {
+                Ok(resp) if resp.status().is_success() => return true,
+                Ok(resp) => {
+                    error!("plug: {}: HTTP status: {}", plug_name, resp.status());
+                }
+                Err(e) => {
+                    error!("plug: {}: request error: {:?}", plug_name, e);
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        false
+    }
+
+    /// Counts the switching and records it for the on-time statistics
+    fn switched(transmitter: &Sender<DbTask>, id: i32, on: bool) {
+        let _ = transmitter.send(DbTask {
+            command: CommandCode::IncrementPlugCounter,
+            value: Some(id),
+        });
+        let _ = transmitter.send(DbTask {
+            command: CommandCode::DeviceSwitched {
+                kind: DeviceKind::Plug,
+                on,
+                time: SystemTime::now(),
+            },
+            value: Some(id),
+        });
+    }
+
+    /// Sends the command in the background, the plug state and the statistics
+    /// are updated only when the plug has confirmed it
+    pub fn turn_on_off(&self, turn_on: bool, dev: &Device, onewire: &OneWire) {
+        let plug_name = dev.name.clone();
+        let id = self.id;
+        let url = match self.command_url(turn_on) {
+            Some(url) => url,
+            None => return,
+        };
+        //measure the latency when the command was triggered by a sensor edge
+        let edge = onewire.edge();
+        let latency = onewire.latency.clone();
+        let relay_devices = onewire.relay_devices.clone();
+        let transmitter = onewire.transmitter.clone();
+        tokio::spawn(async move {
+            if !SmartPlug::http_command(&plug_name, url).await {
+                error!(
+                    "plug: {}: switching <b>{}</> failed, the state is unchanged",
+                    plug_name,
+                    if turn_on { "on" } else { "off" }
+                );
+                return;
+            }
+            if let Some(plug) = relay_devices
+                .write()
+                .unwrap()
+                .smart_plugs
+                .iter_mut()
+                .find(|p| p.id == id)
+            {
+                plug.powered_on = turn_on;
+            }
+            SmartPlug::switched(&transmitter, id, turn_on);
+            if let Some((edge, read_time)) = edge {
+                latency
+                    .write()
+                    .unwrap()
+                    .observe("plug", &plug_name, read_time, edge.elapsed());
+            }
+        });
+    }
+}
@@ -0,0 +1,484 @@
+use postgres::types::{FromSql, Type};
+use rusqlite::types::ValueRef;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::SystemTime;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+pub const DEFAULT_SQLITE_PATH: &str = "/var/lib/hard/hard.db";
+
+/// Database keeping the device definitions, rfid tags and counters
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StorageBackend {
+    Postgres,
+    /// single file database for small installations, without the
+    /// postgres-only data (history, energy costs, on-time, readings)
+    Sqlite,
+}
+
+impl StorageBackend {
+    pub fn parse(backend: &str) -> Option<StorageBackend> {
+        match backend.trim().to_lowercase().as_str() {
+            "postgres" | "postgresql" => Some(StorageBackend::Postgres),
+            "sqlite" => Some(StorageBackend::Sqlite),
+            _ => None,
+        }
+    }
+}
+
+/// Tables named like the postgres views, lists (`relay_agg`, `tags`, ...) are
+/// kept as JSON arrays or comma separated text
+pub const SQLITE_SCHEMA: &str = "
+create table if not exists kinds (
+    id_kind integer primary key,
+    name text not null
+);
+create table if not exists sensors (
+    id_sensor integer primary key,
+    id_kind integer not null,
+    name text not null,
+    family_code integer,
+    address integer not null,
+    bit integer not null default 0,
+    relay_agg text,
+    yeelight_agg text,
+    plug_agg text,
+    tags text,
+    cycles integer not null default 0
+);
+create table if not exists virtual_sensors (
+    id_sensor integer primary key,
+    id_kind integer not null,
+    name text not null,
+    expression text not null,
+    relay_agg text,
+    yeelight_agg text,
+    plug_agg text,
+    tags text
+);
+create table if not exists env_sensors (
+    id_sensor integer primary key,
+    id_kind integer not null,
+    name text not null,
+    family_code integer,
+    address integer not null,
+    relay_agg text,
+    yeelight_agg text,
+    tags text
+);
+create table if not exists relays (
+    id_relay integer primary key,
+    name text not null,
+    family_code integer,
+    address integer not null,
+    bit integer not null,
+    pir_exclude integer not null default 0,
+    pir_hold_secs real,
+    switch_hold_secs real,
+    initial_state integer not null default 0,
+    pir_all_day integer not null default 0,
+    tags text,
+    cycles integer not null default 0
+);
+create table if not exists yeelights (
+    id_yeelight integer primary key,
+    name text not null,
+    ip_address text not null,
+    pir_exclude integer not null default 0,
+    pir_hold_secs real,
+    switch_hold_secs real,
+    pir_all_day integer not null default 0,
+    tags text,
+    cycles integer not null default 0
+);
+create table if not exists smart_plugs (
+    id_plug integer primary key,
+    name text not null,
+    kind text not null,
+    host text not null,
+    channel integer,
+    pir_exclude integer not null default 0,
+    pir_hold_secs real,
+    switch_hold_secs real,
+    pir_all_day integer not null default 0,
+    tags text,
+    cycles integer not null default 0
+);
+create table if not exists relay_schedules (
+    id_schedule integer primary key,
+    id_relay integer not null,
+    on_time text not null,
+    off_time text not null,
+    weekdays text
+);
+create table if not exists relay_cron (
+    id_job integer primary key,
+    cron text not null,
+    id_relay integer,
+    tag_group text,
+    id_yeelight integer,
+    id_plug integer,
+    command text not null,
+    duration integer
+);
+create table if not exists rfid_tags (
+    id_tag integer primary key,
+    name text not null,
+    tags text,
+    relay_agg text,
+    valid_weekdays text,
+    valid_hours text,
+    expires text,
+    max_uses_per_day integer,
+    pin text
+);
+";
+
+/// Column value independent of the backend
+#[derive(Clone, Debug)]
+pub enum Value {
+    Null,
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Text(String),
+    Time(SystemTime),
+    IntArray(Vec<i64>),
+    TextArray(Vec<String>),
+    /// Column type without a conversion or an unreadable value, with the reason
+    Invalid(String),
+}
+
+/// List from a text column: `[1, 2]`, `{1,2}` or `1,2`
+fn parse_list(text: &str) -> Option<Vec<String>> {
+    let text = text.trim();
+    if text.starts_with('[') {
+        let values: Vec<serde_json::Value> = serde_json::from_str(text).ok()?;
+        return Some(
+            values
+                .into_iter()
+                .map(|v| match v {
+                    serde_json::Value::String(s) => s,
+                    v => v.to_string(),
+                })
+                .collect(),
+        );
+    }
+    Some(
+        text.trim_start_matches('{')
+            .trim_end_matches('}')
+            .split(',')
+            .map(|s| s.trim().trim_matches('"').to_string())
+            .filter(|s| !s.is_empty())
+            .collect(),
+    )
+}
+
+/// Conversion of a column value, `None` for a type mismatch
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> Option<Self>;
+}
+
+impl FromValue for i64 {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Int(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for i32 {
+    fn from_value(value: &Value) -> Option<Self> {
+        i64::from_value(value).and_then(|v| i32::try_from(v).ok())
+    }
+}
+
+impl FromValue for i16 {
+    fn from_value(value: &Value) -> Option<Self> {
+        i64::from_value(value).and_then(|v| i16::try_from(v).ok())
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Float(v) => Some(*v),
+            Value::Int(v) => Some(*v as f64),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for f32 {
+    fn from_value(value: &Value) -> Option<Self> {
+        f64::from_value(value).map(|v| v as f32)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Bool(v) => Some(*v),
+            //sqlite has no boolean type
+            Value::Int(v) => Some(*v != 0),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Text(v) => Some(v.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for SystemTime {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Time(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for Vec<i32> {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::IntArray(v) => v.iter().map(|v| i32::try_from(*v).ok()).collect(),
+            Value::Text(v) => parse_list(v)?.iter().map(|v| v.parse().ok()).collect(),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for Vec<String> {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::TextArray(v) => Some(v.clone()),
+            Value::Text(v) => parse_list(v),
+            _ => None,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Null => Some(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+/// Result row of a device view
+pub struct Row {
+    columns: Vec<(String, Value)>,
+}
+
+impl Row {
+    /// Value of the column, `None` when it is missing or of another type
+    pub fn try_get<T: FromValue>(&self, name: &str) -> Option<T> {
+        self.columns
+            .iter()
+            .find(|(column, _)| column == name)
+            .and_then(|(_, value)| T::from_value(value))
+    }
+
+    /// Value of a mandatory column, an error when it is missing or invalid
+    pub fn get<T: FromValue>(&self, name: &str) -> Result<T> {
+        let value = match self.columns.iter().find(|(column, _)| column == name) {
+            Some((_, value)) => value,
+            None => return Err(format!("column {:?} is missing", name).into()),
+        };
+        match (T::from_value(value), value) {
+            (Some(value), _) => Ok(value),
+            (None, Value::Invalid(reason)) => {
+                Err(format!("column {:?}: invalid value: {}", name, reason).into())
+            }
+            (None, value) => {
+                Err(format!("column {:?}: unexpected value: {:?}", name, value).into())
+            }
+        }
+    }
+
+    fn from_postgres(row: &postgres::Row) -> Row {
+        let columns = row
+            .columns()
+            .iter()
+            .enumerate()
+            .map(|(i, column)| {
+                (
+                    column.name().to_string(),
+                    postgres_value(row, i, column.type_()),
+                )
+            })
+            .collect();
+        Row { columns }
+    }
+}
+
+/// NUMERIC column read as a float, the postgres crate has no conversion for it
+struct Numeric(f64);
+
+impl<'a> FromSql<'a> for Numeric {
+    fn from_sql(_: &Type, raw: &'a [u8]) -> Result<Self> {
+        //digit count, weight, sign and display scale, then the base 10000 digits
+        let word = |i: usize| -> Result<u16> {
+            raw.get(i * 2..i * 2 + 2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .ok_or_else(|| "truncated numeric value".into())
+        };
+        let digits = word(0)? as usize;
+        let weight = word(1)? as i16 as i32;
+        let sign = word(2)?;
+        if sign == 0xc000 {
+            return Ok(Numeric(f64::NAN));
+        }
+        let mut value = 0.0;
+        for i in 0..digits {
+            value += word(4 + i)? as f64 * 10000f64.powi(weight - i as i32);
+        }
+        Ok(Numeric(if sign == 0x4000 { -value } else { value }))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::NUMERIC
+    }
+}
+
+fn postgres_value(row: &postgres::Row, i: usize, ty: &Type) -> Value {
+    let value = if *ty == Type::BOOL {
+        row.try_get::<_, Option<bool>>(i)
+            .map(|v| v.map(Value::Bool))
+    } else if *ty == Type::INT2 {
+        row.try_get::<_, Option<i16>>(i)
+            .map(|v| v.map(|v| Value::Int(v as i64)))
+    } else if *ty == Type::INT4 {
+        row.try_get::<_, Option<i32>>(i)
+            .map(|v| v.map(|v| Value::Int(v as i64)))
+    } else if *ty == Type::INT8 {
+        row.try_get::<_, Option<i64>>(i).map(|v| v.map(Value::Int))
+    } else if *ty == Type::FLOAT4 {
+        row.try_get::<_, Option<f32>>(i)
+            .map(|v| v.map(|v| Value::Float(v as f64)))
+    } else if *ty == Type::FLOAT8 {
+        row.try_get::<_, Option<f64>>(i)
+            .map(|v| v.map(Value::Float))
+    } else if *ty == Type::NUMERIC {
+        row.try_get::<_, Option<Numeric>>(i)
+            .map(|v| v.map(|v| Value::Float(v.0)))
+    } else if *ty == Type::TIMESTAMP || *ty == Type::TIMESTAMPTZ {
+        row.try_get::<_, Option<SystemTime>>(i)
+            .map(|v| v.map(Value::Time))
+    } else if *ty == Type::TEXT || *ty == Type::VARCHAR || *ty == Type::NAME || *ty == Type::BPCHAR
+    {
+        row.try_get::<_, Option<String>>(i)
+            .map(|v| v.map(Value::Text))
+    } else if *ty == Type::INT2_ARRAY {
+        row.try_get::<_, Option<Vec<i16>>>(i)
+            .map(|v| v.map(|v| Value::IntArray(v.into_iter().map(|v| v as i64).collect())))
+    } else if *ty == Type::INT4_ARRAY {
+        row.try_get::<_, Option<Vec<i32>>>(i)
+            .map(|v| v.map(|v| Value::IntArray(v.into_iter().map(|v| v as i64).collect())))
+    } else if *ty == Type::INT8_ARRAY {
+        row.try_get::<_, Option<Vec<i64>>>(i)
+            .map(|v| v.map(Value::IntArray))
+    } else if *ty == Type::TEXT_ARRAY || *ty == Type::VARCHAR_ARRAY {
+        row.try_get::<_, Option<Vec<String>>>(i)
+            .map(|v| v.map(Value::TextArray))
+    } else {
+        return Value::Invalid(format!("unsupported column type {}", ty));
+    };
+    match value {
+        Ok(Some(value)) => value,
+        Ok(None) => Value::Null,
+        Err(e) => Value::Invalid(e.to_string()),
+    }
+}
+
+/// Device definitions, rfid tags and toggle counters, common to all backends
+pub trait Storage {
+    /// All rows of a device view, eg. `relays`
+    fn select_all(&mut self, view: &str) -> Result<Vec<Row>>;
+
+    /// Adds the counters to the `cycles` column of the devices in the table (`sensor`, `relay`, ...)
+    fn increment_cycles(&mut self, table: &str, counters: &HashMap<i32, u32>) -> Result<()>;
+}
+
+impl Storage for postgres::Client {
+    fn select_all(&mut self, view: &str) -> Result<Vec<Row>> {
+        let rows = postgres::Client::query(self, format!("select * from {}", view).as_str(), &[])?;
+        Ok(rows.iter().map(Row::from_postgres).collect())
+    }
+
+    fn increment_cycles(&mut self, table: &str, counters: &HashMap<i32, u32>) -> Result<()> {
+        //update all counters of the table in a single statement
+        let ids: Vec<i32> = counters.keys().cloned().collect();
+        let values: Vec<i64> = ids.iter().map(|id| counters[id] as i64).collect();
+        let query = format!(
+            "update {} set cycles=cycles+v.counter from (select unnest($1::int[]) as id, unnest($2::bigint[]) as counter) v where id_{}=v.id",
+            table, table
+        );
+        self.execute(query.as_str(), &[&ids, &values])?;
+        Ok(())
+    }
+}
+
+impl Storage for rusqlite::Connection {
+    fn select_all(&mut self, view: &str) -> Result<Vec<Row>> {
+        let mut stmt = self.prepare(&format!("select * from {}", view))?;
+        let names: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+        let mut rows = stmt.query([])?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            let mut columns = vec![];
+            for (i, name) in names.iter().enumerate() {
+                let value = match row.get_ref(i)? {
+                    ValueRef::Null => Value::Null,
+                    ValueRef::Blob(_) => Value::Invalid("blob value".to_string()),
+                    ValueRef::Integer(v) => Value::Int(v),
+                    ValueRef::Real(v) => Value::Float(v),
+                    ValueRef::Text(v) => Value::Text(String::from_utf8_lossy(v).into_owned()),
+                };
+                columns.push((name.clone(), value));
+            }
+            result.push(Row { columns });
+        }
+        Ok(result)
+    }
+
+    fn increment_cycles(&mut self, table: &str, counters: &HashMap<i32, u32>) -> Result<()> {
+        //the counters are kept in the device tables
+        let (view, id_column) = match table {
+            "sensor" => ("sensors", "id_sensor"),
+            "relay" => ("relays", "id_relay"),
+            "yeelight" => ("yeelights", "id_yeelight"),
+            "plug" => ("smart_plugs", "id_plug"),
+            _ => return Err(format!("unknown counter table: {}", table).into()),
+        };
+        let tx = self.transaction()?;
+        {
+            let mut stmt = tx.prepare(&format!(
+                "update {} set cycles=cycles+?1 where {}=?2",
+                view, id_column
+            ))?;
+            for (id, counter) in counters {
+                stmt.execute(rusqlite::params![*counter as i64, *id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// Opens the database file, creating the tables on the first start
+pub fn open_sqlite(path: &str) -> rusqlite::Result<rusqlite::Connection> {
+    let conn = rusqlite::Connection::open(path)?;
+    conn.execute_batch(SQLITE_SCHEMA)?;
+    Ok(conn)
+}
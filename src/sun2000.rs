@@ -1,29 +1,79 @@
 use crate::database::{CommandCode, DbTask};
+use crate::dsmr::{DSMR_EXPORT_POWER_VALUE, DSMR_IMPORT_POWER_VALUE};
+use crate::energy::EnergyCosts;
+use crate::energy_manager::GridExport;
+use crate::events::{self, Event, EventSender};
+use crate::influx::{Client, InfluxDbWriteable, Timestamp, Type, WriteQuery};
 use crate::lcdproc::{LcdTask, LcdTaskCommand};
+use crate::modbus::{group_register_blocks, read_register_block, BlockRead};
+use crate::mqtt::MqttEvent;
+use crate::notify::{self, Notification, Severity};
+use crate::onewire::{OneWireTask, StateMachine, TaskCommand};
+use crate::queue::Sender;
+use crate::systemd;
+use crate::units;
+use crate::virtual_sensor::{Expr, SensorValues};
+use crate::worker_error::{ErrorReporter, WorkerError};
 use chrono::{Local, LocalResult, NaiveDateTime, TimeZone};
-use influxdb::{Client, InfluxDbWriteable, Timestamp, Type};
-use io::ErrorKind;
 use simplelog::*;
 use std::fmt;
 use std::io;
+use std::net::AddrParseError;
 use std::ops::Add;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::Sender;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
 use std::time::{Duration, Instant};
 use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
 use tokio::time::timeout;
 use tokio_modbus::client::Context;
 use tokio_modbus::prelude::*;
 
 pub const SUN2000_POLL_INTERVAL_SECS: f32 = 2.0; //secs between polling
 pub const SUN2000_STATS_DUMP_INTERVAL_SECS: f32 = 3600.0; //secs between showing stats
+pub const SUN2000_MIN_POLL_INTERVAL_SECS: f32 = 1.0; //the dongle drops the connection when polled faster
 pub const SUN2000_ATTEMPTS_PER_PARAM: u8 = 3; //max read attempts per single parameter
+pub const SUN2000_BACKUP_SOC_REGISTER: u16 = 47102; //backup power SOC (RW, gain 10)
+pub const SUN2000_RULE_DEFAULT_HOLD_SECS: f32 = 300.0; //relay on-time prolonged while the rule condition holds
 
 // Just a generic Result type to ease error handling for us. Errors in multithreaded
 // async contexts needs some extra restrictions
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
+#[derive(Debug, Error)]
+pub enum Sun2000Error {
+    #[error("invalid inverter address {0}: {1}")]
+    InvalidAddress(String, AddrParseError),
+    #[error("connect timeout")]
+    ConnectTimeout,
+    #[error("connection error: {0}")]
+    Connect(io::Error),
+    #[error("connection lost while reading the parameters")]
+    Disconnected,
+    #[error("problem obtaining a complete parameter list (read: {read}, expected: {expected})")]
+    IncompleteRead { read: usize, expected: usize },
+    #[error("invalid {0}: {1}")]
+    InvalidData(String, String),
+}
+
+impl WorkerError for Sun2000Error {
+    fn kind(&self) -> &'static str {
+        match self {
+            Sun2000Error::InvalidAddress(..) => "config",
+            Sun2000Error::ConnectTimeout | Sun2000Error::Connect(_) => "connect",
+            Sun2000Error::Disconnected => "disconnected",
+            Sun2000Error::IncompleteRead { .. } => "incomplete_read",
+            Sun2000Error::InvalidData(..) => "invalid_data",
+        }
+    }
+
+    //the inverter and the dongle are dropping connections quite often, only a config error is fatal
+    fn is_recoverable(&self) -> bool {
+        !matches!(self, Sun2000Error::InvalidAddress(..))
+    }
+}
+
 #[derive(Clone)]
 pub enum ParamKind {
     Text(Option<String>),
@@ -149,10 +199,10 @@ impl Parameter {
                     if self.unit.unwrap_or_default() == "epoch" {
                         match *v {
                             Some(epoch_secs) => {
-                                let naive = NaiveDateTime::from_timestamp(epoch_secs as i64, 0);
-                                match Local.from_local_datetime(&naive) {
-                                    LocalResult::Single(dt) => {
-                                        format!("{}, {:?}", epoch_secs, dt.to_rfc2822())
+                                let naive = NaiveDateTime::from_timestamp_opt(epoch_secs as i64, 0);
+                                match naive.map(|naive| Local.from_local_datetime(&naive)) {
+                                    Some(LocalResult::Single(dt)) => {
+                                        format!("{}, {}", epoch_secs, units::timestamp(dt))
                                     }
                                     _ => "timestamp conversion error".into(),
                                 }
@@ -174,7 +224,7 @@ impl Parameter {
         }
     }
 
-    pub fn get_influx_value(&self) -> influxdb::Type {
+    pub fn get_influx_value(&self) -> Type {
         match &self.value {
             ParamKind::Text(v) => {
                 return Type::Text(v.clone().unwrap());
@@ -234,6 +284,7 @@ impl Sun2000State {
             0x0200 => "On-grid",
             0x0201 => "Grid Connection: power limited",
             0x0202 => "Grid Connection: self-derating",
+            0x0203 => "Off-grid running",
             0x0300 => "Shutdown: fault",
             0x0301 => "Shutdown: command",
             0x0302 => "Shutdown: OVGR",
@@ -260,6 +311,11 @@ impl Sun2000State {
         }
     }
 
+    /// Backup box: inverter is supplying the loads without the grid
+    fn is_off_grid(code: u16) -> bool {
+        code == 0x0203 || code == 0x0a00
+    }
+
     fn get_storage_status_description(code: i16) -> &'static str {
         match code {
             0 => "offline",
@@ -917,6 +973,62 @@ fn get_attribute_name(id: &str) -> &'static str {
     "Unknown attribute"
 }
 
+/// Energy surplus automation: drives relays when the condition over the inverter
+/// values (`soc`, `active_power`, `input_power`, `grid_export`) is met
+pub struct EnergyRule {
+    pub name: String,
+    pub source: String,
+    pub condition: Expr,
+    pub id_relay: Option<i32>,
+    pub tag_group: Option<String>,
+    pub hold: Duration,
+    pub active: bool,
+    pub last_sent: Option<Instant>,
+}
+
+impl EnergyRule {
+    /// Evaluates the rule and returns the relay command to send, if any
+    fn evaluate(&mut self, values: &SensorValues) -> Option<OneWireTask> {
+        let on = self.condition.eval(values)? != 0.0;
+        let command = if on {
+            //keep prolonging the relay while the condition holds
+            if self.active
+                && self
+                    .last_sent
+                    .map_or(false, |t| t.elapsed() < self.hold / 2)
+            {
+                return None;
+            }
+            if !self.active {
+                info!(
+                    "sun2000: ☀️ rule <b>{}</> ({}) met, turning on",
+                    self.name, self.source
+                );
+            }
+            TaskCommand::TurnOnProlong
+        } else {
+            if !self.active {
+                return None;
+            }
+            info!(
+                "sun2000: rule <b>{}</> no longer met, turning off",
+                self.name
+            );
+            TaskCommand::TurnOff
+        };
+        self.active = on;
+        self.last_sent = Some(Instant::now());
+        Some(OneWireTask {
+            command,
+            id_relay: self.id_relay,
+            tag_group: self.tag_group.clone(),
+            id_yeelight: None,
+            id_plug: None,
+            duration: if on { Some(self.hold) } else { None },
+        })
+    }
+}
+
 pub struct Sun2000 {
     pub name: String,
     pub host_port: String,
@@ -925,13 +1037,77 @@ pub struct Sun2000 {
     pub influxdb_url: Option<String>,
     pub lcd_transmitter: Sender<LcdTask>,
     pub db_transmitter: Sender<DbTask>,
+    pub mqtt_transmitter: Sender<MqttEvent>,
+    pub notify_transmitter: Sender<Notification>,
+    pub energy_costs: Arc<RwLock<EnergyCosts>>,
+    pub events: EventSender,
+    pub ow_transmitter: Sender<OneWireTask>,
     pub mode_change_script: Option<String>,
     pub optimizers: bool,
     pub battery_installed: bool,
     pub dongle_connection: bool,
+    pub backup_box: bool,
+    pub load_shedding_tag: Option<String>,
+    pub backup_soc_request: Arc<Mutex<Option<f32>>>,
+    pub rules: Vec<EnergyRule>,
+    pub sensor_values: Arc<RwLock<SensorValues>>,
+    pub grid_export: Arc<RwLock<Option<GridExport>>>,
+    pub poll_interval: Duration,
+    pub stats_interval: Duration,
+    pub errors: ErrorReporter,
 }
 
 impl Sun2000 {
+    /// Reports the error, a fatal one is returned to end the worker
+    fn handle_error(&self, e: Sun2000Error) -> Result<()> {
+        self.errors.report(&e);
+        if e.is_recoverable() {
+            Ok(())
+        } else {
+            Err(e.into())
+        }
+    }
+
+    /// Sends the active alarms (or the all-clear after an alarm) as a notification
+    fn notify_alarms(
+        &self,
+        state: &Sun2000State,
+        previous: (Option<u16>, Option<u16>, Option<u16>),
+    ) {
+        let mut alarms = vec![];
+        if let Some(code) = state.alarm_1.filter(|c| *c != 0) {
+            alarms.push(Sun2000State::get_alarm1_description(code));
+        }
+        if let Some(code) = state.alarm_2.filter(|c| *c != 0) {
+            alarms.push(Sun2000State::get_alarm2_description(code));
+        }
+        if let Some(code) = state.alarm_3.filter(|c| *c != 0) {
+            alarms.push(Sun2000State::get_alarm3_description(code));
+        }
+        let was_active = [previous.0, previous.1, previous.2]
+            .iter()
+            .any(|a| a.map_or(false, |c| c != 0));
+        let notification = if alarms.is_empty() {
+            if !was_active {
+                return;
+            }
+            Notification::new(
+                &self.name,
+                Severity::Info,
+                "alarms cleared",
+                "no active alarms",
+            )
+        } else {
+            Notification::new(
+                &self.name,
+                Severity::Critical,
+                "inverter alarm",
+                notify::plain(&alarms.join(" | ")),
+            )
+        };
+        let _ = self.notify_transmitter.send(notification);
+    }
+
     #[rustfmt::skip]
     pub fn param_table() -> Vec<Parameter> {
         vec![
@@ -981,7 +1157,7 @@ impl Sun2000 {
             Parameter::new("unknown_time_4", ParamKind::NumberU32(None), None, Some("epoch"), 1, 35113, 2, false, false),
             Parameter::new("storage_status", ParamKind::NumberI16(None), None, Some("storage_status_enum"), 1, 37000, 1, false, false),
             Parameter::new("storage_charge_discharge_power", ParamKind::NumberI32(None), None, Some("W"), 1, 37001, 2, false, false),
-            Parameter::new("power_meter_active_power", ParamKind::NumberI32(None), None, Some("W"), 1, 37113, 2, false, false),
+            Parameter::new("power_meter_active_power", ParamKind::NumberI32(None), None, Some("W"), 1, 37113, 2, false, true),
             Parameter::new("grid_A_voltage", ParamKind::NumberI32(None), None, Some("V"), 10, 37101, 2, false, true),
             Parameter::new("grid_B_voltage", ParamKind::NumberI32(None), None, Some("V"), 10, 37103, 2, false, true),
             Parameter::new("grid_C_voltage", ParamKind::NumberI32(None), None, Some("V"), 10, 37105, 2, false, true),
@@ -990,8 +1166,8 @@ impl Sun2000 {
             Parameter::new("active_grid_C_current", ParamKind::NumberI32(None), None, Some("I"), 100, 37111, 2, false, true),
             Parameter::new("active_grid_power_factor", ParamKind::NumberI16(None), None, None, 1000, 37117, 1, false, false),
             Parameter::new("active_grid_frequency", ParamKind::NumberI16(None), None, Some("Hz"), 100, 37118, 1, false, true),
-            Parameter::new("grid_exported_energy", ParamKind::NumberI32(None), None, Some("kWh"), 100, 37119, 2, false, false),
-            Parameter::new("grid_accumulated_energy", ParamKind::NumberU32(None), None, Some("kWh"), 100, 37121, 2, false, false),
+            Parameter::new("grid_exported_energy", ParamKind::NumberI32(None), None, Some("kWh"), 100, 37119, 2, false, true),
+            Parameter::new("grid_accumulated_energy", ParamKind::NumberU32(None), None, Some("kWh"), 100, 37121, 2, false, true),
             Parameter::new("active_grid_A_B_voltage", ParamKind::NumberI32(None), None, Some("V"), 10, 37126, 2, false, true),
             Parameter::new("active_grid_B_C_voltage", ParamKind::NumberI32(None), None, Some("V"), 10, 37128, 2, false, true),
             Parameter::new("active_grid_C_A_voltage", ParamKind::NumberI32(None), None, Some("V"), 10, 37130, 2, false, true),
@@ -1006,49 +1182,31 @@ impl Sun2000 {
         ]
     }
 
-    async fn save_to_influxdb(
-        client: influxdb::Client,
-        thread_name: &String,
-        param: Parameter,
-    ) -> Result<()> {
+    fn influx_timestamp() -> Timestamp {
         let start = SystemTime::now();
         let since_the_epoch = start
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards")
             .as_millis();
-
-        let mut query = Timestamp::Milliseconds(since_the_epoch).into_query(&param.name);
-        query = query.add_field("value", param.get_influx_value());
-
-        match client.query(&query).await {
-            Ok(msg) => {
-                debug!("{}: influxdb write success: {:?}", thread_name, msg);
-            }
-            Err(e) => {
-                error!("<i>{}</>: influxdb write error: <b>{:?}</>", thread_name, e);
-            }
-        }
-
-        Ok(())
+        Timestamp::Milliseconds(since_the_epoch)
     }
 
-    async fn save_ms_to_influxdb(
-        client: influxdb::Client,
-        thread_name: &String,
-        ms: u64,
-        param_count: usize,
-    ) -> Result<()> {
-        let start = SystemTime::now();
-        let since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis();
+    fn param_query(param: &Parameter) -> WriteQuery {
+        Sun2000::influx_timestamp()
+            .into_query(&param.name)
+            .add_field("value", param.get_influx_value())
+    }
 
-        let mut query = Timestamp::Milliseconds(since_the_epoch).into_query("inverter_query_time");
-        query = query.add_field("value", ms);
-        query = query.add_field("param_count", param_count as u8);
+    fn query_time_query(ms: u64, param_count: usize) -> WriteQuery {
+        Sun2000::influx_timestamp()
+            .into_query("inverter_query_time")
+            .add_field("value", ms)
+            .add_field("param_count", param_count as u8)
+    }
 
-        match client.query(&query).await {
+    /// Writes all points of a poll cycle in a single request
+    async fn save_to_influxdb(client: &Client, thread_name: &String, queries: &[WriteQuery]) {
+        match client.write(queries).await {
             Ok(msg) => {
                 debug!("{}: influxdb write success: {:?}", thread_name, msg);
             }
@@ -1056,8 +1214,6 @@ impl Sun2000 {
                 error!("<i>{}</>: influxdb write error: <b>{:?}</>", thread_name, e);
             }
         }
-
-        Ok(())
     }
 
     async fn read_params(
@@ -1065,7 +1221,7 @@ impl Sun2000 {
         mut ctx: Context,
         parameters: &Vec<Parameter>,
         initial_read: bool,
-    ) -> io::Result<(Context, Vec<Parameter>)> {
+    ) -> std::result::Result<(Context, Vec<Parameter>), Sun2000Error> {
         // connect to influxdb
         let client = match &self.influxdb_url {
             Some(url) => Some(Client::new(url, "sun2000")),
@@ -1073,7 +1229,7 @@ impl Sun2000 {
         };
 
         let mut params: Vec<Parameter> = vec![];
-        let mut disconnected = false;
+        let mut queries: Vec<WriteQuery> = vec![];
         let now = Instant::now();
         let mut params_wanted: Vec<_> = parameters
             .into_iter()
@@ -1092,167 +1248,108 @@ impl Sun2000 {
         params_wanted.sort_by(|a, b| a.reg_address.cmp(&b.reg_address));
 
         //group to 64-bytes register blocks
-        let mut reg_block = vec![];
-        let mut all_blocks = vec![];
-        let mut start_addr = None;
-        let mut pe = params_wanted.into_iter().peekable();
-        while pe.peek().is_some() {
-            let p = pe.next().unwrap();
-            if start_addr.is_none() {
-                start_addr = Some(p.reg_address);
-            } else {
-                if p.reg_address + p.len - start_addr.unwrap() > 64 {
-                    start_addr = Some(p.reg_address);
-                    all_blocks.push(reg_block);
-                    reg_block = vec![];
-                }
-            }
-            reg_block.push(p);
-        }
-        //add remainder
-        all_blocks.push(reg_block);
-
-        for (i, reg_block) in all_blocks.iter().enumerate() {
-            if disconnected {
-                break;
-            }
+        let all_blocks = group_register_blocks(params_wanted, |p| (p.reg_address, p.len));
 
+        for reg_block in all_blocks.iter() {
             let last = reg_block.last().unwrap();
             let start_addr = reg_block[0].reg_address;
             let len = last.reg_address + last.len - start_addr;
 
-            let mut attempts = 0;
-            while attempts < SUN2000_ATTEMPTS_PER_PARAM {
-                attempts = attempts + 1;
-                debug!(
-                    "-> obtaining register block #{} start={:#x}, len={}, attempt={}",
-                    i, start_addr, len, attempts
-                );
-                let retval = ctx.read_holding_registers(start_addr, len);
-                let read_res;
-                let start = Instant::now();
-                let read_time;
-                match timeout(Duration::from_secs_f32(5.0), retval).await {
-                    Ok(res) => {
-                        read_res = res;
-                        read_time = start.elapsed();
-                    }
-                    Err(e) => {
-                        let msg = format!(
-                            "<i>{}</i>: read timeout (attempt #{} of {}), register: <green><i>{:#x}+{}</>, error: <b>{}</>",
-                            self.name, attempts, SUN2000_ATTEMPTS_PER_PARAM, start_addr, len, e
+            match read_register_block(
+                &mut ctx,
+                &self.name,
+                false,
+                start_addr,
+                len,
+                SUN2000_ATTEMPTS_PER_PARAM,
+            )
+            .await
+            {
+                BlockRead::Data(data) => {
+                    for p in reg_block {
+                        let offset = (p.reg_address - start_addr) as usize;
+                        let data = &data[offset..offset + (p.len as usize)];
+                        debug!(
+                            "-> parsing {} ({:?}) @ {:#x} offset={:#x} len={}...",
+                            p.name, p.desc, p.reg_address, offset, p.len
                         );
-                        if attempts == SUN2000_ATTEMPTS_PER_PARAM {
-                            error!("{}", msg);
-                            break;
-                        } else {
-                            warn!("{}", msg);
-                            continue;
-                        };
-                    }
-                }
-                match read_res {
-                    Ok(data) => {
-                        if read_time > Duration::from_secs_f32(3.5) {
-                            warn!(
-                                "<i>{}</i>: inverter has lagged during read, register: <green><i>{:#x}+{}</>, read time: <b>{:?}</>",
-                                self.name, start_addr, len, read_time
-                            );
-                        }
-
-                        for p in reg_block {
-                            let offset = (p.reg_address - start_addr) as usize;
-                            let data = &data[offset..offset + (p.len as usize)];
-                            debug!(
-                                "-> parsing {} ({:?}) @ {:#x} offset={:#x} len={}...",
-                                p.name, p.desc, p.reg_address, offset, p.len
-                            );
-                            let mut val;
-                            match &p.value {
-                                ParamKind::Text(_) => {
-                                    let bytes: Vec<u8> = data.iter().fold(vec![], |mut x, elem| {
-                                        if (elem >> 8) as u8 != 0 {
-                                            x.push((elem >> 8) as u8);
-                                        }
-                                        if (elem & 0xff) as u8 != 0 {
-                                            x.push((elem & 0xff) as u8);
-                                        }
-                                        x
-                                    });
-                                    let id = String::from_utf8(bytes).unwrap();
-                                    val = ParamKind::Text(Some(id));
-                                }
-                                ParamKind::NumberU16(_) => {
-                                    debug!("-> {} = {:?}", p.name, data);
-                                    val = ParamKind::NumberU16(Some(data[0] as u16));
-                                }
-                                ParamKind::NumberI16(_) => {
-                                    debug!("-> {} = {:?}", p.name, data);
-                                    val = ParamKind::NumberI16(Some(data[0] as i16));
-                                }
-                                ParamKind::NumberU32(_) => {
-                                    let new_val: u32 = ((data[0] as u32) << 16) | data[1] as u32;
-                                    debug!("-> {} = {:X?} {:X}", p.name, data, new_val);
-                                    val = ParamKind::NumberU32(Some(new_val));
-                                    if p.unit.unwrap_or_default() == "epoch" && new_val == 0 {
-                                        //zero epoch makes no sense, let's set it to None
-                                        val = ParamKind::NumberU32(None);
+                        let mut val;
+                        match &p.value {
+                            ParamKind::Text(_) => {
+                                let bytes: Vec<u8> = data.iter().fold(vec![], |mut x, elem| {
+                                    if (elem >> 8) as u8 != 0 {
+                                        x.push((elem >> 8) as u8);
+                                    }
+                                    if (elem & 0xff) as u8 != 0 {
+                                        x.push((elem & 0xff) as u8);
+                                    }
+                                    x
+                                });
+                                match String::from_utf8(bytes) {
+                                    Ok(id) => val = ParamKind::Text(Some(id)),
+                                    Err(e) => {
+                                        self.errors.report(&Sun2000Error::InvalidData(
+                                            p.name.clone(),
+                                            e.to_string(),
+                                        ));
+                                        continue;
                                     }
-                                }
-                                ParamKind::NumberI32(_) => {
-                                    let new_val: i32 =
-                                        ((data[0] as i32) << 16) | (data[1] as u32) as i32;
-                                    debug!("-> {} = {:X?} {:X}", p.name, data, new_val);
-                                    val = ParamKind::NumberI32(Some(new_val));
                                 }
                             }
-                            let param = Parameter::new_from_string(
-                                p.name.clone(),
-                                val,
-                                p.desc.clone(),
-                                p.unit.clone(),
-                                p.gain,
-                                p.reg_address,
-                                p.len,
-                                p.initial_read,
-                                p.save_to_influx,
-                            );
-                            params.push(param.clone());
-
-                            //write data to influxdb if configured
-                            if let Some(c) = client.clone() {
-                                if !initial_read && p.save_to_influx {
-                                    let _ = Sun2000::save_to_influxdb(c, &self.name, param).await;
+                            ParamKind::NumberU16(_) => {
+                                debug!("-> {} = {:?}", p.name, data);
+                                val = ParamKind::NumberU16(Some(data[0] as u16));
+                            }
+                            ParamKind::NumberI16(_) => {
+                                debug!("-> {} = {:?}", p.name, data);
+                                val = ParamKind::NumberI16(Some(data[0] as i16));
+                            }
+                            ParamKind::NumberU32(_) => {
+                                let new_val: u32 = ((data[0] as u32) << 16) | data[1] as u32;
+                                debug!("-> {} = {:X?} {:X}", p.name, data, new_val);
+                                val = ParamKind::NumberU32(Some(new_val));
+                                if p.unit.unwrap_or_default() == "epoch" && new_val == 0 {
+                                    //zero epoch makes no sense, let's set it to None
+                                    val = ParamKind::NumberU32(None);
                                 }
                             }
+                            ParamKind::NumberI32(_) => {
+                                let new_val: i32 =
+                                    ((data[0] as i32) << 16) | (data[1] as u32) as i32;
+                                debug!("-> {} = {:X?} {:X}", p.name, data, new_val);
+                                val = ParamKind::NumberI32(Some(new_val));
+                            }
                         }
-                        //we parsed all parameters in this block,
-                        //break the attempt loop and try next register block
-                        break;
-                    }
-                    Err(e) => {
-                        let msg = format!(
-                            "<i>{}</i>: read error (attempt #{} of {}), register: <green><i>{:#x}+{}</>, error: <b>{}</>, read time: <b>{:?}</>",
-                            self.name, attempts, SUN2000_ATTEMPTS_PER_PARAM, start_addr, len, e, read_time
+                        let param = Parameter::new_from_string(
+                            p.name.clone(),
+                            val,
+                            p.desc.clone(),
+                            p.unit.clone(),
+                            p.gain,
+                            p.reg_address,
+                            p.len,
+                            p.initial_read,
+                            p.save_to_influx,
                         );
-                        match e.kind() {
-                            ErrorKind::BrokenPipe | ErrorKind::ConnectionReset => {
-                                error!("{}", msg);
-                                disconnected = true;
-                                break;
-                            }
-                            _ => {
-                                if attempts == SUN2000_ATTEMPTS_PER_PARAM {
-                                    error!("{}", msg);
-                                    break;
-                                } else {
-                                    warn!("{}", msg);
-                                    continue;
-                                };
-                            }
+                        params.push(param.clone());
+
+                        //publish the value to mqtt
+                        if !initial_read && p.save_to_influx {
+                            let _ = self.mqtt_transmitter.send(MqttEvent::new(
+                                format!("sun2000/{}", param.name),
+                                param.get_influx_value(),
+                                false,
+                            ));
+                        }
+
+                        //collect data for influxdb if configured
+                        if client.is_some() && !initial_read && p.save_to_influx {
+                            queries.push(Sun2000::param_query(&param));
                         }
                     }
                 }
+                BlockRead::Failed => {}
+                BlockRead::Disconnected => return Err(Sun2000Error::Disconnected),
             }
         }
 
@@ -1265,27 +1362,38 @@ impl Sun2000 {
             ms
         );
 
-        //save query time
+        //save the values with the query time
         if let Some(c) = client {
-            let _ = Sun2000::save_ms_to_influxdb(c, &self.name, ms, params.len()).await;
+            queries.push(Sun2000::query_time_query(ms, params.len()));
+            Sun2000::save_to_influxdb(&c, &self.name, &queries).await;
         }
         Ok((ctx, params))
     }
 
-    pub fn attribute_parser(&self, mut a: Vec<u8>) -> Result<()> {
+    pub fn attribute_parser(&self, mut a: Vec<u8>) -> std::result::Result<(), Sun2000Error> {
         //search for 'Description about the first device' (0x88)
         if let Some(index) = a.iter().position(|&x| x == 0x88) {
             //strip beginning bytes up to descriptor start
             a.drain(0..=index);
 
             //next (first) byte is len
-            let len = a[0] as usize;
+            let len = match a.first() {
+                Some(&len) if (len as usize) < a.len() => len as usize,
+                _ => {
+                    return Err(Sun2000Error::InvalidData(
+                        "device description".into(),
+                        format!("truncated descriptor: {:02X?}", a),
+                    ))
+                }
+            };
 
             //leave only the relevant descriptor string
             a = a.drain(1..=len).collect();
 
             //convert it to string
-            let x = String::from_utf8(a)?;
+            let x = String::from_utf8(a).map_err(|e| {
+                Sun2000Error::InvalidData("device description".into(), e.to_string())
+            })?;
 
             //split by semicolons
             let split = x.split(";");
@@ -1314,9 +1422,68 @@ impl Sun2000 {
         Ok(())
     }
 
-    #[rustfmt::skip]
+    fn grid_state_changed(&self, off_grid: bool, voltage: Option<f32>, frequency: Option<f32>) {
+        if off_grid {
+            warn!(
+                "<i>{}</>: ⚡ grid lost, running <b>off-grid</> (voltage: {:?} V, frequency: {:?} Hz)",
+                self.name, voltage, frequency
+            );
+        } else {
+            info!(
+                "<i>{}</>: ⚡ grid is back, running <b>on-grid</>",
+                self.name
+            );
+        }
+        events::publish(
+            &self.events,
+            Event::GridState {
+                inverter: self.name.clone(),
+                off_grid,
+                voltage,
+                frequency,
+            },
+        );
+        let _ = self.mqtt_transmitter.send(MqttEvent::new(
+            "sun2000/off_grid",
+            if off_grid { "on" } else { "off" },
+            true,
+        ));
+
+        //load shedding: turn off the tagged relays when running from the battery
+        if off_grid {
+            if let Some(ref tag) = self.load_shedding_tag {
+                let _ = self.ow_transmitter.send(OneWireTask {
+                    command: TaskCommand::TurnOff,
+                    id_relay: None,
+                    tag_group: Some(tag.clone()),
+                    id_yeelight: None,
+                    id_plug: None,
+                    duration: None,
+                });
+            }
+        }
+
+        //run a shell script for the notification
+        if let Some(ref command) = self.mode_change_script {
+            let cmd = str::replace(
+                command,
+                "%mode%",
+                if off_grid { "off-grid" } else { "on-grid" },
+            );
+            thread::spawn(move || StateMachine::run_shell_command(cmd));
+        }
+    }
+
     pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
         info!("<i>{}</>: Starting task", self.name);
+        let result = self.poll_loop(worker_cancel_flag).await;
+        systemd::heartbeat_stop(&self.name);
+        info!("{}: task stopped", self.name);
+        result
+    }
+
+    #[rustfmt::skip]
+    async fn poll_loop(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
         let mut poll_interval = Instant::now();
         let mut stats_interval = Instant::now();
         let mut terminated = false;
@@ -1337,8 +1504,14 @@ impl Sun2000 {
             if terminated || worker_cancel_flag.load(Ordering::SeqCst) {
                 break;
             }
+            systemd::heartbeat(&self.name);
 
-            let socket_addr = self.host_port.parse().unwrap();
+            let socket_addr = match self.host_port.parse() {
+                Ok(socket_addr) => socket_addr,
+                Err(e) => {
+                    return self.handle_error(Sun2000Error::InvalidAddress(self.host_port.clone(), e));
+                }
+            };
 
             let slave;
             if self.dongle_connection {
@@ -1354,8 +1527,8 @@ impl Sun2000 {
             let conn;
             match timeout(Duration::from_secs(5), retval).await {
                 Ok(res) => { conn = res; }
-                Err(e) => {
-                    error!("<i>{}</>: connect timeout: <b>{}</>", self.name, e);
+                Err(_) => {
+                    self.handle_error(Sun2000Error::ConnectTimeout)?;
                     tokio::time::sleep(Duration::from_secs(2)).await;
                     continue;
                 }
@@ -1369,7 +1542,14 @@ impl Sun2000 {
                     tokio::time::sleep(Duration::from_secs(2)).await;
 
                     //obtaining all parameters from inverter
-                    let (new_ctx, params) = self.read_params(ctx, &parameters, true).await?;
+                    let (new_ctx, params) = match self.read_params(ctx, &parameters, true).await {
+                        Ok(result) => result,
+                        Err(e) => {
+                            self.handle_error(e)?;
+                            tokio::time::sleep(Duration::from_secs(2)).await;
+                            continue;
+                        }
+                    };
                     ctx = new_ctx;
                     let mut nb_pv_strings: Option<u16> = None;
                     for p in &params {
@@ -1433,6 +1613,7 @@ impl Sun2000 {
 
                     if self.battery_installed {
                         info!("<i>{}</>: config: battery installed", self.name);
+                        parameters.push(Parameter::new("storage_state_of_capacity", ParamKind::NumberU16(None), None, Some("%"), 10, 37004, 1, false, true));
                         parameters.push(Parameter::new("storage_working_mode", ParamKind::NumberI16(None), None, Some("storage_working_mode_enum"), 1, 47004, 1, false, true));
                         parameters.push(Parameter::new("storage_time_of_use_price", ParamKind::NumberI16(None), None, Some("storage_tou_price_enum"), 1, 47027, 1, false, true));
                         parameters.push(Parameter::new("storage_lcoe", ParamKind::NumberU32(None), None, None, 1000, 47069, 2, false, true));
@@ -1447,6 +1628,11 @@ impl Sun2000 {
                         parameters.push(Parameter::new("storage_current_day_discharge_capacity", ParamKind::NumberU32(None), None, Some("kWh"), 100, 37017, 2, false, true));
                     }
 
+                    if self.backup_box {
+                        info!("<i>{}</>: config: backup box installed", self.name);
+                        parameters.push(Parameter::new("backup_power_soc", ParamKind::NumberU16(None), None, Some("%"), 10, SUN2000_BACKUP_SOC_REGISTER, 1, false, true));
+                    }
+
                     // obtain Device Description Definition
                     use tokio_modbus::prelude::*;
                     let retval = ctx.call(Request::Custom(0x2b, vec![0x0e, 0x03, 0x87]));
@@ -1455,7 +1641,9 @@ impl Sun2000 {
                             Ok(rsp) => match rsp {
                                 Response::Custom(f, rsp) => {
                                     debug!("<i>{}</>: Result for function {} is '{:?}'", self.name, f, rsp);
-                                    let _ = self.attribute_parser(rsp);
+                                    if let Err(e) = self.attribute_parser(rsp) {
+                                        self.handle_error(e)?;
+                                    }
                                 }
                                 _ => {
                                     error!("<i>{}</>: unexpected Reading Device Identifiers (0x2B) result", self.name);
@@ -1476,16 +1664,25 @@ impl Sun2000 {
                             debug!("<i>{}</>: Got terminate signal from main", self.name);
                             terminated = true;
                         }
+                        systemd::heartbeat(&self.name);
 
                         if terminated
-                            || stats_interval.elapsed()
-                                > Duration::from_secs_f32(SUN2000_STATS_DUMP_INTERVAL_SECS)
+                            || stats_interval.elapsed() > self.stats_interval
                         {
                             stats_interval = Instant::now();
                             info!(
-                                "<i>{}</>: 📊 inverter query statistics: ok: <b>{}</>, errors: <b>{}</>, daily energy yield: <b>{:.1} kWh</>",
+                                "<i>{}</>: 📊 inverter query statistics: ok: <b>{}</>, errors: <b>{}</>, daily energy yield: <b>{}</>",
                                 self.name, self.poll_ok, self.poll_errors,
-                                daily_yield_energy.unwrap_or_default() as f64 / 100.0,
+                                units::energy(daily_yield_energy.unwrap_or_default() as f64 / 100.0, 1),
+                            );
+
+                            let net = self.energy_costs.read().unwrap().net_metering();
+                            info!(
+                                "<i>{}</>: 📊 grid import/export today: <b>{}</> / <b>{}</>, week: <b>{}</> / <b>{}</>, month: <b>{}</> / <b>{}</>",
+                                self.name,
+                                units::energy(net.today.imported_kwh, 1), units::energy(net.today.exported_kwh, 1),
+                                units::energy(net.week.imported_kwh, 1), units::energy(net.week.exported_kwh, 1),
+                                units::energy(net.month.imported_kwh, 1), units::energy(net.month.exported_kwh, 1),
                             );
 
                             //push daily yield to postgres
@@ -1500,9 +1697,24 @@ impl Sun2000 {
                             }
                         }
 
-                        if poll_interval.elapsed()
-                            > Duration::from_secs_f32(SUN2000_POLL_INTERVAL_SECS)
-                        {
+                        //pending change of the backup power SOC
+                        let requested_soc = self.backup_soc_request.lock().unwrap().take();
+                        if let Some(soc) = requested_soc {
+                            let retval = ctx.write_single_register(SUN2000_BACKUP_SOC_REGISTER, (soc * 10.0) as u16);
+                            match timeout(Duration::from_secs_f32(5.0), retval).await {
+                                Ok(Ok(_)) => {
+                                    info!("<i>{}</>: 🔋 backup power SOC set to <b>{}%</>", self.name, soc);
+                                }
+                                Ok(Err(e)) => {
+                                    error!("<i>{}</>: unable to set backup power SOC: <b>{}</>", self.name, e);
+                                }
+                                Err(e) => {
+                                    error!("<i>{}</>: write timeout setting backup power SOC: <b>{}</>", self.name, e);
+                                }
+                            }
+                        }
+
+                        if poll_interval.elapsed() > self.poll_interval {
                             poll_interval = Instant::now();
                             let mut device_status: Option<u16> = None;
                             let mut storage_status: Option<i16> = None;
@@ -1514,10 +1726,25 @@ impl Sun2000 {
                             let mut alarm_2: Option<u16> = None;
                             let mut alarm_3: Option<u16> = None;
                             let mut active_power: Option<i32> = None;
+                            let mut grid_exported_energy: Option<i32> = None;
+                            let mut grid_accumulated_energy: Option<u32> = None;
+                            let mut accumulated_yield_energy: Option<u32> = None;
+                            let mut phase_a_voltage: Option<u16> = None;
+                            let mut grid_frequency: Option<u16> = None;
+                            let mut storage_soc: Option<u16> = None;
+                            let mut input_power: Option<i32> = None;
+                            let mut power_meter_active_power: Option<i32> = None;
 
                             //obtaining all parameters from inverter
-                            let (new_ctx, params) =
-                                self.read_params(ctx, &parameters, false).await?;
+                            let (new_ctx, params) = match self.read_params(ctx, &parameters, false).await {
+                                Ok(result) => result,
+                                Err(e) => {
+                                    //reconnecting
+                                    self.poll_errors = self.poll_errors + 1;
+                                    self.handle_error(e)?;
+                                    break;
+                                }
+                            };
                             ctx = new_ctx;
                             for p in &params {
                                 match p.value {
@@ -1540,6 +1767,9 @@ impl Sun2000 {
                                         "alarm_1" => alarm_1 = n,
                                         "alarm_2" => alarm_2 = n,
                                         "alarm_3" => alarm_3 = n,
+                                        "phase_A_voltage" => phase_a_voltage = n,
+                                        "grid_frequency" => grid_frequency = n,
+                                        "storage_state_of_capacity" => storage_soc = n,
                                         _ => {}
                                     },
                                     ParamKind::NumberI16(n) => match p.name.as_ref() {
@@ -1549,10 +1779,15 @@ impl Sun2000 {
                                     ParamKind::NumberU32(n) => match p.name.as_ref() {
                                         "state_3" => state_3 = n,
                                         "daily_yield_energy" => daily_yield_energy = n,
+                                        "grid_accumulated_energy" => grid_accumulated_energy = n,
+                                        "accumulated_yield_energy" => accumulated_yield_energy = n,
                                         _ => {}
                                     },
                                     ParamKind::NumberI32(n) => match p.name.as_ref() {
                                         "active_power" => active_power = n,
+                                        "input_power" => input_power = n,
+                                        "power_meter_active_power" => power_meter_active_power = n,
+                                        "grid_exported_energy" => grid_exported_energy = n,
                                         _ => {}
                                     },
                                     _ => {}
@@ -1565,14 +1800,17 @@ impl Sun2000 {
                                 s.name.ends_with("_status") ||
                                 s.name.ends_with("_code")).count();
                             if params.len() != param_count {
-                                error!("<i>{}</>: problem obtaining a complete parameter list (read: {}, expected: {}), reconnecting...", self.name, params.len(), param_count);
+                                //reconnecting
                                 self.poll_errors = self.poll_errors + 1;
+                                self.handle_error(Sun2000Error::IncompleteRead { read: params.len(), expected: param_count })?;
                                 break;
                             } else {
                                 self.poll_ok = self.poll_ok + 1;
                             }
 
                             //setting new inverter state/alarm
+                            let previous_status = state.device_status;
+                            let previous_alarms = (state.alarm_1, state.alarm_2, state.alarm_3);
                             state.set_new_status(
                                 &self.name,
                                 device_status,
@@ -1585,14 +1823,107 @@ impl Sun2000 {
                                 alarm_2,
                                 alarm_3,
                             );
+                            if previous_alarms != (state.alarm_1, state.alarm_2, state.alarm_3) {
+                                self.notify_alarms(&state, previous_alarms);
+                            }
+                            if let Some(status) = device_status {
+                                if previous_status != device_status {
+                                    events::publish(
+                                        &self.events,
+                                        Event::InverterMode {
+                                            inverter: self.name.clone(),
+                                            mode: Sun2000State::get_device_status_description(
+                                                status,
+                                            )
+                                            .to_string(),
+                                        },
+                                    );
+                                }
+                            }
+
+                            //backup box: on-grid/off-grid transitions
+                            if let Some(status) = device_status {
+                                let off_grid = Sun2000State::is_off_grid(status);
+                                if previous_status.map_or(false, Sun2000State::is_off_grid) != off_grid {
+                                    self.grid_state_changed(
+                                        off_grid,
+                                        phase_a_voltage.map(|v| v as f32 / 10.0),
+                                        grid_frequency.map(|f| f as f32 / 100.0),
+                                    );
+                                }
+                            }
+
+                            //energy counters for the cost calculation
+                            if let (Some(imported), Some(exported), Some(pv_yield)) = (
+                                grid_accumulated_energy,
+                                grid_exported_energy,
+                                accumulated_yield_energy,
+                            ) {
+                                self.energy_costs.write().unwrap().update_meter(
+                                    imported as f64 / 100.0,
+                                    exported as f64 / 100.0,
+                                    pv_yield as f64 / 100.0,
+                                );
+                            }
+
+                            //positive meter power means exporting to the grid
+                            if let Some(power) = power_meter_active_power {
+                                GridExport::update(&self.grid_export, "sun2000", power as f32);
+                            }
+
+                            //energy surplus automation rules
+                            if !self.rules.is_empty() {
+                                //the P1 meter values and the variables are usable in the rules too
+                                let mut values = self.sensor_values.read().unwrap().clone();
+                                let mut set = |name: &str, value: Option<f32>| {
+                                    if let Some(value) = value {
+                                        values.insert(name.to_string(), value);
+                                    }
+                                };
+                                set("soc", storage_soc.map(|x| x as f32 / 10.0));
+                                set("active_power", active_power.map(|x| x as f32));
+                                set("input_power", input_power.map(|x| x as f32));
+                                //positive meter power means exporting to the grid
+                                set("grid_export", power_meter_active_power.map(|x| x as f32));
+                                if !values.contains_key("grid_export") {
+                                    let p1 = |name: &str| values.get(name).copied();
+                                    let export = match (
+                                        p1(DSMR_EXPORT_POWER_VALUE),
+                                        p1(DSMR_IMPORT_POWER_VALUE),
+                                    ) {
+                                        (Some(export), import) => {
+                                            Some(export - import.unwrap_or_default())
+                                        }
+                                        _ => None,
+                                    };
+                                    if let Some(export) = export {
+                                        values.insert("grid_export".to_string(), export);
+                                    }
+                                }
+                                for rule in &mut self.rules {
+                                    if let Some(task) = rule.evaluate(&values) {
+                                        let _ = self.ow_transmitter.send(task);
+                                    }
+                                }
+                            }
+
+                            events::publish(
+                                &self.events,
+                                Event::InverterPower {
+                                    inverter: self.name.clone(),
+                                    active_power: active_power.map(|x| x as f32),
+                                    input_power: input_power.map(|x| x as f32),
+                                    soc: storage_soc.map(|x| x as f32 / 10.0),
+                                },
+                            );
 
                             //pass PV info to Lcdproc
                             let task = LcdTask {
                                 command: LcdTaskCommand::SetLineText,
                                 int_arg: 0,
-                                string_arg: Some(format!("PV {} W, {:.1} kWh",
+                                string_arg: Some(format!("PV {} W, {}",
                                     active_power.unwrap_or_default(),
-                                    daily_yield_energy.unwrap_or_default() as f64 / 100.0,
+                                    units::energy(daily_yield_energy.unwrap_or_default() as f64 / 100.0, 1),
                                 ))};
                             let _ = self.lcd_transmitter.send(task);
 
@@ -1613,13 +1944,178 @@ impl Sun2000 {
                     }
                 }
                 Err(e) => {
-                    error!("<i>{}</>: connection error: <b>{}</>", self.name, e);
+                    self.handle_error(Sun2000Error::Connect(e))?;
                     tokio::time::sleep(Duration::from_secs(2)).await;
                 }
             }
         }
-
-        info!("{}: task stopped", self.name);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::WorkerErrors;
+    use crate::queue::{self, OverflowPolicy, QueueMetrics, Receiver};
+    use crate::test_support::{FakeInfluxDb, MockModbusServer, TEST_TIMEOUT};
+    use tokio::sync::broadcast;
+
+    /// Inverter without a reachable device, for the error handling tests
+    fn unreachable_inverter(
+        host_port: &str,
+    ) -> (Sun2000, Receiver<Notification>, Arc<RwLock<WorkerErrors>>) {
+        let metrics = Arc::new(RwLock::new(QueueMetrics::default()));
+        let (lcd_tx, _lcd_rx) = queue::bounded("lcd", 100, OverflowPolicy::DropOldest, &metrics);
+        let (db_tx, _db_rx) = queue::bounded("db", 100, OverflowPolicy::DropOldest, &metrics);
+        let (mqtt_tx, _mqtt_rx) = queue::bounded("mqtt", 100, OverflowPolicy::DropOldest, &metrics);
+        let (notify_tx, notify_rx) =
+            queue::bounded("notify", 100, OverflowPolicy::DropOldest, &metrics);
+        let (ow_tx, _ow_rx) = queue::bounded("ow", 100, OverflowPolicy::DropOldest, &metrics);
+        let worker_errors = Arc::new(RwLock::new(WorkerErrors::default()));
+        let sun2000 = Sun2000 {
+            name: "sun2000".to_string(),
+            host_port: host_port.to_string(),
+            poll_ok: 0,
+            poll_errors: 0,
+            influxdb_url: None,
+            lcd_transmitter: lcd_tx,
+            db_transmitter: db_tx,
+            mqtt_transmitter: mqtt_tx,
+            notify_transmitter: notify_tx.clone(),
+            energy_costs: Arc::new(RwLock::new(EnergyCosts::default())),
+            events: broadcast::channel(16).0,
+            ow_transmitter: ow_tx,
+            mode_change_script: None,
+            optimizers: false,
+            battery_installed: false,
+            dongle_connection: false,
+            backup_box: false,
+            load_shedding_tag: None,
+            backup_soc_request: Arc::new(Mutex::new(None)),
+            rules: vec![],
+            sensor_values: Arc::new(RwLock::new(SensorValues::new())),
+            grid_export: Arc::new(RwLock::new(None)),
+            poll_interval: Duration::from_millis(100),
+            stats_interval: Duration::from_secs(3600),
+            errors: ErrorReporter {
+                worker: "sun2000".to_string(),
+                metrics: worker_errors.clone(),
+                notify_transmitter: notify_tx,
+            },
+        };
+        (sun2000, notify_rx, worker_errors)
+    }
+
+    #[tokio::test]
+    async fn worker_polls_the_inverter_and_writes_to_influxdb() {
+        let server = MockModbusServer::sun2000().await;
+        let influx = FakeInfluxDb::start().await;
+        let metrics = Arc::new(RwLock::new(QueueMetrics::default()));
+        let (lcd_tx, _lcd_rx) = queue::bounded("lcd", 100, OverflowPolicy::DropOldest, &metrics);
+        let (db_tx, _db_rx) = queue::bounded("db", 100, OverflowPolicy::DropOldest, &metrics);
+        let (mqtt_tx, mqtt_rx) = queue::bounded("mqtt", 1000, OverflowPolicy::DropOldest, &metrics);
+        let (notify_tx, _notify_rx) =
+            queue::bounded("notify", 100, OverflowPolicy::DropOldest, &metrics);
+        let (ow_tx, _ow_rx) = queue::bounded("ow", 100, OverflowPolicy::DropOldest, &metrics);
+        let backup_soc_request = Arc::new(Mutex::new(Some(25.0)));
+        let worker_errors = Arc::new(RwLock::new(WorkerErrors::default()));
+        let mut sun2000 = Sun2000 {
+            name: "sun2000".to_string(),
+            host_port: server.host_port.clone(),
+            poll_ok: 0,
+            poll_errors: 0,
+            influxdb_url: Some(influx.url.clone()),
+            lcd_transmitter: lcd_tx,
+            db_transmitter: db_tx,
+            mqtt_transmitter: mqtt_tx,
+            notify_transmitter: notify_tx.clone(),
+            energy_costs: Arc::new(RwLock::new(EnergyCosts::default())),
+            events: broadcast::channel(16).0,
+            ow_transmitter: ow_tx,
+            mode_change_script: None,
+            optimizers: false,
+            battery_installed: false,
+            dongle_connection: false,
+            backup_box: true,
+            load_shedding_tag: None,
+            backup_soc_request: backup_soc_request.clone(),
+            rules: vec![],
+            sensor_values: Arc::new(RwLock::new(SensorValues::new())),
+            grid_export: Arc::new(RwLock::new(None)),
+            poll_interval: Duration::from_millis(100),
+            stats_interval: Duration::from_secs(3600),
+            errors: ErrorReporter {
+                worker: "sun2000".to_string(),
+                metrics: worker_errors.clone(),
+                notify_transmitter: notify_tx,
+            },
+        };
+
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+        let stop = cancel_flag.clone();
+        let watcher = influx.clone();
+        tokio::spawn(async move {
+            watcher.wait_for("active_power", TEST_TIMEOUT).await;
+            stop.store(true, Ordering::SeqCst);
+        });
+        sun2000.worker(cancel_flag).await.unwrap();
+
+        let writes = influx.writes();
+        assert!(writes
+            .iter()
+            .all(|w| w.target.starts_with("/write?db=sun2000")));
+        let line = influx.lines("active_power").pop().expect("no active_power");
+        assert!(line.starts_with("active_power value=3210i "), "{}", line);
+        let line = influx.lines("grid_frequency").pop().unwrap();
+        assert!(line.starts_with("grid_frequency value=50.01 "), "{}", line);
+        let line = influx.lines("pv_01_voltage").pop().unwrap();
+        assert!(line.starts_with("pv_01_voltage value=360.5 "), "{}", line);
+        assert!(influx
+            .lines("power_meter_active_power")
+            .iter()
+            .all(|line| line.starts_with("power_meter_active_power value=-450i ")));
+        assert!(!influx.lines("inverter_query_time").is_empty());
+        assert_eq!(sun2000.poll_errors, 0);
+        assert!(sun2000.poll_ok > 0);
+        assert!(worker_errors.read().unwrap().counts.is_empty());
+
+        //the pending backup SOC request is written in tenths of a percent
+        assert!(backup_soc_request.lock().unwrap().is_none());
+        assert_eq!(server.register(SUN2000_BACKUP_SOC_REGISTER), 250);
+
+        //the polled values are published to mqtt and the meter power to the energy manager
+        let topics: Vec<String> = std::iter::from_fn(|| mqtt_rx.try_recv().ok())
+            .map(|event| event.topic)
+            .collect();
+        assert!(topics.contains(&"sun2000/active_power".to_string()));
+        assert_eq!(sun2000.grid_export.read().unwrap().unwrap().power, -450.0);
+    }
+
+    #[tokio::test]
+    async fn worker_stops_on_an_invalid_address() {
+        let (mut sun2000, notify_rx, worker_errors) = unreachable_inverter("inverter.lan");
+
+        let result = sun2000.worker(Arc::new(AtomicBool::new(false))).await;
+
+        assert!(result.is_err());
+        let worker_errors = worker_errors.read().unwrap();
+        assert_eq!(
+            worker_errors
+                .counts
+                .get(&("sun2000".to_string(), "config", false)),
+            Some(&1)
+        );
+        let notification = notify_rx.try_recv().expect("no notification");
+        assert_eq!(notification.severity, Severity::Critical);
+    }
+
+    #[test]
+    fn truncated_device_description_is_an_error() {
+        let (sun2000, _notify_rx, _worker_errors) = unreachable_inverter("127.0.0.1:502");
+
+        let result = sun2000.attribute_parser(vec![0x01, 0x88, 0x20, b'1', b'=']);
+
+        assert!(matches!(result, Err(Sun2000Error::InvalidData(..))));
+    }
+}
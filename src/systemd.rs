@@ -0,0 +1,79 @@
+use simplelog::*;
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_HEARTBEAT_TIMEOUT_SECS: f32 = 120.0; //worker without a heartbeat for this time is considered hung
+pub const MIN_HEARTBEAT_TIMEOUT_SECS: f32 = 10.0;
+pub const HEARTBEAT_CHECK_INTERVAL_SECS: f32 = 10.0; //secs between checks when the systemd watchdog is disabled
+
+//last heartbeat of the running workers
+static HEARTBEATS: Mutex<Vec<(String, Instant)>> = Mutex::new(Vec::new());
+
+/// Sends a state change to the service manager, eg. `READY=1`, `WATCHDOG=1` or `STOPPING=1`.
+/// Does nothing when not started by systemd as a `Type=notify` service.
+pub fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let socket = match UnixDatagram::unbound() {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("systemd: unable to create notify socket: {:?}", e);
+            return;
+        }
+    };
+    let result = match path.strip_prefix('@') {
+        //socket in the abstract namespace
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes())
+            .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr)),
+        None => socket.send_to(state.as_bytes(), &path),
+    };
+    match result {
+        Ok(_) => debug!("systemd: notify: {}", state.replace('\n', ", ")),
+        Err(e) => error!("systemd: notify {:?} error: {:?}", state, e),
+    }
+}
+
+/// Watchdog ping interval (half of the service `WatchdogSec=`), `None` when the watchdog is disabled
+pub fn watchdog_interval() -> Option<Duration> {
+    //the watchdog may be meant for another process
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// Reports the worker is alive, called from each iteration of its main loop
+pub fn heartbeat(name: &str) {
+    let mut beats = HEARTBEATS.lock().unwrap();
+    match beats.iter_mut().find(|(worker, _)| worker == name) {
+        Some((_, time)) => *time = Instant::now(),
+        None => beats.push((name.to_string(), Instant::now())),
+    }
+}
+
+/// Removes the worker from the liveness checks when it stops on purpose
+pub fn heartbeat_stop(name: &str) {
+    HEARTBEATS
+        .lock()
+        .unwrap()
+        .retain(|(worker, _)| worker != name);
+}
+
+/// Workers without a heartbeat for longer than the given timeout
+pub fn stalled(timeout: Duration) -> Vec<String> {
+    HEARTBEATS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, time)| time.elapsed() > timeout)
+        .map(|(worker, _)| worker.clone())
+        .collect()
+}
@@ -0,0 +1,504 @@
+use crate::onewire::{get_w1_device_name, DS2408_INITIAL_STATE, W1_ROOT_OVERRIDE};
+use crate::skymax::{Skymax, SKYMAX_FRAME_END};
+use crc16::*;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(20); //max wait for a worker to do its job
+pub const MODBUS_ILLEGAL_FUNCTION: u8 = 0x01;
+
+/// Directory in the system temp dir, removed with its content when dropped
+pub struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(prefix: &str) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "hard-{}-{}-{}",
+            prefix,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).expect("unable to create a temp dir");
+        TempDir { path }
+    }
+
+    /// Writes a file in the directory and returns its path
+    pub fn write(&self, name: &str, content: &str) -> String {
+        let path = self.path.join(name);
+        fs::write(&path, content).expect("unable to write a temp file");
+        path.to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Polls the condition until it is true or the timeout elapses
+pub fn wait_until(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    condition()
+}
+
+/// Temporary w1 sysfs tree with the `<family>-<address>/<file>` layout, the onewire code
+/// uses it instead of `/sys/bus/w1/devices` until dropped; the root is process-global,
+/// so the tests holding a tree are serialized by [`W1_TREE_LOCK`]
+pub struct FakeW1Tree {
+    dir: TempDir,
+    _guard: MutexGuard<'static, ()>,
+}
+
+/// Held by the live [`FakeW1Tree`], a test failing with the tree poisons it harmlessly
+static W1_TREE_LOCK: Mutex<()> = Mutex::new(());
+
+impl FakeW1Tree {
+    pub fn new() -> Self {
+        let guard = W1_TREE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = TempDir::new("w1");
+        *W1_ROOT_OVERRIDE.write().unwrap() = Some(dir.path.to_string_lossy().into_owned());
+        FakeW1Tree { dir, _guard: guard }
+    }
+
+    /// Adds a device file and returns the device name
+    pub fn add(&self, family: u8, address: u64, file: &str, data: &[u8]) -> String {
+        let device = get_w1_device_name(family, address);
+        fs::create_dir_all(self.dir.path.join(&device)).expect("unable to create a w1 device");
+        self.write(&device, file, data);
+        device
+    }
+
+    /// DS2408 relay board with the relays turned off
+    pub fn add_relay_board(&self, family: u8, address: u64) -> String {
+        self.add(family, address, "output", &[DS2408_INITIAL_STATE])
+    }
+
+    pub fn write(&self, device: &str, file: &str, data: &[u8]) {
+        fs::write(self.dir.path.join(device).join(file), data).expect("unable to write a w1 file");
+    }
+
+    pub fn read(&self, device: &str, file: &str) -> Vec<u8> {
+        fs::read(self.dir.path.join(device).join(file)).unwrap_or_default()
+    }
+}
+
+impl Drop for FakeW1Tree {
+    fn drop(&mut self) {
+        *W1_ROOT_OVERRIDE.write().unwrap() = None;
+    }
+}
+
+/// DS2413 `state` byte with the given PIO inputs, the upper nibble is the complement
+pub fn ds2413_state(pio_a: bool, pio_b: bool) -> u8 {
+    //latches are off
+    let low = 0b1010 | pio_a as u8 | (pio_b as u8) << 2;
+    (!low << 4) | low
+}
+
+/// Modbus-TCP server answering from a register map, missing registers read as zero
+pub struct MockModbusServer {
+    pub host_port: String,
+    registers: Arc<Mutex<BTreeMap<u16, u16>>>,
+}
+
+impl MockModbusServer {
+    pub async fn start(registers: BTreeMap<u16, u16>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("unable to bind the modbus server");
+        let host_port = listener.local_addr().unwrap().to_string();
+        let registers = Arc::new(Mutex::new(registers));
+        let shared = registers.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_modbus(stream, shared.clone()));
+            }
+        });
+        MockModbusServer {
+            host_port,
+            registers,
+        }
+    }
+
+    /// Server with the Sun2000 register map of a producing inverter
+    pub async fn sun2000() -> Self {
+        MockModbusServer::start(sun2000_registers()).await
+    }
+
+    pub fn register(&self, address: u16) -> u16 {
+        self.registers
+            .lock()
+            .unwrap()
+            .get(&address)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+async fn serve_modbus(
+    mut stream: TcpStream,
+    registers: Arc<Mutex<BTreeMap<u16, u16>>>,
+) -> io::Result<()> {
+    loop {
+        //MBAP header: transaction id, protocol id, length, unit id
+        let mut header = [0u8; 7];
+        stream.read_exact(&mut header).await?;
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let mut pdu = vec![0u8; len.saturating_sub(1)];
+        stream.read_exact(&mut pdu).await?;
+
+        let reply = modbus_reply(&pdu, &registers);
+        let mut frame = header[..4].to_vec();
+        frame.extend_from_slice(&((reply.len() + 1) as u16).to_be_bytes());
+        frame.push(header[6]);
+        frame.extend(reply);
+        stream.write_all(&frame).await?;
+    }
+}
+
+fn modbus_reply(pdu: &[u8], registers: &Mutex<BTreeMap<u16, u16>>) -> Vec<u8> {
+    let function = pdu.first().copied().unwrap_or_default();
+    let word = |i: usize| pdu.get(i..i + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    let mut registers = registers.lock().unwrap();
+    match (function, word(1), word(3)) {
+        //read holding/input registers
+        (0x03 | 0x04, Some(address), Some(count)) if count <= 125 => {
+            let mut reply = vec![function, (count * 2) as u8];
+            for i in 0..count {
+                let value = registers
+                    .get(&address.wrapping_add(i))
+                    .copied()
+                    .unwrap_or_default();
+                reply.extend_from_slice(&value.to_be_bytes());
+            }
+            reply
+        }
+        //write single register
+        (0x06, Some(address), Some(value)) => {
+            registers.insert(address, value);
+            pdu[..5].to_vec()
+        }
+        //write multiple registers
+        (0x10, Some(address), Some(count)) => {
+            for i in 0..count {
+                if let Some(value) = word(6 + i as usize * 2) {
+                    registers.insert(address.wrapping_add(i), value);
+                }
+            }
+            pdu[..5].to_vec()
+        }
+        _ => vec![function | 0x80, MODBUS_ILLEGAL_FUNCTION],
+    }
+}
+
+fn set_u32(registers: &mut BTreeMap<u16, u16>, address: u16, value: u32) {
+    registers.insert(address, (value >> 16) as u16);
+    registers.insert(address + 1, value as u16);
+}
+
+fn set_text(registers: &mut BTreeMap<u16, u16>, address: u16, text: &str) {
+    for (i, chunk) in text.as_bytes().chunks(2).enumerate() {
+        let value = (chunk[0] as u16) << 8 | chunk.get(1).copied().unwrap_or_default() as u16;
+        registers.insert(address + i as u16, value);
+    }
+}
+
+/// Sun2000 register map: an on-grid inverter with two strings, producing 3210 W
+pub fn sun2000_registers() -> BTreeMap<u16, u16> {
+    let mut r = BTreeMap::new();
+    set_text(&mut r, 30000, "SUN2000-5KTL-M1");
+    set_text(&mut r, 30015, "HV2050123456");
+    set_text(&mut r, 30025, "01074954-004");
+    r.insert(30070, 428); //model_id
+    r.insert(30071, 2); //nb_pv_strings
+    r.insert(30072, 2); //nb_mpp_tracks
+    set_u32(&mut r, 30073, 5000); //rated_power
+    set_u32(&mut r, 30075, 5500); //P_max
+    r.insert(32000, 0x0006); //state_1: grid-connected, grid-connected normally
+    r.insert(32016, 3605); //pv_01_voltage
+    r.insert(32017, 512); //pv_01_current
+    r.insert(32018, 3587); //pv_02_voltage
+    r.insert(32019, 498); //pv_02_current
+    set_u32(&mut r, 32064, 3350); //input_power
+    r.insert(32069, 2301); //phase_A_voltage
+    r.insert(32070, 2297); //phase_B_voltage
+    r.insert(32071, 2310); //phase_C_voltage
+    set_u32(&mut r, 32072, 4650); //phase_A_current
+    set_u32(&mut r, 32080, 3210); //active_power
+    r.insert(32084, 999); //power_factor
+    r.insert(32085, 5001); //grid_frequency
+    r.insert(32086, 9820); //efficiency
+    r.insert(32087, 415); //internal_temperature
+    r.insert(32088, 3000); //insulation_resistance
+    r.insert(32089, 0x0200); //device_status: on-grid
+    set_u32(&mut r, 32106, 1234567); //accumulated_yield_energy
+    set_u32(&mut r, 32114, 1834); //daily_yield_energy
+    set_u32(&mut r, 37113, -450i32 as u32); //power_meter_active_power: importing
+    set_u32(&mut r, 37119, 523100); //grid_exported_energy
+    set_u32(&mut r, 37121, 412000); //grid_accumulated_energy
+    r.insert(42000, 114); //grid_code
+    r.insert(43006, 60); //time_zone
+    r
+}
+
+/// A single write received by the fake InfluxDB
+#[derive(Clone, Debug)]
+pub struct InfluxWrite {
+    /// request target with the query string, eg. `/write?db=sun2000&precision=n`
+    pub target: String,
+    pub body: String,
+}
+
+/// HTTP server accepting the line protocol writes of the InfluxDB clients
+#[derive(Clone)]
+pub struct FakeInfluxDb {
+    pub url: String,
+    writes: Arc<Mutex<Vec<InfluxWrite>>>,
+}
+
+impl FakeInfluxDb {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("unable to bind the influxdb server");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let writes = Arc::new(Mutex::new(vec![]));
+        let shared = writes.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_http(stream, shared.clone()));
+            }
+        });
+        FakeInfluxDb { url, writes }
+    }
+
+    pub fn writes(&self) -> Vec<InfluxWrite> {
+        self.writes.lock().unwrap().clone()
+    }
+
+    /// All received lines of the given measurement
+    pub fn lines(&self, measurement: &str) -> Vec<String> {
+        self.writes()
+            .iter()
+            .flat_map(|w| w.body.lines().map(String::from).collect::<Vec<_>>())
+            .filter(|line| {
+                line.strip_prefix(measurement)
+                    .map_or(false, |rest| rest.starts_with(' ') || rest.starts_with(','))
+            })
+            .collect()
+    }
+
+    /// Waits for the first line of the measurement
+    pub async fn wait_for(&self, measurement: &str, timeout: Duration) -> Option<String> {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if let Some(line) = self.lines(measurement).into_iter().next() {
+                return Some(line);
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        None
+    }
+}
+
+async fn serve_http(stream: TcpStream, writes: Arc<Mutex<Vec<InfluxWrite>>>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).await? == 0 {
+            return Ok(());
+        }
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or_default();
+                }
+            }
+        }
+        let mut body = vec![0u8; content_length];
+        reader.read_exact(&mut body).await?;
+        writes.lock().unwrap().push(InfluxWrite {
+            target: request_line
+                .split(' ')
+                .nth(1)
+                .unwrap_or_default()
+                .to_string(),
+            body: String::from_utf8_lossy(&body).into_owned(),
+        });
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n")
+            .await?;
+    }
+}
+
+/// Skymax frame: `(`, the data, the xmodem crc and CR
+pub fn skymax_frame(data: &str) -> Vec<u8> {
+    let mut frame = vec![b'('];
+    frame.extend_from_slice(data.as_bytes());
+    let crc = State::<XMODEM>::calculate(frame.as_slice());
+    frame.push(Skymax::fix_crc16_byte((crc >> 8) as u8));
+    frame.push(Skymax::fix_crc16_byte((crc & 0xff) as u8));
+    frame.push(SKYMAX_FRAME_END);
+    frame
+}
+
+/// Replies of a Skymax inverter running from the grid, the unknown commands are refused
+pub fn skymax_replies() -> HashMap<String, String> {
+    let mut replies = HashMap::new();
+    let mut reply = |command: &str, data: &str| {
+        replies.insert(command.to_string(), data.to_string());
+    };
+    reply("QID", "92931509101901");
+    reply("QVFW", "VERFW:00072.70");
+    reply(
+        "QPIRI",
+        "230.0 21.7 230.0 50.0 21.7 5000 4000 48.0 46.0 42.0 56.4 54.0 2 10 080 0 1 3 9 01 0 0 54.0 0 1",
+    );
+    reply(
+        "QPIGS",
+        "230.0 50.0 230.0 50.0 0161 0119 003 460 57.50 012 100 0069 0014 103.8 57.45 00000 00110110 00 00 00856 010",
+    );
+    reply("QPIWS", &"0".repeat(32));
+    reply("QMOD", "L");
+    replies
+}
+
+/// Pseudo-terminal pair in raw mode, the slave is kept open so the master is readable
+/// before (and after) the device is opened by the worker
+pub fn open_pty() -> io::Result<(File, File, PathBuf)> {
+    unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = File::from_raw_fd(fd);
+        if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut name = [0 as libc::c_char; 64];
+        if libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let path = PathBuf::from(CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned());
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+
+        //no echo and no CR/LF translation of the binary frames
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((master, slave, path))
+    }
+}
+
+/// Skymax inverter behind a hidraw-like device: a pseudo-terminal found through a fake
+/// USB sysfs tree
+pub struct FakeHidraw {
+    /// for `Skymax::device_path`
+    pub sysfs_path: String,
+    /// for `Skymax::device_usbid`
+    pub usb_id: String,
+    /// for `Skymax::device_dir`, the directory of the pseudo-terminal
+    pub dev_dir: String,
+    commands: Arc<Mutex<Vec<String>>>,
+    _sysfs: TempDir,
+    _slave: File,
+}
+
+impl FakeHidraw {
+    pub fn start(replies: HashMap<String, String>) -> Self {
+        let (mut master, slave, path) = open_pty().expect("unable to open a pseudo-terminal");
+        let usb_id = "0665:5161".to_string();
+        let sysfs = TempDir::new("hidraw");
+        let node = path.file_name().unwrap().to_string_lossy().into_owned();
+        fs::create_dir_all(
+            sysfs
+                .path
+                .join(format!("0003:{}.0001", usb_id.to_uppercase()))
+                .join("hidraw")
+                .join(node),
+        )
+        .expect("unable to create the usb sysfs tree");
+        let dev_dir = path
+            .parent()
+            .map(|dir: &Path| dir.to_string_lossy().into_owned())
+            .expect("pseudo-terminal without a directory");
+
+        let commands = Arc::new(Mutex::new(vec![]));
+        let received = commands.clone();
+        thread::spawn(move || {
+            let mut request = vec![];
+            let mut byte = [0u8; 1];
+            //ends with an I/O error when all slave ends are closed
+            while let Ok(1) = master.read(&mut byte) {
+                if byte[0] != SKYMAX_FRAME_END {
+                    request.push(byte[0]);
+                    continue;
+                }
+                //the command is followed by its crc
+                request.truncate(request.len().saturating_sub(2));
+                let command = String::from_utf8_lossy(&request).into_owned();
+                request.clear();
+                let data = replies
+                    .get(&command)
+                    .cloned()
+                    .unwrap_or_else(|| "NAK".to_string());
+                received.lock().unwrap().push(command);
+                if master.write_all(&skymax_frame(&data)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        FakeHidraw {
+            sysfs_path: sysfs.path.to_string_lossy().into_owned(),
+            usb_id,
+            dev_dir,
+            commands,
+            _sysfs: sysfs,
+            _slave: slave,
+        }
+    }
+
+    /// Commands received so far, without the crc
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+}
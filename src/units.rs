@@ -0,0 +1,140 @@
+use chrono::{DateTime, Local, TimeZone, Utc};
+use std::fmt::Display;
+use std::sync::RwLock;
+
+pub const DEFAULT_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+pub const MJ_PER_KWH: f64 = 3.6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnergyUnit {
+    Kwh,
+    Mj,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timezone {
+    Local,
+    Utc,
+}
+
+/// Units and formats used for the values presented to humans (logs, LCD, notifications),
+/// internal processing and the databases always use °C, kWh and UTC
+pub struct Units {
+    pub temperature: TemperatureUnit,
+    pub energy: EnergyUnit,
+    pub timezone: Option<Timezone>,
+    pub time_format: Option<String>,
+    pub decimal_comma: bool,
+}
+
+static UNITS: RwLock<Units> = RwLock::new(Units {
+    temperature: TemperatureUnit::Celsius,
+    energy: EnergyUnit::Kwh,
+    timezone: None,
+    time_format: None,
+    decimal_comma: false,
+});
+
+impl TemperatureUnit {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().trim_start_matches('°').to_lowercase().as_str() {
+            "c" | "celsius" => Some(TemperatureUnit::Celsius),
+            "f" | "fahrenheit" => Some(TemperatureUnit::Fahrenheit),
+            _ => None,
+        }
+    }
+}
+
+impl EnergyUnit {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "kwh" => Some(EnergyUnit::Kwh),
+            "mj" => Some(EnergyUnit::Mj),
+            _ => None,
+        }
+    }
+}
+
+impl Timezone {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "local" => Some(Timezone::Local),
+            "utc" => Some(Timezone::Utc),
+            _ => None,
+        }
+    }
+}
+
+/// Sets the units for the whole process, called once on startup
+pub fn init(units: Units) {
+    *UNITS.write().unwrap() = units;
+}
+
+fn number(value: f64, decimals: usize) -> String {
+    let text = format!("{:.*}", decimals, value);
+    if UNITS.read().unwrap().decimal_comma {
+        text.replace('.', ",")
+    } else {
+        text
+    }
+}
+
+/// Formats a temperature given in °C
+pub fn temperature(celsius: f32) -> String {
+    let unit = UNITS.read().unwrap().temperature;
+    match unit {
+        TemperatureUnit::Celsius => format!("{} °C", number(celsius as f64, 1)),
+        TemperatureUnit::Fahrenheit => {
+            format!("{} °F", number(celsius as f64 * 9.0 / 5.0 + 32.0, 1))
+        }
+    }
+}
+
+/// Formats a temperature difference (hysteresis) given in °C
+pub fn temperature_delta(celsius: f32) -> String {
+    let unit = UNITS.read().unwrap().temperature;
+    match unit {
+        TemperatureUnit::Celsius => format!("{} °C", number(celsius as f64, 1)),
+        TemperatureUnit::Fahrenheit => format!("{} °F", number(celsius as f64 * 9.0 / 5.0, 1)),
+    }
+}
+
+/// Formats an energy given in kWh
+pub fn energy(kwh: f64, decimals: usize) -> String {
+    let unit = UNITS.read().unwrap().energy;
+    match unit {
+        EnergyUnit::Kwh => format!("{} kWh", number(kwh, decimals)),
+        EnergyUnit::Mj => format!("{} MJ", number(kwh * MJ_PER_KWH, decimals)),
+    }
+}
+
+/// Formats a timestamp in the configured timezone
+pub fn timestamp<Tz: TimeZone>(time: DateTime<Tz>) -> String
+where
+    Tz::Offset: Display,
+{
+    let units = UNITS.read().unwrap();
+    let format = units.time_format.as_deref().unwrap_or(DEFAULT_TIME_FORMAT);
+    match units.timezone.unwrap_or(Timezone::Local) {
+        Timezone::Local => time.with_timezone(&Local).format(format).to_string(),
+        Timezone::Utc => time.with_timezone(&Utc).format(format).to_string(),
+    }
+}
+
+/// Formats a date in the configured timezone
+pub fn date<Tz: TimeZone>(time: DateTime<Tz>) -> String
+where
+    Tz::Offset: Display,
+{
+    let units = UNITS.read().unwrap();
+    match units.timezone.unwrap_or(Timezone::Local) {
+        Timezone::Local => time.with_timezone(&Local).format("%Y-%m-%d").to_string(),
+        Timezone::Utc => time.with_timezone(&Utc).format("%Y-%m-%d").to_string(),
+    }
+}
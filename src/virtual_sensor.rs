@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::fmt;
+
+pub const VIRTUAL_SENSOR_CHECK_INTERVAL_SECS: f32 = 5.0; //secs between evaluating virtual sensors
+
+/// Last known values of all sensors by name, used as expression variables
+pub type SensorValues = HashMap<String, f32>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f32),
+    Ident(String),
+    Op(Op),
+    Not,
+    LParen,
+    RParen,
+}
+
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Number(f32),
+    Var(String),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).cloned();
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            match number.parse::<f32>() {
+                Ok(val) => tokens.push(Token::Number(val)),
+                Err(_) => return Err(format!("invalid number: {}", number)),
+            }
+            continue;
+        }
+        if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+            {
+                i += 1;
+            }
+            let ident: String = chars[start..i].iter().collect();
+            tokens.push(match ident.as_ref() {
+                "true" => Token::Number(1.0),
+                "false" => Token::Number(0.0),
+                "and" => Token::Op(Op::And),
+                "or" => Token::Op(Op::Or),
+                "not" => Token::Not,
+                _ => Token::Ident(ident),
+            });
+            continue;
+        }
+        let (token, len) = match (c, next) {
+            ('&', Some('&')) => (Token::Op(Op::And), 2),
+            ('|', Some('|')) => (Token::Op(Op::Or), 2),
+            ('=', Some('=')) => (Token::Op(Op::Eq), 2),
+            ('!', Some('=')) => (Token::Op(Op::Ne), 2),
+            ('<', Some('=')) => (Token::Op(Op::Le), 2),
+            ('>', Some('=')) => (Token::Op(Op::Ge), 2),
+            ('<', _) => (Token::Op(Op::Lt), 1),
+            ('>', _) => (Token::Op(Op::Gt), 1),
+            ('+', _) => (Token::Op(Op::Add), 1),
+            ('-', _) => (Token::Op(Op::Sub), 1),
+            ('*', _) => (Token::Op(Op::Mul), 1),
+            ('/', _) => (Token::Op(Op::Div), 1),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::LParen, 1),
+            (')', _) => (Token::RParen, 1),
+            _ => return Err(format!("unexpected character: '{}'", c)),
+        };
+        tokens.push(token);
+        i += len;
+    }
+    Ok(tokens)
+}
+
+//binding power of binary operators, higher binds tighter
+fn precedence(op: Op) -> u8 {
+    match op {
+        Op::Or => 1,
+        Op::And => 2,
+        Op::Eq | Op::Ne => 3,
+        Op::Lt | Op::Le | Op::Gt | Op::Ge => 4,
+        Op::Add | Op::Sub => 5,
+        Op::Mul | Op::Div => 6,
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.parse_unary()?;
+        loop {
+            let op = match self.tokens.get(self.pos) {
+                Some(Token::Op(op)) if precedence(*op) >= min_precedence => *op,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.parse_binary(precedence(op) + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Number(val)) => Ok(Expr::Number(val)),
+            Some(Token::Ident(name)) => Ok(Expr::Var(name)),
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            Some(Token::Op(Op::Sub)) => Ok(Expr::Neg(Box::new(self.parse_unary()?))),
+            Some(Token::LParen) => {
+                let expr = self.parse_binary(0)?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::RParen) => {
+                        self.pos += 1;
+                        Ok(expr)
+                    }
+                    _ => Err("missing closing parenthesis".to_string()),
+                }
+            }
+            Some(token) => Err(format!("unexpected token: {:?}", token)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+impl Expr {
+    /// Parses expressions like `outside_temp < 15 && room_temp < setpoint`
+    pub fn parse(input: &str) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            pos: 0,
+        };
+        let expr = parser.parse_binary(0)?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected token: {:?}", token)),
+        }
+    }
+
+    /// Evaluates the expression, `None` when some of the variables has no value yet
+    pub fn eval(&self, values: &SensorValues) -> Option<f32> {
+        let bool_val = |x: bool| if x { 1.0 } else { 0.0 };
+        match self {
+            Expr::Number(val) => Some(*val),
+            Expr::Var(name) => values.get(name).cloned(),
+            Expr::Not(expr) => expr.eval(values).map(|x| bool_val(x == 0.0)),
+            Expr::Neg(expr) => expr.eval(values).map(|x| -x),
+            Expr::Binary(op, lhs, rhs) => {
+                let a = lhs.eval(values)?;
+                let b = rhs.eval(values)?;
+                Some(match op {
+                    Op::Or => bool_val(a != 0.0 || b != 0.0),
+                    Op::And => bool_val(a != 0.0 && b != 0.0),
+                    Op::Eq => bool_val(a == b),
+                    Op::Ne => bool_val(a != b),
+                    Op::Lt => bool_val(a < b),
+                    Op::Le => bool_val(a <= b),
+                    Op::Gt => bool_val(a > b),
+                    Op::Ge => bool_val(a >= b),
+                    Op::Add => a + b,
+                    Op::Sub => a - b,
+                    Op::Mul => a * b,
+                    Op::Div => a / b,
+                })
+            }
+        }
+    }
+}
+
+pub struct VirtualSensor {
+    pub id_sensor: i32,
+    pub id_kind: i32,
+    pub name: String,
+    pub source: String,
+    pub expression: Expr,
+    pub tags: Vec<String>,
+    pub associated_relays: Vec<i32>,
+    pub associated_yeelights: Vec<i32>,
+    pub associated_plugs: Vec<i32>,
+    pub last_value: Option<bool>,
+}
+
+impl VirtualSensor {
+    /// Computes the new state and publishes it as a variable for other expressions
+    pub fn evaluate(&self, values: &mut SensorValues) -> Option<bool> {
+        let on = self
+            .expression
+            .eval(values)
+            .map(|x| x != 0.0 && !x.is_nan())?;
+        values.insert(self.name.clone(), if on { 1.0 } else { 0.0 });
+        Some(on)
+    }
+}
+
+impl fmt::Display for VirtualSensor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} = {}", self.name, self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(vars: &[(&str, f32)]) -> SensorValues {
+        vars.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+    }
+
+    fn eval(input: &str, vars: &[(&str, f32)]) -> Option<f32> {
+        Expr::parse(input).unwrap().eval(&values(vars))
+    }
+
+    fn sensor(source: &str) -> VirtualSensor {
+        VirtualSensor {
+            id_sensor: 1,
+            id_kind: 1,
+            name: "heating_needed".to_string(),
+            source: source.to_string(),
+            expression: Expr::parse(source).unwrap(),
+            tags: vec![],
+            associated_relays: vec![],
+            associated_yeelights: vec![],
+            associated_plugs: vec![],
+            last_value: None,
+        }
+    }
+
+    #[test]
+    fn operator_precedence() {
+        assert_eq!(eval("1 + 2 * 3", &[]), Some(7.0));
+        assert_eq!(eval("(1 + 2) * 3", &[]), Some(9.0));
+        assert_eq!(eval("8 - 2 - 1", &[]), Some(5.0));
+        assert_eq!(eval("-2 * 3", &[]), Some(-6.0));
+        assert_eq!(eval("1 < 2 == 1", &[]), Some(1.0));
+        assert_eq!(eval("true || false && false", &[]), Some(1.0));
+        assert_eq!(eval("not (1 > 2) and !false", &[]), Some(1.0));
+    }
+
+    #[test]
+    fn variables() {
+        let vars = [
+            ("outside_temp", 12.5),
+            ("room.temp", 20.0),
+            ("setpoint", 21.0),
+        ];
+        assert_eq!(
+            eval("outside_temp < 15 && room.temp < setpoint", &vars),
+            Some(1.0)
+        );
+        assert_eq!(eval("room.temp >= setpoint", &vars), Some(0.0));
+        //unknown yet
+        assert_eq!(eval("outside_temp < 15 && humidity > 60", &vars), None);
+    }
+
+    #[test]
+    fn malformed_expressions() {
+        for input in &[
+            "", "1 +", "(1 + 2", "1 + 2)", "1 2", "a # b", "1..2", "* 3", "a ! b",
+        ] {
+            assert!(Expr::parse(input).is_err(), "{:?} should not parse", input);
+        }
+        assert_eq!(
+            Expr::parse("(a").unwrap_err(),
+            "missing closing parenthesis".to_string()
+        );
+        assert_eq!(
+            Expr::parse("a $ b").unwrap_err(),
+            "unexpected character: '$'".to_string()
+        );
+    }
+
+    #[test]
+    fn evaluate_publishes_the_state() {
+        let heating = sensor("outside_temp < 15");
+        let mut vars = values(&[("outside_temp", 10.0)]);
+        assert_eq!(heating.evaluate(&mut vars), Some(true));
+        assert_eq!(vars.get("heating_needed"), Some(&1.0));
+
+        vars.insert("outside_temp".to_string(), 18.0);
+        assert_eq!(heating.evaluate(&mut vars), Some(false));
+        assert_eq!(vars.get("heating_needed"), Some(&0.0));
+
+        //0 / 0 is off, not on
+        assert_eq!(sensor("0 / 0").evaluate(&mut vars), Some(false));
+        //a missing variable keeps the published state
+        assert_eq!(sensor("unknown > 1").evaluate(&mut vars), None);
+        assert_eq!(vars.get("heating_needed"), Some(&0.0));
+    }
+}
@@ -1,13 +1,45 @@
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio_compat_02::FutureExt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
+use crate::adaptive_hold::AdaptiveHold;
+use crate::cesspool::CesspoolHistory;
 use crate::database::{CommandCode, DbTask};
-use crate::onewire::{OneWireTask, TaskCommand};
-use rocket::{get, routes, State};
+use crate::energy::EnergyCosts;
+use crate::events::{self, EventCache, EventSender};
+use crate::exerciser::RelayExerciser;
+use crate::gate::{GateAction, GateStates};
+use crate::heating_season::HeatingSeason;
+use crate::logbuffer::LogBuffer;
+use crate::loglevel;
+use crate::mailbox::MailboxState;
+use crate::metrics::{BusMetrics, LatencyMetrics, WorkerErrors};
+use crate::onewire::{
+    get_w1_device_name, OneWireTask, RelayDevices, Relays, SensorDevices, StateMachine, TaskCommand,
+};
+use crate::ontime::{OnTimeStats, DEFAULT_ONTIME_DAYS};
+use crate::queue::{QueueMetrics, Sender};
+use crate::remeha::{RemehaSetpoint, RemehaSetpointRequest};
+use crate::scene::{Scene, SceneStatus};
+use crate::simulation;
+use crate::skymax::SkymaxSetting;
+use crate::virtual_sensor::SensorValues;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::Utc;
+use futures::{SinkExt, StreamExt};
+use rocket::config::LogLevel;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::content::{RawHtml, RawJson};
+use rocket::Response;
+use rocket::{get, post, routes, State};
+use rocket_ws as ws;
 use simplelog::*;
-use std::sync::mpsc::Sender;
+use std::thread;
+use tokio::sync::broadcast::error::RecvError;
 
 // Just a generic Result type to ease error handling for us. Errors in multithreaded
 // async contexts needs some extra restrictions
@@ -17,6 +49,220 @@ pub struct WebServer {
     pub name: String,
     pub ow_transmitter: Sender<OneWireTask>,
     pub db_transmitter: Sender<DbTask>,
+    pub sensor_devices: Arc<RwLock<SensorDevices>>,
+    pub relay_devices: Arc<RwLock<RelayDevices>>,
+    pub relays: Arc<RwLock<Relays>>,
+    pub sensor_values: Arc<RwLock<SensorValues>>,
+    pub cesspool_history: Arc<RwLock<CesspoolHistory>>,
+    pub mailbox_state: Arc<RwLock<MailboxState>>,
+    pub gate_states: GateStates,
+    pub adaptive_hold: Arc<RwLock<AdaptiveHold>>,
+    pub alarm_armed: Arc<AtomicBool>,
+    pub backup_soc_request: Arc<Mutex<Option<f32>>>,
+    pub skymax_setting_requests: Arc<Mutex<Vec<SkymaxSetting>>>,
+    pub remeha_setpoint_requests: Arc<Mutex<Vec<RemehaSetpointRequest>>>,
+    pub heating_season: Arc<RwLock<HeatingSeason>>,
+    pub energy_costs: Arc<RwLock<EnergyCosts>>,
+    pub ontime: Arc<RwLock<OnTimeStats>>,
+    pub events: EventSender,
+    pub bus_metrics: Arc<RwLock<BusMetrics>>,
+    pub latency: Arc<RwLock<LatencyMetrics>>,
+    pub queue_metrics: Arc<RwLock<QueueMetrics>>,
+    pub worker_errors: Arc<RwLock<WorkerErrors>>,
+    pub log_buffer: Arc<Mutex<LogBuffer>>,
+    pub service_control: ServiceControl,
+    pub auth: WebAuth,
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct ServiceControl {
+    pub allow_reboot: bool,
+    pub reboot_command: String,
+    pub workers: Vec<String>,
+    pub restart_requests: Arc<Mutex<Vec<String>>>,
+}
+
+/// Credentials of the api, a token (X-Api-Token header or bearer) or `user:password` for basic auth
+#[derive(Clone, Default)]
+pub struct WebAuth {
+    pub control_token: Option<String>,
+    pub control_user: Option<String>,
+    pub read_token: Option<String>,
+    pub read_user: Option<String>,
+}
+
+#[derive(PartialEq, PartialOrd)]
+enum Access {
+    Denied,
+    Read,
+    Control,
+}
+
+//the only endpoint taking the token from the query, browsers can't set the websocket headers
+const WEBSOCKET_PATH: &str = "/ws";
+
+pub const WEBSERVER_RESTART_MIN_SECS: u64 = 1;
+pub const WEBSERVER_RESTART_MAX_SECS: u64 = 60;
+
+//comparison time doesn't depend on the position of the first difference
+pub fn secure_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+impl WebAuth {
+    pub fn read_protected(&self) -> bool {
+        self.read_token.is_some() || self.read_user.is_some()
+    }
+
+    pub fn control_enabled(&self) -> bool {
+        self.control_token.is_some() || self.control_user.is_some()
+    }
+
+    fn access(&self, req: &Request<'_>) -> Access {
+        let authorization = req.headers().get_one("Authorization");
+        let token = req
+            .headers()
+            .get_one("X-Api-Token")
+            .or_else(|| authorization.and_then(|h| h.strip_prefix("Bearer ")))
+            .or_else(|| {
+                if req.uri().path() == WEBSOCKET_PATH {
+                    req.query_value::<&str>("token").and_then(|t| t.ok())
+                } else {
+                    None
+                }
+            });
+        let basic = authorization
+            .and_then(|h| h.strip_prefix("Basic "))
+            .and_then(|b| BASE64.decode(b.trim()).ok())
+            .and_then(|b| String::from_utf8(b).ok());
+        let valid = |expected_token: &Option<String>, expected_user: &Option<String>| {
+            let token_ok = match (expected_token, token) {
+                (Some(expected), Some(token)) => secure_eq(expected, token),
+                _ => false,
+            };
+            let user_ok = match (expected_user, &basic) {
+                (Some(expected), Some(basic)) => secure_eq(expected, basic),
+                _ => false,
+            };
+            token_ok || user_ok
+        };
+        if valid(&self.control_token, &self.control_user) {
+            Access::Control
+        } else if valid(&self.read_token, &self.read_user) {
+            Access::Read
+        } else {
+            Access::Denied
+        }
+    }
+
+    fn guard<T>(
+        req: &Request<'_>,
+        allowed: impl Fn(&WebAuth, Access) -> bool,
+        guard: T,
+    ) -> Outcome<T, ()> {
+        let auth = match req.rocket().state::<WebAuth>() {
+            Some(auth) => auth,
+            None => return Outcome::Error((Status::Unauthorized, ())),
+        };
+        if allowed(auth, auth.access(req)) {
+            Outcome::Success(guard)
+        } else {
+            warn!("webserver: unauthorized request: {}", redacted_uri(req));
+            Outcome::Error((Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// Request path without the query string, which may carry the websocket token
+fn redacted_uri(req: &Request<'_>) -> String {
+    match req.uri().query() {
+        Some(_) => format!("{}?<redacted>", req.uri().path()),
+        None => req.uri().path().to_string(),
+    }
+}
+
+/// Logs the requests with the query string redacted, in place of the Rocket request log
+pub struct RequestLog;
+
+#[rocket::async_trait]
+impl Fairing for RequestLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "redacted request log",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        debug!(
+            "webserver: {} {}: {}",
+            req.method(),
+            redacted_uri(req),
+            res.status()
+        );
+    }
+}
+
+//request guard: control access, the endpoints are disabled when no control credentials are set
+pub struct ApiToken;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiToken {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        WebAuth::guard(req, |_, access| access == Access::Control, ApiToken)
+    }
+}
+
+//request guard: read-only access, public when no read credentials are set
+pub struct ReadAccess;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ReadAccess {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        WebAuth::guard(
+            req,
+            |auth, access| !auth.read_protected() || access >= Access::Read,
+            ReadAccess,
+        )
+    }
+}
+
+//request guard: the legacy /cmd endpoints, public only while no credentials are configured at all
+pub struct CmdAccess;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CmdAccess {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        WebAuth::guard(
+            req,
+            |auth, access| {
+                (!auth.control_enabled() && !auth.read_protected()) || access == Access::Control
+            },
+            CmdAccess,
+        )
+    }
+}
+
+//single page dashboard, the data is loaded from the api with the token stored in the browser
+static DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+#[get("/")]
+pub fn dashboard() -> RawHtml<&'static str> {
+    RawHtml(DASHBOARD_HTML)
 }
 
 #[get("/hello")]
@@ -24,8 +270,10 @@ pub fn hello() -> &'static str {
     "Hello world!"
 }
 
-#[get("/reload")]
-pub fn reload(transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask>)>>>) -> String {
+//asks the database worker to re-fetch the device definitions
+fn request_reload(
+    transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask>)>>>,
+) -> String {
     let task = DbTask {
         command: CommandCode::ReloadDevices,
         value: None,
@@ -37,13 +285,33 @@ pub fn reload(transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask
     "Reloading config...".to_string()
 }
 
+#[get("/reload")]
+pub fn reload(
+    _access: CmdAccess,
+    transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask>)>>>,
+) -> String {
+    request_reload(transmitters)
+}
+
+#[post("/reload")]
+pub fn reload_devices(
+    _token: ApiToken,
+    transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask>)>>>,
+) -> String {
+    request_reload(transmitters)
+}
+
 #[get("/fan-on")]
-pub fn fan_on(transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask>)>>>) -> String {
+pub fn fan_on(
+    _access: CmdAccess,
+    transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask>)>>>,
+) -> String {
     let task = OneWireTask {
         command: TaskCommand::TurnOnProlong,
         id_relay: Some(14),
         tag_group: None,
         id_yeelight: None,
+        id_plug: None,
         duration: Some(Duration::from_secs(60 * 5)),
     };
     if let Ok(trans) = transmitters.lock() {
@@ -54,12 +322,16 @@ pub fn fan_on(transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask
 }
 
 #[get("/fan-off")]
-pub fn fan_off(transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask>)>>>) -> String {
+pub fn fan_off(
+    _access: CmdAccess,
+    transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask>)>>>,
+) -> String {
     let task = OneWireTask {
         command: TaskCommand::TurnOff,
         id_relay: Some(14),
         tag_group: None,
         id_yeelight: None,
+        id_plug: None,
         duration: None,
     };
     if let Ok(trans) = transmitters.lock() {
@@ -69,6 +341,730 @@ pub fn fan_off(transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTas
     "Turning OFF fan".to_string()
 }
 
+#[get("/health")]
+pub fn health(sensor_devices: &State<Arc<RwLock<SensorDevices>>>) -> RawJson<String> {
+    let boards = match sensor_devices.read() {
+        Ok(dev) => dev.get_health(),
+        Err(_) => vec![],
+    };
+    let status = if boards.iter().any(|b| b.degraded) {
+        "degraded"
+    } else {
+        "ok"
+    };
+    RawJson(
+        serde_json::json!({
+            "status": status,
+            "sensor_boards": boards,
+        })
+        .to_string(),
+    )
+}
+
+#[get("/metrics")]
+pub fn metrics(
+    _access: ReadAccess,
+    bus_metrics: &State<Arc<RwLock<BusMetrics>>>,
+    latency: &State<Arc<RwLock<LatencyMetrics>>>,
+    queues: &State<Arc<RwLock<QueueMetrics>>>,
+    worker_errors: &State<Arc<RwLock<WorkerErrors>>>,
+) -> String {
+    let mut out = match bus_metrics.read() {
+        Ok(metrics) => metrics.to_prometheus(),
+        Err(_) => String::new(),
+    };
+    if let Ok(latency) = latency.read() {
+        out.push_str(&latency.to_prometheus());
+    }
+    if let Ok(queues) = queues.read() {
+        out.push_str(&queues.to_prometheus());
+    }
+    if let Ok(worker_errors) = worker_errors.read() {
+        out.push_str(&worker_errors.to_prometheus());
+    }
+    out
+}
+
+#[get("/cesspool")]
+pub fn cesspool(
+    _access: ReadAccess,
+    history: &State<Arc<RwLock<CesspoolHistory>>>,
+) -> RawJson<String> {
+    match history.read() {
+        Ok(history) => RawJson(
+            serde_json::json!({
+                "level": history.current_level(),
+                "predicted_full": history.predict_full(),
+                "fill_rate_per_day": history.fill_rate(),
+                "days_until_full": history.days_until_full(Utc::now()),
+                "history": history.levels,
+                "emptied": history.emptied,
+            })
+            .to_string(),
+        ),
+        Err(_) => RawJson("{}".to_string()),
+    }
+}
+
+#[get("/mailbox")]
+pub fn mailbox(_access: ReadAccess, state: &State<Arc<RwLock<MailboxState>>>) -> RawJson<String> {
+    match state.read() {
+        Ok(state) => RawJson(serde_json::to_string(&*state).unwrap_or_default()),
+        Err(_) => RawJson("{}".to_string()),
+    }
+}
+
+#[get("/gates")]
+pub fn gate_list(_access: ReadAccess, states: &State<GateStates>) -> RawJson<String> {
+    match states.read() {
+        Ok(states) => RawJson(serde_json::to_string(&*states).unwrap_or_default()),
+        Err(_) => RawJson("{}".to_string()),
+    }
+}
+
+#[post("/gates/<name>/<action>")]
+pub fn gate_command(
+    _token: ApiToken,
+    name: &str,
+    action: &str,
+    states: &State<GateStates>,
+    transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask>)>>>,
+) -> (Status, String) {
+    if !states.read().map_or(false, |s| s.contains_key(name)) {
+        return (Status::NotFound, format!("Unknown gate: {}", name));
+    }
+    let action = match GateAction::parse(action) {
+        Some(action) => action,
+        None => return (Status::BadRequest, format!("Unknown action: {}", action)),
+    };
+    if let Ok(trans) = transmitters.lock() {
+        let _ = trans.0.send(action.task(name));
+    }
+    info!("webserver: 🚧 gate {}: {:?} requested", name, action);
+    (Status::Ok, format!("gate {}: {:?} requested", name, action))
+}
+
+#[get("/adaptive_hold")]
+pub fn adaptive_hold(
+    _access: ReadAccess,
+    learned: &State<Arc<RwLock<AdaptiveHold>>>,
+) -> RawJson<String> {
+    match learned.read() {
+        Ok(learned) => RawJson(serde_json::to_string(&learned.relays).unwrap_or_default()),
+        Err(_) => RawJson("{}".to_string()),
+    }
+}
+
+#[get("/relays")]
+pub fn relay_list(
+    _access: ReadAccess,
+    relay_devices: &State<Arc<RwLock<RelayDevices>>>,
+    relays: &State<Arc<RwLock<Relays>>>,
+) -> RawJson<String> {
+    match (relay_devices.read(), relays.read()) {
+        (Ok(relay_dev), Ok(relays)) => RawJson(
+            serde_json::to_string(&relay_dev.get_relay_status(&relays.relay)).unwrap_or_default(),
+        ),
+        _ => RawJson("[]".to_string()),
+    }
+}
+
+#[get("/sensors")]
+pub fn sensor_list(
+    _access: ReadAccess,
+    sensor_devices: &State<Arc<RwLock<SensorDevices>>>,
+    sensor_values: &State<Arc<RwLock<SensorValues>>>,
+) -> RawJson<String> {
+    let (sensor_dev, values) = match (sensor_devices.read(), sensor_values.read()) {
+        (Ok(sensor_dev), Ok(values)) => (sensor_dev, values),
+        _ => return RawJson("[]".to_string()),
+    };
+    let sensors: Vec<serde_json::Value> = sensor_dev
+        .sensor_boards
+        .iter()
+        .flat_map(|sb| sb.pio_a.iter().chain(sb.pio_b.iter()))
+        .map(|sensor| {
+            serde_json::json!({
+                "id": sensor.id_sensor,
+                "name": sensor.name,
+                "kind": sensor_dev.kinds.get(&sensor.id_kind),
+                "on": values.get(&sensor.name).map(|v| *v != 0.0),
+            })
+        })
+        .collect();
+    RawJson(serde_json::to_string(&sensors).unwrap_or_default())
+}
+
+#[get("/simulation")]
+pub fn simulation_state(_access: ReadAccess) -> (Status, RawJson<String>) {
+    if !simulation::enabled() {
+        return (Status::Conflict, RawJson("{}".to_string()));
+    }
+    let values: BTreeMap<String, String> = simulation::values()
+        .into_iter()
+        .map(|(file, value)| (file, format!("{:#04x}", value)))
+        .collect();
+    (
+        Status::Ok,
+        RawJson(serde_json::to_string(&values).unwrap_or_default()),
+    )
+}
+
+/// Injects a sensor transition into the fake board, picked up by the next bus scan
+#[post("/simulation/sensors/<name>/<state>")]
+pub fn simulation_sensor(
+    _token: ApiToken,
+    name: &str,
+    state: &str,
+    sensor_devices: &State<Arc<RwLock<SensorDevices>>>,
+) -> (Status, String) {
+    if !simulation::enabled() {
+        return (Status::Conflict, "Simulation mode is disabled".to_string());
+    }
+    let on = match state {
+        "on" => true,
+        "off" => false,
+        _ => return (Status::BadRequest, format!("Unknown state: {}", state)),
+    };
+    let target = sensor_devices.read().ok().and_then(|sensor_dev| {
+        sensor_dev.sensor_boards.iter().find_map(|sb| {
+            let device = get_w1_device_name(sb.ow_family, sb.ow_address);
+            match (&sb.pio_a, &sb.pio_b) {
+                (Some(s), _) if s.name == name => Some((device, 0, s.inverted)),
+                (_, Some(s)) if s.name == name => Some((device, 2, s.inverted)),
+                _ => None,
+            }
+        })
+    });
+    let (device, bit, inverted) = match target {
+        Some(target) => target,
+        None => return (Status::NotFound, format!("Unknown sensor: {}", name)),
+    };
+    //the board reads the raw pin, inversion is applied by the reader
+    match simulation::set_input(&device, bit, on != inverted) {
+        Ok(_) => (Status::Ok, format!("sensor {}: {}", name, state)),
+        Err(e) => (
+            Status::InternalServerError,
+            format!("sensor {}: {}", name, e),
+        ),
+    }
+}
+
+#[get("/events/latest")]
+pub fn latest_events(
+    _access: ReadAccess,
+    cache: &State<Arc<RwLock<EventCache>>>,
+) -> RawJson<String> {
+    match cache.read() {
+        Ok(cache) => RawJson(serde_json::to_string(&cache.latest()).unwrap_or_default()),
+        Err(_) => RawJson("[]".to_string()),
+    }
+}
+
+#[get("/yeelights")]
+pub fn yeelight_list(
+    _access: ReadAccess,
+    relay_devices: &State<Arc<RwLock<RelayDevices>>>,
+    relays: &State<Arc<RwLock<Relays>>>,
+) -> RawJson<String> {
+    match (relay_devices.read(), relays.read()) {
+        (Ok(relay_dev), Ok(relays)) => RawJson(
+            serde_json::to_string(&relay_dev.get_yeelight_status(&relays.relay))
+                .unwrap_or_default(),
+        ),
+        _ => RawJson("[]".to_string()),
+    }
+}
+
+//sends a turn on/off command for a relay, yeelight or smart plug
+fn device_command(
+    transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask>)>>>,
+    id: i32,
+    kind: &str,
+    action: &str,
+    duration: Option<u64>,
+) -> (Status, String) {
+    let command = match action {
+        "on" | "prolong" => TaskCommand::TurnOnProlong,
+        "off" => TaskCommand::TurnOff,
+        "toggle" => TaskCommand::Toggle,
+        _ => return (Status::BadRequest, format!("Unknown action: {}", action)),
+    };
+    let task = OneWireTask {
+        command,
+        id_relay: if kind == "relay" { Some(id) } else { None },
+        tag_group: None,
+        id_yeelight: if kind == "yeelight" { Some(id) } else { None },
+        id_plug: if kind == "plug" { Some(id) } else { None },
+        duration: duration.map(Duration::from_secs),
+    };
+    if let Ok(trans) = transmitters.lock() {
+        let _ = trans.0.send(task);
+    }
+    (Status::Ok, format!("{} id={}: {}", kind, id, action))
+}
+
+#[post("/relays/<id>/<action>?<duration>")]
+pub fn relay_command(
+    _token: ApiToken,
+    id: i32,
+    action: &str,
+    duration: Option<u64>,
+    relays: &State<Arc<RwLock<Relays>>>,
+    transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask>)>>>,
+) -> (Status, String) {
+    let found = relays
+        .read()
+        .map_or(false, |r| r.relay.iter().any(|d| d.id == id));
+    if !found {
+        return (Status::NotFound, format!("Unknown relay: {}", id));
+    }
+    device_command(transmitters, id, "relay", action, duration)
+}
+
+#[post("/yeelights/<id>/<action>?<duration>")]
+pub fn yeelight_command(
+    _token: ApiToken,
+    id: i32,
+    action: &str,
+    duration: Option<u64>,
+    relay_devices: &State<Arc<RwLock<RelayDevices>>>,
+    transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask>)>>>,
+) -> (Status, String) {
+    let found = relay_devices
+        .read()
+        .map_or(false, |r| r.yeelight.iter().any(|y| y.id == id));
+    if !found {
+        return (Status::NotFound, format!("Unknown yeelight: {}", id));
+    }
+    device_command(transmitters, id, "yeelight", action, duration)
+}
+
+#[get("/plugs")]
+pub fn plug_list(
+    _access: ReadAccess,
+    relay_devices: &State<Arc<RwLock<RelayDevices>>>,
+    relays: &State<Arc<RwLock<Relays>>>,
+) -> RawJson<String> {
+    match (relay_devices.read(), relays.read()) {
+        (Ok(relay_dev), Ok(relays)) => RawJson(
+            serde_json::to_string(&relay_dev.get_smart_plug_status(&relays.relay))
+                .unwrap_or_default(),
+        ),
+        _ => RawJson("[]".to_string()),
+    }
+}
+
+#[post("/plugs/<id>/<action>?<duration>")]
+pub fn plug_command(
+    _token: ApiToken,
+    id: i32,
+    action: &str,
+    duration: Option<u64>,
+    relay_devices: &State<Arc<RwLock<RelayDevices>>>,
+    transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask>)>>>,
+) -> (Status, String) {
+    let found = relay_devices
+        .read()
+        .map_or(false, |r| r.smart_plugs.iter().any(|p| p.id == id));
+    if !found {
+        return (Status::NotFound, format!("Unknown plug: {}", id));
+    }
+    device_command(transmitters, id, "plug", action, duration)
+}
+
+#[get("/scenes")]
+pub fn scene_list(
+    _access: ReadAccess,
+    relay_devices: &State<Arc<RwLock<RelayDevices>>>,
+) -> RawJson<String> {
+    match relay_devices.read() {
+        Ok(relay_dev) => {
+            let scenes: Vec<SceneStatus> = relay_dev
+                .scenes
+                .iter()
+                .map(|s| SceneStatus {
+                    name: s.name.clone(),
+                    actions: s.actions.len(),
+                })
+                .collect();
+            RawJson(serde_json::to_string(&scenes).unwrap_or_default())
+        }
+        Err(_) => RawJson("[]".to_string()),
+    }
+}
+
+#[post("/scenes/<name>")]
+pub fn scene_command(
+    _token: ApiToken,
+    name: &str,
+    relay_devices: &State<Arc<RwLock<RelayDevices>>>,
+    transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask>)>>>,
+) -> (Status, String) {
+    let found = relay_devices
+        .read()
+        .map_or(false, |r| r.scenes.iter().any(|s| s.name == name));
+    if !found {
+        return (Status::NotFound, format!("Unknown scene: {}", name));
+    }
+    if let Ok(trans) = transmitters.lock() {
+        let _ = trans.0.send(Scene::task(name));
+    }
+    info!("webserver: 🎬 scene {} requested", name);
+    (Status::Ok, format!("scene {}: triggered", name))
+}
+
+#[get("/maintenance/exercise")]
+pub fn exercise_report(_access: ReadAccess, exerciser: &State<RelayExerciser>) -> RawJson<String> {
+    match exerciser.report.lock() {
+        Ok(report) => RawJson(serde_json::to_string(&*report).unwrap_or_default()),
+        Err(_) => RawJson("{}".to_string()),
+    }
+}
+
+//cycles all relays: without confirm=yes only the relays to be tested are returned
+#[post("/maintenance/exercise?<confirm>")]
+pub fn exercise_start(
+    _token: ApiToken,
+    confirm: Option<&str>,
+    exerciser: &State<RelayExerciser>,
+    transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask>)>>>,
+) -> (Status, String) {
+    if confirm != Some("yes") {
+        let (relays, excluded) = exerciser.plan();
+        return (
+            Status::PreconditionRequired,
+            serde_json::json!({
+                "confirm": "add ?confirm=yes to start the test sequence",
+                "relays": relays.iter().map(|r| &r.name).collect::<Vec<_>>(),
+                "excluded": excluded.iter().map(|r| &r.name).collect::<Vec<_>>(),
+            })
+            .to_string(),
+        );
+    }
+    let ow_transmitter = match transmitters.lock() {
+        Ok(trans) => trans.0.clone(),
+        Err(_) => return (Status::InternalServerError, "Internal error".to_string()),
+    };
+    if exerciser.start(ow_transmitter) {
+        (Status::Ok, "Relay test sequence started".to_string())
+    } else {
+        (
+            Status::Conflict,
+            "Relay test sequence already running".to_string(),
+        )
+    }
+}
+
+#[get("/energy/costs")]
+pub fn energy_costs(
+    _access: ReadAccess,
+    costs: &State<Arc<RwLock<EnergyCosts>>>,
+) -> RawJson<String> {
+    match costs.read() {
+        Ok(costs) => RawJson(serde_json::to_string(&costs.months).unwrap_or_default()),
+        Err(_) => RawJson("[]".to_string()),
+    }
+}
+
+#[get("/energy/net_metering")]
+pub fn net_metering(
+    _access: ReadAccess,
+    costs: &State<Arc<RwLock<EnergyCosts>>>,
+) -> RawJson<String> {
+    match costs.read() {
+        Ok(costs) => RawJson(serde_json::to_string(&costs.net_metering()).unwrap_or_default()),
+        Err(_) => RawJson("{}".to_string()),
+    }
+}
+
+#[get("/ontime/daily?<days>")]
+pub fn ontime_daily(
+    _access: ReadAccess,
+    days: Option<usize>,
+    ontime: &State<Arc<RwLock<OnTimeStats>>>,
+) -> RawJson<String> {
+    let days = days.unwrap_or(DEFAULT_ONTIME_DAYS);
+    match ontime.read() {
+        Ok(ontime) => RawJson(serde_json::to_string(&ontime.daily(days)).unwrap_or_default()),
+        Err(_) => RawJson("[]".to_string()),
+    }
+}
+
+#[get("/ontime/monthly")]
+pub fn ontime_monthly(
+    _access: ReadAccess,
+    ontime: &State<Arc<RwLock<OnTimeStats>>>,
+) -> RawJson<String> {
+    match ontime.read() {
+        Ok(ontime) => RawJson(serde_json::to_string(&ontime.monthly()).unwrap_or_default()),
+        Err(_) => RawJson("[]".to_string()),
+    }
+}
+
+//real-time events stream: every event is sent as a JSON text message
+#[get("/ws")]
+pub fn event_stream(
+    _access: ReadAccess,
+    socket: ws::WebSocket,
+    events: &State<EventSender>,
+) -> ws::Channel<'static> {
+    let mut receiver = events.subscribe();
+    socket.channel(move |mut stream| {
+        Box::pin(async move {
+            loop {
+                tokio::select! {
+                    event = receiver.recv() => match event {
+                        Ok(event) => {
+                            let json = serde_json::to_string(&event).unwrap_or_default();
+                            stream.send(ws::Message::Text(json)).await?;
+                        }
+                        Err(RecvError::Lagged(count)) => {
+                            warn!("webserver: websocket client lagging, {} events dropped", count);
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                    message = stream.next() => match message {
+                        Some(Ok(ws::Message::Close(_))) | None => break,
+                        Some(Err(e)) => return Err(e),
+                        _ => {}
+                    },
+                }
+            }
+            Ok(())
+        })
+    })
+}
+
+//intrusion alarm arming state
+pub struct AlarmArmed(pub Arc<AtomicBool>);
+
+#[get("/alarm")]
+pub fn alarm_status(_access: ReadAccess, armed: &State<AlarmArmed>) -> RawJson<String> {
+    RawJson(serde_json::json!({ "armed": armed.0.load(Ordering::SeqCst) }).to_string())
+}
+
+#[post("/alarm/<action>")]
+pub fn alarm_command(
+    _token: ApiToken,
+    action: &str,
+    armed: &State<AlarmArmed>,
+) -> (Status, String) {
+    let arm = match action {
+        "arm" => true,
+        "disarm" => false,
+        _ => return (Status::BadRequest, format!("Unknown action: {}", action)),
+    };
+    armed.0.store(arm, Ordering::SeqCst);
+    info!("webserver: alarm {}ed 🚨", action);
+    (Status::Ok, format!("Alarm {}ed", action))
+}
+
+#[get("/heating_season")]
+pub fn heating_season(
+    _access: ReadAccess,
+    season: &State<Arc<RwLock<HeatingSeason>>>,
+) -> RawJson<String> {
+    match season.read() {
+        Ok(season) => RawJson(serde_json::to_string(&season.status()).unwrap_or_default()),
+        Err(_) => RawJson("{}".to_string()),
+    }
+}
+
+//manual override of the heating season: on, off or auto
+#[post("/heating_season/<mode>")]
+pub fn heating_season_override(
+    _token: ApiToken,
+    mode: &str,
+    season: &State<Arc<RwLock<HeatingSeason>>>,
+) -> (Status, String) {
+    let manual = match mode {
+        "on" => Some(true),
+        "off" => Some(false),
+        "auto" => None,
+        _ => return (Status::BadRequest, format!("Unknown mode: {}", mode)),
+    };
+    if let Ok(mut season) = season.write() {
+        season.manual = manual;
+    }
+    info!("webserver: 🔥 heating season mode set to {}", mode);
+    (Status::Ok, format!("Heating season: {}", mode))
+}
+
+//sun2000 backup box: requested backup power SOC, written by the sun2000 task
+pub struct BackupSocRequest(pub Arc<Mutex<Option<f32>>>);
+
+#[post("/sun2000/backup_soc/<soc>")]
+pub fn backup_soc(
+    _token: ApiToken,
+    soc: f32,
+    request: &State<BackupSocRequest>,
+) -> (Status, String) {
+    if !(0.0..=100.0).contains(&soc) {
+        return (Status::BadRequest, format!("Invalid SOC: {}", soc));
+    }
+    if let Ok(mut request) = request.0.lock() {
+        *request = Some(soc);
+    }
+    info!(
+        "webserver: 🔋 backup power SOC change to {}% requested",
+        soc
+    );
+    (Status::Ok, format!("Setting backup power SOC to {}%", soc))
+}
+
+//skymax settings commands, sent by the skymax task
+pub struct SkymaxSettingRequests(pub Arc<Mutex<Vec<SkymaxSetting>>>);
+
+#[post("/skymax/<setting>/<value>")]
+pub fn skymax_setting(
+    _token: ApiToken,
+    setting: &str,
+    value: &str,
+    requests: &State<SkymaxSettingRequests>,
+) -> (Status, String) {
+    let setting = match SkymaxSetting::parse(setting, value) {
+        Some(setting) => setting,
+        None => {
+            return (
+                Status::BadRequest,
+                format!("Invalid skymax setting: {}={}", setting, value),
+            )
+        }
+    };
+    info!("webserver: ⚙️ skymax {} requested", setting);
+    let response = format!("Setting skymax {}", setting);
+    if let Ok(mut requests) = requests.0.lock() {
+        requests.push(setting);
+    }
+    (Status::Ok, response)
+}
+
+//remeha setpoint writes, sent by the task of the given boiler
+pub struct RemehaSetpointRequests(pub Arc<Mutex<Vec<RemehaSetpointRequest>>>);
+
+#[post("/remeha/<boiler>/<setpoint>/<value>")]
+pub fn remeha_setpoint(
+    _token: ApiToken,
+    boiler: &str,
+    setpoint: &str,
+    value: &str,
+    requests: &State<RemehaSetpointRequests>,
+) -> (Status, String) {
+    let setpoint = match RemehaSetpoint::parse(setpoint, value) {
+        Some(setpoint) => setpoint,
+        None => {
+            return (
+                Status::BadRequest,
+                format!("Invalid remeha setpoint: {}={}", setpoint, value),
+            )
+        }
+    };
+    info!("webserver: ⚙️ remeha {} {} requested", boiler, setpoint);
+    let response = format!("Setting remeha {} {}", boiler, setpoint);
+    if let Ok(mut requests) = requests.0.lock() {
+        requests.push(RemehaSetpointRequest {
+            boiler: boiler.to_string(),
+            setpoint,
+        });
+    }
+    (Status::Ok, response)
+}
+
+#[get("/logs?<level>&<module>")]
+pub fn logs(
+    _token: ApiToken,
+    level: Option<&str>,
+    module: Option<&str>,
+    log_buffer: &State<Arc<Mutex<LogBuffer>>>,
+) -> (Status, RawJson<String>) {
+    let level = match level.map(|l| l.parse::<Level>()) {
+        Some(Ok(level)) => level,
+        Some(Err(_)) => {
+            return (
+                Status::BadRequest,
+                RawJson(serde_json::json!({ "error": "invalid level" }).to_string()),
+            )
+        }
+        None => Level::Trace,
+    };
+    match log_buffer.lock() {
+        Ok(buffer) => (
+            Status::Ok,
+            RawJson(serde_json::to_string(&buffer.get(level, module)).unwrap_or_default()),
+        ),
+        Err(_) => (Status::InternalServerError, RawJson("[]".to_string())),
+    }
+}
+
+#[get("/log_levels")]
+pub fn log_levels(_access: ReadAccess) -> RawJson<String> {
+    RawJson(serde_json::to_string(&loglevel::get_levels()).unwrap_or_default())
+}
+
+/// Sets the verbosity of a module (`default` for all the others), `reset` removes the override
+#[post("/log_levels/<module>/<level>")]
+pub fn log_level_set(_token: ApiToken, module: &str, level: &str) -> (Status, String) {
+    if level == "reset" {
+        loglevel::reset_level(module);
+        info!("webserver: 📝 log level of {} reset to default", module);
+        return (Status::Ok, format!("{}: default level", module));
+    }
+    let level = match level.parse::<LevelFilter>() {
+        Ok(level) => level,
+        Err(_) => return (Status::BadRequest, format!("Invalid level: {}", level)),
+    };
+    let target = if module == "default" {
+        None
+    } else {
+        Some(module)
+    };
+    loglevel::set_level(target, level);
+    info!("webserver: 📝 log level of {} set to {}", module, level);
+    (Status::Ok, format!("{}: {}", module, level))
+}
+
+#[post("/service/restart/<worker>")]
+pub fn service_restart(
+    _token: ApiToken,
+    worker: &str,
+    service: &State<ServiceControl>,
+) -> (Status, String) {
+    if !service.workers.iter().any(|w| w == worker) {
+        return (Status::NotFound, format!("Unknown worker: {}", worker));
+    }
+    if let Ok(mut requests) = service.restart_requests.lock() {
+        requests.push(worker.to_string());
+    }
+    (Status::Ok, format!("Restarting {}...", worker))
+}
+
+#[post("/service/reload")]
+pub fn service_reload(
+    _token: ApiToken,
+    transmitters: &State<Arc<Mutex<(Sender<OneWireTask>, Sender<DbTask>)>>>,
+) -> String {
+    request_reload(transmitters)
+}
+
+#[post("/service/reboot")]
+pub fn service_reboot(_token: ApiToken, service: &State<ServiceControl>) -> (Status, String) {
+    if !service.allow_reboot {
+        return (
+            Status::Forbidden,
+            "Reboot is disabled in config".to_string(),
+        );
+    }
+    warn!("webserver: 🔌 host reboot requested");
+    let cmd = service.reboot_command.clone();
+    thread::spawn(move || {
+        //give a chance to send the response
+        thread::sleep(Duration::from_secs(2));
+        StateMachine::run_shell_command(cmd);
+    });
+    (Status::Ok, "Rebooting host...".to_string())
+}
+
 impl WebServer {
     pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
         //put a transmitter into a mutex and share to handlers
@@ -77,24 +1073,144 @@ impl WebServer {
             self.db_transmitter.clone(),
         )));
 
+        //last state of the devices for the dashboard
+        let event_cache = Arc::new(RwLock::new(EventCache::default()));
+        let cache_task = tokio::spawn(events::cache_events(
+            self.events.clone(),
+            event_cache.clone(),
+        ));
+
+        //relay test sequence for the maintenance
+        let exerciser = RelayExerciser::new(self.relay_devices.clone(), self.relays.clone());
+
+        //Rocket.toml / ROCKET_* environment settings overridden by hard.conf,
+        //the Rocket request log shows the full uri, so the requests are logged by RequestLog
+        let mut figment = rocket::Config::figment().merge(("log_level", LogLevel::Critical));
+        if let Some(address) = &self.address {
+            figment = figment.merge(("address", address));
+        }
+        if let Some(port) = self.port {
+            figment = figment.merge(("port", port));
+        }
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            info!("{}: 🔒 TLS enabled, certificate: {}", self.name, cert);
+            figment = figment.merge(("tls.certs", cert)).merge(("tls.key", key));
+        }
+        if !self.auth.control_enabled() {
+            info!(
+                "{}: no control credentials set, control endpoints are disabled",
+                self.name
+            );
+        }
+
         info!("{}: Starting task", self.name);
+        let mut backoff = Duration::from_secs(WEBSERVER_RESTART_MIN_SECS);
         loop {
             if worker_cancel_flag.load(Ordering::SeqCst) {
                 debug!("Got terminate signal from main");
                 break;
             }
 
-            let result = rocket::build()
+            let result = rocket::custom(figment.clone())
+                .attach(RequestLog)
                 .mount("/cmd", routes![hello, reload, fan_on, fan_off])
+                .mount("/", routes![dashboard, health, metrics, event_stream])
+                .mount(
+                    "/api",
+                    routes![
+                        cesspool,
+                        mailbox,
+                        gate_list,
+                        gate_command,
+                        adaptive_hold,
+                        relay_list,
+                        sensor_list,
+                        simulation_state,
+                        simulation_sensor,
+                        latest_events,
+                        yeelight_list,
+                        relay_command,
+                        yeelight_command,
+                        plug_list,
+                        plug_command,
+                        scene_list,
+                        scene_command,
+                        alarm_status,
+                        alarm_command,
+                        backup_soc,
+                        skymax_setting,
+                        remeha_setpoint,
+                        heating_season,
+                        heating_season_override,
+                        energy_costs,
+                        net_metering,
+                        ontime_daily,
+                        ontime_monthly,
+                        exercise_report,
+                        exercise_start,
+                        logs,
+                        log_levels,
+                        log_level_set,
+                        service_restart,
+                        service_reload,
+                        reload_devices,
+                        service_reboot
+                    ],
+                )
                 .manage(transmitters.clone())
+                .manage(self.sensor_devices.clone())
+                .manage(self.relay_devices.clone())
+                .manage(self.relays.clone())
+                .manage(self.cesspool_history.clone())
+                .manage(self.mailbox_state.clone())
+                .manage(self.gate_states.clone())
+                .manage(self.adaptive_hold.clone())
+                .manage(AlarmArmed(self.alarm_armed.clone()))
+                .manage(BackupSocRequest(self.backup_soc_request.clone()))
+                .manage(SkymaxSettingRequests(self.skymax_setting_requests.clone()))
+                .manage(RemehaSetpointRequests(
+                    self.remeha_setpoint_requests.clone(),
+                ))
+                .manage(self.heating_season.clone())
+                .manage(self.energy_costs.clone())
+                .manage(self.ontime.clone())
+                .manage(self.events.clone())
+                .manage(exerciser.clone())
+                .manage(self.bus_metrics.clone())
+                .manage(self.latency.clone())
+                .manage(self.queue_metrics.clone())
+                .manage(self.worker_errors.clone())
+                .manage(self.log_buffer.clone())
+                .manage(self.service_control.clone())
+                .manage(self.sensor_values.clone())
+                .manage(event_cache.clone())
+                .manage(self.auth.clone())
                 .launch()
-                .compat()
                 .await;
-            result.expect("server failed unexpectedly");
-
-            tokio::time::sleep(Duration::from_millis(50)).await;
+            match result {
+                Ok(_) => {
+                    backoff = Duration::from_secs(WEBSERVER_RESTART_MIN_SECS);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                //eg. unreadable TLS certificate/key or the port already in use
+                Err(e) => {
+                    error!(
+                        "{}: server failed: {}, restarting in {}",
+                        self.name,
+                        e,
+                        humantime::format_duration(backoff)
+                    );
+                    let started = Instant::now();
+                    while started.elapsed() < backoff && !worker_cancel_flag.load(Ordering::SeqCst)
+                    {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                    backoff = (backoff * 2).min(Duration::from_secs(WEBSERVER_RESTART_MAX_SECS));
+                }
+            }
         }
 
+        cache_task.abort();
         info!("{}: task stopped", self.name);
         Ok(())
     }
@@ -0,0 +1,46 @@
+use crate::metrics::WorkerErrors;
+use crate::notify::{Notification, Severity};
+use crate::queue::Sender;
+use simplelog::*;
+use std::sync::{Arc, RwLock};
+
+/// Typed error of a worker: the recoverable ones are reported and the worker continues
+/// (usually with a reconnection), a fatal one ends the worker
+pub trait WorkerError: std::error::Error {
+    /// Short label for the metrics, eg. `connect` or `invalid_data`
+    fn kind(&self) -> &'static str;
+
+    fn is_recoverable(&self) -> bool;
+}
+
+/// Reports the worker errors to the log, metrics and (the fatal ones) notifications
+#[derive(Clone)]
+pub struct ErrorReporter {
+    pub worker: String,
+    pub metrics: Arc<RwLock<WorkerErrors>>,
+    pub notify_transmitter: Sender<Notification>,
+}
+
+impl ErrorReporter {
+    pub fn report<E: WorkerError>(&self, e: &E) {
+        let recoverable = e.is_recoverable();
+        self.metrics
+            .write()
+            .unwrap()
+            .observe(&self.worker, e.kind(), recoverable);
+        if recoverable {
+            error!("<i>{}</>: <b>{}</>", self.worker, e);
+        } else {
+            error!(
+                "<i>{}</>: ☠️ fatal error, stopping: <b>{}</>",
+                self.worker, e
+            );
+            let _ = self.notify_transmitter.send(Notification::new(
+                self.worker.as_str(),
+                Severity::Critical,
+                format!("{} stopped", self.worker),
+                e.to_string(),
+            ));
+        }
+    }
+}
@@ -0,0 +1,529 @@
+use crate::metrics::LatencyMetrics;
+use crate::onewire::{RelayDevices, Relays};
+use crate::systemd;
+use serde::ser::SerializeSeq;
+use serde::{Deserialize, Serialize, Serializer};
+use simplelog::*;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+pub const YEELIGHT_TCP_PORT: u16 = 55443;
+static YEELIGHT_METHOD_SET_POWER: &str = "set_power"; //method value name for powering on/off
+static YEELIGHT_METHOD_GET_PROP: &str = "get_prop"; //method for querying the properties
+static YEELIGHT_METHOD_PROPS: &str = "props"; //notification pushed by the bulb on state change
+static YEELIGHT_EFFECT: &str = "smooth"; //default effect for turning on/off
+pub const YEELIGHT_DURATION_MS: u32 = 500; //duration of above effect
+pub const YEELIGHT_CONNECT_TIMEOUT_SECS: f32 = 2.0;
+pub const YEELIGHT_REPLY_TIMEOUT_SECS: f32 = 1.5; //max time for a command result
+pub const YEELIGHT_RECONNECT_SECS: f32 = 10.0; //secs between connection attempts to an unreachable bulb
+pub const YEELIGHT_RESYNC_INTERVAL_SECS: f32 = 60.0; //secs between get_prop polls (also a keepalive)
+pub const YEELIGHT_SYNC_INTERVAL_SECS: f32 = 30.0; //secs between checking the bulb list for changes
+pub const YEELIGHT_COMMAND_ATTEMPTS: u8 = 3;
+pub const YEELIGHT_COMMAND_QUEUE: usize = 16; //commands waiting for a single bulb
+
+// Just a generic Result type to ease error handling for us. Errors in multithreaded
+// async contexts needs some extra restrictions
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Power command queued by the onewire thread for the connection pool
+pub struct YeelightRequest {
+    pub id: i32,
+    pub turn_on: bool,
+    //sensor edge and its read time when triggered by a sensor, for the latency metrics
+    pub edge: Option<(Instant, Duration)>,
+}
+
+#[derive(Serialize)]
+struct YeelightCommand {
+    id: u32,
+    method: String,
+    #[serde(serialize_with = "params_serialize")]
+    params: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct YeelightError {
+    code: i32,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct YeelightProps {
+    power: Option<String>,
+}
+
+/// Command result or a notification, both are sent on the same connection
+#[derive(Deserialize)]
+struct YeelightMessage {
+    id: Option<u32>,
+    result: Option<Vec<String>>,
+    error: Option<YeelightError>,
+    method: Option<String>,
+    params: Option<YeelightProps>,
+}
+
+fn params_serialize<S>(params: &Vec<String>, serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let mut seq = serializer.serialize_seq(Some(params.len()))?;
+    for (pos, elem) in params.iter().enumerate() {
+        if pos == 2 {
+            //converting last parameter (duration of effect) to integer
+            let duration: u32 = elem.parse().unwrap_or_default();
+            seq.serialize_element(&duration)?;
+        } else {
+            //leaving as String
+            seq.serialize_element(&elem)?;
+        }
+    }
+    seq.end()
+}
+
+enum PendingCommand {
+    SetPower(YeelightRequest, u8),
+    GetProp,
+}
+
+/// Persistent connection to a single bulb
+struct Connection {
+    id: i32,
+    name: String,
+    ip_address: String,
+    lines: Option<Lines<BufReader<OwnedReadHalf>>>,
+    writer: Option<OwnedWriteHalf>,
+    next_connect: Instant,
+    last_resync: Option<Instant>,
+    next_msg_id: u32,
+    //commands waiting for the connection, with the attempt number
+    queued: Vec<(YeelightRequest, u8)>,
+    //commands sent, waiting for the result
+    pending: HashMap<u32, (PendingCommand, Instant)>,
+}
+
+impl Connection {
+    fn new(id: i32, name: String, ip_address: String) -> Self {
+        Connection {
+            id,
+            name,
+            ip_address,
+            lines: None,
+            writer: None,
+            next_connect: Instant::now(),
+            last_resync: None,
+            next_msg_id: 1,
+            queued: vec![],
+            pending: HashMap::new(),
+        }
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        debug!("Yeelight: {}: connecting...", self.name);
+        let stream = timeout(
+            Duration::from_secs_f32(YEELIGHT_CONNECT_TIMEOUT_SECS),
+            TcpStream::connect(format!("{}:{}", self.ip_address, YEELIGHT_TCP_PORT)),
+        )
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "connection timeout"))??;
+        let (reader, writer) = stream.into_split();
+        self.lines = Some(BufReader::new(reader).lines());
+        self.writer = Some(writer);
+        //the bulb may have been switched while we were disconnected
+        self.last_resync = None;
+        info!("Yeelight: {}: 🔗 connected", self.name);
+        Ok(())
+    }
+
+    /// Drops the connection, the commands without a result are queued again
+    fn disconnect(&mut self) {
+        self.lines = None;
+        self.writer = None;
+        self.next_connect = Instant::now() + Duration::from_secs_f32(YEELIGHT_RECONNECT_SECS);
+        for (_, (command, _)) in self.pending.drain() {
+            if let PendingCommand::SetPower(request, attempt) = command {
+                self.queued.push((request, attempt + 1));
+            }
+        }
+        self.queued
+            .retain(|(_, attempt)| *attempt < YEELIGHT_COMMAND_ATTEMPTS);
+    }
+
+    async fn send(&mut self, method: &str, params: Vec<String>) -> Result<u32> {
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| Error::new(ErrorKind::NotConnected, "not connected"))?;
+        let id = self.next_msg_id;
+        self.next_msg_id = self.next_msg_id.wrapping_add(1).max(1);
+        let cmd = YeelightCommand {
+            id,
+            method: method.to_owned(),
+            params,
+        };
+
+        // serialize command to a JSON string
+        let mut json_cmd = serde_json::to_string(&cmd)?;
+        debug!(
+            "Yeelight: {}: generated JSON command={:?}",
+            self.name, json_cmd
+        );
+        json_cmd.push_str("\r\n"); //specs requirement
+        timeout(
+            Duration::from_secs_f32(YEELIGHT_REPLY_TIMEOUT_SECS),
+            writer.write_all(json_cmd.as_bytes()),
+        )
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "write timeout"))??;
+        Ok(id)
+    }
+
+    async fn set_power(&mut self, request: YeelightRequest, attempt: u8) -> Result<()> {
+        let on_off = if request.turn_on { "on" } else { "off" };
+        let params = vec![
+            on_off.to_owned(),
+            YEELIGHT_EFFECT.to_owned(),
+            YEELIGHT_DURATION_MS.to_string(),
+        ];
+        match self.send(YEELIGHT_METHOD_SET_POWER, params).await {
+            Ok(id) => {
+                self.pending.insert(
+                    id,
+                    (PendingCommand::SetPower(request, attempt), Instant::now()),
+                );
+                Ok(())
+            }
+            Err(e) => {
+                self.queued.push((request, attempt));
+                Err(e)
+            }
+        }
+    }
+
+    async fn get_prop(&mut self) -> Result<()> {
+        let id = self
+            .send(YEELIGHT_METHOD_GET_PROP, vec!["power".to_owned()])
+            .await?;
+        self.pending
+            .insert(id, (PendingCommand::GetProp, Instant::now()));
+        self.last_resync = Some(Instant::now());
+        Ok(())
+    }
+}
+
+/// Task of a single bulb with its own command channel, so a slow or unreachable bulb
+/// doesn't delay the commands of the others
+struct Bulb {
+    conn: Connection,
+    commands: mpsc::Receiver<YeelightRequest>,
+    relay_devices: Arc<RwLock<RelayDevices>>,
+    latency: Arc<RwLock<LatencyMetrics>>,
+}
+
+/// Running bulb task, it is stopped when the handle is dropped
+struct BulbHandle {
+    ip_address: String,
+    commands: mpsc::Sender<YeelightRequest>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for BulbHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Next line from the bulb, never ready without a connection
+async fn next_line(
+    lines: &mut Option<Lines<BufReader<OwnedReadHalf>>>,
+) -> std::io::Result<Option<String>> {
+    match lines {
+        Some(lines) => lines.next_line().await,
+        None => std::future::pending().await,
+    }
+}
+
+impl Bulb {
+    fn update_state(&self, powered_on: bool) {
+        let mut relay_dev = self.relay_devices.write().unwrap();
+        if let Some(yeelight) = relay_dev.yeelight.iter_mut().find(|y| y.id == self.conn.id) {
+            if yeelight.powered_on != powered_on {
+                info!(
+                    "Yeelight: {}: 💡 state changed outside of hard, now: <b>{}</>",
+                    self.conn.name,
+                    if powered_on { "on" } else { "off" }
+                );
+                yeelight.powered_on = powered_on;
+            }
+        }
+    }
+
+    fn handle_message(&mut self, raw: &str) {
+        let msg = match serde_json::from_str::<YeelightMessage>(raw) {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!(
+                    "Yeelight: {}: error parsing JSON: {:?}\nraw input data: {:?}",
+                    self.conn.name, e, raw
+                );
+                return;
+            }
+        };
+
+        //state change notification
+        if msg.method.as_deref() == Some(YEELIGHT_METHOD_PROPS) {
+            if let Some(power) = msg.params.and_then(|p| p.power) {
+                debug!(
+                    "Yeelight: {}: notification: power={}",
+                    self.conn.name, power
+                );
+                self.update_state(power == "on");
+            }
+            return;
+        }
+
+        let command = match msg.id.and_then(|id| self.conn.pending.remove(&id)) {
+            Some((command, _)) => command,
+            None => {
+                debug!(
+                    "Yeelight: {}: unexpected message: {:?}",
+                    self.conn.name, raw
+                );
+                return;
+            }
+        };
+        match (command, msg.result) {
+            (PendingCommand::SetPower(request, _), Some(result)) if result == vec!["ok"] => {
+                debug!("Yeelight: {}: command ok", self.conn.name);
+                if let Some((edge, read_time)) = request.edge {
+                    self.latency.write().unwrap().observe(
+                        "yeelight",
+                        &self.conn.name,
+                        read_time,
+                        edge.elapsed(),
+                    );
+                }
+            }
+            (PendingCommand::GetProp, Some(result)) => {
+                if let Some(power) = result.get(0) {
+                    self.update_state(power == "on");
+                }
+            }
+            (command, _) => {
+                let reason = match msg.error {
+                    Some(e) => format!("{} (code {})", e.message, e.code),
+                    None => "unexpected result".to_string(),
+                };
+                error!("Yeelight: {}: command failed: {}", self.conn.name, reason);
+                if let PendingCommand::SetPower(request, attempt) = command {
+                    if attempt + 1 < YEELIGHT_COMMAND_ATTEMPTS {
+                        self.conn.queued.push((request, attempt + 1));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Connects when needed, sends the queued commands and the periodic resync
+    async fn process(&mut self) {
+        let conn = &mut self.conn;
+        if conn.writer.is_none() {
+            //commands are trying to connect right away
+            if conn.queued.is_empty() && Instant::now() < conn.next_connect {
+                return;
+            }
+            if let Err(e) = conn.connect().await {
+                error!("Yeelight: {}: connection error: {:?}", conn.name, e);
+                conn.disconnect();
+                for (_, attempt) in &mut conn.queued {
+                    *attempt += 1;
+                }
+                conn.queued
+                    .retain(|(_, attempt)| *attempt < YEELIGHT_COMMAND_ATTEMPTS);
+                return;
+            }
+        }
+
+        let mut result = Ok(());
+        for (request, attempt) in conn.queued.drain(..).collect::<Vec<_>>() {
+            if result.is_ok() {
+                result = conn.set_power(request, attempt).await;
+            } else {
+                conn.queued.push((request, attempt));
+            }
+        }
+        let resync_interval = Duration::from_secs_f32(YEELIGHT_RESYNC_INTERVAL_SECS);
+        if result.is_ok()
+            && conn
+                .last_resync
+                .map_or(true, |t| t.elapsed() > resync_interval)
+        {
+            result = conn.get_prop().await;
+        }
+
+        //no result within the timeout: the connection is considered dead
+        let reply_timeout = Duration::from_secs_f32(YEELIGHT_REPLY_TIMEOUT_SECS);
+        if result.is_ok()
+            && conn
+                .pending
+                .values()
+                .any(|(_, sent)| sent.elapsed() > reply_timeout)
+        {
+            result = Err(Error::new(ErrorKind::TimedOut, "no command result").into());
+        }
+        if let Err(e) = result {
+            error!("Yeelight: {}: connection lost: {:?}", conn.name, e);
+            conn.disconnect();
+        }
+    }
+
+    /// Time until the next reconnection, resync or reply timeout check
+    fn next_wakeup(&self) -> Duration {
+        let conn = &self.conn;
+        let now = Instant::now();
+        if conn.writer.is_none() {
+            if !conn.queued.is_empty() {
+                return Duration::ZERO;
+            }
+            return conn.next_connect.saturating_duration_since(now);
+        }
+        let resync = conn.last_resync.map_or(now, |t| {
+            t + Duration::from_secs_f32(YEELIGHT_RESYNC_INTERVAL_SECS)
+        });
+        let reply_timeout = Duration::from_secs_f32(YEELIGHT_REPLY_TIMEOUT_SECS);
+        conn.pending
+            .values()
+            .map(|(_, sent)| *sent + reply_timeout)
+            .fold(resync, |earliest, deadline| earliest.min(deadline))
+            .saturating_duration_since(now)
+    }
+
+    async fn run(mut self) {
+        loop {
+            self.process().await;
+            let wakeup = self.next_wakeup();
+            tokio::select! {
+                request = self.commands.recv() => match request {
+                    Some(request) => self.conn.queued.push((request, 0)),
+                    //the bulb was removed
+                    None => break,
+                },
+                line = next_line(&mut self.conn.lines) => match line {
+                    Ok(Some(line)) => self.handle_message(&line),
+                    Ok(None) => {
+                        error!("Yeelight: {}: connection lost: connection closed", self.conn.name);
+                        self.conn.disconnect();
+                    }
+                    Err(e) => {
+                        error!("Yeelight: {}: connection lost: {:?}", self.conn.name, e);
+                        self.conn.disconnect();
+                    }
+                },
+                _ = tokio::time::sleep(wakeup) => (),
+            }
+        }
+    }
+}
+
+/// Async task keeping persistent connections to all yeelight bulbs, each bulb is handled
+/// by its own task. The bulbs push a notification on every state change (also from the app
+/// or a wall switch), additionally the power property is polled periodically to resync `powered_on`.
+pub struct YeelightPool {
+    pub name: String,
+    pub relay_devices: Arc<RwLock<RelayDevices>>,
+    pub relays: Arc<RwLock<Relays>>,
+    pub requests: Arc<Mutex<Vec<YeelightRequest>>>,
+    pub latency: Arc<RwLock<LatencyMetrics>>,
+}
+
+impl YeelightPool {
+    /// Starts the tasks of new bulbs and stops the ones of removed/changed bulbs
+    fn sync_bulbs(&self, bulbs: &mut HashMap<i32, BulbHandle>) {
+        let configured: Vec<(i32, String)> = self
+            .relay_devices
+            .read()
+            .unwrap()
+            .yeelight
+            .iter()
+            .map(|y| (y.id, y.ip_address.clone()))
+            .collect();
+        let names: HashMap<i32, String> = self
+            .relays
+            .read()
+            .unwrap()
+            .relay
+            .iter()
+            .map(|d| (d.id, d.name.clone()))
+            .collect();
+
+        bulbs.retain(|id, bulb| configured.contains(&(*id, bulb.ip_address.clone())));
+        for (id, ip_address) in configured {
+            if bulbs.contains_key(&id) {
+                continue;
+            }
+            let name = names.get(&id).cloned().unwrap_or(ip_address.clone());
+            debug!("{}: adding bulb: {} ({})", self.name, name, ip_address);
+            let (commands, receiver) = mpsc::channel(YEELIGHT_COMMAND_QUEUE);
+            let bulb = Bulb {
+                conn: Connection::new(id, name, ip_address.clone()),
+                commands: receiver,
+                relay_devices: self.relay_devices.clone(),
+                latency: self.latency.clone(),
+            };
+            bulbs.insert(
+                id,
+                BulbHandle {
+                    ip_address,
+                    commands,
+                    task: tokio::spawn(bulb.run()),
+                },
+            );
+        }
+    }
+
+    pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
+        info!("{}: Starting task", self.name);
+        let mut bulbs: HashMap<i32, BulbHandle> = HashMap::new();
+        let mut sync_time: Option<Instant> = None;
+
+        loop {
+            if worker_cancel_flag.load(Ordering::SeqCst) {
+                break;
+            }
+            systemd::heartbeat(&self.name);
+
+            let requests: Vec<YeelightRequest> = self.requests.lock().unwrap().drain(..).collect();
+            let sync_interval = Duration::from_secs_f32(YEELIGHT_SYNC_INTERVAL_SECS);
+            if !requests.is_empty() || sync_time.map_or(true, |t| t.elapsed() > sync_interval) {
+                self.sync_bulbs(&mut bulbs);
+                sync_time = Some(Instant::now());
+            }
+            for request in requests {
+                match bulbs.get(&request.id) {
+                    Some(bulb) => {
+                        if let Err(e) = bulb.commands.try_send(request) {
+                            warn!("{}: command dropped: {}", self.name, e);
+                        }
+                    }
+                    None => warn!("{}: unknown bulb: id={}", self.name, request.id),
+                }
+            }
+
+            tokio::time::sleep(Duration::from_millis(30)).await;
+        }
+
+        //the bulb tasks are stopped with their handles
+        bulbs.clear();
+        systemd::heartbeat_stop(&self.name);
+        info!("{}: task stopped", self.name);
+        Ok(())
+    }
+}